@@ -0,0 +1,91 @@
+// Benchmarks for the hot paths, run with `cargo bench`. Each benchmark is timed over a few
+// batches and the fastest batch is reported, which is the least noisy estimate.
+
+extern crate tracerlib;
+
+use std::time::Instant;
+
+use tracerlib::{ray_trace, Camera, Float, Scene, Vec3};
+use tracerlib::light::PointLight;
+use tracerlib::log::{self, Level};
+use tracerlib::material::Material;
+use tracerlib::ray::Ray;
+use tracerlib::surface::{Plane, Sphere, Surface};
+
+const BATCHES: u32 = 5;
+
+fn bench<F: FnMut() -> Float>(name: &str, iterations: u32, mut f: F) {
+    // Summing the results keeps the optimizer from throwing the work away
+    let mut sink = 0.;
+    let mut best = None;
+    for _ in 0..BATCHES {
+        let start = Instant::now();
+        for _ in 0..iterations {
+            sink += f();
+        }
+        let elapsed = start.elapsed();
+        let nanos = elapsed.as_secs() as f64 * 1e9 + elapsed.subsec_nanos() as f64;
+        let per_iter = nanos / iterations as f64;
+        best = Some(best.map_or(per_iter, |best: f64| best.min(per_iter)));
+    }
+
+    let per_iter = best.unwrap();
+    if per_iter > 1e5 {
+        println!("{:<24} {:>12.3} ms/iter  ({})", name, per_iter / 1e6, sink);
+    } else {
+        println!("{:<24} {:>12.1} ns/iter  ({})", name, per_iter, sink);
+    }
+}
+
+fn material() -> Material {
+    Material::new(Vec3::new(200., 50., 50.), 0.7, 0.2, 20., 0.2, None, None, None)
+}
+
+fn distance(surface: &Surface, ray: &Ray) -> Float {
+    surface.intersect(ray).map_or(0., |hit| hit.dist)
+}
+
+// A grid of n x n spheres over a reflective floor
+fn sphere_grid(n: u32) -> Scene {
+    let mut objects = Vec::new();
+    for i in 0..n {
+        for j in 0..n {
+            let pos = Vec3::new(i as Float - n as Float / 2., 0.4, j as Float);
+            objects.push(Box::new(Sphere::new(pos, 0.4, material())) as Box<Surface>);
+        }
+    }
+    objects.push(Box::new(Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.),
+                                     material())));
+
+    let lights = vec![PointLight::new(Vec3::new(2., 5., -3.), Vec3::new(255., 255., 255.),
+                                      1.5)];
+    let camera = Camera::from_lookat(Vec3::new(0., 4., -6.), Vec3::new(0., 0., n as Float / 2.),
+                                     Vec3::new(0., 1., 0.));
+    Scene::new(objects, lights, 0.1, Vec3::new(255., 255., 255.), camera)
+}
+
+fn render(scene: &Scene) -> Float {
+    ray_trace(scene, 64, 48, 2).get_pixel(32, 24).data[0] as Float
+}
+
+fn main() {
+    log::set_level(Level::Warn);
+
+    let sphere = Sphere::new(Vec3::new(0., 0., 5.), 1., material());
+    let plane = Plane::new(Vec3::new(0., -1., 0.), Vec3::new(0., 1., 0.), material());
+    let hit_ray = Ray::new(Vec3::new(0., 0., 0.), Vec3::new(0.1, 0.1, 1.));
+    let miss_ray = Ray::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.));
+    let plane_ray = Ray::new(Vec3::new(0., 0., 0.), Vec3::new(0., -1., 1.));
+
+    bench("sphere hit", 1000000, || distance(&sphere, &hit_ray));
+    bench("sphere miss", 1000000, || distance(&sphere, &miss_ray));
+    bench("plane hit", 1000000, || distance(&plane, &plane_ray));
+
+    // Whole renders, which is mostly scene traversal for primary, shadow and reflected rays
+    let small = sphere_grid(1);
+    bench("render 1 sphere", 10, || render(&small));
+    let grid = sphere_grid(10);
+    bench("render 10x10 spheres", 3, || render(&grid));
+    let grid = sphere_grid(30);
+    bench("render 30x30 spheres", 1, || render(&grid));
+}
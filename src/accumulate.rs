@@ -0,0 +1,98 @@
+// Averaging several renders of one frame in a single buffer: runs with different seeds, whose
+// noise averages out like that of more samples would, runs with the shutter open at different
+// times, or bracketed exposures of the same view. Renders keep their unclamped colors and alpha,
+// so the average is exact, and post effects are applied to it afterwards, once.
+
+use {ray_trace_events, Float, Scene};
+use hdr::HdrImage;
+use tiles::TileOrder;
+
+pub struct Accumulator {
+    // Colors and alpha of the renders added so far, each times its weight
+    sum: HdrImage,
+    weight: Float,
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Self {
+        let mut sum = HdrImage::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                sum.put_alpha(x, y, 0.);
+            }
+        }
+        Accumulator { sum: sum, weight: 0. }
+    }
+
+    pub fn add(&mut self, image: &HdrImage) {
+        self.add_weighted(image, 1.);
+    }
+
+    // Counts `image` `weight` times, e.g. its number of samples per pixel, when renders with
+    // different sample counts are averaged
+    pub fn add_weighted(&mut self, image: &HdrImage, weight: Float) {
+        self.add_scaled(image, 1., weight);
+    }
+
+    // Adds an image exposed `ev` stops brighter than the others, as a camera's Imaging or the
+    // exposure post effect would, scaling it back first so all exposures agree
+    pub fn add_exposure(&mut self, image: &HdrImage, ev: Float) {
+        self.add_scaled(image, Float::powf(2., -ev), 1.);
+    }
+
+    fn add_scaled(&mut self, image: &HdrImage, scale: Float, weight: Float) {
+        assert!(image.width() == self.sum.width() && image.height() == self.sum.height(),
+                "Can't accumulate a {}x{} image into {}x{}", image.width(), image.height(),
+                self.sum.width(), self.sum.height());
+        assert!(weight > 0., "Renders need a positive weight");
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                let color = self.sum.get_pixel(x, y) + image.get_pixel(x, y) * (scale * weight);
+                let alpha = self.sum.get_alpha(x, y) + image.get_alpha(x, y) * weight;
+                self.sum.put_pixel(x, y, color);
+                self.sum.put_alpha(x, y, alpha);
+            }
+        }
+        self.weight += weight;
+    }
+
+    // The total weight of the renders added so far, their number if all had weight 1
+    pub fn weight(&self) -> Float {
+        self.weight
+    }
+
+    // The weighted average of the renders added so far
+    pub fn image(&self) -> HdrImage {
+        assert!(self.weight > 0., "Nothing was accumulated");
+        let mut average = self.sum.clone();
+        let scale = 1. / self.weight;
+        for pixel in average.pixels_mut() {
+            *pixel = *pixel * scale;
+        }
+        for y in 0..average.height() {
+            for x in 0..average.width() {
+                let alpha = average.get_alpha(x, y);
+                average.put_alpha(x, y, alpha * scale);
+            }
+        }
+        average
+    }
+}
+
+// Renders `scene` `runs` times with samples x samples rays per pixel, each with the next seed
+// from the scene's own, and averages them. The scene's seed is put back afterwards
+pub fn ray_trace_runs(scene: &mut Scene, width: u32, height: u32, max_depth: u16, samples: u32,
+                      runs: u32)
+                      -> HdrImage {
+    assert!(runs > 0, "Need at least one run");
+    let seed = scene.seed;
+    let mut accumulator = Accumulator::new(width, height);
+    for run in 0..runs {
+        scene.set_seed(seed.wrapping_add(run));
+        let image = ray_trace_events(scene, width, height, max_depth, samples,
+                                     TileOrder::Scanline, |_| {});
+        accumulator.add(&image);
+    }
+    scene.set_seed(seed);
+    accumulator.image()
+}
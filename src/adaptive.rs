@@ -0,0 +1,194 @@
+// Progressive rendering with adaptive sampling. Every pixel gets a sample per pass at a jittered
+// position, and the image is the running average. After a few passes over the whole image, the
+// remaining passes go to the tiles where they help most: those whose pixels are still noisy, and
+// which are expensive to render, since those are usually the complicated parts of the image.
+// Tiles of plain sky or flat walls converge after a few passes and are left alone.
+//
+// The result only depends on the scene and settings: samples are seeded by pixel and pass, and
+// the cost of a tile is measured in intersection tests rather than time, so that the schedule
+// doesn't change with the machine's load.
+
+use std::cmp;
+
+use {pixel_seed, trace_primary, with_wavelength, Float, RenderEvent, Scene, Vec3, TILE_SIZE};
+use hdr::HdrImage;
+use log::{self, Level};
+use post::luminance;
+use stats;
+use tiles::{tile_order, TileOrder};
+
+// Sample positions are jittered on a grid this much finer than the pixels
+const JITTER: u32 = 16;
+// Noise is relative to at least this brightness, one 8 bit step, so that the noise of nearly
+// black pixels doesn't count for a lot
+const MIN_LUMINANCE: Float = 1. / 255.;
+
+// How the samples of an adaptive render are spread
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveSettings {
+    samples: u32,
+    min_samples: u32,
+    max_samples: u32,
+    threshold: Float,
+}
+
+impl AdaptiveSettings {
+    // `samples` per pixel on average, spread over the image without further limits
+    pub fn new(samples: u32) -> Self {
+        AdaptiveSettings { samples: samples, min_samples: 4, max_samples: u32::max_value(),
+                           threshold: 0. }
+    }
+
+    // Passes over the whole image before the tiles are compared, 4 by default
+    pub fn with_min_samples(mut self, min_samples: u32) -> Self {
+        assert!(min_samples > 0, "Every pixel needs at least one sample");
+        self.min_samples = min_samples;
+        self
+    }
+
+    // The most samples any pixel gets, however noisy it is
+    pub fn with_max_samples(mut self, max_samples: u32) -> Self {
+        assert!(max_samples > 0, "Every pixel needs at least one sample");
+        self.max_samples = max_samples;
+        self
+    }
+
+    // Tiles stop getting samples once their noise, the mean standard error of their pixels
+    // relative to their brightness, is below `threshold`, even if that leaves samples unused
+    pub fn with_threshold(mut self, threshold: Float) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+}
+
+struct Tile {
+    region: (u32, u32, u32, u32),
+    passes: u32,
+    // Intersection tests done for the tile so far
+    cost: u64,
+    // Mean standard error of the tile's pixels, relative to their brightness
+    error: Float,
+}
+
+// Renders the settings' samples per pixel on average, spread over the image by measured tile cost
+// and noise. `events` gets a TileFinished event for every pass over a tile, with `done` and
+// `total` counting tile passes, and a PassFinished event after each of the passes over the whole
+// image
+pub fn ray_trace_adaptive<F>(scene: &Scene, width: u32, height: u32, max_depth: u16,
+                             settings: AdaptiveSettings, order: TileOrder, mut events: F)
+                             -> HdrImage
+    where F: FnMut(RenderEvent)
+{
+    let samples = settings.samples;
+    let _span = log::span(Level::Info, format!("adaptive ray trace {}x{}, {} samples", width,
+                                               height, samples));
+    let tiles_x = (width + TILE_SIZE - 1) / TILE_SIZE;
+    let tiles_y = (height + TILE_SIZE - 1) / TILE_SIZE;
+    let mut tiles: Vec<Tile> = tile_order(tiles_x, tiles_y, order).into_iter().map(|(tx, ty)| {
+        let (x, y) = (tx * TILE_SIZE, ty * TILE_SIZE);
+        Tile {
+            region: (x, y, cmp::min(x + TILE_SIZE, width), cmp::min(y + TILE_SIZE, height)),
+            passes: 0,
+            cost: 0,
+            error: 0.,
+        }
+    }).collect();
+
+    let mut im = HdrImage::new(width, height);
+    let mut sums = vec![Vec3::new(0., 0., 0.); (width * height) as usize];
+    let mut squares = vec![0.; (width * height) as usize];
+    let mut alphas = vec![0.; (width * height) as usize];
+    let total = samples * tiles.len() as u32;
+    let mut done = 0;
+    let mut pass = |tile: &mut Tile, im: &mut HdrImage| {
+        stats::take_intersection_tests();
+        let (x0, y0, x1, y1) = tile.region;
+        let n = tile.passes + 1;
+        let mut error = 0.;
+        for x in x0..x1 {
+            for y in y0..y1 {
+                // Passes don't know how many more follow, so the sets are open-ended
+                let seed = pixel_seed(scene, x, y);
+                let (u, v) = scene.sampler.get_2d(seed, n - 1, 0, 3);
+                let (jx, jy) = (cmp::min((u * JITTER as Float) as u32, JITTER - 1),
+                                cmp::min((v * JITTER as Float) as u32, JITTER - 1));
+                let ray = scene.camera.get_sampled_ray(x * JITTER + jx, y * JITTER + jy,
+                                                       width * JITTER, height * JITTER,
+                                                       width as Float / height as Float,
+                                                       scene.sampler.get_2d(seed, n - 1, 0, 1),
+                                                       scene.sampler.get_1d(seed, n - 1, 0, 15),
+                                                       JITTER);
+                let ray = with_wavelength(scene, ray, seed, n - 1, 0);
+                let (color, alpha) = trace_primary(scene, &ray, max_depth);
+
+                let i = (y * width + x) as usize;
+                sums[i] = sums[i] + color;
+                squares[i] += luminance(&color) * luminance(&color);
+                alphas[i] += alpha;
+                let mean = sums[i] / n as Float;
+                im.put_pixel(x, y, mean);
+                im.put_alpha(x, y, alphas[i] / n as Float);
+
+                let mean_luminance = luminance(&mean);
+                let variance = (squares[i] / n as Float - mean_luminance * mean_luminance).max(0.);
+                error += (variance / n as Float).sqrt() / mean_luminance.max(MIN_LUMINANCE);
+            }
+        }
+        tile.passes = n;
+        tile.error = error / ((x1 - x0) * (y1 - y0)) as Float;
+        tile.cost += stats::take_intersection_tests();
+    };
+
+    let min_passes = cmp::min(cmp::min(settings.min_samples, settings.max_samples), samples);
+    for _ in 0..min_passes {
+        for tile in tiles.iter_mut() {
+            events(RenderEvent::TileStarted { region: tile.region });
+            pass(tile, &mut im);
+            done += 1;
+            events(RenderEvent::TileFinished { region: tile.region, done: done, total: total,
+                                               image: &im });
+        }
+        events(RenderEvent::PassFinished { image: &im });
+    }
+
+    while done < total {
+        // Noisy tiles first, weighted by how expensive they are to render compared to the average
+        let cost = |tile: &Tile| tile.cost as f64 / tile.passes as f64;
+        let average_cost = tiles.iter().map(&cost).sum::<f64>() / tiles.len() as f64;
+        let priority = |tile: &Tile| {
+            if tile.passes >= settings.max_samples || tile.error <= settings.threshold {
+                0.
+            } else {
+                tile.error as f64 * cost(tile) / average_cost.max(1.)
+            }
+        };
+        let mut best = 0;
+        for i in 1..tiles.len() {
+            if priority(&tiles[i]) > priority(&tiles[best]) {
+                best = i;
+            }
+        }
+
+        if priority(&tiles[best]) == 0. {
+            // Everything has converged, or got the most samples allowed
+            break;
+        }
+
+        let tile = &mut tiles[best];
+        events(RenderEvent::TileStarted { region: tile.region });
+        pass(tile, &mut im);
+        done += 1;
+        events(RenderEvent::TileFinished { region: tile.region, done: done, total: total,
+                                           image: &im });
+    }
+
+    let (min, max) = tiles.iter().fold((u32::max_value(), 0), |(min, max), tile| {
+        (cmp::min(min, tile.passes), cmp::max(max, tile.passes))
+    });
+    debug!("Tiles got between {} and {} samples per pixel", min, max);
+    im
+}
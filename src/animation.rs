@@ -0,0 +1,145 @@
+// Rendering animations. Any table of a scene file can have [[<table>.keyframe]] tables, each with
+// a `frame` number and values for some of the table's keys. `animate <dir>` renders every frame
+// from the first keyframe to the last to <dir>/frame_0001.png and so on, for encoding to video.
+//
+// In each frame a keyed value is interpolated linearly between the keyframes around it that have
+// it, and held before the first and after the last. Numbers and arrays of numbers like positions
+// and colors are interpolated; anything else switches at the next keyframe. Keyframing only the
+// camera just moves it; animating anything else loads the scene again for every frame.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use tracerlib::Float;
+use tracerlib::log::{self, Level};
+use tracerlib::texture::TextureCache;
+
+use toml::Value;
+
+use super::{decode_camera, load_scene_cached, read_toml, render_to_file, Config};
+
+// Used when config.toml has no texture_budget_mb, as in batch
+const DEFAULT_TEXTURE_BUDGET_MB: Float = 1024.;
+
+pub fn animate(config: &Config, args: &[String]) {
+    let usage = "Usage: animate <dir> [<first frame> <last frame>]";
+    assert!(args.len() == 1 || args.len() == 3, "{}", usage);
+    let toml = read_toml(&format!("scenes/{}", config.scene));
+    let mut frames = Vec::new();
+    keyframe_frames(&toml, &mut frames);
+    assert!(!frames.is_empty(), "{} has no keyframes", config.scene);
+    let (first, last) = if args.len() == 3 {
+        (args[1].parse().expect(usage), args[2].parse().expect(usage))
+    } else {
+        (*frames.iter().min().unwrap(), *frames.iter().max().unwrap())
+    };
+
+    let mut camera_frames = Vec::new();
+    keyframe_frames(toml.lookup("scene.camera").unwrap(), &mut camera_frames);
+    let reload = frames.len() > camera_frames.len();
+    let budget = config.texture_budget_mb.unwrap_or(DEFAULT_TEXTURE_BUDGET_MB);
+    let cache = TextureCache::new((budget * 1024. * 1024.) as usize);
+    let mut scene = if reload { None } else { Some(load_scene_cached(&toml, None)) };
+
+    let dir = Path::new(&args[0]);
+    fs::create_dir_all(dir).unwrap();
+    let extension = Path::new(&config.out_file).extension().and_then(|e| e.to_str())
+        .unwrap_or("png");
+    for frame in first..last + 1 {
+        let _span = log::span(Level::Info, format!("frame {} of {}-{}", frame, first, last));
+        let toml = at_frame(&toml, frame as f64);
+        if reload {
+            scene = Some(load_scene_cached(&toml, Some(cache.clone())));
+        } else {
+            let scene = scene.as_mut().unwrap();
+            scene.set_camera(decode_camera(toml.lookup("scene.camera").unwrap()));
+        }
+        let file = dir.join(format!("frame_{:04}.{}", frame, extension));
+        render_to_file(config, scene.as_ref().unwrap(), file.to_str().unwrap(), |_, _| {});
+        info!("Wrote {}", file.display());
+    }
+}
+
+// The frames of all keyframes in `value`
+fn keyframe_frames(value: &Value, frames: &mut Vec<i64>) {
+    match *value {
+        Value::Table(ref table) => {
+            for (key, value) in table.iter() {
+                if key == "keyframe" {
+                    frames.extend(value.as_slice().unwrap().iter().map(frame_of));
+                } else {
+                    keyframe_frames(value, frames);
+                }
+            }
+        }
+        Value::Array(ref values) => {
+            for value in values.iter() {
+                keyframe_frames(value, frames);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn frame_of(keyframe: &Value) -> i64 {
+    keyframe.lookup("frame").and_then(Value::as_integer).expect("Keyframes need a frame number")
+}
+
+// `value` as it is at `frame`, without keyframes
+fn at_frame(value: &Value, frame: f64) -> Value {
+    match *value {
+        Value::Table(ref table) => {
+            let mut result: BTreeMap<String, Value> = table.iter()
+                .filter(|&(key, _)| key != "keyframe")
+                .map(|(key, value)| (key.clone(), at_frame(value, frame)))
+                .collect();
+            if let Some(keyframes) = table.get("keyframe") {
+                let mut keyframes: Vec<&Value> = keyframes.as_slice().unwrap().iter().collect();
+                keyframes.sort_by_key(|&keyframe| frame_of(keyframe));
+                let keys: BTreeSet<&String> = keyframes.iter()
+                    .flat_map(|k| k.as_table().unwrap().keys())
+                    .filter(|&key| key != "frame").collect();
+                for key in keys {
+                    let keyed: Vec<(f64, &Value)> = keyframes.iter()
+                        .filter_map(|k| k.lookup(key).map(|v| (frame_of(k) as f64, v)))
+                        .collect();
+                    result.insert(key.clone(), keyed_value(&keyed, frame));
+                }
+            }
+            Value::Table(result)
+        }
+        Value::Array(ref values) => {
+            Value::Array(values.iter().map(|value| at_frame(value, frame)).collect())
+        }
+        ref value => value.clone(),
+    }
+}
+
+// Between the keyframes, sorted by frame, around `frame`
+fn keyed_value(keyed: &[(f64, &Value)], frame: f64) -> Value {
+    let next = keyed.iter().position(|&(f, _)| f >= frame).unwrap_or(keyed.len() - 1);
+    let (b_frame, b) = keyed[next];
+    if next == 0 || b_frame <= frame {
+        return b.clone();
+    }
+    let (a_frame, a) = keyed[next - 1];
+    interpolate(a, b, (frame - a_frame) / (b_frame - a_frame))
+}
+
+fn interpolate(a: &Value, b: &Value, t: f64) -> Value {
+    let number = |value: &Value| value.as_float().or(value.as_integer().map(|i| i as f64));
+    match (a, b) {
+        (&Value::Array(ref a), &Value::Array(ref b)) if a.len() == b.len() => {
+            Value::Array(a.iter().zip(b).map(|(a, b)| interpolate(a, b, t)).collect())
+        }
+        // Kept whole, for integer settings that don't change
+        (&Value::Integer(a), &Value::Integer(b)) if a == b => Value::Integer(a),
+        _ => {
+            match (number(a), number(b)) {
+                (Some(a), Some(b)) => Value::Float(a + (b - a) * t),
+                _ => a.clone(),
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+// Arbitrary output variables: images of what the camera rays hit first instead of the shaded
+// colors, written next to the rendered image for denoisers and compositing. They use the same
+// rays as the render, so their edges line up with it.
+
+use std::cmp;
+use std::str::FromStr;
+
+use {pixel_rays, Float, Scene, Vec3};
+use hdr::HdrImage;
+use log::{self, Level};
+
+use image::RgbImage;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aov {
+    // Distance from the camera, 0 where nothing is hit
+    Depth,
+    // World space shading normals
+    Normal,
+    // The fraction of light the surface reflects, diffuse plus specular, in 0..1
+    Albedo,
+    // Index of the object hit plus one (0 is no hit), from the pixel's first ray only, since
+    // averaging IDs would give the IDs of other objects
+    ObjectId,
+}
+
+impl FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "depth" => Ok(Aov::Depth),
+            "normal" => Ok(Aov::Normal),
+            "albedo" => Ok(Aov::Albedo),
+            "object_id" => Ok(Aov::ObjectId),
+            _ => Err(format!("Unknown AOV: {}", s)),
+        }
+    }
+}
+
+impl Aov {
+    // As used in file names
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object_id",
+        }
+    }
+}
+
+// The raw values of `aov`, averaged over the same samples x samples rays per pixel as
+// ray_trace_samples, so that a Radiance file of the image holds the values themselves
+pub fn ray_trace_aov(scene: &Scene, width: u32, height: u32, samples: u32, aov: Aov)
+                     -> HdrImage {
+    let _span = log::span(Level::Info, format!("{} pass {}x{}", aov.name(), width, height));
+    let zero = Vec3::new(0., 0., 0.);
+    let mut im = HdrImage::new(width, height);
+    for x in 0..width {
+        for y in 0..height {
+            let (mut sum, mut count, mut first) = (zero, 0, None);
+            pixel_rays(scene, &scene.camera, x, y, width, height, samples, |ray| {
+                let hit = scene.closest_hit(ray);
+                if first.is_none() {
+                    first = Some(hit.as_ref().map_or(0, |&(i, _)| i + 1));
+                }
+                if let Some((i, hit)) = hit {
+                    let material = scene.objects[i].material_at(&hit);
+                    sum = sum + match aov {
+                        Aov::Depth => Vec3::new(hit.dist, hit.dist, hit.dist),
+                        Aov::Normal => hit.normal,
+                        Aov::Albedo => material.albedo(&hit) + material.specular_albedo(&hit),
+                        Aov::ObjectId => zero,
+                    };
+                    count += 1;
+                }
+            });
+            let value = match aov {
+                // Edges would otherwise be pulled towards the camera by the rays that miss
+                Aov::Depth => sum / cmp::max(count, 1) as Float,
+                Aov::ObjectId => {
+                    let id = first.unwrap() as Float;
+                    Vec3::new(id, id, id)
+                }
+                _ => sum / (samples * samples) as Float,
+            };
+            im.put_pixel(x, y, value);
+        }
+    }
+    im
+}
+
+// An 8 bit image of a pass from ray_trace_aov for viewing: depth from white at the camera to
+// black at the far end of the hits, normals mapped from [-1, 1] to [0, 1], and object IDs as
+// red + 256 * green in 8 bit levels, which keeps them exact
+pub fn encode_aov(im: &HdrImage, aov: Aov) -> RgbImage {
+    let values = im.pixels();
+    // Against a high percentile rather than the maximum, like the depth debug mode
+    let mut depths: Vec<Float> = values.iter().map(|p| p.x).filter(|&d| d > 0.).collect();
+    depths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let far = depths.get(depths.len() * 95 / 100).cloned().unwrap_or(1.);
+
+    let mut encoded = HdrImage::new(im.width(), im.height());
+    for (pixel, &value) in encoded.pixels_mut().iter_mut().zip(values) {
+        *pixel = match aov {
+            Aov::Depth if value.x > 0. => {
+                let f = 1. - Float::min(value.x / far, 1.);
+                Vec3::new(f, f, f)
+            }
+            Aov::Depth => Vec3::new(0., 0., 0.),
+            Aov::Normal => (value + Vec3::new(1., 1., 1.)) * 0.5,
+            Aov::Albedo => value,
+            Aov::ObjectId => {
+                let id = value.x.round() as u32;
+                Vec3::new((id % 256) as Float, (id / 256 % 256) as Float, 0.) / 255.
+            }
+        };
+    }
+    encoded.to_rgb()
+}
@@ -0,0 +1,92 @@
+// Files that many materials or surfaces of a scene refer to, loaded once while the scene is. An
+// image texture is shared by every material naming its file, and a mesh by every mesh surface
+// with the same file, material and settings, each placing it by its own scale and position as
+// an instance of it. So a scene's memory and load time grow with the files it uses rather than
+// with how often it uses them. Every load of a scene starts over, e.g. after its files changed.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use tracerlib::surface::Surface;
+use tracerlib::texture::{CachedImageTexture, ImageTexture, Texture, TextureCache};
+
+pub struct Assets {
+    // Textures go through this instead when the scene or batch has a texture budget, since it
+    // shares them by file too
+    budget_cache: Option<Arc<Mutex<TextureCache>>>,
+    // By file and whether it's sRGB
+    textures: RefCell<HashMap<(String, bool), ImageTexture>>,
+    meshes: RefCell<HashMap<String, Arc<Box<Surface>>>>,
+    // The scene's memory budget in bytes, if it has one, and what the geometry loaded so far uses
+    memory_budget: Option<usize>,
+    memory: Cell<usize>,
+}
+
+impl Assets {
+    pub fn new(budget_cache: Option<Arc<Mutex<TextureCache>>>, memory_budget: Option<usize>)
+               -> Self {
+        Assets {
+            budget_cache: budget_cache,
+            textures: RefCell::new(HashMap::new()),
+            meshes: RefCell::new(HashMap::new()),
+            memory_budget: memory_budget,
+            memory: Cell::new(0),
+        }
+    }
+
+    // Counts the `bytes` of geometry `what` just loaded against the memory budget, failing once
+    // it's spent, so a scene too big for it stops loading there instead of after everything
+    pub fn charge(&self, what: &str, bytes: usize) -> Result<(), String> {
+        let memory = self.memory.get() + bytes;
+        self.memory.set(memory);
+        match self.memory_budget {
+            Some(budget) if memory > budget => {
+                Err(format!("Scene needs more than its memory budget of {:.1} KiB: {:.1} KiB of \
+                             geometry after loading the {}", budget as f64 / 1024.,
+                            memory as f64 / 1024., what))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // A color image, sRGB encoded
+    pub fn texture(&self, filename: &str) -> Box<Texture> {
+        self.image_texture(filename, true)
+    }
+
+    // An image of values rather than colors, like a normal or roughness map, taken as it is
+    pub fn linear_texture(&self, filename: &str) -> Box<Texture> {
+        self.image_texture(filename, false)
+    }
+
+    fn image_texture(&self, filename: &str, srgb: bool) -> Box<Texture> {
+        if let Some(ref cache) = self.budget_cache {
+            return Box::new(CachedImageTexture::new(filename, srgb, cache.clone()));
+        }
+        let mut textures = self.textures.borrow_mut();
+        let texture = textures.entry((filename.to_owned(), srgb)).or_insert_with(|| {
+            if srgb { ImageTexture::new(filename) } else { ImageTexture::linear(filename) }
+        });
+        Box::new(texture.clone())
+    }
+
+    // The mesh loaded for `key` before, or else the one `load` loads, which is charged as `what`
+    pub fn mesh<F>(&self, key: &str, what: &str, load: F) -> Result<Arc<Box<Surface>>, String>
+        where F: FnOnce() -> Result<Box<Surface>, String>
+    {
+        if let Some(mesh) = self.meshes.borrow().get(key) {
+            return Ok(mesh.clone());
+        }
+        let mesh = Arc::new(try!(load()));
+        try!(self.charge(what, mem::size_of_val(&**mesh) + mesh.heap_size()));
+        self.meshes.borrow_mut().insert(key.to_owned(), mesh.clone());
+        Ok(mesh)
+    }
+
+    // How many distinct textures and meshes were loaded
+    pub fn counts(&self) -> (usize, usize) {
+        (self.textures.borrow().len(), self.meshes.borrow().len())
+    }
+}
@@ -0,0 +1,76 @@
+// Lightmap baking. Instead of tracing camera rays, the lighting arriving at each texel of every
+// object's uv layout is computed directly, and the objects' lightmaps are packed side by side into
+// one atlas. The lightmaps hold only the light (ambient plus diffuse from the scene's lights,
+// with shadows), so engines multiply them with the surface color themselves.
+
+use std::cmp;
+
+use {ambient_light, light_color, Float, Scene, Vec3};
+use hdr::HdrImage;
+use log::{self, Level};
+
+use nalgebra::dot;
+
+pub struct Tile {
+    // Index of the object, see Scene::object
+    pub object: usize,
+    // Top left corner in the atlas
+    pub x: u32,
+    pub y: u32,
+}
+
+// Bakes every finite object into a `tile_size` square of the atlas through its uv layout, or
+// fails naming the first object whose layout covers none of the tile's texels. Objects that
+// extend forever, like planes, are left out. Tiles are laid out in rows of a roughly square grid
+pub fn bake_lightmaps(scene: &Scene, tile_size: u32) -> Result<(HdrImage, Vec<Tile>), String> {
+    let _span = log::span(Level::Info, "bake");
+    let objects: Vec<usize> = (0..scene.objects.len())
+        .filter(|&i| scene.objects[i].bounds().is_some())
+        .collect();
+    let columns = cmp::max(1, (objects.len() as Float).sqrt().ceil() as u32);
+    let rows = (objects.len() as u32 + columns - 1) / columns;
+    debug!("{} of {} objects are finite", objects.len(), scene.objects.len());
+
+    let mut atlas = HdrImage::new(columns * tile_size, rows * tile_size);
+    let mut tiles = Vec::new();
+    for (n, &object) in objects.iter().enumerate() {
+        let tile = Tile {
+            object: object,
+            x: n as u32 % columns * tile_size,
+            y: n as u32 / columns * tile_size,
+        };
+        if bake_tile(scene, object, tile_size, &tile, &mut atlas) == 0 {
+            return Err(format!("Object {} ({}) has no uv layout to bake",
+                               object, scene.objects[object].name()));
+        }
+        tiles.push(tile);
+    }
+    Ok((atlas, tiles))
+}
+
+// Returns the number of texels the object's uv layout covers
+fn bake_tile(scene: &Scene, object: usize, tile_size: u32, tile: &Tile, atlas: &mut HdrImage)
+             -> usize {
+    let surface = &scene.objects[object];
+    let mut covered = 0;
+    trace!("{} {} at {},{}", surface.name(), object, tile.x, tile.y);
+    for y in 0..tile_size {
+        for x in 0..tile_size {
+            // Sample the texel centers
+            let u = (x as Float + 0.5) / tile_size as Float;
+            let v = (y as Float + 0.5) / tile_size as Float;
+            let point = match surface.surface_point(u, v) {
+                Some(point) => point,
+                None => continue,
+            };
+
+            let ambient = ambient_light(scene, &point.normal);
+            let diffuse = light_color(scene, &point, |shadow_ray| {
+                Vec3::new(1., 1., 1.) * Float::max(0., dot(&point.normal, &shadow_ray.dir))
+            });
+            atlas.put_pixel(tile.x + x, tile.y + y, ambient + diffuse);
+            covered += 1;
+        }
+    }
+    covered
+}
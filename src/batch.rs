@@ -0,0 +1,77 @@
+// Rendering many scenes in one run, e.g. overnight or on a build server. Scenes are rendered one
+// after another with the settings from config.toml, sharing one texture cache so textures used by
+// several scenes are only loaded once. A scene that fails doesn't stop the batch; the summary at
+// the end lists which scenes rendered and which didn't.
+//
+// The scenes are either given on the command line, each written to <dir>/<scene name>.png, or
+// listed in a JSON manifest of {"scene": ..., "out_file": ...} objects.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+use std::time::Instant;
+
+use rustc_serialize::json::Json;
+
+use tracerlib::Float;
+use tracerlib::log::{self, Level};
+use tracerlib::texture::{self, TextureCache};
+
+use super::{load_scene_cached, read_toml, render_to_file, Config};
+
+// Used when config.toml has no texture_budget_mb
+const DEFAULT_TEXTURE_BUDGET_MB: Float = 1024.;
+
+pub fn batch(config: &Config, args: &[String]) {
+    let jobs = match args.len() {
+        0 => panic!("Usage: batch <manifest.json> | batch <dir> <scene>..."),
+        1 => read_manifest(&args[0]),
+        _ => {
+            fs::create_dir_all(&args[0]).unwrap();
+            args[1..].iter().map(|scene| {
+                let name = Path::new(scene).file_stem().unwrap().to_str().unwrap();
+                let out_file = Path::new(&args[0]).join(format!("{}.png", name));
+                (scene.clone(), out_file.to_str().unwrap().to_owned())
+            }).collect()
+        }
+    };
+
+    let budget = config.texture_budget_mb.unwrap_or(DEFAULT_TEXTURE_BUDGET_MB);
+    let cache = TextureCache::new((budget * 1024. * 1024.) as usize);
+    let mut results = Vec::new();
+    for (i, &(ref scene, ref out_file)) in jobs.iter().enumerate() {
+        let _span = log::span(Level::Info, format!("batch {}/{} {}", i + 1, jobs.len(), scene));
+        let start = Instant::now();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let scene = load_scene_cached(&read_toml(&format!("scenes/{}", scene)),
+                                          Some(cache.clone()));
+            render_to_file(config, &scene, out_file, |_, _| {});
+        }));
+        let elapsed = start.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        results.push((scene, out_file, result.is_ok(), secs));
+    }
+
+    let failed = results.iter().filter(|&&(_, _, ok, _)| !ok).count();
+    for &(scene, out_file, ok, secs) in results.iter() {
+        println!("{:6} {:8.2}s {} -> {}", if ok { "ok" } else { "FAILED" }, secs, scene,
+                 out_file);
+    }
+    println!("{} of {} scenes rendered, {:.1} MiB of textures cached", results.len() - failed,
+             results.len(), texture::lock(&cache).used() as f64 / (1024. * 1024.));
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+fn read_manifest(file: &str) -> Vec<(String, String)> {
+    let mut json = String::new();
+    File::open(file).unwrap().read_to_string(&mut json).unwrap();
+    let manifest = Json::from_str(&json).unwrap();
+    manifest.as_array().expect("Batch manifest must be an array").iter().map(|job| {
+        (job.find("scene").and_then(Json::as_string).unwrap().to_owned(),
+         job.find("out_file").and_then(Json::as_string).unwrap().to_owned())
+    }).collect()
+}
@@ -0,0 +1,138 @@
+// Timing renders of a few built-in scenes, so that a change's effect on speed can be measured
+// on any machine without setting up scenes for it. Each scene is rendered a few times and the
+// fastest run is reported, which is the least noisy estimate, with the rays traced per second
+// over all cores. The scenes stand for different loads: a Cornell box of large overlapping
+// planes with reflection and refraction, a grid of many small spheres that's mostly scene
+// traversal, and a subdivided mesh of thousands of triangles.
+
+use std::f64;
+use std::time::Instant;
+
+use tracerlib::{ray_trace_samples, Camera, Float, Scene, Vec3};
+use tracerlib::light::PointLight;
+use tracerlib::log::{self, Level};
+use tracerlib::material::Material;
+use tracerlib::mesh::TriangleMesh;
+use tracerlib::stats;
+use tracerlib::surface::{Plane, Sphere, Surface};
+use tracerlib::tiles::TileOrder;
+
+const SCENES: &'static [&'static str] = &["cornell", "spheres", "mesh"];
+const RUNS: u32 = 3;
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+// Per axis, for 4 rays per pixel
+const SAMPLES: u32 = 2;
+const MAX_DEPTH: u16 = 4;
+
+pub fn bench(args: &[String]) {
+    for name in args {
+        assert!(SCENES.contains(&&name[..]), "Unknown benchmark scene {}, expected one of {}",
+                name, SCENES.join(", "));
+    }
+    let names: Vec<&str> = if args.is_empty() {
+        SCENES.to_vec()
+    } else {
+        args.iter().map(|name| &name[..]).collect()
+    };
+
+    // Log messages from the renders would only get in the way of the results, unless asked for
+    if !log::enabled(Level::Debug) {
+        log::set_level(Level::Warn);
+    }
+    println!("{:<10} {:>12} {:>10} {:>12}", "scene", "rays", "seconds", "Mrays/s");
+    let mut total = 0.;
+    for name in names {
+        let scene = match name {
+            "cornell" => cornell_box(),
+            "spheres" => sphere_grid(30),
+            "mesh" => subdivided_mesh(),
+            _ => unreachable!(),
+        };
+        let (rays, seconds) = (0..RUNS).map(|_| time_render(&scene))
+            .fold((0, f64::INFINITY), |best, run| if run.1 < best.1 { run } else { best });
+        println!("{:<10} {:>12} {:>10.3} {:>12.2}", name, rays, seconds,
+                 rays as f64 / seconds / 1e6);
+        total += seconds;
+    }
+    println!("{:<10} {:>12} {:>10.3}", "total", "", total);
+}
+
+// The number of rays traced rendering `scene`, and how long it took in seconds
+fn time_render(scene: &Scene) -> (u64, f64) {
+    stats::take_total_rays();
+    let start = Instant::now();
+    ray_trace_samples(scene, WIDTH, HEIGHT, MAX_DEPTH, SAMPLES, TileOrder::Scanline, |_, _| {});
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    (stats::take_total_rays(), seconds)
+}
+
+fn matte(color: Vec3) -> Material {
+    Material::new(color, 0.9, 0., 0., 0., None, None, None)
+}
+
+fn white_light(pos: Vec3) -> PointLight {
+    PointLight::new(pos, Vec3::new(1., 1., 1.), 1.)
+}
+
+// A box open towards the camera with a red wall on the left and a green one on the right, a
+// mirror ball and a glass ball
+fn cornell_box() -> Scene {
+    let (white, red, green) = (Vec3::new(0.86, 0.86, 0.86), Vec3::new(0.78, 0.16, 0.16),
+                               Vec3::new(0.16, 0.78, 0.16));
+    let walls = [(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), white),
+                 (Vec3::new(0., 2., 0.), Vec3::new(0., -1., 0.), white),
+                 (Vec3::new(0., 0., 2.), Vec3::new(0., 0., -1.), white),
+                 (Vec3::new(-1., 0., 0.), Vec3::new(1., 0., 0.), red),
+                 (Vec3::new(1., 0., 0.), Vec3::new(-1., 0., 0.), green)];
+    let mut objects: Vec<Box<Surface>> = walls.iter().map(|&(point, normal, color)| {
+        Box::new(Plane::new(point, normal, matte(color))) as Box<Surface>
+    }).collect();
+    let mirror = Material::new(white, 0.1, 0.5, 100., 0.9, None, None, None);
+    let glass = Material::new(white, 0.1, 0.5, 100., 0., None, None, None)
+        .with_transparency(0.9, 1.5);
+    objects.push(Box::new(Sphere::new(Vec3::new(-0.45, 0.35, 1.3), 0.35, mirror)));
+    objects.push(Box::new(Sphere::new(Vec3::new(0.45, 0.35, 0.8), 0.35, glass)));
+
+    let camera = Camera::from_lookat(Vec3::new(0., 1., -2.5), Vec3::new(0., 1., 0.),
+                                     Vec3::new(0., 1., 0.));
+    Scene::new(objects, vec![white_light(Vec3::new(0., 1.9, 1.))], 0.1,
+               Vec3::new(1., 1., 1.), camera)
+}
+
+// A grid of n x n spheres over a floor
+fn sphere_grid(n: u32) -> Scene {
+    let mut objects = Vec::new();
+    for i in 0..n {
+        for j in 0..n {
+            let pos = Vec3::new(i as Float - n as Float / 2., 0.4, j as Float);
+            let color = Vec3::new(0.78, 0.2 + 0.58 * (i % 2) as Float, 0.2);
+            objects.push(Box::new(Sphere::new(pos, 0.4, matte(color))) as Box<Surface>);
+        }
+    }
+    objects.push(Box::new(Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.),
+                                     matte(Vec3::new(0.78, 0.78, 0.78)))));
+
+    let camera = Camera::from_lookat(Vec3::new(0., 4., -6.), Vec3::new(0., 0., n as Float / 2.),
+                                     Vec3::new(0., 1., 0.));
+    Scene::new(objects, vec![white_light(Vec3::new(2., 5., -3.))], 0.1,
+               Vec3::new(1., 1., 1.), camera)
+}
+
+// The cube in resources/ smoothed into a ball of about 12k triangles, over a floor
+fn subdivided_mesh() -> Scene {
+    let material = Material::new(Vec3::new(0.7, 0.7, 0.86), 0.7, 0.3, 40., 0.2, None, None,
+                                 None);
+    let mesh = TriangleMesh::load_subdivided("resources/cube.obj", material, 5)
+        .transformed(2.5, Vec3::new(0., 0., 0.));
+    let objects: Vec<Box<Surface>> = vec![
+        Box::new(mesh),
+        Box::new(Plane::new(Vec3::new(0., -1.25, 0.), Vec3::new(0., 1., 0.),
+                            matte(Vec3::new(0.78, 0.78, 0.78)))),
+    ];
+    let camera = Camera::from_lookat(Vec3::new(1.5, 1.5, -3.), Vec3::new(0., 0., 0.),
+                                     Vec3::new(0., 1., 0.));
+    Scene::new(objects, vec![white_light(Vec3::new(2., 4., -2.))], 0.1,
+               Vec3::new(1., 1., 1.), camera)
+}
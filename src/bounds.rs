@@ -0,0 +1,89 @@
+use {float, Float, Vec3};
+use ray::Ray;
+
+// Axis aligned bounding box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Aabb { min: min, max: max }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(Vec3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y),
+                            self.min.z.min(other.min.z)),
+                  Vec3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y),
+                            self.max.z.max(other.max.z)))
+    }
+
+    pub fn size(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> Float {
+        let size = self.size();
+        2. * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+}
+
+// Whether the ray enters the box before `limit`
+pub fn hits_box(bounds: &Aabb, ray: &Ray, inv_dir: &Vec3, limit: Float) -> bool {
+    slabs(bounds, ray, inv_dir, limit, 1.)
+}
+
+// Like hits_box, but never misses a box the ray grazes because of rounding, by widening the
+// distance to each slab's far side by more than its error. Watertight triangle tests need this
+// to keep their hits on a box's faces, e.g. along the shared edge of two flat triangles
+pub fn hits_box_robust(bounds: &Aabb, ray: &Ray, inv_dir: &Vec3, limit: Float) -> bool {
+    // 1 + 2 gamma(3) of Ize's robust BVH traversal, for the three roundings of each distance
+    let epsilon = float::EPSILON / 2.;
+    let gamma = 3. * epsilon / (1. - 3. * epsilon);
+    slabs(bounds, ray, inv_dir, limit, 1. + 2. * gamma)
+}
+
+fn slabs(bounds: &Aabb, ray: &Ray, inv_dir: &Vec3, limit: Float, far_scale: Float) -> bool {
+    let (mut near, mut far) = (0., limit);
+    for axis in 0..3 {
+        let t0 = (bounds.min[axis] - ray.origin[axis]) * inv_dir[axis];
+        let t1 = (bounds.max[axis] - ray.origin[axis]) * inv_dir[axis];
+        let (t0, t1) = if t0 < t1 { (t0, t1 * far_scale) } else { (t1, t0 * far_scale) };
+        // NaN from 0 * infinity, for a ray running within a face of the box, leaves the
+        // interval as it is
+        if t0 > near {
+            near = t0;
+        }
+        if t1 < far {
+            far = t1;
+        }
+        if near > far {
+            return false;
+        }
+    }
+    true
+}
+
+// The distances along the ray between `near` and `far` where it's inside the box, if any
+pub fn box_interval(bounds: &Aabb, ray: &Ray, near: Float, far: Float) -> Option<(Float, Float)> {
+    let (mut near, mut far) = (near, far);
+    for axis in 0..3 {
+        let inv_dir = 1. / ray.dir[axis];
+        let t0 = (bounds.min[axis] - ray.origin[axis]) * inv_dir;
+        let t1 = (bounds.max[axis] - ray.origin[axis]) * inv_dir;
+        let (t0, t1) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+        // As in hits_box
+        if t0 > near {
+            near = t0;
+        }
+        if t1 < far {
+            far = t1;
+        }
+        if near > far {
+            return None;
+        }
+    }
+    Some((near, far))
+}
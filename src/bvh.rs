@@ -0,0 +1,112 @@
+// Where to split the nodes of bounding volume hierarchies, those of meshes over their triangles
+// and the scene's over its objects. Nodes are split by the surface area heuristic (SAH): the
+// chance of a ray that hits a node also hitting a child is about the ratio of their surface
+// areas, so the expected cost of a split is the cost of visiting the node plus each child's
+// items weighted by that ratio. The items' centers are sorted into bins along each axis, and the
+// cheapest of the boundaries between bins wins, unless testing the items as a leaf is cheaper.
+
+use std::cmp;
+
+use {Float, Vec3};
+use bounds::Aabb;
+
+// Bins per axis that the split candidates lie between
+const BINS: usize = 16;
+
+// The defaults for a scene's objects: up to 2 per leaf, and a node costs about an eighth of
+// testing an object, since most objects cost more to test than a box
+pub const OBJECT_SETTINGS: BvhSettings = BvhSettings { max_leaf_size: 2, traversal_cost: 0.125 };
+// And for a mesh's triangles: up to a pack of them per leaf, and a node costs about as much as
+// testing one triangle
+pub const TRIANGLE_SETTINGS: BvhSettings = BvhSettings { max_leaf_size: 4, traversal_cost: 1. };
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BvhSettings {
+    // Leaves hold no more items than this, unless all their centers coincide
+    pub max_leaf_size: usize,
+    // The cost of visiting a node, relative to testing a ray against one item
+    pub traversal_cost: Float,
+}
+
+impl BvhSettings {
+    pub fn new(max_leaf_size: usize, traversal_cost: Float) -> Self {
+        assert!(max_leaf_size > 0, "BVH leaves need room for at least one item");
+        assert!(traversal_cost >= 0., "BVH traversal cost can't be negative");
+        BvhSettings { max_leaf_size: max_leaf_size, traversal_cost: traversal_cost }
+    }
+}
+
+// How to split the items with `bounds` and `centers` between two children: the axis to sort them
+// along by their centers, and how many of them go to the first child. None if they should be a
+// leaf
+pub fn split(bounds: &[Aabb], centers: &[Vec3], settings: &BvhSettings) -> Option<(usize, usize)> {
+    let count = bounds.len();
+    if count <= 1 {
+        return None;
+    }
+    let extent = centers.iter().skip(1)
+        .fold(Aabb::new(centers[0], centers[0]), |all, c| all.union(&Aabb::new(*c, *c)));
+
+    // The cheapest boundary, as its cost without the node's area to divide by, the axis and the
+    // items before it
+    let mut best: Option<(Float, usize, usize)> = None;
+    for axis in 0..3 {
+        let (low, size) = (extent.min[axis], extent.size()[axis]);
+        if !(size > 0.) {
+            continue;
+        }
+        let mut bins: Vec<(Option<Aabb>, usize)> = vec![(None, 0); BINS];
+        for (b, c) in bounds.iter().zip(centers.iter()) {
+            let bin = cmp::min(((c[axis] - low) / size * BINS as Float) as usize, BINS - 1);
+            bins[bin] = (union(bins[bin].0, Some(*b)), bins[bin].1 + 1);
+        }
+
+        // The area and count of the items before each boundary, then those after it
+        let mut before = Vec::with_capacity(BINS - 1);
+        let (mut all, mut n): (Option<Aabb>, usize) = (None, 0);
+        for &(b, k) in bins[..BINS - 1].iter() {
+            all = union(all, b);
+            n += k;
+            before.push((all.map_or(0., |all| all.surface_area()), n));
+        }
+        let (mut all, mut n): (Option<Aabb>, usize) = (None, 0);
+        for boundary in (0..BINS - 1).rev() {
+            let (b, k) = bins[boundary + 1];
+            all = union(all, b);
+            n += k;
+            let (area_before, n_before) = before[boundary];
+            if n_before == 0 || n == 0 {
+                continue;
+            }
+            let area_after = all.map_or(0., |all| all.surface_area());
+            let cost = area_before * n_before as Float + area_after * n as Float;
+            if best.map_or(true, |(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, n_before));
+            }
+        }
+    }
+
+    let (cost, axis, first) = match best {
+        Some(best) => best,
+        // All centers coincide, so there's nothing to split
+        None => return None,
+    };
+    let area = bounds.iter().skip(1).fold(bounds[0], |all, b| all.union(b)).surface_area();
+    let split_cost = if area > 0. {
+        settings.traversal_cost + cost / area
+    } else {
+        settings.traversal_cost + count as Float
+    };
+    if count <= settings.max_leaf_size && split_cost >= count as Float {
+        None
+    } else {
+        Some((axis, first))
+    }
+}
+
+fn union(a: Option<Aabb>, b: Option<Aabb>) -> Option<Aabb> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.union(&b)),
+        (a, b) => a.or(b),
+    }
+}
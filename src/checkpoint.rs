@@ -0,0 +1,118 @@
+// `--checkpoint file` keeps saving the finished tiles of a render to a file, so that a long render
+// that crashed or was killed can be continued with `--resume file` instead of started over. The
+// resumed render keeps the saved tiles, renders the rest and goes on saving to the same file. It
+// needs the same config and scene; the size, samples and seed are checked.
+//
+// A checkpoint is a "ray-tracer checkpoint" line, a line of JSON with the render's settings and
+// the finished tiles, and then the whole image so far as in partial files (see merge.rs).
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::json::Json;
+
+use tracerlib::{RenderEvent, Vec3};
+use tracerlib::hdr::HdrImage;
+
+use super::Config;
+use merge::{pixel_bytes, pixels_from_bytes};
+
+const MAGIC: &'static str = "ray-tracer checkpoint";
+// Writing the whole image takes a while, and losing a minute of a long render is fine
+const WRITE_INTERVAL_SECS: u64 = 60;
+
+type Region = (u32, u32, u32, u32);
+
+pub struct Checkpoint {
+    file: Option<String>,
+    finished: Vec<Region>,
+    last_write: Instant,
+}
+
+impl Checkpoint {
+    // Does nothing without a file. `finished` are the tiles of a resumed render
+    pub fn new(file: Option<String>, finished: Vec<Region>) -> Self {
+        Checkpoint { file: file, finished: finished, last_write: Instant::now() }
+    }
+
+    pub fn event(&mut self, config: &Config, event: &RenderEvent) {
+        match *event {
+            RenderEvent::TileFinished { region, image, .. } => {
+                self.finished.push(region);
+                if self.last_write.elapsed() >= Duration::from_secs(WRITE_INTERVAL_SECS) {
+                    self.write(config, image);
+                }
+            }
+            RenderEvent::PassFinished { image } => self.write(config, image),
+            RenderEvent::TileStarted { .. } => {}
+        }
+    }
+
+    fn write(&mut self, config: &Config, image: &HdrImage) {
+        let file = match self.file {
+            Some(ref file) => Path::new(file),
+            None => return,
+        };
+        let mut header = settings(config);
+        let tiles = self.finished.iter().map(|&(x0, y0, x1, y1)| {
+            Json::Array([x0, y0, x1, y1].iter().map(|&c| Json::U64(c as u64)).collect())
+        }).collect();
+        header.insert("tiles".to_owned(), Json::Array(tiles));
+
+        let mut data = format!("{}\n{}\n", MAGIC, Json::Object(header)).into_bytes();
+        data.extend(pixel_bytes(image));
+        // Replaced in one step, so a crash while writing leaves the last checkpoint
+        let name = file.file_name().unwrap().to_str().unwrap();
+        let temp = file.with_file_name(format!(".{}", name));
+        File::create(&temp).unwrap().write_all(&data).unwrap();
+        fs::rename(&temp, file).unwrap();
+        debug!("Saved {} finished tiles to {}", self.finished.len(), file.display());
+        self.last_write = Instant::now();
+    }
+}
+
+// What a checkpoint has to match to be resumed with `config`
+fn settings(config: &Config) -> BTreeMap<String, Json> {
+    let mut settings = BTreeMap::new();
+    settings.insert("scene".to_owned(), Json::String(config.scene.clone()));
+    settings.insert("width".to_owned(), Json::U64(config.width as u64));
+    settings.insert("height".to_owned(), Json::U64(config.height as u64));
+    settings.insert("samples".to_owned(), Json::U64(config.samples as u64));
+    settings.insert("seed".to_owned(), Json::U64(config.seed as u64));
+    settings
+}
+
+// The image so far and its finished tiles
+pub fn read_checkpoint(config: &Config, file: &str) -> (HdrImage, Vec<Region>) {
+    let mut data = Vec::new();
+    File::open(file).unwrap().read_to_end(&mut data).unwrap();
+    let mut lines = data.splitn(3, |&b| b == b'\n');
+    assert!(lines.next() == Some(MAGIC.as_bytes()), "{} is not a checkpoint", file);
+    let header = String::from_utf8(lines.next().unwrap().to_vec()).unwrap();
+    let header = Json::from_str(&header).unwrap();
+    for (key, value) in settings(config) {
+        let saved = header.find(&key).unwrap();
+        assert!(*saved == value, "{} was saved with {} {}, not {}", file, key, saved, value);
+    }
+    let tiles: Vec<Region> = header.find("tiles").and_then(Json::as_array).unwrap().iter()
+        .map(|tile| {
+            let c: Vec<u32> = tile.as_array().unwrap().iter()
+                .map(|c| c.as_u64().unwrap() as u32).collect();
+            (c[0], c[1], c[2], c[3])
+        }).collect();
+
+    let pixels = pixels_from_bytes(lines.next().unwrap_or(&[]));
+    assert!(pixels.len() == (config.width * config.height * 4) as usize, "{} is truncated",
+            file);
+    let mut im = HdrImage::new(config.width, config.height);
+    for (n, pixel) in pixels.chunks(4).enumerate() {
+        let (x, y) = (n as u32 % config.width, n as u32 / config.width);
+        im.put_pixel(x, y, Vec3::new(pixel[0], pixel[1], pixel[2]));
+        im.put_alpha(x, y, pixel[3]);
+    }
+    info!("Resuming {} with {} finished tiles", file, tiles.len());
+    (im, tiles)
+}
@@ -0,0 +1,107 @@
+use std::str::FromStr;
+
+use {Float, Vec3};
+
+use nalgebra::clamp;
+
+// How the linear working space colors (where 1 is reference white) are encoded for display
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputTransform {
+    // Written as is, clipping everything brighter than white
+    Linear,
+    // The sRGB transfer curve, for typical monitors and the web
+    Srgb,
+    // The Rec. 709 camera curve, for HD video
+    Rec709,
+    // An approximation of the ACES filmic tone curve followed by sRGB encoding, which rolls off
+    // highlights instead of clipping them
+    Aces,
+    // Reinhard's c / (1 + c) followed by sRGB encoding, which never clips but flattens contrast
+    // more than ACES
+    Reinhard,
+}
+
+impl FromStr for OutputTransform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "linear" => Ok(OutputTransform::Linear),
+            "srgb" => Ok(OutputTransform::Srgb),
+            "rec709" => Ok(OutputTransform::Rec709),
+            "aces" => Ok(OutputTransform::Aces),
+            "reinhard" => Ok(OutputTransform::Reinhard),
+            _ => Err(format!("Unknown output transform: {}", s)),
+        }
+    }
+}
+
+impl OutputTransform {
+    // Maps a linear color to display encoded values in 0..1
+    pub fn apply(&self, color: Vec3) -> Vec3 {
+        let encode = |f: &Fn(Float) -> Float| Vec3::new(f(color.x), f(color.y), f(color.z));
+        match *self {
+            OutputTransform::Linear => encode(&|c| clamp(c, 0., 1.)),
+            OutputTransform::Srgb => encode(&|c| srgb(clamp(c, 0., 1.))),
+            OutputTransform::Rec709 => encode(&|c| rec709(clamp(c, 0., 1.))),
+            OutputTransform::Aces => encode(&|c| srgb(aces(c))),
+            OutputTransform::Reinhard => encode(&|c| srgb(reinhard(c))),
+        }
+    }
+
+    // The linear color that `apply` maps to `encoded`, clamped to 0..1. Display white comes back
+    // as the darkest color that reaches it
+    pub fn invert(&self, encoded: Vec3) -> Vec3 {
+        let decode = |f: &Fn(Float) -> Float| {
+            Vec3::new(f(clamp(encoded.x, 0., 1.)), f(clamp(encoded.y, 0., 1.)),
+                      f(clamp(encoded.z, 0., 1.)))
+        };
+        match *self {
+            OutputTransform::Linear => decode(&|e| e),
+            OutputTransform::Srgb => decode(&srgb_inverse),
+            OutputTransform::Rec709 => decode(&rec709_inverse),
+            OutputTransform::Aces => decode(&|e| aces_inverse(srgb_inverse(e))),
+            OutputTransform::Reinhard => decode(&|e| reinhard_inverse(srgb_inverse(e))),
+        }
+    }
+}
+
+// The sRGB transfer curve, from a linear value to an encoded one, both in 0..1
+pub fn srgb(c: Float) -> Float {
+    if c <= 0.0031308 { 12.92 * c } else { 1.055 * c.powf(1. / 2.4) - 0.055 }
+}
+
+pub fn srgb_inverse(e: Float) -> Float {
+    if e <= 0.04045 { e / 12.92 } else { ((e + 0.055) / 1.055).powf(2.4) }
+}
+
+fn rec709(c: Float) -> Float {
+    if c < 0.018 { 4.5 * c } else { 1.099 * c.powf(0.45) - 0.099 }
+}
+
+fn rec709_inverse(e: Float) -> Float {
+    if e < 0.081 { e / 4.5 } else { ((e + 0.099) / 1.099).powf(1. / 0.45) }
+}
+
+// Krzysztof Narkowicz's fit of the ACES reference rendering and output transforms
+fn aces(c: Float) -> Float {
+    let c = c.max(0.) * 0.6;
+    clamp((c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14), 0., 1.)
+}
+
+// The positive root of the fit solved for c, as a quadratic
+fn aces_inverse(e: Float) -> Float {
+    let (a, b, c) = (2.43 * e - 2.51, 0.59 * e - 0.03, 0.14 * e);
+    (-b - (b * b - 4. * a * c).sqrt()) / (2. * a) / 0.6
+}
+
+fn reinhard(c: Float) -> Float {
+    let c = c.max(0.);
+    c / (1. + c)
+}
+
+// Short of 1, which is infinitely bright
+fn reinhard_inverse(e: Float) -> Float {
+    let e = e.min(0.9999);
+    e / (1. - e)
+}
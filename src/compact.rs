@@ -0,0 +1,629 @@
+// Compact meshes, for scans too large to load: a mesh is converted once into a file holding its
+// vertices quantized and its hierarchy already built, which is then memory mapped instead of
+// read. Positions are 16 bits per axis across the mesh's bounding box and normals 16 bits per
+// component of the octahedral mapping, so a triangle with its share of vertices and nodes takes
+// around 30 bytes instead of the couple of hundred a TriangleMesh needs, and the operating
+// system pages in only the parts of the file rays reach, dropping them again when memory runs
+// short. The mesh is the quantized one: vertices move by up to half a step, a 65535th of the
+// box, and the hierarchy is built over the moved vertices so no hit falls outside it.
+//
+// Texture coordinates are kept as they are. Face materials, smoothing by subdivision and the
+// watertight test aren't, and compact meshes can't be sampled as area lights. Converting needs
+// the memory of loading the mesh once, e.g. on a bigger machine, and the file mustn't change
+// while it's mapped.
+//
+// The file, all little endian: b"RTMESH01", the counts of positions, normals, uvs, triangles and
+// nodes as u32s, 4 zero bytes, the box as f32 min and max corners, then the positions as 3 u16s,
+// padded to 4 bytes, the normals as 2 i16s, the uvs as 2 f32s, the position indices of each
+// triangle as 3 u32s, its normal and uv indices the same way if there are any (u32::MAX where a
+// triangle has none), and the nodes.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use {float, stats, Float, Vec3};
+use bounds::{hits_box, Aabb};
+use bvh::{self, BvhSettings};
+use material::Material;
+use mesh::Triangle;
+use ray::{Intersection, Ray};
+use surface::{self, Surface, UvGrid};
+
+use libc;
+
+use nalgebra::{cross, dot, Norm};
+
+const MAGIC: &'static [u8; 8] = b"RTMESH01";
+const HEADER_SIZE: usize = 64;
+// A node: its bounds as f32 min and max corners, then either the index of its second child and
+// 0, its first child coming right after it, or the first of its triangles and their count
+const NODE_SIZE: usize = 32;
+const NONE: u32 = 0xffffffff;
+
+// Leaves twice the size of TriangleMesh's, as nodes cost as much memory as a few triangles
+const SETTINGS: BvhSettings = BvhSettings { max_leaf_size: 8, traversal_cost: 1. };
+
+pub struct CompactMesh {
+    map: Mapping,
+    counts: Counts,
+    // Where the positions, normals, uvs, position indices, normal indices, uv indices and nodes
+    // start in the file
+    offsets: [usize; 7],
+    // The corner of the box positions are quantized across, and the size of one step along
+    // each axis
+    min: [f32; 3],
+    step: [f32; 3],
+    material: Material,
+    // See TriangleMesh
+    uv_grid: Mutex<Option<Arc<UvGrid>>>,
+}
+
+#[derive(Clone, Copy)]
+struct Counts {
+    positions: usize,
+    normals: usize,
+    uvs: usize,
+    triangles: usize,
+    nodes: usize,
+}
+
+impl CompactMesh {
+    // Maps a file written by write_compact
+    pub fn open(filename: &str, material: Material) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+        let file = try!(File::open(filename));
+        let len = try!(file.metadata()).len() as usize;
+        if len < HEADER_SIZE {
+            return Err(invalid("Not a compact mesh"));
+        }
+        let map = try!(Mapping::new(&file, len));
+        let (counts, min, step) = {
+            let bytes = map.bytes();
+            if &bytes[..8] != MAGIC {
+                return Err(invalid("Not a compact mesh"));
+            }
+            let count = |i: usize| u32_at(bytes, 8 + i * 4) as usize;
+            let counts = Counts {
+                positions: count(0),
+                normals: count(1),
+                uvs: count(2),
+                triangles: count(3),
+                nodes: count(4),
+            };
+            let corner = |offset: usize| {
+                [f32_at(bytes, offset), f32_at(bytes, offset + 4), f32_at(bytes, offset + 8)]
+            };
+            let (min, max) = (corner(32), corner(44));
+            (counts, min, quantization_step(&min, &max))
+        };
+        let offsets = section_offsets(&counts);
+        if counts.triangles == 0 || counts.nodes == 0 ||
+           offsets[6] + counts.nodes * NODE_SIZE != len {
+            return Err(invalid("Compact mesh is truncated"));
+        }
+        let mesh = CompactMesh {
+            map: map,
+            counts: counts,
+            offsets: offsets,
+            min: min,
+            step: step,
+            material: material,
+            uv_grid: Mutex::new(None),
+        };
+        try!(mesh.check().map_err(invalid));
+        debug!("Mapped {} ({} vertices, {} triangles, {:.1} MiB)", filename, counts.positions,
+               counts.triangles, len as f64 / (1024. * 1024.));
+        Ok(mesh)
+    }
+
+    // That every index in the file is in range, so a corrupt file is refused when it's opened
+    // rather than reading out of bounds while rendering. This reads the indices and nodes once,
+    // but not the vertices, which take most of the file
+    fn check(&self) -> Result<(), &'static str> {
+        let counts = self.counts;
+        for i in 0..counts.triangles {
+            if self.corners(3, i).iter().any(|&c| c >= counts.positions) {
+                return Err("Compact mesh has a triangle with a position out of range");
+            }
+            // Either all of a triangle's normals or uvs are there or none are
+            for &(section, count) in &[(4, counts.normals), (5, counts.uvs)] {
+                if count == 0 {
+                    continue;
+                }
+                let c = self.corners(section, i);
+                if !(c.iter().all(|&c| c == NONE as usize) || c.iter().all(|&c| c < count)) {
+                    return Err("Compact mesh has a triangle with a normal or uv out of range");
+                }
+            }
+        }
+        // Children come after their parent, so intersect's walk down them always ends
+        for i in 0..counts.nodes {
+            let (_, first, count) = self.node(i);
+            let in_range = if count == 0 {
+                first > i + 1 && first < counts.nodes
+            } else {
+                first + count <= counts.triangles
+            };
+            if !in_range {
+                return Err("Compact mesh has a node with children or triangles out of range");
+            }
+        }
+        Ok(())
+    }
+
+    fn position(&self, i: usize) -> Vec3 {
+        let bytes = self.map.bytes();
+        let at = self.offsets[0] + i * 6;
+        dequantize(&self.min, &self.step,
+                   [u16_at(bytes, at), u16_at(bytes, at + 2), u16_at(bytes, at + 4)])
+    }
+
+    fn normal(&self, i: usize) -> Vec3 {
+        let bytes = self.map.bytes();
+        let at = self.offsets[1] + i * 4;
+        decode_octahedral(u16_at(bytes, at) as i16, u16_at(bytes, at + 2) as i16)
+    }
+
+    fn uv(&self, i: usize) -> (Float, Float) {
+        let bytes = self.map.bytes();
+        let at = self.offsets[2] + i * 8;
+        (f32_at(bytes, at) as Float, f32_at(bytes, at + 4) as Float)
+    }
+
+    // The position, normal or uv indices of triangle `i`, from the section at `offsets[section]`
+    fn corners(&self, section: usize, i: usize) -> [usize; 3] {
+        let bytes = self.map.bytes();
+        let at = self.offsets[section] + i * 12;
+        [u32_at(bytes, at) as usize, u32_at(bytes, at + 4) as usize, u32_at(bytes, at + 8) as usize]
+    }
+
+    fn triangle_positions(&self, i: usize) -> [Vec3; 3] {
+        let c = self.corners(3, i);
+        [self.position(c[0]), self.position(c[1]), self.position(c[2])]
+    }
+
+    // The bounds of node `i`, and its second child or first triangle, and its triangle count
+    fn node(&self, i: usize) -> (Aabb, usize, usize) {
+        let bytes = self.map.bytes();
+        let at = self.offsets[6] + i * NODE_SIZE;
+        let corner = |at: usize| {
+            Vec3::new(f32_at(bytes, at) as Float, f32_at(bytes, at + 4) as Float,
+                      f32_at(bytes, at + 8) as Float)
+        };
+        (Aabb::new(corner(at), corner(at + 12)), u32_at(bytes, at + 24) as usize,
+         u32_at(bytes, at + 28) as usize)
+    }
+
+    fn uv_grid(&self) -> Arc<UvGrid> {
+        let mut grid = self.uv_grid.lock().unwrap();
+        if grid.is_none() {
+            let triangles = if self.counts.uvs > 0 {
+                (0..self.counts.triangles).filter_map(|i| {
+                    let t = self.corners(5, i);
+                    if t[0] == NONE as usize {
+                        None
+                    } else {
+                        Some((i, [self.uv(t[0]), self.uv(t[1]), self.uv(t[2])]))
+                    }
+                }).collect()
+            } else {
+                Vec::new()
+            };
+            *grid = Some(Arc::new(UvGrid::new(triangles)));
+        }
+        grid.as_ref().unwrap().clone()
+    }
+
+    fn hit(&self, i: usize, ray: &Ray, dist: Float, b1: Float, b2: Float) -> Intersection {
+        let b0 = 1. - b1 - b2;
+        let pos = ray.origin + ray.dir * dist;
+        let p = self.triangle_positions(i);
+        let face_normal = cross(&(p[1] - p[0]), &(p[2] - p[0])).normalize();
+        let normals = if self.counts.normals > 0 { self.corners(4, i) } else { [NONE as usize; 3] };
+        let (normal, terminator) = if normals[0] != NONE as usize {
+            let corners = [self.normal(normals[0]), self.normal(normals[1]),
+                           self.normal(normals[2])];
+            let smooth = corners[0] * b0 + corners[1] * b1 + corners[2] * b2;
+            let terminator = surface::terminator_offset(&pos, &p, &corners, &[b0, b1, b2]);
+            let normal = if smooth.norm_squared() > 0. { smooth.normalize() } else { face_normal };
+            (normal, Some(terminator))
+        } else {
+            (face_normal, None)
+        };
+        let uvs = if self.counts.uvs > 0 { self.corners(5, i) } else { [NONE as usize; 3] };
+        let corner_uvs = if uvs[0] != NONE as usize {
+            [self.uv(uvs[0]), self.uv(uvs[1]), self.uv(uvs[2])]
+        } else {
+            [(0., 0.), (1., 0.), (0., 1.)]
+        };
+        let (t0, t1, t2) = (corner_uvs[0], corner_uvs[1], corner_uvs[2]);
+        let (u, v) = (t0.0 * b0 + t1.0 * b1 + t2.0 * b2, t0.1 * b0 + t1.1 * b1 + t2.1 * b2);
+        let derivatives = surface::uv_derivatives(&p, &corner_uvs);
+        let edge = surface::edge_offset(&pos, &p);
+
+        let normal = if self.material.has_normal_map() {
+            self.material.apply_normal_map(&normal, &pos)
+        } else {
+            normal
+        };
+        let pos = if self.material.has_displacement_map() {
+            self.material.apply_displacement_map(&pos)
+        } else {
+            pos
+        };
+        let hit = Intersection { edge: Some(edge), terminator: terminator,
+                                 ..Intersection::new(pos, normal, dist, u, v) };
+        match derivatives {
+            Some((dpdu, dpdv)) => hit.with_uv_derivatives(dpdu, dpdv),
+            None => hit,
+        }
+    }
+}
+
+impl Surface for CompactMesh {
+    fn name(&self) -> &'static str {
+        "CompactMesh"
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.node(0).0)
+    }
+
+    // Like TriangleMesh's
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        self.uv_grid().find(u, v).map(|(i, b1, b2)| {
+            let b0 = 1. - b1 - b2;
+            let p = self.triangle_positions(i);
+            let face_normal = cross(&(p[1] - p[0]), &(p[2] - p[0])).normalize();
+            let n = if self.counts.normals > 0 { self.corners(4, i) } else { [NONE as usize; 3] };
+            let normal = if n[0] != NONE as usize {
+                let smooth = self.normal(n[0]) * b0 + self.normal(n[1]) * b1 +
+                             self.normal(n[2]) * b2;
+                if smooth.norm_squared() > 0. { smooth.normalize() } else { face_normal }
+            } else {
+                face_normal
+            };
+            Intersection::new(p[0] * b0 + p[1] * b1 + p[2] * b2, normal, 0., u, v)
+        })
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.counts.triangles
+    }
+
+    // The file is mapped rather than on the heap
+    fn heap_size(&self) -> usize {
+        0
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        let inv_dir = Vec3::new(1. / ray.dir.x, 1. / ray.dir.y, 1. / ray.dir.z);
+        let mut closest: Option<(Float, usize, Float, Float)> = None;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            stats::count_node_visit();
+            let (bounds, first, count) = self.node(index);
+            if !hits_box(&bounds, ray, &inv_dir, closest.map_or(float::INFINITY, |c| c.0)) {
+                continue;
+            }
+            if count == 0 {
+                stack.push(first);
+                stack.push(index + 1);
+                continue;
+            }
+            for i in first..first + count {
+                let p = self.triangle_positions(i);
+                if let Some((dist, b1, b2)) = intersect_triangle(ray, &p) {
+                    if closest.map_or(true, |c| dist < c.0) {
+                        closest = Some((dist, i, b1, b2));
+                    }
+                }
+            }
+        }
+        closest.map(|(dist, i, b1, b2)| self.hit(i, ray, dist, b1, b2))
+    }
+}
+
+// Writes the mesh of `positions`, `normals`, `uvs` and `triangles`, as TriangleMesh holds them,
+// as a compact mesh for CompactMesh::open
+pub fn write_compact<W: Write>(positions: &[Vec3], normals: &[Vec3], uvs: &[(Float, Float)],
+                               triangles: &[Triangle], out: &mut W)
+                               -> io::Result<()> {
+    assert!(!triangles.is_empty(), "A mesh needs at least one triangle");
+    assert!(positions.len() < NONE as usize && triangles.len() < NONE as usize,
+            "Too many vertices or triangles for a compact mesh");
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for p in positions {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(p[axis] as f32);
+            max[axis] = max[axis].max(p[axis] as f32);
+        }
+    }
+    let step = quantization_step(&min, &max);
+    let quantized: Vec<[u16; 3]> = positions.iter().map(|p| {
+        let q = |axis: usize| {
+            if step[axis] > 0. {
+                ((p[axis] as f32 - min[axis]) / step[axis]).round().max(0.).min(65535.) as u16
+            } else {
+                0
+            }
+        };
+        [q(0), q(1), q(2)]
+    }).collect();
+
+    // The hierarchy over the quantized triangles
+    let moved: Vec<Vec3> = quantized.iter().map(|&q| dequantize(&min, &step, q)).collect();
+    let mut builder = Builder {
+        positions: &moved,
+        triangles: triangles.to_vec(),
+        nodes: Vec::new(),
+    };
+    let count = triangles.len();
+    builder.build(0, count);
+    let Builder { triangles, nodes, .. } = builder;
+
+    let counts = Counts {
+        positions: positions.len(),
+        normals: normals.len(),
+        uvs: uvs.len(),
+        triangles: triangles.len(),
+        nodes: nodes.len(),
+    };
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(MAGIC);
+    for &n in &[counts.positions, counts.normals, counts.uvs, counts.triangles, counts.nodes, 0] {
+        push_u32(&mut header, n as u32);
+    }
+    for &corner in &[min, max] {
+        for &value in &corner {
+            push_f32(&mut header, value);
+        }
+    }
+    header.resize(HEADER_SIZE, 0);
+    try!(out.write_all(&header));
+
+    let mut section = Vec::with_capacity(quantized.len() * 6 + 2);
+    for q in &quantized {
+        for &value in q {
+            push_u16(&mut section, value);
+        }
+    }
+    if section.len() % 4 != 0 {
+        push_u16(&mut section, 0);
+    }
+    try!(out.write_all(&section));
+
+    section.clear();
+    for n in normals {
+        let (x, y) = encode_octahedral(n);
+        push_u16(&mut section, x as u16);
+        push_u16(&mut section, y as u16);
+    }
+    for &(u, v) in uvs {
+        push_f32(&mut section, u as f32);
+        push_f32(&mut section, v as f32);
+    }
+    try!(out.write_all(&section));
+
+    try!(write_indices(out, &triangles, &|t| Some(t.positions)));
+    if counts.normals > 0 {
+        try!(write_indices(out, &triangles, &|t| t.normals));
+    }
+    if counts.uvs > 0 {
+        try!(write_indices(out, &triangles, &|t| t.uvs));
+    }
+
+    section.clear();
+    for node in &nodes {
+        let (min, max) = (node.bounds.min, node.bounds.max);
+        for &value in &[min.x, min.y, min.z, max.x, max.y, max.z] {
+            push_f32(&mut section, value as f32);
+        }
+        push_u32(&mut section, node.first as u32);
+        push_u32(&mut section, node.count as u32);
+    }
+    out.write_all(&section)
+}
+
+// The `corners` of each triangle as u32s, NONE for all three if it has none
+fn write_indices<W: Write>(out: &mut W, triangles: &[Triangle],
+                           corners: &Fn(&Triangle) -> Option<[usize; 3]>)
+                           -> io::Result<()> {
+    let mut section = Vec::with_capacity(triangles.len() * 12);
+    for triangle in triangles {
+        for &i in &corners(triangle).unwrap_or([NONE as usize; 3]) {
+            push_u32(&mut section, i as u32);
+        }
+    }
+    out.write_all(&section)
+}
+
+struct Node {
+    bounds: Aabb,
+    first: usize,
+    count: usize,
+}
+
+// Builds the hierarchy like TriangleMesh does, sorting `triangles` into the order of its leaves
+struct Builder<'a> {
+    positions: &'a [Vec3],
+    triangles: Vec<Triangle>,
+    nodes: Vec<Node>,
+}
+
+impl<'a> Builder<'a> {
+    fn build(&mut self, first: usize, count: usize) {
+        let positions = self.positions;
+        let corners = |t: &Triangle| {
+            (positions[t.positions[0]], positions[t.positions[1]], positions[t.positions[2]])
+        };
+        let bounds: Vec<Aabb> = self.triangles[first..first + count].iter().map(|t| {
+            let (a, b, c) = corners(t);
+            Aabb::new(a, a).union(&Aabb::new(b, b)).union(&Aabb::new(c, c))
+        }).collect();
+        let all = bounds.iter().skip(1).fold(bounds[0], |all, b| all.union(b));
+        let index = self.nodes.len();
+        self.nodes.push(Node { bounds: all, first: first, count: count });
+
+        let centroids: Vec<Vec3> = self.triangles[first..first + count].iter()
+            .map(|t| {
+                let (a, b, c) = corners(t);
+                (a + b + c) / 3.
+            })
+            .collect();
+        let (axis, half) = match bvh::split(&bounds, &centroids, &SETTINGS) {
+            Some(split) => split,
+            None => return,
+        };
+        let key = |t: &Triangle| {
+            let (a, b, c) = corners(t);
+            (a + b + c)[axis]
+        };
+        self.triangles[first..first + count].sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+
+        self.nodes[index].count = 0;
+        self.build(first, half);
+        self.nodes[index].first = self.nodes.len();
+        self.build(first + half, count - half);
+    }
+}
+
+// A read only memory mapping of a whole file
+struct Mapping {
+    data: *mut libc::c_void,
+    len: usize,
+}
+
+// Nothing writes to the mapping
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(file: &File, len: usize) -> io::Result<Self> {
+        let data = unsafe {
+            libc::mmap(ptr::null_mut(), len as libc::size_t, libc::PROT_READ, libc::MAP_PRIVATE,
+                       file.as_raw_fd(), 0)
+        };
+        if data == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Rays jump around the file, so reading ahead of them would only fill memory
+        unsafe { libc::madvise(data, len as libc::size_t, libc::MADV_RANDOM) };
+        Ok(Mapping { data: data, len: len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.data as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.data, self.len as libc::size_t) };
+    }
+}
+
+// Where each section starts, see the layout at the top
+fn section_offsets(counts: &Counts) -> [usize; 7] {
+    let positions = HEADER_SIZE;
+    let normals = positions + (counts.positions * 6 + 3) / 4 * 4;
+    let uvs = normals + counts.normals * 4;
+    let position_indices = uvs + counts.uvs * 8;
+    let normal_indices = position_indices + counts.triangles * 12;
+    let uv_indices = normal_indices + if counts.normals > 0 { counts.triangles * 12 } else { 0 };
+    let nodes = uv_indices + if counts.uvs > 0 { counts.triangles * 12 } else { 0 };
+    [positions, normals, uvs, position_indices, normal_indices, uv_indices, nodes]
+}
+
+fn quantization_step(min: &[f32; 3], max: &[f32; 3]) -> [f32; 3] {
+    let step = |axis: usize| (max[axis] - min[axis]).max(0.) / 65535.;
+    [step(0), step(1), step(2)]
+}
+
+// Worked out in f32 both when writing and when reading, so the hierarchy always holds the
+// positions rays are tested against
+fn dequantize(min: &[f32; 3], step: &[f32; 3], q: [u16; 3]) -> Vec3 {
+    Vec3::new((min[0] + q[0] as f32 * step[0]) as Float,
+              (min[1] + q[1] as f32 * step[1]) as Float,
+              (min[2] + q[2] as f32 * step[2]) as Float)
+}
+
+// The unit normal `n` folded onto the octahedron |x| + |y| + |z| = 1 and flattened onto its
+// square, the lower half folded over the corners
+fn encode_octahedral(n: &Vec3) -> (i16, i16) {
+    let sum = n.x.abs() + n.y.abs() + n.z.abs();
+    if sum == 0. {
+        return (0, 0);
+    }
+    let (x, y) = (n.x / sum, n.y / sum);
+    let (x, y) = if n.z < 0. {
+        ((1. - y.abs()) * sign(x), (1. - x.abs()) * sign(y))
+    } else {
+        (x, y)
+    };
+    let to_i16 = |f: Float| (f.max(-1.).min(1.) * 32767.).round() as i16;
+    (to_i16(x), to_i16(y))
+}
+
+fn decode_octahedral(x: i16, y: i16) -> Vec3 {
+    let (x, y) = (x as Float / 32767., y as Float / 32767.);
+    let z = 1. - x.abs() - y.abs();
+    let (x, y) = if z < 0. { ((1. - y.abs()) * sign(x), (1. - x.abs()) * sign(y)) } else { (x, y) };
+    let n = Vec3::new(x, y, z);
+    if n.norm_squared() > 0. { n.normalize() } else { n }
+}
+
+fn sign(f: Float) -> Float {
+    if f < 0. { -1. } else { 1. }
+}
+
+// Möller-Trumbore: the distance to the hit on the triangle, and its barycentric coordinates
+fn intersect_triangle(ray: &Ray, p: &[Vec3; 3]) -> Option<(Float, Float, Float)> {
+    let (edge1, edge2) = (p[1] - p[0], p[2] - p[0]);
+    let pvec = cross(&ray.dir, &edge2);
+    let det = dot(&edge1, &pvec);
+    if det.abs() < float::EPSILON * edge1.norm() * edge2.norm() {
+        return None;
+    }
+    let tvec = ray.origin - p[0];
+    let b1 = dot(&tvec, &pvec) / det;
+    let qvec = cross(&tvec, &edge1);
+    let b2 = dot(&ray.dir, &qvec) / det;
+    let dist = dot(&edge2, &qvec) / det;
+    if b1 >= 0. && b2 >= 0. && b1 + b2 <= 1. && dist > 0. { Some((dist, b1, b2)) } else { None }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    bytes[at] as u16 | (bytes[at + 1] as u16) << 8
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u16_at(bytes, at) as u32 | (u16_at(bytes, at + 2) as u32) << 16
+}
+
+fn f32_at(bytes: &[u8], at: usize) -> f32 {
+    f32::from_bits(u32_at(bytes, at))
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.push(value as u8);
+    out.push((value >> 8) as u8);
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    push_u16(out, value as u16);
+    push_u16(out, (value >> 16) as u16);
+}
+
+fn push_f32(out: &mut Vec<u8>, value: f32) {
+    push_u32(out, value.to_bits());
+}
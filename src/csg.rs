@@ -0,0 +1,153 @@
+// Constructive solid geometry: the union, intersection or difference of two surfaces, e.g. a
+// sphere with a hole drilled through it. The hits of both along the ray are walked in order,
+// tracking whether the ray is inside each, and the first one where it enters or leaves the
+// combined solid is returned. This assumes both surfaces are closed, so that a ray is inside
+// exactly when its next hit faces away from it.
+
+use std::mem;
+use std::str::FromStr;
+
+use {float, Float, Vec3};
+use bounds::Aabb;
+use material::Material;
+use ray::{Intersection, Ray};
+use surface::Surface;
+
+use nalgebra::dot;
+
+// Hits of the two surfaces looked at before giving up, e.g. for meshes with many thin layers
+const MAX_HITS: u32 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsgOp {
+    // Inside either surface
+    Union,
+    // Inside both surfaces
+    Intersection,
+    // Inside the first surface but not the second
+    Difference,
+}
+
+impl FromStr for CsgOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "union" => Ok(CsgOp::Union),
+            "intersection" => Ok(CsgOp::Intersection),
+            "difference" => Ok(CsgOp::Difference),
+            _ => Err(format!("Unknown CSG operation: {}", s)),
+        }
+    }
+}
+
+impl CsgOp {
+    fn inside(&self, in_a: bool, in_b: bool) -> bool {
+        match *self {
+            CsgOp::Union => in_a || in_b,
+            CsgOp::Intersection => in_a && in_b,
+            CsgOp::Difference => in_a && !in_b,
+        }
+    }
+}
+
+// Shaded with the material of the first surface throughout, so the walls of a hole cut with
+// Difference look like the rest of the object
+pub struct Csg {
+    op: CsgOp,
+    a: Box<Surface>,
+    b: Box<Surface>,
+}
+
+impl Csg {
+    pub fn new(op: CsgOp, a: Box<Surface>, b: Box<Surface>) -> Self {
+        Csg { op: op, a: a, b: b }
+    }
+}
+
+// The next hit of `surface` at least `start` along `ray`, with the distance from the ray's origin
+fn next_hit(surface: &Surface, ray: &Ray, start: Float) -> Option<Intersection> {
+    let offset = if start > 0. { start + float::EPSILON.sqrt() } else { 0. };
+    let ray = Ray::new(ray.origin + ray.dir * offset, ray.dir).with_time(ray.time);
+    surface.intersect(&ray).map(|hit| Intersection { dist: hit.dist + offset, ..hit })
+}
+
+fn leaving(hit: &Option<Intersection>, ray: &Ray) -> bool {
+    hit.as_ref().map_or(false, |hit| dot(&hit.normal, &ray.dir) > 0.)
+}
+
+impl Surface for Csg {
+    fn name(&self) -> &'static str {
+        "CSG"
+    }
+
+    fn material(&self) -> &Material {
+        self.a.material()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        match (self.op, self.a.bounds(), self.b.bounds()) {
+            (CsgOp::Union, Some(a), Some(b)) => Some(a.union(&b)),
+            (CsgOp::Union, _, _) => None,
+            (CsgOp::Intersection, Some(a), Some(b)) => {
+                Some(Aabb::new(Vec3::new(a.min.x.max(b.min.x), a.min.y.max(b.min.y),
+                                         a.min.z.max(b.min.z)),
+                               Vec3::new(a.max.x.min(b.max.x), a.max.y.min(b.max.y),
+                                         a.max.z.min(b.max.z))))
+            }
+            (CsgOp::Intersection, a, b) => a.or(b),
+            (CsgOp::Difference, a, _) => a,
+        }
+    }
+
+    fn surface_point(&self, _: Float, _: Float) -> Option<Intersection> {
+        None
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        let mut hit_a = next_hit(&*self.a, ray, 0.);
+        let mut hit_b = next_hit(&*self.b, ray, 0.);
+        let mut in_a = leaving(&hit_a, ray);
+        let mut in_b = leaving(&hit_b, ray);
+
+        for _ in 0..MAX_HITS {
+            let a_first = match (&hit_a, &hit_b) {
+                (&Some(ref a), &Some(ref b)) => a.dist <= b.dist,
+                (&Some(_), &None) => true,
+                (&None, &Some(_)) => false,
+                (&None, &None) => return None,
+            };
+            let inside = self.op.inside(in_a, in_b);
+            if a_first {
+                in_a = !in_a;
+                let hit = mem::replace(&mut hit_a, None).unwrap();
+                if self.op.inside(in_a, in_b) != inside {
+                    return Some(hit);
+                }
+                hit_a = next_hit(&*self.a, ray, hit.dist);
+            } else {
+                in_b = !in_b;
+                let hit = mem::replace(&mut hit_b, None).unwrap();
+                if self.op.inside(in_a, in_b) != inside {
+                    // The second surface is turned inside out where it cuts into the first
+                    return Some(if self.op == CsgOp::Difference {
+                        Intersection { normal: -hit.normal, ..hit }
+                    } else {
+                        hit
+                    });
+                }
+                hit_b = next_hit(&*self.b, ray, hit.dist);
+            }
+        }
+        None
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.a.triangle_count() + self.b.triangle_count()
+    }
+
+    fn heap_size(&self) -> usize {
+        mem::size_of_val(&*self.a) + self.a.heap_size() + mem::size_of_val(&*self.b) +
+            self.b.heap_size()
+    }
+}
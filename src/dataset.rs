@@ -0,0 +1,96 @@
+// Batch export of synthetic training data. Each sample is the scene seen from a random camera
+// orbiting the scene's objects, written as a color render plus depth, normal and segmentation
+// images, with manifest.json describing the cameras and what the segmentation ids mean.
+//
+// The camera poses come from a fixed seed, so exporting the same scene twice gives the same data.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+use rustc_serialize::json::Json;
+
+use tracerlib::{Camera, Float, Scene, Vec3};
+use tracerlib::float::consts::PI;
+use tracerlib::debug::{ray_trace_debug, DebugMode};
+use tracerlib::log::{self, Level};
+
+use nalgebra::Norm;
+
+use super::{render, vec3_json, Config};
+
+pub fn export(config: &Config, scene: &mut Scene, count: u32, out_dir: &str) {
+    assert!(config.debug_mode.is_none(), "--dataset can't be combined with a debug mode");
+    let _span = log::span(Level::Info, format!("dataset {}", out_dir));
+    fs::create_dir_all(out_dir).unwrap();
+
+    // Orbit the middle of the finite objects at about the distance of the original camera
+    let target = scene.stats().bounds.map_or(Vec3::new(0., 0., 0.), |b| (b.min + b.max) * 0.5);
+    let dist = (*scene.camera().pos() - target).norm();
+
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let mut samples = Vec::new();
+    for i in 0..count {
+        let azimuth = rng.gen_range(0., 2. * PI);
+        let elevation = rng.gen_range(5. as Float, 60.).to_radians();
+        let radius = dist * rng.gen_range(0.8, 1.2);
+        let pos = target + Vec3::new(azimuth.cos() * elevation.cos(), elevation.sin(),
+                                     azimuth.sin() * elevation.cos()) * radius;
+        let up = Vec3::new(0., 1., 0.);
+        scene.set_camera(Camera::from_lookat(pos, target, up));
+        info!("Sample {}/{}", i + 1, count);
+
+        let name = |kind: &str| format!("{:04}_{}.png", i, kind);
+        let save = |kind: &str, im: &::image::RgbImage| {
+            im.save(Path::new(out_dir).join(name(kind))).unwrap();
+        };
+        save("rgb", &render(config, scene, |_, _| {}).to_rgb());
+        // The label images are rendered at the output size directly, downsampling would blend
+        // the ids and normals of neighboring objects
+        for &(kind, mode) in [("depth", DebugMode::Depth), ("normals", DebugMode::Normals),
+                              ("segmentation", DebugMode::Segmentation)].iter() {
+            save(kind, &ray_trace_debug(scene, config.width, config.height,
+                                        config.reflection_depth, mode, |_, _| {}));
+        }
+
+        let mut sample = BTreeMap::new();
+        for kind in ["rgb", "depth", "normals", "segmentation"].iter() {
+            sample.insert(kind.to_string(), Json::String(name(kind)));
+        }
+        let mut camera = BTreeMap::new();
+        camera.insert("pos".to_owned(), vec3_json(&pos));
+        camera.insert("lookat".to_owned(), vec3_json(&target));
+        camera.insert("up".to_owned(), vec3_json(&up));
+        sample.insert("camera".to_owned(), Json::Object(camera));
+        samples.push(Json::Object(sample));
+    }
+
+    let mut objects = Vec::new();
+    for id in 0..scene.object_count() {
+        let mut object = BTreeMap::new();
+        object.insert("id".to_owned(), Json::U64(id as u64 + 1));
+        object.insert("type".to_owned(), Json::String(scene.object(id).name().to_owned()));
+        objects.push(Json::Object(object));
+    }
+
+    let mut manifest = BTreeMap::new();
+    manifest.insert("width".to_owned(), Json::U64(config.width as u64));
+    manifest.insert("height".to_owned(), Json::U64(config.height as u64));
+    manifest.insert("depth".to_owned(),
+                    Json::String("white is nearest, black the 95th percentile hit distance of \
+                                  each image or beyond".to_owned()));
+    manifest.insert("normals".to_owned(),
+                    Json::String("world space, mapped from [-1, 1] to [0, 255]".to_owned()));
+    manifest.insert("segmentation".to_owned(),
+                    Json::String("object id as red + 256 * green, 0 where nothing is hit"
+                                 .to_owned()));
+    manifest.insert("objects".to_owned(), Json::Array(objects));
+    manifest.insert("samples".to_owned(), Json::Array(samples));
+
+    let path = Path::new(out_dir).join("manifest.json");
+    let mut file = File::create(&path).unwrap();
+    writeln!(file, "{}", Json::Object(manifest).pretty()).unwrap();
+    info!("Wrote {} samples to {}", count, out_dir);
+}
@@ -0,0 +1,162 @@
+use std::str::FromStr;
+
+use {ambient_color, environment_color, float, light_color, stats, to_rgb, trace_ray, Float,
+     Scene, Vec3};
+use log::{self, Level};
+use ray::{Intersection, Ray};
+
+use image::RgbImage;
+
+use nalgebra::Norm;
+
+// False color renders for diagnosing why an image looks wrong
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugMode {
+    // World space normals, mapped from [-1, 1] to [0, 255]
+    Normals,
+    // Distance from the camera, white is nearest and black the far end of the hits in the image
+    Depth,
+    // Texture coordinates in the red and green channels, wrapped to [0, 1)
+    Uv,
+    // Only the ambient, diffuse or specular shading term, without reflections
+    Ambient,
+    Diffuse,
+    Specular,
+    // Number of intersection tests and BVH node visits done for the pixel's whole ray tree
+    // (including shadow and reflection rays), from blue for the cheapest pixels to red for the
+    // most expensive
+    Heatmap,
+    // Index of the object hit plus one (0 is no hit), as red + 256 * green
+    Segmentation,
+    // The shaded image with the edges of mesh triangles drawn over it in black, a pixel wide
+    Wireframe,
+}
+
+impl FromStr for DebugMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "normals" => Ok(DebugMode::Normals),
+            "depth" => Ok(DebugMode::Depth),
+            "uv" => Ok(DebugMode::Uv),
+            "ambient" => Ok(DebugMode::Ambient),
+            "diffuse" => Ok(DebugMode::Diffuse),
+            "specular" => Ok(DebugMode::Specular),
+            "heatmap" => Ok(DebugMode::Heatmap),
+            "segmentation" => Ok(DebugMode::Segmentation),
+            "wireframe" => Ok(DebugMode::Wireframe),
+            _ => Err(format!("Unknown debug mode: {}", s)),
+        }
+    }
+}
+
+pub fn ray_trace_debug<F>(scene: &Scene, width: u32, height: u32, max_depth: u16,
+                          mode: DebugMode, mut progress: F) -> RgbImage
+    where F: FnMut(u32, u32)
+{
+    let _span = log::span(Level::Info, format!("debug render {:?} {}x{}", mode, width, height));
+    let aspect_ratio = width as Float / height as Float;
+
+    // Depth and cost can only be normalized once all the pixels are known, so keep the raw values
+    let mut values = Vec::with_capacity((width * height) as usize);
+    for x in 0..width {
+        for y in 0..height {
+            let ray = scene.camera.get_ray(x, y, width, height, aspect_ratio, scene.seed);
+            if mode == DebugMode::Heatmap {
+                stats::take_intersection_tests();
+                stats::take_node_visits();
+                trace_ray(scene, &ray, 0, 0, max_depth);
+                let tests = (stats::take_intersection_tests() + stats::take_node_visits()) as Float;
+                values.push(Some(Vec3::new(tests, tests, tests)));
+                continue;
+            }
+            if mode == DebugMode::Segmentation {
+                let id = scene.closest_hit(&ray).map_or(0, |(i, _)| i + 1);
+                let levels = Vec3::new((id % 256) as Float, (id / 256 % 256) as Float, 0.);
+                values.push(Some(levels / 255.));
+                continue;
+            }
+            if mode == DebugMode::Wireframe {
+                let color = trace_ray(scene, &ray, 0, 0, max_depth);
+                let wire = scene.intersect(&ray).map_or(0., |(_, hit)| wire_coverage(&ray, &hit));
+                values.push(Some(color * (1. - wire)));
+                continue;
+            }
+
+            let value = scene.intersect(&ray).map(|(obj, hit)| {
+                let material = obj.material_at(&hit);
+                match mode {
+                    DebugMode::Normals => (hit.normal + Vec3::new(1., 1., 1.)) * 0.5,
+                    DebugMode::Depth => Vec3::new(hit.dist, hit.dist, hit.dist),
+                    DebugMode::Uv => Vec3::new(hit.u - hit.u.floor(), hit.v - hit.v.floor(), 0.),
+                    DebugMode::Ambient => ambient_color(scene, material, &hit),
+                    DebugMode::Diffuse => {
+                        let shade = |shadow_ray: &Ray| material.diffuse_color(shadow_ray, &hit);
+                        light_color(scene, &hit, &shade) + environment_color(scene, &hit, &shade)
+                    }
+                    DebugMode::Specular => {
+                        let shade = |shadow_ray: &Ray| {
+                            material.specular_color(shadow_ray, &ray, &hit)
+                        };
+                        light_color(scene, &hit, &shade) + environment_color(scene, &hit, &shade)
+                    }
+                    DebugMode::Heatmap | DebugMode::Segmentation | DebugMode::Wireframe => {
+                        unreachable!()
+                    }
+                }
+            });
+            values.push(value);
+        }
+        progress(x + 1, width);
+    }
+
+    // Normalize against a high percentile rather than the maximum, otherwise a single hit near
+    // the horizon of an infinite plane makes everything else white
+    let mut depths: Vec<Float> = values.iter().filter_map(|v| v.map(|depth| depth.x)).collect();
+    depths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let far = depths.get(depths.len() * 95 / 100).cloned().unwrap_or(1.);
+    let (min_tests, max_tests) = values.iter()
+        .filter_map(|v| v.map(|tests| tests.x))
+        .fold((float::INFINITY, 0.), |(min, max), n| (Float::min(min, n), Float::max(max, n)));
+
+    // Everything else is already a color
+    let mut im = RgbImage::new(width, height);
+    for (i, value) in values.into_iter().enumerate() {
+        let color = match value {
+            Some(depth) if mode == DebugMode::Depth => {
+                let f = 1. - Float::min(depth.x / far, 1.);
+                Vec3::new(f, f, f)
+            }
+            Some(tests) if mode == DebugMode::Heatmap => {
+                heat((tests.x - min_tests) / Float::max(max_tests - min_tests, 1.))
+            }
+            Some(color) => color,
+            None => Vec3::new(0., 0., 0.),
+        };
+        let i = i as u32;
+        im.put_pixel(i / height, i % height, to_rgb(color));
+    }
+    im
+}
+
+// How much of the pixel of `ray` a line along the nearest triangle edge covers at `hit`, fading
+// out a pixel from the edge on either side
+fn wire_coverage(ray: &Ray, hit: &Intersection) -> Float {
+    match (hit.edge, ray.differentials) {
+        (Some(edge), Some(differentials)) => {
+            (1. - edge.norm() / differentials.width(ray, hit)).max(0.)
+        }
+        _ => 0.,
+    }
+}
+
+// Maps [0, 1] to blue, cyan, green, yellow, red
+fn heat(t: Float) -> Vec3 {
+    let colors = [Vec3::new(0., 0., 1.), Vec3::new(0., 1., 1.), Vec3::new(0., 1., 0.),
+                  Vec3::new(1., 1., 0.), Vec3::new(1., 0., 0.)];
+    let t = t * (colors.len() - 1) as Float;
+    let i = Float::min(t, (colors.len() - 2) as Float) as usize;
+    let f = t - i as Float;
+    colors[i] * (1. - f) + colors[i + 1] * f
+}
@@ -0,0 +1,53 @@
+// Blue noise dithering for quantizing to 8 bits. Adding noise below one quantization step before
+// rounding trades the bands of smooth gradients like skies and soft shadows for grain, and blue
+// noise keeps that grain fine and even so it's hardly visible.
+
+use std::cmp;
+
+use Float;
+
+// Side of the square threshold mask, which is tiled over the image
+const SIZE: usize = 64;
+// Spread of the energy each point adds to its neighbors
+const SIGMA: Float = 1.9;
+
+// A SIZE x SIZE mask of thresholds in 0..1, each value once, arranged so that pixels with similar
+// thresholds are spread out evenly. Built by repeatedly ranking the pixel furthest from all
+// pixels ranked so far (the largest void of the void and cluster method)
+pub fn blue_noise_mask() -> Vec<Float> {
+    let n = SIZE * SIZE;
+    // Energy a point adds at each offset, wrapping around so the mask tiles seamlessly
+    let mut kernel = vec![0.; n];
+    for dy in 0..SIZE {
+        for dx in 0..SIZE {
+            let wrap = |d: usize| cmp::min(d, SIZE - d) as Float;
+            let (x, y) = (wrap(dx), wrap(dy));
+            kernel[dy * SIZE + dx] = (-(x * x + y * y) / (2. * SIGMA * SIGMA)).exp();
+        }
+    }
+
+    let mut energy = vec![0.; n];
+    let mut mask = vec![-1.; n];
+    for rank in 0..n {
+        let mut void = 0;
+        for i in 0..n {
+            if mask[i] < 0. && (mask[void] >= 0. || energy[i] < energy[void]) {
+                void = i;
+            }
+        }
+        mask[void] = (rank as Float + 0.5) / n as Float;
+        let (vx, vy) = (void % SIZE, void / SIZE);
+        for y in 0..SIZE {
+            let dy = (y + SIZE - vy) % SIZE;
+            for x in 0..SIZE {
+                energy[y * SIZE + x] += kernel[dy * SIZE + (x + SIZE - vx) % SIZE];
+            }
+        }
+    }
+    mask
+}
+
+// The mask's threshold for pixel (x, y)
+pub fn threshold(mask: &[Float], x: u32, y: u32) -> Float {
+    mask[(y as usize % SIZE) * SIZE + x as usize % SIZE]
+}
@@ -0,0 +1,344 @@
+// Records the whole ray tree of a single pixel, for debugging shading and reflection bugs. This
+// mirrors trace_ray, so keep the two in sync.
+
+use std::fmt;
+
+use {ambient_color, background, bounces_left, emitter_color, environment_color, reflected_ray,
+     refraction_rays, shadow_blocker, shadow_fraction, shadow_ray, shadow_visibility, Float,
+     Scene, Vec3};
+use material::{Compositing, Shading};
+use ray::Ray;
+
+const DIRECTIONAL_LENGTH: Float = 100.;
+
+pub struct RayDump {
+    pub origin: Vec3,
+    pub dir: Vec3,
+    pub depth: u16,
+    pub hit: Option<HitDump>,
+    pub color: Vec3,
+}
+
+pub struct HitDump {
+    pub surface: &'static str,
+    // Index of the surface in the scene, see Scene::object
+    pub object: usize,
+    // The material's name, if it has one, and how it shades
+    pub material: Option<String>,
+    pub shading: Shading,
+    // What the diffuse term and the highlight reflect head on, with textures applied
+    pub albedo: Vec3,
+    pub specular_albedo: Vec3,
+    pub pos: Vec3,
+    pub normal: Vec3,
+    pub dist: Float,
+    pub u: Float,
+    pub v: Float,
+    pub ambient: Vec3,
+    pub lights: Vec<LightDump>,
+    // Light from the environment map, if the scene has one
+    pub environment: Vec3,
+    // Light from the emissive surfaces, and what this surface gives off itself
+    pub emitters: Vec3,
+    pub emission: Vec3,
+    pub reflectivity: Float,
+    pub compositing: Compositing,
+    // For shadow catchers, the fraction of the light that's blocked, which is all that shows
+    pub shadow: Option<Float>,
+    // The reflected ray, if the material is reflective and the depth limit wasn't reached
+    pub reflected: Option<Box<RayDump>>,
+    pub transparency: Float,
+    // The rays passing through, if the material is transparent and the depth limit wasn't
+    // reached
+    pub refraction: Option<RefractionDump>,
+}
+
+pub struct RefractionDump {
+    // The fraction of the light that's reflected at the surface rather than refracted
+    pub fresnel: Float,
+    pub reflected: Box<RayDump>,
+    // None for total internal reflection
+    pub refracted: Option<Box<RayDump>>,
+}
+
+// One per shadow ray, so area lights have several
+pub struct LightDump {
+    // Index of the light in the scene
+    pub light: usize,
+    // Where the shadow ray ends: the light, or the point sampled on an area light. Shadow rays to
+    // directional lights are cut off at DIRECTIONAL_LENGTH
+    pub light_pos: Vec3,
+    pub shadow_origin: Vec3,
+    // Surface name and hit position of whatever is between the hit point and the light
+    pub blocker: Option<(&'static str, Vec3)>,
+    pub color: Vec3,
+}
+
+// Traces the ray through pixel (x, y) of a width x height image
+pub fn trace_pixel(scene: &Scene, x: u32, y: u32, width: u32, height: u32, max_depth: u16)
+                   -> RayDump {
+    let aspect_ratio = width as Float / height as Float;
+    let ray = scene.camera.get_ray(x, y, width, height, aspect_ratio, scene.seed);
+    dump_ray(scene, &ray, 0, 0, max_depth)
+}
+
+fn dump_ray(scene: &Scene, ray: &Ray, depth: u16, refractions: u16, max_depth: u16) -> RayDump {
+    let mut dump = RayDump {
+        origin: ray.origin,
+        dir: ray.dir,
+        depth: depth,
+        hit: None,
+        color: background(scene, ray),
+    };
+
+    let (object, hit) = match scene.closest_hit(ray) {
+        Some(result) => result,
+        None => return dump,
+    };
+    let obj = scene.object(object);
+    let material = obj.material_at(&hit);
+
+    let ambient = ambient_color(scene, material, &hit);
+    let mut color = ambient;
+
+    let mut lights = Vec::new();
+    for (i, light) in scene.lights.iter().enumerate() {
+        let weight = *light.color() * (light.intensity() / light.samples() as Float);
+        for sample in 0..light.samples() {
+            let (shadow_ray, dist) = shadow_ray(scene, light, &hit, sample, light.samples());
+            let blocker = shadow_blocker(scene, &shadow_ray, dist);
+            let light_color = material.color(&shadow_ray, ray, &hit) * weight *
+                (light.attenuation(&shadow_ray.dir, dist) *
+                 shadow_visibility(scene, &shadow_ray, dist));
+            color = color + light_color;
+            lights.push(LightDump {
+                light: i,
+                light_pos: shadow_ray.origin + shadow_ray.dir * dist.min(DIRECTIONAL_LENGTH),
+                shadow_origin: shadow_ray.origin,
+                blocker: blocker.map(|(obj, hit)| (obj.name(), hit.pos)),
+                color: light_color,
+            });
+        }
+    }
+
+    let environment = environment_color(scene, &hit,
+                                        |shadow_ray| material.color(shadow_ray, ray, &hit));
+    color = color + environment;
+    let emitters = emitter_color(scene, &hit, |shadow_ray| material.color(shadow_ray, ray, &hit));
+    color = color + emitters;
+
+    let mut shadow = None;
+    match material.compositing() {
+        Compositing::Shaded => {}
+        Compositing::ShadowCatcher => {
+            let fraction = shadow_fraction(scene, &hit);
+            color = background(scene, ray) * (1. - fraction);
+            shadow = Some(fraction);
+        }
+        Compositing::Holdout => color = Vec3::new(0., 0., 0.),
+    }
+
+    let shaded = material.compositing() == Compositing::Shaded;
+    let (can_reflect, can_refract) = bounces_left(scene, depth, refractions, max_depth);
+    let transparency = material.transparency();
+    let refraction = if shaded && can_refract && transparency > 0. {
+        let (reflected_ray, refracted) = refraction_rays(scene, ray, material, &hit);
+        let reflected = dump_ray(scene, &reflected_ray, depth + 1, refractions + 1, max_depth);
+        let (fresnel, refracted) = match refracted {
+            Some((refracted_ray, fresnel)) => {
+                let refracted = dump_ray(scene, &refracted_ray, depth + 1, refractions + 1,
+                                         max_depth);
+                (fresnel, Some(Box::new(refracted)))
+            }
+            None => (1., None),
+        };
+        let passed = reflected.color * fresnel +
+            refracted.as_ref().map_or(Vec3::new(0., 0., 0.), |r| r.color * (1. - fresnel));
+        color = color * (1. - transparency) + passed * transparency;
+        Some(RefractionDump { fresnel: fresnel, reflected: Box::new(reflected),
+                              refracted: refracted })
+    } else {
+        None
+    };
+
+    let reflectivity = material.reflectivity_at(ray, &hit);
+    let reflected = if shaded && can_reflect && reflectivity > 0. {
+        let reflected = dump_ray(scene, &reflected_ray(scene, ray, &hit), depth + 1, refractions,
+                                 max_depth);
+        color = color + reflected.color * reflectivity;
+        Some(Box::new(reflected))
+    } else {
+        None
+    };
+
+    let emission = if shaded { material.emission() } else { Vec3::new(0., 0., 0.) };
+    dump.color = (color + emission) * material.interior_transmittance(ray, &hit);
+    dump.hit = Some(HitDump {
+        surface: obj.name(),
+        object: object,
+        material: material.name().map(|name| name.to_owned()),
+        shading: material.shading(),
+        albedo: material.albedo(&hit),
+        specular_albedo: material.specular_albedo(&hit),
+        pos: hit.pos,
+        normal: hit.normal,
+        dist: hit.dist,
+        u: hit.u,
+        v: hit.v,
+        ambient: ambient,
+        lights: lights,
+        environment: environment,
+        emitters: emitters,
+        emission: emission,
+        reflectivity: reflectivity,
+        compositing: material.compositing(),
+        shadow: shadow,
+        reflected: reflected,
+        transparency: transparency,
+        refraction: refraction,
+    });
+    dump
+}
+
+struct V<'a>(&'a Vec3);
+
+impl<'a> fmt::Display for V<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({:.3}, {:.3}, {:.3})", self.0.x, self.0.y, self.0.z)
+    }
+}
+
+impl RayDump {
+    fn fmt_indented(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent);
+        try!(writeln!(f, "{}ray (depth {}) from {} dir {}", pad, self.depth, V(&self.origin),
+                      V(&self.dir)));
+        let hit = match self.hit {
+            Some(ref hit) => hit,
+            None => return writeln!(f, "{}  miss, color {}", pad, V(&self.color)),
+        };
+
+        try!(writeln!(f, "{}  hit {} at {} (distance {:.3})", pad, hit.surface, V(&hit.pos),
+                      hit.dist));
+        try!(writeln!(f, "{}  normal {} uv ({:.3}, {:.3})", pad, V(&hit.normal), hit.u, hit.v));
+        let shading = match hit.shading {
+            Shading::Phong => "phong".to_owned(),
+            Shading::Ggx { metallic, roughness } => {
+                format!("ggx, metallic {:.2}, roughness {:.2}", metallic, roughness)
+            }
+        };
+        try!(writeln!(f, "{}  object {}, material {} ({}), albedo {} specular {}", pad,
+                      hit.object, hit.material.as_ref().map_or("unnamed", |name| &name[..]),
+                      shading, V(&hit.albedo), V(&hit.specular_albedo)));
+        try!(writeln!(f, "{}  ambient {}", pad, V(&hit.ambient)));
+        for light in hit.lights.iter() {
+            match light.blocker {
+                Some((name, pos)) => {
+                    try!(writeln!(f, "{}  light {} at {}: blocked by {} at {}", pad, light.light,
+                                  V(&light.light_pos), name, V(&pos)))
+                }
+                None => {
+                    try!(writeln!(f, "{}  light {} at {}: lit, adds {}", pad, light.light,
+                                  V(&light.light_pos), V(&light.color)))
+                }
+            }
+        }
+        if hit.environment != Vec3::new(0., 0., 0.) {
+            try!(writeln!(f, "{}  environment adds {}", pad, V(&hit.environment)));
+        }
+        if hit.emitters != Vec3::new(0., 0., 0.) {
+            try!(writeln!(f, "{}  emissive surfaces add {}", pad, V(&hit.emitters)));
+        }
+        if hit.emission != Vec3::new(0., 0., 0.) {
+            try!(writeln!(f, "{}  emits {}", pad, V(&hit.emission)));
+        }
+        if let Some(shadow) = hit.shadow {
+            try!(writeln!(f, "{}  shadow catcher, {:.1}% of the light blocked", pad,
+                          shadow * 100.));
+            return writeln!(f, "{}  color {}", pad, V(&self.color));
+        }
+        if hit.compositing == Compositing::Holdout {
+            return writeln!(f, "{}  holdout, color {}", pad, V(&self.color));
+        }
+        match hit.refraction {
+            Some(ref refraction) => {
+                try!(writeln!(f, "{}  transparency {}, {:.1}% reflected at the surface:", pad,
+                              hit.transparency, refraction.fresnel * 100.));
+                try!(refraction.reflected.fmt_indented(f, indent + 2));
+                match refraction.refracted {
+                    Some(ref refracted) => {
+                        try!(writeln!(f, "{}  refracting:", pad));
+                        try!(refracted.fmt_indented(f, indent + 2));
+                    }
+                    None => try!(writeln!(f, "{}  total internal reflection", pad)),
+                }
+            }
+            None if hit.transparency > 0. => {
+                try!(writeln!(f, "{}  transparency {}, depth limit reached", pad,
+                              hit.transparency))
+            }
+            None => {}
+        }
+        match hit.reflected {
+            Some(ref reflected) => {
+                try!(writeln!(f, "{}  reflectivity {}, reflecting:", pad, hit.reflectivity));
+                try!(reflected.fmt_indented(f, indent + 2));
+            }
+            None if hit.reflectivity > 0. => {
+                try!(writeln!(f, "{}  reflectivity {}, depth limit reached", pad,
+                              hit.reflectivity))
+            }
+            None => try!(writeln!(f, "{}  not reflective", pad)),
+        }
+        writeln!(f, "{}  color {}", pad, V(&self.color))
+    }
+
+    // Writes the ray tree as OBJ line segments, with camera/reflection rays and shadow rays in
+    // separate groups. Rays that miss everything are drawn with length `miss_length`.
+    pub fn to_obj(&self, miss_length: Float) -> String {
+        let mut rays = Vec::new();
+        let mut shadow_rays = Vec::new();
+        self.collect_segments(miss_length, &mut rays, &mut shadow_rays);
+
+        let mut obj = String::new();
+        let mut vertex = 1;
+        for &(name, ref segments) in [("rays", rays), ("shadow_rays", shadow_rays)].iter() {
+            obj.push_str(&format!("g {}\n", name));
+            for &(a, b) in segments.iter() {
+                obj.push_str(&format!("v {} {} {}\nv {} {} {}\nl {} {}\n", a.x, a.y, a.z,
+                                      b.x, b.y, b.z, vertex, vertex + 1));
+                vertex += 2;
+            }
+        }
+        obj
+    }
+
+    fn collect_segments(&self, miss_length: Float, rays: &mut Vec<(Vec3, Vec3)>,
+                        shadow_rays: &mut Vec<(Vec3, Vec3)>) {
+        let hit = match self.hit {
+            Some(ref hit) => hit,
+            None => return rays.push((self.origin, self.origin + self.dir * miss_length)),
+        };
+
+        rays.push((self.origin, hit.pos));
+        for light in hit.lights.iter() {
+            let end = light.blocker.map(|(_, pos)| pos).unwrap_or(light.light_pos);
+            shadow_rays.push((light.shadow_origin, end));
+        }
+        if let Some(ref refraction) = hit.refraction {
+            refraction.reflected.collect_segments(miss_length, rays, shadow_rays);
+            if let Some(ref refracted) = refraction.refracted {
+                refracted.collect_segments(miss_length, rays, shadow_rays);
+            }
+        }
+        if let Some(ref reflected) = hit.reflected {
+            reflected.collect_segments(miss_length, rays, shadow_rays);
+        }
+    }
+}
+
+impl fmt::Display for RayDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
@@ -0,0 +1,137 @@
+// Environment maps: light arriving from infinitely far away, stored as an equirectangular
+// (latitude/longitude) image. Radiance .hdr files keep the full brightness of the sky; for 8 bit
+// images, `intensity` scales it up for bright skies.
+//
+// For direct lighting, directions are importance sampled from the map's brightness, so a small
+// sun gets most of the samples instead of being missed by nearly all of them.
+
+use std::cmp;
+use std::fs::File;
+use std::io::BufReader;
+
+use {Float, Vec3};
+use float::consts::PI;
+use hdr::HdrImage;
+use post::luminance;
+use sampling::{cdf, pick};
+use sh::Sh9;
+
+use image;
+
+// What rays that miss everything see when the scene has no environment map. Unlike an
+// environment map it doesn't light the scene, except through path traced bounces
+#[derive(Clone, Copy, Debug)]
+pub enum Background {
+    Color(Vec3),
+    // A sky from `horizon` at and below the horizon to `zenith` straight up (+y)
+    Gradient { horizon: Vec3, zenith: Vec3 },
+}
+
+impl Background {
+    pub fn color(&self, dir: &Vec3) -> Vec3 {
+        match *self {
+            Background::Color(color) => color,
+            Background::Gradient { horizon, zenith } => {
+                let t = dir.y.max(0.).min(1.);
+                horizon * (1. - t) + zenith * t
+            }
+        }
+    }
+}
+
+pub struct EnvironmentMap {
+    image: HdrImage,
+    // Cumulative distribution of the rows, and of the pixels within each row, weighted by
+    // luminance and the solid angle each pixel covers
+    row_cdf: Vec<Float>,
+    pixel_cdfs: Vec<Vec<Float>>,
+}
+
+impl EnvironmentMap {
+    pub fn new(filename: &str, intensity: Float) -> Self {
+        let mut im = if filename.ends_with(".hdr") {
+            let mut file = BufReader::new(File::open(filename).unwrap());
+            HdrImage::read_radiance(&mut file).unwrap()
+        } else {
+            let rgb = image::open(filename).unwrap().to_rgb();
+            let mut im = HdrImage::new(rgb.width(), rgb.height());
+            for (x, y, pixel) in rgb.enumerate_pixels() {
+                im.put_pixel(x, y, Vec3::new(pixel.data[0] as Float, pixel.data[1] as Float,
+                                             pixel.data[2] as Float) / 255.);
+            }
+            im
+        };
+        for pixel in im.pixels_mut() {
+            *pixel = *pixel * intensity;
+        }
+        debug!("Loaded environment map {} ({}x{})", filename, im.width(), im.height());
+
+        let mut row_weights = Vec::new();
+        let mut pixel_cdfs = Vec::new();
+        for y in 0..im.height() {
+            // Rows near the poles cover less of the sphere
+            let solid_angle = ((y as Float + 0.5) / im.height() as Float * PI).sin();
+            let weights: Vec<Float> = (0..im.width())
+                .map(|x| luminance(&im.get_pixel(x, y)) * solid_angle)
+                .collect();
+            row_weights.push(weights.iter().sum());
+            pixel_cdfs.push(cdf(&weights));
+        }
+        EnvironmentMap { image: im, row_cdf: cdf(&row_weights), pixel_cdfs: pixel_cdfs }
+    }
+
+    // Light arriving from direction `dir` (pointing away from the scene), +y is the top row
+    pub fn lookup(&self, dir: &Vec3) -> Vec3 {
+        let u = 0.5 + dir.z.atan2(dir.x) / (2. * PI);
+        let v = dir.y.max(-1.).min(1.).acos() / PI;
+        self.image.sample(u * self.image.width() as Float, v * self.image.height() as Float)
+    }
+
+    pub fn to_sh(&self, samples: u32) -> Sh9 {
+        Sh9::project(samples, |dir| self.lookup(&dir))
+    }
+
+    // Maps two uniform numbers in 0..1 to a direction, more likely towards bright parts of the
+    // map. Returns the direction and its probability density per steradian, or None if the map
+    // is completely black
+    pub fn sample(&self, u1: Float, u2: Float) -> Option<(Vec3, Float)> {
+        let (width, height) = (self.image.width(), self.image.height());
+        let y = match pick(&self.row_cdf, u1) {
+            Some(y) => y,
+            None => return None,
+        };
+        let x = match pick(&self.pixel_cdfs[y], u2) {
+            Some(x) => x,
+            None => return None,
+        };
+
+        let theta = (y as Float + 0.5) / height as Float * PI;
+        let phi = ((x as Float + 0.5) / width as Float - 0.5) * 2. * PI;
+        let dir = Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+        Some((dir, self.pixel_pdf(x, y)))
+    }
+
+    // The probability density of `sample` returning the pixel `dir` falls in, per steradian
+    pub fn pdf(&self, dir: &Vec3) -> Float {
+        let (width, height) = (self.image.width(), self.image.height());
+        let u = 0.5 + dir.z.atan2(dir.x) / (2. * PI);
+        let v = dir.y.max(-1.).min(1.).acos() / PI;
+        let x = cmp::min((u * width as Float) as usize, width as usize - 1);
+        let y = cmp::min((v * height as Float) as usize, height as usize - 1);
+        if self.row_cdf.is_empty() || self.pixel_cdfs[y].is_empty() {
+            return 0.;
+        }
+        self.pixel_pdf(x, y)
+    }
+
+    // Probability of the pixel, spread over the solid angle it covers
+    fn pixel_pdf(&self, x: usize, y: usize) -> Float {
+        let (width, height) = (self.image.width(), self.image.height());
+        let row_p = self.row_cdf[y] - if y > 0 { self.row_cdf[y - 1] } else { 0. };
+        let cdf = &self.pixel_cdfs[y];
+        let pixel_p = cdf[x] - if x > 0 { cdf[x - 1] } else { 0. };
+        let theta = (y as Float + 0.5) / height as Float * PI;
+        let pixel_solid_angle = 2. * PI * PI * theta.sin() / (width * height) as Float;
+        row_p * pixel_p / pixel_solid_angle
+    }
+}
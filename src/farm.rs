@@ -0,0 +1,205 @@
+// Splitting a frame into tiles for a render farm. `farm split` writes one job file per worker,
+// listing the tiles it should render (in the config's tile order) and the command line for each,
+// plus manifest.json with all tiles. The farm's own scheduler runs the jobs (each worker needs
+// the same config.toml and scenes), and `farm check` then validates the returned tiles and
+// assembles the frame.
+//
+// Workers can also be handed ranges of tiles instead: `--tiles 0..128/512 <file>` splits the frame
+// into 512 tiles and renders the first 128 of them in the config's tile order into one partial
+// file (see merge.rs), and `merge` assembles the workers' files. Every worker finds the same grid
+// from the count and the image size, so only the range differs between their command lines.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use rustc_serialize::json::Json;
+
+use tracerlib::{ray_trace_region, Scene};
+use tracerlib::hdr::HdrImage;
+use tracerlib::log::{self, Level};
+use tracerlib::tiles::tile_order;
+
+use image::{self, DynamicImage, GenericImage, ImageRgb8, ImageRgba8, RgbaImage};
+
+use super::{encode, save_image, Config};
+
+pub fn farm(config: &Config, args: &[String]) {
+    match args.get(0).map(|s| &s[..]) {
+        Some("split") => {
+            assert!(args.len() == 4, "farm split requires tiles_x,tiles_y, a worker count and \
+                                      a directory");
+            let tiles: Vec<u32> = args[1].split(',').map(|c| c.parse().unwrap()).collect();
+            assert!(tiles.len() == 2, "farm split requires tiles_x,tiles_y");
+            split(config, (tiles[0], tiles[1]), args[2].parse().unwrap(), &args[3]);
+        }
+        Some("check") => {
+            assert!(args.len() == 2, "farm check requires a directory");
+            check(config, &args[1]);
+        }
+        _ => panic!("Usage: farm split <tiles_x>,<tiles_y> <workers> <dir> | farm check <dir>"),
+    }
+}
+
+// The index and pixel region of each tile of a tiles_x x tiles_y grid over the frame, in the
+// config's tile order. Indices count row by row, and tile sizes are rounded so the tiles cover
+// the frame exactly
+fn tile_regions(config: &Config, tiles: (u32, u32)) -> Vec<(u32, (u32, u32, u32, u32))> {
+    let (width, height) = (config.width, config.height);
+    let (tiles_x, tiles_y) = tiles;
+    assert!(tiles_x > 0 && tiles_x <= width && tiles_y > 0 && tiles_y <= height,
+            "Can't split a {}x{} image into {}x{} tiles", width, height, tiles_x, tiles_y);
+    tile_order(tiles_x, tiles_y, config.tile_order).into_iter().map(|(tx, ty)| {
+        (ty * tiles_x + tx, (tx * width / tiles_x, ty * height / tiles_y,
+                             (tx + 1) * width / tiles_x, (ty + 1) * height / tiles_y))
+    }).collect()
+}
+
+// The tiles_x x tiles_y grid of `count` tiles over the frame whose tiles are closest to square
+pub fn tile_grid(config: &Config, count: u32) -> (u32, u32) {
+    let (width, height) = (config.width as f64, config.height as f64);
+    let squareness = |tiles_x: u32| {
+        let aspect = (width / tiles_x as f64) / (height / (count / tiles_x) as f64);
+        aspect.max(1. / aspect)
+    };
+    let grids = (1..count + 1).filter(|&tiles_x| {
+        count % tiles_x == 0 && tiles_x <= config.width && count / tiles_x <= config.height
+    });
+    let tiles_x = grids.fold(None, |best: Option<u32>, tiles_x| match best {
+        Some(best) if squareness(best) <= squareness(tiles_x) => Some(best),
+        _ => Some(tiles_x),
+    });
+    let tiles_x = tiles_x.unwrap_or_else(|| {
+        panic!("Can't split a {}x{} image into {} tiles", config.width, config.height, count)
+    });
+    (tiles_x, count / tiles_x)
+}
+
+// The pixel regions of tiles first..end of the frame split into `count`, see tile_grid
+pub fn tile_range(config: &Config, range: (u32, u32), count: u32) -> Vec<(u32, u32, u32, u32)> {
+    let (first, end) = range;
+    assert!(first < end && end <= count, "Can't render tiles {}..{} of {}", first, end, count);
+    tile_regions(config, tile_grid(config, count)).into_iter()
+        .skip(first as usize).take((end - first) as usize)
+        .map(|(_, region)| region)
+        .collect()
+}
+
+fn split(config: &Config, tiles: (u32, u32), workers: u32, dir: &str) {
+    assert!(workers > 0, "farm split needs at least one worker");
+    fs::create_dir_all(dir).unwrap();
+
+    let mut jobs = vec![Vec::new(); workers as usize];
+    let mut all_tiles = Vec::new();
+    for (n, (i, (x0, y0, x1, y1))) in tile_regions(config, tiles).into_iter().enumerate() {
+        let worker = n % workers as usize;
+        let region = format!("{},{},{},{}", x0, y0, x1, y1);
+        let out_file = Path::new(dir).join(format!("tile_{:04}.png", i));
+        let out_file = out_file.to_str().unwrap().to_owned();
+
+        let mut tile = BTreeMap::new();
+        tile.insert("region".to_owned(), Json::Array(vec![Json::U64(x0 as u64),
+                                                          Json::U64(y0 as u64),
+                                                          Json::U64(x1 as u64),
+                                                          Json::U64(y1 as u64)]));
+        tile.insert("out_file".to_owned(), Json::String(out_file.clone()));
+        tile.insert("worker".to_owned(), Json::U64(worker as u64));
+        all_tiles.push(Json::Object(tile.clone()));
+
+        tile.remove("worker");
+        tile.insert("args".to_owned(), Json::Array(vec![Json::String("--region".to_owned()),
+                                                        Json::String(region),
+                                                        Json::String(out_file)]));
+        jobs[worker].push(Json::Object(tile));
+    }
+
+    for (worker, tiles) in jobs.into_iter().enumerate() {
+        let mut job = BTreeMap::new();
+        job.insert("scene".to_owned(), Json::String(config.scene.clone()));
+        job.insert("worker".to_owned(), Json::U64(worker as u64));
+        job.insert("tiles".to_owned(), Json::Array(tiles));
+        let path = Path::new(dir).join(format!("job_{:03}.json", worker));
+        writeln!(File::create(&path).unwrap(), "{}", Json::Object(job).pretty()).unwrap();
+    }
+
+    let mut manifest = BTreeMap::new();
+    manifest.insert("scene".to_owned(), Json::String(config.scene.clone()));
+    manifest.insert("width".to_owned(), Json::U64(config.width as u64));
+    manifest.insert("height".to_owned(), Json::U64(config.height as u64));
+    manifest.insert("workers".to_owned(), Json::U64(workers as u64));
+    manifest.insert("tiles".to_owned(), Json::Array(all_tiles));
+    let path = Path::new(dir).join("manifest.json");
+    writeln!(File::create(&path).unwrap(), "{}", Json::Object(manifest).pretty()).unwrap();
+    info!("Wrote {} jobs for {}x{} tiles to {}", workers, tiles.0, tiles.1, dir);
+}
+
+// Renders one tile for `--region`. Post effects like bloom need the whole frame, so they're left
+// for after the tiles are assembled
+pub fn render_region(config: &Config, scene: &Scene, region: (u32, u32, u32, u32))
+                     -> DynamicImage {
+    if !config.post.is_empty() || !scene.camera().imaging().is_neutral() {
+        warn!("Post effects and camera imaging are skipped when rendering a region");
+    }
+    encode(config, scene, &render_region_hdr(config, scene, region))
+}
+
+// The region's pixels at the output size, before post effects
+pub fn render_region_hdr(config: &Config, scene: &Scene, region: (u32, u32, u32, u32))
+                         -> HdrImage {
+    assert!(config.debug_mode.is_none() && !config.stereo() && config.adaptive.is_none(),
+            "--region and crops can't be combined with a debug mode, stereo or adaptive sampling");
+    let _span = log::span(Level::Info, "render region");
+    ray_trace_region(scene, config.width, config.height, region, config.reflection_depth,
+                     config.samples, |_, _| {})
+}
+
+// Checks that every tile in the manifest was rendered with the right size, and if so writes the
+// assembled frame to the config's out_file
+fn check(config: &Config, dir: &str) {
+    let mut json = String::new();
+    File::open(Path::new(dir).join("manifest.json")).unwrap().read_to_string(&mut json).unwrap();
+    let manifest = Json::from_str(&json).unwrap();
+    let width = manifest.find("width").and_then(Json::as_u64).unwrap() as u32;
+    let height = manifest.find("height").and_then(Json::as_u64).unwrap() as u32;
+    let tiles = manifest.find("tiles").and_then(Json::as_array).unwrap();
+
+    let mut frame = RgbaImage::new(width, height);
+    // Tiles of scenes with a transparent background have alpha
+    let mut transparent = false;
+    let mut problems = 0;
+    for tile in tiles {
+        let region: Vec<u32> = tile.find("region").and_then(Json::as_array).unwrap()
+            .iter().map(|c| c.as_u64().unwrap() as u32).collect();
+        let out_file = tile.find("out_file").and_then(Json::as_string).unwrap();
+        let worker = tile.find("worker").and_then(Json::as_u64).unwrap();
+        let (w, h) = (region[2] - region[0], region[3] - region[1]);
+        match image::open(out_file) {
+            Ok(im) => {
+                let (iw, ih) = im.dimensions();
+                if (iw, ih) == (w, h) {
+                    transparent |= im.color() == image::RGBA(8);
+                    frame.copy_from(&im.to_rgba(), region[0], region[1]);
+                } else {
+                    println!("{} (worker {}): {}x{}, expected {}x{}", out_file, worker, iw, ih,
+                             w, h);
+                    problems += 1;
+                }
+            }
+            Err(err) => {
+                println!("{} (worker {}): {}", out_file, worker, err);
+                problems += 1;
+            }
+        }
+    }
+
+    if problems > 0 {
+        println!("{} of {} tiles missing or invalid", problems, tiles.len());
+        return;
+    }
+    println!("All {} tiles present", tiles.len());
+    let frame = ImageRgba8(frame);
+    let frame = if transparent { frame } else { ImageRgb8(frame.to_rgb()) };
+    save_image(config, &frame, &config.out_file);
+    info!("Wrote {}", config.out_file);
+}
@@ -0,0 +1,648 @@
+// glTF 2.0 scenes, the format Blender and most other tools export whole scenes in: .gltf JSON
+// with its buffers and images in files next to it or embedded as base64 data URIs, or binary
+// .glb files holding the JSON and one buffer. The triangles of every mesh the nodes of the
+// default scene place are gathered into one mesh, moved by the nodes' transforms, with the
+// metallic-roughness materials as its face materials: base color and its texture, metallic and
+// roughness and their texture, and emission. Primitives without a material use the mesh's own.
+// Cameras are read on their own, see load_camera. Lights, animations, skins, morph targets and
+// the other textures are skipped.
+//
+// glTF is right handed, with y up and cameras looking along -z, while the tracer is left handed,
+// so z is flipped on the way in and triangles are wound the other way to keep facing out.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::str;
+
+use {float, Camera, Float, Projection, Vec3};
+use material::Material;
+use mesh::{remove_degenerate, Triangle, TriangleMesh};
+use mtl::relative_to;
+use stl::u32_le;
+use texture::{ImageTexture, Texture};
+
+use image::{self, Rgb, RgbImage};
+
+use nalgebra::Norm;
+
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::Json;
+
+const GLB_MAGIC: &'static [u8] = b"glTF";
+const GLB_JSON: u32 = 0x4e4f534a;
+const GLB_BIN: u32 = 0x004e4942;
+
+// Asks Gltf::texture for the whole image rather than one channel
+const ALL_CHANNELS: usize = 3;
+
+// Row major, transforming column vectors
+type Matrix = [[Float; 4]; 4];
+
+const IDENTITY: Matrix = [[1., 0., 0., 0.], [0., 1., 0., 0.], [0., 0., 1., 0.], [0., 0., 0., 1.]];
+
+impl TriangleMesh {
+    // Loads all the meshes of a .gltf or .glb file's default scene as one mesh, see above.
+    // `material` is the mesh's, and lends its compositing and visibility to the file's
+    pub fn load_gltf(filename: &str, material: Material) -> Self {
+        let gltf = Gltf::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        gltf.mesh(material).unwrap_or_else(|e| panic!("{}: {}", filename, e))
+    }
+}
+
+// The camera of the `index`th node of the default scene that has one, counting depth first in
+// the order the nodes are listed
+pub fn load_camera(filename: &str, index: usize) -> Camera {
+    let gltf = Gltf::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    gltf.camera(index).unwrap_or_else(|e| panic!("{}: {}", filename, e))
+}
+
+struct Gltf {
+    filename: String,
+    json: Json,
+    // The buffer of a .glb file, which the first buffer refers to when it has no uri
+    binary: Option<Vec<u8>>,
+}
+
+// What the triangles of the primitives are gathered into
+struct Geometry {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(Float, Float)>,
+    triangles: Vec<Triangle>,
+}
+
+impl Gltf {
+    fn open(filename: &str) -> Result<Self, String> {
+        let mut data = Vec::new();
+        try!(File::open(filename).and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|e| e.to_string()));
+        let (json, binary) = if data.starts_with(GLB_MAGIC) {
+            try!(read_glb(&data))
+        } else {
+            let text = try!(String::from_utf8(data).map_err(|_| "not UTF-8 JSON".to_owned()));
+            (try!(Json::from_str(&text).map_err(|e| e.to_string())), None)
+        };
+        let version = json.find_path(&["asset", "version"]).and_then(Json::as_string)
+            .unwrap_or("?").to_owned();
+        if !version.starts_with("2.") {
+            return Err(format!("only glTF 2.0 is supported, not {}", version));
+        }
+        Ok(Gltf { filename: filename.to_owned(), json: json, binary: binary })
+    }
+
+    // The objects in the top level array `key`, none if it's missing
+    fn array(&self, key: &str) -> &[Json] {
+        self.json.find(key).and_then(Json::as_array).map_or(&[][..], |array| &array[..])
+    }
+
+    fn item(&self, key: &str, index: usize) -> Result<&Json, String> {
+        self.array(key).get(index).ok_or_else(|| format!("there is no {} {}", key, index))
+    }
+
+    // The nodes of the default scene and the transforms they're placed with, parents first
+    fn scene_nodes(&self) -> Result<Vec<(&Json, Matrix)>, String> {
+        let roots = match self.json.find("scene").and_then(Json::as_u64) {
+            Some(scene) => try!(indices(try!(self.item("scenes", scene as usize)), "nodes")),
+            None if !self.array("scenes").is_empty() => {
+                try!(indices(&self.array("scenes")[0], "nodes"))
+            }
+            // Without scenes, every node that isn't a child is a root
+            None => {
+                let mut children = Vec::new();
+                for node in self.array("nodes") {
+                    children.extend(try!(indices(node, "children")));
+                }
+                (0..self.array("nodes").len()).filter(|i| !children.contains(i)).collect()
+            }
+        };
+        let mut placed = Vec::new();
+        let mut stack: Vec<(usize, Matrix)> = roots.into_iter().rev().map(|i| (i, IDENTITY))
+            .collect();
+        while let Some((index, parent)) = stack.pop() {
+            // Nodes form trees, so a scene can't place more of them than there are
+            if placed.len() > self.array("nodes").len() {
+                return Err("the nodes of the scene form a cycle".to_owned());
+            }
+            let node = try!(self.item("nodes", index));
+            let transform = multiply(&parent, &try!(node_transform(node)));
+            for &child in try!(indices(node, "children")).iter().rev() {
+                stack.push((child, transform));
+            }
+            placed.push((node, transform));
+        }
+        Ok(placed)
+    }
+
+    fn mesh(&self, material: Material) -> Result<TriangleMesh, String> {
+        let buffers = try!(self.buffers());
+        let mut textures = HashMap::new();
+        let mut materials = Vec::new();
+        for gltf_material in self.array("materials") {
+            materials.push(try!(self.material(gltf_material, &material, &buffers,
+                                              &mut textures)));
+        }
+
+        let mut geometry = Geometry {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            triangles: Vec::new(),
+        };
+        for (node, transform) in try!(self.scene_nodes()) {
+            let mesh = match node.find("mesh").and_then(Json::as_u64) {
+                Some(mesh) => try!(self.item("meshes", mesh as usize)),
+                None => continue,
+            };
+            let primitives = mesh.find("primitives").and_then(Json::as_array)
+                .map_or(&[][..], |p| &p[..]);
+            for primitive in primitives {
+                try!(self.add_primitive(primitive, &transform, &buffers, &mut geometry));
+            }
+        }
+        if geometry.triangles.is_empty() {
+            return Err("the scene has no triangles".to_owned());
+        }
+        let Geometry { positions, normals, uvs, mut triangles } = geometry;
+        let removed = remove_degenerate(&positions, &mut triangles);
+        debug!("Loaded {} ({} vertices, {} triangles, {} degenerate ones left out, {} \
+                materials)", self.filename, positions.len(), triangles.len(), removed,
+               materials.len());
+        Ok(TriangleMesh::new(positions, normals, uvs, triangles, material)
+            .with_face_materials(materials))
+    }
+
+    fn add_primitive(&self, primitive: &Json, transform: &Matrix, buffers: &[Vec<u8>],
+                     geometry: &mut Geometry)
+                     -> Result<(), String> {
+        let mode = primitive.find("mode").and_then(Json::as_u64).unwrap_or(4);
+        if mode < 4 {
+            warn!("{}: skipped a primitive of points or lines", self.filename);
+            return Ok(());
+        }
+        let attribute = |name: &str| {
+            primitive.find_path(&["attributes", name]).and_then(Json::as_u64)
+                .map(|accessor| accessor as usize)
+        };
+        let position = try!(attribute("POSITION").ok_or("a primitive has no POSITION"));
+        let positions = try!(self.accessor(position, buffers));
+        let count = positions.len() / 3;
+
+        let first = geometry.positions.len();
+        for p in positions.chunks(3) {
+            let p = transform_point(transform, &Vec3::new(p[0], p[1], p[2]));
+            geometry.positions.push(Vec3::new(p.x, p.y, -p.z));
+        }
+        let normals = match attribute("NORMAL") {
+            Some(normal) => {
+                let first = geometry.normals.len();
+                let matrix = normal_matrix(transform);
+                for n in try!(self.accessor(normal, buffers)).chunks(3) {
+                    let n = transform_dir(&matrix, &Vec3::new(n[0], n[1], n[2])).normalize();
+                    geometry.normals.push(Vec3::new(n.x, n.y, -n.z));
+                }
+                Some(first)
+            }
+            None => None,
+        };
+        let uvs = match attribute("TEXCOORD_0") {
+            Some(uv) => {
+                let first = geometry.uvs.len();
+                geometry.uvs.extend(try!(self.accessor(uv, buffers)).chunks(2)
+                    .map(|uv| (uv[0], uv[1])));
+                Some(first)
+            }
+            None => None,
+        };
+
+        let indices: Vec<usize> = match primitive.find("indices").and_then(Json::as_u64) {
+            Some(indices) => {
+                try!(self.accessor(indices as usize, buffers)).iter().map(|&i| i as usize)
+                    .collect()
+            }
+            None => (0..count).collect(),
+        };
+        if indices.iter().any(|&i| i >= count) {
+            return Err("a primitive's indices are out of range".to_owned());
+        }
+        let corners: Vec<[usize; 3]> = match mode {
+            4 => indices.chunks(3).filter(|c| c.len() == 3).map(|c| [c[0], c[1], c[2]]).collect(),
+            // Every other triangle of a strip is wound the other way round
+            5 => {
+                (2..indices.len()).map(|i| if i % 2 == 0 {
+                    [indices[i - 2], indices[i - 1], indices[i]]
+                } else {
+                    [indices[i - 1], indices[i - 2], indices[i]]
+                }).collect()
+            }
+            6 => (2..indices.len()).map(|i| [indices[0], indices[i - 1], indices[i]]).collect(),
+            _ => return Err(format!("unknown primitive mode {}", mode)),
+        };
+
+        // Flipping z mirrors the triangles, and so does a transform that turns them inside out,
+        // in which case the two cancel out
+        let mirrored = determinant(transform) < 0.;
+        let material = primitive.find("material").and_then(Json::as_u64)
+            .map_or(0, |m| m as usize + 1);
+        if material > self.array("materials").len() {
+            return Err(format!("there is no material {}", material - 1));
+        }
+        for c in corners {
+            let c = if mirrored { c } else { [c[0], c[2], c[1]] };
+            let offset = |first: usize| [first + c[0], first + c[1], first + c[2]];
+            geometry.triangles.push(Triangle {
+                positions: offset(first),
+                normals: normals.map(&offset),
+                uvs: uvs.map(&offset),
+                material: material,
+            });
+        }
+        Ok(())
+    }
+
+    // The contents of the buffers, in order
+    fn buffers(&self) -> Result<Vec<Vec<u8>>, String> {
+        let mut buffers = Vec::new();
+        for (i, buffer) in self.array("buffers").iter().enumerate() {
+            let data = match buffer.find("uri").and_then(Json::as_string) {
+                Some(uri) => try!(self.read_uri(uri)),
+                None => {
+                    match self.binary {
+                        Some(ref binary) if i == 0 => binary.clone(),
+                        _ => return Err(format!("buffer {} has no uri", i)),
+                    }
+                }
+            };
+            let length = buffer.find("byteLength").and_then(Json::as_u64).unwrap_or(0) as usize;
+            if data.len() < length {
+                return Err(format!("buffer {} is {} bytes, not {}", i, data.len(), length));
+            }
+            buffers.push(data);
+        }
+        Ok(buffers)
+    }
+
+    // A base64 data URI, or a file relative to the glTF file
+    fn read_uri(&self, uri: &str) -> Result<Vec<u8>, String> {
+        if uri.starts_with("data:") {
+            let start = try!(uri.find(";base64,").ok_or("only base64 data URIs are supported"));
+            return uri[start + 8..].from_base64().map_err(|e| e.to_string());
+        }
+        let file = relative_to(&self.filename, &percent_decode(uri));
+        let mut data = Vec::new();
+        try!(File::open(&file).and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|e| format!("{}: {}", file, e)));
+        Ok(data)
+    }
+
+    // The values of an accessor, element after element, normalized integers scaled to 0..1 or
+    // -1..1
+    fn accessor(&self, index: usize, buffers: &[Vec<u8>]) -> Result<Vec<Float>, String> {
+        let accessor = try!(self.item("accessors", index));
+        let number = |key: &str| accessor.find(key).and_then(Json::as_u64).map(|n| n as usize);
+        if accessor.find("sparse").is_some() {
+            return Err("sparse accessors aren't supported".to_owned());
+        }
+        let count = try!(number("count").ok_or("an accessor has no count"));
+        let components = match accessor.find("type").and_then(Json::as_string) {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") => 4,
+            type_ => return Err(format!("unsupported accessor type {:?}", type_)),
+        };
+        let component_type = try!(number("componentType").ok_or("an accessor has no type"));
+        let size = match component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            _ => return Err(format!("unknown component type {}", component_type)),
+        };
+        let normalized = accessor.find("normalized").and_then(Json::as_boolean).unwrap_or(false);
+        // Accessors without a buffer view are all zeros
+        let view = match number("bufferView") {
+            Some(view) => try!(self.item("bufferViews", view)),
+            None => return Ok(vec![0.; count * components]),
+        };
+        let view_number = |key: &str| view.find(key).and_then(Json::as_u64).map(|n| n as usize);
+        let buffer = try!(view_number("buffer").and_then(|b| buffers.get(b))
+            .ok_or("a buffer view has no buffer"));
+        let start = view_number("byteOffset").unwrap_or(0) + number("byteOffset").unwrap_or(0);
+        let stride = view_number("byteStride").unwrap_or(components * size);
+
+        let mut values = Vec::with_capacity(count * components);
+        for i in 0..count {
+            for c in 0..components {
+                let at = start + i * stride + c * size;
+                let bytes = try!(buffer.get(at..at + size)
+                    .ok_or("an accessor runs past the end of its buffer"));
+                let value = match component_type {
+                    5120 => bytes[0] as i8 as f64,
+                    5121 => bytes[0] as f64,
+                    5122 => (bytes[0] as u16 | (bytes[1] as u16) << 8) as i16 as f64,
+                    5123 => (bytes[0] as u16 | (bytes[1] as u16) << 8) as f64,
+                    5125 => u32_le(bytes) as f64,
+                    _ => f32::from_bits(u32_le(bytes)) as f64,
+                };
+                let value = match (normalized, component_type) {
+                    (true, 5120) => (value / 127.).max(-1.),
+                    (true, 5121) => value / 255.,
+                    (true, 5122) => (value / 32767.).max(-1.),
+                    (true, 5123) => value / 65535.,
+                    _ => value,
+                };
+                values.push(value as Float);
+            }
+        }
+        Ok(values)
+    }
+
+    fn material(&self, material: &Json, base: &Material, buffers: &[Vec<u8>],
+                textures: &mut HashMap<(usize, usize), ImageTexture>)
+                -> Result<Material, String> {
+        let pbr = material.find("pbrMetallicRoughness");
+        let field = |key: &str| pbr.and_then(|pbr| pbr.find(key));
+        let factor = |key: &str| field(key).and_then(Json::as_f64).map_or(1., |f| f as Float);
+        let color = field("baseColorFactor").and_then(|c| vec3(c))
+            .unwrap_or(Vec3::new(1., 1., 1.));
+        let texture = match field("baseColorTexture") {
+            Some(info) => {
+                Some(Box::new(try!(self.texture(info, ALL_CHANNELS, buffers, textures)))
+                     as Box<Texture>)
+            }
+            None => None,
+        };
+        let unit = |x: Float| x.max(0.).min(1.);
+        let m = Material::new(color, 1., 0., 0., 0., texture, None, None)
+            .with_ggx(unit(factor("metallicFactor")), unit(factor("roughnessFactor")))
+            .with_compositing(base.compositing())
+            .with_visibility(base.visibility());
+        // Roughness is in the green channel and metallic in the blue one
+        let m = match field("metallicRoughnessTexture") {
+            Some(info) => {
+                let roughness = try!(self.texture(info, 1, buffers, textures));
+                let metallic = try!(self.texture(info, 2, buffers, textures));
+                m.with_roughness_map(Box::new(roughness)).with_metallic_map(Box::new(metallic))
+            }
+            None => m,
+        };
+        let strength = material.find_path(&["extensions", "KHR_materials_emissive_strength",
+                                            "emissiveStrength"])
+            .and_then(Json::as_f64).map_or(1., |s| s as Float);
+        Ok(match material.find("emissiveFactor").and_then(|e| vec3(e)) {
+            Some(emission) if emission != Vec3::new(0., 0., 0.) => {
+                m.with_emission(emission * strength)
+            }
+            _ => m,
+        })
+    }
+
+    // The image of a texture info object, or one channel of it as gray, decoded once for all
+    // the materials using it
+    fn texture(&self, info: &Json, channel: usize, buffers: &[Vec<u8>],
+               textures: &mut HashMap<(usize, usize), ImageTexture>)
+               -> Result<ImageTexture, String> {
+        let texture = try!(info.find("index").and_then(Json::as_u64)
+            .ok_or("a texture info has no index"));
+        let source = try!(try!(self.item("textures", texture as usize)).find("source")
+            .and_then(Json::as_u64).ok_or("a texture has no source image"));
+        let key = (source as usize, channel);
+        if let Some(texture) = textures.get(&key) {
+            return Ok(texture.clone());
+        }
+        let image = try!(self.image(source as usize, buffers));
+        let image = if channel == ALL_CHANNELS {
+            image
+        } else {
+            let (width, height) = image.dimensions();
+            RgbImage::from_fn(width, height, |x, y| {
+                let value = image.get_pixel(x, y).data[channel];
+                Rgb { data: [value, value, value] }
+            })
+        };
+        // Base colors are sRGB, metallic and roughness linear
+        let texture = if channel == ALL_CHANNELS {
+            ImageTexture::from_image(image)
+        } else {
+            ImageTexture::from_linear_image(image)
+        };
+        textures.insert(key, texture.clone());
+        Ok(texture)
+    }
+
+    fn image(&self, index: usize, buffers: &[Vec<u8>]) -> Result<RgbImage, String> {
+        let image = try!(self.item("images", index));
+        let data = match image.find("uri").and_then(Json::as_string) {
+            Some(uri) => try!(self.read_uri(uri)),
+            None => {
+                let view = try!(image.find("bufferView").and_then(Json::as_u64)
+                    .ok_or(format!("image {} has neither a uri nor a buffer view", index)));
+                let view = try!(self.item("bufferViews", view as usize));
+                let number = |key: &str| {
+                    view.find(key).and_then(Json::as_u64).map(|n| n as usize)
+                };
+                let buffer = try!(number("buffer").and_then(|b| buffers.get(b))
+                    .ok_or("a buffer view has no buffer"));
+                let start = number("byteOffset").unwrap_or(0);
+                let end = start + number("byteLength").unwrap_or(0);
+                try!(buffer.get(start..end).ok_or("an image runs past the end of its buffer"))
+                    .to_vec()
+            }
+        };
+        let image = try!(image::load_from_memory(&data)
+            .map_err(|e| format!("image {}: {}", index, e)));
+        Ok(image.to_rgb())
+    }
+
+    fn camera(&self, index: usize) -> Result<Camera, String> {
+        let nodes = try!(self.scene_nodes());
+        let cameras: Vec<_> = nodes.iter()
+            .filter_map(|&(node, transform)| {
+                node.find("camera").and_then(Json::as_u64).map(|camera| (camera, transform))
+            })
+            .collect();
+        let &(camera, transform) = try!(cameras.get(index)
+            .ok_or(format!("the scene has {} cameras, not {}", cameras.len(), index + 1)));
+        let camera = try!(self.item("cameras", camera as usize));
+        let flip = |v: Vec3| Vec3::new(v.x, v.y, -v.z);
+        let pos = flip(transform_point(&transform, &Vec3::new(0., 0., 0.)));
+        let dir = flip(transform_dir(&transform, &Vec3::new(0., 0., -1.)));
+        let up = flip(transform_dir(&transform, &Vec3::new(0., 1., 0.)));
+
+        let number = |kind: &str, key: &str| {
+            camera.find_path(&[kind, key]).and_then(Json::as_f64).map(|n| n as Float)
+        };
+        let (projection, near, far) = match camera.find("type").and_then(Json::as_string) {
+            Some("perspective") => {
+                let fov = try!(number("perspective", "yfov").ok_or("a camera has no yfov"));
+                (Projection::Perspective { fov: fov.to_degrees() },
+                 number("perspective", "znear"), number("perspective", "zfar"))
+            }
+            Some("orthographic") => {
+                let ymag = try!(number("orthographic", "ymag").ok_or("a camera has no ymag"));
+                (Projection::Orthographic { height: 2. * ymag.abs() },
+                 number("orthographic", "znear"), number("orthographic", "zfar"))
+            }
+            type_ => return Err(format!("unknown camera type {:?}", type_)),
+        };
+        Ok(Camera::new(pos, dir, up).with_projection(projection)
+            .with_clip(near.unwrap_or(0.), far.unwrap_or(float::INFINITY)))
+    }
+}
+
+// The JSON and the binary buffer of a .glb file
+fn read_glb(data: &[u8]) -> Result<(Json, Option<Vec<u8>>), String> {
+    if data.len() < 12 || u32_le(&data[4..8]) != 2 {
+        return Err("only version 2 GLB files are supported".to_owned());
+    }
+    let (mut json, mut binary) = (None, None);
+    let mut at = 12;
+    while at + 8 <= data.len() {
+        let (length, kind) = (u32_le(&data[at..at + 4]) as usize, u32_le(&data[at + 4..at + 8]));
+        let chunk = try!(data.get(at + 8..at + 8 + length)
+            .ok_or("a GLB chunk runs past the end of the file"));
+        match kind {
+            GLB_JSON => {
+                let text = try!(String::from_utf8(chunk.to_vec())
+                    .map_err(|_| "the GLB's JSON isn't UTF-8".to_owned()));
+                json = Some(try!(Json::from_str(&text).map_err(|e| e.to_string())));
+            }
+            GLB_BIN if binary.is_none() => binary = Some(chunk.to_vec()),
+            _ => {}
+        }
+        at += 8 + length;
+    }
+    match json {
+        Some(json) => Ok((json, binary)),
+        None => Err("the GLB file has no JSON chunk".to_owned()),
+    }
+}
+
+// The array of indices at `key`, none if it's missing
+fn indices(object: &Json, key: &str) -> Result<Vec<usize>, String> {
+    match object.find(key).and_then(Json::as_array) {
+        Some(array) => {
+            array.iter().map(|i| i.as_u64().map(|i| i as usize)
+                .ok_or(format!("{} must be indices", key))).collect()
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+// The first three numbers of an array
+fn vec3(json: &Json) -> Option<Vec3> {
+    let array = json.as_array().map_or(&[][..], |a| &a[..]);
+    let number = |i: usize| array.get(i).and_then(Json::as_f64).map(|n| n as Float);
+    match (number(0), number(1), number(2)) {
+        (Some(x), Some(y), Some(z)) => Some(Vec3::new(x, y, z)),
+        _ => None,
+    }
+}
+
+// A node's `matrix`, or its translation, rotation and scale applied in reverse order
+fn node_transform(node: &Json) -> Result<Matrix, String> {
+    let numbers = |key: &str, len: usize| -> Result<Option<Vec<Float>>, String> {
+        match node.find(key).and_then(Json::as_array) {
+            Some(array) => {
+                let values: Vec<Float> = array.iter().filter_map(Json::as_f64)
+                    .map(|n| n as Float).collect();
+                if values.len() == len { Ok(Some(values)) } else {
+                    Err(format!("a node's {} must be {} numbers", key, len))
+                }
+            }
+            None => Ok(None),
+        }
+    };
+    // Column major
+    if let Some(m) = try!(numbers("matrix", 16)) {
+        let mut matrix = IDENTITY;
+        for row in 0..4 {
+            for column in 0..4 {
+                matrix[row][column] = m[column * 4 + row];
+            }
+        }
+        return Ok(matrix);
+    }
+    let t = try!(numbers("translation", 3)).unwrap_or(vec![0., 0., 0.]);
+    let r = try!(numbers("rotation", 4)).unwrap_or(vec![0., 0., 0., 1.]);
+    let s = try!(numbers("scale", 3)).unwrap_or(vec![1., 1., 1.]);
+    let (x, y, z, w) = (r[0], r[1], r[2], r[3]);
+    let rotation = [[1. - 2. * (y * y + z * z), 2. * (x * y - z * w), 2. * (x * z + y * w)],
+                    [2. * (x * y + z * w), 1. - 2. * (x * x + z * z), 2. * (y * z - x * w)],
+                    [2. * (x * z - y * w), 2. * (y * z + x * w), 1. - 2. * (x * x + y * y)]];
+    let mut matrix = IDENTITY;
+    for row in 0..3 {
+        for column in 0..3 {
+            matrix[row][column] = rotation[row][column] * s[column];
+        }
+        matrix[row][3] = t[row];
+    }
+    Ok(matrix)
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut product = [[0.; 4]; 4];
+    for row in 0..4 {
+        for column in 0..4 {
+            product[row][column] = (0..4).map(|i| a[row][i] * b[i][column]).sum();
+        }
+    }
+    product
+}
+
+fn transform_point(m: &Matrix, p: &Vec3) -> Vec3 {
+    transform_dir(m, p) + Vec3::new(m[0][3], m[1][3], m[2][3])
+}
+
+fn transform_dir(m: &Matrix, d: &Vec3) -> Vec3 {
+    Vec3::new(m[0][0] * d.x + m[0][1] * d.y + m[0][2] * d.z,
+              m[1][0] * d.x + m[1][1] * d.y + m[1][2] * d.z,
+              m[2][0] * d.x + m[2][1] * d.y + m[2][2] * d.z)
+}
+
+// Of the rotation and scale part
+fn determinant(m: &Matrix) -> Float {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+    m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+    m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+// The matrix that transforms normals like `m` transforms surfaces: the inverse transpose of the
+// rotation and scale part, short of the scale, which normalizing undoes anyway
+fn normal_matrix(m: &Matrix) -> Matrix {
+    let sign = if determinant(m) < 0. { -1. } else { 1. };
+    let cofactor = |row: usize, column: usize| {
+        let (r0, r1) = ((row + 1) % 3, (row + 2) % 3);
+        let (c0, c1) = ((column + 1) % 3, (column + 2) % 3);
+        (m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]) * sign
+    };
+    let mut normal = IDENTITY;
+    for row in 0..3 {
+        for column in 0..3 {
+            normal[row][column] = cofactor(row, column);
+        }
+    }
+    normal
+}
+
+// URIs escape spaces and other characters in file names as %20 and so on
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) if bytes[i] == b'%' => {
+                decoded.push(byte);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
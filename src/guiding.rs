@@ -0,0 +1,144 @@
+// Path guiding for environment light. Importance sampling the environment map finds its bright
+// parts, but not whether they can be seen: in a room lit through a window, most samples towards
+// the sun hit the walls. A guide learns, for each cell of a grid over the scene, how much light
+// actually arrives from each direction, and environment samples then go half to the map and half
+// to the guide.
+//
+// The guide is learned in a pass before rendering, from points seen from the camera in every
+// direction, so that tiles and regions of a frame all use the same guide and the result stays
+// independent of the tile order.
+
+use std::cmp;
+
+use {Float, Scene, Vec3};
+use float::consts::PI;
+use bounds::Aabb;
+use log::{self, Level};
+use nalgebra::{dot, Norm};
+use post::luminance;
+use ray::{Ray, RayKind};
+use sampling::{self, cdf, pick};
+use sh::sphere_points;
+
+// Cells per axis of the grid
+const RESOLUTION: usize = 8;
+// Directions are binned into bands of equal height along y, each split into sectors around it,
+// so that all bins cover the same solid angle
+const BANDS: usize = 8;
+const SECTORS: usize = 16;
+// Rays from the camera whose hits the guide learns from
+const TRAINING_RAYS: u32 = 4096;
+// The grid spans the nearest hits, leaving out far away ones on e.g. infinite planes
+const TRAINING_COVERAGE: Float = 0.9;
+
+pub struct Guide {
+    bounds: Aabb,
+    // Distribution over the direction bins for each cell, empty if no light was seen there
+    cells: Vec<Vec<Float>>,
+}
+
+impl Guide {
+    // Learns from `samples` environment samples at each training point
+    pub fn learn(scene: &Scene, samples: u32) -> Guide {
+        let _span = log::span(Level::Info, "learn path guide");
+        let map = match scene.environment {
+            Some((ref map, _)) => map,
+            None => panic!("Path guiding needs an environment map"),
+        };
+
+        let origin = *scene.camera().pos();
+        let mut hits: Vec<_> = sphere_points(TRAINING_RAYS).into_iter()
+            .filter_map(|dir| scene.closest_hit(&Ray::new(origin, dir)).map(|(_, hit)| hit))
+            .collect();
+        hits.sort_by(|a, b| a.dist.partial_cmp(&b.dist).unwrap());
+        let nearest = cmp::max((hits.len() as Float * TRAINING_COVERAGE).ceil() as usize, 1);
+        let bounds = hits.iter().take(nearest).fold(None, |bounds: Option<Aabb>, hit| {
+            let point = Aabb::new(hit.pos, hit.pos);
+            Some(bounds.map_or(point, |bounds| bounds.union(&point)))
+        });
+        let mut guide = Guide {
+            bounds: bounds.unwrap_or(Aabb::new(origin, origin)),
+            cells: vec![Vec::new(); RESOLUTION * RESOLUTION * RESOLUTION],
+        };
+
+        let mut weights = vec![vec![0.; BANDS * SECTORS]; guide.cells.len()];
+        for (n, hit) in hits.iter().enumerate() {
+            let start = scene.bias.origin(hit, &hit.normal);
+            for i in 0..samples {
+                let (dir, pdf) = match map.sample(sampling::uniform(n as u32, i, 1),
+                                                  sampling::uniform(n as u32, i, 2)) {
+                    Some(sample) => sample,
+                    None => break,
+                };
+                let cos = dot(&hit.normal, &dir);
+                let ray = Ray::new(start, dir).with_kind(RayKind::Shadow);
+                if cos > 0. && scene.closest_hit(&ray).is_none() {
+                    weights[guide.cell(&hit.pos)][bin(&dir)] +=
+                        luminance(&map.lookup(&dir)) * cos / pdf;
+                }
+            }
+        }
+        guide.cells = weights.iter().map(|weights| cdf(weights)).collect();
+        let learned = guide.cells.iter().filter(|cell| !cell.is_empty()).count();
+        debug!("Path guide saw light in {} of {} cells", learned, guide.cells.len());
+        guide
+    }
+
+    // Whether light reached the cell of `pos` while learning
+    pub fn has_light(&self, pos: &Vec3) -> bool {
+        !self.cells[self.cell(pos)].is_empty()
+    }
+
+    // Maps two uniform numbers in 0..1 to a direction, more likely towards where light reached
+    // the cell of `pos`. Returns the direction and its probability density per steradian, or
+    // None if the guide has seen no light there
+    pub fn sample(&self, pos: &Vec3, u1: Float, u2: Float) -> Option<(Vec3, Float)> {
+        let cell = &self.cells[self.cell(pos)];
+        let i = match pick(cell, u1) {
+            Some(i) => i,
+            None => return None,
+        };
+
+        // Reuse the fraction of u1 within the bin, so the direction is spread over it
+        let (low, high) = (if i > 0 { cell[i - 1] } else { 0. }, cell[i]);
+        let u = ((u1 - low) / (high - low)).max(0.).min(1.);
+        let y = -1. + 2. * ((i / SECTORS) as Float + u) / BANDS as Float;
+        let phi = -PI + 2. * PI * ((i % SECTORS) as Float + u2) / SECTORS as Float;
+        let r = (1. - y * y).max(0.).sqrt();
+        let dir = Vec3::new(r * phi.cos(), y, r * phi.sin());
+        Some((dir, self.pdf_in(cell, i)))
+    }
+
+    // The probability density of `sample` returning `dir` at `pos`, per steradian
+    pub fn pdf(&self, pos: &Vec3, dir: &Vec3) -> Float {
+        let cell = &self.cells[self.cell(pos)];
+        if cell.is_empty() { 0. } else { self.pdf_in(cell, bin(dir)) }
+    }
+
+    fn pdf_in(&self, cell: &[Float], i: usize) -> Float {
+        let p = cell[i] - if i > 0 { cell[i - 1] } else { 0. };
+        p * (BANDS * SECTORS) as Float / (4. * PI)
+    }
+
+    // Index of the cell containing `pos`, or the nearest one outside the grid
+    fn cell(&self, pos: &Vec3) -> usize {
+        let size = self.bounds.size();
+        let index = |p: Float, min: Float, size: Float| {
+            let t = if size > 0. { (p - min) / size } else { 0. };
+            cmp::min((t * RESOLUTION as Float).max(0.) as usize, RESOLUTION - 1)
+        };
+        let x = index(pos.x, self.bounds.min.x, size.x);
+        let y = index(pos.y, self.bounds.min.y, size.y);
+        let z = index(pos.z, self.bounds.min.z, size.z);
+        (z * RESOLUTION + y) * RESOLUTION + x
+    }
+}
+
+// Index of the direction bin containing `dir`
+fn bin(dir: &Vec3) -> usize {
+    let dir = dir.normalize();
+    let band = cmp::min(((dir.y + 1.) / 2. * BANDS as Float) as usize, BANDS - 1);
+    let sector = cmp::min(((dir.z.atan2(dir.x) + PI) / (2. * PI) * SECTORS as Float) as usize,
+                          SECTORS - 1);
+    band * SECTORS + sector
+}
@@ -0,0 +1,285 @@
+use std::cmp;
+use std::io::{self, BufRead, Write};
+
+use {to_rgb, Float, Vec3};
+use color::OutputTransform;
+use dither::{blue_noise_mask, threshold};
+
+use image::{Rgba, RgbaImage, RgbImage};
+
+use nalgebra::clamp;
+
+// An image of unclamped linear colors, where 1 is the brightest displayable value. Colors are
+// premultiplied by an alpha channel, which is opaque unless the scene has a transparent
+// background
+#[derive(Clone)]
+pub struct HdrImage {
+    width: u32,
+    height: u32,
+    pixels: Vec<Vec3>,
+    alpha: Vec<Float>,
+}
+
+impl HdrImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        HdrImage {
+            width: width,
+            height: height,
+            pixels: vec![Vec3::new(0., 0., 0.); (width * height) as usize],
+            alpha: vec![1.; (width * height) as usize],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Vec3 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn put_pixel(&mut self, x: u32, y: u32, color: Vec3) {
+        self.pixels[(y * self.width + x) as usize] = color;
+    }
+
+    pub fn get_alpha(&self, x: u32, y: u32) -> Float {
+        self.alpha[(y * self.width + x) as usize]
+    }
+
+    pub fn put_alpha(&mut self, x: u32, y: u32, alpha: Float) {
+        self.alpha[(y * self.width + x) as usize] = alpha;
+    }
+
+    // Bilinearly interpolates between pixel centers, clamping to the edges
+    pub fn sample(&self, x: Float, y: Float) -> Vec3 {
+        let x = clamp(x - 0.5, 0., (self.width - 1) as Float);
+        let y = clamp(y - 0.5, 0., (self.height - 1) as Float);
+        let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+        let (x1, y1) = (cmp::min(x0 + 1, self.width - 1), cmp::min(y0 + 1, self.height - 1));
+        let (tx, ty) = (x - x0 as Float, y - y0 as Float);
+
+        let top = self.get_pixel(x0, y0) * (1. - tx) + self.get_pixel(x1, y0) * tx;
+        let bottom = self.get_pixel(x0, y1) * (1. - tx) + self.get_pixel(x1, y1) * tx;
+        top * (1. - ty) + bottom * ty
+    }
+
+    // Pixels in row major order
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [Vec3] {
+        &mut self.pixels
+    }
+
+    // Averages each factor x factor block into one pixel
+    pub fn downsample(&self, factor: u32) -> HdrImage {
+        let mut im = HdrImage::new(self.width / factor, self.height / factor);
+        let weight = 1. / (factor * factor) as Float;
+        for y in 0..im.height {
+            for x in 0..im.width {
+                let mut sum = Vec3::new(0., 0., 0.);
+                let mut alpha = 0.;
+                for sy in 0..factor {
+                    for sx in 0..factor {
+                        sum = sum + self.get_pixel(x * factor + sx, y * factor + sy);
+                        alpha += self.get_alpha(x * factor + sx, y * factor + sy);
+                    }
+                }
+                im.put_pixel(x, y, sum * weight);
+                im.put_alpha(x, y, alpha * weight);
+            }
+        }
+        im
+    }
+
+    // Clamps to the displayable range
+    pub fn to_rgb(&self) -> RgbImage {
+        self.encode(OutputTransform::Linear, false)
+    }
+
+    // With `dither`, the colors are dithered with blue noise instead of being rounded down, so
+    // smooth gradients don't band
+    pub fn encode(&self, transform: OutputTransform, dither: bool) -> RgbImage {
+        let mask = if dither { Some(blue_noise_mask()) } else { None };
+        let mut im = RgbImage::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let color = quantize(transform.apply(self.get_pixel(x, y)), x, y, &mask);
+                im.put_pixel(x, y, to_rgb(color));
+            }
+        }
+        im
+    }
+
+    // Reads a Radiance RGBE (.hdr) file, flat or run length encoded. Only the usual top to
+    // bottom, left to right orientation is supported
+    pub fn read_radiance<R: BufRead>(input: &mut R) -> io::Result<HdrImage> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if try!(input.read_line(&mut line)) == 0 {
+                return Err(invalid("Radiance header ends early"));
+            }
+            if line.trim().is_empty() {
+                break;
+            }
+            if line.starts_with("FORMAT=") && line.trim() != "FORMAT=32-bit_rle_rgbe" {
+                return Err(invalid("Only RGBE Radiance images are supported"));
+            }
+        }
+        line.clear();
+        try!(input.read_line(&mut line));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (height, width) = match (fields.get(1).and_then(|h| h.parse().ok()),
+                                     fields.get(3).and_then(|w| w.parse().ok())) {
+            (Some(height), Some(width)) if fields[0] == "-Y" && fields[2] == "+X" => {
+                (height, width)
+            }
+            _ => return Err(invalid("Unsupported Radiance image orientation")),
+        };
+
+        let mut im = HdrImage::new(width, height);
+        let mut row = vec![0; width as usize * 4];
+        for y in 0..height {
+            try!(read_radiance_row(input, &mut row, width));
+            for x in 0..width {
+                let rgbe = &row[x as usize * 4..x as usize * 4 + 4];
+                let scale = if rgbe[3] == 0 { 0. } else { Float::powi(2., rgbe[3] as i32 - 136) };
+                let color = Vec3::new(rgbe[0] as Float + 0.5, rgbe[1] as Float + 0.5,
+                                      rgbe[2] as Float + 0.5) * scale;
+                im.put_pixel(x, y, color);
+            }
+        }
+        Ok(im)
+    }
+
+    // Writes the unclamped colors as a Radiance RGBE (.hdr) file, for tone mapping in other tools.
+    // Transparent pixels end up over black
+    pub fn write_radiance<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(write_radiance_header(out, self.width, self.height));
+        self.write_radiance_rows(out)
+    }
+
+    // The scanlines of write_radiance without the header, so a frame can be written a band of
+    // rows at a time
+    pub fn write_radiance_rows<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut row = Vec::with_capacity(self.width as usize * 4);
+        for y in 0..self.height {
+            row.clear();
+            for x in 0..self.width {
+                row.extend_from_slice(&rgbe(self.get_pixel(x, y)));
+            }
+            try!(out.write_all(&row));
+        }
+        Ok(())
+    }
+
+    // Like encode, but keeping the alpha channel, with colors no longer premultiplied
+    pub fn encode_rgba(&self, transform: OutputTransform, dither: bool) -> RgbaImage {
+        let mask = if dither { Some(blue_noise_mask()) } else { None };
+        let mut im = RgbaImage::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let alpha = clamp(self.get_alpha(x, y), 0., 1.);
+                let color = self.get_pixel(x, y) / if alpha > 0. { alpha } else { 1. };
+                let rgb = to_rgb(quantize(transform.apply(color), x, y, &mask));
+                im.put_pixel(x, y, Rgba([rgb.data[0], rgb.data[1], rgb.data[2],
+                                         (alpha * 255.).round() as u8]));
+            }
+        }
+        im
+    }
+
+    // Like encode, or encode_rgba with `alpha`, but rounding to 16 bits per channel, so there's
+    // no banding to dither away. Returns the channels of each pixel in turn
+    pub fn encode_16(&self, transform: OutputTransform, alpha: bool) -> Vec<u16> {
+        let channels = if alpha { 4 } else { 3 };
+        let mut samples = Vec::with_capacity(self.pixels.len() * channels);
+        let to_16 = |value: Float| (clamp(value, 0., 1.) * 65535.).round() as u16;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let a = if alpha { clamp(self.get_alpha(x, y), 0., 1.) } else { 1. };
+                let color = self.get_pixel(x, y) / if a > 0. { a } else { 1. };
+                let color = transform.apply(color);
+                samples.extend_from_slice(&[to_16(color.x), to_16(color.y), to_16(color.z)]);
+                if alpha {
+                    samples.push(to_16(a));
+                }
+            }
+        }
+        samples
+    }
+}
+
+pub fn write_radiance_header<W: Write>(out: &mut W, width: u32, height: u32) -> io::Result<()> {
+    write!(out, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width)
+}
+
+// Reads one scanline of RGBE pixels into `row`. Run length encoded scanlines start with 2, 2
+// and the width, then hold each channel in turn as runs of one repeated byte and literal bytes
+fn read_radiance_row<R: BufRead>(input: &mut R, row: &mut [u8], width: u32) -> io::Result<()> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Bad Radiance scanline");
+    let mut start = [0; 4];
+    try!(input.read_exact(&mut start));
+    if width < 8 || width > 0x7fff || start[0] != 2 || start[1] != 2 ||
+       ((start[2] as u32) << 8 | start[3] as u32) != width {
+        row[..4].copy_from_slice(&start);
+        return input.read_exact(&mut row[4..]);
+    }
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width as usize {
+            let mut count = [0; 1];
+            try!(input.read_exact(&mut count));
+            let (count, repeat) = if count[0] > 128 {
+                (count[0] as usize - 128, true)
+            } else {
+                (count[0] as usize, false)
+            };
+            if count == 0 || x + count > width as usize {
+                return Err(invalid());
+            }
+            let mut value = [0; 1];
+            for i in 0..count {
+                if i == 0 || !repeat {
+                    try!(input.read_exact(&mut value));
+                }
+                row[(x + i) * 4 + channel] = value[0];
+            }
+            x += count;
+        }
+    }
+    Ok(())
+}
+
+// Radiance's shared exponent encoding: a mantissa for each channel of `color` and an exponent for
+// the brightest of them
+fn rgbe(color: Vec3) -> [u8; 4] {
+    let color = Vec3::new(color.x.max(0.), color.y.max(0.), color.z.max(0.));
+    let max = color.x.max(color.y).max(color.z);
+    if max < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    let mut exponent = max.log2().floor() as i32 + 1;
+    if max * Float::powi(2., 8 - exponent) >= 256. {
+        exponent += 1;
+    }
+    let scale = Float::powi(2., 8 - exponent);
+    [(color.x * scale) as u8, (color.y * scale) as u8, (color.z * scale) as u8,
+     (exponent + 128) as u8]
+}
+
+// Adds the dither threshold for (x, y), in 8 bit steps, to an encoded color, so that rounding it
+// down to 8 bits rounds up with the probability of its fraction
+fn quantize(color: Vec3, x: u32, y: u32, mask: &Option<Vec<Float>>) -> Vec3 {
+    match *mask {
+        Some(ref mask) => color + Vec3::new(1., 1., 1.) * (threshold(mask, x, y) / 255.),
+        None => color,
+    }
+}
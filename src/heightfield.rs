@@ -0,0 +1,275 @@
+// Terrain from a grid of heights, e.g. the pixels of a grayscale image, without building a mesh
+// of its triangles. Each cell between four neighboring heights is split into two triangles, with
+// the normals smoothed across cells. A ray walks over the grid cell by cell in the order it
+// crosses them (a 2D DDA), and only tests the triangles of the cells whose range of heights it
+// passes through, so the first cell with a hit holds the nearest one and the search stops there.
+
+use std::cmp;
+use std::mem;
+
+use {float, Float, Vec3};
+use bounds::{box_interval, Aabb};
+use material::Material;
+use ray::{Intersection, Ray};
+use surface::Surface;
+
+use image;
+
+use nalgebra::{cross, dot, Norm};
+
+pub struct Heightfield {
+    // columns x rows heights above `pos`, row by row, each row along x and the rows along z
+    heights: Vec<Float>,
+    normals: Vec<Vec3>,
+    // The lowest and highest corner of each cell, row by row
+    ranges: Vec<(Float, Float)>,
+    columns: usize,
+    rows: usize,
+    // The corner at the first height, and the size of a cell along x and z
+    pos: Vec3,
+    cell: (Float, Float),
+    bounds: Aabb,
+    material: Material,
+}
+
+impl Heightfield {
+    // `heights` are columns x rows values, row by row, scaled by `size.y` and spread evenly over
+    // `size.x` along x and `size.z` along z from `pos`
+    pub fn new(heights: Vec<Float>, columns: usize, rows: usize, pos: Vec3, size: Vec3,
+               material: Material)
+               -> Self {
+        assert!(columns >= 2 && rows >= 2, "Heightfields need at least 2 x 2 heights");
+        assert!(heights.len() == columns * rows,
+                "A {} x {} heightfield needs {} heights, not {}", columns, rows, columns * rows,
+                heights.len());
+        assert!(size.x > 0. && size.z > 0., "Heightfields need a positive width and depth");
+        let heights: Vec<Float> = heights.iter().map(|h| h * size.y).collect();
+        let cell = (size.x / (columns - 1) as Float, size.z / (rows - 1) as Float);
+
+        // Central differences inside the grid, one sided at its edges
+        let mut normals = Vec::with_capacity(heights.len());
+        for j in 0..rows {
+            for i in 0..columns {
+                let at = |i: usize, j: usize| heights[j * columns + i];
+                let (i0, i1) = (i.saturating_sub(1), cmp::min(i + 1, columns - 1));
+                let (j0, j1) = (j.saturating_sub(1), cmp::min(j + 1, rows - 1));
+                let dx = (at(i1, j) - at(i0, j)) / ((i1 - i0) as Float * cell.0);
+                let dz = (at(i, j1) - at(i, j0)) / ((j1 - j0) as Float * cell.1);
+                normals.push(Vec3::new(-dx, 1., -dz).normalize());
+            }
+        }
+
+        let mut ranges = Vec::with_capacity((columns - 1) * (rows - 1));
+        for j in 0..rows - 1 {
+            for i in 0..columns - 1 {
+                let corners = [heights[j * columns + i], heights[j * columns + i + 1],
+                               heights[(j + 1) * columns + i], heights[(j + 1) * columns + i + 1]];
+                ranges.push(range(&corners));
+            }
+        }
+
+        let (low, high) = range(&heights);
+        let bounds = Aabb::new(pos + Vec3::new(0., low, 0.), pos + Vec3::new(size.x, high, size.z));
+        Heightfield {
+            heights: heights,
+            normals: normals,
+            ranges: ranges,
+            columns: columns,
+            rows: rows,
+            pos: pos,
+            cell: cell,
+            bounds: bounds,
+            material: material,
+        }
+    }
+
+    // One height per pixel of a grayscale image, from 0 for black to 1 for white, with the rows of
+    // the image along z
+    pub fn load(filename: &str, pos: Vec3, size: Vec3, material: Material) -> Self {
+        let image = image::open(filename).unwrap().to_luma();
+        let (columns, rows) = image.dimensions();
+        let heights = image.pixels().map(|pixel| pixel.data[0] as Float / 255.).collect();
+        Heightfield::new(heights, columns as usize, rows as usize, pos, size, material)
+    }
+
+    fn corner(&self, i: usize, j: usize) -> Vec3 {
+        self.pos + Vec3::new(i as Float * self.cell.0, self.heights[j * self.columns + i],
+                             j as Float * self.cell.1)
+    }
+
+    // The triangles of cell i, j: corners 00, 10, 11 above the cell's diagonal and 00, 11, 01
+    // below it
+    fn intersect_cell(&self, ray: &Ray, i: usize, j: usize) -> Option<Float> {
+        let (c00, c10) = (self.corner(i, j), self.corner(i + 1, j));
+        let (c01, c11) = (self.corner(i, j + 1), self.corner(i + 1, j + 1));
+        let above = intersect_triangle(ray, &c00, &c10, &c11);
+        match (above, intersect_triangle(ray, &c00, &c11, &c01)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    // The height and smooth normal at fractions fx, fz across cell i, j, interpolated over the
+    // triangle they're in
+    fn interpolate(&self, i: usize, j: usize, fx: Float, fz: Float) -> (Float, Vec3) {
+        let index = |i: usize, j: usize| j * self.columns + i;
+        let (i00, i10, i01, i11) = (index(i, j), index(i + 1, j), index(i, j + 1),
+                                    index(i + 1, j + 1));
+        // Barycentric weights of the corners
+        let (weights, corners) = if fx >= fz {
+            ([1. - fx, fx - fz, fz], [i00, i10, i11])
+        } else {
+            ([1. - fz, fx, fz - fx], [i00, i11, i01])
+        };
+        let mut height = 0.;
+        let mut normal = Vec3::new(0., 0., 0.);
+        for (w, &c) in weights.iter().zip(corners.iter()) {
+            height += self.heights[c] * w;
+            normal = normal + self.normals[c] * *w;
+        }
+        (height, normal.normalize())
+    }
+
+    // The cell that x, z is over and how far across it, clamped to the grid
+    fn locate(&self, x: Float, z: Float) -> (usize, usize, Float, Float) {
+        let locate = |offset: Float, size: Float, cells: usize| {
+            let t = (offset / size).max(0.);
+            let index = cmp::min(t.floor() as usize, cells - 1);
+            (index, (t - index as Float).min(1.))
+        };
+        let (i, fx) = locate(x - self.pos.x, self.cell.0, self.columns - 1);
+        let (j, fz) = locate(z - self.pos.z, self.cell.1, self.rows - 1);
+        (i, j, fx, fz)
+    }
+
+    fn hit(&self, ray: &Ray, dist: Float) -> Intersection {
+        let pos = ray.origin + ray.dir * dist;
+        let (i, j, fx, fz) = self.locate(pos.x, pos.z);
+        let (_, normal) = self.interpolate(i, j, fx, fz);
+        let u = (i as Float + fx) / (self.columns - 1) as Float;
+        let v = (j as Float + fz) / (self.rows - 1) as Float;
+
+        let material = &self.material;
+        let normal = if material.has_normal_map() {
+            material.apply_normal_map(&normal, &pos)
+        } else {
+            normal
+        };
+        let pos = if material.has_displacement_map() {
+            material.apply_displacement_map(&pos)
+        } else {
+            pos
+        };
+        Intersection::new(pos, normal, dist, u, v)
+    }
+}
+
+impl Surface for Heightfield {
+    fn name(&self) -> &'static str {
+        "Heightfield"
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    // u along x and v along z, from 0 to 1 across the whole grid
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        if u < 0. || u > 1. || v < 0. || v > 1. {
+            return None;
+        }
+        let x = self.pos.x + u * self.cell.0 * (self.columns - 1) as Float;
+        let z = self.pos.z + v * self.cell.1 * (self.rows - 1) as Float;
+        let (i, j, fx, fz) = self.locate(x, z);
+        let (height, normal) = self.interpolate(i, j, fx, fz);
+        Some(Intersection::new(Vec3::new(x, self.pos.y + height, z), normal, 0., u, v))
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        let (near, far) = match box_interval(&self.bounds, ray, 0., float::INFINITY) {
+            Some(interval) => interval,
+            None => return None,
+        };
+        let start = ray.origin + ray.dir * near;
+        let (i, j, _, _) = self.locate(start.x, start.z);
+        let (mut i, mut j) = (i as isize, j as isize);
+
+        // The distance along the ray to the next cell boundary on each axis, and between them
+        let boundary = |axis: usize, index: isize, size: Float| {
+            let dir = ray.dir[axis];
+            if dir == 0. {
+                return (float::INFINITY, float::INFINITY);
+            }
+            let next = if dir > 0. { index + 1 } else { index };
+            let edge = self.pos[axis] + next as Float * size;
+            ((edge - ray.origin[axis]) / dir, size / dir.abs())
+        };
+        let (mut next_x, step_x) = boundary(0, i, self.cell.0);
+        let (mut next_z, step_z) = boundary(2, j, self.cell.1);
+        let (di, dj) = (if ray.dir.x > 0. { 1 } else { -1 }, if ray.dir.z > 0. { 1 } else { -1 });
+
+        let mut entry = near;
+        loop {
+            let exit = next_x.min(next_z).min(far);
+            // Only cells whose heights the ray passes through can hold a hit
+            let (y0, y1) = (ray.origin.y + ray.dir.y * entry, ray.origin.y + ray.dir.y * exit);
+            let (low, high) = self.ranges[j as usize * (self.columns - 1) + i as usize];
+            let slack = 1e-4 * (1. + high.abs());
+            if y0.min(y1) <= self.pos.y + high + slack && y0.max(y1) >= self.pos.y + low - slack {
+                if let Some(dist) = self.intersect_cell(ray, i as usize, j as usize) {
+                    return Some(self.hit(ray, dist));
+                }
+            }
+            if exit >= far {
+                return None;
+            }
+            if next_x < next_z {
+                i += di;
+                entry = next_x;
+                next_x += step_x;
+            } else {
+                j += dj;
+                entry = next_z;
+                next_z += step_z;
+            }
+            if i < 0 || j < 0 || i >= self.columns as isize - 1 || j >= self.rows as isize - 1 {
+                return None;
+            }
+        }
+    }
+
+    fn triangle_count(&self) -> usize {
+        2 * (self.columns - 1) * (self.rows - 1)
+    }
+
+    fn heap_size(&self) -> usize {
+        self.heights.len() * mem::size_of::<Float>() + self.normals.len() * mem::size_of::<Vec3>() +
+        self.ranges.len() * mem::size_of::<(Float, Float)>()
+    }
+}
+
+// The lowest and highest of `values`
+fn range(values: &[Float]) -> (Float, Float) {
+    values.iter()
+        .fold((float::INFINITY, -float::INFINITY), |(low, high), &h| (low.min(h), high.max(h)))
+}
+
+// Möller-Trumbore: the distance to the hit on triangle a, b, c, if any
+fn intersect_triangle(ray: &Ray, a: &Vec3, b: &Vec3, c: &Vec3) -> Option<Float> {
+    let (edge1, edge2) = (*b - *a, *c - *a);
+    let pvec = cross(&ray.dir, &edge2);
+    let det = dot(&edge1, &pvec);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let tvec = ray.origin - *a;
+    let b1 = dot(&tvec, &pvec) / det;
+    let qvec = cross(&tvec, &edge1);
+    let b2 = dot(&ray.dir, &qvec) / det;
+    let dist = dot(&edge2, &qvec) / det;
+    if b1 >= 0. && b2 >= 0. && b1 + b2 <= 1. && dist > 0. { Some(dist) } else { None }
+}
@@ -0,0 +1,129 @@
+// A bounding volume hierarchy over the objects of a scene, built like the ones meshes have over
+// their triangles, so a ray only tests the objects near it. This is what makes scenes with
+// thousands of instances fast. Objects without bounds, like planes, are tested by every ray.
+
+
+use {float, stats, Vec3};
+use bounds::{hits_box, Aabb};
+use bvh::{self, BvhSettings};
+use ray::{Intersection, Ray};
+use surface::Surface;
+
+// Either two children, the first right after the node and the second at `second`, or a leaf with
+// `count` objects starting at `first` in the hierarchy's order
+struct Node {
+    bounds: Aabb,
+    first: usize,
+    count: usize,
+    second: usize,
+}
+
+pub struct Hierarchy {
+    nodes: Vec<Node>,
+    // Indices of the bounded objects, sorted so that each leaf's are next to each other
+    order: Vec<usize>,
+    // By object, unused for unbounded ones
+    bounds: Vec<Aabb>,
+    unbounded: Vec<usize>,
+}
+
+impl Hierarchy {
+    pub fn new(objects: &[Box<Surface>], settings: &BvhSettings) -> Self {
+        let mut hierarchy = Hierarchy { nodes: Vec::new(), order: Vec::new(),
+                                        bounds: Vec::new(), unbounded: Vec::new() };
+        for (i, obj) in objects.iter().enumerate() {
+            let bounds = obj.bounds();
+            match bounds {
+                Some(_) => hierarchy.order.push(i),
+                None => hierarchy.unbounded.push(i),
+            }
+            let origin = Vec3::new(0., 0., 0.);
+            hierarchy.bounds.push(bounds.unwrap_or(Aabb::new(origin, origin)));
+        }
+        let count = hierarchy.order.len();
+        if count > 0 {
+            hierarchy.build(0, count, settings);
+        }
+        hierarchy
+    }
+
+    fn centroid(&self, object: usize) -> Vec3 {
+        let bounds = &self.bounds[object];
+        (bounds.min + bounds.max) / 2.
+    }
+
+    // Adds the nodes for order[first..first + count], split where bvh::split says
+    fn build(&mut self, first: usize, count: usize, settings: &BvhSettings) {
+        let bounds: Vec<Aabb> = self.order[first..first + count].iter()
+            .map(|&i| self.bounds[i])
+            .collect();
+        let all = bounds.iter().skip(1).fold(bounds[0], |all, b| all.union(b));
+        let index = self.nodes.len();
+        self.nodes.push(Node { bounds: all, first: first, count: count, second: 0 });
+
+        let centers: Vec<Vec3> = self.order[first..first + count].iter()
+            .map(|&i| self.centroid(i))
+            .collect();
+        let (axis, half) = match bvh::split(&bounds, &centers, settings) {
+            Some(split) => split,
+            None => return,
+        };
+        {
+            let bounds = &self.bounds;
+            let key = |i: usize| bounds[i].min[axis] + bounds[i].max[axis];
+            self.order[first..first + count]
+                .sort_by(|&a, &b| key(a).partial_cmp(&key(b)).unwrap());
+        }
+
+        self.nodes[index].count = 0;
+        self.build(first, half, settings);
+        self.nodes[index].second = self.nodes.len();
+        self.build(first + half, count - half, settings);
+    }
+
+    // The closest of the hits `hit` gives for the objects `ray` may reach, skipping those whose
+    // bounds lie beyond the closest hit so far. On ties the object listed first wins
+    pub fn closest<F>(&self, ray: &Ray, mut hit: F) -> Option<(usize, Intersection)>
+        where F: FnMut(usize) -> Option<Intersection>
+    {
+        let mut closest: Option<(usize, Intersection)> = None;
+        {
+            let mut test = |i: usize, closest: &mut Option<(usize, Intersection)>| {
+                if let Some(hit) = hit(i) {
+                    let better = closest.as_ref().map_or(true, |&(old, ref old_hit)| {
+                        hit.dist < old_hit.dist || (hit.dist == old_hit.dist && i < old)
+                    });
+                    if better {
+                        *closest = Some((i, hit));
+                    }
+                }
+            };
+            for &i in self.unbounded.iter() {
+                test(i, &mut closest);
+            }
+            if self.nodes.is_empty() {
+                return closest;
+            }
+
+            let inv_dir = Vec3::new(1. / ray.dir.x, 1. / ray.dir.y, 1. / ray.dir.z);
+            let mut stack = vec![0];
+            while let Some(index) = stack.pop() {
+                stats::count_node_visit();
+                let node = &self.nodes[index];
+                let limit = closest.as_ref().map_or(float::INFINITY, |c| c.1.dist);
+                if !hits_box(&node.bounds, ray, &inv_dir, limit) {
+                    continue;
+                }
+                if node.count == 0 {
+                    stack.push(node.second);
+                    stack.push(index + 1);
+                    continue;
+                }
+                for &i in self.order[node.first..node.first + node.count].iter() {
+                    test(i, &mut closest);
+                }
+            }
+        }
+        closest
+    }
+}
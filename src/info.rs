@@ -0,0 +1,75 @@
+// Summary of what's in a scene, for `--info` and for tools checking what they loaded
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+
+use Scene;
+use bounds::Aabb;
+use light::PointLight;
+
+pub struct SceneStats {
+    // Number of objects of each surface type
+    pub objects: BTreeMap<&'static str, usize>,
+    // Triangles of all meshes
+    pub triangles: usize,
+    pub lights: usize,
+    // Bounds of all finite objects, or None if there are none
+    pub bounds: Option<Aabb>,
+    // Objects like planes that extend forever and aren't included in the bounds
+    pub unbounded: usize,
+    // Bytes used by the scene's objects and lights, not counting textures
+    pub memory: usize,
+}
+
+impl Scene {
+    pub fn stats(&self) -> SceneStats {
+        let mut objects = BTreeMap::new();
+        let mut bounds: Option<Aabb> = None;
+        let mut unbounded = 0;
+        let mut triangles = 0;
+        let mut memory = mem::size_of::<Scene>();
+        for obj in self.objects.iter() {
+            *objects.entry(obj.name()).or_insert(0) += 1;
+            match obj.bounds() {
+                Some(b) => bounds = Some(bounds.map_or(b, |bounds| bounds.union(&b))),
+                None => unbounded += 1,
+            }
+            triangles += obj.triangle_count();
+            memory += mem::size_of_val(&**obj) + mem::size_of_val(obj) + obj.heap_size();
+        }
+        memory += self.lights.len() * mem::size_of::<PointLight>();
+
+        SceneStats {
+            objects: objects,
+            triangles: triangles,
+            lights: self.lights.len(),
+            bounds: bounds,
+            unbounded: unbounded,
+            memory: memory,
+        }
+    }
+}
+
+impl fmt::Display for SceneStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total: usize = self.objects.values().sum();
+        try!(writeln!(f, "objects:   {}", total));
+        for (name, count) in self.objects.iter() {
+            try!(writeln!(f, "  {:<8} {}", name, count));
+        }
+        try!(writeln!(f, "triangles: {}", self.triangles));
+        try!(writeln!(f, "lights:    {}", self.lights));
+        match self.bounds {
+            Some(b) => {
+                try!(writeln!(f, "bounds:    ({:.2}, {:.2}, {:.2}) to ({:.2}, {:.2}, {:.2})",
+                              b.min.x, b.min.y, b.min.z, b.max.x, b.max.y, b.max.z));
+            }
+            None => try!(writeln!(f, "bounds:    none")),
+        }
+        if self.unbounded > 0 {
+            try!(writeln!(f, "  plus {} unbounded", self.unbounded));
+        }
+        writeln!(f, "memory:    {:.1} KiB", self.memory as f64 / 1024.)
+    }
+}
@@ -0,0 +1,364 @@
+// `--interactive` flies the camera through the scene, with the view shown in the `--preview`
+// file. There's no window to take input from, so the controls are keys in the terminal: W, A,
+// S and D move forward, left, back and right, Q and E move down and up, and the arrow keys or
+// I, J, K and L look around. In terminals that report the mouse, dragging looks around too. +
+// and - double and halve the speed of moving, C prints the camera as a scene file snippet, P
+// prints what the pixel in the middle of the view hits and how it's shaded, space pauses and
+// resumes rendering, and X or ctrl-C quits.
+//
+// Passes of one sample per pixel are averaged into the preview until the camera moves, which
+// starts the average over. While paused no passes are rendered, so the preview stays as it is;
+// moving meanwhile starts over once rendering resumes.
+//
+// The scene file is watched while flying, and reloaded when it's saved, keeping where the camera
+// has flown to. Saving a change that can't alter the image, to comments, formatting or where the
+// file puts the camera, keeps the passes averaged so far; anything else starts over with the
+// new scene. A file that doesn't parse or validate is reported and the old scene kept.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use libc;
+use nalgebra::{cross, dot, Norm};
+use toml;
+
+use tracerlib::{ray_trace_events, Float, RenderEvent, Scene, Vec3};
+use tracerlib::accumulate::Accumulator;
+use tracerlib::dump::trace_pixel;
+use tracerlib::float::consts::FRAC_PI_2;
+use tracerlib::path::Integrator;
+
+use super::{parse_toml, try_load_scene, Config};
+use preview::Preview;
+use validate;
+
+// Passes averaged before waiting for the camera to move again
+const MAX_PASSES: u32 = 256;
+// Radians per key press, and per character cell dragged with the mouse
+const KEY_TURN: Float = 0.05;
+const DRAG_TURN: Float = 0.02;
+// Keeps the camera from turning over, where `up` would be parallel to the view
+const MAX_PITCH: Float = FRAC_PI_2 - 0.01;
+// How often the scene file is checked for changes once all passes are done
+const WATCH_INTERVAL_MS: u64 = 250;
+
+enum Input {
+    // Right, up and forward, in steps
+    Move(Float, Float, Float),
+    // Radians to the right and up
+    Turn(Float, Float),
+    Speed(Float),
+    Print,
+    Pick,
+    Pause,
+    Quit,
+}
+
+// `up` is the world's up, which turning left and right goes around, and `step` the distance
+// moved per key press to start with. Reloaded scenes get `integrator` too, if given
+pub fn run(config: &Config, mut scene: Scene, integrator: Option<Integrator>, up: Vec3,
+           step: Float) {
+    assert!(config.preview.is_some(), "--interactive requires --preview");
+    assert!(unsafe { libc::isatty(0) } != 0, "--interactive requires a terminal");
+    let (width, height) = (config.width, config.height);
+    let up = up.normalize();
+    let dir = *scene.camera().dir();
+    // The horizontal direction the camera faces to start with, and the one to its right
+    let ahead = (dir - up * dot(&dir, &up)).normalize();
+    let side = cross(&up, &ahead).normalize();
+    let (mut yaw, mut pitch) = (0. as Float, dot(&dir, &up).asin());
+    let mut pos = *scene.camera().pos();
+    let mut step = step;
+    let mut scene_file = SceneFile::new(format!("scenes/{}", config.scene));
+
+    let _terminal = RawTerminal::enable();
+    let inputs = read_inputs();
+    let mut preview = Preview::new(config.preview.clone());
+    let mut sum = Accumulator::new(width, height);
+    let mut passes = 0;
+    let mut paused = false;
+    // Since the average was last started over, so the first pass starts it too
+    let mut moved = true;
+    info!("Move with WASD, Q and E, look with the arrow keys, pick with P, pause with space, \
+           quit with X");
+    loop {
+        let mut pending: Vec<Input> = inputs.try_iter().collect();
+        if pending.is_empty() && (passes == MAX_PASSES || paused) {
+            match inputs.recv_timeout(Duration::from_millis(WATCH_INTERVAL_MS)) {
+                Ok(input) => pending.push(input),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        for input in pending {
+            let facing = ahead * yaw.cos() + side * yaw.sin();
+            let dir = facing * pitch.cos() + up * pitch.sin();
+            match input {
+                Input::Move(x, y, z) => {
+                    let right = cross(&up, &dir).normalize();
+                    pos = pos + (right * x + up * y + dir * z) * step;
+                    moved = true;
+                }
+                Input::Turn(right, raise) => {
+                    yaw += right;
+                    pitch = (pitch + raise).max(-MAX_PITCH).min(MAX_PITCH);
+                    moved = true;
+                }
+                Input::Speed(factor) => step *= factor,
+                Input::Print => print_camera(&pos, &dir, &up, step),
+                Input::Pick => {
+                    let camera = scene.camera().moved_to(pos, dir, up);
+                    scene.set_camera(camera);
+                    print_pick(config, &scene);
+                }
+                Input::Pause => {
+                    paused = !paused;
+                    info!("{} after {} passes", if paused { "Paused" } else { "Resumed" },
+                          passes);
+                }
+                Input::Quit => return,
+            }
+        }
+        if let Some(toml) = scene_file.changed() {
+            match try_load_scene(&toml, None) {
+                Ok(mut reloaded) => {
+                    if let Some(integrator) = integrator {
+                        reloaded.set_integrator(integrator);
+                    }
+                    scene = reloaded;
+                    info!("Reloaded {}", scene_file.path);
+                    moved = true;
+                }
+                Err(error) => warn!("Keeping the scene as it was: {}", error),
+            }
+        }
+        if paused || (passes == MAX_PASSES && !moved) {
+            continue;
+        }
+        if moved {
+            let facing = ahead * yaw.cos() + side * yaw.sin();
+            let dir = facing * pitch.cos() + up * pitch.sin();
+            let camera = scene.camera().moved_to(pos, dir, up);
+            scene.set_camera(camera);
+            sum = Accumulator::new(width, height);
+            passes = 0;
+            moved = false;
+        }
+
+        scene.set_seed(config.seed.wrapping_add(passes));
+        let image = ray_trace_events(&scene, width, height, config.reflection_depth, 1,
+                                     config.tile_order, |_| {});
+        sum.add(&image);
+        passes += 1;
+        let mut average = sum.image();
+        config.post.apply(&mut average, &scene);
+        preview.event(config, &scene, &RenderEvent::PassFinished { image: &average });
+    }
+}
+
+// The scene file as last loaded, to tell when it's saved again and how much changed
+struct SceneFile {
+    path: String,
+    modified: Option<SystemTime>,
+    toml: Option<toml::Value>,
+}
+
+impl SceneFile {
+    fn new(path: String) -> Self {
+        let modified = modified(&path);
+        let toml = parse_toml(&path).ok();
+        SceneFile { path: path, modified: modified, toml: toml }
+    }
+
+    // The file's new contents if it was saved since the last call and they change more than
+    // where the camera is, which flying overrides anyway
+    fn changed(&mut self) -> Option<toml::Value> {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        let toml = match parse_toml(&self.path) {
+            Ok(toml) => toml,
+            Err(error) => {
+                warn!("Keeping the scene as it was: {}", error);
+                return None;
+            }
+        };
+        let problems = validate::validate_scene(&toml);
+        if !problems.is_empty() {
+            warn!("Keeping the scene as it was, {} is invalid:\n{}", self.path,
+                  problems.join("\n"));
+            return None;
+        }
+        let same = self.toml.as_ref().map_or(false, |old| {
+            without_camera_placement(old) == without_camera_placement(&toml)
+        });
+        self.toml = Some(toml.clone());
+        if same {
+            debug!("{} changed nothing that's rendered", self.path);
+            None
+        } else {
+            Some(toml)
+        }
+    }
+}
+
+// When the file at `path` was last written, if it can be told
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// A scene file's contents without the camera's pos, lookat and up
+fn without_camera_placement(toml: &toml::Value) -> toml::Value {
+    let mut toml = toml.clone();
+    if let toml::Value::Table(ref mut root) = toml {
+        if let Some(&mut toml::Value::Table(ref mut scene)) = root.get_mut("scene") {
+            if let Some(&mut toml::Value::Table(ref mut camera)) = scene.get_mut("camera") {
+                for key in &["pos", "lookat", "up"] {
+                    camera.remove(*key);
+                }
+            }
+        }
+    }
+    toml
+}
+
+// Looking at a point `step` ahead, which is where the camera would be after one key press
+fn print_camera(pos: &Vec3, dir: &Vec3, up: &Vec3, step: Float) {
+    let lookat = *pos + *dir * step;
+    let vec = |v: &Vec3| format!("[{}, {}, {}]", v.x, v.y, v.z);
+    println!("[scene.camera]\npos = {}\nlookat = {}\nup = {}\n", vec(pos), vec(&lookat),
+             vec(up));
+    let _ = io::stdout().flush();
+}
+
+// The ray tree of the pixel in the middle of the view, as --trace-pixel prints it
+fn print_pick(config: &Config, scene: &Scene) {
+    let (x, y) = (config.width / 2, config.height / 2);
+    let dump = trace_pixel(scene, x, y, config.width, config.height, config.reflection_depth);
+    println!("Pixel {},{}:\n{}", x, y, dump);
+    let _ = io::stdout().flush();
+}
+
+// Reads inputs from the terminal on another thread, so the render doesn't wait for them
+fn read_inputs() -> Receiver<Input> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut bytes = stdin.lock().bytes().filter_map(|byte| byte.ok());
+        // Where the mouse was last seen while a button is held
+        let mut drag = None;
+        while let Some(byte) = bytes.next() {
+            let input = match byte {
+                b'\x1b' => escape_sequence(&mut bytes, &mut drag),
+                _ => key(byte),
+            };
+            if let Some(input) = input {
+                if sender.send(input).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+fn key(byte: u8) -> Option<Input> {
+    match byte as char {
+        'w' | 'W' => Some(Input::Move(0., 0., 1.)),
+        's' | 'S' => Some(Input::Move(0., 0., -1.)),
+        'a' | 'A' => Some(Input::Move(-1., 0., 0.)),
+        'd' | 'D' => Some(Input::Move(1., 0., 0.)),
+        'q' | 'Q' => Some(Input::Move(0., -1., 0.)),
+        'e' | 'E' => Some(Input::Move(0., 1., 0.)),
+        'i' | 'I' => Some(Input::Turn(0., KEY_TURN)),
+        'k' | 'K' => Some(Input::Turn(0., -KEY_TURN)),
+        'j' | 'J' => Some(Input::Turn(-KEY_TURN, 0.)),
+        'l' | 'L' => Some(Input::Turn(KEY_TURN, 0.)),
+        '+' | '=' => Some(Input::Speed(2.)),
+        '-' => Some(Input::Speed(0.5)),
+        'c' | 'C' => Some(Input::Print),
+        'p' | 'P' => Some(Input::Pick),
+        ' ' => Some(Input::Pause),
+        // ctrl-C doesn't interrupt in raw mode, so the terminal is restored on the way out
+        'x' | 'X' | '\x03' => Some(Input::Quit),
+        _ => None,
+    }
+}
+
+// The rest of an arrow key, `\x1b[A` to `\x1b[D`, or of a mouse report in SGR mode,
+// `\x1b[<button;x;y` followed by M for presses and motion and m for releases
+fn escape_sequence<I>(bytes: &mut I, drag: &mut Option<(i32, i32)>) -> Option<Input>
+    where I: Iterator<Item = u8>
+{
+    if bytes.next() != Some(b'[') {
+        return None;
+    }
+    match bytes.next() {
+        Some(b'A') => Some(Input::Turn(0., KEY_TURN)),
+        Some(b'B') => Some(Input::Turn(0., -KEY_TURN)),
+        Some(b'C') => Some(Input::Turn(KEY_TURN, 0.)),
+        Some(b'D') => Some(Input::Turn(-KEY_TURN, 0.)),
+        Some(b'<') => {
+            let mut report = String::new();
+            let end = loop {
+                match bytes.next() {
+                    Some(b'M') | None => break b'M',
+                    Some(b'm') => break b'm',
+                    Some(byte) => report.push(byte as char),
+                }
+            };
+            let numbers: Vec<i32> = report.split(';').filter_map(|n| n.parse().ok()).collect();
+            if numbers.len() != 3 || end == b'm' {
+                *drag = None;
+                return None;
+            }
+            let (x, y) = (numbers[1], numbers[2]);
+            let last = mem::replace(drag, Some((x, y)));
+            // Bit 32 of the button is set for motion; a press only starts the drag
+            match last {
+                Some((last_x, last_y)) if numbers[0] & 32 != 0 => {
+                    Some(Input::Turn((x - last_x) as Float * DRAG_TURN,
+                                     (last_y - y) as Float * DRAG_TURN))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Keys are read as they're pressed instead of by line, without echoing them, and mouse drags
+// are reported, until this is dropped
+struct RawTerminal {
+    saved: libc::termios,
+}
+
+impl RawTerminal {
+    fn enable() -> Self {
+        let mut saved: libc::termios = unsafe { mem::zeroed() };
+        assert!(unsafe { libc::tcgetattr(0, &mut saved) } == 0, "Can't read terminal settings");
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        unsafe { libc::tcsetattr(0, libc::TCSANOW, &raw) };
+        // Motion while a button is held, in SGR form
+        print!("\x1b[?1002h\x1b[?1006h");
+        let _ = io::stdout().flush();
+        RawTerminal { saved: saved }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[?1006l\x1b[?1002l");
+        let _ = io::stdout().flush();
+        unsafe { libc::tcsetattr(0, libc::TCSANOW, &self.saved) };
+    }
+}
@@ -0,0 +1,305 @@
+// A baseline JPEG encoder for 8 bit RGB images, since the image crate's has no quality setting.
+// Colors are converted to YCbCr and each channel is split into 8 x 8 blocks at full resolution,
+// without chroma subsampling, which would smear the edges of colored objects. The discrete cosine
+// transform of each block is divided by the example quantization tables of the JPEG standard,
+// scaled by the quality the way libjpeg does, and written with the standard's Huffman tables.
+
+use std::io::{self, Write};
+
+// Annex K of the standard, in row major order
+const LUMA_QUANTIZATION: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+const CHROMA_QUANTIZATION: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+// The row major index of each coefficient in the order they're written, from low to high
+// frequencies
+const ZIGZAG: [u8; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10,
+    17, 24, 32, 25, 18, 11, 4, 5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// The Huffman tables as the number of codes of each length from 1 to 16 bits, then the values
+// they code for, shortest first
+const LUMA_DC_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const CHROMA_DC_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const LUMA_AC_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+const LUMA_AC_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+const CHROMA_AC_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const CHROMA_AC_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+// The code and its length in bits for each value
+struct HuffmanTable {
+    codes: Vec<(u16, u8)>,
+}
+
+impl HuffmanTable {
+    // The canonical codes: counting up through each length, then appending a zero bit for the
+    // next length
+    fn new(bits: &[u8; 16], values: &[u8]) -> Self {
+        let mut codes = vec![(0, 0); 256];
+        let mut code = 0u16;
+        let mut next = 0;
+        for (i, &count) in bits.iter().enumerate() {
+            for _ in 0..count {
+                codes[values[next] as usize] = (code, i as u8 + 1);
+                code += 1;
+                next += 1;
+            }
+            code <<= 1;
+        }
+        HuffmanTable { codes: codes }
+    }
+}
+
+// Collects the entropy coded bits, high bits first, with a zero byte stuffed after each 0xff so
+// it doesn't read as a marker
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u16, length: u8) {
+        self.bits = self.bits << length | value as u32 & ((1 << length) - 1);
+        self.count += length as u32;
+        while self.count >= 8 {
+            let byte = (self.bits >> (self.count - 8)) as u8;
+            self.bytes.push(byte);
+            if byte == 0xff {
+                self.bytes.push(0);
+            }
+            self.count -= 8;
+        }
+        self.bits &= (1 << self.count) - 1;
+    }
+
+    fn code(&mut self, table: &HuffmanTable, value: u8) {
+        let (code, length) = table.codes[value as usize];
+        self.write(code, length);
+    }
+
+    // Pads the last byte with ones
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            let padding = 8 - self.count as u8;
+            self.write(0xff, padding);
+        }
+        self.bytes
+    }
+}
+
+// Writes `rgb`, `width` by `height` pixels of 3 bytes each, as a JPEG file (JFIF) of `quality`
+// from 1 to 100, where 50 uses the standard's tables as they are and 100 hardly quantizes at all
+pub fn write_jpeg<W: Write>(out: &mut W, rgb: &[u8], width: u32, height: u32, quality: u8)
+                            -> io::Result<()> {
+    assert!(quality >= 1 && quality <= 100, "JPEG quality must be from 1 to 100, not {}", quality);
+    assert!(width > 0 && height > 0 && width <= 0xffff && height <= 0xffff,
+            "JPEG images are from 1 to 65535 pixels wide and high");
+    assert_eq!(rgb.len(), width as usize * height as usize * 3);
+    let quantization = [scaled_table(&LUMA_QUANTIZATION, quality),
+                        scaled_table(&CHROMA_QUANTIZATION, quality)];
+
+    try!(out.write_all(&[0xff, 0xd8]));
+    try!(segment(out, 0xe0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0"));
+    let mut tables = Vec::with_capacity(130);
+    for (id, table) in quantization.iter().enumerate() {
+        tables.push(id as u8);
+        tables.extend(ZIGZAG.iter().map(|&i| table[i as usize]));
+    }
+    try!(segment(out, 0xdb, &tables));
+    let (w, h) = (width as u16, height as u16);
+    // 8 bit samples, and three components at full resolution, the chroma sharing a table
+    try!(segment(out, 0xc0, &[8, (h >> 8) as u8, h as u8, (w >> 8) as u8, w as u8, 3, 1, 0x11,
+                              0, 2, 0x11, 1, 3, 0x11, 1]));
+    let mut tables = Vec::new();
+    for &(id, bits, values) in &[(0x00, &LUMA_DC_BITS, &DC_VALUES[..]),
+                                 (0x10, &LUMA_AC_BITS, &LUMA_AC_VALUES[..]),
+                                 (0x01, &CHROMA_DC_BITS, &DC_VALUES[..]),
+                                 (0x11, &CHROMA_AC_BITS, &CHROMA_AC_VALUES[..])] {
+        tables.push(id);
+        tables.extend_from_slice(bits);
+        tables.extend_from_slice(values);
+    }
+    try!(segment(out, 0xc4, &tables));
+    try!(segment(out, 0xda, &[3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0]));
+
+    let huffman = [(HuffmanTable::new(&LUMA_DC_BITS, &DC_VALUES),
+                    HuffmanTable::new(&LUMA_AC_BITS, &LUMA_AC_VALUES)),
+                   (HuffmanTable::new(&CHROMA_DC_BITS, &DC_VALUES),
+                    HuffmanTable::new(&CHROMA_AC_BITS, &CHROMA_AC_VALUES))];
+    let mut bits = BitWriter { bytes: Vec::new(), bits: 0, count: 0 };
+    let mut previous_dc = [0; 3];
+    for by in 0..(height + 7) / 8 {
+        for bx in 0..(width + 7) / 8 {
+            let blocks = ycbcr_blocks(rgb, width, height, bx * 8, by * 8);
+            for c in 0..3 {
+                let table = if c == 0 { 0 } else { 1 };
+                let coefficients = quantize(&dct(&blocks[c]), &quantization[table]);
+                let (ref dc, ref ac) = huffman[table];
+                encode_block(&mut bits, &coefficients, &mut previous_dc[c], dc, ac);
+            }
+        }
+    }
+    try!(out.write_all(&bits.finish()));
+    out.write_all(&[0xff, 0xd9])
+}
+
+// A marker and its data, after the length of both
+fn segment<W: Write>(out: &mut W, marker: u8, data: &[u8]) -> io::Result<()> {
+    let length = data.len() + 2;
+    try!(out.write_all(&[0xff, marker, (length >> 8) as u8, length as u8]));
+    out.write_all(data)
+}
+
+// libjpeg's scaling of the quantization steps, coarser below 50 and finer above
+fn scaled_table(table: &[u8; 64], quality: u8) -> [u8; 64] {
+    let quality = quality as u32;
+    let scale = if quality < 50 { 5000 / quality } else { 200 - 2 * quality };
+    let mut scaled = [0; 64];
+    for (s, &step) in scaled.iter_mut().zip(table.iter()) {
+        *s = ((step as u32 * scale + 50) / 100).max(1).min(255) as u8;
+    }
+    scaled
+}
+
+// The 8 x 8 block at (x0, y0) of each of Y, Cb and Cr, centered around 0. Blocks at the right and
+// bottom edges repeat the last column and row
+fn ycbcr_blocks(rgb: &[u8], width: u32, height: u32, x0: u32, y0: u32) -> [[f32; 64]; 3] {
+    let mut blocks = [[0.; 64]; 3];
+    for y in 0..8 {
+        for x in 0..8 {
+            let px = (x0 + x).min(width - 1) as usize;
+            let py = (y0 + y).min(height - 1) as usize;
+            let i = (py * width as usize + px) * 3;
+            let (r, g, b) = (rgb[i] as f32, rgb[i + 1] as f32, rgb[i + 2] as f32);
+            let j = (y * 8 + x) as usize;
+            blocks[0][j] = 0.299 * r + 0.587 * g + 0.114 * b - 128.;
+            blocks[1][j] = -0.168736 * r - 0.331264 * g + 0.5 * b;
+            blocks[2][j] = 0.5 * r - 0.418688 * g - 0.081312 * b;
+        }
+    }
+    blocks
+}
+
+// The two dimensional DCT-II with the standard's scaling, along the rows and then the columns
+fn dct(block: &[f32; 64]) -> [f32; 64] {
+    let mut basis = [[0.; 8]; 8];
+    for (u, row) in basis.iter_mut().enumerate() {
+        let scale = if u == 0 { (0.125f32).sqrt() } else { 0.5 };
+        for (x, b) in row.iter_mut().enumerate() {
+            let angle = (2 * x + 1) as f32 * u as f32 * ::std::f32::consts::PI / 16.;
+            *b = scale * angle.cos();
+        }
+    }
+    let mut rows = [0.; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| basis[u][x] * block[y * 8 + x]).sum();
+        }
+    }
+    let mut coefficients = [0.; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            coefficients[v * 8 + u] = (0..8).map(|y| basis[v][y] * rows[y * 8 + u]).sum();
+        }
+    }
+    coefficients
+}
+
+fn quantize(coefficients: &[f32; 64], table: &[u8; 64]) -> [i32; 64] {
+    let mut quantized = [0; 64];
+    for i in 0..64 {
+        quantized[i] = (coefficients[i] / table[i] as f32).round() as i32;
+    }
+    quantized
+}
+
+// The DC coefficient as the difference from the last block's of the channel, then the AC
+// coefficients in zigzag order as runs of zeros before each other value, coded together with
+// its size in bits, and an end of block code for the zeros after the last
+fn encode_block(bits: &mut BitWriter, coefficients: &[i32; 64], previous_dc: &mut i32,
+                dc: &HuffmanTable, ac: &HuffmanTable) {
+    let (size, value) = magnitude(coefficients[0] - *previous_dc);
+    *previous_dc = coefficients[0];
+    bits.code(dc, size);
+    bits.write(value, size);
+    let mut zeros = 0;
+    for &i in ZIGZAG[1..].iter() {
+        let coefficient = coefficients[i as usize];
+        if coefficient == 0 {
+            zeros += 1;
+            continue;
+        }
+        while zeros > 15 {
+            bits.code(ac, 0xf0);
+            zeros -= 16;
+        }
+        let (size, value) = magnitude(coefficient);
+        bits.code(ac, zeros << 4 | size);
+        bits.write(value, size);
+        zeros = 0;
+    }
+    if zeros > 0 {
+        bits.code(ac, 0x00);
+    }
+}
+
+// The bits needed for `value`, and its bits in them: as is when positive, and one less than it
+// when negative, which leaves the leading bit zero
+fn magnitude(value: i32) -> (u8, u16) {
+    let size = 32 - value.abs().leading_zeros();
+    let bits = if value < 0 { value - 1 } else { value };
+    (size as u8, (bits & ((1 << size) - 1)) as u16)
+}
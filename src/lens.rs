@@ -0,0 +1,90 @@
+// Thin lens depth of field. Rays start from a random point on the aperture and pass through the
+// point on the focal plane the pinhole ray would have hit, so only things at the focus distance
+// are sharp. The shape of the aperture is the shape out of focus highlights take.
+
+use std::cmp;
+
+use Float;
+use float::consts::PI;
+
+use image;
+
+#[derive(Clone, Debug)]
+pub enum Aperture {
+    Disc,
+    // A regular polygon, like the blades of a camera iris. Rotation is in radians
+    Polygon { blades: u32, rotation: Float },
+    // Points in -1..1 where a grayscale mask image is bright, each covering `cell` x `cell`
+    Mask { points: Vec<(Float, Float)>, cell: Float },
+}
+
+impl Aperture {
+    pub fn from_mask(filename: &str) -> Self {
+        let mask = image::open(filename).unwrap().to_luma();
+        let (width, height) = mask.dimensions();
+        // Fit the mask into -1..1 keeping its aspect ratio
+        let cell = 2. / cmp::max(width, height) as Float;
+        let mut points = Vec::new();
+        for (x, y, pixel) in mask.enumerate_pixels() {
+            if pixel.data[0] >= 128 {
+                points.push(((x as Float - width as Float / 2.) * cell,
+                             (y as Float - height as Float / 2.) * cell));
+            }
+        }
+        assert!(!points.is_empty(), "Aperture mask {} is completely dark", filename);
+        debug!("Loaded aperture mask {} ({}x{})", filename, width, height);
+        Aperture::Mask { points: points, cell: cell }
+    }
+
+    // Maps two uniform numbers in 0..1 to a uniformly distributed point on the aperture, in -1..1
+    fn sample(&self, u: Float, v: Float) -> (Float, Float) {
+        match *self {
+            Aperture::Disc => {
+                let r = u.sqrt();
+                let angle = 2. * PI * v;
+                (r * angle.cos(), r * angle.sin())
+            }
+            Aperture::Polygon { blades, rotation } => {
+                // Pick one of the triangles between the center and two adjacent corners, then a
+                // point in it
+                let blade = u * blades as Float;
+                let i = blade.floor();
+                let a = rotation + 2. * PI * i / blades as Float;
+                let b = rotation + 2. * PI * (i + 1.) / blades as Float;
+                let (s, t) = (blade - i, v);
+                let (s, t) = if s + t > 1. { (1. - s, 1. - t) } else { (s, t) };
+                (a.cos() * s + b.cos() * t, a.sin() * s + b.sin() * t)
+            }
+            Aperture::Mask { ref points, cell } => {
+                let n = u * points.len() as Float;
+                let i = cmp::min(n as usize, points.len() - 1);
+                let (x, y) = points[i];
+                // Reuse the fractional part for the position within the cell
+                (x + (n - n.floor()) * cell, y + v * cell)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Lens {
+    radius: Float,
+    focus_dist: Float,
+    aperture: Aperture,
+}
+
+impl Lens {
+    pub fn new(radius: Float, focus_dist: Float, aperture: Aperture) -> Self {
+        Lens { radius: radius, focus_dist: focus_dist, aperture: aperture }
+    }
+
+    pub fn focus_dist(&self) -> Float {
+        self.focus_dist
+    }
+
+    // Offset on the lens in camera right/up units
+    pub fn sample(&self, u: Float, v: Float) -> (Float, Float) {
+        let (x, y) = self.aperture.sample(u, v);
+        (x * self.radius, y * self.radius)
+    }
+}
@@ -1,38 +1,169 @@
 extern crate image;
+extern crate libc;
 extern crate nalgebra;
 extern crate noise;
+extern crate num_cpus;
+extern crate rayon;
+extern crate rustc_serialize;
 
+#[macro_use]
+pub mod log;
+
+pub mod accumulate;
+pub mod adaptive;
+pub mod aov;
+pub mod bake;
+pub mod bounds;
+pub mod bvh;
+pub mod color;
+pub mod compact;
+pub mod csg;
+pub mod debug;
+mod dither;
+pub mod dump;
+pub mod environment;
+pub mod gltf;
+pub mod guiding;
+pub mod hdr;
+pub mod heightfield;
+mod hierarchy;
+pub mod info;
+mod jpeg;
+pub mod lens;
 pub mod light;
 pub mod material;
-mod ray;
+pub mod medium;
+pub mod mesh;
+pub mod occlusion;
+pub mod output;
+pub mod path;
+mod mtl;
+mod ply;
+pub mod portal;
+pub mod post;
+pub mod probes;
+pub mod procedural;
+pub mod quartic;
+pub mod ray;
+pub mod sampler;
+mod sampling;
+pub mod sdf;
+pub mod section;
+pub mod sh;
+pub mod spectral;
+pub mod stats;
+pub mod stereo;
+pub mod subdivision;
+mod stl;
+pub mod subsurface;
 pub mod surface;
 pub mod texture;
+pub mod tiles;
+pub mod transform;
 
-use std::f32;
+use std::cmp;
+use std::str::FromStr;
 
-use light::PointLight;
-use ray::{Intersection, Ray};
+use bvh::BvhSettings;
+use environment::{Background, EnvironmentMap};
+use guiding::Guide;
+use hdr::HdrImage;
+use hierarchy::Hierarchy;
+use lens::Lens;
+use light::{LightSelection, LightShape, PointLight};
+use material::{Backfaces, Compositing, Material};
+use medium::Medium;
+use path::Integrator;
+use portal::Portal;
+use sampler::{Sampler, StratifiedSampler};
+use log::Level;
+use post::{luminance, Imaging};
+use ray::{Bias, Differentials, Hit, Intersection, Ray, RayKind};
+use section::SectionPlane;
+use sh::Sh9;
 use surface::Surface;
+use tiles::{tile_order, TileOrder};
 
 use image::{RgbImage, Rgb, Pixel};
 
+use rayon::prelude::*;
+
 use nalgebra::{clamp, cross, dot, Norm};
 
-pub type Vec3 = nalgebra::Vector3<f32>;
+// The floating point type of all the math, f32 unless built with the f64 feature for scenes large
+// enough that single precision runs out, and its module of constants
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+#[cfg(not(feature = "f64"))]
+pub use std::f32 as float;
+#[cfg(feature = "f64")]
+pub use std::f64 as float;
+
+pub type Vec3 = nalgebra::Vector3<Float>;
+
+// How camera rays fan out over the image. Angles are in degrees, across the image height
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    // The default field of view fits a square of the distance to the camera in the image height
+    Perspective { fov: Float },
+    // Parallel rays over a view `height` in scene units
+    Orthographic { height: Float },
+    // Equidistant: the angle from the view direction grows evenly towards the edges, so a `fov`
+    // beyond 180 degrees sees backwards
+    Fisheye { fov: Float },
+    // All around, 360 degrees across the width and 180 across the height, centered on the view
+    // direction
+    Equirectangular,
+}
+
+// 2 * atan(0.5), in degrees
+const DEFAULT_FOV: Float = 53.1301;
+
+impl FromStr for Projection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "perspective" => Ok(Projection::Perspective { fov: DEFAULT_FOV }),
+            "orthographic" => Ok(Projection::Orthographic { height: 1. }),
+            "fisheye" => Ok(Projection::Fisheye { fov: 180. }),
+            "equirectangular" => Ok(Projection::Equirectangular),
+            _ => Err(format!("Unknown projection: {}", s)),
+        }
+    }
+}
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Camera {
     pos: Vec3,
     dir: Vec3,
     up: Vec3,
     right: Vec3,
+    projection: Projection,
+    lens: Option<Lens>,
+    // Sideways offset of the eye for an omnidirectional stereo panorama, instead of a perspective
+    // image
+    ods: Option<Float>,
+    // Distances along camera rays that hits must lie between
+    near: Float,
+    far: Float,
+    // The times between 0 and 1 that the shutter opens and closes, see Ray::time
+    shutter: (Float, Float),
+    imaging: Imaging,
 }
 
 impl Camera {
     pub fn new(pos: Vec3, dir: Vec3, up: Vec3) -> Self {
-        let right = cross(&up, &dir).normalize();
+        let right = cross(&up, &dir);
+        assert!(right.norm_squared() > 0., "The camera's up vector can't be parallel to its \
+                                            direction");
+        let right = right.normalize();
         let up = cross(&right, &dir).normalize();
-        Camera { pos: pos, dir: dir.normalize(), up: up, right: right }
+        Camera { pos: pos, dir: dir.normalize(), up: up, right: right,
+                 projection: Projection::Perspective { fov: DEFAULT_FOV }, lens: None, ods: None,
+                 near: 0., far: float::INFINITY, shutter: (0., 0.), imaging: Imaging::default() }
     }
 
     pub fn from_lookat(pos: Vec3, lookat: Vec3, up: Vec3) -> Self {
@@ -40,117 +171,1408 @@ impl Camera {
         Camera::new(pos, dir, up)
     }
 
-    fn get_ray(&self, x: u32, y: u32, width: u32, height: u32, aspect_ratio: f32) -> Ray {
-        let norm_x = (x as f32 / width as f32) - 0.5;
-        let norm_y = (y as f32 / height as f32) - 0.5;
-        let norm_x = norm_x * aspect_ratio;
+    pub fn pos(&self) -> &Vec3 {
+        &self.pos
+    }
+
+    pub fn dir(&self) -> &Vec3 {
+        &self.dir
+    }
+
+    // Square to dir, whichever up the camera was made with
+    pub fn up(&self) -> &Vec3 {
+        &self.up
+    }
+
+    // The same camera moved to `pos` and looking along `dir`, e.g. for flying through a scene.
+    // Keeps the projection, lens, clipping, shutter and imaging
+    pub fn moved_to(&self, pos: Vec3, dir: Vec3, up: Vec3) -> Self {
+        let moved = Camera::new(pos, dir, up);
+        Camera { pos: moved.pos, dir: moved.dir, up: moved.up, right: moved.right, ..self.clone() }
+    }
+
+    pub fn with_projection(mut self, projection: Projection) -> Self {
+        match projection {
+            Projection::Perspective { fov } => {
+                assert!(fov > 0. && fov < 180., "Perspective fov must be between 0 and 180")
+            }
+            Projection::Orthographic { height } => {
+                assert!(height > 0., "Orthographic view height must be positive")
+            }
+            Projection::Fisheye { fov } => {
+                assert!(fov > 0. && fov <= 360., "Fisheye fov must be between 0 and 360")
+            }
+            Projection::Equirectangular => {}
+        }
+        self.projection = projection;
+        self
+    }
+
+    // Adds depth of field, the default is a pinhole camera where everything is in focus
+    pub fn with_lens(mut self, lens: Lens) -> Self {
+        self.lens = Some(lens);
+        self
+    }
+
+    // Hides everything closer than `near` or further than `far` from the camera
+    pub fn with_clip(mut self, near: Float, far: Float) -> Self {
+        self.near = near;
+        self.far = far;
+        self
+    }
+
+    // Keeps the shutter open from time `open` to `close`, blurring surfaces that move in
+    // between. The default is an instant exposure at time 0
+    pub fn with_shutter(mut self, open: Float, close: Float) -> Self {
+        assert!(0. <= open && open <= close && close <= 1., "The shutter must open and close \
+                                                               at times between 0 and 1");
+        self.shutter = (open, close);
+        self
+    }
+
+    // Exposure, white balance and vignetting of the camera's frames, applied with the post effects
+    pub fn with_imaging(mut self, imaging: Imaging) -> Self {
+        self.imaging = imaging;
+        self
+    }
+
+    pub fn imaging(&self) -> &Imaging {
+        &self.imaging
+    }
+
+    // Renders one eye of an omnidirectional stereo (ODS) panorama: an equirectangular image
+    // centered on the view direction, where each column is seen from an eye `eye_offset` to the
+    // right of the camera (negative for the left eye) as it turns to face that way. The lens is
+    // ignored
+    pub fn with_ods(mut self, eye_offset: Float) -> Self {
+        self.ods = Some(eye_offset);
+        self
+    }
+
+    fn get_ray(&self, x: u32, y: u32, width: u32, height: u32, aspect_ratio: Float, seed: u32)
+               -> Ray {
+        let x_seed = sampling::reseed(x, seed);
+        let lens_sample = (sampling::uniform(x_seed, y, 1), sampling::uniform(x_seed, y, 2));
+        self.get_sampled_ray(x, y, width, height, aspect_ratio, lens_sample,
+                             sampling::uniform(x_seed, y, 15), 1)
+    }
+
+    // Like get_ray, with the point on the lens and the time in the shutter interval picked by
+    // numbers in 0..1. The ray's differentials reach `spacing` further in x and y, how far apart
+    // the rays of neighbouring samples are
+    fn get_sampled_ray(&self, x: u32, y: u32, width: u32, height: u32, aspect_ratio: Float,
+                       lens_sample: (Float, Float), time_sample: Float, spacing: u32) -> Ray {
+        let (open, close) = self.shutter;
+        let time = if close > open { open + (close - open) * time_sample } else { open };
+        if let Some(eye_offset) = self.ods {
+            return self.ods_ray(x, y, width, height, eye_offset).with_time(time);
+        }
+        let through = |x: u32, y: u32| {
+            self.lens_ray(x as Float / width as Float, y as Float / height as Float,
+                          aspect_ratio, lens_sample)
+        };
+        let (origin, dir) = through(x, y);
+        let ((origin_x, dir_x), (origin_y, dir_y)) = (through(x + spacing, y),
+                                                      through(x, y + spacing));
+        let dir = dir.normalize();
+        let differentials = Differentials {
+            origin_dx: origin_x - origin,
+            origin_dy: origin_y - origin,
+            dir_dx: dir_x.normalize() - dir,
+            dir_dy: dir_y.normalize() - dir,
+        };
+        Ray::new(origin, dir).with_extent(self.near, self.far).with_time(time)
+            .with_differentials(Some(differentials))
+    }
+
+    // The origin and direction of the ray through `fx`, `fy` across and down the image, from 0
+    // to 1, and through the point of the lens at `lens_sample`
+    fn lens_ray(&self, fx: Float, fy: Float, aspect_ratio: Float, lens_sample: (Float, Float))
+                -> (Vec3, Vec3) {
+        let norm_x = (fx - 0.5) * aspect_ratio;
+        let norm_y = fy - 0.5;
+
+        let (origin, dir) = match self.projection {
+            Projection::Perspective { fov } => {
+                let scale = 2. * (fov.to_radians() / 2.).tan();
+                (self.pos, (self.right * norm_x + self.up * norm_y) * scale + self.dir)
+            }
+            Projection::Orthographic { height } => {
+                (self.pos + (self.right * norm_x + self.up * norm_y) * height, self.dir)
+            }
+            Projection::Fisheye { fov } => {
+                let r = (norm_x * norm_x + norm_y * norm_y).sqrt();
+                let angle = r * fov.to_radians();
+                let sideways = if r > 0. {
+                    (self.right * norm_x + self.up * norm_y) / r
+                } else {
+                    Vec3::new(0., 0., 0.)
+                };
+                (self.pos, self.dir * angle.cos() + sideways * angle.sin())
+            }
+            Projection::Equirectangular => {
+                let longitude = (fx - 0.5) * 2. * float::consts::PI;
+                let latitude = (0.5 - fy) * float::consts::PI;
+                (self.pos, self.panorama_dir(longitude, latitude))
+            }
+        };
+        match self.lens {
+            Some(ref lens) => {
+                let focus = origin + dir * lens.focus_dist();
+                let (lens_x, lens_y) = lens.sample(lens_sample.0, lens_sample.1);
+                let origin = origin + self.right * lens_x + self.up * lens_y;
+                (origin, focus - origin)
+            }
+            None => (origin, dir),
+        }
+    }
+
+    // At `longitude` to the right of the view direction and `latitude` above it, both in radians
+    fn panorama_dir(&self, longitude: Float, latitude: Float) -> Vec3 {
+        // self.up points down the image
+        let facing = self.dir * longitude.cos() + self.right * longitude.sin();
+        facing * latitude.cos() - self.up * latitude.sin()
+    }
+
+    fn ods_ray(&self, x: u32, y: u32, width: u32, height: u32, eye_offset: Float) -> Ray {
+        let longitude = (x as Float / width as Float - 0.5) * 2. * float::consts::PI;
+        let latitude = (0.5 - y as Float / height as Float) * float::consts::PI;
+        let dir = self.panorama_dir(longitude, latitude);
+        let eye = self.right * longitude.cos() - self.dir * longitude.sin();
+        Ray::new(self.pos + eye * eye_offset, dir).with_extent(self.near, self.far)
+    }
 
-        let dir = self.right * norm_x + self.up * norm_y + self.dir;
-        Ray::new(self.pos, dir)
+    // The same camera moved sideways by `offset`, positive to the right
+    pub fn shifted(&self, offset: Float) -> Self {
+        let pos = self.pos + self.right * offset;
+        Camera { pos: pos, ..self.clone() }
+    }
+
+    // How much of the image height a sphere of `radius` around `center` spans, 1 where it just
+    // fills it, or infinite with the camera inside it
+    pub fn screen_size(&self, center: &Vec3, radius: Float) -> Float {
+        let dist = (*center - self.pos).norm();
+        if dist <= radius {
+            return float::INFINITY;
+        }
+        let angle = 2. * (radius / dist).asin();
+        match self.projection {
+            Projection::Perspective { fov } => radius / (dist * (fov.to_radians() / 2.).tan()),
+            Projection::Orthographic { height } => 2. * radius / height,
+            Projection::Fisheye { fov } => angle / fov.to_radians(),
+            Projection::Equirectangular => angle / float::consts::PI,
+        }
+    }
+
+    // The inverse of get_ray: where `point` appears in the image, in 0..1 from the top left, or
+    // None if the camera can't see it, e.g. behind a perspective camera
+    pub fn project(&self, point: &Vec3, aspect_ratio: Float) -> Option<(Float, Float)> {
+        let offset = *point - self.pos;
+        let (depth, side, down) = (dot(&offset, &self.dir), dot(&offset, &self.right),
+                                   dot(&offset, &self.up));
+        let (norm_x, norm_y) = match self.projection {
+            Projection::Perspective { .. } | Projection::Orthographic { .. } if depth <= 0. => {
+                return None;
+            }
+            Projection::Perspective { fov } => {
+                let scale = 2. * (fov.to_radians() / 2.).tan();
+                (side / depth / scale, down / depth / scale)
+            }
+            Projection::Orthographic { height } => (side / height, down / height),
+            Projection::Fisheye { fov } => {
+                let sideways = (side * side + down * down).sqrt();
+                if sideways == 0. && depth <= 0. {
+                    return None;
+                }
+                let r = sideways.atan2(depth) / fov.to_radians();
+                let scale = if sideways > 0. { r / sideways } else { 0. };
+                (side * scale, down * scale)
+            }
+            Projection::Equirectangular => {
+                if offset.norm() == 0. {
+                    return None;
+                }
+                let longitude = side.atan2(depth);
+                let latitude = (-down / offset.norm()).asin();
+                return Some((longitude / (2. * float::consts::PI) + 0.5,
+                             0.5 - latitude / float::consts::PI));
+            }
+        };
+        Some((norm_x / aspect_ratio + 0.5, norm_y + 0.5))
+    }
+
+    // Where `point` appears in a width x height image, in the pixel coordinates get_ray takes.
+    // Useful for drawing labels or markers at 3D positions
+    pub fn project_pixel(&self, point: &Vec3, width: u32, height: u32) -> Option<(Float, Float)> {
+        self.project(point, width as Float / height as Float)
+            .map(|(x, y)| (x * width as Float, y * height as Float))
     }
 }
 
+const DEFAULT_EMISSION_SAMPLES: u32 = 16;
+// Back faces a culled camera or reflected ray passes through on one object before it counts as
+// missing it, e.g. for a mesh folded many times over
+const MAX_CULLED_FACES: u32 = 64;
+
 pub struct Scene {
     objects: Vec<Box<Surface>>,
+    hierarchy: Hierarchy,
     lights: Vec<PointLight>,
-    ambient_coeff: f32,
+    ambient_coeff: Float,
     ambient_color: Vec3,
+    // Directional ambient light, replacing ambient_color
+    ambient_sh: Option<Sh9>,
+    // Light from the environment, and the number of directions sampled per shading point
+    environment: Option<(EnvironmentMap, u32)>,
+    // Where environment light actually arrives, to steer the environment samples
+    guide: Option<Guide>,
+    // Openings environment light comes in through, to steer them too
+    portals: Vec<Portal>,
+    // Seen where rays miss everything, if there's no environment
+    background: Background,
+    // Lights sampled per shading point instead of all of them
+    light_samples: Option<u32>,
+    integrator: Integrator,
+    // Varies the random numbers of a render, e.g. of partial renders to be merged
+    seed: u32,
+    // Whether camera rays that miss everything give transparent pixels instead of the background
+    transparent: bool,
+    // Whether camera rays carry a wavelength, see spectral
+    spectral: bool,
+    sections: Vec<SectionPlane>,
+    // Fog or smoke between the surfaces
+    medium: Option<Medium>,
+    // Bounces through transparent surfaces, limited apart from reflections if set
+    refraction_depth: Option<u16>,
+    // Transparent surfaces shadow rays pass through
+    shadow_depth: u16,
+    // Path tracer bounces before Russian roulette starts, and at most
+    path_bounces: (u32, u32),
+    // Spreads the samples of pixels, lenses, area lights and hemispheres
+    sampler: Box<Sampler>,
+    // The emissive objects whose light is sampled like area lights, and the shadow rays traced
+    // to each one from each shading point
+    emitters: Vec<usize>,
+    emission_samples: u32,
+    // How far rays leaving surfaces start off them
+    bias: Bias,
+    // Whether a render includes each light, and the light that isn't from lights, see
+    // set_light_selection
+    selected_lights: Vec<bool>,
+    other_light: bool,
     camera: Camera,
 }
 
 impl Scene {
     pub fn new(objects: Vec<Box<Surface>>,
            lights: Vec<PointLight>,
-           ambient_coeff: f32,
+           ambient_coeff: Float,
            ambient_color: Vec3,
            camera: Camera) -> Self {
+        let emitters = (0..objects.len()).filter(|&i| {
+            objects[i].material().is_emissive() && objects[i].sample_area(0.5, 0.5).is_some()
+        }).collect();
+        let selected_lights = vec![true; lights.len()];
         Scene {
+            hierarchy: Hierarchy::new(&objects, &bvh::OBJECT_SETTINGS),
             objects: objects,
             lights: lights,
             ambient_coeff: ambient_coeff,
             ambient_color: ambient_color,
+            ambient_sh: None,
+            environment: None,
+            guide: None,
+            portals: Vec::new(),
+            background: Background::Color(Vec3::new(0., 0., 0.)),
+            light_samples: None,
+            integrator: Integrator::Whitted,
+            seed: 0,
+            transparent: false,
+            spectral: false,
+            sections: Vec::new(),
+            medium: None,
+            refraction_depth: None,
+            shadow_depth: 0,
+            path_bounces: (path::MIN_BOUNCES, path::MAX_BOUNCES),
+            sampler: Box::new(StratifiedSampler),
+            emitters: emitters,
+            emission_samples: DEFAULT_EMISSION_SAMPLES,
+            bias: Bias::default(),
+            selected_lights: selected_lights,
+            other_light: true,
             camera: camera,
         }
     }
 
+    // The closest surface hit by the ray from `origin` in direction `dir`, for picking and
+    // collision checks
+    pub fn raycast(&self, origin: Vec3, dir: Vec3) -> Option<Hit> {
+        self.closest_hit(&Ray::new(origin, dir)).map(|(object, hit)| {
+            Hit { object: object, pos: hit.pos, normal: hit.normal, dist: hit.dist, u: hit.u,
+                  v: hit.v }
+        })
+    }
+
+    // Whether the segment from `p` to `q` is unobstructed, e.g. for line of sight checks. Hits
+    // right at either end are ignored, so the points can lie on surfaces. It's traced as a shadow
+    // ray, so objects that cast no shadows don't block it, and ones hidden from the camera do
+    pub fn visible(&self, p: Vec3, q: Vec3) -> bool {
+        let dist = (q - p).norm();
+        let tolerance = float::EPSILON.sqrt();
+        if dist <= 2. * tolerance {
+            return true;
+        }
+        let dir = (q - p) / dist;
+        let ray = Ray::new(p + dir * tolerance, dir).with_kind(RayKind::Shadow);
+        match self.closest_hit(&ray) {
+            Some((_, hit)) => hit.dist >= dist - 2. * tolerance,
+            None => true,
+        }
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn set_camera(&mut self, camera: Camera) {
+        self.camera = camera;
+    }
+
+    // Uses an environment (e.g. from EnvironmentMap::to_sh) for the ambient light, so it comes
+    // from some directions more than others
+    pub fn set_ambient_environment(&mut self, sh: Sh9) {
+        self.ambient_sh = Some(sh);
+    }
+
+    // Lights the scene with an environment map, sampling it in `samples` directions wherever a
+    // surface is shaded. Rays that miss everything see the environment
+    pub fn set_environment(&mut self, map: EnvironmentMap, samples: u32) {
+        self.environment = Some((map, samples));
+    }
+
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+    }
+
+    // Learns a path guide for the environment set with set_environment, from `samples`
+    // environment samples at each training point. Needs to be called again after changing the
+    // scene
+    pub fn learn_guide(&mut self, samples: u32) {
+        self.guide = None;
+        let guide = Guide::learn(self, samples);
+        self.guide = Some(guide);
+    }
+
+    // Sends environment samples through `portal` too, see portal
+    pub fn add_portal(&mut self, portal: Portal) {
+        self.portals.push(portal);
+    }
+
+    // For scenes with many lights: each shaded point traces shadow rays to only `samples` of
+    // them, picked by how much they would light it if nothing were in the way
+    pub fn set_light_samples(&mut self, samples: u32) {
+        assert!(samples > 0, "Need at least one light sample");
+        self.light_samples = Some(samples);
+    }
+
+    // Path tracing also finds the light bouncing between surfaces, see path
+    pub fn set_integrator(&mut self, integrator: Integrator) {
+        self.integrator = integrator;
+    }
+
+    // Limits the bounces of Whitted rays through transparent surfaces to `depth`, and mirror
+    // reflections alone to the render's reflection depth. By default both count towards the
+    // reflection depth together
+    pub fn set_refraction_depth(&mut self, depth: u16) {
+        self.refraction_depth = Some(depth);
+    }
+
+    // Lets shadow rays pass through up to `depth` transparent surfaces, each letting through its
+    // transparency, so glass casts lighter shadows. The default 0 stops them at any surface
+    pub fn set_shadow_depth(&mut self, depth: u16) {
+        self.shadow_depth = depth;
+    }
+
+    // Path tracer paths bounce at least `roulette` times before Russian roulette may end them,
+    // and at most `max` times
+    pub fn set_path_bounces(&mut self, roulette: u32, max: u32) {
+        assert!(roulette <= max && max > 0, "Paths need at least one bounce, and Russian \
+                                              roulette can't start after the last");
+        self.path_bounces = (roulette, max);
+    }
+
+    // Picks the samples of anti-aliasing, depth of field, motion blur, area lights, environment
+    // lighting and ambient occlusion, see sampler. The default is a StratifiedSampler
+    pub fn set_sampler(&mut self, sampler: Box<Sampler>) {
+        self.sampler = sampler;
+    }
+
+    // Shadow rays traced to each emissive surface from each shading point, 16 by default
+    pub fn set_emission_samples(&mut self, samples: u32) {
+        assert!(samples > 0, "Need at least one emission sample");
+        self.emission_samples = samples;
+    }
+
+    // How far shadow, reflected and refracted rays start off the surface they leave, see Bias
+    pub fn set_bias(&mut self, bias: Bias) {
+        self.bias = bias;
+    }
+
+    // Rebuilds the hierarchy over the objects with other leaf sizes and costs, e.g. for scenes of
+    // objects much slower to test than spheres
+    pub fn set_bvh(&mut self, settings: BvhSettings) {
+        self.hierarchy = Hierarchy::new(&self.objects, &settings);
+    }
+
+    // The names of the groups of lights, in the order of their first lights. Each light without
+    // a group is a group of its own, named light0, light1 and so on by its index
+    pub fn light_groups(&self) -> Vec<String> {
+        let mut groups = Vec::new();
+        for i in 0..self.lights.len() {
+            let group = self.light_group(i);
+            if !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+        groups
+    }
+
+    fn light_group(&self, i: usize) -> String {
+        match self.lights[i].group() {
+            Some(group) => group.to_owned(),
+            None => format!("light{}", i),
+        }
+    }
+
+    // Renders only some of the light, e.g. that of one group of lights, so that each group can be
+    // brightened, dimmed or tinted after rendering. Renders of each group and of the rest take
+    // the same samples, so they add up to the render of all of it
+    pub fn set_light_selection(&mut self, selection: LightSelection) {
+        if let LightSelection::Group(ref group) = selection {
+            assert!(self.light_groups().contains(group), "There's no light group {}", group);
+        }
+        let selected: Vec<bool> = (0..self.lights.len()).map(|i| match selection {
+            LightSelection::All => true,
+            LightSelection::Group(ref group) => self.light_group(i) == *group,
+            LightSelection::Rest => false,
+        }).collect();
+        self.selected_lights = selected;
+        self.other_light = selection == LightSelection::All || selection == LightSelection::Rest;
+    }
+
+    // Renders with a different seed use different random samples, so their average has less
+    // noise than either. The default is 0
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+    }
+
+    // Makes the background transparent, for compositing the render over other footage. Rendered
+    // images then have an alpha channel, shadow catchers show their shadows as alpha, and
+    // holdouts cut out transparent holes
+    pub fn set_transparent(&mut self, transparent: bool) {
+        self.transparent = transparent;
+    }
+
+    pub fn transparent(&self) -> bool {
+        self.transparent
+    }
+
+    // Traces a single wavelength per camera ray, so dispersive materials split white light into
+    // its colors, see spectral. The default is off
+    pub fn set_spectral(&mut self, spectral: bool) {
+        self.spectral = spectral;
+    }
+
+    pub fn spectral(&self) -> bool {
+        self.spectral
+    }
+
+    pub fn set_medium(&mut self, medium: Medium) {
+        self.medium = Some(medium);
+    }
+
+    pub fn add_section(&mut self, section: SectionPlane) {
+        self.sections.push(section);
+    }
+
+    pub fn object(&self, id: usize) -> &Surface {
+        &*self.objects[id]
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
     fn intersect(&self, ray: &Ray) -> Option<(&Box<Surface>, Intersection)> {
-        let mut result = None;
-        for obj in self.objects.iter() {
-            if let Some(hit) = obj.intersect(ray) {
-                match result.clone() {
-                    None => result = Some((obj, hit)),
-                    Some((_, ref old_hit)) =>
-                        if hit.dist < old_hit.dist { result = Some((obj, hit)) }
-                }
+        self.closest_hit(ray).map(|(i, hit)| (&self.objects[i], hit))
+    }
+
+    fn closest_hit(&self, ray: &Ray) -> Option<(usize, Intersection)> {
+        stats::count_ray(ray.kind);
+        let (mut near, mut far) = (ray.near, ray.far);
+        let entry_section = section::clip_all(&self.sections, ray, &mut near, &mut far);
+        if near >= far {
+            return None;
+        }
+
+        // Start the ray at `near`, so that each object's first hit is the first one that counts
+        let start = if near > 0. {
+            Some(Ray::new(ray.origin + ray.dir * near, ray.dir).with_time(ray.time)
+                 .with_kind(ray.kind))
+        } else {
+            None
+        };
+        let result = self.hierarchy.closest(ray, |i| {
+            let obj = &self.objects[i];
+            if !obj.material().visibility().sees(ray.kind) {
+                return None;
             }
+            stats::count_intersection_test();
+            let hit = match start {
+                Some(ref start) => {
+                    obj.intersect(start).map(|hit| Intersection { dist: hit.dist + near, ..hit })
+                }
+                None => obj.intersect(ray),
+            };
+            let hit = match obj.material().backfaces() {
+                Backfaces::Shaded => hit,
+                Backfaces::Flipped => {
+                    hit.map(|hit| if dot(&ray.dir, &hit.normal) > 0. {
+                        Intersection { normal: -hit.normal, ..hit }
+                    } else {
+                        hit
+                    })
+                }
+                Backfaces::Culled if ray.kind != RayKind::Shadow => {
+                    self.past_backfaces(&**obj, ray, hit)
+                }
+                Backfaces::Culled => hit,
+            };
+            hit.and_then(|hit| {
+                if hit.dist <= far { Some(Intersection { time: ray.time, ..hit }) } else { None }
+            })
+        });
+
+        // Seeing the inside of an object right after entering through a section plane means the
+        // object was cut open there
+        result.map(|(i, hit)| {
+            let hit = section::capped(ray, entry_section, near, hit);
+            let footprint = ray.differentials.map_or(0., |d| d.footprint(ray, &hit));
+            let hit = Intersection { footprint: footprint, ..hit };
+            (i, self.objects[i].material_at(&hit).apply_normal_textures(hit))
+        })
+    }
+
+    // The first front face of `obj` from `hit` on along `ray`, skipping the back faces it passes
+    // through
+    fn past_backfaces(&self, obj: &Surface, ray: &Ray, hit: Option<Intersection>)
+                      -> Option<Intersection> {
+        let is_back = |hit: &Intersection| dot(&ray.dir, &hit.normal) > 0.;
+        let mut hit = hit;
+        for _ in 0..MAX_CULLED_FACES {
+            let from = match hit {
+                Some(ref back) if is_back(back) => back.dist + self.bias.offset(back),
+                _ => break,
+            };
+            let rest = Ray::new(ray.origin + ray.dir * from, ray.dir).with_time(ray.time)
+                .with_kind(ray.kind);
+            hit = obj.intersect(&rest).map(|hit| Intersection { dist: hit.dist + from, ..hit });
         }
-        result
+        hit.and_then(|hit| if is_back(&hit) { None } else { Some(hit) })
     }
 }
 
 pub fn ray_trace(scene: &Scene, width: u32, height: u32, max_depth: u16) -> RgbImage {
-    let aspect_ratio = width as f32 / height as f32;
+    ray_trace_progress(scene, width, height, max_depth, |_, _| {})
+}
 
-    let mut im: RgbImage = RgbImage::new(width, height);
-    for x in 0..width {
-        for y in 0..height {
-            let ray = scene.camera.get_ray(x, y, width, height, aspect_ratio);
-            let color = trace_ray(&scene, &ray, 0, max_depth);
+// Like ray_trace, but calls `progress` with the number of finished tiles and the total
+pub fn ray_trace_progress<F>(scene: &Scene, width: u32, height: u32, max_depth: u16,
+                             progress: F) -> RgbImage
+    where F: FnMut(u32, u32)
+{
+    ray_trace_hdr(scene, width, height, max_depth, progress).to_rgb()
+}
+
+// Renders without clamping colors to the displayable range, e.g. for post processing
+pub fn ray_trace_hdr<F>(scene: &Scene, width: u32, height: u32, max_depth: u16,
+                        progress: F) -> HdrImage
+    where F: FnMut(u32, u32)
+{
+    ray_trace_tiles(scene, width, height, max_depth, TileOrder::Scanline, progress)
+}
 
-            let color = Rgb::from_channels(clamp(color.x, 0., 255.) as u8,
-                                           clamp(color.y, 0., 255.) as u8,
-                                           clamp(color.z, 0., 255.) as u8,
-                                           255);
-            im.put_pixel(x, y, color);
+// Like ray_trace_hdr, but renders the image tile by tile in `order`, calling `progress` with the
+// number of finished tiles and the total
+pub fn ray_trace_tiles<F>(scene: &Scene, width: u32, height: u32, max_depth: u16,
+                          order: TileOrder, mut progress: F) -> HdrImage
+    where F: FnMut(u32, u32)
+{
+    ray_trace_events(scene, width, height, max_depth, 1, order, |event| {
+        if let RenderEvent::TileFinished { done, total, .. } = event {
+            progress(done, total);
+        }
+    })
+}
+
+// What a render is doing, for showing live progress. Regions are x0, y0, x1, y1 in pixels of
+// the image being rendered
+pub enum RenderEvent<'a> {
+    TileStarted { region: (u32, u32, u32, u32) },
+    // `image` is the whole image so far, with the tiles not yet rendered still black
+    TileFinished { region: (u32, u32, u32, u32), done: u32, total: u32, image: &'a HdrImage },
+    // Every tile has been rendered once more. Most renders have a single pass, so this comes
+    // right before the image is returned; see adaptive for renders with several
+    PassFinished { image: &'a HdrImage },
+}
+
+// Like ray_trace_samples, but tells `events` about every tile started and finished, with the
+// image so far. This lets a GUI or server show the render as it progresses, e.g. by sending
+// copies of the image down a channel
+pub fn ray_trace_events<F>(scene: &Scene, width: u32, height: u32, max_depth: u16, samples: u32,
+                           order: TileOrder, events: F) -> HdrImage
+    where F: FnMut(RenderEvent)
+{
+    ray_trace_camera(scene, &scene.camera, width, height, (0, 0, width, height), order,
+                     max_depth, samples, events)
+}
+
+// Like ray_trace_events, but continues a render that was stopped partway, e.g. from a
+// checkpoint: `image` already holds the `finished` tiles, which are kept instead of rendered
+// again. Their events aren't repeated, but `done` counts them
+pub fn ray_trace_resumed<F>(scene: &Scene, width: u32, height: u32, max_depth: u16,
+                            samples: u32, order: TileOrder, image: HdrImage,
+                            finished: &[(u32, u32, u32, u32)], events: F) -> HdrImage
+    where F: FnMut(RenderEvent)
+{
+    assert!(image.width() == width && image.height() == height,
+            "Resumed image is {}x{}, expected {}x{}", image.width(), image.height(), width,
+            height);
+    ray_trace_camera_from(scene, &scene.camera, width, height, (0, 0, width, height), order,
+                          max_depth, samples, image, finished, events)
+}
+
+// Like ray_trace_tiles, but anti-aliased with samples x samples rays through each pixel, each at
+// a random position in its own cell of a grid over the pixel, averaged
+pub fn ray_trace_samples<F>(scene: &Scene, width: u32, height: u32, max_depth: u16, samples: u32,
+                            order: TileOrder, mut progress: F) -> HdrImage
+    where F: FnMut(u32, u32)
+{
+    ray_trace_events(scene, width, height, max_depth, samples, order, |event| {
+        if let RenderEvent::TileFinished { done, total, .. } = event {
+            progress(done, total);
+        }
+    })
+}
+
+// Renders only the pixels x0..x1, y0..y1 of a width x height image, e.g. one tile of a frame
+// split across machines, with `samples` per axis as in ray_trace_samples. The result is the size
+// of the region
+pub fn ray_trace_region<F>(scene: &Scene, width: u32, height: u32, region: (u32, u32, u32, u32),
+                           max_depth: u16, samples: u32, mut progress: F) -> HdrImage
+    where F: FnMut(u32, u32)
+{
+    let (x0, y0, x1, y1) = region;
+    assert!(x0 < x1 && x1 <= width && y0 < y1 && y1 <= height,
+            "Region {},{},{},{} is outside the {}x{} image", x0, y0, x1, y1, width, height);
+    ray_trace_camera(scene, &scene.camera, width, height, region, TileOrder::Scanline,
+                     max_depth, samples, |event| {
+        if let RenderEvent::TileFinished { done, total, .. } = event {
+            progress(done, total);
+        }
+    })
+}
+
+const TILE_SIZE: u32 = 32;
+const TILES_PER_CORE: usize = 4;
+// Anti-aliasing samples are jittered on a grid this much finer than their cells
+const JITTER: u32 = 16;
+
+// Renders the region x0..x1, y0..y1 of the image seen from `camera` instead of the scene's own
+// camera, in tiles of TILE_SIZE pixels rendered on all cores, with `samples` per axis as in
+// ray_trace_samples
+fn ray_trace_camera<F>(scene: &Scene, camera: &Camera, width: u32, height: u32,
+                       region: (u32, u32, u32, u32), order: TileOrder, max_depth: u16,
+                       samples: u32, events: F) -> HdrImage
+    where F: FnMut(RenderEvent)
+{
+    let (x0, y0, x1, y1) = region;
+    ray_trace_camera_from(scene, camera, width, height, region, order, max_depth, samples,
+                          HdrImage::new(x1 - x0, y1 - y0), &[], events)
+}
+
+// Like ray_trace_camera, but only renders the tiles that aren't `finished` in `im`
+fn ray_trace_camera_from<F>(scene: &Scene, camera: &Camera, width: u32, height: u32,
+                            region: (u32, u32, u32, u32), order: TileOrder, max_depth: u16,
+                            samples: u32, mut im: HdrImage, finished: &[(u32, u32, u32, u32)],
+                            mut events: F) -> HdrImage
+    where F: FnMut(RenderEvent)
+{
+    let (x0, y0, x1, y1) = region;
+    let _span = log::span(Level::Info, format!("ray trace {}x{}", x1 - x0, y1 - y0));
+    debug!("{} objects, {} lights, max depth {}", scene.objects.len(), scene.lights.len(),
+           max_depth);
+    assert!(samples > 0, "Need at least one sample per pixel");
+    let tiles_x = (x1 - x0 + TILE_SIZE - 1) / TILE_SIZE;
+    let tiles_y = (y1 - y0 + TILE_SIZE - 1) / TILE_SIZE;
+    let tiles: Vec<(u32, u32, u32, u32)> = tile_order(tiles_x, tiles_y, order).iter()
+        .map(|&(tx, ty)| {
+            let (tile_x, tile_y) = (tx * TILE_SIZE, ty * TILE_SIZE);
+            (tile_x, tile_y, cmp::min(tile_x + TILE_SIZE, x1 - x0),
+             cmp::min(tile_y + TILE_SIZE, y1 - y0))
+        }).collect();
+
+    let total = tiles.len() as u32;
+    let (done_tiles, tiles): (Vec<_>, Vec<_>) = tiles.into_iter()
+        .partition(|tile| finished.contains(tile));
+
+    // Tiles are rendered in batches of a few per core, so that events still come from this
+    // thread, in order, between batches
+    let mut done = done_tiles.len() as u32;
+    for batch in tiles.chunks(num_cpus::get() * TILES_PER_CORE) {
+        for &tile in batch {
+            events(RenderEvent::TileStarted { region: tile });
+        }
+        let mut results = Vec::new();
+        batch.par_iter().weight_max().map(|&tile| {
+            let mut pixels = Vec::new();
+            for x in tile.0..tile.2 {
+                for y in tile.1..tile.3 {
+                    pixels.push(trace_pixel(scene, camera, x0 + x, y0 + y, width, height,
+                                            max_depth, samples));
+                }
+            }
+            stats::add_rays();
+            pixels
+        }).collect_into(&mut results);
+
+        for (&tile, pixels) in batch.iter().zip(results) {
+            let mut pixels = pixels.into_iter();
+            for x in tile.0..tile.2 {
+                for y in tile.1..tile.3 {
+                    let (color, alpha) = pixels.next().unwrap();
+                    im.put_pixel(x, y, color);
+                    im.put_alpha(x, y, alpha);
+                }
+            }
+            done += 1;
+            events(RenderEvent::TileFinished { region: tile, done: done, total: total,
+                                               image: &im });
         }
     }
+    events(RenderEvent::PassFinished { image: &im });
     im
 }
 
-fn trace_ray(scene: &Scene, ray: &Ray, depth: u16, max_depth: u16) -> Vec3 {
-    let mut color = Vec3::new(0., 0., 0.); // TODO: Background color
-    if let Some((obj, hit)) = scene.intersect(ray) {
-        let material = obj.material();
-
-        // Ambient color
-        color = material.raw_color() * (scene.ambient_color / 255.) * scene.ambient_coeff;
-
-        // Trace shadow rays
-        for light in scene.lights.iter() {
-            let pos = hit.pos + hit.normal * f32::EPSILON.sqrt();
-            let dir = *light.pos() - pos;
-            let dist = dir.norm();
-            let shadow_ray = Ray::new(pos, dir);
-            if let Some((_, shadow_hit)) = scene.intersect(&shadow_ray) {
-                if shadow_hit.dist > dist {
-                    // Diffuse/specular color
-                    color = color + material.color(&shadow_ray, &ray, &hit)
-                        * (*light.color() / 255.) * light.intensity();
+// The average color and alpha of samples x samples jittered rays through pixel (x, y), see
+// pixel_rays
+fn trace_pixel(scene: &Scene, camera: &Camera, x: u32, y: u32, width: u32, height: u32,
+               max_depth: u16, samples: u32) -> (Vec3, Float) {
+    let mut color = Vec3::new(0., 0., 0.);
+    let mut alpha = 0.;
+    pixel_rays(scene, camera, x, y, width, height, samples, |ray| {
+        let (sample_color, sample_alpha) = trace_primary(scene, ray, max_depth);
+        color = color + sample_color;
+        alpha += sample_alpha;
+    });
+    let n = (samples * samples) as Float;
+    (color / n, alpha / n)
+}
+
+// Calls `sample` with each of the samples x samples jittered rays through pixel (x, y). A single
+// sample goes through the pixel's corner, as in non anti-aliased renders
+fn pixel_rays<F>(scene: &Scene, camera: &Camera, x: u32, y: u32, width: u32, height: u32,
+                 samples: u32, mut sample: F)
+    where F: FnMut(&Ray)
+{
+    let aspect_ratio = width as Float / height as Float;
+    if samples == 1 {
+        let ray = camera.get_ray(x, y, width, height, aspect_ratio, scene.seed);
+        return sample(&with_wavelength(scene, ray, pixel_seed(scene, x, y), 0, 0));
+    }
+
+    let side = samples * JITTER;
+    let seed = pixel_seed(scene, x, y);
+    let count = samples * samples;
+    for i in 0..count {
+        let (u, v) = scene.sampler.get_2d(seed, i, count, 3);
+        let (sx, sy) = (cmp::min((u * side as Float) as u32, side - 1),
+                        cmp::min((v * side as Float) as u32, side - 1));
+        let ray = camera.get_sampled_ray(x * side + sx, y * side + sy, width * side,
+                                         height * side, aspect_ratio,
+                                         scene.sampler.get_2d(seed, i, count, 1),
+                                         scene.sampler.get_1d(seed, i, count, 15), JITTER);
+        sample(&with_wavelength(scene, ray, seed, i, count));
+    }
+}
+
+// Gives a camera ray sample `index` of `count` of the set `seed` its wavelength, in spectral
+// mode
+fn with_wavelength(scene: &Scene, ray: Ray, seed: u32, index: u32, count: u32) -> Ray {
+    if !scene.spectral {
+        return ray;
+    }
+    let u = scene.sampler.get_1d(seed, index, count, 16);
+    ray.with_wavelength(Some(spectral::wavelength(u)))
+}
+
+// Seed for the samples of pixel (x, y)
+fn pixel_seed(scene: &Scene, x: u32, y: u32) -> u32 {
+    sampling::hash(sampling::reseed(x, scene.seed), y, 0)
+}
+
+// Converts display encoded values in 0..1 to 8 bits, rounding down
+fn to_rgb(color: Vec3) -> Rgb<u8> {
+    Rgb::from_channels(clamp(color.x * 255., 0., 255.) as u8,
+                       clamp(color.y * 255., 0., 255.) as u8,
+                       clamp(color.z * 255., 0., 255.) as u8,
+                       255)
+}
+
+// Traces a ray from the camera, returning its color and alpha. With a transparent background,
+// what would show the background is transparent black instead
+fn trace_primary(scene: &Scene, ray: &Ray, max_depth: u16) -> (Vec3, Float) {
+    let (color, alpha) = trace_unweighted(scene, ray, max_depth);
+    match ray.wavelength {
+        Some(wavelength) => (color * spectral::weight(wavelength), alpha),
+        None => (color, alpha),
+    }
+}
+
+// trace_primary before weighting the light of a single wavelength by its color
+fn trace_unweighted(scene: &Scene, ray: &Ray, max_depth: u16) -> (Vec3, Float) {
+    if !scene.transparent {
+        return (trace_integrated(scene, ray, max_depth), 1.);
+    }
+    let black = Vec3::new(0., 0., 0.);
+    match scene.intersect(ray) {
+        Some((obj, hit)) => {
+            match obj.material_at(&hit).compositing() {
+                Compositing::Shaded if scene.integrator != Integrator::Whitted => {
+                    (trace_integrated(scene, ray, max_depth), 1.)
                 }
-            } else {
-                // Diffuse/specular color
-                color = color + material.color(&shadow_ray, &ray, &hit)
-                    * (*light.color() / 255.) * light.intensity();
+                Compositing::Shaded => {
+                    let color = shade(scene, ray, obj.material_at(&hit), &hit, 0, 0, max_depth);
+                    (in_medium(scene, ray, hit.dist, color), 1.)
+                }
+                Compositing::ShadowCatcher => (black, shadow_fraction(scene, &hit)),
+                Compositing::Holdout => (black, 0.),
             }
         }
+        None => (black, 0.),
+    }
+}
 
-        if depth >= max_depth {
-            return color;
+// The color of a camera ray, by the scene's integrator
+fn trace_integrated(scene: &Scene, ray: &Ray, max_depth: u16) -> Vec3 {
+    match scene.integrator {
+        Integrator::Whitted => trace_ray(scene, ray, 0, 0, max_depth),
+        Integrator::Path => path::trace_path(scene, ray),
+        Integrator::AmbientOcclusion { samples, distance } => {
+            occlusion::trace_occlusion(scene, ray, samples, distance)
         }
+    }
+}
 
-        // Get reflected color
-        let reflectivity = material.reflectivity();
-        if reflectivity > 0. {
-            let reflected_ray = reflected_ray(ray, &hit);
-            let reflected_color = trace_ray(scene, &reflected_ray, depth + 1, max_depth);
-            color = color + reflected_color * reflectivity;
+// `depth` counts the bounces so far, `refractions` those through transparent surfaces
+fn trace_ray(scene: &Scene, ray: &Ray, depth: u16, refractions: u16, max_depth: u16) -> Vec3 {
+    let (color, dist) = match scene.intersect(ray) {
+        Some((obj, hit)) => {
+            let material = obj.material_at(&hit);
+            let color = match material.compositing() {
+                Compositing::Shaded => {
+                    shade(scene, ray, material, &hit, depth, refractions, max_depth)
+                }
+                Compositing::ShadowCatcher => {
+                    background(scene, ray) * (1. - shadow_fraction(scene, &hit))
+                }
+                Compositing::Holdout => Vec3::new(0., 0., 0.),
+            };
+            (color * material.interior_transmittance(ray, &hit), hit.dist)
+        }
+        None => (background(scene, ray), float::INFINITY),
+    };
+    in_medium(scene, ray, dist, color)
+}
+
+// `color` as seen through the scene's medium, if it has one, from `dist` along `ray`
+fn in_medium(scene: &Scene, ray: &Ray, dist: Float, color: Vec3) -> Vec3 {
+    match scene.medium {
+        Some(ref medium) => medium::through_medium(scene, medium, ray, dist, color),
+        None => color,
+    }
+}
+
+// The fraction of light the scene's medium lets through along `ray` over `dist`
+fn transmittance(scene: &Scene, ray: &Ray, dist: Float) -> Float {
+    scene.medium.as_ref().map_or(1., |medium| medium.transmittance(ray, dist))
+}
+
+// The color of a hit on a normally shaded surface
+fn shade(scene: &Scene, ray: &Ray, material: &Material, hit: &Intersection, depth: u16,
+         refractions: u16, max_depth: u16) -> Vec3 {
+    // Ambient color
+    let mut color = ambient_color(scene, material, hit);
+
+    // Diffuse/specular color
+    let shade = |shadow_ray: &Ray| material.color(shadow_ray, ray, hit);
+    color = color + light_color(scene, hit, &shade) + environment_color(scene, hit, &shade);
+
+    let (can_reflect, can_refract) = bounces_left(scene, depth, refractions, max_depth);
+
+    // Get refracted color
+    let transparency = material.transparency();
+    if can_refract && transparency > 0. {
+        let refracted_color = refracted_color(scene, ray, material, hit, depth, refractions,
+                                              max_depth);
+        color = color * (1. - transparency) + refracted_color * transparency;
+    }
+
+    // Get reflected color
+    let reflectivity = material.reflectivity_at(ray, hit);
+    if can_reflect && reflectivity > 0. {
+        let reflected_ray = reflected_ray(scene, ray, hit);
+        let reflected_color = trace_ray(scene, &reflected_ray, depth + 1, refractions, max_depth);
+        color = color + reflected_color * reflectivity;
+    }
+    color + emission(scene, material)
+}
+
+// The light given off by a material, unless only the light of lights is rendered
+fn emission(scene: &Scene, material: &Material) -> Vec3 {
+    if scene.other_light { material.emission() } else { Vec3::new(0., 0., 0.) }
+}
+
+// Whether a ray after `depth` bounces, `refractions` of them through transparent surfaces, can
+// still be reflected by a mirror and pass through a transparent surface
+fn bounces_left(scene: &Scene, depth: u16, refractions: u16, max_depth: u16) -> (bool, bool) {
+    match scene.refraction_depth {
+        Some(limit) => (depth - refractions < max_depth, refractions < limit),
+        None => (depth < max_depth, depth < max_depth),
+    }
+}
+
+fn ambient_color(scene: &Scene, material: &Material, hit: &Intersection) -> Vec3 {
+    material.raw_color() * ambient_light(scene, &hit.normal)
+}
+
+// Ambient light arriving at a surface with the given normal
+fn ambient_light(scene: &Scene, normal: &Vec3) -> Vec3 {
+    if !scene.other_light {
+        return Vec3::new(0., 0., 0.);
+    }
+    let light = match scene.ambient_sh {
+        Some(ref sh) => sh.irradiance(normal) / float::consts::PI,
+        None => scene.ambient_color,
+    };
+    light * scene.ambient_coeff
+}
+
+// Sums `shade` over the shadow rays to all lights visible from the hit point, weighted by the
+// light's color and intensity. Area lights are split over their shadow rays. Includes the light
+// of emissive surfaces
+fn light_color<F>(scene: &Scene, hit: &Intersection, shade: F) -> Vec3
+    where F: Fn(&Ray) -> Vec3
+{
+    emitter_color(scene, hit, &shade) + lights_color(scene, hit, shade)
+}
+
+// light_color without the emissive surfaces
+fn lights_color<F>(scene: &Scene, hit: &Intersection, shade: F) -> Vec3
+    where F: Fn(&Ray) -> Vec3
+{
+    if let Some(samples) = scene.light_samples {
+        if (samples as usize) < scene.lights.len() {
+            return resampled_light_color(scene, hit, shade, samples);
+        }
+    }
+    let mut color = Vec3::new(0., 0., 0.);
+    for (i, light) in scene.lights.iter().enumerate() {
+        if !scene.selected_lights[i] {
+            continue;
+        }
+        let weight = *light.color() * (light.intensity() / light.samples() as Float);
+        for sample in 0..light.samples() {
+            let (shadow_ray, dist) = shadow_ray(scene, light, hit, sample, light.samples());
+            let attenuation = light.attenuation(&shadow_ray.dir, dist);
+            if attenuation > 0. {
+                let visible = shadow_visibility(scene, &shadow_ray, dist);
+                if visible > 0. {
+                    let transmittance = transmittance(scene, &shadow_ray, dist);
+                    color = color + shade(&shadow_ray) * weight *
+                                    (attenuation * visible * transmittance);
+                }
+            }
+        }
+    }
+    color
+}
+
+// Sums `shade` over shadow rays to random points on the emissive surfaces, weighted like
+// environment_color, so the light of an emitter is the same whether it's sampled here or found
+// by a path bouncing into it
+fn emitter_color<F>(scene: &Scene, hit: &Intersection, shade: F) -> Vec3
+    where F: Fn(&Ray) -> Vec3
+{
+    weighted_emitter_color(scene, hit, shade, |_, _| 1.)
+}
+
+// emitter_color with each sample scaled by `weight` of its shadow ray and emitter_density there,
+// for combining it with other ways of finding the same light
+fn weighted_emitter_color<F, W>(scene: &Scene, hit: &Intersection, shade: F, weight: W) -> Vec3
+    where F: Fn(&Ray) -> Vec3,
+          W: Fn(&Ray, Float) -> Float
+{
+    let mut color = Vec3::new(0., 0., 0.);
+    if !scene.other_light {
+        return color;
+    }
+    let origin = scene.bias.origin(hit, &hit.normal);
+    let samples = scene.emission_samples;
+    for &i in scene.emitters.iter() {
+        let obj = &scene.objects[i];
+        let seed = sampling::reseed(hit_seed(scene, hit), i as u32);
+        for sample in 0..samples {
+            let (u1, u2) = scene.sampler.get_2d(seed, sample, samples, 17);
+            let (pos, normal, area) = match obj.sample_area(u1, u2) {
+                Some(point) => point,
+                None => continue,
+            };
+            let to_emitter = pos - origin;
+            let dist = to_emitter.norm();
+            if dist <= 0. {
+                continue;
+            }
+            let dir = to_emitter / dist;
+            let shadow_ray = Ray::new(origin, dir).with_time(hit.time).with_kind(RayKind::Shadow);
+            // Stopping short of the point, so the emitter doesn't shadow itself there
+            let reach = dist * (1. - 1e-3);
+            let visible = shadow_visibility(scene, &shadow_ray, reach);
+            // Emitters shine from both sides
+            let cos = dot(&normal, &dir).abs();
+            if visible > 0. && cos > 0. {
+                let density = samples as Float * dist * dist / (cos * area);
+                let scale = visible * transmittance(scene, &shadow_ray, reach) *
+                            weight(&shadow_ray, density) / (density * float::consts::PI);
+                color = color + shade(&shadow_ray) * obj.material().emission() * scale;
+            }
         }
     }
     color
 }
 
-fn reflected_ray(ray: &Ray, hit: &Intersection) -> Ray {
-    let pos = hit.pos + hit.normal * f32::EPSILON.sqrt();
-    let dir = ray.dir - hit.normal * 2. * dot(&ray.dir, &hit.normal);
-    Ray::new(pos, dir)
+// The density of emitter_color's shadow rays, per unit solid angle and times their number,
+// around the direction of `ray` to its `hit` on the emitter `obj`
+fn emitter_density(scene: &Scene, obj: &Surface, ray: &Ray, hit: &Intersection) -> Float {
+    let area = obj.sample_area(0.5, 0.5).map_or(0., |(_, _, area)| area);
+    let cos = dot(&hit.normal, &ray.dir).abs();
+    scene.emission_samples as Float * hit.dist * hit.dist / (cos * area)
+}
+
+// Candidate lights considered for each light sample
+const LIGHT_CANDIDATES: usize = 32;
+
+// Estimates light_color from `samples` shadow rays. Each sample resamples a light from a few
+// candidates in proportion to its unshadowed contribution, so lights that are far too dim or
+// behind the surface are rarely traced, and is weighted so the estimate stays unbiased
+fn resampled_light_color<F>(scene: &Scene, hit: &Intersection, shade: F, samples: u32) -> Vec3
+    where F: Fn(&Ray) -> Vec3
+{
+    let lights = &scene.lights;
+    // Each candidate is a random point of the light if it's an area light
+    let unshadowed = |light: &PointLight, n: u32| {
+        // The candidates of different lights don't form one set
+        let (shadow_ray, dist) = shadow_ray(scene, light, hit, n, 0);
+        let color = shade(&shadow_ray) * *light.color() * light.intensity() *
+                    light.attenuation(&shadow_ray.dir, dist);
+        (shadow_ray, dist, color)
+    };
+
+    // With few lights, every one is a candidate
+    let candidates = cmp::min(LIGHT_CANDIDATES, lights.len());
+    let seed = hit_seed(scene, hit);
+    let mut color = Vec3::new(0., 0., 0.);
+    for s in 0..samples {
+        let mut total = 0.;
+        let mut chosen = None;
+        for c in 0..candidates {
+            let n = s * LIGHT_CANDIDATES as u32 + c as u32;
+            let i = if candidates == lights.len() {
+                c
+            } else {
+                cmp::min((sampling::uniform(seed, n, 4) * lights.len() as Float) as usize,
+                         lights.len() - 1)
+            };
+            let candidate = unshadowed(&lights[i], n);
+            let target = luminance(&candidate.2);
+            // Candidates are drawn with probability 1 / lights, so they count for that many
+            let weight = target * lights.len() as Float / candidates as Float;
+            total += weight;
+            if weight > 0. && sampling::uniform(seed, n, 5) * total < weight {
+                chosen = Some((candidate, target, i));
+            }
+        }
+
+        // Lights are chosen among all of them either way, so that the renders of each group
+        // take the same samples
+        if let Some(((shadow_ray, dist, contribution), target, i)) = chosen {
+            if !scene.selected_lights[i] {
+                continue;
+            }
+            let visible = shadow_visibility(scene, &shadow_ray, dist);
+            if visible > 0. {
+                color = color + contribution * (total / (target * samples as Float) * visible *
+                                                transmittance(scene, &shadow_ray, dist));
+            }
+        }
+    }
+    color
+}
+
+// Seed for the random numbers at a hit, from its position so neighboring pixels differ
+fn hit_seed(scene: &Scene, hit: &Intersection) -> u32 {
+    let seed = sampling::hash(sampling::bits(hit.pos.x), sampling::bits(hit.pos.y),
+                              sampling::bits(hit.pos.z));
+    sampling::reseed(seed, scene.seed)
+}
+
+// Monte Carlo estimate of `shade` over the environment, weighted like light_color so that a
+// uniform white environment lights like a white light of intensity 1 straight along the normal
+fn environment_color<F>(scene: &Scene, hit: &Intersection, shade: F) -> Vec3
+    where F: Fn(&Ray) -> Vec3
+{
+    weighted_environment_color(scene, hit, shade, |_, _| 1.)
+}
+
+// environment_color with each sample scaled by `weight` of its shadow ray and its density, like
+// weighted_emitter_color
+fn weighted_environment_color<F, W>(scene: &Scene, hit: &Intersection, shade: F, weight: W)
+                                    -> Vec3
+    where F: Fn(&Ray) -> Vec3,
+          W: Fn(&Ray, Float) -> Float
+{
+    let mut color = Vec3::new(0., 0., 0.);
+    if !scene.other_light {
+        return color;
+    }
+    environment_samples(scene, hit, |shadow_ray, radiance, density| {
+        if scene.closest_hit(shadow_ray).is_none() {
+            let scale = weight(shadow_ray, density) *
+                        transmittance(scene, shadow_ray, float::INFINITY);
+            color = color + shade(shadow_ray) * (radiance * scale);
+        }
+    });
+    color
+}
+
+// The density of environment_samples' directions from `pos`, per unit solid angle and times
+// their number, around `dir`. 0 without an environment map
+fn environment_density(scene: &Scene, pos: &Vec3, dir: &Vec3) -> Float {
+    match scene.environment {
+        Some((ref map, samples)) => samples as Float * environment_pdf(scene, map, pos, dir),
+        None => 0.,
+    }
+}
+
+// The guide, if it has seen light around `pos`
+fn guide_at<'a>(scene: &'a Scene, pos: &Vec3) -> Option<&'a Guide> {
+    scene.guide.as_ref().and_then(|guide| if guide.has_light(pos) { Some(guide) } else { None })
+}
+
+// The density of a single environment sample from `pos` picking `dir`. Samples are split evenly
+// between the map and, where they can help, the guide and the portals
+fn environment_pdf(scene: &Scene, map: &EnvironmentMap, pos: &Vec3, dir: &Vec3) -> Float {
+    let (mut sum, mut techniques) = (map.pdf(dir), 1.);
+    if let Some(guide) = guide_at(scene, pos) {
+        sum += guide.pdf(pos, dir);
+        techniques += 1.;
+    }
+    if !scene.portals.is_empty() {
+        let portals = &scene.portals;
+        sum += portals.iter().map(|portal| portal.pdf(pos, dir)).sum::<Float>() /
+               portals.len() as Float;
+        techniques += 1.;
+    }
+    sum / techniques
+}
+
+// Calls `f` with a ray towards each sampled direction of the environment, the environment's
+// radiance in that direction weighted so that the weights add up to a light color like
+// light_color uses, and the density of the samples around it as in environment_density
+fn environment_samples<F>(scene: &Scene, hit: &Intersection, mut f: F)
+    where F: FnMut(&Ray, Vec3, Float)
+{
+    let (map, samples) = match scene.environment {
+        Some((ref map, samples)) => (map, samples),
+        None => return,
+    };
+
+    let seed = hit_seed(scene, hit);
+    let origin = scene.bias.origin(hit, &hit.normal);
+    let guide = guide_at(scene, &hit.pos);
+    let portals = &scene.portals;
+    let techniques = 1 + guide.is_some() as usize + (!portals.is_empty()) as usize;
+    for i in 0..samples {
+        let (u1, u2) = scene.sampler.get_2d(seed, i, samples, 1);
+        // The guide first, then the portals, with the rest of the number picking a portal
+        let pick = scene.sampler.get_1d(seed, i, samples, 3) * techniques as Float;
+        let technique = cmp::min(pick as usize, techniques - 1);
+        let technique = if guide.is_none() { technique + 1 } else { technique };
+        let sample = match (technique, guide) {
+            (0, Some(guide)) => guide.sample(&hit.pos, u1, u2).map(|(dir, _)| dir),
+            (1, _) if !portals.is_empty() => {
+                let n = cmp::min((pick.fract() * portals.len() as Float) as usize,
+                                 portals.len() - 1);
+                portals[n].sample(&hit.pos, u1, u2)
+            }
+            _ => map.sample(u1, u2).map(|(dir, _)| dir),
+        };
+        let dir = match sample {
+            Some(dir) => dir,
+            None => continue,
+        };
+        let pdf = environment_pdf(scene, map, &hit.pos, &dir);
+        if pdf <= 0. {
+            continue;
+        }
+        let ray = Ray::new(origin, dir).with_time(hit.time).with_kind(RayKind::Shadow);
+        let density = pdf * samples as Float;
+        f(&ray, map.lookup(&dir) / (density * float::consts::PI), density);
+    }
+}
+
+// How much of the light from lights and the environment that would reach a diffuse surface at
+// the hit point is blocked, from 0 for none to 1 for all of it
+fn shadow_fraction(scene: &Scene, hit: &Intersection) -> Float {
+    let (mut lit, mut total) = (0., 0.);
+    for light in scene.lights.iter() {
+        for sample in 0..light.samples() {
+            let (shadow_ray, dist) = shadow_ray(scene, light, hit, sample, light.samples());
+            let amount = dot(&hit.normal, &shadow_ray.dir).max(0.) * luminance(light.color()) *
+                         light.intensity() * light.attenuation(&shadow_ray.dir, dist) /
+                         light.samples() as Float;
+            total += amount;
+            if shadow_blocker(scene, &shadow_ray, dist).is_none() {
+                lit += amount;
+            }
+        }
+    }
+    environment_samples(scene, hit, |shadow_ray, weight, _| {
+        let amount = dot(&hit.normal, &shadow_ray.dir).max(0.) * luminance(&weight);
+        total += amount;
+        if scene.closest_hit(shadow_ray).is_none() {
+            lit += amount;
+        }
+    });
+    if total > 0. { 1. - lit / total } else { 0. }
+}
+
+fn background(scene: &Scene, ray: &Ray) -> Vec3 {
+    if !scene.other_light {
+        return Vec3::new(0., 0., 0.);
+    }
+    match scene.environment {
+        Some((ref map, _)) => map.lookup(&ray.dir),
+        None => scene.background.color(&ray.dir),
+    }
+}
+
+// Returns the ray from the hit point towards the light, and the distance to the light. For area
+// lights, `sample` of `count` picks a point on the light
+fn shadow_ray(scene: &Scene, light: &PointLight, hit: &Intersection, sample: u32, count: u32)
+              -> (Ray, Float) {
+    let pos = scene.bias.origin(hit, &hit.normal);
+    let (u1, u2) = match light.shape() {
+        LightShape::Sphere(_) | LightShape::Rect(..) => {
+            scene.sampler.get_2d(hit_seed(scene, hit), sample, count, 6)
+        }
+        LightShape::Point | LightShape::Directional(_) => (0., 0.),
+    };
+    let (dir, dist) = light.sample(&pos, u1, u2);
+    (Ray::new(pos, dir).with_time(hit.time).with_kind(RayKind::Shadow), dist)
+}
+
+// Returns the closest object between the shadow ray's origin and the light, if any
+fn shadow_blocker<'a>(scene: &'a Scene, shadow_ray: &Ray, dist: Float)
+                      -> Option<(&'a Box<Surface>, Intersection)> {
+    match scene.intersect(shadow_ray) {
+        Some((obj, hit)) => if hit.dist > dist { None } else { Some((obj, hit)) },
+        None => None,
+    }
+}
+
+// The fraction of the light at `dist` along the shadow ray that reaches its origin: 0 behind
+// something opaque, or the product of the transparencies of the surfaces in between, up to the
+// scene's shadow depth of them
+fn shadow_visibility(scene: &Scene, shadow_ray: &Ray, dist: Float) -> Float {
+    let mut visibility = 1.;
+    let (mut ray, mut dist) = (shadow_ray.clone(), dist);
+    for passed in 0..scene.shadow_depth + 1 {
+        let (obj, hit) = match shadow_blocker(scene, &ray, dist) {
+            Some(blocker) => blocker,
+            None => return visibility,
+        };
+        visibility *= obj.material_at(&hit).transparency();
+        if visibility == 0. || passed == scene.shadow_depth {
+            return 0.;
+        }
+        // On past the surface
+        let offset = scene.bias.offset(&hit);
+        ray = Ray::new(hit.pos + ray.dir * offset, ray.dir).with_time(ray.time)
+            .with_kind(RayKind::Shadow);
+        dist -= hit.dist + offset;
+    }
+    0.
+}
+
+// The light passing through a transparent surface: refracted by Snell's law, plus the part
+// reflected at the surface by Schlick's approximation of the Fresnel equations, or all of it
+// when the ray can't leave the object (total internal reflection)
+fn refracted_color(scene: &Scene, ray: &Ray, material: &Material, hit: &Intersection,
+                   depth: u16, refractions: u16, max_depth: u16) -> Vec3 {
+    let (reflected_ray, refracted) = refraction_rays(scene, ray, material, hit);
+    let reflected = trace_ray(scene, &reflected_ray, depth + 1, refractions + 1, max_depth);
+    match refracted {
+        Some((refracted_ray, fresnel)) => {
+            let refracted = trace_ray(scene, &refracted_ray, depth + 1, refractions + 1,
+                                      max_depth);
+            reflected * fresnel + refracted * (1. - fresnel)
+        }
+        None => reflected,
+    }
+}
+
+// The rays leaving a transparent surface: the reflected ray, and unless it's totally reflected,
+// the refracted ray with the fraction of the light that's reflected instead
+fn refraction_rays(scene: &Scene, ray: &Ray, material: &Material, hit: &Intersection)
+                   -> (Ray, Option<(Ray, Float)>) {
+    // Normals point out of objects, so a ray on the same side as the normal is leaving one
+    let leaving = dot(&ray.dir, &hit.normal) > 0.;
+    let ior = material.ior_at(ray.wavelength);
+    let (normal, eta) = if leaving { (-hit.normal, ior) } else { (hit.normal, 1. / ior) };
+    let offset = normal * scene.bias.offset(hit);
+    let reflected = Ray::new(hit.pos + offset, ray::reflect(&ray.dir, &normal))
+        .with_time(ray.time).with_kind(RayKind::Reflection)
+        .with_differentials(ray.differentials.and_then(|d| d.reflected(ray, hit, &normal)))
+        .with_wavelength(ray.wavelength);
+
+    let refracted = ray::refract(&ray.dir, &normal, eta).map(|dir| {
+        // The angle on the less dense side decides how much is reflected
+        let cos = if leaving { dot(&dir, &hit.normal) } else { -dot(&ray.dir, &hit.normal) };
+        let r0 = ((1. - ior) / (1. + ior)).powi(2);
+        let differentials = ray.differentials
+            .and_then(|d| d.refracted(ray, hit, &normal, eta, &dir));
+        (Ray::new(hit.pos - offset, dir).with_time(ray.time).with_kind(RayKind::Reflection)
+             .with_differentials(differentials).with_wavelength(ray.wavelength),
+         ray::schlick(r0, cos))
+    });
+    (reflected, refracted)
+}
+
+fn reflected_ray(scene: &Scene, ray: &Ray, hit: &Intersection) -> Ray {
+    let pos = scene.bias.origin(hit, &hit.normal);
+    Ray::new(pos, ray::reflect(&ray.dir, &hit.normal)).with_time(ray.time)
+        .with_kind(RayKind::Reflection)
+        .with_differentials(ray.differentials.and_then(|d| d.reflected(ray, hit, &hit.normal)))
+        .with_wavelength(ray.wavelength)
 }
@@ -1,14 +1,134 @@
-use Vec3;
+use std::str::FromStr;
+
+use nalgebra::{cross, dot, Norm};
+
+use {float, Float, Vec3};
+
+// Lights with a falloff get no brighter than this many times their intensity, instead of going to
+// infinity right at them, e.g. for surfaces touching an area light
+const MIN_DIMMING: Float = 1e-4;
+
+// The shape light is emitted from. Shadows of area lights have soft edges, from shadow rays
+// spread over the light's surface
+#[derive(Clone, Copy, Debug)]
+pub enum LightShape {
+    Point,
+    // A ball of this radius around the light's position
+    Sphere(Float),
+    // A rectangle centered on the light's position, spanned by these two edges
+    Rect(Vec3, Vec3),
+    // Infinitely far away and shining along this direction, like the sun. The light's position
+    // is ignored
+    Directional(Vec3),
+}
+
+// How a light dims with the distance from it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Falloff {
+    // The same brightness at any distance
+    None,
+    // Physically correct, with `intensity` at a distance of 1
+    InverseSquare,
+    // 1 / (constant + linear * distance + quadratic * distance²), to tune by eye
+    Polynomial { constant: Float, linear: Float, quadratic: Float },
+}
+
+impl FromStr for Falloff {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "none" => Ok(Falloff::None),
+            "inverse_square" => Ok(Falloff::InverseSquare),
+            _ => Err(format!("Unknown light falloff: {}", s)),
+        }
+    }
+}
+
+// Limits a light to a cone. Cosines of the angles from its axis: full intensity inside `inner`,
+// fading to none at `outer`
+#[derive(Clone, Copy, Debug)]
+struct Spot {
+    dir: Vec3,
+    cos_inner: Float,
+    cos_outer: Float,
+}
+
+// Which of the light in a scene a render includes, to render each group of lights on its own
+#[derive(Clone, Debug, PartialEq)]
+pub enum LightSelection {
+    All,
+    // Only the light of the lights in this group, see Scene::light_groups
+    Group(String),
+    // Only the light that isn't from lights: ambient light, the environment and background, and
+    // emissive surfaces
+    Rest,
+}
 
 pub struct PointLight {
     pos: Vec3,
     color: Vec3,
-    intensity: f32,
+    intensity: Float,
+    shape: LightShape,
+    // Shadow rays traced to the light from each shaded point
+    samples: u32,
+    spot: Option<Spot>,
+    falloff: Falloff,
+    group: Option<String>,
 }
 
 impl PointLight {
-    pub fn new(pos: Vec3, color: Vec3, intensity: f32) -> Self {
-        PointLight { pos: pos, color: color, intensity: intensity }
+    // A light of linear `color`, where 1 is white, scaled by `intensity`. Intensity is in units
+    // of the light that shows a white diffuse surface facing it as exactly white, an irradiance
+    // of pi, so it means the same for every material and integrator
+    pub fn new(pos: Vec3, color: Vec3, intensity: Float) -> Self {
+        PointLight { pos: pos, color: color, intensity: intensity, shape: LightShape::Point,
+                     samples: 1, spot: None, falloff: Falloff::None, group: None }
+    }
+
+    // Makes this an area light, lighting each point with `samples` shadow rays spread over it
+    pub fn with_shape(mut self, shape: LightShape, samples: u32) -> Self {
+        assert!(samples > 0, "Area lights need at least one shadow ray");
+        self.shape = match shape {
+            LightShape::Directional(dir) => LightShape::Directional(dir.normalize()),
+            _ => shape,
+        };
+        self.samples = match shape {
+            LightShape::Point | LightShape::Directional(_) => 1,
+            _ => samples,
+        };
+        self
+    }
+
+    // Makes this a spot light shining along `dir`, in a cone of `angle` radians from its axis
+    // with the last `falloff` radians fading out
+    pub fn with_spot(mut self, dir: Vec3, angle: Float, falloff: Float) -> Self {
+        assert!(falloff >= 0. && falloff <= angle, "Spot falloff must be within the cone");
+        self.spot = Some(Spot { dir: dir.normalize(), cos_inner: (angle - falloff).cos(),
+                                cos_outer: angle.cos() });
+        self
+    }
+
+    // Dims the light with distance. Directional lights are infinitely far away and don't
+    pub fn with_falloff(mut self, falloff: Falloff) -> Self {
+        if let Falloff::Polynomial { constant, linear, quadratic } = falloff {
+            assert!(constant >= 0. && linear >= 0. && quadratic >= 0. &&
+                    constant + linear + quadratic > 0.,
+                    "Falloff coefficients must not be negative, and not all 0");
+        }
+        self.falloff = falloff;
+        self
+    }
+
+    // Puts the light in a named group, e.g. "key" or "rim", whose light can be rendered apart
+    // from the others
+    pub fn with_group(mut self, group: &str) -> Self {
+        self.group = Some(group.to_owned());
+        self
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_ref().map(|group| &group[..])
     }
 
     pub fn pos(&self) -> &Vec3 {
@@ -19,7 +139,78 @@ impl PointLight {
         &self.color
     }
 
-    pub fn intensity(&self) -> f32 {
+    pub fn intensity(&self) -> Float {
         self.intensity
     }
+
+    pub fn shape(&self) -> LightShape {
+        self.shape
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    // The direction from `from` towards a point on the light for u1, u2 in 0..1, and the distance
+    // to it. Sphere lights are sampled on the disc they appear as
+    pub fn sample(&self, from: &Vec3, u1: Float, u2: Float) -> (Vec3, Float) {
+        let target = match self.shape {
+            LightShape::Point => self.pos,
+            LightShape::Sphere(radius) => {
+                let axis = (self.pos - *from).normalize();
+                let helper = if axis.x.abs() < 0.9 {
+                    Vec3::new(1., 0., 0.)
+                } else {
+                    Vec3::new(0., 1., 0.)
+                };
+                let tangent = cross(&axis, &helper).normalize();
+                let bitangent = cross(&axis, &tangent);
+                let r = radius * u1.sqrt();
+                let angle = 2. * float::consts::PI * u2;
+                self.pos + tangent * (r * angle.cos()) + bitangent * (r * angle.sin())
+            }
+            LightShape::Rect(edge_u, edge_v) => {
+                self.pos + edge_u * (u1 - 0.5) + edge_v * (u2 - 0.5)
+            }
+            LightShape::Directional(dir) => return (-dir, float::INFINITY),
+        };
+        let to_light = target - *from;
+        (to_light.normalize(), to_light.norm())
+    }
+
+    // How much of the light's intensity arrives `dist` away along `dir`, a unit direction
+    // towards the light: nothing outside the cone of a spot light, and less further away with a
+    // falloff
+    pub fn attenuation(&self, dir: &Vec3, dist: Float) -> Float {
+        self.spot_attenuation(dir) * self.distance_attenuation(dist)
+    }
+
+    fn spot_attenuation(&self, dir: &Vec3) -> Float {
+        let spot = match self.spot {
+            Some(ref spot) => spot,
+            None => return 1.,
+        };
+        let cos = -dot(dir, &spot.dir);
+        if cos >= spot.cos_inner {
+            1.
+        } else if cos <= spot.cos_outer {
+            0.
+        } else {
+            // Smoothstep between the two
+            let t = (cos - spot.cos_outer) / (spot.cos_inner - spot.cos_outer);
+            t * t * (3. - 2. * t)
+        }
+    }
+
+    fn distance_attenuation(&self, dist: Float) -> Float {
+        let dimming = match self.falloff {
+            _ if dist == float::INFINITY => return 1.,
+            Falloff::None => return 1.,
+            Falloff::InverseSquare => dist * dist,
+            Falloff::Polynomial { constant, linear, quadratic } => {
+                constant + linear * dist + quadratic * dist * dist
+            }
+        };
+        1. / Float::max(dimming, MIN_DIMMING)
+    }
 }
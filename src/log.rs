@@ -0,0 +1,113 @@
+// Minimal structured logging to stderr. Messages are prefixed with the names of the spans they
+// were logged in, and spans log how long they took when they end:
+//
+//     let _span = log::span(Level::Info, format!("load scene {}", path));
+//     debug!("{} materials", materials.len());
+//
+// prints "[DEBUG] load scene room.toml: 3 materials" and then
+// "[INFO ] load scene room.toml: done in 0.012s".
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicIsize, ATOMIC_ISIZE_INIT, Ordering};
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn from_str(s: &str) -> Option<Level> {
+        match &s.to_lowercase()[..] {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+// Stored relative to Info so the zero initialized static is the default level
+static VERBOSITY: AtomicIsize = ATOMIC_ISIZE_INIT;
+
+thread_local!(static SPANS: RefCell<Vec<String>> = RefCell::new(Vec::new()));
+
+pub fn set_level(level: Level) {
+    VERBOSITY.store(level as isize - Level::Info as isize, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as isize - Level::Info as isize <= VERBOSITY.load(Ordering::Relaxed)
+}
+
+pub fn log(level: Level, message: &str) {
+    if !enabled(level) {
+        return;
+    }
+    let prefix = SPANS.with(|spans| {
+        spans.borrow().iter().fold(String::new(), |prefix, span| prefix + span + ": ")
+    });
+    let _ = writeln!(io::stderr(), "[{}] {}{}", level.name(), prefix, message);
+}
+
+pub struct Span {
+    level: Level,
+    start: Instant,
+}
+
+// Starts a span that lasts until the returned value is dropped
+pub fn span<S: Into<String>>(level: Level, name: S) -> Span {
+    SPANS.with(|spans| spans.borrow_mut().push(name.into()));
+    Span { level: level, start: Instant::now() }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        log(self.level, &format!("done in {:.3}s", secs));
+        SPANS.with(|spans| spans.borrow_mut().pop());
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log::log($crate::log::Level::Error, &format!($($arg)*)))
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log::log($crate::log::Level::Warn, &format!($($arg)*)))
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log::log($crate::log::Level::Info, &format!($($arg)*)))
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log::log($crate::log::Level::Debug, &format!($($arg)*)))
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log::log($crate::log::Level::Trace, &format!($($arg)*)))
+}
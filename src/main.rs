@@ -1,43 +1,205 @@
+#[macro_use]
 extern crate tracerlib;
 
+extern crate flate2;
 extern crate image;
+extern crate libc;
 extern crate nalgebra;
+extern crate rand;
+extern crate rustc_serialize;
 extern crate toml;
 
+mod animation;
+mod assets;
+mod batch;
+mod bench;
+mod checkpoint;
+mod dataset;
+mod farm;
+mod interactive;
+mod merge;
+mod pause;
+mod preview;
+mod progress;
+mod serve;
+mod stream;
+mod validate;
+
 use std::collections::BTreeMap;
+use std::env;
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufWriter, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rustc_serialize::json::Json;
+
+use assets::Assets;
+use checkpoint::{read_checkpoint, Checkpoint};
+use preview::Preview;
+use progress::ProgressBar;
 
-use tracerlib::{ray_trace, Camera, Scene, Vec3};
-use tracerlib::light::PointLight;
-use tracerlib::material::{DisplacementMap, Material, NormalMap};
-use tracerlib::surface::{Plane, Sphere, Surface};
-use tracerlib::texture::{CheckerboardTexture, ImageTexture, Texture};
+use tracerlib::{float, ray_trace_resumed, Camera, Float, Projection, RenderEvent, Scene,
+                Vec3};
+use tracerlib::adaptive::{ray_trace_adaptive, AdaptiveSettings};
+use tracerlib::aov::{encode_aov, ray_trace_aov, Aov};
+use tracerlib::bake::bake_lightmaps;
+use tracerlib::bounds::Aabb;
+use tracerlib::bvh::{self, BvhSettings};
+use tracerlib::color::OutputTransform;
+use tracerlib::compact::CompactMesh;
+use tracerlib::csg::Csg;
+use tracerlib::debug::{ray_trace_debug, DebugMode};
+use tracerlib::dump::trace_pixel;
+use tracerlib::environment::{Background, EnvironmentMap};
+use tracerlib::gltf;
+use tracerlib::lens::{Aperture, Lens};
+use tracerlib::light::{Falloff, LightSelection, LightShape, PointLight};
+use tracerlib::log::{self, Level};
+use tracerlib::hdr::HdrImage;
+use tracerlib::heightfield::Heightfield;
+use tracerlib::material::{Backfaces, Compositing, DisplacementMap, Material, NormalMap,
+                          Visibility};
+use tracerlib::medium::Medium;
+use tracerlib::mesh::TriangleMesh;
+use tracerlib::output::{self, Format};
+use tracerlib::path::{self, Integrator};
+use tracerlib::portal::Portal;
+use tracerlib::probes::bake_probes;
+use tracerlib::procedural::{Basis, Bump, Pattern, Procedural, ProceduralTexture};
+use tracerlib::ray::Bias;
+use tracerlib::sampler;
+use tracerlib::sdf::{Sdf, Shape};
+use tracerlib::section::{SectionPlane, Sectioned};
+use tracerlib::post::{Bloom, ChromaticAberration, Denoise, Exposure, FilmGrain, Imaging,
+                      LensFlare, PostEffect, PostPipeline, Saturation, Vignette, WhiteBalance};
+use tracerlib::stats;
+use tracerlib::stereo::{ray_trace_anaglyph, ray_trace_ods, ray_trace_side_by_side};
+use tracerlib::subsurface::Subsurface;
+use tracerlib::surface::{Cone, Cylinder, Disk, Plane, PlanePattern, Quad, Sphere, Surface, Torus,
+                         Triangle};
+use tracerlib::texture::{CheckerboardTexture, Texture, TextureCache};
+use tracerlib::tiles::TileOrder;
+use tracerlib::transform::{Instance, Moving, Transform, Transformed};
 
-use image::FilterType;
+use image::{DynamicImage, FilterType, GenericImage, ImageRgb8, ImageRgba8};
 use image::imageops::resize;
 
-struct Config {
+use nalgebra::Norm;
+
+pub struct Config {
     width: u32,
     height: u32,
     out_file: String,
     samples: u32,
     reflection_depth: u16,
     scene: String,
+    debug_mode: Option<DebugMode>,
+    post: PostPipeline,
+    output_transform: OutputTransform,
+    // Blue noise dithering when quantizing to 8 bits
+    dither: bool,
+    // 8 or 16 bits per channel for rendered frames in formats that have 16, see output.rs
+    bit_depth: u8,
+    // From 1 to 100
+    jpeg_quality: u8,
+    anaglyph: Option<Float>,
+    // Eye separation for the two eyes' images side by side
+    side_by_side: Option<Float>,
+    // Eye separation for an omnidirectional stereo panorama
+    ods: Option<Float>,
+    tile_order: TileOrder,
+    // For adaptive rendering, instead of `samples` on a grid
+    adaptive: Option<AdaptiveSettings>,
+    // Shared by all scenes of a batch
+    texture_budget_mb: Option<Float>,
+    // Passed on to the scene, see Scene::set_seed
+    seed: u32,
+    // Where the image so far is written while rendering, see preview.rs
+    preview: Option<String>,
+    // Written next to the rendered image, see aov_file
+    aovs: Vec<Aov>,
+    // Where finished tiles are saved while rendering, and whether to continue from there, see
+    // checkpoint.rs
+    checkpoint: Option<String>,
+    resume: bool,
+    // Only this x0, y0, x1, y1 rectangle of the frame is rendered into out_file, see crop_region
+    crop: Option<(u32, u32, u32, u32)>,
+    // Write out_file a band of rows at a time instead of keeping the whole frame, see stream.rs
+    stream: bool,
+    // Also write the light of each group of lights on its own, see write_light_groups
+    light_groups: bool,
+    // Print what the rays of the render did afterwards, and where a heatmap of their cost goes,
+    // see print_stats
+    stats: bool,
+    stats_heatmap: Option<String>,
 }
 
 impl Config {
     fn new(filename: &str) -> Self {
-        let mut toml_str = String::new();
-        File::open(filename).unwrap().read_to_string(&mut toml_str).unwrap();
+        let toml = read_toml(filename);
+        let problems = validate::validate_config(&toml);
+        assert!(problems.is_empty(), "Invalid config:\n{}", problems.join("\n"));
+        Config::from_toml(&toml)
+    }
 
-        let toml: toml::Value = toml_str.parse().unwrap();
+    fn from_toml(toml: &toml::Value) -> Self {
         let width = toml.lookup("config.width").unwrap().as_integer().unwrap();
         let height = toml.lookup("config.height").unwrap().as_integer().unwrap();
         let out_file = decode_string(toml.lookup("config.out_file").unwrap());
         let samples = toml.lookup("config.samples").unwrap().as_integer().unwrap();
         let depth = toml.lookup("config.reflection_depth").unwrap().as_integer().unwrap();
         let scene_name = decode_string(toml.lookup("config.scene").unwrap());
+        let debug_mode = toml.lookup("config.debug_mode")
+            .map(|mode| decode_string(mode).parse().unwrap());
+        let output_transform = toml.lookup("config.output_transform")
+            .map_or(OutputTransform::Linear, |t| decode_string(t).parse().unwrap());
+        let post = toml.lookup("config.post")
+            .map_or(PostPipeline::new(), |post| decode_post(post, output_transform));
+        let dither = toml.lookup("config.dither").map_or(false, |d| d.as_bool().unwrap());
+        let bit_depth = toml.lookup("config.bit_depth").map_or(8, |b| b.as_integer().unwrap());
+        assert!(bit_depth == 8 || bit_depth == 16, "The bit depth must be 8 or 16, not {}",
+                bit_depth);
+        let jpeg_quality = toml.lookup("config.jpeg_quality")
+            .map_or(90, |q| q.as_integer().unwrap());
+        assert!(jpeg_quality >= 1 && jpeg_quality <= 100,
+                "The JPEG quality must be from 1 to 100, not {}", jpeg_quality);
+        let anaglyph = toml.lookup("config.anaglyph").map(decode_f32);
+        let side_by_side = toml.lookup("config.side_by_side").map(decode_f32);
+        let ods = toml.lookup("config.ods").map(decode_f32);
+        let tile_order = toml.lookup("config.tile_order")
+            .map_or(TileOrder::Scanline, |order| decode_string(order).parse().unwrap());
+        let adaptive = toml.lookup("config.adaptive_samples").map(|samples| {
+            let count = |key: &str| toml.lookup(key).map(|n| n.as_integer().unwrap() as u32);
+            let mut settings = AdaptiveSettings::new(samples.as_integer().unwrap() as u32);
+            if let Some(min_samples) = count("config.adaptive_min_samples") {
+                settings = settings.with_min_samples(min_samples);
+            }
+            if let Some(max_samples) = count("config.adaptive_max_samples") {
+                settings = settings.with_max_samples(max_samples);
+            }
+            match toml.lookup("config.adaptive_threshold") {
+                Some(threshold) => settings.with_threshold(decode_f32(threshold)),
+                None => settings,
+            }
+        });
+        let texture_budget_mb = toml.lookup("config.texture_budget_mb").map(decode_f32);
+        let seed = toml.lookup("config.seed").map_or(0, |seed| seed.as_integer().unwrap());
+        let aovs = toml.lookup("config.aovs").map_or(Vec::new(), |aovs| {
+            aovs.as_slice().unwrap().iter().map(|aov| decode_string(aov).parse().unwrap())
+                .collect()
+        });
+        let stream = toml.lookup("config.stream").map_or(false, |s| s.as_bool().unwrap());
+        let light_groups = toml.lookup("config.light_groups")
+            .map_or(false, |groups| groups.as_bool().unwrap());
+        let stats = toml.lookup("config.stats").map_or(false, |stats| stats.as_bool().unwrap());
+        let stats_heatmap = toml.lookup("config.stats_heatmap").map(decode_string);
+        let crop = toml.lookup("config.crop").map(|crop| {
+            let coords: Vec<u32> = crop.as_slice().unwrap().iter()
+                .map(|c| c.as_integer().unwrap() as u32).collect();
+            crop_region(&coords)
+        });
 
         Config {
             width: width as u32,
@@ -46,19 +208,645 @@ impl Config {
             samples: samples as u32,
             reflection_depth: depth as u16,
             scene: scene_name,
+            debug_mode: debug_mode,
+            post: post,
+            output_transform: output_transform,
+            dither: dither,
+            bit_depth: bit_depth as u8,
+            jpeg_quality: jpeg_quality as u8,
+            anaglyph: anaglyph,
+            side_by_side: side_by_side,
+            ods: ods,
+            tile_order: tile_order,
+            adaptive: adaptive,
+            texture_budget_mb: texture_budget_mb,
+            seed: seed as u32,
+            preview: None,
+            aovs: aovs,
+            checkpoint: None,
+            resume: false,
+            crop: crop,
+            stream: stream,
+            light_groups: light_groups,
+            stats: stats || stats_heatmap.is_some(),
+            stats_heatmap: stats_heatmap,
         }
     }
+
+    // Whether both eyes are rendered, as an anaglyph, side by side or as a stereo panorama
+    fn stereo(&self) -> bool {
+        self.anaglyph.is_some() || self.side_by_side.is_some() || self.ods.is_some()
+    }
+}
+
+// The pixels x0..x1, y0..y1 of a crop given as x, y, width, height
+fn crop_region(coords: &[u32]) -> (u32, u32, u32, u32) {
+    assert!(coords.len() == 4, "A crop needs x, y, width and height");
+    (coords[0], coords[1], coords[0] + coords[2], coords[1] + coords[3])
 }
 
 fn main() {
-    let config = Config::new("config.toml");
-    let scene = setup_scene(&config.scene);
+    let mut args: Vec<String> = env::args().collect();
+
+    // Verbosity comes from RAY_TRACE_LOG=<level>, or -q/-v/-vv anywhere on the command line
+    if let Ok(level) = env::var("RAY_TRACE_LOG") {
+        log::set_level(Level::from_str(&level).expect("Unknown log level in RAY_TRACE_LOG"));
+    }
+    args.retain(|arg| {
+        match &arg[..] {
+            "-q" => log::set_level(Level::Error),
+            "-v" => log::set_level(Level::Debug),
+            "-vv" => log::set_level(Level::Trace),
+            _ => return true,
+        }
+        false
+    });
+
+    if args.len() > 1 && args[1] == "serve" {
+        let addr = args.get(2).map(|s| &s[..]).unwrap_or("127.0.0.1:8080");
+        serve::serve(addr, "config.toml");
+        return;
+    }
+
+    // Built-in scenes, so no config.toml is needed
+    if args.len() > 1 && args[1] == "bench" {
+        bench::bench(&args[2..]);
+        return;
+    }
+
+    // Converts a mesh to be memory mapped, see compact.rs
+    if args.len() > 1 && args[1] == "compact" {
+        assert!(args.len() == 4, "Usage: compact <mesh file> <out.rtmesh>");
+        write_compact_mesh(&args[2], &args[3]);
+        return;
+    }
+
+    let mut config = Config::new("config.toml");
+
+    if args.len() > 1 && args[1] == "farm" {
+        farm::farm(&config, &args[2..]);
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "merge" {
+        merge::merge(&config, &args[2..]);
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "animate" {
+        animation::animate(&config, &args[2..]);
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "batch" {
+        batch::batch(&config, &args[2..]);
+        return;
+    }
+
+    let mut pixel = None;
+    let mut obj_file = None;
+    let mut info = false;
+    let mut dataset = None;
+    let mut bake = None;
+    let mut probes = None;
+    let mut region = None;
+    let mut tiles = None;
+    let mut partial = false;
+    let mut integrator = None;
+    let mut interactive = false;
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--debug-mode" => {
+                let mode = args.next().expect("--debug-mode requires a mode");
+                config.debug_mode = Some(mode.parse().unwrap());
+            }
+            "--trace-pixel" => {
+                let coords = args.next().expect("--trace-pixel requires x,y");
+                let coords: Vec<u32> = coords.split(',').map(|c| c.parse().unwrap()).collect();
+                assert!(coords.len() == 2, "--trace-pixel requires x,y");
+                pixel = Some((coords[0], coords[1]));
+            }
+            "--trace-obj" => obj_file = Some(args.next().expect("--trace-obj requires a file")),
+            "--info" => info = true,
+            "--dataset" => {
+                let count = args.next().expect("--dataset requires a count and a directory");
+                let dir = args.next().expect("--dataset requires a count and a directory");
+                dataset = Some((count.parse().unwrap(), dir));
+            }
+            "--bake" => {
+                let size = args.next().expect("--bake requires a tile size and a file");
+                let file = args.next().expect("--bake requires a tile size and a file");
+                bake = Some((size.parse().unwrap(), file));
+            }
+            "--probes" => {
+                let counts = args.next().expect("--probes requires nx,ny,nz and a file");
+                let counts: Vec<u32> = counts.split(',').map(|c| c.parse().unwrap()).collect();
+                assert!(counts.len() == 3, "--probes requires nx,ny,nz");
+                let file = args.next().expect("--probes requires nx,ny,nz and a file");
+                probes = Some(((counts[0], counts[1], counts[2]), file));
+            }
+            "--region" => {
+                let coords = args.next().expect("--region requires x0,y0,x1,y1 and a file");
+                let coords: Vec<u32> = coords.split(',').map(|c| c.parse().unwrap()).collect();
+                assert!(coords.len() == 4, "--region requires x0,y0,x1,y1");
+                let file = args.next().expect("--region requires x0,y0,x1,y1 and a file");
+                region = Some(((coords[0], coords[1], coords[2], coords[3]), file.clone()));
+            }
+            "--tiles" => {
+                let range = args.next().expect("--tiles requires first..end/count and a file");
+                let parts: Vec<u32> = range.split(|c: char| c == '.' || c == '/')
+                    .filter(|part| !part.is_empty()).map(|part| part.parse().unwrap()).collect();
+                assert!(parts.len() == 3 && range.contains("..") && range.contains('/'),
+                        "--tiles requires first..end/count, e.g. 0..128/512");
+                let file = args.next().expect("--tiles requires first..end/count and a file");
+                tiles = Some(((parts[0], parts[1]), parts[2], file));
+            }
+            "--crop" => {
+                let coords = args.next().expect("--crop requires x,y,width,height");
+                let coords: Vec<u32> = coords.split(',').map(|c| c.parse().unwrap()).collect();
+                config.crop = Some(crop_region(&coords));
+            }
+            "--seed" => {
+                let seed = args.next().expect("--seed requires a number");
+                config.seed = seed.parse().unwrap();
+            }
+            "--partial" => partial = true,
+            "--stream" => config.stream = true,
+            "--light-groups" => config.light_groups = true,
+            "--stats" => config.stats = true,
+            "--stats-heatmap" => {
+                let file = args.next().expect("--stats-heatmap requires a file");
+                config.stats = true;
+                config.stats_heatmap = Some(file.clone());
+            }
+            "--interactive" => interactive = true,
+            "--integrator" => {
+                let name = args.next().expect("--integrator requires whitted, path or ao");
+                integrator = Some(name.parse().unwrap());
+            }
+            "--scene" => config.scene = args.next().expect("--scene requires a scene").clone(),
+            "--width" => {
+                config.width = args.next().expect("--width requires a number").parse().unwrap();
+            }
+            "--height" => {
+                config.height = args.next().expect("--height requires a number").parse().unwrap();
+            }
+            "--output" => {
+                config.out_file = args.next().expect("--output requires a file").clone();
+            }
+            "--max-depth" => {
+                let depth = args.next().expect("--max-depth requires a number");
+                config.reflection_depth = depth.parse().unwrap();
+            }
+            "--preview" => {
+                config.preview = Some(args.next().expect("--preview requires a file").clone());
+            }
+            "--checkpoint" => {
+                let file = args.next().expect("--checkpoint requires a file");
+                config.checkpoint = Some(file.clone());
+            }
+            "--resume" => {
+                config.checkpoint = Some(args.next().expect("--resume requires a file").clone());
+                config.resume = true;
+            }
+            "--aov" => {
+                let aov = args.next().expect("--aov requires depth, normal, albedo or object_id");
+                config.aovs.push(aov.parse().unwrap());
+            }
+            "--samples" => {
+                let samples = args.next().expect("--samples requires a number");
+                config.samples = samples.parse().unwrap();
+            }
+            _ => panic!("Unknown argument: {}", arg),
+        }
+    }
+
+    // A crop is a region written to out_file, framed as in the full image
+    let region = region.or_else(|| config.crop.map(|crop| (crop, config.out_file.clone())));
+
+    let mut scene = setup_scene(&config.scene);
+    scene.set_seed(config.seed);
+    if let Some(integrator) = integrator {
+        scene.set_integrator(integrator);
+    }
+
+    if info {
+        print!("{}", scene.stats());
+        return;
+    }
+
+    if let Some((count, dir)) = dataset {
+        dataset::export(&config, &mut scene, count, dir);
+        return;
+    }
+
+    if let Some((tile_size, file)) = bake {
+        let (atlas, tiles) = bake_lightmaps(&scene, tile_size).unwrap_or_else(|e| panic!("{}", e));
+        assert!(!tiles.is_empty(), "None of the objects in the scene are finite, to bake");
+        for tile in tiles.iter() {
+            println!("{} {} {},{}", tile.object, scene.object(tile.object).name(), tile.x,
+                     tile.y);
+        }
+        let atlas = ImageRgb8(atlas.encode(config.output_transform, config.dither));
+        save_image(&config, &atlas, file);
+        info!("Wrote {}", file);
+        return;
+    }
+
+    if let Some((counts, file)) = probes {
+        write_probes(&config, &scene, counts, file);
+        return;
+    }
+
+    if let Some((x, y)) = pixel {
+        let dump = trace_pixel(&scene, x, y, config.width, config.height,
+                               config.reflection_depth);
+        print!("{}", dump);
+        if let Some(obj_file) = obj_file {
+            File::create(obj_file).unwrap().write_all(dump.to_obj(100.).as_bytes()).unwrap();
+        }
+        return;
+    }
 
-    let im = ray_trace(&scene, config.samples * config.width, config.samples * config.height,
-                       config.reflection_depth);
+    // A worker's share of a farmed out frame, see farm.rs
+    if let Some((range, count, file)) = tiles {
+        let regions = farm::tile_range(&config, range, count);
+        let rendered: Vec<_> = regions.into_iter().map(|region| {
+            (farm::render_region_hdr(&config, &scene, region), region)
+        }).collect();
+        merge::write_partials(&config, &rendered, file);
+        info!("Wrote tiles {}..{} of {} to {}", range.0, range.1, count, file);
+        return;
+    }
 
-    let im = resize(&im, config.width, config.height, FilterType::Triangle);
-    im.save(&config.out_file).unwrap();
+    if partial && region.is_none() && config.adaptive.is_some() {
+        merge::write_adaptive_partial(&config, &scene, &config.out_file);
+        info!("Wrote {}", config.out_file);
+        return;
+    }
+    if partial {
+        let (im, region, file) = match region {
+            Some((region, file)) => {
+                (farm::render_region_hdr(&config, &scene, region), region, file)
+            }
+            None => {
+                let im = render_hdr(&config, &scene, |_, _| {});
+                (im, (0, 0, config.width, config.height), config.out_file.clone())
+            }
+        };
+        merge::write_partial(&config, &im, region, &file);
+        info!("Wrote {}", file);
+        return;
+    }
+
+    if let Some((region, file)) = region {
+        save_image(&config, &farm::render_region(&config, &scene, region), &file);
+        info!("Wrote {}", file);
+        return;
+    }
+
+    if interactive {
+        // Turning goes around the camera's up, which works for cameras from glTF files or without
+        // a lookat too, and moving starts at a tenth of the distance to what's in the middle of
+        // the view per key press
+        let (pos, dir, up) = {
+            let camera = scene.camera();
+            (*camera.pos(), *camera.dir(), *camera.up())
+        };
+        let step = scene.raycast(pos, dir).map_or(1., |hit| hit.dist / 10.);
+        interactive::run(&config, scene, integrator, up, step);
+        return;
+    }
+
+    pause::install();
+    stats::enable_report(config.stats);
+    if config.stream {
+        stream::render_streaming(&config, &scene, &config.out_file);
+        info!("Wrote {}", config.out_file);
+    } else {
+        let mut progress = ProgressBar::new();
+        render_to_file(&config, &scene, &config.out_file, |done, total| {
+            progress.update(done, total);
+            pause::wait_while_paused();
+        });
+        info!("Wrote {}", config.out_file);
+        if config.light_groups {
+            write_light_groups(&config, &mut scene, &config.out_file);
+        }
+    }
+    if config.stats {
+        print_stats(&config, &scene);
+    }
+}
+
+// Prints the rays of each kind that the render traced, with the BVH nodes they visited and the
+// objects they were tested against, and writes a heatmap of the cost of each pixel if asked to.
+// The heatmap is rendered at one ray per pixel, so it doesn't count towards the numbers
+fn print_stats(config: &Config, scene: &Scene) {
+    stats::enable_report(false);
+    print!("{}", stats::take_report());
+    if let Some(ref file) = config.stats_heatmap {
+        let im = ray_trace_debug(scene, config.width, config.height, config.reflection_depth,
+                                 DebugMode::Heatmap, |_, _| {});
+        save_image(config, &ImageRgb8(im), file);
+        info!("Wrote {}", file);
+    }
+}
+
+// Writes the light of each group of lights in the scene on its own, and the rest of the light
+// (ambient, environment and emissive surfaces), as Radiance files next to `file`: out.png gets
+// out.key.hdr, out.fill.hdr and so on, and out.other.hdr. They're unclamped and without post
+// effects, so that scaling each and adding them up rebalances the lighting of the frame
+fn write_light_groups(config: &Config, scene: &mut Scene, file: &str) {
+    assert!(config.checkpoint.is_none(), "Light groups don't work with checkpoints");
+    let groups = scene.light_groups();
+    assert!(!groups.iter().any(|group| group == "other"),
+            "\"other\" is the name of the light that isn't from lights, not a light group");
+    let path = Path::new(file);
+    let stem = path.file_stem().unwrap().to_str().unwrap().to_owned();
+    let selections = groups.into_iter().map(|group| (group.clone(), LightSelection::Group(group)))
+        .chain(Some(("other".to_owned(), LightSelection::Rest)));
+    for (name, selection) in selections {
+        let _span = log::span(Level::Info, format!("light group {}", name));
+        scene.set_light_selection(selection);
+        let im = render_hdr(config, scene, |_, _| {});
+        let group_file = path.with_file_name(format!("{}.{}.hdr", stem, name));
+        im.write_radiance(&mut BufWriter::new(File::create(&group_file).unwrap())).unwrap();
+        info!("Wrote {}", group_file.display());
+    }
+    scene.set_light_selection(LightSelection::All);
+}
+
+fn render<F>(config: &Config, scene: &Scene, progress: F) -> DynamicImage
+    where F: FnMut(u32, u32)
+{
+    let _span = log::span(Level::Info, "render");
+    if let Some(mode) = config.debug_mode {
+        let (width, height) = (config.samples * config.width, config.samples * config.height);
+        let im = ray_trace_debug(scene, width, height, config.reflection_depth, mode, progress);
+        debug!("Downsampling {} samples per axis to {}x{}", config.samples, config.width,
+               config.height);
+        return ImageRgb8(resize(&im, config.width, config.height, FilterType::Triangle));
+    }
+
+    encode(config, scene, &render_frame(config, scene, progress))
+}
+
+// Renders the frame and writes it to `file`, unclamped if it's a Radiance .hdr file
+fn render_to_file<F>(config: &Config, scene: &Scene, file: &str, progress: F)
+    where F: FnMut(u32, u32)
+{
+    if config.debug_mode.is_some() {
+        save_image(config, &render(config, scene, progress), file);
+    } else {
+        let _span = log::span(Level::Info, "render");
+        save_frame(config, scene, &render_frame(config, scene, progress), file);
+    }
+
+    for &aov in config.aovs.iter() {
+        let im = ray_trace_aov(scene, config.width, config.height, config.samples, aov);
+        let aov_file = aov_file(file, aov);
+        if aov_file.ends_with(".hdr") {
+            im.write_radiance(&mut BufWriter::new(File::create(&aov_file).unwrap())).unwrap();
+        } else {
+            save_image(config, &ImageRgb8(encode_aov(&im, aov)), &aov_file);
+        }
+        info!("Wrote {}", aov_file);
+    }
+}
+
+// out.png becomes out.depth.png, and so on. Object IDs don't survive the rounding of Radiance
+// files, so they're always written as PNG
+fn aov_file(file: &str, aov: Aov) -> String {
+    let path = Path::new(file);
+    let stem = path.file_stem().unwrap().to_str().unwrap();
+    let extension = match (aov, path.extension().and_then(|e| e.to_str())) {
+        (Aov::ObjectId, Some("hdr")) | (_, None) => "png",
+        (_, Some(extension)) => extension,
+    };
+    path.with_file_name(format!("{}.{}.{}", stem, aov.name(), extension))
+        .to_str().unwrap().to_owned()
+}
+
+// The finished frame, after post effects
+fn render_frame<F>(config: &Config, scene: &Scene, progress: F) -> HdrImage
+    where F: FnMut(u32, u32)
+{
+    let mut im = render_hdr(config, scene, progress);
+    config.post.apply(&mut im, scene);
+    im
+}
+
+// The rendered frame at the output size, before post effects
+fn render_hdr<F>(config: &Config, scene: &Scene, mut progress: F) -> HdrImage
+    where F: FnMut(u32, u32)
+{
+    let (width, height, samples) = (config.width, config.height, config.samples);
+    let depth = config.reflection_depth;
+    let mut preview = Preview::new(config.preview.clone());
+    assert!(config.checkpoint.is_none() || (config.adaptive.is_none() && !config.stereo()),
+            "Checkpoints don't work with adaptive_samples or stereo renders");
+    if let Some(settings) = config.adaptive {
+        return ray_trace_adaptive(scene, config.width, config.height, depth, settings,
+                                  config.tile_order, |event| {
+            if let RenderEvent::TileFinished { done, total, .. } = event {
+                progress(done, total);
+            }
+            preview.event(config, scene, &event);
+        });
+    }
+
+    match (config.anaglyph, config.side_by_side, config.ods) {
+        (Some(separation), _, _) => {
+            ray_trace_anaglyph(scene, width, height, depth, samples, separation, progress)
+        }
+        (None, Some(separation), _) => {
+            ray_trace_side_by_side(scene, width, height, depth, samples, separation, progress)
+        }
+        (None, None, Some(separation)) => {
+            ray_trace_ods(scene, width, height, depth, samples, separation, progress)
+        }
+        (None, None, None) => {
+            let (image, finished) = match config.checkpoint {
+                Some(ref file) if config.resume => read_checkpoint(config, file),
+                _ => (HdrImage::new(width, height), Vec::new()),
+            };
+            let mut checkpoint = Checkpoint::new(config.checkpoint.clone(), finished.clone());
+            ray_trace_resumed(scene, width, height, depth, samples, config.tile_order, image,
+                              &finished, |event| {
+                if let RenderEvent::TileFinished { done, total, .. } = event {
+                    progress(done, total);
+                }
+                preview.event(config, scene, &event);
+                checkpoint.event(config, &event);
+            })
+        }
+    }
+}
+
+// With an alpha channel if the scene has a transparent background
+fn encode(config: &Config, scene: &Scene, im: &HdrImage) -> DynamicImage {
+    if scene.transparent() {
+        ImageRgba8(im.encode_rgba(config.output_transform, config.dither))
+    } else {
+        ImageRgb8(im.encode(config.output_transform, config.dither))
+    }
+}
+
+// Writes a frame after post effects: as is to Radiance .hdr files, or encoded for display at the
+// configured bit depth
+fn save_frame(config: &Config, scene: &Scene, im: &HdrImage, file: &str) {
+    if file.ends_with(".hdr") {
+        im.write_radiance(&mut BufWriter::new(File::create(file).unwrap())).unwrap();
+    } else if config.bit_depth == 16 {
+        let format = Format::of_file(file).unwrap_or_else(|e| panic!("{}", e));
+        assert!(format.has_16_bits(), "{} can't have 16 bits per channel", file);
+        let alpha = scene.transparent() && format.has_alpha();
+        let samples = im.encode_16(config.output_transform, alpha);
+        output::write_16(&mut BufWriter::new(File::create(file).unwrap()), format, &samples,
+                         im.width(), im.height(), if alpha { 4 } else { 3 })
+            .unwrap();
+    } else {
+        save_image(config, &encode(config, scene, im), file);
+    }
+}
+
+// In the format of the file's extension, without the alpha channel if it has none
+fn save_image(config: &Config, im: &DynamicImage, file: &str) {
+    let format = Format::of_file(file).unwrap_or_else(|e| panic!("{}", e));
+    let (width, height) = im.dimensions();
+    let (data, channels) = match *im {
+        ImageRgba8(ref im) if format.has_alpha() => (im.clone().into_raw(), 4),
+        _ => (im.to_rgb().into_raw(), 3),
+    };
+    output::write_8(&mut BufWriter::new(File::create(file).unwrap()), format, &data, width,
+                    height, channels, config.jpeg_quality)
+        .unwrap();
+}
+
+// Post effects are an array of tables, applied in order:
+//
+//     [[config.post]]
+//     effect = "saturation"
+//     amount = 1.2
+//
+// Film grain is added to colors encoded by the output transform
+fn decode_post(post: &toml::Value, transform: OutputTransform) -> PostPipeline {
+    let mut pipeline = PostPipeline::new();
+    for effect in post.as_slice().unwrap() {
+        pipeline.push(decode_post_effect(effect, transform));
+    }
+    pipeline
+}
+
+fn decode_post_effect(effect: &toml::Value, transform: OutputTransform) -> Box<PostEffect> {
+    let name = effect.lookup("effect").unwrap().as_str().unwrap();
+    trace!("post effect {}", name);
+    match name {
+        "saturation" => Box::new(Saturation::new(decode_f32(effect.lookup("amount").unwrap()))),
+        "exposure" => Box::new(Exposure::new(decode_f32(effect.lookup("ev").unwrap()))),
+        "white_balance" => {
+            let temperature = decode_f32(effect.lookup("temperature").unwrap());
+            let tint = effect.lookup("tint").map_or(0., decode_f32);
+            Box::new(WhiteBalance::new(temperature, tint))
+        }
+        "denoise" => {
+            let radius = effect.lookup("radius").map_or(3, |r| r.as_integer().unwrap());
+            let color_sigma = effect.lookup("color_sigma").map(decode_level);
+            Box::new(Denoise::new(radius as u32, color_sigma))
+        }
+        "bloom" => {
+            let threshold = decode_level(effect.lookup("threshold").unwrap());
+            let radius = decode_f32(effect.lookup("radius").unwrap());
+            // The standard deviation of the blur, in pixels
+            assert!(radius > 0., "The bloom radius must be positive, not {}", radius);
+            let strength = decode_f32(effect.lookup("strength").unwrap());
+            Box::new(Bloom::new(threshold, radius, strength))
+        }
+        "chromatic_aberration" => {
+            Box::new(ChromaticAberration::new(decode_f32(effect.lookup("amount").unwrap())))
+        }
+        "film_grain" => {
+            let amount = decode_level(effect.lookup("amount").unwrap());
+            let seed = effect.lookup("seed").map_or(0, |seed| seed.as_integer().unwrap());
+            Box::new(FilmGrain::new(amount, seed as u32, transform))
+        }
+        "lens_flare" => Box::new(LensFlare::new(decode_f32(effect.lookup("strength").unwrap()))),
+        "vignette" => {
+            let amount = decode_f32(effect.lookup("amount").unwrap());
+            Box::new(Vignette::new(amount, effect.lookup("radius").map_or(0.5, decode_f32)))
+        }
+        _ => panic!("Unsupported post effect: {}", name)
+    }
+}
+
+const PROBE_SAMPLES: u32 = 1024;
+
+// Bakes irradiance probes over the bounds of the scene's finite objects, and writes them as JSON
+fn write_probes(config: &Config, scene: &Scene, counts: (u32, u32, u32), file: &str) {
+    let bounds = scene.stats().bounds.expect("--probes needs at least one finite object");
+    let probes = bake_probes(scene, &bounds, counts, PROBE_SAMPLES, config.reflection_depth);
+
+    let probes = probes.iter().map(|probe| {
+        let mut json = BTreeMap::new();
+        json.insert("pos".to_owned(), vec3_json(&probe.pos));
+        json.insert("sh".to_owned(), Json::Array(probe.sh.coeffs.iter().map(vec3_json).collect()));
+        Json::Object(json)
+    }).collect();
+    let mut json = BTreeMap::new();
+    json.insert("counts".to_owned(), Json::Array(vec![Json::U64(counts.0 as u64),
+                                                      Json::U64(counts.1 as u64),
+                                                      Json::U64(counts.2 as u64)]));
+    json.insert("min".to_owned(), vec3_json(&bounds.min));
+    json.insert("max".to_owned(), vec3_json(&bounds.max));
+    json.insert("basis".to_owned(),
+                Json::String("order 2 real spherical harmonics of incoming radiance, 9 rgb \
+                              coefficients per probe, probes ordered by x then y then z"
+                             .to_owned()));
+    json.insert("probes".to_owned(), Json::Array(probes));
+
+    let mut out = File::create(file).unwrap();
+    writeln!(out, "{}", Json::Object(json).pretty()).unwrap();
+    info!("Wrote {}", file);
+}
+
+fn vec3_json(v: &Vec3) -> Json {
+    Json::Array(vec![Json::F64(v.x as f64), Json::F64(v.y as f64), Json::F64(v.z as f64)])
+}
+
+fn read_toml(filename: &str) -> toml::Value {
+    match parse_toml(filename) {
+        Ok(toml) => toml,
+        Err(error) => panic!("{}", error),
+    }
+}
+
+// The contents of a TOML file, or why they can't be read
+fn parse_toml(filename: &str) -> Result<toml::Value, String> {
+    let mut toml_str = String::new();
+    try!(File::open(filename).and_then(|mut file| file.read_to_string(&mut toml_str))
+        .map_err(|e| format!("Can't read {}: {}", filename, e)));
+    let mut parser = toml::Parser::new(&toml_str);
+    match parser.parse() {
+        Some(table) => Ok(toml::Value::Table(table)),
+        None => {
+            let errors: Vec<String> = parser.errors.iter().map(|error| {
+                let (line, col) = parser.to_linecol(error.lo);
+                format!("{}:{}:{}: {}", filename, line + 1, col + 1, error.desc)
+            }).collect();
+            Err(format!("Can't parse {}:\n{}", filename, errors.join("\n")))
+        }
+    }
+}
+
+fn write_compact_mesh(file: &str, out_file: &str) {
+    // Compact meshes take the material of the surface using them
+    let material = Material::new(Vec3::new(1., 1., 1.), 1., 0., 0., 0., None, None, None);
+    let mesh = TriangleMesh::load(file, material);
+    let mut out = BufWriter::new(File::create(out_file).unwrap());
+    mesh.write_compact(&mut out).unwrap();
+    info!("Wrote {} ({} triangles)", out_file, mesh.triangle_count());
 }
 
 fn setup_scene(scene: &str) -> Scene {
@@ -66,37 +854,109 @@ fn setup_scene(scene: &str) -> Scene {
     path.push_str("scenes/");
     path.push_str(&scene);
 
-    let mut toml_str = String::new();
-    File::open(path).unwrap().read_to_string(&mut toml_str).unwrap();
+    let _span = log::span(Level::Info, format!("load scene {}", path));
+    load_scene(&read_toml(&path))
+}
+
+fn load_scene(toml: &toml::Value) -> Scene {
+    load_scene_cached(toml, None)
+}
 
-    let toml: toml::Value = toml_str.parse().unwrap();
+// Loads textures through `cache` if given, instead of the scene's own texture budget
+fn load_scene_cached(toml: &toml::Value, cache: Option<Arc<Mutex<TextureCache>>>) -> Scene {
+    match try_load_scene(toml, cache) {
+        Ok(scene) => scene,
+        Err(error) => panic!("{}", error),
+    }
+}
+
+// The scene, or why it can't be loaded: the problems validate_scene finds, or the surface that
+// took it over its memory budget
+fn try_load_scene(toml: &toml::Value, cache: Option<Arc<Mutex<TextureCache>>>)
+                  -> Result<Scene, String> {
+    let problems = validate::validate_scene(toml);
+    if !problems.is_empty() {
+        return Err(format!("Invalid scene:\n{}", problems.join("\n")));
+    }
+    // With a budget, textures are loaded when first needed instead of up front
+    let cache = cache.or_else(|| {
+        toml.lookup("scene.texture_budget_mb")
+            .map(|mb| TextureCache::new((decode_f32(mb) * 1024. * 1024.) as usize))
+    });
+    let budget = toml.lookup("scene.memory_budget_mb")
+        .map(|mb| (decode_f32(mb) * 1024. * 1024.) as usize);
+    // Dropped before measuring the scene, which counts each shared mesh once over the surfaces
+    // holding it
+    let scene = {
+        let assets = Assets::new(cache, budget);
+        let materials = decode_materials(toml.lookup("material").unwrap(), &assets);
+        debug!("{} materials", materials.len());
+        let scene = try!(decode_scene(toml.lookup("scene").unwrap(), materials, &assets));
+        let (textures, meshes) = assets.counts();
+        debug!("Loaded {} texture files and {} meshes", textures, meshes);
+        scene
+    };
 
-    let materials = decode_materials(toml.lookup("material").unwrap());
-    decode_scene(toml.lookup("scene").unwrap(), materials)
+    // The geometry was charged as it loaded, this also counts the lights and the scene itself
+    let memory = scene.stats().memory;
+    debug!("Scene data uses {:.1} KiB", memory as f64 / 1024.);
+    match budget {
+        Some(budget) if memory > budget => {
+            Err(format!("Scene needs {:.1} KiB, more than its memory budget of {:.1} KiB",
+                        memory as f64 / 1024., budget as f64 / 1024.))
+        }
+        _ => Ok(scene),
+    }
 }
 
-fn decode_materials(materials: &toml::Value) -> BTreeMap<String, Material> {
+fn decode_materials(materials: &toml::Value, assets: &Assets) -> BTreeMap<String, Material> {
     let mut map = BTreeMap::new();
     for material in materials.as_slice().unwrap() {
-        let (name, m) = decode_material(material);
+        let (name, m) = decode_material(material, assets);
         map.insert(name, m);
     }
     map
 }
 
-fn decode_material(material: &toml::Value) -> (String, Material) {
+fn decode_material(material: &toml::Value, assets: &Assets) -> (String, Material) {
     let name = decode_string(material.lookup("name").unwrap());
-    let color = decode_vec3(material.lookup("color").unwrap());
-    let diffuse = material.lookup("diffuse").unwrap().as_float().unwrap() as f32;
-    let specular = material.lookup("specular").unwrap().as_float().unwrap() as f32;
-    let glossiness = material.lookup("glossiness").unwrap().as_float().unwrap() as f32;
-    let reflectivity = material.lookup("reflectivity").unwrap().as_float().unwrap() as f32;
+    let color = decode_color(material.lookup("color").unwrap());
+    // GGX materials don't use the Phong coefficients, so they can be left out. A roughness map
+    // alone scales the whole range of roughness. Two roughnesses are anisotropic, along and
+    // across the tangent
+    let roughness_map = material.lookup("roughness_map").map(|file| linear_texture(file, assets));
+    let anisotropic = material.lookup("roughness").and_then(|r| r.as_slice()).map(|r| {
+        (decode_f32(&r[0]), decode_f32(&r[1]))
+    });
+    let roughness = match anisotropic {
+        Some((along, across)) => Some((along + across) / 2.),
+        None => material.lookup("roughness").map(decode_f32),
+    }.or(if roughness_map.is_some() { Some(1.) } else { None });
+    let coeff = |material_name: &str, key: &str| match material.lookup(key) {
+        Some(value) => decode_f32(value),
+        None if roughness.is_some() => if key == "diffuse" { 1. } else { 0. },
+        None => panic!("Material {} needs {}", material_name, key),
+    };
+    let diffuse = coeff(&name, "diffuse");
+    let specular = coeff(&name, "specular");
+    let glossiness = coeff(&name, "glossiness");
+    let reflectivity = coeff(&name, "reflectivity");
     let texture = if let Some(checkerboard) = material.lookup("checkerboard") {
-        Some(Box::new(CheckerboardTexture::new(checkerboard.as_float().unwrap() as f32))
+        Some(Box::new(CheckerboardTexture::new(decode_f32(checkerboard)))
              as Box<Texture>)
     } else {
         if let Some(texture) = material.lookup("texture") {
-            Some(Box::new(ImageTexture::new(texture.as_str().unwrap())) as Box<Texture>)
+            Some(image_texture(texture, assets))
+        } else if let Some(procedural) = material.lookup("procedural") {
+            let (low, high) = match procedural.lookup("colors") {
+                Some(colors) => {
+                    let colors = colors.as_slice().unwrap();
+                    (decode_color(&colors[0]), decode_color(&colors[1]))
+                }
+                None => (Vec3::new(0., 0., 0.), Vec3::new(1., 1., 1.)),
+            };
+            Some(Box::new(ProceduralTexture::new(decode_procedural(procedural), low, high))
+                 as Box<Texture>)
         } else {
             None
         }
@@ -104,11 +964,11 @@ fn decode_material(material: &toml::Value) -> (String, Material) {
 
     let normal_map = if let Some(map) = material.lookup("normal_map") {
         let v = map.as_slice().unwrap();
-        let seed = v[0].as_float().unwrap() as u32;
-        let octaves = v[1].as_float().unwrap() as usize;
-        let wavelength = v[2].as_float().unwrap() as f32;
-        let persistence = v[3].as_float().unwrap() as f32;
-        let lacunarity = v[4].as_float().unwrap() as f32;
+        let seed = decode_f32(&v[0]) as u32;
+        let octaves = decode_f32(&v[1]) as usize;
+        let wavelength = decode_f32(&v[2]);
+        let persistence = decode_f32(&v[3]);
+        let lacunarity = decode_f32(&v[4]);
         Some(NormalMap::new(seed, octaves, wavelength, persistence, lacunarity))
     } else {
         None
@@ -116,70 +976,805 @@ fn decode_material(material: &toml::Value) -> (String, Material) {
 
     let displacement_map = if let Some(map) = material.lookup("displacement_map") {
         let v = map.as_slice().unwrap();
-        let seed = v[0].as_float().unwrap() as u32;
-        let octaves = v[1].as_float().unwrap() as usize;
-        let wavelength = v[2].as_float().unwrap() as f32;
-        let persistence = v[3].as_float().unwrap() as f32;
-        let lacunarity = v[4].as_float().unwrap() as f32;
+        let seed = decode_f32(&v[0]) as u32;
+        let octaves = decode_f32(&v[1]) as usize;
+        let wavelength = decode_f32(&v[2]);
+        let persistence = decode_f32(&v[3]);
+        let lacunarity = decode_f32(&v[4]);
         Some(DisplacementMap::new(seed, octaves, wavelength, persistence, lacunarity))
     } else {
         None
     };
     let m = Material::new(color, diffuse, specular, glossiness, reflectivity, texture, normal_map,
                           displacement_map);
+    let shadow_catcher = material.lookup("shadow_catcher").map_or(false, |b| b.as_bool().unwrap());
+    let m = if shadow_catcher { m.with_compositing(Compositing::ShadowCatcher) } else { m };
+    let fresnel = material.lookup("fresnel").map_or(false, |b| b.as_bool().unwrap());
+    let m = if fresnel { m.with_fresnel() } else { m };
+    let m = match material.lookup("transparency") {
+        Some(transparency) => {
+            let ior = material.lookup("ior").map_or(1.5, decode_f32);
+            m.with_transparency(decode_f32(transparency), ior)
+        }
+        None => m,
+    };
+    let m = match material.lookup("dispersion") {
+        Some(dispersion) => m.with_dispersion(decode_f32(dispersion)),
+        None => m,
+    };
+    let m = match material.lookup("absorption") {
+        Some(absorption) => m.with_absorption(decode_vec3(absorption)),
+        None => m,
+    };
+    let m = match material.lookup("subsurface_radius") {
+        Some(radius) => {
+            let albedo = material.lookup("subsurface_albedo")
+                .map_or(Vec3::new(1., 1., 1.), decode_vec3);
+            m.with_subsurface(Subsurface::new(decode_vec3(radius), albedo))
+        }
+        None => m,
+    };
+    let m = match roughness {
+        Some(roughness) => {
+            let metallic_map = material.lookup("metallic_map")
+                .map(|file| linear_texture(file, assets));
+            let metallic = material.lookup("metallic")
+                .map_or(if metallic_map.is_some() { 1. } else { 0. }, decode_f32);
+            let m = m.with_ggx(metallic, roughness);
+            let m = match anisotropic {
+                Some((along, across)) => m.with_anisotropic_roughness(along, across),
+                None => m,
+            };
+            let m = match roughness_map {
+                Some(map) => m.with_roughness_map(map),
+                None => m,
+            };
+            match metallic_map {
+                Some(map) => m.with_metallic_map(map),
+                None => m,
+            }
+        }
+        None => m,
+    };
+    let m = match material.lookup("emission") {
+        Some(emission) => {
+            let strength = material.lookup("emission_strength").map_or(1., decode_f32);
+            m.with_emission(decode_color(emission) * strength)
+        }
+        None => m,
+    };
+    let m = match material.lookup("bump") {
+        Some(bump) => {
+            let strength = bump.lookup("strength").map_or(1., decode_f32);
+            m.with_bump(Bump::new(decode_procedural(bump), strength))
+        }
+        None => m,
+    };
+    let m = match material.lookup("normal_texture") {
+        Some(file) => m.with_normal_texture(linear_texture(file, assets)),
+        None => m,
+    };
+    let m = match material.lookup("bump_texture") {
+        Some(file) => {
+            let strength = material.lookup("bump_strength").map_or(1., decode_f32);
+            m.with_bump_texture(linear_texture(file, assets), strength)
+        }
+        None => m,
+    };
+    let m = match decode_backfaces(material) {
+        Some(backfaces) => m.with_backfaces(backfaces),
+        None => m,
+    };
+    let m = m.with_name(&name);
     (name, m)
 }
 
-fn decode_scene(scene: &toml::Value, materials: BTreeMap<String, Material>) -> Scene {
+// An image file's texture, shared with the other materials using the file
+fn image_texture(file: &toml::Value, assets: &Assets) -> Box<Texture> {
+    assets.texture(file.as_str().unwrap())
+}
+
+// An image file of values, like a roughness or normal map, which unlike colors aren't sRGB
+fn linear_texture(file: &toml::Value, assets: &Assets) -> Box<Texture> {
+    assets.linear_texture(file.as_str().unwrap())
+}
+
+fn decode_procedural(procedural: &toml::Value) -> Procedural {
+    let basis = procedural.lookup("noise").map_or(Basis::Perlin,
+                                                  |n| decode_string(n).parse().unwrap());
+    let pattern = procedural.lookup("pattern").map_or(Pattern::Fbm,
+                                                      |p| decode_string(p).parse().unwrap());
+    let seed = procedural.lookup("seed").map_or(0, |seed| seed.as_integer().unwrap());
+    let octaves = procedural.lookup("octaves").map_or(4, |n| n.as_integer().unwrap());
+    let scale = procedural.lookup("scale").map_or(1., decode_f32);
+    Procedural::new(basis, pattern, seed as u32, octaves as u32, scale)
+}
+
+fn decode_scene(scene: &toml::Value, materials: BTreeMap<String, Material>, assets: &Assets)
+                -> Result<Scene, String> {
     let camera = decode_camera(scene.lookup("camera").unwrap());
-    let surfaces = decode_surfaces(scene.lookup("surface").unwrap(), materials);
-    let lights = decode_lights(scene.lookup("light").unwrap());
-    let ambient_const = scene.lookup("ambient_const").unwrap().as_float().unwrap() as f32;
-    let ambient_color = decode_vec3(scene.lookup("ambient_color").unwrap());
+    let objects = match scene.lookup("object") {
+        Some(objects) => try!(decode_objects(objects, &materials, assets)),
+        None => BTreeMap::new(),
+    };
+    let surfaces = try!(decode_surfaces(scene.lookup("surface").unwrap(), materials, &objects,
+                                        &camera, assets));
+    // Scenes lit only by emissive surfaces have no lights
+    let lights = scene.lookup("light").map_or(Vec::new(), decode_lights);
+    debug!("{} surfaces, {} lights", surfaces.len(), lights.len());
+    let ambient_const = decode_f32(scene.lookup("ambient_const").unwrap());
+    let ambient_color = decode_color(scene.lookup("ambient_color").unwrap());
 
-    Scene::new(surfaces, lights, ambient_const, ambient_color, camera)
+    let mut scene_ = Scene::new(surfaces, lights, ambient_const, ambient_color, camera);
+    if let Some(map) = scene.lookup("ambient_map") {
+        let intensity = scene.lookup("ambient_map_intensity").map_or(1., decode_f32);
+        let environment = EnvironmentMap::new(map.as_str().unwrap(), intensity);
+        scene_.set_ambient_environment(environment.to_sh(AMBIENT_MAP_SAMPLES));
+    }
+    if let Some(map) = scene.lookup("environment") {
+        let intensity = scene.lookup("environment_intensity").map_or(1., decode_f32);
+        let samples = scene.lookup("environment_samples").map_or(16, |n| n.as_integer().unwrap());
+        scene_.set_environment(EnvironmentMap::new(map.as_str().unwrap(), intensity),
+                               samples as u32);
+    }
+    if let Some(portals) = scene.lookup("portal") {
+        for portal in portals.as_slice().unwrap() {
+            let vec = |key: &str| decode_vec3(portal.lookup(key).unwrap());
+            scene_.add_portal(Portal::new(vec("pos"), vec("u"), vec("v")));
+        }
+    }
+    // Either a color, or a [scene.background] table with `horizon` and `zenith` colors
+    if let Some(background) = scene.lookup("background") {
+        scene_.set_background(match background.lookup("zenith") {
+            Some(zenith) => {
+                let horizon = decode_color(background.lookup("horizon").unwrap());
+                Background::Gradient { horizon: horizon, zenith: decode_color(zenith) }
+            }
+            None => Background::Color(decode_color(background)),
+        });
+    }
+    if let Some(sections) = scene.lookup("section") {
+        for section in sections.as_slice().unwrap() {
+            scene_.add_section(decode_section(section));
+        }
+    }
+    if let Some(medium) = scene.lookup("medium") {
+        scene_.set_medium(decode_medium(medium));
+    }
+    if let Some(transparent) = scene.lookup("transparent_background") {
+        scene_.set_transparent(transparent.as_bool().unwrap());
+    }
+    if let Some(spectral) = scene.lookup("spectral") {
+        scene_.set_spectral(spectral.as_bool().unwrap());
+    }
+    if let Some(integrator) = scene.lookup("integrator") {
+        let integrator = match integrator.as_str().unwrap().parse().unwrap() {
+            Integrator::AmbientOcclusion { samples, distance } => {
+                Integrator::AmbientOcclusion {
+                    samples: scene.lookup("ao_samples")
+                        .map_or(samples, |n| n.as_integer().unwrap() as u32),
+                    distance: scene.lookup("ao_distance").map_or(distance, decode_f32),
+                }
+            }
+            integrator => integrator,
+        };
+        scene_.set_integrator(integrator);
+    }
+    if let Some(depth) = scene.lookup("refraction_depth") {
+        scene_.set_refraction_depth(depth.as_integer().unwrap() as u16);
+    }
+    if let Some(depth) = scene.lookup("shadow_depth") {
+        scene_.set_shadow_depth(depth.as_integer().unwrap() as u16);
+    }
+    match (scene.lookup("ray_bias"), scene.lookup("ray_bias_scale")) {
+        (None, None) => {}
+        (min, relative) => {
+            let default = Bias::default();
+            scene_.set_bias(Bias::new(min.map_or(default.min, decode_f32),
+                                      relative.map_or(default.relative, decode_f32)));
+        }
+    }
+    if let Some(settings) = scene.lookup("bvh") {
+        scene_.set_bvh(decode_bvh(settings, bvh::OBJECT_SETTINGS));
+    }
+    let bounces = |key: &str| scene.lookup(key).map(|n| n.as_integer().unwrap() as u32);
+    match (bounces("roulette_bounces"), bounces("max_bounces")) {
+        (None, None) => {}
+        (roulette, max) => {
+            scene_.set_path_bounces(roulette.unwrap_or(path::MIN_BOUNCES),
+                                    max.unwrap_or(path::MAX_BOUNCES))
+        }
+    }
+    if let Some(sampler) = scene.lookup("sampler") {
+        let name = sampler.as_str().unwrap();
+        scene_.set_sampler(sampler::by_name(name).expect(&format!("Unknown sampler {}", name)));
+    }
+    if let Some(samples) = scene.lookup("emission_samples") {
+        scene_.set_emission_samples(samples.as_integer().unwrap() as u32);
+    }
+    if let Some(samples) = scene.lookup("light_samples") {
+        scene_.set_light_samples(samples.as_integer().unwrap() as u32);
+    }
+    if let Some(samples) = scene.lookup("guiding_samples") {
+        scene_.learn_guide(samples.as_integer().unwrap() as u32);
+    }
+    Ok(scene_)
+}
+
+const AMBIENT_MAP_SAMPLES: u32 = 4096;
+
+fn decode_medium(medium: &toml::Value) -> Medium {
+    let density = medium.lookup("density").map_or(1., decode_f32);
+    let scattering = decode_f32(medium.lookup("scattering").unwrap());
+    let absorption = medium.lookup("absorption").map_or(0., decode_f32);
+    let mut medium_ = Medium::new(density, scattering, absorption);
+    if let Some(anisotropy) = medium.lookup("anisotropy") {
+        medium_ = medium_.with_anisotropy(decode_f32(anisotropy));
+    }
+    if let Some(min) = medium.lookup("min") {
+        let max = decode_vec3(medium.lookup("max").expect("A medium with min also needs max"));
+        medium_ = medium_.with_bounds(Aabb::new(decode_vec3(min), max));
+    }
+    match medium.lookup("steps") {
+        Some(steps) => medium_.with_steps(steps.as_integer().unwrap() as u32),
+        None => medium_,
+    }
 }
 
 fn decode_camera(camera: &toml::Value) -> Camera {
+    // A camera of a glTF scene, the first one unless `gltf_camera` picks another
+    let camera_ = match camera.lookup("gltf") {
+        Some(file) => {
+            let index = camera.lookup("gltf_camera").map_or(0, |i| i.as_integer().unwrap());
+            gltf::load_camera(&decode_string(file), index as usize)
+        }
+        None => decode_view(camera),
+    };
+    let camera_ = match camera.lookup("shutter").and_then(|s| s.as_slice()) {
+        Some(shutter) => camera_.with_shutter(decode_f32(&shutter[0]), decode_f32(&shutter[1])),
+        None => camera_,
+    };
+    let camera_ = match camera.lookup("aperture") {
+        Some(aperture) => camera_.with_lens(decode_lens(camera, decode_f32(aperture))),
+        None => camera_,
+    };
+    camera_.with_imaging(decode_imaging(camera))
+}
+
+// The camera's exposure in stops, white balance temperature and vignetting
+fn decode_imaging(camera: &toml::Value) -> Imaging {
+    Imaging {
+        ev: camera.lookup("exposure").map_or(0., decode_f32),
+        white_balance: camera.lookup("white_balance").map(|temperature| {
+            (decode_f32(temperature), camera.lookup("tint").map_or(0., decode_f32))
+        }),
+        vignette: camera.lookup("vignette").map(|amount| {
+            (decode_f32(amount), camera.lookup("vignette_radius").map_or(0.5, decode_f32))
+        }),
+    }
+}
+
+// Where the camera is, where it looks and how it projects the scene
+fn decode_view(camera: &toml::Value) -> Camera {
     let pos = decode_vec3(camera.lookup("pos").unwrap());
     let lookat = decode_vec3(camera.lookup("lookat").unwrap());
     let up = decode_vec3(camera.lookup("up").unwrap());
-    Camera::from_lookat(pos, lookat, up)
+    let near = camera.lookup("near").map_or(0., decode_f32);
+    let far = camera.lookup("far").map_or(float::INFINITY, decode_f32);
+    let camera_ = Camera::from_lookat(pos, lookat, up).with_clip(near, far);
+    match camera.lookup("projection") {
+        Some(projection) => {
+            let projection = match projection.as_str().unwrap().parse().unwrap() {
+                Projection::Perspective { fov } => {
+                    Projection::Perspective { fov: camera.lookup("fov").map_or(fov, decode_f32) }
+                }
+                // Frames what's around `lookat` like the default perspective does
+                Projection::Orthographic { .. } => {
+                    let height = camera.lookup("view_height")
+                        .map_or((lookat - pos).norm(), decode_f32);
+                    Projection::Orthographic { height: height }
+                }
+                Projection::Fisheye { fov } => {
+                    Projection::Fisheye { fov: camera.lookup("fov").map_or(fov, decode_f32) }
+                }
+                projection => projection,
+            };
+            camera_.with_projection(projection)
+        }
+        None => match camera.lookup("fov") {
+            Some(fov) => camera_.with_projection(Projection::Perspective { fov: decode_f32(fov) }),
+            None => camera_,
+        },
+    }
+}
+
+fn decode_lens(camera: &toml::Value, radius: Float) -> Lens {
+    let focus_dist = decode_f32(camera.lookup("focus_dist").unwrap());
+    let aperture = if let Some(blades) = camera.lookup("aperture_blades") {
+        let blades = blades.as_integer().unwrap();
+        assert!(blades >= 3, "An aperture needs at least 3 blades, not {}", blades);
+        let rotation = camera.lookup("aperture_rotation").map_or(0., decode_f32);
+        Aperture::Polygon {
+            blades: blades as u32,
+            rotation: rotation.to_radians(),
+        }
+    } else if let Some(mask) = camera.lookup("aperture_mask") {
+        Aperture::from_mask(mask.as_str().unwrap())
+    } else {
+        Aperture::Disc
+    };
+    Lens::new(radius, focus_dist, aperture)
+}
+
+// Named surfaces in [[scene.object]] tables, to be placed any number of times by instances. Each
+// can use the objects before it
+// A [[scene.object]], with the coarser levels of detail in its [[lod]] tables and the share of
+// the image height each is used below, largest first
+struct Object {
+    surface: Arc<Box<Surface>>,
+    lods: Vec<(Float, Arc<Box<Surface>>)>,
+}
+
+fn decode_objects(objects: &toml::Value, materials: &BTreeMap<String, Material>, assets: &Assets)
+                  -> Result<BTreeMap<String, Object>, String> {
+    let mut map = BTreeMap::new();
+    for object in objects.as_slice().unwrap() {
+        let name = decode_string(object.lookup("name").unwrap());
+        let surface = try!(decode_surface(object, materials, &map, None, None, assets));
+        let mut lods = Vec::new();
+        for level in object.lookup("lod").map_or(&[][..], |lods| lods.as_slice().unwrap()) {
+            let below = decode_f32(level.lookup("screen_size").unwrap());
+            let level = try!(decode_surface(level, materials, &map, None, None, assets));
+            lods.push((below, Arc::new(level)));
+        }
+        lods.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        map.insert(name, Object { surface: Arc::new(surface), lods: lods });
+    }
+    Ok(map)
 }
 
-fn decode_surfaces(surfaces: &toml::Value, materials: BTreeMap<String, Material>)
-                   -> Vec<Box<Surface>> {
+fn decode_surfaces(surfaces: &toml::Value, materials: BTreeMap<String, Material>,
+                   objects: &BTreeMap<String, Object>, camera: &Camera, assets: &Assets)
+                   -> Result<Vec<Box<Surface>>, String> {
     let mut v = Vec::new();
     for surface in surfaces.as_slice().unwrap() {
-        v.push(decode_surface(surface, &materials))
+        try!(decode_node(surface, &materials, objects, None, Some(camera), assets, &mut v));
     }
-    v
+    Ok(v)
+}
+
+// Adds the surface in `node` to `surfaces`, or if it's a group, the surfaces in its
+// [[scene.surface.surface]] tables and the groups among them, each placed by the transforms of
+// the groups it's in. They're all flattened into `surfaces`, so the scene's bounding volume
+// hierarchy sees each one on its own. `parent` is the transform of the groups around `node`
+fn decode_node(node: &toml::Value, materials: &BTreeMap<String, Material>,
+               objects: &BTreeMap<String, Object>, parent: Option<&Transform>,
+               camera: Option<&Camera>, assets: &Assets, surfaces: &mut Vec<Box<Surface>>)
+               -> Result<(), String> {
+    if node.lookup("type").unwrap().as_str().unwrap() != "group" {
+        surfaces.push(try!(decode_surface(node, materials, objects, parent, camera, assets)));
+        return Ok(());
+    }
+    let transform = place(node.lookup("transform").map(decode_placement), parent);
+    for child in node.lookup("surface").unwrap().as_slice().unwrap() {
+        try!(decode_node(child, materials, objects, transform.as_ref(), camera, assets,
+                         surfaces));
+    }
+    Ok(())
 }
 
-fn decode_surface(surface: &toml::Value, materials: &BTreeMap<String, Material>) -> Box<Surface> {
+// `own` and then `parent`, either of which may be missing
+fn place(own: Option<Transform>, parent: Option<&Transform>) -> Option<Transform> {
+    match (own, parent) {
+        (Some(own), Some(parent)) => Some(own.then(parent)),
+        (own, None) => own,
+        (None, parent) => parent.cloned(),
+    }
+}
+
+// The surface in `surface`, placed by its own transform and then by `parent`, the transform of
+// the groups it's in. Instances pick their objects' levels of detail by how big `camera` sees
+// them, if the camera and where they end up in the scene are known
+fn decode_surface(surface: &toml::Value, materials: &BTreeMap<String, Material>,
+                  objects: &BTreeMap<String, Object>, parent: Option<&Transform>,
+                  camera: Option<&Camera>, assets: &Assets)
+                  -> Result<Box<Surface>, String> {
+    let motion = surface.lookup("motion").map(decode_vec3);
+    // A moving surface moves within its groups, so only its own transform goes inside the motion
+    let inner = if motion.is_some() { None } else { parent };
+    let own = surface.lookup("transform").map(decode_placement);
+    let placed: Box<Surface> = match surface.lookup("type").unwrap().as_str().unwrap() {
+        // Placed by its transform rather than wrapped in another one
+        "instance" => {
+            let camera = camera.map(|camera| (camera, place(own.clone(), parent)));
+            Box::new(decode_instance(surface, objects, place(own, inner), camera))
+        }
+        type_ => {
+            let decoded: Box<Surface> = if type_ == "csg" {
+                Box::new(try!(decode_csg(surface, materials, objects, assets)))
+            } else {
+                try!(decode_primitive(surface, materials, assets))
+            };
+            match place(own, inner) {
+                Some(transform) => Box::new(Transformed::placed(decoded, transform)),
+                None => decoded,
+            }
+        }
+    };
+    // How far the surface moves over the frame, for motion blur
+    let moved: Box<Surface> = match (motion, parent) {
+        (Some(motion), Some(parent)) => {
+            let moving = Box::new(Moving::new(placed, motion));
+            Box::new(Transformed::placed(moving, parent.clone()))
+        }
+        (Some(motion), None) => Box::new(Moving::new(placed, motion)),
+        (None, _) => placed,
+    };
+    // Cut by its own section planes, which stay put in the scene as the surface moves
+    Ok(match surface.lookup("section") {
+        Some(sections) => {
+            let planes = sections.as_slice().unwrap().iter().map(decode_section).collect();
+            Box::new(Sectioned::new(moved, planes))
+        }
+        None => moved,
+    })
+}
+
+fn decode_section(section: &toml::Value) -> SectionPlane {
+    let pos = decode_vec3(section.lookup("pos").unwrap());
+    let normal = decode_vec3(section.lookup("normal").unwrap());
+    let cap = section.lookup("cap").map_or(false, |b| b.as_bool().unwrap());
+    SectionPlane::new(pos, normal, cap)
+}
+
+// By `two_sided` or `cull_backfaces` on a material or surface, if it has either
+fn decode_backfaces(table: &toml::Value) -> Option<Backfaces> {
+    if table.lookup("two_sided").is_none() && table.lookup("cull_backfaces").is_none() {
+        return None;
+    }
+    let flag = |key: &str| table.lookup(key).map_or(false, |b| b.as_bool().unwrap());
+    Some(if flag("two_sided") {
+        Backfaces::Flipped
+    } else if flag("cull_backfaces") {
+        Backfaces::Culled
+    } else {
+        Backfaces::Shaded
+    })
+}
+
+// Meshes are charged against the memory budget as they load, see decode_mesh, and the other
+// primitives once they're decoded
+fn decode_primitive(surface: &toml::Value, materials: &BTreeMap<String, Material>,
+                    assets: &Assets)
+                    -> Result<Box<Surface>, String> {
     let material_name = surface.lookup("material").unwrap().as_str().unwrap();
     let material = materials.get(material_name).unwrap().clone();
+    let material = match surface.lookup("holdout").map(|b| b.as_bool().unwrap()) {
+        Some(true) => material.with_compositing(Compositing::Holdout),
+        _ => material,
+    };
+    let flag = |key: &str| surface.lookup(key).map_or(true, |b| b.as_bool().unwrap());
+    let material = material.with_visibility(Visibility {
+        camera: flag("visible_to_camera"),
+        reflections: flag("visible_in_reflections"),
+        shadows: flag("casts_shadows"),
+    });
+    // Or as the material has it
+    let material = match decode_backfaces(surface) {
+        Some(backfaces) => material.with_backfaces(backfaces),
+        None => material,
+    };
 
     let type_ = surface.lookup("type").unwrap().as_str().unwrap();
-    match type_ {
-        "plane" => Box::new(decode_plane(surface, material)),
+    trace!("{} with material {}", type_, material_name);
+    let primitive: Box<Surface> = match type_ {
+        "plane" => Box::new(decode_plane(surface, material, materials)),
         "sphere" => Box::new(decode_sphere(surface, material)),
+        "mesh" => return decode_mesh(surface, material, assets),
+        "heightfield" => Box::new(decode_heightfield(surface, material)),
+        "cylinder" => {
+            let (base, top, radius) = decode_round(surface);
+            Box::new(Cylinder::new(base, top, radius, material))
+        }
+        "cone" => {
+            let (base, tip, radius) = decode_round(surface);
+            Box::new(Cone::new(base, tip, radius, material))
+        }
+        "disk" => Box::new(decode_disk(surface, material)),
+        "triangle" => Box::new(decode_triangle(surface, material)),
+        "quad" => Box::new(decode_quad(surface, material)),
+        "torus" => Box::new(decode_torus(surface, material)),
+        "sdf" => Box::new(Sdf::new(decode_shape(surface.lookup("shape").unwrap()), material)),
         _ => panic!("Unsupported object type: {}", type_)
-    }
+    };
+    try!(assets.charge(type_, mem::size_of_val(&*primitive) + primitive.heap_size()));
+    Ok(primitive)
+}
+
+// Combines the surfaces in the [scene.surface.a] and [scene.surface.b] tables by `operation`.
+// They can be CSG surfaces themselves
+fn decode_csg(csg: &toml::Value, materials: &BTreeMap<String, Material>,
+              objects: &BTreeMap<String, Object>, assets: &Assets)
+              -> Result<Csg, String> {
+    let op = csg.lookup("operation").unwrap().as_str().unwrap().parse().unwrap();
+    let a = try!(decode_surface(csg.lookup("a").unwrap(), materials, objects, None, None, assets));
+    let b = try!(decode_surface(csg.lookup("b").unwrap(), materials, objects, None, None, assets));
+    Ok(Csg::new(op, a, b))
+}
+
+// The [[scene.object]] named by `object`, placed by `transform` if any. Given the camera and
+// where the instance ends up in the scene, the coarsest of the object's levels of detail whose
+// screen_size it's smaller than instead
+fn decode_instance(instance: &toml::Value, objects: &BTreeMap<String, Object>,
+                   transform: Option<Transform>, camera: Option<(&Camera, Option<Transform>)>)
+                   -> Instance {
+    let name = instance.lookup("object").unwrap().as_str().unwrap();
+    let object = objects.get(name).unwrap_or_else(|| panic!("Unknown object: {}", name));
+    let identity = || {
+        Transform::new(Vec3::new(1., 1., 1.), Vec3::new(0., 0., 0.), Vec3::new(0., 0., 0.))
+    };
+    let transform = transform.unwrap_or_else(&identity);
+    let (camera, world) = match camera {
+        Some(camera) if !object.lods.is_empty() => camera,
+        _ => return Instance::placed(object.surface.clone(), transform),
+    };
+    // Measured around the finest level, so all levels switch at the same distance
+    let size = Instance::placed(object.surface.clone(), world.unwrap_or_else(&identity))
+        .bounds()
+        .map_or(float::INFINITY, |bounds| {
+            let center = (bounds.min + bounds.max) / 2.;
+            camera.screen_size(&center, (bounds.max - bounds.min).norm() / 2.)
+        });
+    let level = object.lods.iter().rev().find(|&&(below, _)| size < below)
+        .map_or(&object.surface, |&(_, ref level)| level);
+    Instance::placed(level.clone(), transform)
+}
+
+// A [scene.surface.transform] table, applied after the surface's own position: `scale` (a
+// number or per axis), `rotate` (degrees about x, y and z, in that order) and `translate`
+fn decode_placement(transform: &toml::Value) -> Transform {
+    let (scale, rotate, translate) = decode_transform_parts(transform);
+    Transform::new(scale, rotate, translate)
+}
+
+// The scale, rotation in radians and translation of a transform table
+fn decode_transform_parts(transform: &toml::Value) -> (Vec3, Vec3, Vec3) {
+    let scale = match transform.lookup("scale") {
+        Some(scale) if scale.as_slice().is_some() => decode_vec3(scale),
+        Some(scale) => Vec3::new(1., 1., 1.) * decode_f32(scale),
+        None => Vec3::new(1., 1., 1.),
+    };
+    let rotate = transform.lookup("rotate").map_or(Vec3::new(0., 0., 0.), decode_vec3);
+    let rotate = Vec3::new(rotate.x.to_radians(), rotate.y.to_radians(), rotate.z.to_radians());
+    let translate = transform.lookup("translate").map_or(Vec3::new(0., 0., 0.), decode_vec3);
+    (scale, rotate, translate)
 }
 
 fn decode_sphere(sphere: &toml::Value, material: Material) -> Sphere {
     let pos = decode_vec3(sphere.lookup("pos").unwrap());
-    let radius = sphere.lookup("radius").unwrap().as_float().unwrap() as f32;
+    let radius = decode_f32(sphere.lookup("radius").unwrap());
 
     Sphere::new(pos, radius, material)
 }
 
-fn decode_plane(plane: &toml::Value, material: Material) -> Plane {
+fn decode_plane(plane: &toml::Value, material: Material, materials: &BTreeMap<String, Material>)
+                -> Plane {
     let pos = decode_vec3(plane.lookup("pos").unwrap());
     let normal = decode_vec3(plane.lookup("normal").unwrap());
 
-    Plane::new(pos, normal, material)
+    match plane.lookup("pattern") {
+        Some(pattern) => {
+            let size = pattern.lookup("size").map_or(1., decode_f32);
+            let type_ = pattern.lookup("type").unwrap().as_str().unwrap();
+            let pattern_ = match type_ {
+                "checkerboard" => PlanePattern::Checkerboard { size: size },
+                "grid" => {
+                    let width = pattern.lookup("line_width").map_or(size / 20., decode_f32);
+                    PlanePattern::Grid { size: size, width: width }
+                }
+                _ => panic!("Unknown plane pattern: {}", type_),
+            };
+            // The pattern's material keeps the plane's holdout and visibility
+            let name = pattern.lookup("material").unwrap().as_str().unwrap();
+            let other = materials.get(name).unwrap().clone()
+                .with_compositing(material.compositing())
+                .with_visibility(material.visibility());
+            Plane::new(pos, normal, material).with_pattern(pattern_, other)
+        }
+        None => Plane::new(pos, normal, material),
+    }
+}
+
+// The center of the base, the center of the top (the tip of cones) and the radius
+fn decode_round(surface: &toml::Value) -> (Vec3, Vec3, Float) {
+    (decode_vec3(surface.lookup("pos").unwrap()), decode_vec3(surface.lookup("top").unwrap()),
+     decode_f32(surface.lookup("radius").unwrap()))
+}
+
+fn decode_disk(disk: &toml::Value, material: Material) -> Disk {
+    let pos = decode_vec3(disk.lookup("pos").unwrap());
+    let normal = decode_vec3(disk.lookup("normal").unwrap());
+    let radius = decode_f32(disk.lookup("radius").unwrap());
+
+    Disk::new(pos, normal, radius, material)
+}
+
+fn decode_triangle(triangle: &toml::Value, material: Material) -> Triangle {
+    let (c, normals, uvs) = decode_corners(triangle, 3);
+    let triangle = Triangle::new([c[0], c[1], c[2]], material);
+    let triangle = match normals {
+        Some(n) => triangle.with_normals([n[0], n[1], n[2]]),
+        None => triangle,
+    };
+    match uvs {
+        Some(t) => triangle.with_uvs([t[0], t[1], t[2]]),
+        None => triangle,
+    }
+}
+
+fn decode_quad(quad: &toml::Value, material: Material) -> Quad {
+    let (c, normals, uvs) = decode_corners(quad, 4);
+    let quad = Quad::new([c[0], c[1], c[2], c[3]], material);
+    let quad = match normals {
+        Some(n) => quad.with_normals([n[0], n[1], n[2], n[3]]),
+        None => quad,
+    };
+    match uvs {
+        Some(t) => quad.with_uvs([t[0], t[1], t[2], t[3]]),
+        None => quad,
+    }
+}
+
+// The `corners` of a triangle or quad, and the `normals` and `uvs` of each corner if it has them
+fn decode_corners(surface: &toml::Value, count: usize)
+                  -> (Vec<Vec3>, Option<Vec<Vec3>>, Option<Vec<(Float, Float)>>) {
+    let type_ = surface.lookup("type").unwrap().as_str().unwrap();
+    let list = |key: &str, values: &toml::Value| {
+        let values = values.as_slice().unwrap().to_vec();
+        assert!(values.len() == count, "A {} needs {} {}, not {}", type_, count, key,
+                values.len());
+        values
+    };
+    let vecs = |key: &str| {
+        surface.lookup(key).map(|values| list(key, values).iter().map(decode_vec3).collect())
+    };
+    let uvs = surface.lookup("uvs").map(|values| {
+        list("uvs", values).iter().map(|uv| {
+            let uv = uv.as_slice().unwrap();
+            (decode_f32(&uv[0]), decode_f32(&uv[1]))
+        }).collect()
+    });
+    (vecs("corners").unwrap(), vecs("normals"), uvs)
+}
+
+fn decode_torus(torus: &toml::Value, material: Material) -> Torus {
+    let pos = decode_vec3(torus.lookup("pos").unwrap());
+    let normal = decode_vec3(torus.lookup("normal").unwrap());
+    let radius = decode_f32(torus.lookup("radius").unwrap());
+    let minor_radius = decode_f32(torus.lookup("minor_radius").unwrap());
+
+    Torus::new(pos, normal, radius, minor_radius, material)
+}
+
+// The [scene.surface.shape] table of an SDF surface. Smooth unions blend the shapes in their
+// [scene.surface.shape.a] and [scene.surface.shape.b] tables, which can be smooth unions too
+fn decode_shape(shape: &toml::Value) -> Shape {
+    let center = shape.lookup("pos").map_or(Vec3::new(0., 0., 0.), decode_vec3);
+    let number = |key: &str| decode_f32(shape.lookup(key).unwrap());
+    match shape.lookup("type").unwrap().as_str().unwrap() {
+        "sphere" => Shape::Sphere { center: center, radius: number("radius") },
+        "rounded_box" => {
+            Shape::RoundedBox {
+                center: center,
+                size: decode_vec3(shape.lookup("size").unwrap()),
+                radius: shape.lookup("radius").map_or(0., decode_f32),
+            }
+        }
+        "torus" => {
+            Shape::Torus {
+                center: center,
+                radius: number("radius"),
+                minor_radius: number("minor_radius"),
+            }
+        }
+        "smooth_union" => {
+            let a = decode_shape(shape.lookup("a").unwrap());
+            let b = decode_shape(shape.lookup("b").unwrap());
+            Shape::SmoothUnion(Box::new(a), Box::new(b), number("blend"))
+        }
+        type_ => panic!("Unknown SDF shape: {}", type_),
+    }
+}
+
+// The keys of a mesh surface that make it a different mesh, rather than the same one placed
+// elsewhere
+const MESH_KEYS: &'static [&'static str] = &["file", "subdivisions", "bvh", "watertight",
+                                              "crease_angle", "material", "holdout",
+                                              "visible_to_camera", "visible_in_reflections",
+                                              "casts_shadows"];
+
+// The mesh in `file`, shared with the other surfaces using it with the same material and
+// settings, scaled by `scale` and moved by `pos`. Emissive meshes are loaded on their own,
+// since only they can be sampled as area lights, not instances of them
+fn decode_mesh(mesh: &toml::Value, material: Material, assets: &Assets)
+               -> Result<Box<Surface>, String> {
+    let file = mesh.lookup("file").unwrap().as_str().unwrap();
+    let scale = mesh.lookup("scale").map_or(1., decode_f32);
+    let pos = mesh.lookup("pos").map_or(Vec3::new(0., 0., 0.), decode_vec3);
+    let settings = mesh.lookup("bvh").map(|table| decode_bvh(table, bvh::TRIANGLE_SETTINGS));
+    let watertight = mesh.lookup("watertight").map_or(false, |w| w.as_bool().unwrap());
+    let crease_angle = mesh.lookup("crease_angle").map(decode_f32);
+
+    let subdivisions = mesh.lookup("subdivisions").map_or(0, |s| s.as_integer().unwrap());
+    if subdivisions > 0 {
+        assert!(file.ends_with(".obj"), "Only OBJ meshes can be subdivided: {}", file);
+    }
+    let load = |material: Material| {
+        let loaded = if subdivisions > 0 {
+            TriangleMesh::load_subdivided(file, material, subdivisions as u32)
+        } else {
+            TriangleMesh::load(file, material)
+        };
+        let loaded = loaded.with_watertight(watertight);
+        let loaded = match crease_angle {
+            Some(degrees) => loaded.with_crease_angle(degrees),
+            None => loaded,
+        };
+        match settings {
+            Some(settings) => loaded.with_bvh(settings),
+            None => loaded,
+        }
+    };
+    let what = format!("mesh {}", file);
+    let compact = file.ends_with(".rtmesh");
+    if material.is_emissive() {
+        if compact {
+            return Err(format!("{}: compact meshes can't be emissive", file));
+        }
+        let loaded = load(material).transformed(scale, pos);
+        try!(assets.charge(&what, mem::size_of_val(&loaded) + loaded.heap_size()));
+        return Ok(Box::new(loaded));
+    }
+    let key: Vec<String> = MESH_KEYS.iter()
+        .map(|key| mesh.lookup(key).map_or(String::new(), |value| value.to_string()))
+        .collect();
+    let shared = try!(assets.mesh(&key.join("\n"), &what, || {
+        if compact {
+            CompactMesh::open(file, material).map(|mesh| Box::new(mesh) as Box<Surface>)
+                .map_err(|error| format!("{}: {}", file, error))
+        } else {
+            Ok(Box::new(load(material)))
+        }
+    }));
+    Ok(Box::new(Instance::new(shared, Vec3::new(scale, scale, scale), Vec3::new(0., 0., 0.),
+                              pos)))
+}
+
+// A [bvh] table's max_leaf_size and traversal_cost, or those of `default` that it leaves out
+fn decode_bvh(settings: &toml::Value, default: BvhSettings) -> BvhSettings {
+    let max_leaf_size = settings.lookup("max_leaf_size")
+        .map_or(default.max_leaf_size, |n| n.as_integer().unwrap() as usize);
+    let traversal_cost = settings.lookup("traversal_cost")
+        .map_or(default.traversal_cost, decode_f32);
+    BvhSettings::new(max_leaf_size, traversal_cost)
+}
+
+// Heights from a grayscale image `file`, or `heights`, an array of rows of numbers, spread over
+// `size` from the corner at `pos`
+fn decode_heightfield(heightfield: &toml::Value, material: Material) -> Heightfield {
+    let pos = heightfield.lookup("pos").map_or(Vec3::new(0., 0., 0.), decode_vec3);
+    let size = decode_vec3(heightfield.lookup("size").unwrap());
+    match heightfield.lookup("file") {
+        Some(file) => Heightfield::load(&decode_string(file), pos, size, material),
+        None => {
+            let rows = heightfield.lookup("heights").unwrap().as_slice().unwrap();
+            let columns = rows[0].as_slice().unwrap().len();
+            let heights = rows.iter()
+                .flat_map(|row| row.as_slice().unwrap().iter().map(decode_f32))
+                .collect();
+            Heightfield::new(heights, columns, rows.len(), pos, size, material)
+        }
+    }
 }
 
 fn decode_lights(lights: &toml::Value) -> Vec<PointLight> {
@@ -191,26 +1786,88 @@ fn decode_lights(lights: &toml::Value) -> Vec<PointLight> {
 }
 
 fn decode_light(light: &toml::Value) -> PointLight {
-    let pos = decode_vec3(light.lookup("pos").unwrap());
-    let color = decode_vec3(light.lookup("color").unwrap());
-    let intensity = light.lookup("intensity").unwrap().as_float().unwrap() as f32;
+    let type_ = light.lookup("type").map_or("point", |t| t.as_str().unwrap());
+    // Directional lights have no position
+    let pos = if type_ == "directional" {
+        Vec3::new(0., 0., 0.)
+    } else {
+        decode_vec3(light.lookup("pos").unwrap())
+    };
+    let color = decode_color(light.lookup("color").unwrap());
+    let intensity = decode_f32(light.lookup("intensity").unwrap());
+    let samples = light.lookup("samples").map_or(16, |n| n.as_integer().unwrap()) as u32;
+
+    let light_ = PointLight::new(pos, color, intensity);
+    let light_ = match light.lookup("distance_falloff") {
+        Some(falloff) => light_.with_falloff(decode_falloff(falloff)),
+        None => light_,
+    };
+    let light_ = match light.lookup("group") {
+        Some(group) => light_.with_group(&decode_string(group)),
+        None => light_,
+    };
+    match type_ {
+        "point" => light_,
+        "sphere" => {
+            let radius = decode_f32(light.lookup("radius").unwrap());
+            light_.with_shape(LightShape::Sphere(radius), samples)
+        }
+        "rect" => {
+            let u = decode_vec3(light.lookup("u").unwrap());
+            let v = decode_vec3(light.lookup("v").unwrap());
+            light_.with_shape(LightShape::Rect(u, v), samples)
+        }
+        "spot" => {
+            let dir = decode_vec3(light.lookup("dir").unwrap());
+            let angle = decode_f32(light.lookup("angle").unwrap());
+            let falloff = light.lookup("falloff").map_or(0., decode_f32);
+            light_.with_spot(dir, angle.to_radians(), falloff.to_radians())
+        }
+        "directional" => {
+            let dir = decode_vec3(light.lookup("dir").unwrap());
+            light_.with_shape(LightShape::Directional(dir), 1)
+        }
+        _ => panic!("Unsupported light type: {}", type_),
+    }
+}
 
-    PointLight::new(pos, color, intensity)
+// A name, or the [constant, linear, quadratic] coefficients of a polynomial
+fn decode_falloff(falloff: &toml::Value) -> Falloff {
+    match falloff.as_slice() {
+        Some(coeffs) => {
+            assert!(coeffs.len() == 3, "distance_falloff needs [constant, linear, quadratic]");
+            Falloff::Polynomial { constant: decode_f32(&coeffs[0]), linear: decode_f32(&coeffs[1]),
+                                  quadratic: decode_f32(&coeffs[2]) }
+        }
+        None => falloff.as_str().unwrap().parse().unwrap(),
+    }
 }
 
 fn decode_string(s: &toml::Value) -> String {
     s.as_str().unwrap().to_owned()
 }
 
+// Accepts both floats and integers, since JSON scenes don't distinguish 1 from 1.0
+fn decode_f32(f: &toml::Value) -> Float {
+    match f.as_float() {
+        Some(f) => f as Float,
+        None => f.as_integer().unwrap() as Float,
+    }
+}
+
 fn decode_vec3(vec: &toml::Value) -> Vec3 {
     let v = vec.as_slice().unwrap();
-    if v[0].as_float().is_none() {
-        Vec3::new(v[0].as_integer().unwrap() as f32,
-                  v[1].as_integer().unwrap() as f32,
-                  v[2].as_integer().unwrap() as f32)
-    } else {
-        Vec3::new(v[0].as_float().unwrap() as f32,
-                  v[1].as_float().unwrap() as f32,
-                  v[2].as_float().unwrap() as f32)
-    }
+    Vec3::new(decode_f32(&v[0]), decode_f32(&v[1]), decode_f32(&v[2]))
+}
+
+// Scene files give colors, and brightness levels like the bloom threshold, on the familiar 0-255
+// scale of 8 bit images, while the renderer works in linear 0..1
+const WHITE_LEVEL: Float = 255.;
+
+fn decode_color(color: &toml::Value) -> Vec3 {
+    decode_vec3(color) / WHITE_LEVEL
+}
+
+fn decode_level(level: &toml::Value) -> Float {
+    decode_f32(level) / WHITE_LEVEL
 }
@@ -1,22 +1,121 @@
-use std::f32;
 
-use Vec3;
+use {float, Float, Vec3};
+use procedural::Bump;
 use texture::Texture;
-use ray::{Intersection, Ray};
+use ray::{self, Intersection, Ray, RayKind};
+use sampling;
+use spectral;
+use subsurface::Subsurface;
 
-use nalgebra::{dot, Norm};
+use nalgebra::{cross, dot, Norm};
 
 use noise::{self, Brownian3, Seed};
 
 pub struct Material {
     color: Vec3,
-    diffuse_coeff: f32,
-    specular_coeff: f32,
-    glossiness: f32,
-    reflectivity: f32,
+    diffuse_coeff: Float,
+    specular_coeff: Float,
+    glossiness: Float,
+    reflectivity: Float,
+    // Whether reflectivity is weighted by the angle of view, see with_fresnel
+    fresnel: bool,
+    // Fraction of the light that passes through instead of being shaded, and the index of
+    // refraction it's bent by
+    transparency: Float,
+    ior: Float,
+    // Cauchy's B coefficient, in square micrometers, see with_dispersion
+    dispersion: Float,
+    // Per unit distance inside, for each channel, see with_absorption
+    absorption: Vec3,
+    subsurface: Option<Subsurface>,
     texture: Option<Box<Texture>>,
     normal_map: Option<NormalMap>,
     displacement_map: Option<DisplacementMap>,
+    bump: Option<Bump>,
+    // Images tilting the normal over the surface's texture coordinates, see with_normal_texture
+    // and with_bump_texture
+    normal_texture: Option<Box<Texture>>,
+    bump_texture: Option<(Box<Texture>, Float)>,
+    // Grayscale textures scaling a GGX material's roughness and metallic across the surface
+    roughness_map: Option<Box<Texture>>,
+    metallic_map: Option<Box<Texture>>,
+    // A GGX material's roughness along and across the tangent, see with_anisotropic_roughness
+    anisotropic: Option<(Float, Float)>,
+    // Light given off by the surface, in the same units as colors, added to whatever it reflects
+    emission: Vec3,
+    compositing: Compositing,
+    visibility: Visibility,
+    backfaces: Backfaces,
+    shading: Shading,
+    // As the scene or material library calls it, for debugging output
+    name: Option<String>,
+}
+
+// How light reflects off a material
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shading {
+    // Lambertian diffuse light plus a Blinn-Phong highlight, from the diffuse and specular
+    // coefficients and the glossiness
+    Phong,
+    // Physically based, with the color as albedo: diffuse light plus the highlight of a GGX
+    // microfacet surface. Metals (metallic 1) have no diffuse light and tint their highlight with
+    // their color; rough surfaces (roughness up to 1) spread it out
+    Ggx { metallic: Float, roughness: Float },
+}
+
+// How a material appears when the render is composited over other footage
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compositing {
+    // Shaded normally
+    Shaded,
+    // Only the shadows falling on it show, darkening whatever is behind it: the background, or
+    // with a transparent background, black with the strength of the shadow as alpha. For
+    // standing in for the ground of a photograph that objects are placed on
+    ShadowCatcher,
+    // Black, and transparent with a transparent background, but still hiding what's behind it
+    // and casting shadows. For objects in the photograph that rendered objects pass behind
+    Holdout,
+}
+
+// Which kinds of rays see surfaces with a material. Hiding some are cheats for lighting a shot,
+// e.g. a light blocker that only casts a shadow, or an object that shows no reflection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Visibility {
+    pub camera: bool,
+    pub reflections: bool,
+    pub shadows: bool,
+}
+
+impl Visibility {
+    pub fn all() -> Self {
+        Visibility { camera: true, reflections: true, shadows: true }
+    }
+
+    pub fn sees(&self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Reflection => self.reflections,
+            RayKind::Shadow => self.shadows,
+        }
+    }
+}
+
+// What rays do on the back of a surface, the side its normal points away from. Meshes are only
+// meant to be seen from the front if their faces are wound consistently, so imported ones often
+// need one of the others. Either is decided by the surface's material, also for the faces of
+// meshes with materials of their own
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backfaces {
+    // The normal is kept, so the back is lit from behind, as the inside of a closed object is.
+    // The default
+    Shaded,
+    // The normal is turned towards the ray, so both sides of an open surface like a leaf or a
+    // sheet are lit like its front. Not for transparent materials, which tell by the normal
+    // whether a ray enters or leaves
+    Flipped,
+    // Camera and reflected rays pass through the back of faces, as if they weren't there, while
+    // shadow rays still find them, so a mesh facing the wrong way still casts its shadow
+    Culled,
 }
 
 impl Clone for Material {
@@ -27,49 +126,452 @@ impl Clone for Material {
             specular_coeff: self.specular_coeff,
             glossiness: self.glossiness,
             reflectivity: self.reflectivity,
+            fresnel: self.fresnel,
+            transparency: self.transparency,
+            ior: self.ior,
+            dispersion: self.dispersion,
+            absorption: self.absorption,
+            subsurface: self.subsurface,
             texture: self.texture.as_ref().map(|t| t.clone_()),
             normal_map: self.normal_map.as_ref().map(|m| m.clone()),
             displacement_map: self.displacement_map.as_ref().map(|m| m.clone()),
+            bump: self.bump.clone(),
+            normal_texture: self.normal_texture.as_ref().map(|t| t.clone_()),
+            bump_texture: self.bump_texture.as_ref().map(|&(ref t, s)| (t.clone_(), s)),
+            roughness_map: self.roughness_map.as_ref().map(|t| t.clone_()),
+            metallic_map: self.metallic_map.as_ref().map(|t| t.clone_()),
+            anisotropic: self.anisotropic,
+            emission: self.emission,
+            compositing: self.compositing,
+            visibility: self.visibility,
+            backfaces: self.backfaces,
+            shading: self.shading,
+            name: self.name.clone(),
         }
     }
 }
 
 impl Material {
-    pub fn new(color: Vec3, diffuse_coeff: f32, specular_coeff: f32, glossiness: f32,
-               reflectivity: f32, texture: Option<Box<Texture>>,
+    // `color` is linear, from 0 to 1 in each channel: the fraction of the light the diffuse term
+    // reflects, scaled by `diffuse_coeff`. The highlight and the mirror reflection are scaled by
+    // `specular_coeff` and `reflectivity` and aren't tinted
+    pub fn new(color: Vec3, diffuse_coeff: Float, specular_coeff: Float, glossiness: Float,
+               reflectivity: Float, texture: Option<Box<Texture>>,
                normal_map: Option<NormalMap>, displacement_map: Option<DisplacementMap>) -> Self {
         Material { color: color, diffuse_coeff: diffuse_coeff,
                    specular_coeff: specular_coeff, glossiness: glossiness,
-                   reflectivity: reflectivity, texture: texture, normal_map: normal_map,
-                   displacement_map: displacement_map }
+                   reflectivity: reflectivity, fresnel: false, transparency: 0., ior: 1.,
+                   dispersion: 0., absorption: Vec3::new(0., 0., 0.), subsurface: None,
+                   texture: texture, normal_map: normal_map,
+                   displacement_map: displacement_map, bump: None, normal_texture: None,
+                   bump_texture: None,
+                   roughness_map: None, metallic_map: None, anisotropic: None,
+                   emission: Vec3::new(0., 0., 0.), compositing: Compositing::Shaded,
+                   visibility: Visibility::all(), backfaces: Backfaces::Shaded,
+                   shading: Shading::Phong, name: None }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| &name[..])
+    }
+
+    // Shades the material physically based instead, see Shading::Ggx. The diffuse and specular
+    // coefficients and glossiness are ignored then
+    pub fn with_ggx(mut self, metallic: Float, roughness: Float) -> Self {
+        assert!(metallic >= 0. && metallic <= 1., "Metallic must be between 0 and 1");
+        assert!(roughness >= 0. && roughness <= 1., "Roughness must be between 0 and 1");
+        self.shading = Shading::Ggx { metallic: metallic, roughness: roughness };
+        self
+    }
+
+    pub fn shading(&self) -> Shading {
+        self.shading
+    }
+
+    // Scales the GGX roughness by a grayscale texture, from none where it's black to all of it
+    // where it's white, e.g. for smudges on a polished surface
+    pub fn with_roughness_map(mut self, map: Box<Texture>) -> Self {
+        self.roughness_map = Some(map);
+        self
+    }
+
+    // Scales the GGX metallic likewise, e.g. for paint chipped off metal
+    pub fn with_metallic_map(mut self, map: Box<Texture>) -> Self {
+        self.metallic_map = Some(map);
+        self
+    }
+
+    // Gives a GGX material a different roughness along the surface's tangent, the direction its
+    // texture's u grows in, than across it, both 0 to 1, instead of its one roughness. Highlights
+    // stretch out in the rougher direction, like across the grooves of brushed metal or along
+    // hair. The roughness map scales both. Surfaces that don't know their tangent, and those
+    // without texture coordinates, use a fixed direction along them instead
+    pub fn with_anisotropic_roughness(mut self, tangent: Float, bitangent: Float) -> Self {
+        assert!(tangent >= 0. && tangent <= 1. && bitangent >= 0. && bitangent <= 1.,
+                "Roughness must be between 0 and 1");
+        let metallic = match self.shading {
+            Shading::Ggx { metallic, .. } => metallic,
+            Shading::Phong => panic!("Only GGX materials can have anisotropic roughness"),
+        };
+        self.shading = Shading::Ggx { metallic: metallic, roughness: (tangent + bitangent) / 2. };
+        self.anisotropic = Some((tangent, bitangent));
+        self
+    }
+
+    // The metallic and roughness of a GGX material at `hit`, with the maps applied
+    fn ggx_at(&self, hit: &Intersection) -> Option<(Float, Float)> {
+        match self.shading {
+            Shading::Phong => None,
+            Shading::Ggx { metallic, roughness } => {
+                Some((metallic * map_scale(&self.metallic_map, hit),
+                      roughness * map_scale(&self.roughness_map, hit)))
+            }
+        }
+    }
+
+    // The microfacets of a GGX material's highlight at `hit`, which has `roughness` there
+    fn lobe(&self, hit: &Intersection, roughness: Float) -> Lobe {
+        match self.anisotropic {
+            Some((along, across)) => {
+                let scale = map_scale(&self.roughness_map, hit);
+                Lobe { normal: hit.normal, alpha: (ggx_alpha(along * scale),
+                                                   ggx_alpha(across * scale)),
+                       frame: Some(tangent_frame(hit)) }
+            }
+            None => {
+                let alpha = ggx_alpha(roughness);
+                Lobe { normal: hit.normal, alpha: (alpha, alpha), frame: None }
+            }
+        }
+    }
+
+    pub fn with_compositing(mut self, compositing: Compositing) -> Self {
+        self.compositing = compositing;
+        self
+    }
+
+    pub fn compositing(&self) -> Compositing {
+        self.compositing
+    }
+
+    // Tilts the normal by a procedural pattern, after the normal map if there is one
+    pub fn with_bump(mut self, bump: Bump) -> Self {
+        self.bump = Some(bump);
+        self
+    }
+
+    // Tilts the normal by a tangent space normal map: red, green and blue from 0 to 1 are the
+    // new normal from -1 to 1 along u, along v and out of the surface, so the usual light blue is
+    // the normal as it is. The tangents follow the surface's texture coordinates
+    pub fn with_normal_texture(mut self, map: Box<Texture>) -> Self {
+        self.normal_texture = Some(map);
+        self
+    }
+
+    // Tilts the normal as if the surface were raised by a grayscale image, `strength` units
+    // where it's white, over the surface's texture coordinates. After a normal texture
+    pub fn with_bump_texture(mut self, map: Box<Texture>, strength: Float) -> Self {
+        self.bump_texture = Some((map, strength));
+        self
+    }
+
+    // Makes the surface glow with `emission`, e.g. [1, 1, 1] to look white at any
+    // distance. Emissive spheres, disks, cylinders and meshes light the scene like area lights
+    pub fn with_emission(mut self, emission: Vec3) -> Self {
+        assert!(emission.x >= 0. && emission.y >= 0. && emission.z >= 0.,
+                "Emission can't be negative");
+        self.emission = emission;
+        self
+    }
+
+    pub fn emission(&self) -> Vec3 {
+        self.emission
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission != Vec3::new(0., 0., 0.)
+    }
+
+    pub fn with_visibility(mut self, visibility: Visibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+
+    pub fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    pub fn with_backfaces(mut self, backfaces: Backfaces) -> Self {
+        self.backfaces = backfaces;
+        self
+    }
+
+    pub fn backfaces(&self) -> Backfaces {
+        self.backfaces
+    }
+
+    // Makes the material transparent like glass, e.g. with an `ior` of 1.5
+    pub fn with_transparency(mut self, transparency: Float, ior: Float) -> Self {
+        assert!(transparency >= 0. && transparency <= 1., "Transparency must be between 0 and 1");
+        assert!(ior > 0., "Index of refraction must be positive");
+        self.transparency = transparency;
+        self.ior = ior;
+        self
+    }
+
+    // Tints the light passing through the inside of a transparent material by Beer-Lambert's
+    // law: over each unit of distance a channel keeps e^-absorption of itself, so thick glass and
+    // deep liquids are more deeply colored than thin ones
+    pub fn with_absorption(mut self, absorption: Vec3) -> Self {
+        assert!(absorption.x >= 0. && absorption.y >= 0. && absorption.z >= 0.,
+                "Absorption can't be negative");
+        self.absorption = absorption;
+        self
+    }
+
+    // Lets the light the diffuse term reflects scatter around inside first, see Subsurface. The
+    // path tracer then takes the diffuse light to come out wherever that leaves it, tinted by the
+    // color where it went in
+    pub fn with_subsurface(mut self, subsurface: Subsurface) -> Self {
+        self.subsurface = Some(subsurface);
+        self
+    }
+
+    pub fn subsurface(&self) -> Option<&Subsurface> {
+        self.subsurface.as_ref()
+    }
+
+    // The fraction of each channel of the light arriving at `hit` along `ray` that's left after
+    // crossing the inside of the material, or all of it if the ray came from outside
+    pub fn interior_transmittance(&self, ray: &Ray, hit: &Intersection) -> Vec3 {
+        // Normals point out of objects, so a ray on the same side as the normal is leaving one
+        if self.transparency == 0. || dot(&ray.dir, &hit.normal) <= 0. {
+            return Vec3::new(1., 1., 1.);
+        }
+        let a = self.absorption * hit.dist;
+        Vec3::new((-a.x).exp(), (-a.y).exp(), (-a.z).exp())
     }
 
-    pub fn reflectivity(&self) -> f32 {
+    pub fn transparency(&self) -> Float {
+        self.transparency
+    }
+
+    pub fn ior(&self) -> Float {
+        self.ior
+    }
+
+    // Makes the index of refraction depend on the wavelength by Cauchy's equation, n = A + B /
+    // wavelength^2 with the wavelength in micrometers, such that it's the material's `ior` at
+    // spectral::REFERENCE_WAVELENGTH. `dispersion` is B: about 0.004 for crown glass, 0.01 for
+    // flint glass and 0.018 for diamond. It only shows in spectral mode, see spectral
+    pub fn with_dispersion(mut self, dispersion: Float) -> Self {
+        assert!(dispersion >= 0., "Dispersion can't be negative");
+        self.dispersion = dispersion;
+        self
+    }
+
+    pub fn dispersion(&self) -> Float {
+        self.dispersion
+    }
+
+    // The index of refraction for light of `wavelength` in nanometers, or for white light
+    pub fn ior_at(&self, wavelength: Option<Float>) -> Float {
+        match wavelength {
+            Some(wavelength) if self.dispersion > 0. => {
+                let micrometers = |nm: Float| nm / 1000.;
+                let reference = micrometers(spectral::REFERENCE_WAVELENGTH);
+                self.ior + self.dispersion *
+                           (1. / micrometers(wavelength).powi(2) - 1. / reference.powi(2))
+            }
+            _ => self.ior,
+        }
+    }
+
+    pub fn reflectivity(&self) -> Float {
         self.reflectivity
     }
 
+    // Weights the mirror reflection by Schlick's approximation of the Fresnel equations, so that
+    // the reflectivity is what's reflected looking straight at the surface and it rises to a
+    // full mirror at grazing angles, like on varnish, water or a polished floor
+    pub fn with_fresnel(mut self) -> Self {
+        self.fresnel = true;
+        self
+    }
+
+    // The fraction of the light reflected in the mirror direction towards `ray`, which hit the
+    // surface at `hit`
+    pub fn reflectivity_at(&self, ray: &Ray, hit: &Intersection) -> Float {
+        if self.fresnel {
+            ray::schlick(self.reflectivity, dot(&ray.dir, &hit.normal).abs().min(1.))
+        } else {
+            self.reflectivity
+        }
+    }
+
     pub fn raw_color(&self) -> Vec3 {
         self.color
     }
 
     pub fn color(&self, shadow_ray: &Ray, camera_ray: &Ray, hit: &Intersection) -> Vec3 {
-        let f = f32::max(0., dot(&hit.normal, &shadow_ray.dir));
-        let diffuse_color = self.color * f * self.diffuse_coeff * match self.texture {
-            Some(ref t) => t.color(hit.u, hit.v) / 255.,
-            None => Vec3::new(1., 1., 1.)
+        self.diffuse_color(shadow_ray, hit) + self.specular_color(shadow_ray, camera_ray, hit)
+    }
+
+    pub fn diffuse_color(&self, shadow_ray: &Ray, hit: &Intersection) -> Vec3 {
+        let f = Float::max(0., dot(&hit.normal, &shadow_ray.dir));
+        self.albedo(hit) * f
+    }
+
+    // The fraction of the light arriving from all directions that the diffuse term reflects
+    pub fn albedo(&self, hit: &Intersection) -> Vec3 {
+        let coeff = match self.ggx_at(hit) {
+            None => self.diffuse_coeff,
+            Some((metallic, _)) => 1. - metallic,
         };
+        self.base_color(hit) * coeff
+    }
 
+    // The color, with the texture applied
+    fn base_color(&self, hit: &Intersection) -> Vec3 {
+        self.color * match self.texture {
+            Some(ref t) => t.color_filtered(hit.u, hit.v, hit.footprint),
+            None => Vec3::new(1., 1., 1.)
+        }
+    }
+
+    pub fn specular_color(&self, shadow_ray: &Ray, camera_ray: &Ray, hit: &Intersection) -> Vec3 {
+        if let Some((_, roughness)) = self.ggx_at(hit) {
+            let view = -camera_ray.dir;
+            let half_vec = (shadow_ray.dir + view).normalize();
+            let n_l = dot(&hit.normal, &shadow_ray.dir);
+            let n_v = dot(&hit.normal, &view);
+            if n_l <= 0. || n_v <= 0. {
+                return Vec3::new(0., 0., 0.);
+            }
+            let lobe = self.lobe(hit, roughness);
+            let d = lobe.d(&half_vec);
+            let g = lobe.g1(&shadow_ray.dir) * lobe.g1(&view);
+            let fresnel = self.fresnel(hit, dot(&view, &half_vec));
+            // Times pi and n_l, like the diffuse term, where white light of intensity 1 falling
+            // straight onto a white diffuse surface shows as white
+            return fresnel * (d * g / (4. * n_v) * float::consts::PI);
+        }
         // Average the angles, flipping the camera ray because it's in the opposite direction
         let half_vec = ((shadow_ray.dir - camera_ray.dir) / 2.).normalize();
-        let f = f32::max(0., dot(&half_vec, &hit.normal)).powf(self.glossiness);
+        let f = Float::max(0., dot(&half_vec, &hit.normal)).powf(self.glossiness);
         // TODO: Specular default color
-        let specular_color = Vec3::new(255., 255., 255.) * f * self.specular_coeff;
+        Vec3::new(1., 1., 1.) * f * self.specular_coeff
+    }
+
+    // The fraction of the light reflected straight back, for GGX materials: 4% for
+    // dielectrics, the color for metals
+    pub fn specular_albedo(&self, hit: &Intersection) -> Vec3 {
+        match self.ggx_at(hit) {
+            None => Vec3::new(0., 0., 0.),
+            Some((metallic, _)) => {
+                Vec3::new(0.04, 0.04, 0.04) * (1. - metallic) + self.base_color(hit) * metallic
+            }
+        }
+    }
+
+    // Schlick's approximation of the GGX highlight's Fresnel term
+    fn fresnel(&self, hit: &Intersection, cos: Float) -> Vec3 {
+        let f0 = self.specular_albedo(hit);
+        f0 + (Vec3::new(1., 1., 1.) - f0) * (1. - cos.max(0.)).powi(5)
+    }
+
+    // For path tracing GGX materials: a direction the highlight reflects `camera_ray` towards,
+    // sampled by the microfacet distribution from u1, u2 in 0..1, and the highlight's color
+    // along it divided by its probability. None if the sampled direction is below the surface
+    pub fn sample_specular(&self, camera_ray: &Ray, hit: &Intersection, u1: Float, u2: Float)
+                           -> Option<(Vec3, Vec3)> {
+        let roughness = match self.ggx_at(hit) {
+            None => return None,
+            Some((_, roughness)) => roughness,
+        };
+        let lobe = self.lobe(hit, roughness);
+        let half_vec = lobe.sample(u1, u2);
+        let dir = ray::reflect(&camera_ray.dir, &half_vec);
+        let view = -camera_ray.dir;
+        let (n_l, n_v) = (dot(&hit.normal, &dir), dot(&hit.normal, &view));
+        let v_h = dot(&view, &half_vec);
+        if n_l <= 0. || n_v <= 0. || v_h <= 0. {
+            return None;
+        }
+        // The distribution cancels out against the sample's density
+        let g = lobe.g1(&dir) * lobe.g1(&view);
+        let weight = self.fresnel(hit, v_h) * (g * v_h / (n_v * dot(&hit.normal, &half_vec)));
+        Some((dir, weight))
+    }
 
-        diffuse_color + specular_color
+    // The density of sample_specular picking `dir`, per unit solid angle. 0 if it never does
+    pub fn specular_pdf(&self, camera_ray: &Ray, hit: &Intersection, dir: &Vec3) -> Float {
+        let roughness = match self.ggx_at(hit) {
+            None => return 0.,
+            Some((_, roughness)) => roughness,
+        };
+        let view = -camera_ray.dir;
+        let half_vec = (*dir + view).normalize();
+        let (n_h, v_h) = (dot(&hit.normal, &half_vec), dot(&view, &half_vec));
+        if dot(&hit.normal, dir) <= 0. || dot(&hit.normal, &view) <= 0. || v_h <= 0. {
+            return 0.;
+        }
+        // Half vectors are sampled by D times n.h, and reflecting about them stretches angles by
+        // 4 v.h
+        self.lobe(hit, roughness).d(&half_vec) * n_h / (4. * v_h)
     }
 
+    // Also true with a bump, which is applied along with the normal map
     pub fn has_normal_map(&self) -> bool {
-        self.normal_map.is_some()
+        self.normal_map.is_some() || self.bump.is_some()
+    }
+
+    // Applies the normal and bump textures once the scene has found a hit and its footprint.
+    // Unlike normal maps they need the surface's texture coordinates and how the surface runs
+    // along them, so surfaces that don't know are left as they are
+    pub fn apply_normal_textures(&self, hit: Intersection) -> Intersection {
+        let (dpdu, dpdv) = match hit.uv_derivatives {
+            Some(derivatives) if self.normal_texture.is_some() || self.bump_texture.is_some() => {
+                derivatives
+            }
+            _ => return hit,
+        };
+        let mut normal = hit.normal;
+        if let Some(ref map) = self.normal_texture {
+            let t = map.color_filtered(hit.u, hit.v, hit.footprint) * 2. - Vec3::new(1., 1., 1.);
+            // Mirrored texture coordinates flip v against u
+            let tangent = dpdu - normal * dot(&normal, &dpdu);
+            if tangent.norm_squared() > 0. {
+                let tangent = tangent.normalize();
+                let handedness = if dot(&cross(&normal, &dpdu), &dpdv) < 0. { -1. } else { 1. };
+                let bitangent = cross(&normal, &tangent) * handedness;
+                let mapped = tangent * t.x + bitangent * t.y + normal * t.z;
+                if mapped.norm_squared() > 0. {
+                    normal = mapped.normalize();
+                }
+            }
+        }
+        if let Some((ref map, strength)) = self.bump_texture {
+            // Central differences across the pixel's footprint, or a fraction of a texel
+            let h = hit.footprint.max(1e-3) / 2.;
+            let height = |u: Float, v: Float| {
+                let texel = map.color_filtered(u, v, hit.footprint);
+                (texel.x + texel.y + texel.z) / 3. * strength
+            };
+            let du = (height(hit.u + h, hit.v) - height(hit.u - h, hit.v)) / (2. * h);
+            let dv = (height(hit.u, hit.v + h) - height(hit.u, hit.v - h)) / (2. * h);
+            let bumped = cross(&(dpdu + normal * du), &(dpdv + normal * dv));
+            if bumped.norm_squared() > 0. {
+                let bumped = bumped.normalize();
+                normal = if dot(&bumped, &normal) < 0. { -bumped } else { bumped };
+            }
+        }
+        Intersection { normal: normal, ..hit }
     }
 
     pub fn has_displacement_map(&self) -> bool {
@@ -77,9 +579,13 @@ impl Material {
     }
 
     pub fn apply_normal_map(&self, normal: &Vec3, hit_pos: &Vec3) -> Vec3 {
-        match &self.normal_map {
+        let normal = match &self.normal_map {
             &Some(ref map) => map.map(normal, hit_pos),
             &None => *normal
+        };
+        match self.bump {
+            Some(ref bump) => bump.apply(&normal, hit_pos),
+            None => normal,
         }
     }
 
@@ -91,13 +597,108 @@ impl Material {
     }
 }
 
+// Perfectly smooth GGX surfaces would need infinitely bright highlights from point lights
+fn ggx_alpha(roughness: Float) -> Float {
+    (roughness * roughness).max(1e-3)
+}
+
+// The average of a grayscale texture at `hit`, from 0 to 1, or 1 without one
+fn map_scale(map: &Option<Box<Texture>>, hit: &Intersection) -> Float {
+    match *map {
+        Some(ref map) => {
+            let texel = map.color_filtered(hit.u, hit.v, hit.footprint);
+            ((texel.x + texel.y + texel.z) / 3.).max(0.).min(1.)
+        }
+        None => 1.,
+    }
+}
+
+// Unit directions along the surface at `hit`, perpendicular to its normal and each other: the
+// surface's tangent, turned to be perpendicular to a normal tilted by maps, and the bitangent
+// across it. A fixed one where the surface has no tangent
+fn tangent_frame(hit: &Intersection) -> (Vec3, Vec3) {
+    let n = hit.normal;
+    let along = hit.tangent.map(|t| t - n * dot(&n, &t))
+        .and_then(|t| if t.norm_squared() > 1e-12 { Some(t.normalize()) } else { None });
+    let tangent = along.unwrap_or_else(|| {
+        let helper = if n.x.abs() < 0.9 { Vec3::new(1., 0., 0.) } else { Vec3::new(0., 1., 0.) };
+        cross(&n, &helper).normalize()
+    });
+    (tangent, cross(&n, &tangent))
+}
+
+// The microfacets of a GGX highlight, with `alpha` along the tangent and across it, both the
+// same for isotropic materials, which don't need the tangent and bitangent of `frame`
+struct Lobe {
+    normal: Vec3,
+    alpha: (Float, Float),
+    frame: Option<(Vec3, Vec3)>,
+}
+
+impl Lobe {
+    // The distribution of the facet normals, at half vector `h`
+    fn d(&self, h: &Vec3) -> Float {
+        let cos = dot(&self.normal, h);
+        let (tangent, bitangent) = match self.frame {
+            Some(frame) => frame,
+            None => return ggx_d(cos, self.alpha.0),
+        };
+        let (ax, ay) = self.alpha;
+        let (x, y) = (dot(&tangent, h) / ax, dot(&bitangent, h) / ay);
+        let s = x * x + y * y + cos * cos;
+        1. / (float::consts::PI * ax * ay * s * s)
+    }
+
+    // Smith's shadowing and masking for `dir`, with the alpha of the direction it leans in
+    fn g1(&self, dir: &Vec3) -> Float {
+        let cos = dot(&self.normal, dir);
+        let (tangent, bitangent) = match self.frame {
+            Some(frame) => frame,
+            None => return smith_g1(cos, self.alpha.0),
+        };
+        let (ax, ay) = self.alpha;
+        let (x, y) = (dot(&tangent, dir), dot(&bitangent, dir));
+        let lean = x * x + y * y;
+        let alpha = if lean > 0. {
+            ((x * x * ax * ax + y * y * ay * ay) / lean).sqrt()
+        } else {
+            (ax * ay).sqrt()
+        };
+        smith_g1(cos, alpha)
+    }
+
+    // A facet normal distributed like the facets are, times n.h, for u1, u2 in 0..1. The slope
+    // of an anisotropic one is that of a facet of alpha 1, stretched by the alphas
+    fn sample(&self, u1: Float, u2: Float) -> Vec3 {
+        let (tangent, bitangent) = match self.frame {
+            Some(frame) => frame,
+            None => return sampling::ggx_half_vector(&self.normal, self.alpha.0, u1, u2),
+        };
+        let slope = (u1 / (1. - u1).max(1e-12)).sqrt();
+        let angle = 2. * float::consts::PI * u2;
+        (tangent * (self.alpha.0 * slope * angle.cos()) +
+         bitangent * (self.alpha.1 * slope * angle.sin()) + self.normal).normalize()
+    }
+}
+
+// The GGX distribution of microfacet normals, at n.h of `cos`
+fn ggx_d(cos: Float, alpha: Float) -> Float {
+    alpha * alpha / (float::consts::PI * (cos * cos * (alpha * alpha - 1.) + 1.).powi(2))
+}
+
+// Smith's shadowing and masking term for one direction, by Schlick's approximation
+fn smith_g1(cos: Float, alpha: Float) -> Float {
+    let k = alpha / 2.;
+    cos / (cos * (1. - k) + k)
+}
+
 pub struct NormalMap {
     seed: Seed,
     seed_val: u32,
     octaves: usize,
-    wavelength: f32,
-    persistence: f32,
-    lacunarity: f32,
+    wavelength: Float,
+    persistence: Float,
+    lacunarity: Float,
 }
 
 impl Clone for NormalMap {
@@ -110,8 +711,8 @@ impl Clone for NormalMap {
 }
 
 impl NormalMap {
-    pub fn new(seed_val: u32, octaves: usize, wavelength: f32, persistence: f32,
-               lacunarity: f32) -> Self {
+    pub fn new(seed_val: u32, octaves: usize, wavelength: Float, persistence: Float,
+               lacunarity: Float) -> Self {
         let seed = Seed::new(seed_val);
 
         NormalMap { seed: seed, seed_val: seed_val, octaves: octaves, wavelength: wavelength,
@@ -137,9 +738,9 @@ pub struct DisplacementMap {
     seed: Seed,
     seed_val: u32,
     octaves: usize,
-    wavelength: f32,
-    persistence: f32,
-    lacunarity: f32,
+    wavelength: Float,
+    persistence: Float,
+    lacunarity: Float,
 }
 
 impl Clone for DisplacementMap {
@@ -152,8 +753,8 @@ impl Clone for DisplacementMap {
 }
 
 impl DisplacementMap {
-    pub fn new(seed_val: u32, octaves: usize, wavelength: f32, persistence: f32,
-               lacunarity: f32) -> Self {
+    pub fn new(seed_val: u32, octaves: usize, wavelength: Float, persistence: Float,
+               lacunarity: Float) -> Self {
         let seed = Seed::new(seed_val);
 
         DisplacementMap { seed: seed, seed_val: seed_val, octaves: octaves, wavelength: wavelength,
@@ -0,0 +1,147 @@
+// A homogeneous participating medium like fog, haze or smoke, filling the whole scene or a box.
+// Light passing through it is dimmed by what it absorbs and scatters, and the light of the lights
+// is scattered towards the camera along the way, so beams show up where objects shadow parts of
+// the medium. The attenuation is exact; the scattered light is gathered by marching along the
+// ray with a shadow ray towards each light at every step. Only the Whitted integrator sees it.
+
+
+use {ambient_light, float, shadow_visibility, Float, Scene, Vec3};
+use bounds::{box_interval, Aabb};
+use ray::{Ray, RayKind};
+use sampling;
+
+use nalgebra::dot;
+
+const DEFAULT_STEPS: u32 = 32;
+// Beyond where this little light gets through, nothing more is gathered
+const MIN_TRANSMITTANCE: Float = 1e-3;
+
+pub struct Medium {
+    // Per unit distance
+    scattering: Float,
+    absorption: Float,
+    anisotropy: Float,
+    bounds: Option<Aabb>,
+    steps: u32,
+}
+
+impl Medium {
+    // `scattering` and `absorption` are the fractions of light scattered and absorbed per unit
+    // distance at a `density` of 1. Mostly scattering gives bright fog, mostly absorbing gives
+    // dark smoke
+    pub fn new(density: Float, scattering: Float, absorption: Float) -> Self {
+        assert!(density >= 0. && scattering >= 0. && absorption >= 0.,
+                "Medium density and coefficients can't be negative");
+        Medium { scattering: density * scattering, absorption: density * absorption,
+                 anisotropy: 0., bounds: None, steps: DEFAULT_STEPS }
+    }
+
+    // The Henyey-Greenstein g, from -1 to 1: 0 scatters light evenly in all directions, positive
+    // values mostly onwards, so lights glow when looked at through the medium
+    pub fn with_anisotropy(mut self, anisotropy: Float) -> Self {
+        assert!(anisotropy > -1. && anisotropy < 1., "Anisotropy must be between -1 and 1");
+        self.anisotropy = anisotropy;
+        self
+    }
+
+    // Fills only the box instead of the whole scene
+    pub fn with_bounds(mut self, bounds: Aabb) -> Self {
+        self.bounds = Some(bounds);
+        self
+    }
+
+    // Points per ray the scattered light is gathered at. More give smoother beams
+    pub fn with_steps(mut self, steps: u32) -> Self {
+        assert!(steps > 0, "A medium needs at least one step");
+        self.steps = steps;
+        self
+    }
+
+    fn extinction(&self) -> Float {
+        self.scattering + self.absorption
+    }
+
+    // The part of `ray` within `dist` of its origin that's inside the medium
+    fn interval(&self, ray: &Ray, dist: Float) -> Option<(Float, Float)> {
+        match self.bounds {
+            Some(ref bounds) => box_interval(bounds, ray, 0., dist),
+            None => Some((0., dist)),
+        }
+    }
+
+    // The fraction of light that gets through the medium along `ray` over `dist`. Unless the
+    // medium has bounds, nothing gets through from infinitely far away, e.g. from directional
+    // lights
+    pub fn transmittance(&self, ray: &Ray, dist: Float) -> Float {
+        let extinction = self.extinction();
+        match self.interval(ray, dist) {
+            Some((near, far)) if extinction > 0. => (-(far - near) * extinction).exp(),
+            _ => 1.,
+        }
+    }
+
+    // Per solid angle, of light arriving along `from` and leaving along `to`
+    fn phase(&self, from: &Vec3, to: &Vec3) -> Float {
+        let g = self.anisotropy;
+        let cos = dot(from, to);
+        (1. - g * g) / (4. * float::consts::PI * (1. + g * g - 2. * g * cos).powf(1.5))
+    }
+}
+
+// What arrives at the origin of `ray` when `color` arrives from `dist` along it: dimmed, plus
+// what the medium in between scatters towards the origin
+pub fn through_medium(scene: &Scene, medium: &Medium, ray: &Ray, dist: Float, color: Vec3)
+                      -> Vec3 {
+    let extinction = medium.extinction();
+    let (near, far) = match medium.interval(ray, dist) {
+        Some(interval) if extinction > 0. => interval,
+        _ => return color,
+    };
+    let transmittance = (-(far - near) * extinction).exp();
+    let albedo = medium.scattering / extinction;
+    // Ambient light comes from everywhere, so every direction scatters it the same, which adds
+    // up to this in closed form. It's lit like a surface facing up
+    let ambient = ambient_light(scene, &Vec3::new(0., 1., 0.)) * albedo * (1. - transmittance);
+    let mut result = color * transmittance + ambient;
+    if scene.lights.is_empty() || medium.scattering == 0. {
+        return result;
+    }
+
+    let end = near + Float::min(far - near, -MIN_TRANSMITTANCE.ln() / extinction);
+    let step = (end - near) / medium.steps as Float;
+    let bits = |a: Float, b: Float| sampling::bits(a) ^ sampling::bits(b);
+    let seed = sampling::reseed(sampling::hash(bits(ray.origin.x, ray.dir.x),
+                                               bits(ray.origin.y, ray.dir.y),
+                                               bits(ray.origin.z, ray.dir.z)),
+                                scene.seed);
+    let to_origin = -ray.dir;
+    for i in 0..medium.steps {
+        let t = near + step * (i as Float + sampling::uniform(seed, i, 18));
+        let pos = ray.origin + ray.dir * t;
+        let mut lit = Vec3::new(0., 0., 0.);
+        for (j, light) in scene.lights.iter().enumerate() {
+            if !scene.selected_lights[j] {
+                continue;
+            }
+            let (dir, light_dist) = light.sample(&pos, sampling::uniform(seed, i, 19),
+                                                 sampling::uniform(seed, i, 20));
+            let attenuation = light.attenuation(&dir, light_dist);
+            let shadow_ray = Ray::new(pos, dir).with_time(ray.time).with_kind(RayKind::Shadow);
+            let visible = if attenuation > 0. {
+                shadow_visibility(scene, &shadow_ray, light_dist)
+            } else {
+                0.
+            };
+            if visible > 0. {
+                lit = lit + *light.color() * (light.intensity() * attenuation * visible *
+                                              medium.phase(&-dir, &to_origin) *
+                                              medium.transmittance(&shadow_ray, light_dist));
+            }
+        }
+        // Lights light surfaces facing them with π times their color, see light_color
+        let weight = medium.scattering * float::consts::PI * step *
+                     (-(t - near) * extinction).exp();
+        result = result + lit * weight;
+    }
+    result
+}
@@ -0,0 +1,237 @@
+// Merging partial renders of one frame, e.g. from machines that each rendered some tiles or all
+// of it with a different --seed, or from a run resumed later. `--partial` writes the unclamped
+// pixels before post effects along with how many samples they got, and `merge` averages the
+// partials covering each pixel weighted by their samples, then applies post effects and writes
+// the config's out_file.
+//
+// A partial file is a "ray-tracer partial" line, a line of JSON describing it, and then the
+// region's premultiplied red, green, blue and alpha as little endian f32, row by row. A worker
+// rendering several tiles (see farm.rs) writes one of those per tile, one after another, and so
+// does an adaptive render, whose tiles each got their own number of samples.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process;
+
+use rustc_serialize::json::Json;
+
+use tracerlib::{Float, RenderEvent, Scene, Vec3};
+use tracerlib::adaptive::ray_trace_adaptive;
+use tracerlib::hdr::HdrImage;
+use tracerlib::log::{self, Level};
+
+use super::{save_frame, setup_scene, Config};
+
+const MAGIC: &'static str = "ray-tracer partial";
+
+struct Partial {
+    scene: String,
+    width: u32,
+    height: u32,
+    region: (u32, u32, u32, u32),
+    samples: u32,
+    seed: u32,
+    pixels: Vec<Float>,
+}
+
+// Writes `im`, the rendered pixels of `region`, as a partial of the config's frame
+pub fn write_partial(config: &Config, im: &HdrImage, region: (u32, u32, u32, u32), file: &str) {
+    let samples = config.samples * config.samples;
+    File::create(file).unwrap().write_all(&partial_bytes(config, im, region, samples)).unwrap();
+}
+
+// Writes the rendered pixels of several regions of the config's frame to one file
+pub fn write_partials(config: &Config, tiles: &[(HdrImage, (u32, u32, u32, u32))], file: &str) {
+    let mut data = Vec::new();
+    for &(ref im, region) in tiles {
+        data.extend(partial_bytes(config, im, region, config.samples * config.samples));
+    }
+    File::create(file).unwrap().write_all(&data).unwrap();
+}
+
+// Renders the whole frame with the config's adaptive sampling and writes it as a partial per
+// tile, with the samples that tile got, so merging weighs each tile by what it really got
+pub fn write_adaptive_partial(config: &Config, scene: &Scene, file: &str) {
+    let settings = config.adaptive.unwrap();
+    let mut passes = HashMap::new();
+    let im = ray_trace_adaptive(scene, config.width, config.height, config.reflection_depth,
+                                settings, config.tile_order, |event| {
+        if let RenderEvent::TileFinished { region, .. } = event {
+            *passes.entry(region).or_insert(0) += 1;
+        }
+    });
+    let mut regions: Vec<_> = passes.into_iter().collect();
+    regions.sort();
+    let mut data = Vec::new();
+    for (region, samples) in regions {
+        let (x0, y0, x1, y1) = region;
+        let mut tile = HdrImage::new(x1 - x0, y1 - y0);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                tile.put_pixel(x - x0, y - y0, im.get_pixel(x, y));
+                tile.put_alpha(x - x0, y - y0, im.get_alpha(x, y));
+            }
+        }
+        data.extend(partial_bytes(config, &tile, region, samples));
+    }
+    File::create(file).unwrap().write_all(&data).unwrap();
+}
+
+// `samples` is what each pixel of `im` got
+fn partial_bytes(config: &Config, im: &HdrImage, region: (u32, u32, u32, u32), samples: u32)
+                 -> Vec<u8> {
+    let (x0, y0, x1, y1) = region;
+    assert!(im.width() == x1 - x0 && im.height() == y1 - y0);
+    let mut header = BTreeMap::new();
+    header.insert("scene".to_owned(), Json::String(config.scene.clone()));
+    header.insert("width".to_owned(), Json::U64(config.width as u64));
+    header.insert("height".to_owned(), Json::U64(config.height as u64));
+    header.insert("region".to_owned(), Json::Array(vec![Json::U64(x0 as u64),
+                                                        Json::U64(y0 as u64),
+                                                        Json::U64(x1 as u64),
+                                                        Json::U64(y1 as u64)]));
+    header.insert("samples".to_owned(), Json::U64(samples as u64));
+    header.insert("seed".to_owned(), Json::U64(config.seed as u64));
+
+    let mut data = format!("{}\n{}\n", MAGIC, Json::Object(header)).into_bytes();
+    data.extend(pixel_bytes(im));
+    data
+}
+
+// The premultiplied red, green, blue and alpha of `im` as little endian f32, row by row
+pub fn pixel_bytes(im: &HdrImage) -> Vec<u8> {
+    let mut data = Vec::new();
+    for y in 0..im.height() {
+        for x in 0..im.width() {
+            let color = im.get_pixel(x, y);
+            for value in [color.x, color.y, color.z, im.get_alpha(x, y)].iter() {
+                let bits = (*value as f32).to_bits();
+                data.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8,
+                                         (bits >> 24) as u8]);
+            }
+        }
+    }
+    data
+}
+
+// The inverse of pixel_bytes, four values per pixel
+pub fn pixels_from_bytes(data: &[u8]) -> Vec<Float> {
+    data.chunks(4).map(|b| {
+        f32::from_bits(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 |
+                       (b[3] as u32) << 24) as Float
+    }).collect()
+}
+
+// The partials in `file`, one after another
+fn read_partials(file: &str) -> Vec<Partial> {
+    let mut data = Vec::new();
+    File::open(file).unwrap().read_to_end(&mut data).unwrap();
+    let mut partials = Vec::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        let (partial, next) = read_partial(rest, file);
+        partials.push(partial);
+        rest = next;
+    }
+    assert!(!partials.is_empty(), "{} is not a partial render", file);
+    partials
+}
+
+// The partial at the start of `data`, and what follows it
+fn read_partial<'a>(data: &'a [u8], file: &str) -> (Partial, &'a [u8]) {
+    let mut lines = data.splitn(3, |&b| b == b'\n');
+    assert!(lines.next() == Some(MAGIC.as_bytes()), "{} is not a partial render", file);
+    let header = String::from_utf8(lines.next().unwrap().to_vec()).unwrap();
+    let header = Json::from_str(&header).unwrap();
+    let field = |name: &str| header.find(name).and_then(Json::as_u64).unwrap() as u32;
+    let region: Vec<u32> = header.find("region").and_then(Json::as_array).unwrap()
+        .iter().map(|c| c.as_u64().unwrap() as u32).collect();
+    let (width, height) = (field("width"), field("height"));
+    let (x0, y0, x1, y1) = (region[0], region[1], region[2], region[3]);
+    assert!(x0 < x1 && x1 <= width && y0 < y1 && y1 <= height,
+            "{} has an invalid region", file);
+
+    let rest = lines.next().unwrap_or(&[]);
+    let length = ((x1 - x0) * (y1 - y0) * 16) as usize;
+    assert!(rest.len() >= length, "{} is truncated", file);
+    let scene = header.find("scene").and_then(Json::as_string).unwrap().to_owned();
+    let partial = Partial {
+        scene: scene,
+        width: width,
+        height: height,
+        region: (x0, y0, x1, y1),
+        samples: field("samples"),
+        seed: field("seed"),
+        pixels: pixels_from_bytes(&rest[..length]),
+    };
+    (partial, &rest[length..])
+}
+
+pub fn merge(config: &Config, files: &[String]) {
+    assert!(!files.is_empty(), "Usage: merge <partial>...");
+    let _span = log::span(Level::Info, format!("merge {} partials", files.len()));
+    let mut partials = Vec::new();
+    let mut partial_files = Vec::new();
+    for file in files {
+        for partial in read_partials(file) {
+            partials.push(partial);
+            partial_files.push(file);
+        }
+    }
+    let (width, height) = (partials[0].width, partials[0].height);
+
+    let mut sums = vec![(Vec3::new(0., 0., 0.), 0.); (width * height) as usize];
+    let mut weights = vec![0; (width * height) as usize];
+    for (i, (partial, file)) in partials.iter().zip(partial_files).enumerate() {
+        assert!((partial.width, partial.height) == (width, height),
+                "{} is {}x{}, expected {}x{}", file, partial.width, partial.height, width,
+                height);
+        // Its pixels are finished with the config's scene, so they'd better be of it
+        assert!(partial.scene == config.scene, "{} is a render of {}, not {}", file,
+                partial.scene, config.scene);
+        // The same seed gives the same samples, so they'd be counted twice
+        if partials[..i].iter().any(|other| other.seed == partial.seed &&
+                                    overlap(other.region, partial.region)) {
+            warn!("{} overlaps an earlier partial with the same seed {}", file, partial.seed);
+        }
+
+        let (x0, y0, x1, _) = partial.region;
+        for (n, pixel) in partial.pixels.chunks(4).enumerate() {
+            let (x, y) = (x0 + n as u32 % (x1 - x0), y0 + n as u32 / (x1 - x0));
+            let i = (y * width + x) as usize;
+            let samples = partial.samples as Float;
+            let (color, alpha) = sums[i];
+            sums[i] = (color + Vec3::new(pixel[0], pixel[1], pixel[2]) * samples,
+                       alpha + pixel[3] * samples);
+            weights[i] += partial.samples;
+        }
+    }
+
+    let missing = weights.iter().filter(|&&w| w == 0).count();
+    if missing > 0 {
+        println!("{} of {} pixels aren't covered by any partial", missing, weights.len());
+        process::exit(1);
+    }
+    let mut im = HdrImage::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let i = (y * width + x) as usize;
+            let (color, alpha) = sums[i];
+            im.put_pixel(x, y, color / weights[i] as Float);
+            im.put_alpha(x, y, alpha / weights[i] as Float);
+        }
+    }
+    let total: u64 = weights.iter().map(|&w| w as u64).sum();
+    println!("Merged {} partials, {:.1} samples per pixel on average", files.len(),
+             total as f64 / weights.len() as f64);
+
+    let scene = setup_scene(&config.scene);
+    config.post.apply(&mut im, &scene);
+    save_frame(config, &scene, &im, &config.out_file);
+    info!("Wrote {}", config.out_file);
+}
+
+fn overlap(a: (u32, u32, u32, u32), b: (u32, u32, u32, u32)) -> bool {
+    a.0 < b.2 && b.0 < a.2 && a.1 < b.3 && b.1 < a.3
+}
@@ -0,0 +1,895 @@
+// Triangle meshes, loaded from Wavefront OBJ, PLY (see ply.rs) or STL (see stl.rs) files. Each
+// mesh has its own bounding volume hierarchy, so a ray only tests the few triangles near it. With
+// vertex normals in the file, the shading normal is interpolated across each triangle for smooth
+// shading; without them, the triangles are flat, or for OBJ faces in a smoothing group, smoothed
+// with the normals of the group's other faces, or with a crease angle, smoothed across the edges
+// shallower than it. OBJ polygons can also be smoothed by subdivision first (see
+// subdivision.rs), and can each use a material from the file's MTL libraries (see mtl.rs).
+//
+// Rays are tested against four triangles at once with Möller-Trumbore, which is fast but can let
+// a ray through exactly where two triangles meet, leaving a speck of background along their
+// shared edge. Watertight meshes use Woop, Benthin and Wald's test instead, which decides the
+// side of an edge the ray passes the same way for both triangles sharing it, so a ray through a
+// closed mesh always hits it.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::ops;
+use std::path::Path;
+use std::str::SplitWhitespace;
+use std::sync::{Arc, Mutex};
+
+use {float, stats, Float, Vec3};
+use bounds::{hits_box, hits_box_robust, Aabb};
+use bvh::{self, BvhSettings};
+use compact;
+use material::Material;
+use mtl::{read_mtl, relative_to};
+use ray::{Intersection, Ray};
+use sampling;
+use subdivision::{Face, PolygonMesh};
+use surface::{self, Surface, UvGrid};
+
+use nalgebra::{cross, dot, Norm};
+
+// Indices of a face corner's position, texture coordinate and normal in an OBJ file
+type Corner = (usize, Option<usize>, Option<usize>);
+
+// An OBJ face: its corners, its smoothing group (0 for none), and the index + 1 of the material
+// it uses in its file's `usemtl` names, or 0 before the first
+struct ObjFace {
+    corners: Vec<Corner>,
+    smoothing: u32,
+    material: usize,
+}
+
+struct ObjFile {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(Float, Float)>,
+    faces: Vec<ObjFace>,
+    // The files of the `mtllib` statements, and the names of the `usemtl` ones in order of first
+    // use
+    libraries: Vec<String>,
+    materials: Vec<String>,
+}
+
+// Triangles tested at once, see Pack. Lanes spells out its operations for 4
+const LANES: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Triangle {
+    pub positions: [usize; 3],
+    // Indices into the mesh's normals and uvs, if the file has them for this triangle
+    pub normals: Option<[usize; 3]>,
+    pub uvs: Option<[usize; 3]>,
+    // The mesh's material for 0, or one of those given by with_face_materials
+    pub material: usize,
+}
+
+// A node of the hierarchy: either two children, the first right after the node and the second
+// at `second`, or a leaf with `count` triangles starting at `first`, packed from `pack` on
+struct Node {
+    bounds: Aabb,
+    first: usize,
+    count: usize,
+    second: usize,
+    pack: usize,
+}
+
+// Up to LANES triangles of a leaf stored lane by lane, x, y and z apart, so that a ray is tested
+// against all of them with the same operations on each lane, which the compiler turns into
+// vector instructions. The operations are those of testing one triangle at a time, so hits come
+// out exactly the same
+#[derive(Clone, Copy)]
+struct Pack {
+    corner: [[Float; LANES]; 3],
+    edge1: [[Float; LANES]; 3],
+    edge2: [[Float; LANES]; 3],
+    // The smallest determinant that isn't a ray parallel to the triangle, and infinite for
+    // unused lanes so they're never hit
+    parallel: [Float; LANES],
+    // The index of each lane's triangle
+    triangles: [usize; LANES],
+}
+
+pub struct TriangleMesh {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(Float, Float)>,
+    triangles: Vec<Triangle>,
+    nodes: Vec<Node>,
+    packs: Vec<Pack>,
+    bvh: BvhSettings,
+    // The mesh's material, then the face materials
+    materials: Vec<Material>,
+    // The running sum of the triangles' areas over the total, to sample points evenly over the
+    // mesh, and the total
+    area_cdf: Vec<Float>,
+    area: Float,
+    watertight: bool,
+    // Where the uv layout puts the triangles, built the first time surface_point needs it, as
+    // only baking does
+    uv_grid: Mutex<Option<Arc<UvGrid>>>,
+}
+
+impl TriangleMesh {
+    pub fn new(positions: Vec<Vec3>, normals: Vec<Vec3>, uvs: Vec<(Float, Float)>,
+               triangles: Vec<Triangle>, material: Material) -> Self {
+        assert!(!triangles.is_empty(), "A mesh needs at least one triangle");
+        for triangle in triangles.iter() {
+            assert!(triangle.positions.iter().all(|&i| i < positions.len()) &&
+                    triangle.normals.map_or(true, |n| n.iter().all(|&i| i < normals.len())) &&
+                    triangle.uvs.map_or(true, |t| t.iter().all(|&i| i < uvs.len())),
+                    "Triangle indices out of range");
+        }
+        let mut mesh = TriangleMesh {
+            positions: positions,
+            normals: normals,
+            uvs: uvs,
+            triangles: triangles,
+            nodes: Vec::new(),
+            packs: Vec::new(),
+            bvh: bvh::TRIANGLE_SETTINGS,
+            materials: vec![material],
+            area_cdf: Vec::new(),
+            area: 0.,
+            watertight: false,
+            uv_grid: Mutex::new(None),
+        };
+        mesh.rebuild();
+        mesh.measure();
+        mesh
+    }
+
+    // Rebuilds the hierarchy over the triangles with other leaf sizes and costs
+    pub fn with_bvh(mut self, settings: BvhSettings) -> Self {
+        self.bvh = settings;
+        self.rebuild();
+        self
+    }
+
+    // Gives the triangles without normals smooth ones, averaged at each corner over the triangles
+    // around it that meet at less than `degrees` with the triangle, so curved surfaces shade
+    // smoothly while sharper edges stay creased
+    pub fn with_crease_angle(mut self, degrees: Float) -> Self {
+        let cos = (degrees.max(0.).min(180.) * float::consts::PI / 180.).cos();
+        crease_normals(&self.positions, &mut self.normals, &mut self.triangles, cos);
+        self
+    }
+
+    // Tests rays with the watertight triangle test rather than the faster Möller-Trumbore one
+    pub fn with_watertight(mut self, watertight: bool) -> Self {
+        self.watertight = watertight;
+        self
+    }
+
+    // Loads an OBJ, PLY, STL or glTF file, going by its extension
+    pub fn load(filename: &str, material: Material) -> Self {
+        let extension = Path::new(filename).extension().and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match extension.as_ref().map(|e| &e[..]) {
+            Some("obj") => TriangleMesh::load_obj(filename, material),
+            Some("ply") => TriangleMesh::load_ply(filename, material),
+            Some("stl") => TriangleMesh::load_stl(filename, material),
+            Some("gltf") | Some("glb") => TriangleMesh::load_gltf(filename, material),
+            _ => panic!("{}: unknown mesh format, expected .obj, .ply, .stl, .gltf or .glb",
+                        filename),
+        }
+    }
+
+    // Loads the vertices and faces of an OBJ file, splitting polygons into triangle fans. Faces
+    // after a `usemtl` use that material from the file's MTL libraries (see mtl.rs), with the
+    // compositing and visibility of `material`, which the other faces use. Groups and objects
+    // are ignored
+    pub fn load_obj(filename: &str, material: Material) -> Self {
+        let ObjFile { positions, mut normals, uvs, faces, libraries, materials } =
+            read_obj(filename);
+        let mut triangles = Vec::new();
+        let mut smoothing = Vec::new();
+        for face in faces.iter() {
+            let corners = &face.corners;
+            for i in 1..corners.len() - 1 {
+                let (a, b, c) = (corners[0], corners[i], corners[i + 1]);
+                let both = |x: Option<usize>, y: Option<usize>, z: Option<usize>| {
+                    match (x, y, z) {
+                        (Some(x), Some(y), Some(z)) => Some([x, y, z]),
+                        _ => None,
+                    }
+                };
+                triangles.push(Triangle {
+                    positions: [a.0, b.0, c.0],
+                    uvs: both(a.1, b.1, c.1),
+                    normals: both(a.2, b.2, c.2),
+                    material: face.material,
+                });
+                smoothing.push(face.smoothing);
+            }
+        }
+        smooth_groups(&positions, &mut normals, &mut triangles, &smoothing);
+        let removed = remove_degenerate(&positions, &mut triangles);
+        debug!("Loaded {} ({} vertices, {} triangles, {} degenerate ones left out)", filename,
+               positions.len(), triangles.len(), removed);
+
+        // Without mtllib, a library named like the OBJ file is used if there is one
+        let companion = Path::new(filename).with_extension("mtl");
+        let libraries = if libraries.is_empty() && !materials.is_empty() && companion.exists() {
+            vec![companion.to_string_lossy().into_owned()]
+        } else {
+            libraries.iter().map(|file| relative_to(filename, file)).collect()
+        };
+        let library: Vec<_> = libraries.iter()
+            .flat_map(|file| read_mtl(file, &material))
+            .collect();
+        let face_materials = materials.iter().map(|name| {
+            match library.iter().find(|&&(ref n, _)| n == name) {
+                Some(&(_, ref m)) => m.clone(),
+                None => {
+                    warn!("{}: no material {} in its MTL libraries", filename, name);
+                    material.clone()
+                }
+            }
+        }).collect();
+        TriangleMesh::new(positions, normals, uvs, triangles, material)
+            .with_face_materials(face_materials)
+    }
+
+    // Materials for the triangles whose `material` is 1 and up, in order
+    pub fn with_face_materials(mut self, materials: Vec<Material>) -> Self {
+        assert!(self.triangles.iter().all(|t| t.material <= materials.len()),
+                "Triangle material out of range");
+        self.materials.truncate(1);
+        self.materials.extend(materials);
+        self
+    }
+
+    // Loads the polygons of an OBJ file and smooths them by `levels` steps of Catmull-Clark
+    // subdivision (see subdivision.rs). The file's normals are replaced by the smooth surface's
+    pub fn load_subdivided(filename: &str, material: Material, levels: u32) -> Self {
+        let ObjFile { positions, uvs, faces, .. } = read_obj(filename);
+        let faces = faces.iter().map(|face| {
+            let corners = &face.corners;
+            let face_uvs: Vec<_> = corners.iter().filter_map(|c| c.1.map(|t| uvs[t])).collect();
+            Face {
+                corners: corners.iter().map(|c| c.0).collect(),
+                uvs: if face_uvs.len() == corners.len() { Some(face_uvs) } else { None },
+            }
+        }).collect();
+        let mut polygons = PolygonMesh::new(positions, faces);
+        for _ in 0..levels {
+            polygons = polygons.subdivided();
+        }
+        debug!("Subdivided {} {} times to {} faces", filename, levels, polygons.faces.len());
+        polygons.to_triangle_mesh(material)
+    }
+
+    // Scales the mesh around the origin, then moves it by `offset`
+    pub fn transformed(mut self, scale: Float, offset: Vec3) -> Self {
+        assert!(scale > 0., "Mesh scale must be positive");
+        for pos in self.positions.iter_mut() {
+            *pos = *pos * scale + offset;
+        }
+        self.rebuild();
+        self.measure();
+        self
+    }
+
+    // Writes the mesh as a compact mesh, to be memory mapped instead of loaded, see compact.rs
+    pub fn write_compact<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        compact::write_compact(&self.positions, &self.normals, &self.uvs, &self.triangles, out)
+    }
+
+    fn rebuild(&mut self) {
+        // Building sorts the triangles
+        *self.uv_grid.get_mut().unwrap() = None;
+        self.nodes.clear();
+        let count = self.triangles.len();
+        self.build(0, count);
+        self.build_packs();
+    }
+
+    fn measure(&mut self) {
+        let areas: Vec<Float> = self.triangles.iter().map(|t| self.triangle_area(t)).collect();
+        self.area = areas.iter().sum();
+        self.area_cdf = sampling::cdf(&areas);
+    }
+
+    fn triangle_area(&self, triangle: &Triangle) -> Float {
+        let p = |i: usize| self.positions[triangle.positions[i]];
+        cross(&(p(1) - p(0)), &(p(2) - p(0))).norm() / 2.
+    }
+
+    fn triangle_bounds(&self, triangle: &Triangle) -> Aabb {
+        let p = |i: usize| self.positions[triangle.positions[i]];
+        let a = Aabb::new(p(0), p(0));
+        a.union(&Aabb::new(p(1), p(1))).union(&Aabb::new(p(2), p(2)))
+    }
+
+    fn centroid(&self, triangle: &Triangle) -> Vec3 {
+        let p = |i: usize| self.positions[triangle.positions[i]];
+        (p(0) + p(1) + p(2)) / 3.
+    }
+
+    // Adds the nodes for triangles first..first + count, split where bvh::split says
+    fn build(&mut self, first: usize, count: usize) {
+        let bounds: Vec<Aabb> = self.triangles[first..first + count].iter()
+            .map(|t| self.triangle_bounds(t))
+            .collect();
+        let all = bounds.iter().skip(1).fold(bounds[0], |all, b| all.union(b));
+        let index = self.nodes.len();
+        self.nodes.push(Node { bounds: all, first: first, count: count, second: 0, pack: 0 });
+
+        let centroids: Vec<Vec3> = self.triangles[first..first + count].iter()
+            .map(|t| self.centroid(t))
+            .collect();
+        let (axis, half) = match bvh::split(&bounds, &centroids, &self.bvh) {
+            Some(split) => split,
+            None => return,
+        };
+        {
+            let positions = &self.positions;
+            let key = |t: &Triangle| {
+                (positions[t.positions[0]] + positions[t.positions[1]] +
+                 positions[t.positions[2]])[axis]
+            };
+            self.triangles[first..first + count]
+                .sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        }
+
+        self.nodes[index].count = 0;
+        self.build(first, half);
+        self.nodes[index].second = self.nodes.len();
+        self.build(first + half, count - half);
+    }
+
+    // Packs the triangles of each leaf, after the hierarchy is built
+    fn build_packs(&mut self) {
+        let (positions, triangles) = (&self.positions, &self.triangles);
+        let mut packs = Vec::new();
+        for node in self.nodes.iter_mut().filter(|node| node.count > 0) {
+            node.pack = packs.len();
+            for k in 0..(node.count + LANES - 1) / LANES {
+                let first = node.first + k * LANES;
+                let mut pack = Pack {
+                    corner: [[0.; LANES]; 3],
+                    edge1: [[0.; LANES]; 3],
+                    edge2: [[0.; LANES]; 3],
+                    parallel: [float::INFINITY; LANES],
+                    triangles: [first; LANES],
+                };
+                for i in first..cmp::min(first + LANES, node.first + node.count) {
+                    let p = |c: usize| positions[triangles[i].positions[c]];
+                    let (edge1, edge2) = (p(1) - p(0), p(2) - p(0));
+                    let lane = i - first;
+                    for axis in 0..3 {
+                        pack.corner[axis][lane] = p(0)[axis];
+                        pack.edge1[axis][lane] = edge1[axis];
+                        pack.edge2[axis][lane] = edge2[axis];
+                    }
+                    pack.parallel[lane] = float::EPSILON * edge1.norm() * edge2.norm();
+                    pack.triangles[lane] = i;
+                }
+                packs.push(pack);
+            }
+        }
+        self.packs = packs;
+    }
+
+    fn uv_grid(&self) -> Arc<UvGrid> {
+        let mut grid = self.uv_grid.lock().unwrap();
+        if grid.is_none() {
+            let triangles = self.triangles.iter().enumerate().filter_map(|(i, triangle)| {
+                triangle.uvs.map(|t| (i, [self.uvs[t[0]], self.uvs[t[1]], self.uvs[t[2]]]))
+            }).collect();
+            *grid = Some(Arc::new(UvGrid::new(triangles)));
+        }
+        grid.as_ref().unwrap().clone()
+    }
+
+    fn hit(&self, triangle: &Triangle, ray: &Ray, dist: Float, b1: Float, b2: Float)
+           -> Intersection {
+        let b0 = 1. - b1 - b2;
+        let pos = ray.origin + ray.dir * dist;
+        let p = |i: usize| self.positions[triangle.positions[i]];
+        let face_normal = cross(&(p(1) - p(0)), &(p(2) - p(0))).normalize();
+        let (normal, terminator) = match triangle.normals {
+            Some(n) => {
+                let smooth = self.normals[n[0]] * b0 + self.normals[n[1]] * b1 +
+                             self.normals[n[2]] * b2;
+                let unit = |i: usize| {
+                    let normal = self.normals[n[i]];
+                    if normal.norm_squared() > 0. { normal.normalize() } else { face_normal }
+                };
+                let terminator = surface::terminator_offset(&pos, &[p(0), p(1), p(2)],
+                                                            &[unit(0), unit(1), unit(2)],
+                                                            &[b0, b1, b2]);
+                let normal = if smooth.norm_squared() > 0. {
+                    smooth.normalize()
+                } else {
+                    face_normal
+                };
+                (normal, Some(terminator))
+            }
+            None => (face_normal, None),
+        };
+        let corner_uvs = match triangle.uvs {
+            Some(t) => [self.uvs[t[0]], self.uvs[t[1]], self.uvs[t[2]]],
+            None => [(0., 0.), (1., 0.), (0., 1.)],
+        };
+        let (t0, t1, t2) = (corner_uvs[0], corner_uvs[1], corner_uvs[2]);
+        let (u, v) = (t0.0 * b0 + t1.0 * b1 + t2.0 * b2, t0.1 * b0 + t1.1 * b1 + t2.1 * b2);
+        let derivatives = surface::uv_derivatives(&[p(0), p(1), p(2)], &corner_uvs);
+        let edge = surface::edge_offset(&pos, &[p(0), p(1), p(2)]);
+
+        let material = &self.materials[triangle.material];
+        let normal = if material.has_normal_map() {
+            material.apply_normal_map(&normal, &pos)
+        } else {
+            normal
+        };
+        let pos = if material.has_displacement_map() {
+            material.apply_displacement_map(&pos)
+        } else {
+            pos
+        };
+        let hit = Intersection::new(pos, normal, dist, u, v);
+        let hit = match derivatives {
+            Some((dpdu, dpdv)) => hit.with_uv_derivatives(dpdu, dpdv),
+            None => hit,
+        };
+        Intersection { material: triangle.material, edge: Some(edge), terminator: terminator,
+                       ..hit }
+    }
+}
+
+impl Surface for TriangleMesh {
+    fn name(&self) -> &'static str {
+        "TriangleMesh"
+    }
+
+    fn material(&self) -> &Material {
+        &self.materials[0]
+    }
+
+    fn material_at(&self, hit: &Intersection) -> &Material {
+        &self.materials[hit.material]
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.nodes[0].bounds)
+    }
+
+    // On the triangle whose texture coordinates cover (u, v), with the normal interpolated there
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        self.uv_grid().find(u, v).map(|(i, b1, b2)| {
+            let triangle = &self.triangles[i];
+            let b0 = 1. - b1 - b2;
+            let p = |j: usize| self.positions[triangle.positions[j]];
+            let face_normal = cross(&(p(1) - p(0)), &(p(2) - p(0))).normalize();
+            let normal = match triangle.normals {
+                Some(n) => {
+                    let smooth = self.normals[n[0]] * b0 + self.normals[n[1]] * b1 +
+                                 self.normals[n[2]] * b2;
+                    if smooth.norm_squared() > 0. { smooth.normalize() } else { face_normal }
+                }
+                None => face_normal,
+            };
+            let pos = p(0) * b0 + p(1) * b1 + p(2) * b2;
+            Intersection { material: triangle.material, ..Intersection::new(pos, normal, 0., u, v) }
+        })
+    }
+
+    // Picks a triangle by its area with u1, and reuses what's left of u1 for the point on it
+    fn sample_area(&self, u1: Float, u2: Float) -> Option<(Vec3, Vec3, Float)> {
+        let i = match sampling::pick(&self.area_cdf, u1) {
+            Some(i) => i,
+            None => return None,
+        };
+        let low = if i == 0 { 0. } else { self.area_cdf[i - 1] };
+        let u1 = ((u1 - low) / (self.area_cdf[i] - low)).max(0.).min(1.);
+        let p = |j: usize| self.positions[self.triangles[i].positions[j]];
+        let r = u1.sqrt();
+        let pos = p(0) * (1. - r) + p(1) * (r * (1. - u2)) + p(2) * (r * u2);
+        let normal = cross(&(p(1) - p(0)), &(p(2) - p(0))).normalize();
+        Some((pos, normal, self.area))
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    fn heap_size(&self) -> usize {
+        (self.positions.len() + self.normals.len()) * mem::size_of::<Vec3>() +
+        self.uvs.len() * mem::size_of::<(Float, Float)>() +
+        self.triangles.len() * mem::size_of::<Triangle>() +
+        self.nodes.len() * mem::size_of::<Node>() + self.packs.len() * mem::size_of::<Pack>() +
+        self.area_cdf.len() * mem::size_of::<Float>()
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        let inv_dir = Vec3::new(1. / ray.dir.x, 1. / ray.dir.y, 1. / ray.dir.z);
+        let mut closest: Option<(Float, usize, Float, Float)> = None;
+        let shear = if self.watertight { Some(Shear::new(ray)) } else { None };
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            stats::count_node_visit();
+            let node = &self.nodes[index];
+            let limit = closest.map_or(float::INFINITY, |c| c.0);
+            let hit = if shear.is_some() {
+                hits_box_robust(&node.bounds, ray, &inv_dir, limit)
+            } else {
+                hits_box(&node.bounds, ray, &inv_dir, limit)
+            };
+            if !hit {
+                continue;
+            }
+            if node.count == 0 {
+                stack.push(node.second);
+                stack.push(index + 1);
+                continue;
+            }
+            // One triangle at a time, from the same corners for every triangle sharing them
+            if let Some(ref shear) = shear {
+                for i in node.first..node.first + node.count {
+                    let p = |c: usize| &self.positions[self.triangles[i].positions[c]];
+                    if let Some((dist, b1, b2)) = shear.intersect(p(0), p(1), p(2)) {
+                        if closest.map_or(true, |c| dist < c.0) {
+                            closest = Some((dist, i, b1, b2));
+                        }
+                    }
+                }
+                continue;
+            }
+            for pack in &self.packs[node.pack..node.pack + (node.count + LANES - 1) / LANES] {
+                let (dists, b1s, b2s) = pack.intersect(ray);
+                for lane in 0..LANES {
+                    if closest.map_or(true, |c| dists[lane] < c.0) &&
+                       dists[lane] < float::INFINITY {
+                        closest = Some((dists[lane], pack.triangles[lane], b1s[lane], b2s[lane]));
+                    }
+                }
+            }
+        }
+        closest.map(|(dist, i, b1, b2)| self.hit(&self.triangles[i], ray, dist, b1, b2))
+    }
+}
+
+impl Pack {
+    // Möller-Trumbore on each lane, giving the distances of the hits, infinite for misses, and
+    // their barycentric coordinates
+    fn intersect(&self, ray: &Ray) -> ([Float; LANES], [Float; LANES], [Float; LANES]) {
+        let v = |a: &[[Float; LANES]; 3]| [Lanes(a[0]), Lanes(a[1]), Lanes(a[2])];
+        let (edge1, edge2, corner) = (v(&self.edge1), v(&self.edge2), v(&self.corner));
+        let dir = [Lanes::splat(ray.dir.x), Lanes::splat(ray.dir.y), Lanes::splat(ray.dir.z)];
+        let origin = [Lanes::splat(ray.origin.x), Lanes::splat(ray.origin.y),
+                      Lanes::splat(ray.origin.z)];
+        let pvec = cross_lanes(&dir, &edge2);
+        let det = dot_lanes(&edge1, &pvec);
+        let tvec = [origin[0] - corner[0], origin[1] - corner[1], origin[2] - corner[2]];
+        let b1 = dot_lanes(&tvec, &pvec) / det;
+        let qvec = cross_lanes(&tvec, &edge1);
+        let b2 = dot_lanes(&dir, &qvec) / det;
+        let dist = dot_lanes(&edge2, &qvec) / det;
+        let mut dists = [float::INFINITY; LANES];
+        for l in 0..LANES {
+            // Without branching between the tests
+            let hit = (det.0[l].abs() >= self.parallel[l]) & (b1.0[l] >= 0.) & (b1.0[l] <= 1.) &
+                      (b2.0[l] >= 0.) & (b1.0[l] + b2.0[l] <= 1.) & (dist.0[l] > 0.);
+            if hit {
+                dists[l] = dist.0[l];
+            }
+        }
+        (dists, b1.0, b2.0)
+    }
+}
+
+// A ray as the watertight test sees it: moved to the origin, its axes renamed so that it goes
+// furthest along z, and sheared so that it goes straight along z. The same for all triangles
+struct Shear {
+    origin: Vec3,
+    // The axes that become x, y and z
+    axes: (usize, usize, usize),
+    // The shear of x and y by z, and the scale of z
+    shear: (Float, Float, Float),
+}
+
+impl Shear {
+    fn new(ray: &Ray) -> Self {
+        let d = ray.dir;
+        let kz = if d.x.abs() > d.y.abs() {
+            if d.x.abs() > d.z.abs() { 0 } else { 2 }
+        } else if d.y.abs() > d.z.abs() {
+            1
+        } else {
+            2
+        };
+        // Swapping x and y for a ray going down z keeps the triangles' winding
+        let (kx, ky) = ((kz + 1) % 3, (kz + 2) % 3);
+        let (kx, ky) = if d[kz] < 0. { (ky, kx) } else { (kx, ky) };
+        Shear {
+            origin: ray.origin,
+            axes: (kx, ky, kz),
+            shear: (d[kx] / d[kz], d[ky] / d[kz], 1. / d[kz]),
+        }
+    }
+
+    // Woop, Benthin and Wald: the distance to the hit on triangle a, b, c and the barycentric
+    // coordinates of b and c, if the ray hits it. Where the ray passes so close to an edge that
+    // its edge function rounds to 0, it's worked out again in double precision
+    fn intersect(&self, a: &Vec3, b: &Vec3, c: &Vec3) -> Option<(Float, Float, Float)> {
+        let (kx, ky, kz) = self.axes;
+        let (sx, sy, sz) = self.shear;
+        let (a, b, c) = (*a - self.origin, *b - self.origin, *c - self.origin);
+        let (ax, ay) = (a[kx] - sx * a[kz], a[ky] - sy * a[kz]);
+        let (bx, by) = (b[kx] - sx * b[kz], b[ky] - sy * b[kz]);
+        let (cx, cy) = (c[kx] - sx * c[kz], c[ky] - sy * c[kz]);
+        let (mut u, mut v, mut w) = (cx * by - cy * bx, ax * cy - ay * cx, bx * ay - by * ax);
+        if u == 0. || v == 0. || w == 0. {
+            let edge = |px: Float, py: Float, qx: Float, qy: Float| {
+                (px as f64 * qy as f64 - py as f64 * qx as f64) as Float
+            };
+            u = edge(cx, cy, bx, by);
+            v = edge(ax, ay, cx, cy);
+            w = edge(bx, by, ax, ay);
+        }
+        // The ray passes inside all three edges, whichever way the triangle faces
+        if (u < 0. || v < 0. || w < 0.) && (u > 0. || v > 0. || w > 0.) {
+            return None;
+        }
+        let det = u + v + w;
+        if det == 0. {
+            return None;
+        }
+        let t = u * (sz * a[kz]) + v * (sz * b[kz]) + w * (sz * c[kz]);
+        let dist = t / det;
+        if dist > 0. && dist < float::INFINITY { Some((dist, v / det, w / det)) } else { None }
+    }
+}
+
+// A value for each lane of a Pack, with arithmetic lane by lane
+#[derive(Clone, Copy)]
+struct Lanes([Float; LANES]);
+
+impl Lanes {
+    fn splat(x: Float) -> Self {
+        Lanes([x; LANES])
+    }
+}
+
+macro_rules! lanes_op {
+    ($trait_:ident, $method:ident, $op:tt) => {
+        impl ops::$trait_ for Lanes {
+            type Output = Lanes;
+
+            #[inline(always)]
+            fn $method(self, other: Lanes) -> Lanes {
+                let (a, b) = (self.0, other.0);
+                Lanes([a[0] $op b[0], a[1] $op b[1], a[2] $op b[2], a[3] $op b[3]])
+            }
+        }
+    }
+}
+
+lanes_op!(Add, add, +);
+lanes_op!(Sub, sub, -);
+lanes_op!(Mul, mul, *);
+lanes_op!(Div, div, /);
+
+// Like nalgebra's cross and dot products, including the order of the sums, so that the results
+// match those for single vectors
+#[inline(always)]
+fn cross_lanes(a: &[Lanes; 3], b: &[Lanes; 3]) -> [Lanes; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+#[inline(always)]
+fn dot_lanes(a: &[Lanes; 3], b: &[Lanes; 3]) -> Lanes {
+    a[0] * b[0] + (a[1] * b[1] + a[2] * b[2])
+}
+
+// The positions, normals and texture coordinates of an OBJ file, its faces with the indices of
+// each corner's position, texture coordinate and normal, and its materials
+fn read_obj(filename: &str) -> ObjFile {
+    let file = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    let (mut positions, mut normals, mut uvs, mut faces) =
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let (mut libraries, mut materials) = (Vec::new(), Vec::<String>::new());
+    let (mut smoothing, mut material) = (0, 0);
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.unwrap();
+        let mut words = line.split_whitespace();
+        let error = |what: &str| format!("{}:{}: {}", filename, n + 1, what);
+        let floats = |words: &mut SplitWhitespace| {
+            parse_floats(words).unwrap_or_else(|| panic!(error("invalid number")))
+        };
+        match words.next() {
+            Some("v") => {
+                let v = floats(&mut words);
+                assert!(v.len() >= 3, error("vertex needs x y z"));
+                positions.push(Vec3::new(v[0], v[1], v[2]));
+            }
+            Some("vn") => {
+                let v = floats(&mut words);
+                assert!(v.len() >= 3, error("normal needs x y z"));
+                normals.push(Vec3::new(v[0], v[1], v[2]).normalize());
+            }
+            Some("vt") => {
+                let v = floats(&mut words);
+                assert!(v.len() >= 2, error("texture coordinate needs u v"));
+                // OBJ has v pointing up, images have y pointing down
+                uvs.push((v[0], 1. - v[1]));
+            }
+            Some("f") => {
+                let corners: Vec<_> = words.map(|corner| {
+                    parse_corner(corner, positions.len(), uvs.len(), normals.len())
+                        .unwrap_or_else(|| panic!(error("invalid face")))
+                }).collect();
+                assert!(corners.len() >= 3, error("face needs at least 3 vertices"));
+                faces.push(ObjFace { corners: corners, smoothing: smoothing, material: material });
+            }
+            Some("s") => {
+                smoothing = match words.next() {
+                    Some("off") => 0,
+                    Some(group) => {
+                        group.parse().unwrap_or_else(|_| panic!(error("invalid group")))
+                    }
+                    None => panic!(error("smoothing needs a group or off")),
+                };
+            }
+            Some("mtllib") => libraries.extend(words.map(|file| file.to_owned())),
+            // Names may have spaces
+            Some("usemtl") => {
+                let name = words.collect::<Vec<_>>().join(" ");
+                material = match materials.iter().position(|m| *m == name) {
+                    Some(i) => i + 1,
+                    None => {
+                        materials.push(name);
+                        materials.len()
+                    }
+                };
+            }
+            _ => {}
+        }
+    }
+    ObjFile {
+        positions: positions,
+        normals: normals,
+        uvs: uvs,
+        faces: faces,
+        libraries: libraries,
+        materials: materials,
+    }
+}
+
+// Gives the triangles without normals in smoothing group `smoothing[i]` other than 0 normals
+// averaged over the group's triangles around each corner, weighted by area. Triangles of
+// different groups meeting at a corner stay creased there
+fn smooth_groups(positions: &[Vec3], normals: &mut Vec<Vec3>, triangles: &mut [Triangle],
+                 smoothing: &[u32]) {
+    let mut sums: HashMap<(usize, u32), Vec3> = HashMap::new();
+    let smoothed = |t: &Triangle, group: u32| t.normals.is_none() && group != 0;
+    for (t, &group) in triangles.iter().zip(smoothing) {
+        if smoothed(t, group) {
+            let p = |i: usize| positions[t.positions[i]];
+            let normal = cross(&(p(1) - p(0)), &(p(2) - p(0)));
+            for &i in t.positions.iter() {
+                let sum = sums.entry((i, group)).or_insert(Vec3::new(0., 0., 0.));
+                *sum = *sum + normal;
+            }
+        }
+    }
+    let mut indices = HashMap::new();
+    for (t, &group) in triangles.iter_mut().zip(smoothing) {
+        if smoothed(t, group) {
+            let mut corners = [0; 3];
+            for (corner, &i) in corners.iter_mut().zip(t.positions.iter()) {
+                *corner = *indices.entry((i, group)).or_insert_with(|| {
+                    let sum = sums[&(i, group)];
+                    // Left at zero where the group's faces cancel out, so the face normal is used
+                    normals.push(if sum.norm_squared() > 0. { sum.normalize() } else { sum });
+                    normals.len() - 1
+                });
+            }
+            t.normals = Some(corners);
+        }
+    }
+}
+
+// Sets the normals of the triangles without them for with_crease_angle, `cos` being the cosine of
+// the crease angle. Corners are told apart by position rather than index, as STL files and
+// others don't share vertices between triangles. Each triangle's normal is weighted by its area
+fn crease_normals(positions: &[Vec3], normals: &mut Vec<Vec3>, triangles: &mut [Triangle],
+                  cos: Float) {
+    let key = |p: &Vec3| (p.x.to_bits(), p.y.to_bits(), p.z.to_bits());
+    let face_normals: Vec<Vec3> = triangles.iter().map(|t| {
+        let p = |i: usize| positions[t.positions[i]];
+        cross(&(p(1) - p(0)), &(p(2) - p(0)))
+    }).collect();
+    let mut around = HashMap::new();
+    for (i, t) in triangles.iter().enumerate() {
+        for &corner in t.positions.iter() {
+            around.entry(key(&positions[corner])).or_insert_with(Vec::new).push(i);
+        }
+    }
+    // Corners whose sums come out the same share a normal
+    let mut indices = HashMap::new();
+    for (i, t) in triangles.iter_mut().enumerate() {
+        if t.normals.is_some() {
+            continue;
+        }
+        let own = face_normals[i].normalize();
+        let mut corners = [0; 3];
+        for (n, &corner) in corners.iter_mut().zip(t.positions.iter()) {
+            let sum = around[&key(&positions[corner])].iter()
+                .map(|&j| face_normals[j])
+                .filter(|normal| dot(&own, &normal.normalize()) >= cos)
+                .fold(Vec3::new(0., 0., 0.), |sum, normal| sum + normal);
+            // The triangle's own normal is in the sum, so it's only 0 if others cancel it out
+            let normal = if sum.norm_squared() > 0. { sum.normalize() } else { own };
+            *n = *indices.entry((corner, key(&normal))).or_insert_with(|| {
+                normals.push(normal);
+                normals.len() - 1
+            });
+        }
+        t.normals = Some(corners);
+    }
+}
+
+// Drops the triangles without area, which scanned and converted meshes often have, and returns
+// how many there were. They can never be hit, but would still be tested and take up space
+pub fn remove_degenerate(positions: &[Vec3], triangles: &mut Vec<Triangle>) -> usize {
+    let before = triangles.len();
+    triangles.retain(|t| {
+        let (a, b, c) = (t.positions[0], t.positions[1], t.positions[2]);
+        let area = cross(&(positions[b] - positions[a]), &(positions[c] - positions[a])).norm();
+        a != b && b != c && a != c && area > 0.
+    });
+    before - triangles.len()
+}
+
+fn parse_floats<'a, I: Iterator<Item = &'a str>>(words: I) -> Option<Vec<Float>> {
+    let mut floats = Vec::new();
+    for word in words {
+        match word.parse() {
+            Ok(f) => floats.push(f),
+            Err(_) => return None,
+        }
+    }
+    Some(floats)
+}
+
+// Index of the vertex, texture coordinate and normal of a face corner like "1/2/3", "1//3" or
+// "-1", where negative indices count back from the last one read so far
+fn parse_corner(corner: &str, positions: usize, uvs: usize, normals: usize) -> Option<Corner> {
+    let resolve = |index: &str, count: usize| -> Option<usize> {
+        let i: i64 = match index.parse() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let i = if i < 0 { count as i64 + i } else { i - 1 };
+        if i >= 0 && i < count as i64 { Some(i as usize) } else { None }
+    };
+    let mut parts = corner.split('/');
+    let position = match parts.next().and_then(|p| resolve(p, positions)) {
+        Some(p) => p,
+        None => return None,
+    };
+    let uv = match parts.next() {
+        Some("") | None => None,
+        Some(t) => match resolve(t, uvs) {
+            Some(t) => Some(t),
+            None => return None,
+        },
+    };
+    let normal = match parts.next() {
+        Some("") | None => None,
+        Some(n) => match resolve(n, normals) {
+            Some(n) => Some(n),
+            None => return None,
+        },
+    };
+    Some((position, uv, normal))
+}
@@ -0,0 +1,157 @@
+// Wavefront MTL material libraries, which OBJ files name with `mtllib` and pick materials from
+// with `usemtl`. The diffuse color and texture, the highlight, transparency and index of
+// refraction are read into Phong materials, and mirror reflections, weighted by Fresnel or not,
+// for the illumination models that have them. Materials with the roughness or metallic
+// statements or maps of the PBR extension are shaded with GGX instead. Other statements, like
+// ambient colors and the other texture maps, are skipped.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use {Float, Vec3};
+use material::Material;
+use texture::{ImageTexture, Texture};
+
+// A material as the file describes it, before it becomes a Material
+struct Description {
+    name: String,
+    diffuse: Vec3,
+    specular: Vec3,
+    exponent: Float,
+    dissolve: Float,
+    ior: Option<Float>,
+    texture: Option<String>,
+    illumination: u32,
+    roughness: Option<Float>,
+    metallic: Option<Float>,
+    roughness_map: Option<String>,
+    metallic_map: Option<String>,
+}
+
+// The materials of an MTL file by name. They keep the compositing and visibility of `base`, the
+// material of the surface the mesh is loaded for
+pub fn read_mtl(filename: &str, base: &Material) -> Vec<(String, Material)> {
+    let file = File::open(filename).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    let mut descriptions: Vec<Description> = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.unwrap();
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let error = |what: &str| format!("{}:{}: {}", filename, n + 1, what);
+        if keyword == "newmtl" {
+            let name = words.next().unwrap_or_else(|| panic!(error("material needs a name")));
+            descriptions.push(Description {
+                name: name.to_owned(),
+                diffuse: Vec3::new(0.8, 0.8, 0.8),
+                specular: Vec3::new(0., 0., 0.),
+                exponent: 0.,
+                dissolve: 1.,
+                ior: None,
+                texture: None,
+                illumination: 2,
+                roughness: None,
+                metallic: None,
+                roughness_map: None,
+                metallic_map: None,
+            });
+            continue;
+        }
+        let description = match descriptions.last_mut() {
+            Some(description) => description,
+            None => continue,
+        };
+        let numbers: Vec<Float> = words.clone().filter_map(|w| w.parse().ok()).collect();
+        let number = || *numbers.first().unwrap_or_else(|| panic!(error("expected a number")));
+        // A single number is a gray
+        let color = || match numbers.len() {
+            1 => Vec3::new(numbers[0], numbers[0], numbers[0]),
+            n if n >= 3 => Vec3::new(numbers[0], numbers[1], numbers[2]),
+            _ => panic!(error("expected r g b")),
+        };
+        match keyword {
+            "Kd" => description.diffuse = color(),
+            "Ks" => description.specular = color(),
+            "Ns" => description.exponent = number(),
+            "d" => description.dissolve = number(),
+            "Tr" => description.dissolve = 1. - number(),
+            "Ni" => description.ior = Some(number()),
+            "illum" => description.illumination = number() as u32,
+            "Pr" => description.roughness = Some(number()),
+            "Pm" => description.metallic = Some(number()),
+            // Options like -s come before the file name
+            "map_Kd" => description.texture = words.last().map(|file| relative_to(filename, file)),
+            "map_Pr" => {
+                description.roughness_map = words.last().map(|file| relative_to(filename, file))
+            }
+            "map_Pm" => {
+                description.metallic_map = words.last().map(|file| relative_to(filename, file))
+            }
+            _ => {}
+        }
+    }
+    descriptions.into_iter().map(|d| (d.name.clone(), to_material(d, base))).collect()
+}
+
+fn to_material(description: Description, base: &Material) -> Material {
+    let image = |file: String| Box::new(ImageTexture::new(&file)) as Box<Texture>;
+    let texture = description.texture.map(&image);
+    let s = description.specular;
+    let specular = (s.x + s.y + s.z) / 3.;
+    // Models 3 to 7 reflect the scene, by the specular color
+    let reflectivity = match description.illumination {
+        3...7 => specular,
+        _ => 0.,
+    };
+    let material = Material::new(description.diffuse, 1., specular, description.exponent,
+                                 reflectivity, texture, None, None)
+        .with_name(&description.name)
+        .with_compositing(base.compositing())
+        .with_visibility(base.visibility());
+    // Models 5 and 7 weight the reflection by Fresnel
+    let material = match description.illumination {
+        5 | 7 => material.with_fresnel(),
+        _ => material,
+    };
+    // Values rather than colors
+    let linear = |file: String| Box::new(ImageTexture::linear(&file)) as Box<Texture>;
+    let (roughness_map, metallic_map) = (description.roughness_map.map(&linear),
+                                         description.metallic_map.map(&linear));
+    let ggx = description.roughness.is_some() || description.metallic.is_some() ||
+              roughness_map.is_some() || metallic_map.is_some();
+    let material = if ggx {
+        // A map alone gives the whole range, scaled down where it's dark
+        let unit = |x: Float| x.max(0.).min(1.);
+        let roughness = description.roughness
+            .unwrap_or(if roughness_map.is_some() { 1. } else { 0.5 });
+        let metallic = description.metallic.unwrap_or(if metallic_map.is_some() { 1. } else { 0. });
+        let material = material.with_ggx(unit(metallic), unit(roughness));
+        let material = match roughness_map {
+            Some(map) => material.with_roughness_map(map),
+            None => material,
+        };
+        match metallic_map {
+            Some(map) => material.with_metallic_map(map),
+            None => material,
+        }
+    } else {
+        material
+    };
+    let transparency = (1. - description.dissolve).max(0.).min(1.);
+    if transparency > 0. {
+        material.with_transparency(transparency, description.ior.unwrap_or(1.5))
+    } else {
+        material
+    }
+}
+
+// `file` as named in `from`, which is relative to the directory of `from`
+pub fn relative_to(from: &str, file: &str) -> String {
+    match Path::new(from).parent() {
+        Some(dir) => dir.join(file).to_string_lossy().into_owned(),
+        None => file.to_owned(),
+    }
+}
@@ -0,0 +1,31 @@
+// Ambient occlusion: each point the camera sees is as bright as the fraction of the hemisphere
+// above it that's open, judged by a few rays sent out from it. There's no lighting or materials
+// at all, which makes it fast and good for checking a scene's composition before a full render.
+
+use {hit_seed, Float, Scene, Vec3};
+use ray::{Ray, RayKind};
+use sampling;
+
+use nalgebra::dot;
+
+// White where the point is fully open, and for rays that miss everything
+pub fn trace_occlusion(scene: &Scene, ray: &Ray, samples: u32, distance: Float) -> Vec3 {
+    let white = Vec3::new(1., 1., 1.);
+    let hit = match scene.closest_hit(ray) {
+        Some((_, hit)) => hit,
+        None => return white,
+    };
+    // Seen from behind, e.g. inside a cut open object, the open side faces the camera
+    let normal = if dot(&hit.normal, &ray.dir) > 0. { -hit.normal } else { hit.normal };
+    let seed = hit_seed(scene, &hit);
+    let origin = scene.bias.origin(&hit, &normal);
+    let open = (0..samples).filter(|&i| {
+        // Cosine weighted, so rays near the horizon count less like they do for diffuse light
+        let (u1, u2) = scene.sampler.get_2d(seed, i, samples, 16);
+        let dir = sampling::cosine_hemisphere(&normal, u1, u2);
+        let ray = Ray::new(origin, dir).with_extent(0., distance).with_time(ray.time)
+            .with_kind(RayKind::Shadow);
+        scene.closest_hit(&ray).is_none()
+    }).count();
+    white * (open as Float / samples as Float)
+}
@@ -0,0 +1,148 @@
+// The formats rendered images are written in, picked by the extension of the file: PNG, JPEG,
+// TIFF and binary PPM. PNG, TIFF and PPM can hold 16 bits per channel instead of 8, which keeps
+// the precision of dark gradients and leaves room for grading the image later, and PNG and TIFF
+// keep the alpha channel of a transparent background. Radiance .hdr files are written by
+// HdrImage::write_radiance instead, since they keep the unencoded colors.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use jpeg::write_jpeg;
+
+use image::ColorType;
+use image::png::PNGEncoder;
+use image::ppm::PPMEncoder;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Png,
+    Jpeg,
+    Tiff,
+    Ppm,
+}
+
+impl Format {
+    // By the extension of `file`, in any case
+    pub fn of_file(file: &str) -> Result<Self, String> {
+        let extension = Path::new(file).extension().and_then(|e| e.to_str()).unwrap_or("");
+        match &extension.to_lowercase()[..] {
+            "png" => Ok(Format::Png),
+            "jpg" | "jpeg" => Ok(Format::Jpeg),
+            "tif" | "tiff" => Ok(Format::Tiff),
+            "ppm" => Ok(Format::Ppm),
+            _ => Err(format!("Unknown image format of {}, expected png, jpg, tif or ppm", file)),
+        }
+    }
+
+    pub fn has_16_bits(&self) -> bool {
+        *self != Format::Jpeg
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        *self == Format::Png || *self == Format::Tiff
+    }
+}
+
+// Writes 8 bit `data`, `width` by `height` pixels of `channels` bytes each: 3 for RGB, or 4 for
+// RGBA if the format has alpha. `jpeg_quality` from 1 to 100 only applies to JPEG
+pub fn write_8<W: Write>(out: &mut W, format: Format, data: &[u8], width: u32, height: u32,
+                         channels: u8, jpeg_quality: u8)
+                         -> io::Result<()> {
+    assert!(channels == 3 || channels == 4 && format.has_alpha());
+    assert_eq!(data.len(), width as usize * height as usize * channels as usize);
+    let color = if channels == 4 { ColorType::RGBA(8) } else { ColorType::RGB(8) };
+    match format {
+        Format::Png => PNGEncoder::new(out).encode(data, width, height, color),
+        Format::Jpeg => write_jpeg(out, data, width, height, jpeg_quality),
+        Format::Tiff => write_tiff(out, data, width, height, channels, 8),
+        Format::Ppm => PPMEncoder::new(out).encode(data, width, height, color),
+    }
+}
+
+// Like write_8, with 16 bits per channel, for formats that have them
+pub fn write_16<W: Write>(out: &mut W, format: Format, data: &[u16], width: u32, height: u32,
+                          channels: u8)
+                          -> io::Result<()> {
+    assert!(format.has_16_bits(), "{:?} images only have 8 bits per channel", format);
+    assert!(channels == 3 || channels == 4 && format.has_alpha());
+    assert_eq!(data.len(), width as usize * height as usize * channels as usize);
+    let color = if channels == 4 { ColorType::RGBA(16) } else { ColorType::RGB(16) };
+    match format {
+        Format::Png => PNGEncoder::new(out).encode(&bytes_16(data, true), width, height, color),
+        Format::Tiff => write_tiff(out, &bytes_16(data, false), width, height, channels, 16),
+        Format::Ppm => PPMEncoder::new(out).encode(&bytes_16(data, true), width, height, color),
+        Format::Jpeg => unreachable!(),
+    }
+}
+
+// A little endian, uncompressed baseline TIFF of one strip, with `bytes` of samples of `bits`
+// each in the file's byte order. A fourth channel is unassociated alpha
+fn write_tiff<W: Write>(out: &mut W, bytes: &[u8], width: u32, height: u32, channels: u8,
+                        bits: u16)
+                        -> io::Result<()> {
+    const SHORT: u16 = 3;
+    const LONG: u16 = 4;
+    const RATIONAL: u16 = 5;
+    let entries = if channels == 4 { 14 } else { 13 };
+    // The header, then the directory, and the values too large for its entries after it
+    let bits_offset = 8 + 2 + entries * 12 + 4;
+    let resolution_offset = bits_offset + channels as u32 * 2;
+    let data_offset = resolution_offset + 8;
+    let mut tags = vec![(256, LONG, 1, width),
+                        (257, LONG, 1, height),
+                        (258, SHORT, channels as u32, bits_offset),
+                        // Uncompressed
+                        (259, SHORT, 1, 1),
+                        // RGB
+                        (262, SHORT, 1, 2),
+                        (273, LONG, 1, data_offset),
+                        (277, SHORT, 1, channels as u32),
+                        (278, LONG, 1, height),
+                        (279, LONG, 1, bytes.len() as u32),
+                        // 72 pixels per inch for both, sharing the rational
+                        (282, RATIONAL, 1, resolution_offset),
+                        (283, RATIONAL, 1, resolution_offset),
+                        // Interleaved channels
+                        (284, SHORT, 1, 1),
+                        (296, SHORT, 1, 2)];
+    if channels == 4 {
+        tags.push((338, SHORT, 1, 2));
+    }
+    assert_eq!(tags.len() as u32, entries);
+
+    let mut header = b"II*\0\x08\0\0\0".to_vec();
+    header.extend_from_slice(&u16_le(entries as u16));
+    for &(tag, kind, count, value) in &tags {
+        header.extend_from_slice(&u16_le(tag));
+        header.extend_from_slice(&u16_le(kind));
+        header.extend_from_slice(&u32_le(count));
+        // Values of a single short go in the low bytes
+        header.extend_from_slice(&u32_le(value));
+    }
+    header.extend_from_slice(&[0; 4]);
+    for _ in 0..channels {
+        header.extend_from_slice(&u16_le(bits));
+    }
+    header.extend_from_slice(&u32_le(72));
+    header.extend_from_slice(&u32_le(1));
+    try!(out.write_all(&header));
+    out.write_all(bytes)
+}
+
+// PNG and PPM store samples big endian, and the TIFFs written here little endian
+fn bytes_16(data: &[u16], big_endian: bool) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() * 2);
+    for &sample in data {
+        let (high, low) = ((sample >> 8) as u8, sample as u8);
+        bytes.extend_from_slice(&if big_endian { [high, low] } else { [low, high] });
+    }
+    bytes
+}
+
+fn u16_le(n: u16) -> [u8; 2] {
+    [n as u8, (n >> 8) as u8]
+}
+
+fn u32_le(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
@@ -0,0 +1,247 @@
+// Path tracing, as an alternative to the recursive tracer in lib.rs that also finds the light
+// bouncing off diffuse surfaces. Each camera ray continues along a single path, choosing at every
+// hit between a diffuse bounce, a glossy bounce off GGX materials, a mirror reflection and
+// refraction in proportion to how much each contributes, and lights, emissive surfaces and the
+// environment are sampled directly at every hit. Emissive surfaces and the environment can also
+// be found by diffuse and glossy bounces, so the two estimates of their light are combined by
+// multiple importance sampling with the balance heuristic: each is weighted by how likely it was
+// to pick its direction out of both, which keeps small bright emitters and glossy highlights
+// from being noisy under either one alone. Paths don't stop at the reflection depth but at
+// random by Russian roulette once they carry little light, which keeps the result unbiased.
+// The diffuse bounce off a subsurface material first takes a random walk inside it, see
+// subsurface.
+//
+// The ambient term is left out, since the bounced light is what it stands in for.
+
+use std::str::FromStr;
+
+use {background, emission, emitter_density, environment_density, float, hit_seed,
+     lights_color, reflected_ray, refraction_rays, shadow_fraction, weighted_emitter_color,
+     weighted_environment_color, Float, Scene, Vec3};
+use material::{Compositing, Material};
+use post::luminance;
+use ray::{Intersection, Ray, RayKind};
+use sampling;
+use subsurface;
+
+use nalgebra::dot;
+
+// How the light reaching the camera is computed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Integrator {
+    // Direct light, ambient light and mirror reflections and refraction up to the reflection
+    // depth
+    Whitted,
+    // Monte Carlo path tracing with global illumination
+    Path,
+    // Only how much of the sky each point seen from the camera is open to, from `samples` rays
+    // that count as blocked if they hit something within `distance`. A quick preview of the
+    // scene's shapes, see occlusion
+    AmbientOcclusion { samples: u32, distance: Float },
+}
+
+impl FromStr for Integrator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "whitted" => Ok(Integrator::Whitted),
+            "path" => Ok(Integrator::Path),
+            "ao" => {
+                Ok(Integrator::AmbientOcclusion { samples: DEFAULT_OCCLUSION_SAMPLES,
+                                                  distance: float::INFINITY })
+            }
+            _ => Err(format!("Unknown integrator: {}", s)),
+        }
+    }
+}
+
+const DEFAULT_OCCLUSION_SAMPLES: u32 = 16;
+
+// Bounces before Russian roulette starts, unless set with Scene::set_path_bounces
+pub const MIN_BOUNCES: u32 = 3;
+// Paths are cut off after this many bounces anyway, e.g. between two parallel mirrors
+pub const MAX_BOUNCES: u32 = 256;
+
+pub fn trace_path(scene: &Scene, ray: &Ray) -> Vec3 {
+    let mut ray = ray.clone();
+    let mut color = Vec3::new(0., 0., 0.);
+    let mut throughput = Vec3::new(1., 1., 1.);
+    // After a diffuse or glossy bounce, the density it picked the ray's direction with and where
+    // from. The environment and emitters were sampled directly there too, so what the ray finds
+    // of them is weighted against that
+    let mut last_bounce: Option<(Float, Vec3)> = None;
+    let (roulette_bounces, max_bounces) = scene.path_bounces;
+    for bounce in 0..max_bounces {
+        let (i, hit) = match scene.closest_hit(&ray) {
+            Some(result) => result,
+            None => {
+                let weight = match last_bounce {
+                    Some((pdf, ref from)) => {
+                        balance(pdf, environment_density(scene, from, &ray.dir))
+                    }
+                    None => 1.,
+                };
+                color = color + throughput * background(scene, &ray) * weight;
+                break;
+            }
+        };
+        let obj = &scene.objects[i];
+        let material = obj.material_at(&hit);
+        throughput = throughput * material.interior_transmittance(&ray, &hit);
+        match material.compositing() {
+            Compositing::Shaded => {}
+            Compositing::ShadowCatcher => {
+                let shadow = shadow_fraction(scene, &hit);
+                color = color + throughput * background(scene, &ray) * (1. - shadow);
+                break;
+            }
+            Compositing::Holdout => break,
+        }
+        let weight = match last_bounce {
+            Some((pdf, _)) if scene.emitters.contains(&i) => {
+                balance(pdf, emitter_density(scene, &**obj, &ray, &hit))
+            }
+            _ => 1.,
+        };
+        color = color + throughput * emission(scene, material) * weight;
+
+        // What happens next is picked in proportion to how much light each option carries
+        let transparency = material.transparency();
+        let albedo = material.albedo(&hit);
+        let diffuse = (1. - transparency) * luminance(&albedo);
+        let specular_albedo = material.specular_albedo(&hit);
+        let glossy = (1. - transparency) * luminance(&specular_albedo);
+        let total = diffuse + glossy + transparency + material.reflectivity_at(&ray, &hit);
+        let (diffuse_odds, glossy_odds) = if total > 0. {
+            (diffuse / total, glossy / total)
+        } else {
+            (0., 0.)
+        };
+        // The diffuse light of subsurface materials comes out where their random walk leaves
+        // them instead, so it's neither lit nor bounced here
+        let subsurface = material.subsurface();
+        let reflected_odds = if subsurface.is_some() { 0. } else { diffuse_odds };
+
+        {
+            let shade = |shadow_ray: &Ray| if subsurface.is_some() {
+                material.specular_color(shadow_ray, &ray, &hit)
+            } else {
+                material.color(shadow_ray, &ray, &hit)
+            };
+            let weight = |shadow_ray: &Ray, density: Float| {
+                let pdf = bounce_pdf(material, &ray, &hit, reflected_odds, glossy_odds,
+                                     &shadow_ray.dir);
+                balance(density, pdf)
+            };
+            let direct = lights_color(scene, &hit, &shade) +
+                         weighted_emitter_color(scene, &hit, &shade, &weight) +
+                         weighted_environment_color(scene, &hit, &shade, &weight);
+            color = color + throughput * direct * (1. - transparency);
+        }
+
+        if total <= 0. {
+            break;
+        }
+        let seed = hit_seed(scene, &hit);
+        let choice = sampling::uniform(seed, bounce, 8) * total;
+        let (next, weight, bounced) = if choice < diffuse {
+            let weight = albedo * (total / luminance(&albedo));
+            // Off the surface, or out of it again where the walk leaves
+            let (from, weight) = match subsurface {
+                Some(inside) => {
+                    match subsurface::walk(scene, inside, &ray, &hit, seed, bounce) {
+                        Some((exit, through)) => {
+                            throughput = throughput * weight * through;
+                            color = color + throughput * exit_color(scene, &exit);
+                            (exit, Vec3::new(1., 1., 1.))
+                        }
+                        None => break,
+                    }
+                }
+                None => (hit.clone(), weight),
+            };
+            let dir = sampling::cosine_hemisphere(&from.normal,
+                                                  sampling::uniform(seed, bounce, 9),
+                                                  sampling::uniform(seed, bounce, 10));
+            let origin = scene.bias.origin(&from, &from.normal);
+            let next = Ray::new(origin, dir).with_time(ray.time).with_kind(RayKind::Reflection)
+                .with_wavelength(ray.wavelength);
+            let pdf = if subsurface.is_some() {
+                diffuse_pdf(&from.normal, &dir)
+            } else {
+                bounce_pdf(material, &ray, &hit, diffuse_odds, glossy_odds, &dir)
+            };
+            (next, weight, Some((pdf, from.pos)))
+        } else if choice < diffuse + glossy {
+            let sample = material.sample_specular(&ray, &hit, sampling::uniform(seed, bounce, 13),
+                                                  sampling::uniform(seed, bounce, 14));
+            match sample {
+                Some((dir, weight)) => {
+                    let origin = scene.bias.origin(&hit, &hit.normal);
+                    let next = Ray::new(origin, dir).with_time(ray.time)
+                        .with_kind(RayKind::Reflection).with_wavelength(ray.wavelength);
+                    let pdf = bounce_pdf(material, &ray, &hit, reflected_odds, glossy_odds, &dir);
+                    (next, weight * (total / glossy), Some((pdf, hit.pos)))
+                }
+                None => break,
+            }
+        } else if choice < diffuse + glossy + transparency {
+            let (reflected, refracted) = refraction_rays(scene, &ray, material, &hit);
+            let next = match refracted {
+                Some((refracted, fresnel)) => {
+                    let u = sampling::uniform(seed, bounce, 11);
+                    if u < fresnel { reflected } else { refracted }
+                }
+                None => reflected,
+            };
+            (next, Vec3::new(total, total, total), None)
+        } else {
+            (reflected_ray(scene, &ray, &hit), Vec3::new(total, total, total), None)
+        };
+        last_bounce = bounced;
+        throughput = throughput * weight;
+        ray = next;
+
+        if bounce + 1 >= roulette_bounces {
+            let survival = throughput.x.max(throughput.y).max(throughput.z).min(1.);
+            if sampling::uniform(seed, bounce, 12) >= survival {
+                break;
+            }
+            throughput = throughput / survival;
+        }
+    }
+    color
+}
+
+// The light of the lights, emitters and environment that a subsurface material's random walk
+// carries out at `exit`, spread out diffusely from there as if off a white surface
+fn exit_color(scene: &Scene, exit: &Intersection) -> Vec3 {
+    let shade = |shadow_ray: &Ray| {
+        Vec3::new(1., 1., 1.) * dot(&exit.normal, &shadow_ray.dir).max(0.)
+    };
+    let weight = |shadow_ray: &Ray, density: Float| {
+        balance(density, diffuse_pdf(&exit.normal, &shadow_ray.dir))
+    };
+    lights_color(scene, exit, &shade) + weighted_emitter_color(scene, exit, &shade, &weight) +
+    weighted_environment_color(scene, exit, &shade, &weight)
+}
+
+// The density of trace_path picking `dir` for the next ray by a diffuse or glossy bounce off
+// `hit`, which it does with chances `diffuse` and `glossy` out of 1
+fn bounce_pdf(material: &Material, ray: &Ray, hit: &Intersection, diffuse: Float, glossy: Float,
+              dir: &Vec3)
+              -> Float {
+    diffuse * diffuse_pdf(&hit.normal, dir) + glossy * material.specular_pdf(ray, hit, dir)
+}
+
+// The density of a diffuse bounce off `normal` picking `dir`
+fn diffuse_pdf(normal: &Vec3, dir: &Vec3) -> Float {
+    dot(normal, dir).max(0.) / float::consts::PI
+}
+
+// The balance heuristic's weight for an estimate from a technique with density `pdf`, against
+// one with density `other`, both already times their number of samples
+fn balance(pdf: Float, other: Float) -> Float {
+    if pdf + other > 0. { pdf / (pdf + other) } else { 0. }
+}
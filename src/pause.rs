@@ -0,0 +1,58 @@
+// Pausing of long renders. The render loop calls wait_while_paused() between tiles, so the
+// partially rendered image just stays in memory until the render is resumed.
+//
+// Pausing is toggled by SIGUSR1 (e.g. `kill -USR1 <pid>` for headless renders), or by pressing
+// enter when running from a terminal.
+
+use std::io::{self, BufRead};
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+static PAUSED: AtomicBool = ATOMIC_BOOL_INIT;
+
+pub fn install() {
+    install_signal_handler();
+
+    if unsafe { libc::isatty(0) } != 0 {
+        info!("Press enter to pause/resume");
+        thread::spawn(|| {
+            let stdin = io::stdin();
+            for _ in stdin.lock().lines() {
+                toggle();
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+fn install_signal_handler() {
+    extern "C" fn on_sigusr1(_: libc::c_int) {
+        toggle();
+    }
+
+    unsafe {
+        libc::signal(libc::SIGUSR1, on_sigusr1 as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_signal_handler() {}
+
+fn toggle() {
+    PAUSED.fetch_xor(true, Ordering::SeqCst);
+}
+
+pub fn wait_while_paused() {
+    if !PAUSED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    info!("Paused");
+    while PAUSED.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    info!("Resumed");
+}
@@ -0,0 +1,285 @@
+// PLY meshes, the format most scanned models come in, in the ASCII and both binary variants.
+// Vertices may have normals and texture coordinates, under the names most programs write them
+// with, and faces are split into triangle fans like OBJ polygons. Other elements and properties,
+// like vertex colors, are skipped.
+
+use std::f64;
+use std::fs::File;
+use std::io::Read;
+
+use {Float, Vec3};
+use material::Material;
+use mesh::{remove_degenerate, Triangle, TriangleMesh};
+
+use nalgebra::Norm;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Type {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl Type {
+    fn parse(name: &str) -> Result<Type, String> {
+        match name {
+            "char" | "int8" => Ok(Type::Int8),
+            "uchar" | "uint8" => Ok(Type::UInt8),
+            "short" | "int16" => Ok(Type::Int16),
+            "ushort" | "uint16" => Ok(Type::UInt16),
+            "int" | "int32" => Ok(Type::Int32),
+            "uint" | "uint32" => Ok(Type::UInt32),
+            "float" | "float32" => Ok(Type::Float32),
+            "double" | "float64" => Ok(Type::Float64),
+            _ => Err(format!("unknown property type {}", name)),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            Type::Int8 | Type::UInt8 => 1,
+            Type::Int16 | Type::UInt16 => 2,
+            Type::Int32 | Type::UInt32 | Type::Float32 => 4,
+            Type::Float64 => 8,
+        }
+    }
+}
+
+enum Property {
+    Scalar(Type),
+    // Types of the length and of the items
+    List(Type, Type),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<(String, Property)>,
+}
+
+impl Element {
+    // Index of the first property with one of `names`
+    fn find(&self, names: &[&str]) -> Option<usize> {
+        self.properties.iter().position(|p| names.contains(&&p.0[..]))
+    }
+}
+
+impl TriangleMesh {
+    pub fn load_ply(filename: &str, material: Material) -> Self {
+        let mut data = Vec::new();
+        File::open(filename).and_then(|mut file| file.read_to_end(&mut data))
+            .unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        let (positions, normals, uvs, mut triangles) =
+            read(&data).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        let removed = remove_degenerate(&positions, &mut triangles);
+        debug!("Loaded {} ({} vertices, {} triangles, {} degenerate ones left out)", filename,
+               positions.len(), triangles.len(), removed);
+        TriangleMesh::new(positions, normals, uvs, triangles, material)
+    }
+}
+
+// The format, the elements and where the data after the header starts
+fn read_header(data: &[u8]) -> Result<(Format, Vec<Element>, usize), String> {
+    let marker = b"end_header";
+    let end = try!(data.windows(marker.len()).position(|w| w == marker)
+        .ok_or("no end_header"));
+    let start = try!(data[end..].iter().position(|&b| b == b'\n').map(|i| end + i + 1)
+        .ok_or("no end_header"));
+    let header = String::from_utf8_lossy(&data[..end]);
+    let mut lines = header.lines();
+    if lines.next().map(|line| line.trim()) != Some("ply") {
+        return Err("not a PLY file".to_owned());
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.get(0).cloned() {
+            Some("format") if words.len() >= 2 => {
+                format = Some(match words[1] {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::LittleEndian,
+                    "binary_big_endian" => Format::BigEndian,
+                    _ => return Err(format!("unknown format {}", words[1])),
+                });
+            }
+            Some("element") if words.len() == 3 => {
+                let count = try!(words[2].parse()
+                    .map_err(|_| format!("invalid element count {}", words[2])));
+                elements.push(Element { name: words[1].to_owned(), count: count,
+                                        properties: Vec::new() });
+            }
+            Some("property") => {
+                let element = try!(elements.last_mut().ok_or("property before any element"));
+                let property = if words.len() == 5 && words[1] == "list" {
+                    (words[4], Property::List(try!(Type::parse(words[2])),
+                                              try!(Type::parse(words[3]))))
+                } else if words.len() == 3 {
+                    (words[2], Property::Scalar(try!(Type::parse(words[1]))))
+                } else {
+                    return Err(format!("invalid property: {}", line));
+                };
+                element.properties.push((property.0.to_owned(), property.1));
+            }
+            // Comments and obj_info
+            _ => {}
+        }
+    }
+    let format = try!(format.ok_or("no format"));
+    Ok((format, elements, start))
+}
+
+// The data after the header, read one value at a time
+struct Body<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: Format,
+}
+
+impl<'a> Body<'a> {
+    fn value(&mut self, ty: Type) -> Result<f64, String> {
+        let data = self.data;
+        if self.format == Format::Ascii {
+            while self.pos < data.len() && (data[self.pos] as char).is_whitespace() {
+                self.pos += 1;
+            }
+            let start = self.pos;
+            while self.pos < data.len() && !(data[self.pos] as char).is_whitespace() {
+                self.pos += 1;
+            }
+            let word = String::from_utf8_lossy(&data[start..self.pos]);
+            if word.is_empty() {
+                return Err("file ends early".to_owned());
+            }
+            return word.parse().map_err(|_| format!("invalid number {}", word));
+        }
+
+        let size = ty.size();
+        if self.pos + size > data.len() {
+            return Err("file ends early".to_owned());
+        }
+        let bytes = &data[self.pos..self.pos + size];
+        self.pos += size;
+        let bits = if self.format == Format::LittleEndian {
+            bytes.iter().rev().fold(0u64, |bits, &b| bits << 8 | b as u64)
+        } else {
+            bytes.iter().fold(0u64, |bits, &b| bits << 8 | b as u64)
+        };
+        Ok(match ty {
+            Type::Int8 => bits as u8 as i8 as f64,
+            Type::Int16 => bits as u16 as i16 as f64,
+            Type::Int32 => bits as u32 as i32 as f64,
+            Type::UInt8 | Type::UInt16 | Type::UInt32 => bits as f64,
+            Type::Float32 => f32::from_bits(bits as u32) as f64,
+            Type::Float64 => f64::from_bits(bits),
+        })
+    }
+
+    // Reads one element into `values`, by property, with the length for lists. The items of the
+    // list property `list` go into `items`, those of other lists are skipped
+    fn record(&mut self, element: &Element, list: Option<usize>, values: &mut [f64],
+              items: &mut Vec<f64>) -> Result<(), String> {
+        items.clear();
+        for (i, &(_, ref property)) in element.properties.iter().enumerate() {
+            values[i] = match *property {
+                Property::Scalar(ty) => try!(self.value(ty)),
+                Property::List(length, item) => {
+                    let count = try!(self.value(length));
+                    for _ in 0..count as usize {
+                        let value = try!(self.value(item));
+                        if list == Some(i) {
+                            items.push(value);
+                        }
+                    }
+                    count
+                }
+            };
+        }
+        Ok(())
+    }
+}
+
+fn read(data: &[u8]) -> Result<(Vec<Vec3>, Vec<Vec3>, Vec<(Float, Float)>, Vec<Triangle>), String> {
+    let (format, elements, start) = try!(read_header(data));
+    let mut body = Body { data: data, pos: start, format: format };
+    let (mut positions, mut normals, mut uvs, mut triangles) =
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let mut items = Vec::new();
+    for element in elements.iter() {
+        let mut values = vec![0.; element.properties.len()];
+        match &element.name[..] {
+            "vertex" => {
+                let xyz = [element.find(&["x"]), element.find(&["y"]), element.find(&["z"])];
+                let n = [element.find(&["nx"]), element.find(&["ny"]), element.find(&["nz"])];
+                let uv = [element.find(&["u", "s", "texture_u"]),
+                          element.find(&["v", "t", "texture_v"])];
+                if xyz.iter().any(|i| i.is_none()) {
+                    return Err("vertices need x, y and z".to_owned());
+                }
+                for _ in 0..element.count {
+                    try!(body.record(element, None, &mut values, &mut items));
+                    let get = |i: Option<usize>| values[i.unwrap()] as Float;
+                    positions.push(Vec3::new(get(xyz[0]), get(xyz[1]), get(xyz[2])));
+                    if n.iter().all(|i| i.is_some()) {
+                        normals.push(Vec3::new(get(n[0]), get(n[1]), get(n[2])).normalize());
+                    }
+                    if uv.iter().all(|i| i.is_some()) {
+                        // Like OBJ, v points up
+                        uvs.push((get(uv[0]), 1. - get(uv[1])));
+                    }
+                }
+            }
+            "face" => {
+                let list = element.find(&["vertex_indices", "vertex_index"]);
+                if list.is_none() {
+                    return Err("faces need vertex_indices".to_owned());
+                }
+                for _ in 0..element.count {
+                    try!(body.record(element, list, &mut values, &mut items));
+                    if items.len() < 3 {
+                        return Err("face needs at least 3 vertices".to_owned());
+                    }
+                    for i in 1..items.len() - 1 {
+                        triangles.push([items[0], items[i], items[i + 1]]);
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..element.count {
+                    try!(body.record(element, None, &mut values, &mut items));
+                }
+            }
+        }
+    }
+
+    let count = positions.len() as f64;
+    if triangles.iter().any(|t| t.iter().any(|&i| i < 0. || i >= count)) {
+        return Err("face vertex index out of range".to_owned());
+    }
+    let triangles = triangles.iter().map(|t| {
+        let corners = [t[0] as usize, t[1] as usize, t[2] as usize];
+        let shared = |attributes: usize| {
+            if attributes == positions.len() { Some(corners) } else { None }
+        };
+        Triangle {
+            positions: corners,
+            normals: shared(normals.len()),
+            uvs: shared(uvs.len()),
+            material: 0,
+        }
+    }).collect();
+    Ok((positions, normals, uvs, triangles))
+}
@@ -0,0 +1,69 @@
+// Light portals: rectangles over the openings environment light comes in through, like the
+// windows and doors of a room. Importance sampling the environment map finds its bright parts,
+// but from inside a room nearly all of those directions end at a wall, so its few samples that
+// get out through a small window make for very noisy light. Environment samples then also go
+// towards points on the portals, which is where the light that matters comes from, and are
+// weighed against the map's own by the density of either picking them, so light from outside
+// the portals is still found and nothing is lost if they're placed badly.
+//
+// Portals aren't surfaces: rays pass through them without noticing, and they light nothing by
+// themselves. They work from both sides, and unlike the guide, need no learning pass.
+
+use {Float, Vec3};
+
+use nalgebra::{cross, dot, Norm};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Portal {
+    corner: Vec3,
+    edge_u: Vec3,
+    edge_v: Vec3,
+    // The cross product of the edges, whose length is the area of the portal
+    normal: Vec3,
+}
+
+impl Portal {
+    // The rectangle centered on `pos`, spanned by the edges `edge_u` and `edge_v`, like a
+    // rectangular light
+    pub fn new(pos: Vec3, edge_u: Vec3, edge_v: Vec3) -> Self {
+        let normal = cross(&edge_u, &edge_v);
+        assert!(normal.norm_squared() > 0., "A portal's edges must span a rectangle");
+        Portal {
+            corner: pos - (edge_u + edge_v) * 0.5,
+            edge_u: edge_u,
+            edge_v: edge_v,
+            normal: normal,
+        }
+    }
+
+    // The unit direction from `from` towards the point at `u1`, `u2` from 0 to 1 across the
+    // portal, or None if `from` lies on it
+    pub fn sample(&self, from: &Vec3, u1: Float, u2: Float) -> Option<Vec3> {
+        let to = self.corner + self.edge_u * u1 + self.edge_v * u2 - *from;
+        let length = to.norm();
+        if length > 0. { Some(to / length) } else { None }
+    }
+
+    // The density per steradian of sample picking the unit direction `dir` from `from`, which
+    // is 0 where it misses the portal
+    pub fn pdf(&self, from: &Vec3, dir: &Vec3) -> Float {
+        let facing = dot(dir, &self.normal);
+        if facing == 0. {
+            return 0.;
+        }
+        let dist = dot(&(self.corner - *from), &self.normal) / facing;
+        if dist <= 0. {
+            return 0.;
+        }
+        // Where along each edge the direction crosses the portal's plane
+        let p = *from + *dir * dist - self.corner;
+        let area_squared = self.normal.norm_squared();
+        let u = dot(&cross(&p, &self.edge_v), &self.normal) / area_squared;
+        let v = dot(&cross(&self.edge_u, &p), &self.normal) / area_squared;
+        if u < 0. || u > 1. || v < 0. || v > 1. {
+            return 0.;
+        }
+        // The distance squared over the area the portal shows in that direction
+        dist * dist / facing.abs()
+    }
+}
@@ -0,0 +1,555 @@
+// Post processing of rendered images. Effects operate on the unclamped HDR image after
+// downsampling and run in order, before the image is clamped and encoded:
+//
+//     let post = PostPipeline::new().then(Saturation::new(1.2));
+//     post.apply(&mut im, &scene);
+//
+// Effects get the scene too, for camera effects that depend on what's in view. The scene camera's
+// own imaging controls (see Imaging) run before all of them.
+
+
+use aov::{ray_trace_aov, Aov};
+use color::OutputTransform;
+use hdr::HdrImage;
+use light::LightShape;
+use ray::Ray;
+use sampling;
+use log::{self, Level};
+use {float, Float, Scene, Vec3};
+
+use nalgebra::{clamp, dot, Norm};
+
+pub trait PostEffect {
+    fn apply(&self, image: &mut HdrImage, scene: &Scene);
+    fn name(&self) -> &'static str;
+}
+
+pub struct PostPipeline {
+    effects: Vec<Box<PostEffect>>,
+}
+
+impl PostPipeline {
+    pub fn new() -> Self {
+        PostPipeline { effects: Vec::new() }
+    }
+
+    pub fn then<E: PostEffect + 'static>(mut self, effect: E) -> Self {
+        self.push(Box::new(effect));
+        self
+    }
+
+    pub fn push(&mut self, effect: Box<PostEffect>) {
+        self.effects.push(effect);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    pub fn apply(&self, image: &mut HdrImage, scene: &Scene) {
+        scene.camera().imaging().apply(image, scene);
+        for effect in self.effects.iter() {
+            let _span = log::span(Level::Debug, format!("post {}", effect.name()));
+            effect.apply(image, scene);
+        }
+    }
+}
+
+// Scales each color's distance from its luminance, 0 gives grayscale and 1 leaves it unchanged
+pub struct Saturation {
+    amount: Float,
+}
+
+impl Saturation {
+    pub fn new(amount: Float) -> Self {
+        Saturation { amount: amount }
+    }
+}
+
+impl PostEffect for Saturation {
+    fn apply(&self, image: &mut HdrImage, _: &Scene) {
+        for pixel in image.pixels_mut() {
+            let gray = luminance(pixel);
+            let gray = Vec3::new(gray, gray, gray);
+            *pixel = gray + (*pixel - gray) * self.amount;
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "saturation"
+    }
+}
+
+// Scales the brightness by 2^ev, like opening the aperture by `ev` stops
+pub struct Exposure {
+    ev: Float,
+}
+
+impl Exposure {
+    pub fn new(ev: Float) -> Self {
+        Exposure { ev: ev }
+    }
+}
+
+impl PostEffect for Exposure {
+    fn apply(&self, image: &mut HdrImage, _: &Scene) {
+        let scale = self.ev.exp2();
+        for pixel in image.pixels_mut() {
+            *pixel = *pixel * scale;
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "exposure"
+    }
+}
+
+// Corrects for lighting of the given color temperature in Kelvin, so that white lit by it ends
+// up white. Lower temperatures than the 6500K reference make the image cooler, higher ones make
+// it warmer. `tint` shifts between green (negative) and magenta (positive)
+pub struct WhiteBalance {
+    scale: Vec3,
+}
+
+impl WhiteBalance {
+    pub fn new(temperature: Float, tint: Float) -> Self {
+        let white = blackbody(temperature);
+        let reference = blackbody(6500.);
+        let scale = Vec3::new(reference.x / white.x,
+                              reference.y / white.y * (1. - tint * 0.5),
+                              reference.z / white.z);
+        // Keep the overall brightness
+        WhiteBalance { scale: scale / luminance(&scale) }
+    }
+}
+
+impl PostEffect for WhiteBalance {
+    fn apply(&self, image: &mut HdrImage, _: &Scene) {
+        for pixel in image.pixels_mut() {
+            *pixel = Vec3::new(pixel.x * self.scale.x, pixel.y * self.scale.y,
+                               pixel.z * self.scale.z);
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "white balance"
+    }
+}
+
+// Darkens the image towards its corners, like a lens passing less light at the edge of its
+// field. Pixels within `radius` of the center (as a fraction of the distance to the corners) are
+// left alone, and from there the darkening eases in to `amount` at the corners
+pub struct Vignette {
+    amount: Float,
+    radius: Float,
+}
+
+impl Vignette {
+    pub fn new(amount: Float, radius: Float) -> Self {
+        assert!(amount >= 0. && amount <= 1., "Vignette amounts must be between 0 and 1");
+        assert!(radius >= 0. && radius < 1., "Vignette radii must be at least 0 and below 1");
+        Vignette { amount: amount, radius: radius }
+    }
+}
+
+impl PostEffect for Vignette {
+    fn apply(&self, image: &mut HdrImage, _: &Scene) {
+        let (width, height) = (image.width(), image.height());
+        let center = (width as Float / 2., height as Float / 2.);
+        let corner = (center.0 * center.0 + center.1 * center.1).sqrt();
+        for y in 0..height {
+            for x in 0..width {
+                let dx = x as Float + 0.5 - center.0;
+                let dy = y as Float + 0.5 - center.1;
+                let t = (((dx * dx + dy * dy).sqrt() / corner - self.radius) /
+                         (1. - self.radius)).max(0.).min(1.);
+                let scale = 1. - self.amount * t * t * (3. - 2. * t);
+                let pixel = image.get_pixel(x, y);
+                image.put_pixel(x, y, pixel * scale);
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "vignette"
+    }
+}
+
+// The imaging controls of a camera, applied to its frames before the post effects: white
+// balance, then exposure, then vignetting. The default leaves the image as it is
+#[derive(Clone, Debug, PartialEq)]
+pub struct Imaging {
+    // Stops brighter, see Exposure
+    pub ev: Float,
+    // Temperature and tint, see WhiteBalance
+    pub white_balance: Option<(Float, Float)>,
+    // Amount and radius, see Vignette
+    pub vignette: Option<(Float, Float)>,
+}
+
+impl Default for Imaging {
+    fn default() -> Self {
+        Imaging { ev: 0., white_balance: None, vignette: None }
+    }
+}
+
+impl Imaging {
+    pub fn is_neutral(&self) -> bool {
+        *self == Imaging::default()
+    }
+
+    pub fn apply(&self, image: &mut HdrImage, scene: &Scene) {
+        if let Some((temperature, tint)) = self.white_balance {
+            WhiteBalance::new(temperature, tint).apply(image, scene);
+        }
+        if self.ev != 0. {
+            Exposure::new(self.ev).apply(image, scene);
+        }
+        if let Some((amount, radius)) = self.vignette {
+            Vignette::new(amount, radius).apply(image, scene);
+        }
+    }
+}
+
+// Approximate color of a black body at the given temperature in Kelvin, in 0..1. A curve fit
+// to the CIE data that's good from 1000K to 40000K
+fn blackbody(temperature: Float) -> Vec3 {
+    let t = clamp(temperature, 1000., 40000.) / 100.;
+    let red = if t <= 66. { 255. } else { 329.699 * (t - 60.).powf(-0.1332047) };
+    let green = if t <= 66. {
+        99.4708 * t.ln() - 161.1196
+    } else {
+        288.1222 * (t - 60.).powf(-0.0755148)
+    };
+    let blue = if t >= 66. {
+        255.
+    } else if t <= 19. {
+        0.
+    } else {
+        138.5177 * (t - 10.).ln() - 305.0448
+    };
+    // Avoid dividing by zero blue for very warm light
+    Vec3::new(clamp(red, 1., 255.), clamp(green, 1., 255.), clamp(blue, 1., 255.)) / 255.
+}
+
+// Smooths the noise of renders with few samples, e.g. path traces, by averaging each pixel with
+// its neighbors within `radius` pixels. The neighbors count less the more their normal and
+// albedo differ from the pixel's, which are rendered for the purpose like the normal and albedo
+// AOVs, so the average doesn't cross the edges of objects or of their textures. With a
+// `color_sigma`, neighbors also count less the more their color differs, which keeps more of
+// the shading's detail but less of the noise is removed. It should run first, before effects
+// that spread bright pixels around such as bloom. The guides are the scene camera's view of the
+// whole image, so they don't line up with the eyes of stereo renders
+pub struct Denoise {
+    radius: u32,
+    color_sigma: Option<Float>,
+}
+
+// How far apart normals (as vectors) and albedos (0 to 1) can be and still be averaged
+const DENOISE_NORMAL_SIGMA: Float = 0.3;
+const DENOISE_ALBEDO_SIGMA: Float = 0.1;
+// Samples per axis of the normal and albedo, so they're anti-aliased like the image
+const DENOISE_GUIDE_SAMPLES: u32 = 2;
+
+impl Denoise {
+    pub fn new(radius: u32, color_sigma: Option<Float>) -> Self {
+        assert!(radius > 0, "Denoising needs a radius of at least one pixel");
+        assert!(color_sigma.map_or(true, |sigma| sigma > 0.), "color_sigma must be positive");
+        Denoise { radius: radius, color_sigma: color_sigma }
+    }
+}
+
+impl PostEffect for Denoise {
+    fn apply(&self, image: &mut HdrImage, scene: &Scene) {
+        let (width, height) = (image.width(), image.height());
+        let guide = |aov| ray_trace_aov(scene, width, height, DENOISE_GUIDE_SAMPLES, aov);
+        let (normals, albedos) = (guide(Aov::Normal), guide(Aov::Albedo));
+        let noisy = image.clone();
+
+        let radius = self.radius as i32;
+        // Half way to the edge of the window, so that its corners count for little
+        let spatial_sigma = self.radius as Float / 2.;
+        let falloff = |distance_squared: Float, sigma: Float| {
+            (-distance_squared / (2. * sigma * sigma)).exp()
+        };
+        for y in 0..height as i32 {
+            for x in 0..width as i32 {
+                let (px, py) = (x as u32, y as u32);
+                let (color, normal) = (noisy.get_pixel(px, py), normals.get_pixel(px, py));
+                let albedo = albedos.get_pixel(px, py);
+                let (mut sum, mut total) = (Vec3::new(0., 0., 0.), 0.);
+                for dy in -radius..radius + 1 {
+                    for dx in -radius..radius + 1 {
+                        let (qx, qy) = (x + dx, y + dy);
+                        if qx < 0 || qy < 0 || qx >= width as i32 || qy >= height as i32 {
+                            continue;
+                        }
+                        let (qx, qy) = (qx as u32, qy as u32);
+                        let neighbor = noisy.get_pixel(qx, qy);
+                        let mut weight = falloff((dx * dx + dy * dy) as Float, spatial_sigma) *
+                            falloff((normals.get_pixel(qx, qy) - normal).norm_squared(),
+                                    DENOISE_NORMAL_SIGMA) *
+                            falloff((albedos.get_pixel(qx, qy) - albedo).norm_squared(),
+                                    DENOISE_ALBEDO_SIGMA);
+                        if let Some(sigma) = self.color_sigma {
+                            weight *= falloff((neighbor - color).norm_squared(), sigma);
+                        }
+                        sum = sum + neighbor * weight;
+                        total += weight;
+                    }
+                }
+                // The pixel itself always has weight 1, so total is never 0
+                image.put_pixel(px, py, sum / total);
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "denoise"
+    }
+}
+
+// Blurs the parts of the image brighter than `threshold` and adds them back on top, so bright
+// highlights bleed into their surroundings instead of clipping to flat white
+pub struct Bloom {
+    threshold: Float,
+    radius: Float,
+    strength: Float,
+}
+
+impl Bloom {
+    pub fn new(threshold: Float, radius: Float, strength: Float) -> Self {
+        Bloom { threshold: threshold, radius: radius, strength: strength }
+    }
+}
+
+impl PostEffect for Bloom {
+    fn apply(&self, image: &mut HdrImage, _: &Scene) {
+        let mut bright = image.clone();
+        for pixel in bright.pixels_mut() {
+            // Keep the hue of the bright part by scaling the whole color down
+            let lum = luminance(pixel);
+            *pixel = if lum > self.threshold {
+                *pixel * ((lum - self.threshold) / lum)
+            } else {
+                Vec3::new(0., 0., 0.)
+            };
+        }
+
+        let glow = blur(&bright, self.radius);
+        for (pixel, glow) in image.pixels_mut().iter_mut().zip(glow.pixels()) {
+            *pixel = *pixel + *glow * self.strength;
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "bloom"
+    }
+}
+
+// Samples the red and blue channels slightly further out and in from the image center than green,
+// like a lens that focuses colors differently. `amount` is the offset at the corners as a fraction
+// of the distance to the center
+pub struct ChromaticAberration {
+    amount: Float,
+}
+
+impl ChromaticAberration {
+    pub fn new(amount: Float) -> Self {
+        ChromaticAberration { amount: amount }
+    }
+}
+
+impl PostEffect for ChromaticAberration {
+    fn apply(&self, image: &mut HdrImage, _: &Scene) {
+        let source = image.clone();
+        let center_x = image.width() as Float / 2.;
+        let center_y = image.height() as Float / 2.;
+        let max_dist_squared = center_x * center_x + center_y * center_y;
+
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                let dx = x as Float + 0.5 - center_x;
+                let dy = y as Float + 0.5 - center_y;
+                // The offset grows quadratically with the distance from the center
+                let offset = self.amount * (dx * dx + dy * dy) / max_dist_squared;
+                let (out, inward) = (1. + offset, 1. - offset);
+                let red = source.sample(center_x + dx * out, center_y + dy * out);
+                let blue = source.sample(center_x + dx * inward, center_y + dy * inward);
+                let green = source.get_pixel(x, y);
+                image.put_pixel(x, y, Vec3::new(red.x, green.y, blue.z));
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "chromatic aberration"
+    }
+}
+
+// Adds monochrome noise of up to `amount` (where 1 is white), strongest in the midtones like
+// film grain. Noise is a hash of the pixel position and `seed`, so renders are reproducible. As
+// it's meant for the displayed image this should be the last effect, and the noise is added to
+// the colors encoded by `transform`, the output transform they'll be written with. The image
+// keeps linear colors, moved by however much gets them to the grainy encoded ones
+pub struct FilmGrain {
+    amount: Float,
+    seed: u32,
+    transform: OutputTransform,
+}
+
+impl FilmGrain {
+    pub fn new(amount: Float, seed: u32, transform: OutputTransform) -> Self {
+        FilmGrain { amount: amount, seed: seed, transform: transform }
+    }
+}
+
+impl PostEffect for FilmGrain {
+    fn apply(&self, image: &mut HdrImage, _: &Scene) {
+        let width = image.width();
+        for (i, pixel) in image.pixels_mut().iter_mut().enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            let encoded = self.transform.apply(*pixel);
+            let lum = clamp(luminance(&encoded), 0., 1.);
+            let strength = self.amount * 4. * lum * (1. - lum);
+            let noise = (sampling::uniform(x, y, self.seed) * 2. - 1.) * strength;
+            let grainy = self.transform.invert(encoded + Vec3::new(noise, noise, noise));
+            // Rather than the inverse itself, so highlights past white stay as bright
+            *pixel = *pixel + grainy - self.transform.invert(encoded);
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "film grain"
+    }
+}
+
+// Streaks of ghosts along the line from each light in the frame through the image center, plus a
+// halo around the light itself, like internal reflections in a camera lens. Lights hidden behind
+// objects don't flare. Lights are placed by the scene camera, not by either eye of a stereo render
+pub struct LensFlare {
+    strength: Float,
+}
+
+impl LensFlare {
+    pub fn new(strength: Float) -> Self {
+        LensFlare { strength: strength }
+    }
+}
+
+// Position along the light to center line (1 is the light, -1 the opposite point), radius as a
+// fraction of the image height, brightness and tint of each ghost
+const GHOSTS: [(Float, Float, Float, (Float, Float, Float)); 6] = [
+    (1., 0.08, 0.6, (1., 0.95, 0.85)),
+    (0.4, 0.03, 0.15, (1., 0.6, 0.3)),
+    (-0.2, 0.05, 0.1, (0.4, 1., 0.5)),
+    (-0.5, 0.08, 0.06, (0.4, 0.6, 1.)),
+    (-0.8, 0.02, 0.2, (1., 0.5, 0.8)),
+    (-1.2, 0.12, 0.05, (0.6, 0.8, 1.)),
+];
+
+impl PostEffect for LensFlare {
+    fn apply(&self, image: &mut HdrImage, scene: &Scene) {
+        let (width, height) = (image.width() as Float, image.height() as Float);
+        let center = (width / 2., height / 2.);
+
+        for light in scene.lights.iter() {
+            // Directional lights are projected from a point one unit towards them
+            let (target, dist) = match light.shape() {
+                LightShape::Directional(dir) => (scene.camera.pos - dir, float::INFINITY),
+                _ => (*light.pos(), (*light.pos() - scene.camera.pos).norm()),
+            };
+            let (x, y) = match scene.camera.project(&target, width / height) {
+                Some((x, y)) if x >= 0. && x <= 1. && y >= 0. && y <= 1. => {
+                    (x * width, y * height)
+                }
+                _ => continue,
+            };
+            let ray = Ray::new(scene.camera.pos, target - scene.camera.pos);
+            let attenuation = light.attenuation(&ray.dir, dist);
+            if attenuation == 0. {
+                continue;
+            }
+            if let Some((_, hit)) = scene.intersect(&ray) {
+                if hit.dist < dist {
+                    continue;
+                }
+            }
+            trace!("flare at {:.0},{:.0}", x, y);
+
+            let color = *light.color() * light.intensity() * attenuation * self.strength;
+            for &(t, radius, brightness, (r, g, b)) in GHOSTS.iter() {
+                let ghost_x = center.0 + (x - center.0) * t;
+                let ghost_y = center.1 + (y - center.1) * t;
+                let tint = Vec3::new(color.x * r, color.y * g, color.z * b) * brightness;
+                add_disc(image, ghost_x, ghost_y, radius * height, tint);
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "lens flare"
+    }
+}
+
+// Adds a disc that fades out smoothly towards its edge
+fn add_disc(image: &mut HdrImage, x: Float, y: Float, radius: Float, color: Vec3) {
+    let min_x = clamp(x - radius, 0., image.width() as Float) as u32;
+    let max_x = clamp(x + radius + 1., 0., image.width() as Float) as u32;
+    let min_y = clamp(y - radius, 0., image.height() as Float) as u32;
+    let max_y = clamp(y + radius + 1., 0., image.height() as Float) as u32;
+    for py in min_y..max_y {
+        for px in min_x..max_x {
+            let dx = px as Float + 0.5 - x;
+            let dy = py as Float + 0.5 - y;
+            let t = 1. - (dx * dx + dy * dy).sqrt() / radius;
+            if t > 0. {
+                let pixel = image.get_pixel(px, py);
+                image.put_pixel(px, py, pixel + color * (t * t));
+            }
+        }
+    }
+}
+
+// Separable gaussian blur with standard deviation `sigma` pixels, clamping at the edges
+pub fn blur(image: &HdrImage, sigma: Float) -> HdrImage {
+    let radius = (sigma * 3.).ceil() as i32;
+    let mut kernel: Vec<Float> = (-radius..radius + 1)
+        .map(|i| (-(i * i) as Float / (2. * sigma * sigma)).exp())
+        .collect();
+    let sum: Float = kernel.iter().sum();
+    for weight in kernel.iter_mut() {
+        *weight /= sum;
+    }
+
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    let mut horizontal = HdrImage::new(image.width(), image.height());
+    for y in 0..height {
+        for x in 0..width {
+            let mut color = Vec3::new(0., 0., 0.);
+            for (i, weight) in kernel.iter().enumerate() {
+                let sx = clamp(x + i as i32 - radius, 0, width - 1);
+                color = color + image.get_pixel(sx as u32, y as u32) * *weight;
+            }
+            horizontal.put_pixel(x as u32, y as u32, color);
+        }
+    }
+
+    let mut result = HdrImage::new(image.width(), image.height());
+    for y in 0..height {
+        for x in 0..width {
+            let mut color = Vec3::new(0., 0., 0.);
+            for (i, weight) in kernel.iter().enumerate() {
+                let sy = clamp(y + i as i32 - radius, 0, height - 1);
+                color = color + horizontal.get_pixel(x as u32, sy as u32) * *weight;
+            }
+            result.put_pixel(x as u32, y as u32, color);
+        }
+    }
+    result
+}
+
+// Rec. 709 luma weights
+pub fn luminance(color: &Vec3) -> Float {
+    dot(color, &Vec3::new(0.2126, 0.7152, 0.0722))
+}
@@ -0,0 +1,56 @@
+// `--preview file.png` keeps writing the image so far to a file while rendering, so a long render
+// can be watched in an image viewer that reloads changed files, and stopped early if it looks
+// wrong. With adaptive_samples the whole image appears after the first pass and then refines
+// with every pass. The file is replaced in one step, so viewers never see it half written.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tracerlib::{RenderEvent, Scene};
+use tracerlib::hdr::HdrImage;
+
+use super::{encode, save_image, Config};
+
+// Encoding the whole image after every tile would slow small tiles down
+const WRITE_INTERVAL_MILLIS: u64 = 1000;
+
+pub struct Preview {
+    file: Option<String>,
+    last_write: Option<Instant>,
+}
+
+impl Preview {
+    // Does nothing without a file
+    pub fn new(file: Option<String>) -> Self {
+        Preview { file: file, last_write: None }
+    }
+
+    pub fn event(&mut self, config: &Config, scene: &Scene, event: &RenderEvent) {
+        match *event {
+            RenderEvent::TileFinished { image, .. } => {
+                let due = self.last_write.map_or(true, |last| {
+                    last.elapsed() >= Duration::from_millis(WRITE_INTERVAL_MILLIS)
+                });
+                if due {
+                    self.write(config, scene, image);
+                }
+            }
+            RenderEvent::PassFinished { image } => self.write(config, scene, image),
+            RenderEvent::TileStarted { .. } => {}
+        }
+    }
+
+    fn write(&mut self, config: &Config, scene: &Scene, image: &HdrImage) {
+        let file = match self.file {
+            Some(ref file) => Path::new(file),
+            None => return,
+        };
+        // Hidden next to the preview, with the same extension for the image format
+        let name = file.file_name().unwrap().to_str().unwrap();
+        let temp = file.with_file_name(format!(".{}", name));
+        save_image(config, &encode(config, scene, image), temp.to_str().unwrap());
+        fs::rename(&temp, file).unwrap();
+        self.last_write = Some(Instant::now());
+    }
+}
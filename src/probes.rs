@@ -0,0 +1,41 @@
+// Irradiance probes for feeding global illumination to game engines. The light arriving at each
+// point of a regular grid is traced in many directions and stored as spherical harmonics.
+
+use {trace_ray, Float, Scene, Vec3};
+use bounds::Aabb;
+use log::{self, Level};
+use ray::{Ray, RayKind};
+use sh::Sh9;
+
+pub struct Probe {
+    pub pos: Vec3,
+    pub sh: Sh9,
+}
+
+// Bakes a counts.0 x counts.1 x counts.2 grid of probes spanning `bounds`, each from `samples`
+// rays. Probes are ordered by x, then y, then z
+pub fn bake_probes(scene: &Scene, bounds: &Aabb, counts: (u32, u32, u32), samples: u32,
+                   max_depth: u16) -> Vec<Probe> {
+    let _span = log::span(Level::Info, format!("probes {}x{}x{}", counts.0, counts.1, counts.2));
+    let step = |n: u32, size: Float| if n > 1 { size / (n - 1) as Float } else { 0. };
+    let size = bounds.size();
+    let (step_x, step_y, step_z) = (step(counts.0, size.x), step(counts.1, size.y),
+                                    step(counts.2, size.z));
+
+    let mut probes = Vec::new();
+    for z in 0..counts.2 {
+        for y in 0..counts.1 {
+            for x in 0..counts.0 {
+                let pos = bounds.min +
+                          Vec3::new(x as Float * step_x, y as Float * step_y, z as Float * step_z);
+                let sh = Sh9::project(samples, |dir| {
+                    let ray = Ray::new(pos, dir).with_kind(RayKind::Reflection);
+                    trace_ray(scene, &ray, 0, 0, max_depth)
+                });
+                probes.push(Probe { pos: pos, sh: sh });
+            }
+        }
+        debug!("{} of {} probe layers", z + 1, counts.2);
+    }
+    probes
+}
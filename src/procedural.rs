@@ -0,0 +1,186 @@
+// Procedural patterns from noise, for textures and bumps that need no image files. A pattern is
+// a value from 0 to 1 at every point of space. As a texture it's looked up at the texture
+// coordinates, as (u, 0, v), and blends between two colors; as a bump it's looked up at the hit
+// position and tilts the normal up its slope.
+
+use std::str::FromStr;
+
+use {float, Float, Vec3};
+use texture::Texture;
+
+use nalgebra::{dot, Norm};
+
+use noise::{self, Seed};
+
+// The noise patterns are built from, from -1 to 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Basis {
+    Perlin,
+    Simplex,
+    // The distance to the nearest of randomly scattered points, for cells like stone or scales
+    Worley,
+}
+
+impl FromStr for Basis {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "perlin" => Ok(Basis::Perlin),
+            "simplex" => Ok(Basis::Simplex),
+            "worley" => Ok(Basis::Worley),
+            _ => Err(format!("Unknown noise: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+    // Octaves of noise, each twice as fine and half as strong as the one before
+    Fbm,
+    // Octaves of the absolute value of noise, billowy like smoke and clouds
+    Turbulence,
+    // Stripes across x, bent by turbulence
+    Marble,
+    // Rings around the y axis, bent by noise
+    Wood,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "fbm" => Ok(Pattern::Fbm),
+            "turbulence" => Ok(Pattern::Turbulence),
+            "marble" => Ok(Pattern::Marble),
+            "wood" => Ok(Pattern::Wood),
+            _ => Err(format!("Unknown pattern: {}", s)),
+        }
+    }
+}
+
+pub struct Procedural {
+    basis: Basis,
+    pattern: Pattern,
+    seed: Seed,
+    seed_val: u32,
+    octaves: u32,
+    // Features per unit of distance
+    scale: Float,
+}
+
+impl Clone for Procedural {
+    fn clone(&self) -> Self {
+        Procedural::new(self.basis, self.pattern, self.seed_val, self.octaves, self.scale)
+    }
+}
+
+impl Procedural {
+    pub fn new(basis: Basis, pattern: Pattern, seed_val: u32, octaves: u32, scale: Float) -> Self {
+        assert!(octaves > 0, "Procedural patterns need at least one octave");
+        assert!(scale > 0., "Procedural pattern scale must be positive");
+        Procedural { basis: basis, pattern: pattern, seed: Seed::new(seed_val),
+                     seed_val: seed_val, octaves: octaves, scale: scale }
+    }
+
+    pub fn scale(&self) -> Float {
+        self.scale
+    }
+
+    // The pattern at `pos`, from 0 to 1
+    pub fn value(&self, pos: &Vec3) -> Float {
+        let p = *pos * self.scale;
+        let value = match self.pattern {
+            Pattern::Fbm => 0.5 + 0.5 * self.layered(&p, |n| n),
+            Pattern::Turbulence => self.layered(&p, |n: Float| n.abs()),
+            Pattern::Marble => {
+                let turbulence = self.layered(&p, |n: Float| n.abs());
+                0.5 + 0.5 * (float::consts::PI * (p.x + 4. * turbulence)).sin()
+            }
+            Pattern::Wood => {
+                let rings = (p.x * p.x + p.z * p.z).sqrt() + 0.5 * self.layered(&p, |n| n);
+                rings - rings.floor()
+            }
+        };
+        value.max(0.).min(1.)
+    }
+
+    // The average of `shape` over the octaves of noise at `p`, weighted by their strength
+    fn layered<F>(&self, p: &Vec3, shape: F) -> Float
+        where F: Fn(Float) -> Float
+    {
+        let (mut sum, mut total, mut amplitude, mut frequency) = (0., 0., 1., 1.);
+        for _ in 0..self.octaves {
+            sum += shape(self.noise_at(&(*p * frequency))) * amplitude;
+            total += amplitude;
+            amplitude *= 0.5;
+            frequency *= 2.;
+        }
+        sum / total
+    }
+
+    fn noise_at(&self, p: &Vec3) -> Float {
+        let point = [p.x, p.y, p.z];
+        match self.basis {
+            Basis::Perlin => noise::perlin3(&self.seed, &point),
+            Basis::Simplex => noise::open_simplex3(&self.seed, &point),
+            // cell3_range is the squared distance, up to about 1 between points a cell apart
+            Basis::Worley => {
+                noise::cell3_range(&self.seed, &point).sqrt().min(1.) * 2. - 1.
+            }
+        }
+    }
+}
+
+// A texture blending from `low` where the pattern is 0 to `high` where it's 1
+#[derive(Clone)]
+pub struct ProceduralTexture {
+    pattern: Procedural,
+    low: Vec3,
+    high: Vec3,
+}
+
+impl ProceduralTexture {
+    pub fn new(pattern: Procedural, low: Vec3, high: Vec3) -> Self {
+        ProceduralTexture { pattern: pattern, low: low, high: high }
+    }
+}
+
+impl Texture for ProceduralTexture {
+    fn color(&self, u: Float, v: Float) -> Vec3 {
+        let t = self.pattern.value(&Vec3::new(u, 0., v));
+        self.low * (1. - t) + self.high * t
+    }
+
+    fn clone_(&self) -> Box<Texture> {
+        Box::new(self.clone())
+    }
+}
+
+// Tilts normals as if the surface were raised by the pattern, `strength` units where it's 1
+#[derive(Clone)]
+pub struct Bump {
+    pattern: Procedural,
+    strength: Float,
+}
+
+impl Bump {
+    pub fn new(pattern: Procedural, strength: Float) -> Self {
+        Bump { pattern: pattern, strength: strength }
+    }
+
+    pub fn apply(&self, normal: &Vec3, pos: &Vec3) -> Vec3 {
+        // Central differences a small fraction of a feature apart
+        let h = 1e-2 / self.pattern.scale();
+        let slope = |axis: Vec3| {
+            (self.pattern.value(&(*pos + axis * h)) - self.pattern.value(&(*pos - axis * h))) /
+            (2. * h)
+        };
+        let gradient = Vec3::new(slope(Vec3::new(1., 0., 0.)), slope(Vec3::new(0., 1., 0.)),
+                                 slope(Vec3::new(0., 0., 1.)));
+        // Only the part of the slope along the surface tilts it
+        let along = gradient - *normal * dot(&gradient, normal);
+        (*normal - along * self.strength).normalize()
+    }
+}
@@ -0,0 +1,73 @@
+// A progress bar with the time remaining, drawn on stderr while rendering. It's only drawn when
+// stderr is a terminal and info messages are logged, so it stays out of log files and -q runs.
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use tracerlib::log::{self, Level};
+
+const WIDTH: usize = 40;
+// Redrawing for every tile would slow down renders of small tiles
+const REDRAW_MILLIS: u64 = 100;
+
+pub struct ProgressBar {
+    start: Instant,
+    last_draw: Option<Instant>,
+    enabled: bool,
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        let enabled = unsafe { libc::isatty(2) } != 0 && log::enabled(Level::Info);
+        ProgressBar { start: Instant::now(), last_draw: None, enabled: enabled }
+    }
+
+    // Call with the work done so far and the total, e.g. tiles. The bar is cleared when it's done
+    pub fn update(&mut self, done: u32, total: u32) {
+        if !self.enabled || total == 0 {
+            return;
+        }
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        if done >= total {
+            let _ = write!(stderr, "\r\x1b[K");
+            return;
+        }
+        let now = Instant::now();
+        if let Some(last) = self.last_draw {
+            if now.duration_since(last) < Duration::from_millis(REDRAW_MILLIS) {
+                return;
+            }
+        }
+        self.last_draw = Some(now);
+
+        let fraction = done as f64 / total as f64;
+        let filled = (fraction * WIDTH as f64) as usize;
+        let elapsed = seconds(now.duration_since(self.start));
+        let remaining = if done > 0 {
+            format_time(elapsed * (1. - fraction) / fraction)
+        } else {
+            "?".to_owned()
+        };
+        let _ = write!(stderr, "\r[{}{}] {:3.0}% {} elapsed, {} left\x1b[K", "#".repeat(filled),
+                       ".".repeat(WIDTH - filled), fraction * 100., format_time(elapsed),
+                       remaining);
+        let _ = stderr.flush();
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+// As m:ss, or h:mm:ss for an hour or more
+fn format_time(seconds: f64) -> String {
+    let s = seconds.round() as u64;
+    if s >= 3600 {
+        format!("{}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+    } else {
+        format!("{}:{:02}", s / 60, s % 60)
+    }
+}
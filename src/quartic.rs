@@ -0,0 +1,126 @@
+// Real roots of polynomials up to degree four, for surfaces like the torus whose intersections
+// need them. The closed forms lose precision when roots are close together or far apart, so the
+// roots are computed in f64 and polished with Newton's method on the original polynomial.
+
+use std::cmp::Ordering;
+use std::f64;
+
+// Newton steps per root; the closed forms are close enough that it converges in a few
+const POLISH_STEPS: u32 = 4;
+
+// The real roots of a*x^2 + b*x + c, in increasing order
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if a == 0. {
+        return if b == 0. { vec![] } else { vec![-c / b] };
+    }
+    let discriminant = b * b - 4. * a * c;
+    if discriminant < 0. {
+        return vec![];
+    }
+    // Avoids subtracting two nearly equal values, as for spheres
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let mut roots = if q == 0. { vec![0., 0.] } else { vec![q / a, c / q] };
+    sort(&mut roots);
+    roots
+}
+
+// The real roots of a*x^3 + b*x^2 + c*x + d, in increasing order
+pub fn solve_cubic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    if a == 0. {
+        return solve_quadratic(b, c, d);
+    }
+    let (b, c, d) = (b / a, c / a, d / a);
+    // x = y - b/3 gives y^3 + p*y + q
+    let shift = b / 3.;
+    let p = c - b * shift;
+    let q = d - c * shift + 2. * shift * shift * shift;
+    let mut roots = if p == 0. {
+        vec![-q.cbrt()]
+    } else {
+        let discriminant = q * q / 4. + p * p * p / 27.;
+        if discriminant > 0. {
+            let root = discriminant.sqrt();
+            vec![(-q / 2. + root).cbrt() + (-q / 2. - root).cbrt()]
+        } else {
+            // Three real roots, from the trigonometric form
+            let m = 2. * (-p / 3.).sqrt();
+            let angle = (3. * q / (p * m)).max(-1.).min(1.).acos() / 3.;
+            (0..3).map(|k| m * (angle - 2. * f64::consts::PI * k as f64 / 3.).cos()).collect()
+        }
+    };
+    for root in roots.iter_mut() {
+        *root = polish(&[1., b, c, d], *root - shift);
+    }
+    sort(&mut roots);
+    roots
+}
+
+// The real roots of a*x^4 + b*x^3 + c*x^2 + d*x + e, in increasing order. Double roots may be
+// missed or given twice
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    if a == 0. {
+        return solve_cubic(b, c, d, e);
+    }
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+    // x = y - b/4 gives y^4 + p*y^2 + q*y + r
+    let shift = b / 4.;
+    let p = c - 6. * shift * shift;
+    let q = d - 2. * c * shift + 8. * shift * shift * shift;
+    let r = e - d * shift + c * shift * shift - 3. * shift * shift * shift * shift;
+
+    let mut roots = Vec::new();
+    // Ferrari's method: add 2m*y^2 + m^2 + m*p to both sides so that the right one is a square,
+    // for the largest root m of the resolvent cubic
+    let m = solve_cubic(8., 8. * p, 2. * p * p - 8. * r, -q * q).last().cloned().unwrap_or(0.);
+    // m is only 0 if q is, and dividing by a tiny m loses everything
+    if m <= f64::EPSILON * (1. + p.abs()) {
+        // A quadratic in y^2
+        for z in solve_quadratic(1., p, r) {
+            if z >= 0. {
+                roots.push(z.sqrt());
+                roots.push(-z.sqrt());
+            }
+        }
+    } else {
+        let s = (2. * m).sqrt();
+        roots.extend(solve_quadratic(1., -s, p / 2. + m + q / (2. * s)));
+        roots.extend(solve_quadratic(1., s, p / 2. + m - q / (2. * s)));
+    }
+    for root in roots.iter_mut() {
+        *root = polish(&[1., b, c, d, e], *root - shift);
+    }
+    sort(&mut roots);
+    roots
+}
+
+// Newton's method from `x` on the polynomial with `coeffs`, highest power first. Stops where a
+// step doesn't help, as near double roots where the slope vanishes
+fn polish(coeffs: &[f64], mut x: f64) -> f64 {
+    let eval = |x: f64| {
+        let (mut value, mut slope) = (0., 0.);
+        for &coeff in coeffs {
+            slope = slope * x + value;
+            value = value * x + coeff;
+        }
+        (value, slope)
+    };
+    let (mut value, mut slope) = eval(x);
+    for _ in 0..POLISH_STEPS {
+        if value == 0. || slope == 0. {
+            break;
+        }
+        let next = x - value / slope;
+        let (next_value, next_slope) = eval(next);
+        if !(next_value.abs() < value.abs()) {
+            break;
+        }
+        x = next;
+        value = next_value;
+        slope = next_slope;
+    }
+    x
+}
+
+fn sort(roots: &mut Vec<f64>) {
+    roots.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+}
@@ -1,30 +1,292 @@
-use Vec3;
 
-use nalgebra::Norm;
+use {float, Float, Vec3};
 
-#[derive(Debug)]
+use nalgebra::{dot, Norm};
+
+#[derive(Clone, Debug)]
 pub struct Ray {
     pub origin: Vec3,
     pub dir: Vec3,
+    // Only hits between these distances along the ray count, e.g. for the camera's clipping
+    // planes. Surfaces ignore this; it's applied by the scene
+    pub near: Float,
+    pub far: Float,
+    // When the ray was sent, from 0 at the start of the frame to 1 at its end. Moving surfaces
+    // are where they are at this time
+    pub time: Float,
+    // Surfaces can be hidden from some kinds of rays, see Material::with_visibility
+    pub kind: RayKind,
+    // For filtering the textures it hits, see Differentials. None for rays that don't need
+    // them, e.g. shadow rays and diffuse bounces
+    pub differentials: Option<Differentials>,
+    // The single wavelength the ray carries in spectral mode, in nanometers, see spectral
+    pub wavelength: Option<Float>,
+}
+
+// What a ray is traced for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RayKind {
+    // From the camera, and for picking and line of sight checks
+    Camera,
+    // Reflected or refracted by a surface, or bounced by the path tracer
+    Reflection,
+    // Towards a light or the environment, or looking for occluders
+    Shadow,
 }
 
 impl Ray {
     pub fn new(origin: Vec3, dir: Vec3) -> Self {
-        Ray { origin: origin, dir: dir.normalize() }
+        Ray { origin: origin, dir: dir.normalize(), near: 0., far: float::INFINITY, time: 0.,
+              kind: RayKind::Camera, differentials: None, wavelength: None }
+    }
+
+    pub fn with_kind(mut self, kind: RayKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn with_time(mut self, time: Float) -> Self {
+        self.time = time;
+        self
+    }
+
+    pub fn with_extent(mut self, near: Float, far: Float) -> Self {
+        self.near = near;
+        self.far = far;
+        self
+    }
+
+    pub fn with_differentials(mut self, differentials: Option<Differentials>) -> Self {
+        self.differentials = differentials;
+        self
+    }
+
+    pub fn with_wavelength(mut self, wavelength: Option<Float>) -> Self {
+        self.wavelength = wavelength;
+        self
     }
 }
 
+// How a ray's origin and unit direction change towards the rays of the next pixel across and
+// the next one down the image, after Igehy's ray differentials. They tell how wide the patch of
+// a surface is that one pixel sees, so textures can be filtered over it rather than sampled at a
+// point, which shimmers wherever a pixel covers many texels, e.g. on a floor at grazing angles.
+// They follow mirror reflections and refractions, taking the surface as flat at each hit, so
+// curved mirrors and lenses magnify or shrink the footprint a little less than they should
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Differentials {
+    pub origin_dx: Vec3,
+    pub origin_dy: Vec3,
+    pub dir_dx: Vec3,
+    pub dir_dy: Vec3,
+}
+
+impl Differentials {
+    // How far from `hit` the neighbouring rays meet the plane touching the surface there, or
+    // None if `ray` grazes it
+    fn offsets(&self, ray: &Ray, hit: &Intersection) -> Option<(Vec3, Vec3)> {
+        let n = &hit.normal;
+        let cos = dot(&ray.dir, n);
+        if cos.abs() < 1e-6 {
+            return None;
+        }
+        let offset = |origin: &Vec3, dir: &Vec3| {
+            let p = *origin + *dir * hit.dist;
+            p - ray.dir * (dot(&p, n) / cos)
+        };
+        Some((offset(&self.origin_dx, &self.dir_dx), offset(&self.origin_dy, &self.dir_dy)))
+    }
+
+    // How wide a square in uv the pixel of `ray` covers at `hit`, or 0 if the surface doesn't
+    // know how its texture coordinates change
+    pub fn footprint(&self, ray: &Ray, hit: &Intersection) -> Float {
+        let (dpdu, dpdv) = match hit.uv_derivatives {
+            Some(derivatives) => derivatives,
+            None => return 0.,
+        };
+        let (dx, dy) = match self.offsets(ray, hit) {
+            Some(offsets) => offsets,
+            None => return 0.,
+        };
+        // The du, dv whose step along the surface comes closest to each offset
+        let (a, b, c) = (dpdu.norm_squared(), dot(&dpdu, &dpdv), dpdv.norm_squared());
+        let det = a * c - b * b;
+        if det.abs() < 1e-20 {
+            return 0.;
+        }
+        let uv_length = |offset: &Vec3| {
+            let (p, q) = (dot(&dpdu, offset), dot(&dpdv, offset));
+            let (du, dv) = ((c * p - b * q) / det, (a * q - b * p) / det);
+            (du * du + dv * dv).sqrt()
+        };
+        uv_length(&dx).max(uv_length(&dy))
+    }
+
+    // How wide the pixel of `ray` is along the surface at `hit`, or infinite if `ray` grazes it
+    pub fn width(&self, ray: &Ray, hit: &Intersection) -> Float {
+        self.offsets(ray, hit).map_or(float::INFINITY, |(dx, dy)| dx.norm().max(dy.norm()))
+    }
+
+    // Those of `ray` mirrored about `normal` at `hit`
+    pub fn reflected(&self, ray: &Ray, hit: &Intersection, normal: &Vec3) -> Option<Self> {
+        self.offsets(ray, hit).map(|(dx, dy)| {
+            let mirror = |dir: &Vec3| reflect(dir, normal);
+            Differentials {
+                origin_dx: dx,
+                origin_dy: dy,
+                dir_dx: mirror(&self.dir_dx),
+                dir_dy: mirror(&self.dir_dy),
+            }
+        })
+    }
+
+    // Those of `ray` bent into `refracted` by refract with `normal` and `eta`
+    pub fn refracted(&self, ray: &Ray, hit: &Intersection, normal: &Vec3, eta: Float,
+                     refracted: &Vec3)
+                     -> Option<Self> {
+        let (cos_in, cos_out) = (dot(&ray.dir, normal), dot(refracted, normal));
+        if cos_out.abs() < 1e-6 {
+            return None;
+        }
+        self.offsets(ray, hit).map(|(dx, dy)| {
+            // refracted is eta * dir - mu * normal, with mu changing along with the angle
+            let bend = |dir: &Vec3| {
+                let dmu = (eta - eta * eta * cos_in / cos_out) * dot(dir, normal);
+                *dir * eta - *normal * dmu
+            };
+            Differentials {
+                origin_dx: dx,
+                origin_dy: dy,
+                dir_dx: bend(&self.dir_dx),
+                dir_dy: bend(&self.dir_dy),
+            }
+        })
+    }
+}
+
+// Mirrors `dir` about `normal`, which must be unit length
+pub fn reflect(dir: &Vec3, normal: &Vec3) -> Vec3 {
+    *dir - *normal * 2. * dot(dir, normal)
+}
+
+// Bends `dir` through a surface with `normal` facing against it by Snell's law, where `eta` is
+// the ratio of the refractive indices on the incoming and outgoing side. None for total internal
+// reflection
+pub fn refract(dir: &Vec3, normal: &Vec3, eta: Float) -> Option<Vec3> {
+    let cos_i = -dot(dir, normal);
+    let sin2_t = eta * eta * (1. - cos_i * cos_i);
+    if sin2_t > 1. {
+        return None;
+    }
+    Some(*dir * eta + *normal * (eta * cos_i - (1. - sin2_t).sqrt()))
+}
+
+// Schlick's approximation of the Fresnel equations: the fraction of the light reflected at an
+// angle with cosine `cos` to the normal, where `r0` is the fraction reflected head on
+pub fn schlick(r0: Float, cos: Float) -> Float {
+    r0 + (1. - r0) * (1. - cos).powi(5)
+}
+
 #[derive(Clone, Debug)]
 pub struct Intersection {
     pub pos: Vec3,
     pub normal: Vec3,
-    pub dist: f32,
-    pub u: f32,
-    pub v: f32,
+    pub dist: Float,
+    pub u: Float,
+    pub v: Float,
+    // The time of the ray that found the hit, for the rays leaving it. Set by the scene
+    pub time: Float,
+    // Which of the surface's materials is at the hit, for meshes with several, see
+    // Surface::material_at. 0 for all other surfaces
+    pub material: usize,
+    // The unit direction u grows in along the surface, which anisotropic materials stretch their
+    // highlight by, for surfaces that know it
+    pub tangent: Option<Vec3>,
+    // How the position changes with u and with v, for surfaces that know it
+    pub uv_derivatives: Option<(Vec3, Vec3)>,
+    // How wide a square in uv the pixel covers here, which textures are filtered over, or 0 to
+    // sample them at u, v. Set by the scene from the ray's differentials
+    pub footprint: Float,
+    // From the hit to the closest point on the edges of the triangle it's on, for meshes, which
+    // the wireframe debug mode draws
+    pub edge: Option<Vec3>,
+    // How far rays leaving the front of a smooth shaded mesh start off its flat triangle, towards
+    // the curved surface its normals describe, see surface::terminator_offset
+    pub terminator: Option<Vec3>,
 }
 
 impl Intersection {
-    pub fn new(pos: Vec3, normal: Vec3, dist: f32, u: f32, v: f32) -> Self {
-        Intersection { pos: pos, normal: normal, dist: dist, u: u, v: v }
+    pub fn new(pos: Vec3, normal: Vec3, dist: Float, u: Float, v: Float) -> Self {
+        Intersection { pos: pos, normal: normal, dist: dist, u: u, v: v, time: 0., material: 0,
+                       tangent: None, uv_derivatives: None, footprint: 0., edge: None,
+                       terminator: None }
+    }
+
+    // Normalizes `tangent`, and leaves it out if it's 0, e.g. at the poles of a sphere
+    pub fn with_tangent(mut self, tangent: Vec3) -> Self {
+        let length = tangent.norm();
+        self.tangent = if length > 0. { Some(tangent / length) } else { None };
+        self
+    }
+
+    // Also takes `dpdu` as the tangent
+    pub fn with_uv_derivatives(self, dpdu: Vec3, dpdv: Vec3) -> Self {
+        Intersection { uv_derivatives: Some((dpdu, dpdv)), ..self.with_tangent(dpdu) }
     }
 }
+
+// How far rays leaving a surface start off it, so that rounding errors in the hit position don't
+// make them hit the same surface again and speckle it with shadow acne. Those errors grow with
+// the size of the hit's coordinates and with the distance it was found at, so a fixed offset
+// that suits a small scene is too small for a large one, while one large enough for everything
+// makes small objects' shadows come loose from them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bias {
+    // At least this far
+    pub min: Float,
+    // And this fraction of the largest coordinate of the hit and its distance along the ray
+    pub relative: Float,
+}
+
+impl Bias {
+    pub fn new(min: Float, relative: Float) -> Self {
+        assert!(min >= 0. && relative >= 0., "Ray bias can't be negative");
+        Bias { min: min, relative: relative }
+    }
+
+    pub fn offset(&self, hit: &Intersection) -> Float {
+        let (pos, dist) = (&hit.pos, hit.dist.abs());
+        let magnitude = pos.x.abs().max(pos.y.abs()).max(pos.z.abs()).max(dist);
+        self.min.max(self.relative * magnitude)
+    }
+
+    // Where a ray leaving `hit` on the side `normal` points to starts, lifted off the triangle of
+    // a smooth shaded mesh on its front
+    pub fn origin(&self, hit: &Intersection, normal: &Vec3) -> Vec3 {
+        let pos = match hit.terminator {
+            Some(terminator) if dot(normal, &hit.normal) > 0. => hit.pos + terminator,
+            _ => hit.pos,
+        };
+        pos + *normal * self.offset(hit)
+    }
+}
+
+impl Default for Bias {
+    // Small enough not to be seen in scenes a few hundred units across, and past that growing
+    // about a hundred times faster than f32 rounding errors do
+    fn default() -> Self {
+        Bias::new(float::EPSILON.sqrt(), 1e-5)
+    }
+}
+
+// A hit returned by Scene::raycast. `object` is the index of the surface in the scene, see
+// Scene::object
+#[derive(Clone, Debug)]
+pub struct Hit {
+    pub object: usize,
+    pub pos: Vec3,
+    pub normal: Vec3,
+    pub dist: Float,
+    pub u: Float,
+    pub v: Float,
+}
@@ -0,0 +1,197 @@
+// Where the samples of a set fall, e.g. the rays through one pixel or the shadow rays to an area
+// light from one point. Random numbers leave clumps and gaps that take many samples to average
+// out; stratified and low discrepancy samples cover 0..1 evenly, so the noise goes away sooner.
+//
+// A set is identified by a seed, and each of its samples has an `index` of `count`. A count of
+// 0 means the set has no fixed size, e.g. for progressive passes; stratified samples are then
+// random. Each kind of number drawn for a set (pixel position, lens position, ..) is a separate
+// `dim`, so they aren't correlated with each other.
+
+use Float;
+use sampling::{hash, uniform};
+
+pub trait Sampler: Send + Sync {
+    fn get_1d(&self, seed: u32, index: u32, count: u32, dim: u32) -> Float;
+    // Points in the unit square, e.g. for positions on a lens or directions in a hemisphere.
+    // Uses dims `dim` and `dim + 1`
+    fn get_2d(&self, seed: u32, index: u32, count: u32, dim: u32) -> (Float, Float);
+}
+
+// The sampler called `name` in scene files: random, stratified, halton or sobol
+pub fn by_name(name: &str) -> Option<Box<Sampler>> {
+    match name {
+        "random" => Some(Box::new(RandomSampler)),
+        "stratified" => Some(Box::new(StratifiedSampler)),
+        "halton" => Some(Box::new(HaltonSampler)),
+        "sobol" => Some(Box::new(SobolSampler)),
+        _ => None,
+    }
+}
+
+// Independent random numbers
+pub struct RandomSampler;
+
+impl Sampler for RandomSampler {
+    fn get_1d(&self, seed: u32, index: u32, _: u32, dim: u32) -> Float {
+        uniform(seed, index, dim)
+    }
+
+    fn get_2d(&self, seed: u32, index: u32, _: u32, dim: u32) -> (Float, Float) {
+        (uniform(seed, index, dim), uniform(seed, index, dim + 1))
+    }
+}
+
+// Splits 0..1 into `count` strata in 1D, or the unit square into a grid of about `count` cells
+// in 2D, and jitters one sample in each
+pub struct StratifiedSampler;
+
+impl Sampler for StratifiedSampler {
+    fn get_1d(&self, seed: u32, index: u32, count: u32, dim: u32) -> Float {
+        let jitter = uniform(seed, index, dim);
+        if count == 0 || index >= count {
+            return jitter;
+        }
+        let stratum = permute(index, count, hash(seed, dim, 1));
+        unit((stratum as Float + jitter) / count as Float)
+    }
+
+    fn get_2d(&self, seed: u32, index: u32, count: u32, dim: u32) -> (Float, Float) {
+        let jitter = (uniform(seed, index, dim), uniform(seed, index, dim + 1));
+        if count == 0 || index >= count {
+            return jitter;
+        }
+        // With spare cells when count isn't a product of the two sides, left empty at random
+        let columns = (count as Float).sqrt().ceil() as u32;
+        let rows = (count + columns - 1) / columns;
+        let cell = permute(index, columns * rows, hash(seed, dim, 1));
+        (unit(((cell % columns) as Float + jitter.0) / columns as Float),
+         unit(((cell / columns) as Float + jitter.1) / rows as Float))
+    }
+}
+
+// The Halton sequence in bases 2 and 3, shifted at random for each set
+pub struct HaltonSampler;
+
+impl Sampler for HaltonSampler {
+    fn get_1d(&self, seed: u32, index: u32, count: u32, dim: u32) -> Float {
+        let i = shuffled(seed, index, count, dim);
+        shift(radical_inverse(i, 2), uniform(seed, dim, 2))
+    }
+
+    fn get_2d(&self, seed: u32, index: u32, count: u32, dim: u32) -> (Float, Float) {
+        let i = shuffled(seed, index, count, dim);
+        (shift(radical_inverse(i, 2), uniform(seed, dim, 2)),
+         shift(radical_inverse(i, 3), uniform(seed, dim + 1, 2)))
+    }
+}
+
+// The first two dimensions of the Sobol sequence, with their binary digits scrambled at random
+// for each set. Any 2^k consecutive points of them have one point in each of 2^k equal
+// rectangles of the unit square, of any shape
+pub struct SobolSampler;
+
+impl Sampler for SobolSampler {
+    fn get_1d(&self, seed: u32, index: u32, count: u32, dim: u32) -> Float {
+        let i = shuffled(seed, index, count, dim);
+        to_unit(reverse_bits(i) ^ hash(seed, dim, 3))
+    }
+
+    fn get_2d(&self, seed: u32, index: u32, count: u32, dim: u32) -> (Float, Float) {
+        let i = shuffled(seed, index, count, dim);
+        (to_unit(reverse_bits(i) ^ hash(seed, dim, 3)),
+         to_unit(sobol_second(i) ^ hash(seed, dim + 1, 3)))
+    }
+}
+
+// The index of a sample in a fixed order for each dim, so that the dims of one sample aren't
+// the same point of the sequence. Sets of a known size keep their points, just reordered
+fn shuffled(seed: u32, index: u32, count: u32, dim: u32) -> u32 {
+    if count == 0 || index >= count {
+        index
+    } else {
+        permute(index, count, hash(seed, dim, 4))
+    }
+}
+
+// u + offset, wrapped to 0..1
+fn shift(u: Float, offset: Float) -> Float {
+    let u = u + offset;
+    unit(if u >= 1. { u - 1. } else { u })
+}
+
+// Keeps rounding from reaching 1
+fn unit(u: Float) -> Float {
+    u.min(1. - ::float::EPSILON / 2.)
+}
+
+fn to_unit(bits: u32) -> Float {
+    unit((bits >> 8) as Float / (1 << 24) as Float)
+}
+
+// The digits of `i` in `base` mirrored around the radix point
+fn radical_inverse(mut i: u32, base: u32) -> Float {
+    let (mut result, mut scale) = (0f64, 1. / base as f64);
+    while i > 0 {
+        result += (i % base) as f64 * scale;
+        i /= base;
+        scale /= base as f64;
+    }
+    unit(result as Float)
+}
+
+fn reverse_bits(mut x: u32) -> u32 {
+    x = (x << 16) | (x >> 16);
+    x = ((x & 0x00ff00ff) << 8) | ((x & 0xff00ff00) >> 8);
+    x = ((x & 0x0f0f0f0f) << 4) | ((x & 0xf0f0f0f0) >> 4);
+    x = ((x & 0x33333333) << 2) | ((x & 0xcccccccc) >> 2);
+    ((x & 0x55555555) << 1) | ((x & 0xaaaaaaaa) >> 1)
+}
+
+// The second Sobol dimension, whose direction numbers come from the polynomial x + 1
+fn sobol_second(mut i: u32) -> u32 {
+    let (mut v, mut result) = (1u32 << 31, 0);
+    while i != 0 {
+        if i & 1 != 0 {
+            result ^= v;
+        }
+        i >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// A random permutation of 0..len picked by `p`, from Kensler's "Correlated Multi-Jittered
+// Sampling". Needs i < len
+fn permute(mut i: u32, len: u32, p: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    // A permutation of 0..w + 1, repeated until it lands in 0..len
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < len {
+            break;
+        }
+    }
+    ((i as u64 + p as u64) % len as u64) as u32
+}
@@ -0,0 +1,97 @@
+// Deterministic pseudo random numbers, hashed from pixel coordinates so that renders are
+// reproducible and need no shared generator state
+
+use std::cmp;
+
+use nalgebra::{cross, Norm};
+
+use {float, Float, Vec3};
+
+pub fn hash(x: u32, y: u32, seed: u32) -> u32 {
+    let mut h = x.wrapping_mul(0x8da6b343) ^ y.wrapping_mul(0xd8163841) ^
+                seed.wrapping_mul(0xcb1ab31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^= h >> 16;
+    h
+}
+
+// The bits of `x` for hashing, with the two halves of a double folded together
+#[cfg(not(feature = "f64"))]
+pub fn bits(x: Float) -> u32 {
+    x.to_bits()
+}
+
+#[cfg(feature = "f64")]
+pub fn bits(x: Float) -> u32 {
+    let bits = x.to_bits();
+    bits as u32 ^ (bits >> 32) as u32
+}
+
+// Mixes the seed of a render into a coordinate, so that renders with different seeds get
+// different random numbers while seed 0 leaves it unchanged
+pub fn reseed(x: u32, seed: u32) -> u32 {
+    x ^ seed.wrapping_mul(0x9e3779b9)
+}
+
+// Uniform in 0..1
+pub fn uniform(x: u32, y: u32, seed: u32) -> Float {
+    hash(x, y, seed) as Float / u32::max_value() as Float
+}
+
+// Normalized running sum, empty if all weights are 0
+pub fn cdf(weights: &[Float]) -> Vec<Float> {
+    let total: Float = weights.iter().sum();
+    if total <= 0. {
+        return Vec::new();
+    }
+    let mut sum = 0.;
+    weights.iter().map(|w| {
+        sum += w / total;
+        sum
+    }).collect()
+}
+
+// Index of the first entry of `cdf` above `u`
+pub fn pick(cdf: &[Float], u: Float) -> Option<usize> {
+    if cdf.is_empty() {
+        return None;
+    }
+    let i = match cdf.binary_search_by(|p| p.partial_cmp(&u).unwrap()) {
+        Ok(i) => i + 1,
+        Err(i) => i,
+    };
+    Some(cmp::min(i, cdf.len() - 1))
+}
+
+// A microfacet normal of a GGX surface with roughness `alpha` around `normal`, distributed like
+// the facets, for u1, u2 in 0..1
+pub fn ggx_half_vector(normal: &Vec3, alpha: Float, u1: Float, u2: Float) -> Vec3 {
+    let helper = if normal.x.abs() < 0.9 { Vec3::new(1., 0., 0.) } else { Vec3::new(0., 1., 0.) };
+    let tangent = cross(normal, &helper).normalize();
+    let bitangent = cross(normal, &tangent);
+    let cos2 = (1. - u1) / (1. + (alpha * alpha - 1.) * u1);
+    let (cos, sin) = (cos2.sqrt(), (1. - cos2).max(0.).sqrt());
+    let angle = 2. * float::consts::PI * u2;
+    tangent * (sin * angle.cos()) + bitangent * (sin * angle.sin()) + *normal * cos
+}
+
+// A direction around `normal` with density cos(angle to normal) / pi, for u1, u2 in 0..1
+pub fn cosine_hemisphere(normal: &Vec3, u1: Float, u2: Float) -> Vec3 {
+    let helper = if normal.x.abs() < 0.9 { Vec3::new(1., 0., 0.) } else { Vec3::new(0., 1., 0.) };
+    let tangent = cross(normal, &helper).normalize();
+    let bitangent = cross(normal, &tangent);
+    let r = u1.sqrt();
+    let angle = 2. * float::consts::PI * u2;
+    tangent * (r * angle.cos()) + bitangent * (r * angle.sin()) + *normal * (1. - u1).sqrt()
+}
+
+// A direction with the same density 1 / (4 pi) all around, for u1, u2 in 0..1
+pub fn uniform_sphere(u1: Float, u2: Float) -> Vec3 {
+    let z = 1. - 2. * u1;
+    let r = (1. - z * z).max(0.).sqrt();
+    let angle = 2. * float::consts::PI * u2;
+    Vec3::new(r * angle.cos(), r * angle.sin(), z)
+}
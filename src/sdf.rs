@@ -0,0 +1,198 @@
+// Surfaces given by a signed distance function: the distance from any point to the nearest point
+// of the surface, negative inside. A ray is sphere traced, stepping forward by the distance at
+// each point it reaches, which can't carry it through the surface, until it's so close that it
+// counts as a hit. Shapes built this way can blend into each other with smooth fillets, which
+// no analytic primitive or CSG of them can, for organic shapes like drops merging or limbs.
+
+use {float, Float, Vec3};
+use bounds::{box_interval, Aabb};
+use material::Material;
+use ray::{Intersection, Ray};
+use surface::Surface;
+
+use nalgebra::Norm;
+
+// Steps before giving up on a ray, e.g. one grazing the surface that only slowly gets nearer
+const MAX_STEPS: u32 = 512;
+// How close a ray gets to count as a hit, relative to how far it has come (plus one)
+const HIT_DISTANCE: Float = 1e-5;
+// Between the points the normal is measured from
+const NORMAL_STEP: Float = 1e-4;
+
+pub enum Shape {
+    Sphere { center: Vec3, radius: Float },
+    // `size` across along each axis, with the edges and corners rounded by `radius`
+    RoundedBox { center: Vec3, size: Vec3, radius: Float },
+    // Around the y axis through `center`, the tube `minor_radius` thick around a circle of
+    // `radius`. Transforms turn it
+    Torus { center: Vec3, radius: Float, minor_radius: Float },
+    // Both shapes, joined by a fillet about `blend` wide where they meet, or simply both for a
+    // blend of 0. Smooth unions can be blended again
+    SmoothUnion(Box<Shape>, Box<Shape>, Float),
+}
+
+impl Shape {
+    // The signed distance from `p` to the surface, or less than it, since a smooth union only
+    // bounds the distance near its fillet
+    pub fn distance(&self, p: &Vec3) -> Float {
+        match *self {
+            Shape::Sphere { center, radius } => (*p - center).norm() - radius,
+            Shape::RoundedBox { center, size, radius } => {
+                let d = *p - center;
+                let q = Vec3::new(d.x.abs() - size.x / 2. + radius,
+                                  d.y.abs() - size.y / 2. + radius,
+                                  d.z.abs() - size.z / 2. + radius);
+                let outside = Vec3::new(q.x.max(0.), q.y.max(0.), q.z.max(0.)).norm();
+                outside + q.x.max(q.y).max(q.z).min(0.) - radius
+            }
+            Shape::Torus { center, radius, minor_radius } => {
+                let d = *p - center;
+                let across = (d.x * d.x + d.z * d.z).sqrt() - radius;
+                (across * across + d.y * d.y).sqrt() - minor_radius
+            }
+            // The polynomial smooth minimum
+            Shape::SmoothUnion(ref a, ref b, blend) => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                if blend == 0. {
+                    return a.min(b);
+                }
+                let h = (blend - (a - b).abs()).max(0.) / blend;
+                a.min(b) - h * h * blend / 4.
+            }
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        match *self {
+            Shape::Sphere { center, radius } => {
+                let r = Vec3::new(radius, radius, radius);
+                Aabb::new(center - r, center + r)
+            }
+            Shape::RoundedBox { center, size, .. } => {
+                Aabb::new(center - size / 2., center + size / 2.)
+            }
+            Shape::Torus { center, radius, minor_radius } => {
+                let outer = radius + minor_radius;
+                let r = Vec3::new(outer, minor_radius, outer);
+                Aabb::new(center - r, center + r)
+            }
+            // The fillet reaches at most a quarter of the blend beyond either shape
+            Shape::SmoothUnion(ref a, ref b, blend) => {
+                let both = a.bounds().union(&b.bounds());
+                let r = Vec3::new(blend, blend, blend) / 4.;
+                Aabb::new(both.min - r, both.max + r)
+            }
+        }
+    }
+
+    fn check(&self) {
+        match *self {
+            Shape::Sphere { radius, .. } => {
+                assert!(radius > 0., "SDF spheres need a positive radius, not {}", radius);
+            }
+            Shape::RoundedBox { size, radius, .. } => {
+                let smallest = size.x.min(size.y).min(size.z);
+                assert!(smallest > 0., "SDF boxes need a positive size");
+                assert!(radius >= 0. && radius <= smallest / 2.,
+                        "SDF boxes need a rounding radius in 0..{}, not {}", smallest / 2., radius);
+            }
+            Shape::Torus { radius, minor_radius, .. } => {
+                assert!(minor_radius > 0. && minor_radius <= radius,
+                        "SDF tori need a minor radius in 0..radius, not {}", minor_radius);
+            }
+            Shape::SmoothUnion(ref a, ref b, blend) => {
+                assert!(blend >= 0., "Smooth unions can't blend by a negative width");
+                a.check();
+                b.check();
+            }
+        }
+    }
+}
+
+pub struct Sdf {
+    shape: Shape,
+    bounds: Aabb,
+    material: Material,
+}
+
+impl Sdf {
+    pub fn new(shape: Shape, material: Material) -> Self {
+        shape.check();
+        let bounds = shape.bounds();
+        Sdf { shape: shape, bounds: bounds, material: material }
+    }
+
+    // The unit gradient of the distance at `pos`, from the differences between the corners of a
+    // tetrahedron around it, which takes 4 distances rather than the 6 of central differences
+    fn normal(&self, pos: &Vec3) -> Vec3 {
+        let corners = [Vec3::new(1., -1., -1.), Vec3::new(-1., -1., 1.), Vec3::new(-1., 1., -1.),
+                       Vec3::new(1., 1., 1.)];
+        let gradient = corners.iter().fold(Vec3::new(0., 0., 0.), |sum, &corner| {
+            sum + corner * self.shape.distance(&(*pos + corner * NORMAL_STEP))
+        });
+        if gradient.norm_squared() > 0. {
+            gradient.normalize()
+        } else {
+            Vec3::new(0., 1., 0.)
+        }
+    }
+}
+
+impl Surface for Sdf {
+    fn name(&self) -> &'static str {
+        "Sdf"
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    // There's no layout to bake into
+    fn surface_point(&self, _: Float, _: Float) -> Option<Intersection> {
+        None
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        let (near, far) = match box_interval(&self.bounds, ray, 0., float::INFINITY) {
+            Some(interval) => interval,
+            None => return None,
+        };
+        // A ray starting inside, e.g. refracted into the shape, steps by the distance to leaving
+        let sign = if self.shape.distance(&(ray.origin + ray.dir * near)) < 0. { -1. } else { 1. };
+        let mut dist = near;
+        for _ in 0..MAX_STEPS {
+            let pos = ray.origin + ray.dir * dist;
+            let step = sign * self.shape.distance(&pos);
+            if step < HIT_DISTANCE * (1. + dist) {
+                if dist <= 0. {
+                    return None;
+                }
+                let normal = self.normal(&pos);
+                // Texture coordinates from the normal's direction, like a sphere's
+                let u = 0.5 + normal.z.atan2(normal.x) / (2. * float::consts::PI);
+                let v = 0.5 - normal.y.max(-1.).min(1.).asin() / float::consts::PI;
+                let material = &self.material;
+                let normal = if material.has_normal_map() {
+                    material.apply_normal_map(&normal, &pos)
+                } else {
+                    normal
+                };
+                let pos = if material.has_displacement_map() {
+                    material.apply_displacement_map(&pos)
+                } else {
+                    pos
+                };
+                return Some(Intersection::new(pos, normal, dist, u, v));
+            }
+            dist += step;
+            if dist > far {
+                return None;
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,158 @@
+// Section planes cut away everything on the side their normal points to, for cutaway views of
+// interiors and CAD models. They cut all rays, so light gets in through the opening too.
+//
+// With `cap`, objects that are cut open look solid instead of hollow: a ray that enters an object
+// through the plane hits the plane there, shaded with that object's material. This assumes the
+// objects are closed, so that seeing the inside of a surface means looking through the cut.
+//
+// A Sectioned surface is cut by planes of its own instead, leaving the rest of the scene whole,
+// e.g. to show the inside of one engine part among others.
+
+use std::mem;
+
+use {float, Float, Vec3};
+use bounds::Aabb;
+use material::Material;
+use ray::{Intersection, Ray};
+use surface::Surface;
+
+use nalgebra::{dot, Norm};
+
+#[derive(Clone, Debug)]
+pub struct SectionPlane {
+    point: Vec3,
+    normal: Vec3,
+    cap: bool,
+}
+
+impl SectionPlane {
+    pub fn new(point: Vec3, normal: Vec3, cap: bool) -> Self {
+        SectionPlane { point: point, normal: normal.normalize(), cap: cap }
+    }
+
+    pub fn normal(&self) -> &Vec3 {
+        &self.normal
+    }
+
+    pub fn cap(&self) -> bool {
+        self.cap
+    }
+
+    // Narrows the distances near..far along the ray to the part that isn't cut away. Returns
+    // true if the plane moved `near`, i.e. the ray comes from the cut side and crosses the plane
+    // at the new `near`
+    pub fn clip(&self, ray: &Ray, near: &mut Float, far: &mut Float) -> bool {
+        let offset = dot(&(ray.origin - self.point), &self.normal);
+        let speed = dot(&ray.dir, &self.normal);
+        if speed == 0. {
+            if offset > 0. {
+                *far = *near;
+            }
+            return false;
+        }
+
+        let crossing = -offset / speed;
+        if speed > 0. {
+            *far = far.min(crossing);
+            false
+        } else if crossing > *near {
+            *near = crossing;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Clips near..far by all the `planes`, returning the one the ray enters the kept part through, if
+// any. The kept part is a single interval, since each plane keeps a half space
+pub fn clip_all<'a>(planes: &'a [SectionPlane], ray: &Ray, near: &mut Float, far: &mut Float)
+                    -> Option<&'a SectionPlane> {
+    let mut entry = None;
+    for plane in planes {
+        if plane.clip(ray, near, far) {
+            entry = Some(plane);
+        }
+    }
+    entry
+}
+
+// The hit of a ray that entered near..far through `entry`, for the first hit `hit` after it:
+// the cap on the plane if the ray sees the inside of the surface there
+pub fn capped(ray: &Ray, entry: Option<&SectionPlane>, near: Float, hit: Intersection)
+              -> Intersection {
+    match entry {
+        Some(plane) if plane.cap() && dot(&ray.dir, &hit.normal) > 0. => {
+            let pos = ray.origin + ray.dir * near;
+            Intersection { pos: pos, normal: plane.normal, dist: near, ..hit }
+        }
+        _ => hit,
+    }
+}
+
+// A surface with everything on the cut side of its planes taken away. Like Instance, it isn't
+// sampled as an area light
+pub struct Sectioned {
+    surface: Box<Surface>,
+    planes: Vec<SectionPlane>,
+}
+
+impl Sectioned {
+    pub fn new(surface: Box<Surface>, planes: Vec<SectionPlane>) -> Self {
+        Sectioned { surface: surface, planes: planes }
+    }
+}
+
+impl Surface for Sectioned {
+    fn name(&self) -> &'static str {
+        self.surface.name()
+    }
+
+    fn material(&self) -> &Material {
+        self.surface.material()
+    }
+
+    fn material_at(&self, hit: &Intersection) -> &Material {
+        self.surface.material_at(hit)
+    }
+
+    // The whole surface's, which is simpler than cutting the box and seldom much larger
+    fn bounds(&self) -> Option<Aabb> {
+        self.surface.bounds()
+    }
+
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        self.surface.surface_point(u, v).and_then(|point| {
+            let cut = self.planes.iter().any(|plane| {
+                dot(&(point.pos - plane.point), &plane.normal) > 0.
+            });
+            if cut { None } else { Some(point) }
+        })
+    }
+
+    // Like the scene's sections: the ray starts where it enters the part that's kept
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        let (mut near, mut far) = (0., float::INFINITY);
+        let entry = clip_all(&self.planes, ray, &mut near, &mut far);
+        if near >= far {
+            return None;
+        }
+        let hit = if near > 0. {
+            let start = Ray::new(ray.origin + ray.dir * near, ray.dir).with_time(ray.time)
+                .with_kind(ray.kind);
+            self.surface.intersect(&start).map(|hit| Intersection { dist: hit.dist + near, ..hit })
+        } else {
+            self.surface.intersect(ray)
+        };
+        hit.and_then(|hit| if hit.dist <= far { Some(capped(ray, entry, near, hit)) } else { None })
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.surface.triangle_count()
+    }
+
+    fn heap_size(&self) -> usize {
+        mem::size_of_val(&*self.surface) + self.surface.heap_size() +
+        self.planes.len() * mem::size_of::<SectionPlane>()
+    }
+}
@@ -0,0 +1,371 @@
+// A small HTTP API around the renderer, meant to back a web based scene editor:
+//
+//   POST /render     body is a scene as JSON (same layout as the scene TOML files, plus an
+//                    optional "config" table overriding config.toml). The response streams
+//                    "progress <percent>" lines while rendering and ends with "done <image path>".
+//   GET /image/<id>  returns a finished render as PNG, while it's among the latest ones kept.
+//
+// Files named in requests are looked up under `serve_root` from config.toml, the working directory
+// by default, and can't leave it.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use rustc_serialize::json::Json;
+use toml;
+
+use image::ImageFormat;
+
+use super::{read_toml, render, try_load_scene, Config};
+use validate::{validate_config, validate_scene};
+
+// The finished renders, of which only the latest are kept, see MAX_IMAGES and MAX_IMAGE_BYTES
+struct Images {
+    next_id: u32,
+    images: BTreeMap<u32, Vec<u8>>,
+    // The total size of the PNGs in images
+    bytes: usize,
+}
+
+impl Images {
+    fn new() -> Self {
+        Images { next_id: 0, images: BTreeMap::new(), bytes: 0 }
+    }
+
+    // Stores `png` under a new ID, evicting the oldest images once there are too many
+    fn insert(&mut self, png: Vec<u8>) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.bytes += png.len();
+        self.images.insert(id, png);
+        // IDs only grow, so the first is the oldest. The newest image is kept even if it's
+        // bigger than the whole budget, so its client can still fetch it
+        while self.images.len() > MAX_IMAGES ||
+              (self.bytes > MAX_IMAGE_BYTES && self.images.len() > 1) {
+            let oldest = *self.images.keys().next().unwrap();
+            self.bytes -= self.images.remove(&oldest).unwrap().len();
+        }
+        id
+    }
+}
+
+// How many finished renders are kept for GET /image, and how many bytes of PNGs at most
+const MAX_IMAGES: usize = 100;
+const MAX_IMAGE_BYTES: usize = 256 * 1024 * 1024;
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+// Why a request couldn't be read
+enum BadRequest {
+    Malformed,
+    // The body is longer than MAX_BODY_BYTES
+    TooLarge,
+    // More than MAX_HEADER_LINES lines, or one longer than MAX_HEADER_LINE_BYTES
+    HeadersTooLarge,
+}
+
+// Scenes are small, they name their meshes and textures instead of holding them, so anything
+// bigger is refused before it's read into memory
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+// Headers are only read for Content-Length, so these are generous
+const MAX_HEADER_LINES: usize = 100;
+const MAX_HEADER_LINE_BYTES: usize = 8 * 1024;
+
+// Connections are handled by this many threads, and the rest wait their turn. Each render already
+// uses every core, so more at once would only make them all slower
+const WORKERS: usize = 4;
+
+pub fn serve(addr: &str, config_file: &str) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("Can't listen on {}: {}", addr, error);
+            process::exit(1);
+        }
+    };
+    let defaults = read_toml(config_file);
+    let root = PathBuf::from(defaults.lookup("config.serve_root").and_then(|r| r.as_str())
+        .unwrap_or("."));
+    if !root.is_dir() {
+        error!("serve_root {} isn't a directory", root.display());
+        process::exit(1);
+    }
+    let images = Arc::new(Mutex::new(Images::new()));
+
+    let (sender, receiver) = mpsc::channel();
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..WORKERS {
+        let (receiver, defaults, root, images) =
+            (receiver.clone(), defaults.clone(), root.clone(), images.clone());
+        thread::spawn(move || work(&receiver, &defaults, &root, &images));
+    }
+    info!("Listening on {}", addr);
+
+    for stream in listener.incoming() {
+        if let Ok(stream) = stream {
+            sender.send(stream).unwrap();
+        }
+    }
+}
+
+// Handles connections until the server stops. A bad scene only takes down its own request: the
+// panic is caught and the worker goes on to the next connection
+fn work(connections: &Mutex<Receiver<TcpStream>>, defaults: &toml::Value, root: &Path,
+        images: &Mutex<Images>) {
+    loop {
+        let stream = match connections.lock().unwrap().recv() {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            handle_connection(stream, defaults, root, images)
+        }));
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, defaults: &toml::Value, root: &Path,
+                     images: &Mutex<Images>) {
+    let request = match read_request(&mut stream) {
+        Ok(request) => request,
+        Err(BadRequest::Malformed) => {
+            return write_response(&mut stream, "400 Bad Request", "text/plain", b"Bad request\n")
+        }
+        Err(BadRequest::TooLarge) => {
+            warn!("Rejected a request body over {} bytes", MAX_BODY_BYTES);
+            return write_response(&mut stream, "413 Payload Too Large", "text/plain",
+                                  b"Request body too large\n");
+        }
+        Err(BadRequest::HeadersTooLarge) => {
+            warn!("Rejected a request with headers over {} lines or {} bytes a line",
+                  MAX_HEADER_LINES, MAX_HEADER_LINE_BYTES);
+            return write_response(&mut stream, "431 Request Header Fields Too Large",
+                                  "text/plain", b"Request headers too large\n");
+        }
+    };
+
+    info!("{} {}", request.method, request.path);
+    match (&request.method[..], &request.path[..]) {
+        ("POST", "/render") => {
+            handle_render(&mut stream, &request.body, defaults, root, images)
+        }
+        ("GET", path) if path.starts_with("/image/") => {
+            let image = path["/image/".len()..].parse().ok()
+                .and_then(|id| images.lock().unwrap().images.get(&id).cloned());
+            match image {
+                Some(png) => write_response(&mut stream, "200 OK", "image/png", &png),
+                None => {
+                    write_response(&mut stream, "404 Not Found", "text/plain", b"No such image\n")
+                }
+            }
+        }
+        _ => write_response(&mut stream, "404 Not Found", "text/plain", b"Not found\n"),
+    }
+}
+
+fn handle_render(stream: &mut TcpStream, body: &[u8], defaults: &toml::Value, root: &Path,
+                 images: &Mutex<Images>) {
+    let json = String::from_utf8(body.to_vec()).ok().and_then(|s| Json::from_str(&s).ok());
+    let mut scene_toml = match json {
+        Some(json @ Json::Object(_)) => json_to_toml(&json),
+        _ => {
+            warn!("Rejected render request with invalid JSON");
+            return write_response(stream, "400 Bad Request", "text/plain", b"Invalid JSON\n");
+        }
+    };
+
+    // The overrides are part of the scene, so their files are restricted too
+    let mut problems = Vec::new();
+    restrict_paths(&mut scene_toml, root, &mut problems);
+    if !problems.is_empty() {
+        warn!("Rejected render request with files outside {}", root.display());
+        let body = format!("{}\n", problems.join("\n"));
+        return write_response(stream, "400 Bad Request", "text/plain", body.as_bytes());
+    }
+    let mut config_toml = defaults.clone();
+    if let Some(overrides) = scene_toml.lookup("config").and_then(|c| c.as_table()) {
+        merge_config(&mut config_toml, overrides);
+    }
+    // The overrides come from the client too, so they're checked before decoding like the scene
+    let mut problems = validate_config(&config_toml);
+    problems.extend(validate_scene(&scene_toml));
+    if !problems.is_empty() {
+        warn!("Rejected render request with {} problems", problems.len());
+        let body = format!("{}\n", problems.join("\n"));
+        return write_response(stream, "400 Bad Request", "text/plain", body.as_bytes());
+    }
+    let config = Config::from_toml(&config_toml);
+    // Which can still fail on the scene's memory budget
+    let scene = match try_load_scene(&scene_toml, None) {
+        Ok(scene) => scene,
+        Err(error) => {
+            warn!("Rejected render request: {}", error);
+            let body = format!("{}\n", error);
+            return write_response(stream, "400 Bad Request", "text/plain", body.as_bytes());
+        }
+    };
+
+    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\
+                               Transfer-Encoding: chunked\r\n\r\n");
+    let mut last_percent = None;
+    let im = render(&config, &scene, |done, total| {
+        let percent = done * 100 / total;
+        if last_percent != Some(percent) {
+            last_percent = Some(percent);
+            let _ = write_chunk(stream, format!("progress {}\n", percent).as_bytes());
+        }
+    });
+
+    let mut png = Vec::new();
+    im.save(&mut png, ImageFormat::PNG).unwrap();
+    let id = images.lock().unwrap().insert(png);
+
+    let _ = write_chunk(stream, format!("done /image/{}\n", id).as_bytes());
+    let _ = write_chunk(stream, b"");
+}
+
+// Overrides values in the [config] table of `base` with those in `overrides`
+fn merge_config(base: &mut toml::Value, overrides: &toml::Table) {
+    if let Some(&mut toml::Value::Table(ref mut config)) = base.lookup_mut("config") {
+        for (key, value) in overrides.iter() {
+            config.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+// The keys, at any depth of a scene or its config overrides, that name files to read or write
+const PATH_KEYS: &'static [&'static str] = &["file", "texture", "roughness_map", "metallic_map",
+                                             "normal_texture", "bump_texture", "gltf",
+                                             "aperture_mask", "ambient_map", "environment",
+                                             "out_file", "scene", "stats_heatmap"];
+
+// Makes the files named in `value` relative to `root`, with a problem for each one that's
+// absolute or goes up with `..`
+fn restrict_paths(value: &mut toml::Value, root: &Path, problems: &mut Vec<String>) {
+    match *value {
+        toml::Value::Table(ref mut table) => {
+            for (key, value) in table.iter_mut() {
+                match *value {
+                    toml::Value::String(ref mut file) if PATH_KEYS.contains(&&key[..]) => {
+                        let path = Path::new(file).to_owned();
+                        if path.components().all(|c| match c {
+                            Component::Normal(_) | Component::CurDir => true,
+                            _ => false,
+                        }) {
+                            *file = root.join(path).to_string_lossy().into_owned();
+                        } else {
+                            problems.push(format!("{}: {} isn't a relative path inside the \
+                                                   served directory", key, file));
+                        }
+                    }
+                    _ => restrict_paths(value, root, problems),
+                }
+            }
+        }
+        toml::Value::Array(ref mut array) => {
+            for value in array.iter_mut() {
+                restrict_paths(value, root, problems);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn json_to_toml(json: &Json) -> toml::Value {
+    match *json {
+        Json::I64(i) => toml::Value::Integer(i),
+        Json::U64(u) => toml::Value::Integer(u as i64),
+        Json::F64(f) => toml::Value::Float(f),
+        Json::String(ref s) => toml::Value::String(s.clone()),
+        Json::Boolean(b) => toml::Value::Boolean(b),
+        Json::Array(ref a) => toml::Value::Array(a.iter().map(json_to_toml).collect()),
+        Json::Object(ref o) => toml::Value::Table(o.iter()
+            .map(|(k, v)| (k.clone(), json_to_toml(v)))
+            .collect()),
+        // TOML has no null, treat it as an empty table
+        Json::Null => toml::Value::Table(BTreeMap::new()),
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<Request, BadRequest> {
+    let mut reader = BufReader::new(stream);
+
+    let line = try!(read_header_line(&mut reader));
+    let mut parts = line.split_whitespace();
+    let method = match parts.next() {
+        Some(m) => m.to_owned(),
+        None => return Err(BadRequest::Malformed),
+    };
+    let path = match parts.next() {
+        Some(p) => p.to_owned(),
+        None => return Err(BadRequest::Malformed),
+    };
+
+    let mut content_length = 0;
+    for lines in 0.. {
+        if lines == MAX_HEADER_LINES {
+            return Err(BadRequest::HeadersTooLarge);
+        }
+        let header = try!(read_header_line(&mut reader));
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        let mut kv = header.splitn(2, ':');
+        let key = kv.next().unwrap().trim().to_lowercase();
+        if key == "content-length" {
+            content_length = match kv.next().and_then(|v| v.trim().parse().ok()) {
+                Some(len) => len,
+                None => return Err(BadRequest::Malformed),
+            };
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Err(BadRequest::TooLarge);
+    }
+
+    let mut body = vec![0; content_length];
+    if reader.read_exact(&mut body).is_err() {
+        return Err(BadRequest::Malformed);
+    }
+
+    Ok(Request { method: method, path: path, body: body })
+}
+
+// A line of at most MAX_HEADER_LINE_BYTES, so a client can't make it grow without end
+fn read_header_line<R: BufRead>(reader: &mut R) -> Result<String, BadRequest> {
+    let mut line = String::new();
+    let limit = MAX_HEADER_LINE_BYTES as u64 + 1;
+    if reader.take(limit).read_line(&mut line).is_err() {
+        return Err(BadRequest::Malformed);
+    }
+    if line.len() > MAX_HEADER_LINE_BYTES {
+        return Err(BadRequest::HeadersTooLarge);
+    }
+    Ok(line)
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) {
+    let header = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                          Connection: close\r\n\r\n",
+                         status, content_type, body.len());
+    let _ = stream.write_all(header.as_bytes()).and_then(|_| stream.write_all(body));
+}
+
+fn write_chunk(stream: &mut TcpStream, data: &[u8]) -> ::std::io::Result<()> {
+    try!(stream.write_all(format!("{:x}\r\n", data.len()).as_bytes()));
+    try!(stream.write_all(data));
+    try!(stream.write_all(b"\r\n"));
+    stream.flush()
+}
@@ -0,0 +1,94 @@
+// Order 2 (9 coefficient) real spherical harmonics, for compact low frequency lighting
+
+use std::ops::{Add, Mul};
+
+use {Float, Vec3};
+use float::consts::PI;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sh9 {
+    pub coeffs: [Vec3; 9],
+}
+
+impl Sh9 {
+    pub fn new() -> Self {
+        Sh9 { coeffs: [Vec3::new(0., 0., 0.); 9] }
+    }
+
+    // Projects a function over the sphere, sampled at `n` evenly spread directions
+    pub fn project<F: FnMut(Vec3) -> Vec3>(n: u32, mut f: F) -> Self {
+        let mut sh = Sh9::new();
+        for dir in sphere_points(n) {
+            let value = f(dir);
+            for (coeff, basis) in sh.coeffs.iter_mut().zip(basis(&dir).iter()) {
+                *coeff = *coeff + value * *basis;
+            }
+        }
+        sh * (4. * PI / n as Float)
+    }
+
+    // Treating the coefficients as incoming radiance, the irradiance on a surface with normal
+    // `normal`. Divide by pi for the average radiance over the hemisphere, which is what a
+    // diffuse surface reflects
+    pub fn irradiance(&self, normal: &Vec3) -> Vec3 {
+        // Convolution with the clamped cosine lobe scales each band by a constant
+        let bands = [PI, 2. * PI / 3., 2. * PI / 3., 2. * PI / 3., PI / 4., PI / 4., PI / 4.,
+                     PI / 4., PI / 4.];
+        self.coeffs.iter().zip(basis(normal).iter()).zip(bands.iter())
+            .fold(Vec3::new(0., 0., 0.), |sum, ((coeff, basis), band)| {
+                sum + *coeff * *basis * *band
+            })
+    }
+
+    // Value of the projected function in direction `dir`, which must be unit length
+    pub fn eval(&self, dir: &Vec3) -> Vec3 {
+        self.coeffs.iter().zip(basis(dir).iter())
+            .fold(Vec3::new(0., 0., 0.), |sum, (coeff, basis)| sum + *coeff * *basis)
+    }
+}
+
+impl Add for Sh9 {
+    type Output = Sh9;
+
+    fn add(mut self, other: Sh9) -> Sh9 {
+        for (a, b) in self.coeffs.iter_mut().zip(other.coeffs.iter()) {
+            *a = *a + *b;
+        }
+        self
+    }
+}
+
+impl Mul<Float> for Sh9 {
+    type Output = Sh9;
+
+    fn mul(mut self, f: Float) -> Sh9 {
+        for coeff in self.coeffs.iter_mut() {
+            *coeff = *coeff * f;
+        }
+        self
+    }
+}
+
+pub fn basis(dir: &Vec3) -> [Float; 9] {
+    let (x, y, z) = (dir.x, dir.y, dir.z);
+    [0.282095,
+     0.488603 * y,
+     0.488603 * z,
+     0.488603 * x,
+     1.092548 * x * y,
+     1.092548 * y * z,
+     0.315392 * (3. * z * z - 1.),
+     1.092548 * x * z,
+     0.546274 * (x * x - y * y)]
+}
+
+// `n` unit vectors spread evenly over the sphere along a Fibonacci spiral
+pub fn sphere_points(n: u32) -> Vec<Vec3> {
+    let golden_angle = PI * (3. - Float::sqrt(5.));
+    (0..n).map(|i| {
+        let y = 1. - 2. * (i as Float + 0.5) / n as Float;
+        let r = (1. - y * y).sqrt();
+        let angle = golden_angle * i as Float;
+        Vec3::new(r * angle.cos(), y, r * angle.sin())
+    }).collect()
+}
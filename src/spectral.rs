@@ -0,0 +1,57 @@
+// Spectral rendering, for the dispersion of glass and gems: their index of refraction is higher
+// for blue light than for red, so a prism fans white light out into a rainbow. In spectral mode
+// each camera ray carries a single wavelength, picked at random across the visible spectrum,
+// which dispersive materials bend by their index of refraction for it, see
+// Material::with_dispersion. What the ray brings back is then weighted by how the CIE color
+// matching functions see that wavelength, converted to linear sRGB, so the samples of a pixel
+// add up to its color again. Colors are kept as RGB otherwise, and a scene without dispersive
+// materials renders as it would without spectral mode, only with colored noise that takes more
+// samples to average out.
+
+use {Float, Vec3};
+
+// The visible spectrum wavelengths are picked from, in nanometers
+pub const MIN_WAVELENGTH: Float = 380.;
+pub const MAX_WAVELENGTH: Float = 730.;
+
+// Where the index of refraction of a dispersive material is the one it was given: the sodium D
+// line, which refractive indices are usually measured at
+pub const REFERENCE_WAVELENGTH: Float = 589.3;
+
+// The average of rgb over the spectrum, which weight divides by so white light stays white
+const AVERAGE_RGB: [Float; 3] = [0.366753, 0.290127, 0.277286];
+
+// The wavelength at `u` from 0 to 1 across the visible spectrum
+pub fn wavelength(u: Float) -> Float {
+    MIN_WAVELENGTH + (MAX_WAVELENGTH - MIN_WAVELENGTH) * u
+}
+
+// What the light a ray of `wavelength` brings back counts for in each channel. Averaged over the
+// spectrum it's 1 in all three, and it's negative for wavelengths more saturated than sRGB can
+// show, which their neighbours make up for
+pub fn weight(wavelength: Float) -> Vec3 {
+    let rgb = rgb(wavelength);
+    Vec3::new(rgb.x / AVERAGE_RGB[0], rgb.y / AVERAGE_RGB[1], rgb.z / AVERAGE_RGB[2])
+}
+
+// The linear sRGB color of light of a single `wavelength`
+fn rgb(wavelength: Float) -> Vec3 {
+    let (x, y, z) = xyz(wavelength);
+    Vec3::new(3.2406 * x - 1.5372 * y - 0.4986 * z,
+              -0.9689 * x + 1.8758 * y + 0.0415 * z,
+              0.0557 * x - 0.2040 * y + 1.0570 * z)
+}
+
+// The CIE 1931 color matching functions, by the multi-lobe fit of Wyman, Sloan and Shirley's
+// "Simple Analytic Approximations to the CIE XYZ Color Matching Functions"
+fn xyz(wavelength: Float) -> (Float, Float, Float) {
+    // A Gaussian with different widths below and above its peak
+    let lobe = |peak: Float, below: Float, above: Float| {
+        let t = (wavelength - peak) / if wavelength < peak { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+    (1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7) -
+     0.065 * lobe(501.1, 20.4, 26.2),
+     0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+     1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8))
+}
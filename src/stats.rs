@@ -0,0 +1,129 @@
+// Per thread counters of the work done while tracing, used for cost visualization and for
+// scheduling adaptive sampling, a count of rays over all threads for benchmarks, and the counts
+// of the ray statistics report, which are only kept while it's enabled
+
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use ray::RayKind;
+
+thread_local!(static INTERSECTION_TESTS: Cell<u64> = Cell::new(0));
+thread_local!(static NODE_VISITS: Cell<u64> = Cell::new(0));
+thread_local!(static RAYS: Cell<u64> = Cell::new(0));
+
+// The rays the threads have added with add_rays, for the whole process
+static TOTAL_RAYS: AtomicUsize = AtomicUsize::new(0);
+
+// Every ray, visit and test touches these from all threads, which costs a little speed, so
+// they're only counted while the report is enabled
+static REPORT: AtomicBool = AtomicBool::new(false);
+static CAMERA_RAYS: AtomicUsize = AtomicUsize::new(0);
+static SECONDARY_RAYS: AtomicUsize = AtomicUsize::new(0);
+static SHADOW_RAYS: AtomicUsize = AtomicUsize::new(0);
+static REPORT_NODE_VISITS: AtomicUsize = AtomicUsize::new(0);
+static REPORT_INTERSECTION_TESTS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn count_intersection_test() {
+    INTERSECTION_TESTS.with(|n| n.set(n.get() + 1));
+    if REPORT.load(Ordering::Relaxed) {
+        REPORT_INTERSECTION_TESTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Returns the number of ray/surface tests since the last call, and resets the counter
+pub fn take_intersection_tests() -> u64 {
+    INTERSECTION_TESTS.with(|n| n.replace(0))
+}
+
+// A node of a bounding volume hierarchy whose box was tested, of the scene's or a mesh's
+pub fn count_node_visit() {
+    NODE_VISITS.with(|n| n.set(n.get() + 1));
+    if REPORT.load(Ordering::Relaxed) {
+        REPORT_NODE_VISITS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Returns the number of node visits since the last call, and resets the counter
+pub fn take_node_visits() -> u64 {
+    NODE_VISITS.with(|n| n.replace(0))
+}
+
+pub fn count_ray(kind: RayKind) {
+    RAYS.with(|n| n.set(n.get() + 1));
+    if REPORT.load(Ordering::Relaxed) {
+        let counter = match kind {
+            RayKind::Camera => &CAMERA_RAYS,
+            RayKind::Reflection => &SECONDARY_RAYS,
+            RayKind::Shadow => &SHADOW_RAYS,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Adds the rays this thread traced since the last call to the total. Renders call it once per
+// tile, so the threads seldom touch the shared counter
+pub fn add_rays() {
+    let rays = RAYS.with(|n| n.replace(0));
+    TOTAL_RAYS.fetch_add(rays as usize, Ordering::Relaxed);
+}
+
+// Returns the number of rays all threads added since the last call, and resets it
+pub fn take_total_rays() -> u64 {
+    TOTAL_RAYS.swap(0, Ordering::Relaxed) as u64
+}
+
+// What the rays of the renders since the report was enabled, or last taken, did
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Report {
+    pub camera_rays: u64,
+    // Reflected, refracted and bounced
+    pub secondary_rays: u64,
+    pub shadow_rays: u64,
+    pub node_visits: u64,
+    pub intersection_tests: u64,
+}
+
+impl Report {
+    pub fn rays(&self) -> u64 {
+        self.camera_rays + self.secondary_rays + self.shadow_rays
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rays = self.rays();
+        let per_ray = |n: u64| if rays == 0 { 0. } else { n as f64 / rays as f64 };
+        let share = |n: u64| 100. * per_ray(n);
+        try!(writeln!(f, "{:<20} {:>14} {:>10}", "", "count", "per ray"));
+        try!(writeln!(f, "{:<20} {:>14} {:>9.1}%", "camera rays", self.camera_rays,
+                      share(self.camera_rays)));
+        try!(writeln!(f, "{:<20} {:>14} {:>9.1}%", "secondary rays", self.secondary_rays,
+                      share(self.secondary_rays)));
+        try!(writeln!(f, "{:<20} {:>14} {:>9.1}%", "shadow rays", self.shadow_rays,
+                      share(self.shadow_rays)));
+        try!(writeln!(f, "{:<20} {:>14}", "total rays", rays));
+        try!(writeln!(f, "{:<20} {:>14} {:>10.2}", "BVH node visits", self.node_visits,
+                      per_ray(self.node_visits)));
+        writeln!(f, "{:<20} {:>14} {:>10.2}", "intersection tests", self.intersection_tests,
+                 per_ray(self.intersection_tests))
+    }
+}
+
+// Starts or stops counting the rays, node visits and intersection tests of all threads for
+// take_report
+pub fn enable_report(enabled: bool) {
+    REPORT.store(enabled, Ordering::Relaxed);
+}
+
+// Returns the counts since the report was enabled or last taken, and resets them
+pub fn take_report() -> Report {
+    let take = |counter: &AtomicUsize| counter.swap(0, Ordering::Relaxed) as u64;
+    Report {
+        camera_rays: take(&CAMERA_RAYS),
+        secondary_rays: take(&SECONDARY_RAYS),
+        shadow_rays: take(&SHADOW_RAYS),
+        node_visits: take(&REPORT_NODE_VISITS),
+        intersection_tests: take(&REPORT_INTERSECTION_TESTS),
+    }
+}
@@ -0,0 +1,108 @@
+// Stereo rendering. Both eyes are rendered from parallel cameras `separation` apart, centered on
+// the scene's camera, and combined into an anaglyph or put side by side, or for VR video
+// rendered as omnidirectional stereo panoramas.
+
+use {ray_trace_camera, Float, RenderEvent, Scene, Vec3};
+use hdr::HdrImage;
+use log::{self, Level};
+use tiles::TileOrder;
+
+// Renders the left and right eye images, with `samples` per axis as in ray_trace_samples
+pub fn ray_trace_stereo<F>(scene: &Scene, width: u32, height: u32, max_depth: u16, samples: u32,
+                           separation: Float, mut progress: F) -> (HdrImage, HdrImage)
+    where F: FnMut(u32, u32)
+{
+    let _span = log::span(Level::Info, "stereo");
+    let left_camera = scene.camera.shifted(-separation / 2.);
+    let right_camera = scene.camera.shifted(separation / 2.);
+
+    // Report progress over both eyes
+    let (frame, order) = ((0, 0, width, height), TileOrder::Scanline);
+    let left = ray_trace_camera(scene, &left_camera, width, height, frame, order, max_depth,
+                                samples, |event| {
+        if let RenderEvent::TileFinished { done, total, .. } = event {
+            progress(done, total * 2);
+        }
+    });
+    let right = ray_trace_camera(scene, &right_camera, width, height, frame, order, max_depth,
+                                 samples, |event| {
+        if let RenderEvent::TileFinished { done, total, .. } = event {
+            progress(total + done, total * 2);
+        }
+    });
+    (left, right)
+}
+
+// Red/cyan anaglyph for viewing with colored glasses: red from the left eye, green and blue from
+// the right
+pub fn ray_trace_anaglyph<F>(scene: &Scene, width: u32, height: u32, max_depth: u16,
+                             samples: u32, separation: Float, progress: F) -> HdrImage
+    where F: FnMut(u32, u32)
+{
+    let (left, mut im) = ray_trace_stereo(scene, width, height, max_depth, samples, separation,
+                                          progress);
+    for (pixel, left) in im.pixels_mut().iter_mut().zip(left.pixels()) {
+        *pixel = Vec3::new(left.x, pixel.y, pixel.z);
+    }
+    im
+}
+
+// The left eye's image beside the right eye's, each width / 2 x height, for stereo viewers and
+// 3D displays that take side-by-side frames
+pub fn ray_trace_side_by_side<F>(scene: &Scene, width: u32, height: u32, max_depth: u16,
+                                 samples: u32, separation: Float, progress: F) -> HdrImage
+    where F: FnMut(u32, u32)
+{
+    assert!(width % 2 == 0, "Side-by-side images need an even width, got {}", width);
+    let eye_width = width / 2;
+    let (left, right) = ray_trace_stereo(scene, eye_width, height, max_depth, samples,
+                                         separation, progress);
+    let mut im = HdrImage::new(width, height);
+    for &(eye, offset) in [(&left, 0), (&right, eye_width)].iter() {
+        for y in 0..height {
+            for x in 0..eye_width {
+                im.put_pixel(x + offset, y, eye.get_pixel(x, y));
+                im.put_alpha(x + offset, y, eye.get_alpha(x, y));
+            }
+        }
+    }
+    im
+}
+
+// Omnidirectional stereo for VR headsets: the left eye's equirectangular panorama above the right
+// eye's, each width x height / 2, with the eyes `separation` apart
+pub fn ray_trace_ods<F>(scene: &Scene, width: u32, height: u32, max_depth: u16, samples: u32,
+                        separation: Float, mut progress: F) -> HdrImage
+    where F: FnMut(u32, u32)
+{
+    assert!(height % 2 == 0, "ODS images need an even height, got {}", height);
+    let _span = log::span(Level::Info, "omnidirectional stereo");
+    let eye_height = height / 2;
+    let left_camera = scene.camera.clone().with_ods(-separation / 2.);
+    let right_camera = scene.camera.clone().with_ods(separation / 2.);
+
+    let (frame, order) = ((0, 0, width, eye_height), TileOrder::Scanline);
+    let left = ray_trace_camera(scene, &left_camera, width, eye_height, frame, order, max_depth,
+                                samples, |event| {
+        if let RenderEvent::TileFinished { done, total, .. } = event {
+            progress(done, total * 2);
+        }
+    });
+    let right = ray_trace_camera(scene, &right_camera, width, eye_height, frame, order,
+                                 max_depth, samples, |event| {
+        if let RenderEvent::TileFinished { done, total, .. } = event {
+            progress(total + done, total * 2);
+        }
+    });
+
+    let mut im = HdrImage::new(width, height);
+    for &(eye, offset) in [(&left, 0), (&right, eye_height)].iter() {
+        for y in 0..eye_height {
+            for x in 0..width {
+                im.put_pixel(x, y + offset, eye.get_pixel(x, y));
+                im.put_alpha(x, y + offset, eye.get_alpha(x, y));
+            }
+        }
+    }
+    im
+}
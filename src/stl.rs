@@ -0,0 +1,102 @@
+// STL meshes, as written by CAD programs and 3D scanners, in both the binary and the ASCII
+// variant. STL stores every triangle with its own three corners, so corners at the same position
+// are merged into one vertex. The normals in the file are ignored, the triangles are flat.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+use {Float, Vec3};
+use material::Material;
+use mesh::{remove_degenerate, Triangle, TriangleMesh};
+
+// Header and triangle count, then 50 bytes per triangle
+const BINARY_HEADER: usize = 84;
+const BINARY_TRIANGLE: usize = 50;
+
+impl TriangleMesh {
+    pub fn load_stl(filename: &str, material: Material) -> Self {
+        let mut data = Vec::new();
+        File::open(filename).and_then(|mut file| file.read_to_end(&mut data))
+            .unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        let corners = if is_binary(&data) {
+            read_binary(&data)
+        } else {
+            read_ascii(&data).unwrap_or_else(|e| panic!("{}: {}", filename, e))
+        };
+
+        let mut positions = Vec::new();
+        let mut triangles: Vec<Triangle> = {
+            let mut indices = HashMap::new();
+            let mut index = |p: Vec3| -> usize {
+                let key = [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+                *indices.entry(key).or_insert_with(|| {
+                    positions.push(p);
+                    positions.len() - 1
+                })
+            };
+            corners.chunks(3).map(|c| {
+                Triangle { positions: [index(c[0]), index(c[1]), index(c[2])], normals: None,
+                           uvs: None, material: 0 }
+            }).collect()
+        };
+        let removed = remove_degenerate(&positions, &mut triangles);
+        debug!("Loaded {} ({} vertices, {} triangles, {} degenerate ones left out)", filename,
+               positions.len(), triangles.len(), removed);
+        TriangleMesh::new(positions, Vec::new(), Vec::new(), triangles, material)
+    }
+}
+
+// ASCII files start with "solid", but so do some binary ones, so trust the size if it matches
+fn is_binary(data: &[u8]) -> bool {
+    if data.len() >= BINARY_HEADER {
+        let count = u32_le(&data[80..84]) as usize;
+        if data.len() == BINARY_HEADER + count * BINARY_TRIANGLE {
+            return true;
+        }
+    }
+    !data.starts_with(b"solid")
+}
+
+pub fn u32_le(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn read_binary(data: &[u8]) -> Vec<Vec3> {
+    assert!(data.len() >= BINARY_HEADER, "STL file too short");
+    let count = u32_le(&data[80..84]) as usize;
+    assert!(data.len() >= BINARY_HEADER + count * BINARY_TRIANGLE,
+            "STL file has fewer triangles than its header says");
+    let float = |at: usize| f32::from_bits(u32_le(&data[at..at + 4])) as Float;
+    let mut corners = Vec::with_capacity(count * 3);
+    for i in 0..count {
+        // After the normal
+        let start = BINARY_HEADER + i * BINARY_TRIANGLE + 12;
+        for corner in 0..3 {
+            let at = start + corner * 12;
+            corners.push(Vec3::new(float(at), float(at + 4), float(at + 8)));
+        }
+    }
+    corners
+}
+
+// Only the vertex lines matter, three per facet
+fn read_ascii(data: &[u8]) -> Result<Vec<Vec3>, String> {
+    let text = String::from_utf8_lossy(data);
+    let mut corners = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("vertex") {
+            continue;
+        }
+        let v: Vec<Float> = words.filter_map(|w| w.parse().ok()).collect();
+        if v.len() != 3 {
+            return Err(format!("line {}: vertex needs x y z", n + 1));
+        }
+        corners.push(Vec3::new(v[0], v[1], v[2]));
+    }
+    if corners.len() % 3 != 0 {
+        return Err("facets need 3 vertices each".to_owned());
+    }
+    Ok(corners)
+}
@@ -0,0 +1,194 @@
+// Writing frames too large to hold in memory, e.g. 16k renders. The frame is rendered a band of
+// rows at a time, and each band is encoded and appended to the file as soon as it's finished,
+// so only one band of pixels is ever kept. PNG rows go through one zlib stream that's cut into
+// IDAT chunks as it fills; Radiance .hdr scanlines are written flat. Post effects need the
+// whole frame, so they're skipped.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+
+use tracerlib::Scene;
+use tracerlib::color::OutputTransform;
+use tracerlib::hdr::{write_radiance_header, HdrImage};
+use tracerlib::log::{self, Level};
+
+use super::Config;
+use farm::render_region_hdr;
+use pause;
+use progress::ProgressBar;
+
+// Rows rendered at a time, a multiple of the dither mask size so the bands dither seamlessly
+const BAND_HEIGHT: u32 = 64;
+// Compressed bytes per IDAT chunk
+const CHUNK_SIZE: usize = 1 << 16;
+
+// Takes the bands of a frame from top to bottom
+trait BandWriter {
+    fn write_band(&mut self, band: &HdrImage) -> io::Result<()>;
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+// Renders the frame to `file`, which must be a .png or .hdr file
+pub fn render_streaming(config: &Config, scene: &Scene, file: &str) {
+    if !config.post.is_empty() || !scene.camera().imaging().is_neutral() {
+        warn!("Post effects and camera imaging are skipped when streaming a render to disk");
+    }
+    let _span = log::span(Level::Info, "render streaming");
+    let out = BufWriter::new(File::create(file).unwrap());
+    let mut writer: Box<BandWriter> = if file.ends_with(".hdr") {
+        Box::new(RadianceWriter::new(out, config.width, config.height).unwrap())
+    } else if file.ends_with(".png") {
+        Box::new(PngWriter::new(out, config.width, config.height, scene.transparent(),
+                                config.output_transform, config.dither)
+            .unwrap())
+    } else {
+        panic!("Only .png and .hdr files can be streamed to, not {}", file);
+    };
+
+    let bands = (config.height + BAND_HEIGHT - 1) / BAND_HEIGHT;
+    let mut progress = ProgressBar::new();
+    for band in 0..bands {
+        let (y0, y1) = (band * BAND_HEIGHT, cmp::min((band + 1) * BAND_HEIGHT, config.height));
+        let im = render_region_hdr(config, scene, (0, y0, config.width, y1));
+        writer.write_band(&im).unwrap();
+        progress.update(band + 1, bands);
+        pause::wait_while_paused();
+    }
+    writer.finish().unwrap();
+}
+
+struct RadianceWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> RadianceWriter<W> {
+    fn new(mut out: W, width: u32, height: u32) -> io::Result<Self> {
+        try!(write_radiance_header(&mut out, width, height));
+        Ok(RadianceWriter { out: out })
+    }
+}
+
+impl<W: Write> BandWriter for RadianceWriter<W> {
+    fn write_band(&mut self, band: &HdrImage) -> io::Result<()> {
+        band.write_radiance_rows(&mut self.out)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+struct PngWriter<W: Write> {
+    // None once finished
+    rows: Option<ZlibEncoder<Chunks<W>>>,
+    alpha: bool,
+    transform: OutputTransform,
+    dither: bool,
+}
+
+impl<W: Write> PngWriter<W> {
+    fn new(mut out: W, width: u32, height: u32, alpha: bool, transform: OutputTransform,
+           dither: bool)
+           -> io::Result<Self> {
+        try!(out.write_all(b"\x89PNG\r\n\x1a\n"));
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&be_bytes(width));
+        header.extend_from_slice(&be_bytes(height));
+        // 8 bits per channel, RGBA or RGB, and the only compression, filtering and interlacing
+        // methods there are
+        header.extend_from_slice(&[8, if alpha { 6 } else { 2 }, 0, 0, 0]);
+        try!(write_chunk(&mut out, b"IHDR", &header));
+        let chunks = Chunks { out: out, data: Vec::with_capacity(CHUNK_SIZE) };
+        Ok(PngWriter {
+            rows: Some(ZlibEncoder::new(chunks, Compression::Default)),
+            alpha: alpha,
+            transform: transform,
+            dither: dither,
+        })
+    }
+}
+
+impl<W: Write> BandWriter for PngWriter<W> {
+    fn write_band(&mut self, band: &HdrImage) -> io::Result<()> {
+        let (pixels, channels) = if self.alpha {
+            (band.encode_rgba(self.transform, self.dither).into_raw(), 4)
+        } else {
+            (band.encode(self.transform, self.dither).into_raw(), 3)
+        };
+        let rows = self.rows.as_mut().expect("PNG already finished");
+        for row in pixels.chunks(band.width() as usize * channels) {
+            // Each row starts with its filter type, 0 for none
+            try!(rows.write_all(&[0]));
+            try!(rows.write_all(row));
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut chunks = try!(self.rows.take().expect("PNG already finished").finish());
+        try!(chunks.flush_chunk());
+        try!(write_chunk(&mut chunks.out, b"IEND", &[]));
+        chunks.out.flush()
+    }
+}
+
+// Cuts the compressed image data into IDAT chunks of CHUNK_SIZE bytes
+struct Chunks<W: Write> {
+    out: W,
+    data: Vec<u8>,
+}
+
+impl<W: Write> Chunks<W> {
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        if !self.data.is_empty() {
+            try!(write_chunk(&mut self.out, b"IDAT", &self.data));
+            self.data.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Chunks<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = cmp::min(buf.len(), CHUNK_SIZE - self.data.len());
+        self.data.extend_from_slice(&buf[..n]);
+        if self.data.len() == CHUNK_SIZE {
+            try!(self.flush_chunk());
+        }
+        Ok(n)
+    }
+
+    // Chunks are only cut when full, so that flushes of the zlib stream don't make tiny ones
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A PNG chunk: the length of its data, its type, the data, and a CRC of the type and data
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    try!(out.write_all(&be_bytes(data.len() as u32)));
+    try!(out.write_all(kind));
+    try!(out.write_all(data));
+    out.write_all(&be_bytes(crc32(&[&kind[..], data])))
+}
+
+fn be_bytes(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+// The CRC-32 of the bytes of `parts` one after another, bit by bit rather than with a table,
+// which is still fast next to compressing them
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,183 @@
+// Catmull-Clark subdivision surfaces. Each step splits every face of a polygon mesh into quads
+// and moves the vertices towards a smooth limit surface, so a coarse cage of a few hundred quads
+// renders as a smooth model once it's split into triangles. Edges with a face on only one side
+// stay curves through their own ends instead of being pulled in by that face. Texture
+// coordinates are interpolated linearly within each face.
+
+use std::collections::HashMap;
+
+use {Float, Vec3};
+use material::Material;
+use mesh::{remove_degenerate, Triangle, TriangleMesh};
+
+use nalgebra::{cross, Norm};
+
+#[derive(Clone, Debug)]
+pub struct Face {
+    // Indices into the mesh's positions, counterclockwise seen from the front
+    pub corners: Vec<usize>,
+    // Of each corner, if the face has them
+    pub uvs: Option<Vec<(Float, Float)>>,
+}
+
+pub struct PolygonMesh {
+    pub positions: Vec<Vec3>,
+    pub faces: Vec<Face>,
+}
+
+impl PolygonMesh {
+    pub fn new(positions: Vec<Vec3>, faces: Vec<Face>) -> Self {
+        for face in faces.iter() {
+            assert!(face.corners.len() >= 3, "A face needs at least 3 corners");
+            assert!(face.corners.iter().all(|&i| i < positions.len()) &&
+                    face.uvs.as_ref().map_or(true, |uvs| uvs.len() == face.corners.len()),
+                    "Face corners out of range");
+        }
+        PolygonMesh { positions: positions, faces: faces }
+    }
+
+    // One step of subdivision: a face with n corners becomes n quads, each between one of its
+    // corners, the middles of the two edges there and the middle of the face
+    pub fn subdivided(&self) -> PolygonMesh {
+        let zero = Vec3::new(0., 0., 0.);
+        let vertices = self.positions.len();
+        // Each edge once, by its ends, with the faces on either side
+        let mut edge_index = HashMap::new();
+        let mut edges: Vec<(usize, usize, Vec<usize>)> = Vec::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            let n = face.corners.len();
+            for i in 0..n {
+                let key = edge_key(face.corners[i], face.corners[(i + 1) % n]);
+                let e = *edge_index.entry(key).or_insert_with(|| {
+                    edges.push((key.0, key.1, Vec::new()));
+                    edges.len() - 1
+                });
+                edges[e].2.push(f);
+            }
+        }
+
+        let face_points: Vec<Vec3> = self.faces.iter().map(|face| {
+            face.corners.iter().fold(zero, |sum, &i| sum + self.positions[i]) /
+                face.corners.len() as Float
+        }).collect();
+        let edge_points: Vec<Vec3> = edges.iter().map(|&(a, b, ref faces)| {
+            let ends = self.positions[a] + self.positions[b];
+            if faces.len() == 2 {
+                (ends + face_points[faces[0]] + face_points[faces[1]]) / 4.
+            } else {
+                ends / 2.
+            }
+        }).collect();
+
+        // Vertices move towards the average of the faces and edges around them, or along the
+        // boundary if they're on one
+        let mut faces_around = vec![(zero, 0); vertices];
+        for (f, face) in self.faces.iter().enumerate() {
+            for &v in face.corners.iter() {
+                faces_around[v].0 = faces_around[v].0 + face_points[f];
+                faces_around[v].1 += 1;
+            }
+        }
+        let mut edges_around = vec![(zero, 0); vertices];
+        let mut boundary_neighbors = vec![Vec::new(); vertices];
+        for &(a, b, ref faces) in edges.iter() {
+            let middle = (self.positions[a] + self.positions[b]) / 2.;
+            for &(v, other) in [(a, b), (b, a)].iter() {
+                edges_around[v].0 = edges_around[v].0 + middle;
+                edges_around[v].1 += 1;
+                if faces.len() != 2 {
+                    boundary_neighbors[v].push(other);
+                }
+            }
+        }
+        let vertex_points = (0..vertices).map(|v| {
+            let p = self.positions[v];
+            let neighbors = &boundary_neighbors[v];
+            let (face_sum, n) = faces_around[v];
+            let (edge_sum, edges) = edges_around[v];
+            if n == 0 {
+                p
+            } else if neighbors.is_empty() {
+                let n = n as Float;
+                (face_sum / n + edge_sum / edges as Float * 2. + p * (n - 3.)) / n
+            } else if neighbors.len() == 2 {
+                p * 0.75 + (self.positions[neighbors[0]] + self.positions[neighbors[1]]) * 0.125
+            } else {
+                // Where boundaries meet, it stays a sharp corner
+                p
+            }
+        });
+
+        let mut positions: Vec<Vec3> = vertex_points.collect();
+        positions.extend(edge_points);
+        positions.extend(face_points);
+        let mut faces = Vec::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            let n = face.corners.len();
+            let edge = |i: usize| {
+                vertices + edge_index[&edge_key(face.corners[i], face.corners[(i + 1) % n])]
+            };
+            let center = vertices + edges.len() + f;
+            let center_uv = face.uvs.as_ref().map(|uvs| {
+                let sum = uvs.iter().fold((0., 0.), |sum, uv| (sum.0 + uv.0, sum.1 + uv.1));
+                (sum.0 / n as Float, sum.1 / n as Float)
+            });
+            for i in 0..n {
+                let (previous, next) = ((i + n - 1) % n, (i + 1) % n);
+                let uvs = face.uvs.as_ref().map(|uvs| {
+                    vec![uvs[i], middle(uvs[i], uvs[next]), center_uv.unwrap(),
+                         middle(uvs[previous], uvs[i])]
+                });
+                faces.push(Face { corners: vec![face.corners[i], edge(i), center, edge(previous)],
+                                  uvs: uvs });
+            }
+        }
+        PolygonMesh::new(positions, faces)
+    }
+
+    // Splits the faces into triangle fans, shaded smoothly with normals averaged over the faces
+    // around each vertex, weighted by their area
+    pub fn to_triangle_mesh(self, material: Material) -> TriangleMesh {
+        let positions = self.positions;
+        let mut normals = vec![Vec3::new(0., 0., 0.); positions.len()];
+        let mut uvs = Vec::new();
+        let mut triangles = Vec::new();
+        for face in self.faces.iter() {
+            let first_uv = uvs.len();
+            if let Some(ref face_uvs) = face.uvs {
+                uvs.extend(face_uvs.iter().cloned());
+            }
+            for i in 1..face.corners.len() - 1 {
+                let corners = [face.corners[0], face.corners[i], face.corners[i + 1]];
+                let (a, b, c) = (positions[corners[0]], positions[corners[1]],
+                                 positions[corners[2]]);
+                let normal = cross(&(b - a), &(c - a));
+                for &v in corners.iter() {
+                    normals[v] = normals[v] + normal;
+                }
+                triangles.push(Triangle {
+                    positions: corners,
+                    normals: Some(corners),
+                    uvs: face.uvs.as_ref().map(|_| [first_uv, first_uv + i, first_uv + i + 1]),
+                    material: 0,
+                });
+            }
+        }
+        for normal in normals.iter_mut() {
+            // Vertices only of triangles without area, which are left out below
+            if normal.norm() > 0. {
+                *normal = normal.normalize();
+            }
+        }
+        remove_degenerate(&positions, &mut triangles);
+        TriangleMesh::new(positions, normals, uvs, triangles, material)
+    }
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+fn middle(a: (Float, Float), b: (Float, Float)) -> (Float, Float) {
+    ((a.0 + b.0) / 2., (a.1 + b.1) / 2.)
+}
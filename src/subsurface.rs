@@ -0,0 +1,89 @@
+// Subsurface scattering, for materials like skin, wax, marble and milk that light enters and
+// scatters around in before it leaves again, often some way from where it went in. That softens
+// their shading and lets light glow through their thin parts, where a diffuse surface of the
+// same color looks like plastic. The path tracer follows the light on a random walk inside: it
+// enters diffusely, flies a random distance between each two scattering events through a
+// homogeneous medium filling the object, and leaves diffusely where it reaches a surface again.
+// The Whitted integrator shades such materials as plain diffuse ones.
+
+use {Float, Scene, Vec3};
+use ray::{Intersection, Ray, RayKind};
+use sampling;
+
+// Scattering events before light that hasn't left yet counts as absorbed
+const MAX_STEPS: u32 = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Subsurface {
+    radius: Vec3,
+    albedo: Vec3,
+}
+
+impl Subsurface {
+    // `radius` is how far light of each channel gets between scattering events on average, in
+    // scene units, and `albedo` the fraction of each channel left after each, from 0 to 1. The
+    // channel with the largest radius and albedo bleeds furthest, e.g. red in skin
+    pub fn new(radius: Vec3, albedo: Vec3) -> Self {
+        assert!(radius.x > 0. && radius.y > 0. && radius.z > 0.,
+                "Subsurface scattering needs a positive radius");
+        assert!(albedo.x >= 0. && albedo.y >= 0. && albedo.z >= 0. && albedo.x <= 1. &&
+                albedo.y <= 1. && albedo.z <= 1., "Subsurface albedo must be between 0 and 1");
+        Subsurface { radius: radius, albedo: albedo }
+    }
+
+    pub fn radius(&self) -> Vec3 {
+        self.radius
+    }
+
+    pub fn albedo(&self) -> Vec3 {
+        self.albedo
+    }
+}
+
+// Follows the light that `ray` brings into the surface at `hit` through the inside, with the
+// random numbers of path `seed` at `bounce`. Returns where it leaves and the fraction of each
+// channel that does, or None if it's absorbed, or lost because the object isn't closed. Light
+// reaching any surface leaves there, also one of another object inside this one
+pub fn walk(scene: &Scene, subsurface: &Subsurface, ray: &Ray, hit: &Intersection, seed: u32,
+            bounce: u32)
+            -> Option<(Intersection, Vec3)> {
+    let u = |step: u32, k: u32| sampling::uniform(seed, bounce, 16 + 4 * step + k);
+    let inwards = -hit.normal;
+    let mut origin = scene.bias.origin(hit, &inwards);
+    let mut dir = sampling::cosine_hemisphere(&inwards, u(0, 0), u(0, 1));
+    let mut weight = Vec3::new(1., 1., 1.);
+    let r = subsurface.radius;
+    let extinction = [1. / r.x, 1. / r.y, 1. / r.z];
+    let transmittance = |dist: Float| {
+        Vec3::new((-extinction[0] * dist).exp(), (-extinction[1] * dist).exp(),
+                  (-extinction[2] * dist).exp())
+    };
+    for step in 0..MAX_STEPS {
+        // The distance comes from the extinction of a random channel, so the density it was
+        // picked with is the average of the three
+        let channel = ((u(step, 2) * 3.) as usize).min(2);
+        let flight = -(1. - u(step, 3)).ln() / extinction[channel];
+        let next = Ray::new(origin, dir).with_time(ray.time).with_kind(RayKind::Reflection);
+        let exit = match scene.closest_hit(&next) {
+            Some((_, exit)) => exit,
+            None => return None,
+        };
+        if exit.dist <= flight {
+            // Flying at least this far has the chance of the transmittance
+            let t = transmittance(exit.dist);
+            weight = weight * t / ((t.x + t.y + t.z) / 3.);
+            return Some((exit, weight));
+        }
+        let t = transmittance(flight);
+        let density = Vec3::new(extinction[0], extinction[1], extinction[2]) * t;
+        weight = weight * subsurface.albedo * density /
+                 ((density.x + density.y + density.z) / 3.);
+        if weight.x.max(weight.y).max(weight.z) <= 0. {
+            return None;
+        }
+        origin = origin + dir * flight;
+        dir = sampling::uniform_sphere(u(step + 1, 0), u(step + 1, 1));
+    }
+    None
+}
+
@@ -1,26 +1,52 @@
-use std::f32;
 
-use Vec3;
+use {float, Float, Vec3};
+use bounds::Aabb;
 use material::Material;
+use quartic;
 use ray::{Intersection, Ray};
 
 use nalgebra::{dot, cross, Norm};
 
-pub trait Surface {
+pub trait Surface: Send + Sync {
     fn intersect(&self, &Ray) -> Option<Intersection>;
     fn material(&self) -> &Material;
+    // The material at a hit found by `intersect`, which differs from `material` only for meshes
+    // with a material per face
+    fn material_at(&self, _: &Intersection) -> &Material {
+        self.material()
+    }
+    // None for surfaces that extend forever
+    fn bounds(&self) -> Option<Aabb>;
+    // The point with texture coordinates (u, v) in 0..1, for baking. None where the uv layout
+    // doesn't cover (u, v), or if the surface has no finite layout
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection>;
+    // A point spread evenly over the surface for u1, u2 in 0..1, its normal, and the area of the
+    // whole surface, for sampling the light of emissive surfaces. None if the surface can't be
+    // sampled; it then only glows where rays happen to hit it
+    fn sample_area(&self, _: Float, _: Float) -> Option<(Vec3, Vec3, Float)> {
+        None
+    }
     // For debugging
     fn name(&self) -> &'static str;
+    // For scene statistics: the number of triangles of meshes, and memory used beyond the
+    // surface itself
+    fn triangle_count(&self) -> usize {
+        0
+    }
+    fn heap_size(&self) -> usize {
+        0
+    }
 }
 
 pub struct Sphere {
     pos: Vec3,
-    radius: f32,
+    radius: Float,
     material: Material,
 }
 
 impl Sphere {
-    pub fn new(pos: Vec3, radius: f32, material: Material) -> Self {
+    pub fn new(pos: Vec3, radius: Float, material: Material) -> Self {
+        assert!(radius > 0., "Spheres need a positive radius, not {}", radius);
         Sphere { pos: pos, radius: radius, material: material }
     }
 }
@@ -34,17 +60,52 @@ impl Surface for Sphere {
         &self.material
     }
 
+    fn bounds(&self) -> Option<Aabb> {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        Some(Aabb::new(self.pos - r, self.pos + r))
+    }
+
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        // The inverse of the mapping in intersect, which only covers v in 0.25..0.75
+        let y = ((0.5 - v) * float::consts::PI).tan();
+        if y.abs() > 1. {
+            return None;
+        }
+        let angle = (u - 0.5) * 2. * float::consts::PI;
+        let r = (1. - y * y).sqrt();
+        let center_vec = Vec3::new(r * angle.cos(), y, r * angle.sin());
+        let pos = self.pos - center_vec * self.radius;
+        Some(Intersection::new(pos, -center_vec, 0., u, v))
+    }
+
+    fn sample_area(&self, u1: Float, u2: Float) -> Option<(Vec3, Vec3, Float)> {
+        let y = 1. - 2. * u1;
+        let r = (1. - y * y).max(0.).sqrt();
+        let angle = 2. * float::consts::PI * u2;
+        let normal = Vec3::new(r * angle.cos(), y, r * angle.sin());
+        let area = 4. * float::consts::PI * self.radius * self.radius;
+        Some((self.pos + normal * self.radius, normal, area))
+    }
+
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         let center_offset = ray.origin - self.pos;
-        let b = 2. * dot(&ray.dir, &center_offset);
-        let c = center_offset.norm_squared() - self.radius * self.radius;
+        let b = dot(&ray.dir, &center_offset);
 
-        let discriminant = b * b - 4. * c;
+        // Take the discriminant from the distance between the center and the closest point on
+        // the ray, rather than |offset|^2 - b^2, which loses all precision in f32 for small or
+        // distant spheres
+        let closest = center_offset - ray.dir * b;
+        let discriminant = self.radius * self.radius - closest.norm_squared();
 
         if discriminant >= 0. {
-            let disc_sqrt = discriminant.sqrt();
-            let d1 = 0.5 * (-b + disc_sqrt);
-            let d2 = 0.5 * (-b - disc_sqrt);
+            // Numerically stable roots of d^2 + 2bd + c, avoiding the subtraction of two
+            // nearly equal values
+            let c = center_offset.norm_squared() - self.radius * self.radius;
+            let q = -b - b.signum() * discriminant.sqrt();
+            if q == 0. {
+                return None;
+            }
+            let (d1, d2) = if c / q > q { (c / q, q) } else { (q, c / q) };
 
             // d1 should always be larger than d2, we want the smallest positive distance
             let d = if d2 > 0. {
@@ -73,10 +134,21 @@ impl Surface for Sphere {
             };
 
             let center_vec = (self.pos - pos).normalize();
-            let u = 0.5 + center_vec.z.atan2(center_vec.x) / (2. * f32::consts::PI);
-            let v = 0.5 - center_vec.y.atan() / f32::consts::PI;
+            let u = 0.5 + center_vec.z.atan2(center_vec.x) / (2. * float::consts::PI);
+            let v = 0.5 - center_vec.y.atan() / float::consts::PI;
 
-            Some(Intersection::new(pos, normal, d, u, v))
+            // u turns around the y axis, and v follows the arctangent of the height, neither of
+            // which changes smoothly at the poles
+            let hit = Intersection::new(pos, normal, d, u, v);
+            let (y, around) = (center_vec.y, 1. - center_vec.y * center_vec.y);
+            if around < 1e-6 {
+                return Some(hit);
+            }
+            let pi = float::consts::PI;
+            let dpdu = Vec3::new(center_vec.z, 0., -center_vec.x) * 2. * pi * self.radius;
+            let up = Vec3::new(-y * center_vec.x / around, 1., -y * center_vec.z / around);
+            let dpdv = up * pi * (1. + y * y) * self.radius;
+            Some(hit.with_uv_derivatives(dpdu, dpdv))
         } else {
             None
         }
@@ -87,11 +159,55 @@ pub struct Plane {
     point: Vec3,
     normal: Vec3,
     material: Material,
+    // Where the pattern is on, hits get the second material, see material_at
+    pattern: Option<(PlanePattern, Material)>,
 }
 
 impl Plane {
     pub fn new(point: Vec3, normal: Vec3, material: Material) -> Self {
-        Plane { point: point, normal: normal, material: material }
+        Plane { point: point, normal: normal.normalize(), material: material, pattern: None }
+    }
+
+    // Lays `pattern` over the plane in `material`, by the plane's texture coordinates, e.g. for
+    // the checkered floor of a test scene
+    pub fn with_pattern(mut self, pattern: PlanePattern, material: Material) -> Self {
+        match pattern {
+            PlanePattern::Checkerboard { size } => {
+                assert!(size > 0., "Checkerboard squares need a positive size, not {}", size);
+            }
+            PlanePattern::Grid { size, width } => {
+                assert!(width > 0. && width < size,
+                        "Grid lines must be thinner than the {} between them, not {}", size,
+                        width);
+            }
+        }
+        self.pattern = Some((pattern, material));
+        self
+    }
+}
+
+// Patterns of a second material over a plane, which unlike textures can differ in everything,
+// e.g. black squares that are matte next to white ones that reflect
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlanePattern {
+    // Squares `size` wide alternating between the plane's material and the pattern's
+    Checkerboard { size: Float },
+    // Lines `width` wide along both axes, `size` apart
+    Grid { size: Float, width: Float },
+}
+
+impl PlanePattern {
+    // Whether the pattern is on at texture coordinates u, v
+    pub fn covers(&self, u: Float, v: Float) -> bool {
+        match *self {
+            PlanePattern::Checkerboard { size } => {
+                ((u / size).floor() + (v / size).floor()) % 2. != 0.
+            }
+            PlanePattern::Grid { size, width } => {
+                let on_line = |x: Float| (x / size - (x / size).round()).abs() * size < width / 2.;
+                on_line(u) || on_line(v)
+            }
+        }
     }
 }
 
@@ -104,6 +220,21 @@ impl Surface for Plane {
         &self.material
     }
 
+    fn material_at(&self, hit: &Intersection) -> &Material {
+        match self.pattern {
+            Some((_, ref material)) if hit.material == 1 => material,
+            _ => &self.material,
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    fn surface_point(&self, _: Float, _: Float) -> Option<Intersection> {
+        None
+    }
+
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         let denom = dot(&ray.dir, &self.normal);
         if denom == 0. {
@@ -118,22 +249,743 @@ impl Surface for Plane {
             let v_axis = cross(&u_axis, n);
             let u = dot(&pos, &u_axis);
             let v = dot(&pos, &v_axis);
+            let (index, material) = match self.pattern {
+                Some((ref pattern, ref material)) if pattern.covers(u, v) => (1, material),
+                _ => (0, &self.material),
+            };
 
-            let normal = if self.material.has_normal_map() {
-                self.material.apply_normal_map(&self.normal, &pos)
+            let normal = if material.has_normal_map() {
+                material.apply_normal_map(&self.normal, &pos)
             } else {
                 self.normal
             };
 
-            let pos = if self.material.has_displacement_map() {
-                self.material.apply_displacement_map(&pos)
+            let pos = if material.has_displacement_map() {
+                material.apply_displacement_map(&pos)
             } else {
                 pos
             };
 
-            Some(Intersection::new(pos, normal, d, u, v))
+            let hit = Intersection::new(pos, normal, d, u, v).with_uv_derivatives(u_axis, v_axis);
+            Some(Intersection { material: index, ..hit })
         } else {
             None
         }
     }
 }
+
+// Two directions perpendicular to `axis` and each other, for the uv layout of round surfaces
+fn perpendicular_axes(axis: &Vec3) -> (Vec3, Vec3) {
+    let helper = if axis.x.abs() < 0.9 { Vec3::new(1., 0., 0.) } else { Vec3::new(0., 1., 0.) };
+    let u_axis = cross(axis, &helper).normalize();
+    (u_axis, cross(axis, &u_axis))
+}
+
+// u in 0..1 for the angle of `offset` around `axis`
+fn angle_u(axis: &Vec3, offset: &Vec3) -> Float {
+    let (u_axis, v_axis) = perpendicular_axes(axis);
+    0.5 + dot(offset, &v_axis).atan2(dot(offset, &u_axis)) / (2. * float::consts::PI)
+}
+
+// The unit direction at angle_u `u` around `axis`
+fn angle_dir(axis: &Vec3, u: Float) -> Vec3 {
+    let (u_axis, v_axis) = perpendicular_axes(axis);
+    let angle = (u - 0.5) * 2. * float::consts::PI;
+    u_axis * angle.cos() + v_axis * angle.sin()
+}
+
+// The bounds of a disk of `radius` around `center`, facing along the unit vector `normal`
+fn disk_bounds(center: &Vec3, normal: &Vec3, radius: Float) -> Aabb {
+    let extent = |n: Float| radius * (1. - n * n).max(0.).sqrt();
+    let e = Vec3::new(extent(normal.x), extent(normal.y), extent(normal.z));
+    Aabb::new(*center - e, *center + e)
+}
+
+// How the position changes with u and with v across the triangle between `corners`, with
+// texture coordinates `uvs` there, or None if they don't span any of the texture
+pub fn uv_derivatives(corners: &[Vec3; 3], uvs: &[(Float, Float); 3]) -> Option<(Vec3, Vec3)> {
+    let (e1, e2) = (corners[1] - corners[0], corners[2] - corners[0]);
+    let (du1, dv1) = (uvs[1].0 - uvs[0].0, uvs[1].1 - uvs[0].1);
+    let (du2, dv2) = (uvs[2].0 - uvs[0].0, uvs[2].1 - uvs[0].1);
+    let det = du1 * dv2 - du2 * dv1;
+    if det == 0. {
+        return None;
+    }
+    Some(((e1 * dv2 - e2 * dv1) / det, (e2 * du1 - e1 * du2) / det))
+}
+
+// The barycentric coordinates of the second and third corners where (u, v) falls in the triangle
+// of texture coordinates `uvs`, or None outside it
+pub fn uv_barycentric(uvs: &[(Float, Float); 3], u: Float, v: Float) -> Option<(Float, Float)> {
+    let (t0, t1, t2) = (uvs[0], uvs[1], uvs[2]);
+    let (e1, e2) = ((t1.0 - t0.0, t1.1 - t0.1), (t2.0 - t0.0, t2.1 - t0.1));
+    let p = (u - t0.0, v - t0.1);
+    let det = e1.0 * e2.1 - e2.0 * e1.1;
+    if det == 0. {
+        return None;
+    }
+    let b1 = (p.0 * e2.1 - e2.0 * p.1) / det;
+    let b2 = (e1.0 * p.1 - p.0 * e1.1) / det;
+    if b1 < 0. || b2 < 0. || b1 + b2 > 1. {
+        return None;
+    }
+    Some((b1, b2))
+}
+
+// A grid over the 0..1 square of a mesh's texture coordinates, for finding the triangle of its
+// uv layout that covers a point when baking. Each cell lists the triangles whose uv bounds
+// reach into it, as their index and corner uvs
+pub struct UvGrid {
+    size: usize,
+    cells: Vec<Vec<usize>>,
+    triangles: Vec<(usize, [(Float, Float); 3])>,
+}
+
+impl UvGrid {
+    // Over the triangles with texture coordinates, given as their index and corner uvs
+    pub fn new(triangles: Vec<(usize, [(Float, Float); 3])>) -> Self {
+        // Around one triangle per cell for layouts that fill the square
+        let size = ((triangles.len() as Float).sqrt().ceil() as usize).max(1).min(1024);
+        let mut cells = vec![Vec::new(); size * size];
+        let cell = |t: Float| ((t * size as Float).floor().max(0.) as usize).min(size - 1);
+        for (i, &(_, uvs)) in triangles.iter().enumerate() {
+            let (u0, u1) = (uvs[0].0.min(uvs[1].0).min(uvs[2].0),
+                            uvs[0].0.max(uvs[1].0).max(uvs[2].0));
+            let (v0, v1) = (uvs[0].1.min(uvs[1].1).min(uvs[2].1),
+                            uvs[0].1.max(uvs[1].1).max(uvs[2].1));
+            if u1 < 0. || u0 > 1. || v1 < 0. || v0 > 1. {
+                continue;
+            }
+            for y in cell(v0)..cell(v1) + 1 {
+                for x in cell(u0)..cell(u1) + 1 {
+                    cells[y * size + x].push(i);
+                }
+            }
+        }
+        UvGrid { size: size, cells: cells, triangles: triangles }
+    }
+
+    // The index of the first triangle covering (u, v), and the barycentric coordinates of its
+    // second and third corners there
+    pub fn find(&self, u: Float, v: Float) -> Option<(usize, Float, Float)> {
+        if !(u >= 0. && u <= 1. && v >= 0. && v <= 1.) {
+            return None;
+        }
+        let cell = |t: Float| ((t * self.size as Float) as usize).min(self.size - 1);
+        self.cells[cell(v) * self.size + cell(u)].iter().filter_map(|&i| {
+            let (triangle, ref uvs) = self.triangles[i];
+            uv_barycentric(uvs, u, v).map(|(b1, b2)| (triangle, b1, b2))
+        }).next()
+    }
+}
+
+// From `pos` on the triangle between `corners` to the closest point on its edges
+pub fn edge_offset(pos: &Vec3, corners: &[Vec3; 3]) -> Vec3 {
+    let mut closest = Vec3::new(float::INFINITY, 0., 0.);
+    for i in 0..3 {
+        let (a, b) = (corners[i], corners[(i + 1) % 3]);
+        let edge = b - a;
+        let length_squared = edge.norm_squared();
+        let t = if length_squared > 0. { dot(&(*pos - a), &edge) / length_squared } else { 0. };
+        let offset = a + edge * t.max(0.).min(1.) - *pos;
+        if offset.norm_squared() < closest.norm_squared() {
+            closest = offset;
+        }
+    }
+    closest
+}
+
+// Where smooth shading's shadows should start from at `pos` on the triangle between `corners`,
+// with barycentric coordinates `b` and unit vertex normals `normals`, as an offset from it. The
+// flat triangle cuts under the curved surface the normals describe, so near the shadow
+// terminator of a coarse mesh, rays towards a light would hit the next triangle over and leave
+// its facets in blocky shadow. Following Hanika's "Hacking the shadow terminator", the point
+// is moved up to each corner's tangent plane where it lies below it, blended over the corners.
+// Corners the point is above, where the surface curves inwards, don't move it
+pub fn terminator_offset(pos: &Vec3, corners: &[Vec3; 3], normals: &[Vec3; 3], b: &[Float; 3])
+                         -> Vec3 {
+    let mut offset = Vec3::new(0., 0., 0.);
+    for i in 0..3 {
+        let below = dot(&(*pos - corners[i]), &normals[i]).min(0.);
+        offset = offset - normals[i] * (below * b[i]);
+    }
+    offset
+}
+
+// The hit with the material's normal and displacement maps applied
+fn mapped_hit(material: &Material, pos: Vec3, normal: Vec3, d: Float, u: Float, v: Float)
+              -> Intersection {
+    let normal = if material.has_normal_map() {
+        material.apply_normal_map(&normal, &pos)
+    } else {
+        normal
+    };
+    let pos = if material.has_displacement_map() {
+        material.apply_displacement_map(&pos)
+    } else {
+        pos
+    };
+    Intersection::new(pos, normal, d, u, v)
+}
+
+// The nearest positive root of a*d^2 + b*d + c
+fn nearest_root(a: Float, b: Float, c: Float, valid: &Fn(Float) -> bool) -> Option<Float> {
+    let (d1, d2) = if a.abs() < 1e-9 {
+        if b == 0. {
+            return None;
+        }
+        (-c / b, -c / b)
+    } else {
+        let discriminant = b * b - 4. * a * c;
+        if discriminant < 0. {
+            return None;
+        }
+        // Numerically stable, as for spheres
+        let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+        if q == 0. {
+            return None;
+        }
+        let (r1, r2) = (q / a, c / q);
+        if r1 < r2 { (r1, r2) } else { (r2, r1) }
+    };
+    [d1, d2].iter().cloned().find(|&d| d > 0. && valid(d))
+}
+
+fn nearest(a: Option<(Float, Vec3)>, b: Option<(Float, Vec3)>) -> Option<(Float, Vec3)> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a.0 <= b.0 { a } else { b }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+// The distance to a disk, and the hit position
+fn intersect_disk(ray: &Ray, center: &Vec3, normal: &Vec3, radius: Float) -> Option<(Float, Vec3)> {
+    let denom = dot(&ray.dir, normal);
+    if denom == 0. {
+        return None;
+    }
+    let d = dot(normal, &(*center - ray.origin)) / denom;
+    let pos = ray.origin + ray.dir * d;
+    if d > 0. && (pos - *center).norm_squared() <= radius * radius {
+        Some((d, pos))
+    } else {
+        None
+    }
+}
+
+// A flat round disk, e.g. a tabletop or a lid
+pub struct Disk {
+    center: Vec3,
+    normal: Vec3,
+    radius: Float,
+    material: Material,
+}
+
+impl Disk {
+    pub fn new(center: Vec3, normal: Vec3, radius: Float, material: Material) -> Self {
+        Disk { center: center, normal: normal.normalize(), radius: radius, material: material }
+    }
+}
+
+impl Surface for Disk {
+    fn name(&self) -> &'static str {
+        "Disk"
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(disk_bounds(&self.center, &self.normal, self.radius))
+    }
+
+    // u goes around the disk and v out from its center
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        let pos = self.center + angle_dir(&self.normal, u) * (v * self.radius);
+        Some(Intersection::new(pos, self.normal, 0., u, v))
+    }
+
+    fn sample_area(&self, u1: Float, u2: Float) -> Option<(Vec3, Vec3, Float)> {
+        let pos = self.center + angle_dir(&self.normal, u2) * (u1.sqrt() * self.radius);
+        Some((pos, self.normal, float::consts::PI * self.radius * self.radius))
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        intersect_disk(ray, &self.center, &self.normal, self.radius).map(|(d, pos)| {
+            let offset = pos - self.center;
+            let u = angle_u(&self.normal, &offset);
+            mapped_hit(&self.material, pos, self.normal, d, u, offset.norm() / self.radius)
+                .with_tangent(cross(&self.normal, &offset))
+        })
+    }
+}
+
+// A flat triangle between three corners, for small bits of geometry placed right in a scene
+// rather than loaded from a mesh file. Normals and texture coordinates given for the corners
+// are interpolated across it by the hit's barycentric coordinates; without them it's flat, with
+// u going from the first corner to the second and v from the first to the third
+pub struct Triangle {
+    corners: [Vec3; 3],
+    normals: Option<[Vec3; 3]>,
+    uvs: [(Float, Float); 3],
+    // Facing the way the corners go around counterclockwise
+    face_normal: Vec3,
+    area: Float,
+    material: Material,
+}
+
+impl Triangle {
+    pub fn new(corners: [Vec3; 3], material: Material) -> Self {
+        let normal = cross(&(corners[1] - corners[0]), &(corners[2] - corners[0]));
+        assert!(normal.norm_squared() > 0., "A triangle's corners can't lie on a line");
+        Triangle {
+            corners: corners,
+            normals: None,
+            uvs: [(0., 0.), (1., 0.), (0., 1.)],
+            face_normal: normal.normalize(),
+            area: normal.norm() / 2.,
+            material: material,
+        }
+    }
+
+    pub fn with_normals(mut self, normals: [Vec3; 3]) -> Self {
+        assert!(normals.iter().all(|n| n.norm_squared() > 0.), "Corner normals can't be zero");
+        self.normals = Some([normals[0].normalize(), normals[1].normalize(),
+                             normals[2].normalize()]);
+        self
+    }
+
+    pub fn with_uvs(mut self, uvs: [(Float, Float); 3]) -> Self {
+        self.uvs = uvs;
+        self
+    }
+
+    // Möller-Trumbore: the distance to the hit and its barycentric coordinates for the second
+    // and third corners
+    fn hit(&self, ray: &Ray) -> Option<(Float, Float, Float)> {
+        let a = self.corners[0];
+        let (edge1, edge2) = (self.corners[1] - a, self.corners[2] - a);
+        let pvec = cross(&ray.dir, &edge2);
+        let det = dot(&edge1, &pvec);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let tvec = ray.origin - a;
+        let b1 = dot(&tvec, &pvec) / det;
+        let qvec = cross(&tvec, &edge1);
+        let b2 = dot(&ray.dir, &qvec) / det;
+        let dist = dot(&edge2, &qvec) / det;
+        if b1 >= 0. && b2 >= 0. && b1 + b2 <= 1. && dist > 0. { Some((dist, b1, b2)) } else { None }
+    }
+
+    // The point at barycentric coordinates b1, b2, with its interpolated normal and uv
+    fn point(&self, b1: Float, b2: Float) -> (Vec3, Vec3, Float, Float) {
+        let b0 = 1. - b1 - b2;
+        let c = &self.corners;
+        let pos = c[0] * b0 + c[1] * b1 + c[2] * b2;
+        let normal = match self.normals {
+            Some(n) => {
+                let smooth = n[0] * b0 + n[1] * b1 + n[2] * b2;
+                if smooth.norm_squared() > 0. { smooth.normalize() } else { self.face_normal }
+            }
+            None => self.face_normal,
+        };
+        let t = &self.uvs;
+        (pos, normal, t[0].0 * b0 + t[1].0 * b1 + t[2].0 * b2,
+         t[0].1 * b0 + t[1].1 * b1 + t[2].1 * b2)
+    }
+}
+
+impl Surface for Triangle {
+    fn name(&self) -> &'static str {
+        "Triangle"
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let c = &self.corners;
+        Some(Aabb::new(c[0], c[0]).union(&Aabb::new(c[1], c[1])).union(&Aabb::new(c[2], c[2])))
+    }
+
+    // Where (u, v) falls in the triangle of the corners' texture coordinates
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        let (b1, b2) = match uv_barycentric(&self.uvs, u, v) {
+            Some(b) => b,
+            None => return None,
+        };
+        let (pos, normal, _, _) = self.point(b1, b2);
+        Some(Intersection::new(pos, normal, 0., u, v))
+    }
+
+    fn sample_area(&self, u1: Float, u2: Float) -> Option<(Vec3, Vec3, Float)> {
+        let s = u1.sqrt();
+        let (pos, _, _, _) = self.point(s * (1. - u2), s * u2);
+        Some((pos, self.face_normal, self.area))
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        self.hit(ray).map(|(d, b1, b2)| {
+            let (_, normal, u, v) = self.point(b1, b2);
+            let hit = mapped_hit(&self.material, ray.origin + ray.dir * d, normal, d, u, v);
+            match uv_derivatives(&self.corners, &self.uvs) {
+                Some((dpdu, dpdv)) => hit.with_uv_derivatives(dpdu, dpdv),
+                None => hit,
+            }
+        })
+    }
+}
+
+// A flat four sided polygon, e.g. a wall or a sign, made of the triangles of its first three
+// corners and of the first, third and fourth, so the corners go around it in order. By default
+// u goes from the first corner to the second and v from the first to the fourth, and like a
+// Triangle it can be given normals and texture coordinates for its corners
+pub struct Quad {
+    first: Triangle,
+    second: Triangle,
+}
+
+impl Quad {
+    pub fn new(corners: [Vec3; 4], material: Material) -> Self {
+        let c = corners;
+        Quad {
+            first: Triangle::new([c[0], c[1], c[2]], material.clone())
+                .with_uvs([(0., 0.), (1., 0.), (1., 1.)]),
+            second: Triangle::new([c[0], c[2], c[3]], material)
+                .with_uvs([(0., 0.), (1., 1.), (0., 1.)]),
+        }
+    }
+
+    pub fn with_normals(self, normals: [Vec3; 4]) -> Self {
+        let n = normals;
+        Quad {
+            first: self.first.with_normals([n[0], n[1], n[2]]),
+            second: self.second.with_normals([n[0], n[2], n[3]]),
+        }
+    }
+
+    pub fn with_uvs(self, uvs: [(Float, Float); 4]) -> Self {
+        let t = uvs;
+        Quad {
+            first: self.first.with_uvs([t[0], t[1], t[2]]),
+            second: self.second.with_uvs([t[0], t[2], t[3]]),
+        }
+    }
+}
+
+impl Surface for Quad {
+    fn name(&self) -> &'static str {
+        "Quad"
+    }
+
+    fn material(&self) -> &Material {
+        &self.first.material
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        match (self.first.bounds(), self.second.bounds()) {
+            (Some(first), Some(second)) => Some(first.union(&second)),
+            _ => None,
+        }
+    }
+
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        self.first.surface_point(u, v).or_else(|| self.second.surface_point(u, v))
+    }
+
+    // A point of either half in proportion to its area
+    fn sample_area(&self, u1: Float, u2: Float) -> Option<(Vec3, Vec3, Float)> {
+        let (first, second) = (self.first.area, self.second.area);
+        let t = u1 * (first + second);
+        let sample = if t < first {
+            self.first.sample_area(t / first, u2)
+        } else {
+            self.second.sample_area(((t - first) / second).min(1.), u2)
+        };
+        sample.map(|(pos, normal, _)| (pos, normal, first + second))
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        match (self.first.intersect(ray), self.second.intersect(ray)) {
+            (Some(a), Some(b)) => Some(if a.dist <= b.dist { a } else { b }),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+// A closed cylinder from the center of its base to the center of its top, capped with disks at
+// both ends
+pub struct Cylinder {
+    base: Vec3,
+    // Unit vector from the base to the top
+    axis: Vec3,
+    height: Float,
+    radius: Float,
+    material: Material,
+}
+
+impl Cylinder {
+    pub fn new(base: Vec3, top: Vec3, radius: Float, material: Material) -> Self {
+        let height = (top - base).norm();
+        assert!(height > 0., "Cylinders need a top apart from their base");
+        Cylinder { base: base, axis: (top - base) / height, height: height, radius: radius,
+                   material: material }
+    }
+}
+
+impl Surface for Cylinder {
+    fn name(&self) -> &'static str {
+        "Cylinder"
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let top = self.base + self.axis * self.height;
+        Some(disk_bounds(&self.base, &self.axis, self.radius)
+             .union(&disk_bounds(&top, &self.axis, self.radius)))
+    }
+
+    // The side only: u goes around the axis and v up from the base
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        let out = angle_dir(&self.axis, u);
+        let pos = self.base + self.axis * (v * self.height) + out * self.radius;
+        Some(Intersection::new(pos, out, 0., u, v))
+    }
+
+    // The side and both caps, each in proportion to its area
+    fn sample_area(&self, u1: Float, u2: Float) -> Option<(Vec3, Vec3, Float)> {
+        let side = 2. * float::consts::PI * self.radius * self.height;
+        let cap = float::consts::PI * self.radius * self.radius;
+        let area = side + 2. * cap;
+        let t = u1 * area;
+        let sample = if t < side {
+            let out = angle_dir(&self.axis, u2);
+            (self.base + self.axis * (t / side * self.height) + out * self.radius, out)
+        } else {
+            // Reuses what's left of u1 for the distance from the center of the cap
+            let (center, normal, r) = if t < side + cap {
+                (self.base, -self.axis, (t - side) / cap)
+            } else {
+                (self.base + self.axis * self.height, self.axis, (t - side - cap) / cap)
+            };
+            (center + angle_dir(&self.axis, u2) * (r.min(1.).sqrt() * self.radius), normal)
+        };
+        Some((sample.0, sample.1, area))
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        // Solve for the distance to the infinite cylinder, ignoring the parts along the axis
+        let offset = ray.origin - self.base;
+        let dir_across = ray.dir - self.axis * dot(&ray.dir, &self.axis);
+        let offset_across = offset - self.axis * dot(&offset, &self.axis);
+        let a = dir_across.norm_squared();
+        let b = 2. * dot(&dir_across, &offset_across);
+        let c = offset_across.norm_squared() - self.radius * self.radius;
+        let height = |d: Float| dot(&(offset + ray.dir * d), &self.axis);
+        let side = nearest_root(a, b, c, &|d| {
+            let h = height(d);
+            h >= 0. && h <= self.height
+        }).map(|d| {
+            let pos = ray.origin + ray.dir * d;
+            let across = pos - self.base - self.axis * height(d);
+            (d, across / self.radius)
+        });
+
+        let top = self.base + self.axis * self.height;
+        let base_cap = intersect_disk(ray, &self.base, &self.axis, self.radius)
+            .map(|(d, _)| (d, -self.axis));
+        let top_cap = intersect_disk(ray, &top, &self.axis, self.radius)
+            .map(|(d, _)| (d, self.axis));
+
+        nearest(side, nearest(base_cap, top_cap)).map(|(d, normal)| {
+            let pos = ray.origin + ray.dir * d;
+            let offset = pos - self.base;
+            let u = angle_u(&self.axis, &offset);
+            let v = dot(&offset, &self.axis) / self.height;
+            mapped_hit(&self.material, pos, normal.normalize(), d, u, v)
+                .with_tangent(cross(&self.axis, &offset))
+        })
+    }
+}
+
+// A closed cone from a disk of `radius` around the center of its base to its tip
+pub struct Cone {
+    base: Vec3,
+    // Unit vector from the base to the tip
+    axis: Vec3,
+    height: Float,
+    radius: Float,
+    material: Material,
+}
+
+impl Cone {
+    pub fn new(base: Vec3, tip: Vec3, radius: Float, material: Material) -> Self {
+        let height = (tip - base).norm();
+        assert!(height > 0., "Cones need a tip apart from their base");
+        Cone { base: base, axis: (tip - base) / height, height: height, radius: radius,
+               material: material }
+    }
+
+    // The slope of the side: how much the radius shrinks per unit of height
+    fn slope(&self) -> Float {
+        self.radius / self.height
+    }
+}
+
+impl Surface for Cone {
+    fn name(&self) -> &'static str {
+        "Cone"
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let tip = self.base + self.axis * self.height;
+        Some(disk_bounds(&self.base, &self.axis, self.radius).union(&Aabb::new(tip, tip)))
+    }
+
+    // The side only: u goes around the axis and v up from the base
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        let out = angle_dir(&self.axis, u);
+        let pos = self.base + self.axis * (v * self.height) + out * (self.radius * (1. - v));
+        let normal = (out + self.axis * self.slope()).normalize();
+        Some(Intersection::new(pos, normal, 0., u, v))
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        // Points on the side are `slope * (height - h)` from the axis at height h
+        let k2 = self.slope() * self.slope();
+        let offset = ray.origin - self.base;
+        let (dir_along, offset_along) = (dot(&ray.dir, &self.axis), dot(&offset, &self.axis));
+        let dir_across = ray.dir - self.axis * dir_along;
+        let offset_across = offset - self.axis * offset_along;
+        let below_tip = self.height - offset_along;
+        let a = dir_across.norm_squared() - k2 * dir_along * dir_along;
+        let b = 2. * (dot(&dir_across, &offset_across) + k2 * dir_along * below_tip);
+        let c = offset_across.norm_squared() - k2 * below_tip * below_tip;
+        let height = |d: Float| offset_along + dir_along * d;
+        // The equation also has a mirrored cone above the tip
+        let side = nearest_root(a, b, c, &|d| {
+            let h = height(d);
+            h >= 0. && h <= self.height
+        }).map(|d| {
+            let across = offset_across + dir_across * d;
+            let out = across.normalize();
+            (d, out + self.axis * self.slope())
+        });
+        let base_cap = intersect_disk(ray, &self.base, &self.axis, self.radius)
+            .map(|(d, _)| (d, -self.axis));
+
+        nearest(side, base_cap).map(|(d, normal)| {
+            let pos = ray.origin + ray.dir * d;
+            let offset = pos - self.base;
+            let u = angle_u(&self.axis, &offset);
+            let v = dot(&offset, &self.axis) / self.height;
+            mapped_hit(&self.material, pos, normal.normalize(), d, u, v)
+                .with_tangent(cross(&self.axis, &offset))
+        })
+    }
+}
+
+// A ring around `center`, facing along `normal`: the points `minor_radius` from the circle of
+// `radius` around the center
+pub struct Torus {
+    center: Vec3,
+    // Unit vector along the axis through the hole
+    axis: Vec3,
+    radius: Float,
+    minor_radius: Float,
+    material: Material,
+}
+
+impl Torus {
+    pub fn new(center: Vec3, normal: Vec3, radius: Float, minor_radius: Float, material: Material)
+               -> Self {
+        assert!(minor_radius > 0. && minor_radius <= radius,
+                "Tori need a minor radius in 0..radius, not {}", minor_radius);
+        Torus { center: center, axis: normal.normalize(), radius: radius,
+                minor_radius: minor_radius, material: material }
+    }
+
+    // The unit vectors from the axis towards `pos`, and to `pos` from the nearest point on the
+    // circle through the tube
+    fn directions(&self, pos: &Vec3) -> (Vec3, Vec3) {
+        let offset = *pos - self.center;
+        let across = offset - self.axis * dot(&offset, &self.axis);
+        let out = if across.norm_squared() > 0. {
+            across.normalize()
+        } else {
+            perpendicular_axes(&self.axis).0
+        };
+        (out, (offset - out * self.radius).normalize())
+    }
+}
+
+impl Surface for Torus {
+    fn name(&self) -> &'static str {
+        "Torus"
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let ring = disk_bounds(&self.center, &self.axis, self.radius);
+        let r = Vec3::new(self.minor_radius, self.minor_radius, self.minor_radius);
+        Some(Aabb::new(ring.min - r, ring.max + r))
+    }
+
+    // u goes around the axis and v around the tube, from its inside
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        let out = angle_dir(&self.axis, u);
+        let angle = (v - 0.5) * 2. * float::consts::PI;
+        let normal = -out * angle.cos() - self.axis * angle.sin();
+        let pos = self.center + out * self.radius + normal * self.minor_radius;
+        Some(Intersection::new(pos, normal, 0., u, v))
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        // Start from the bounding sphere, since the roots lose precision far from the torus
+        let outer = (self.radius + self.minor_radius) as f64;
+        let offset = ray.origin - self.center;
+        let b = dot(&ray.dir, &offset) as f64;
+        let closest = (offset - ray.dir * b as Float).norm_squared() as f64;
+        if closest > outer * outer {
+            return None;
+        }
+        let start = (-b - (outer * outer - closest).sqrt()).max(0.);
+        let offset = offset + ray.dir * start as Float;
+
+        // With p = offset + t*dir for the unit dir, solve
+        // (|p|^2 - R^2 - r^2)^2 = 4R^2 (r^2 - (p.axis)^2)
+        let (big, small) = (self.radius as f64, self.minor_radius as f64);
+        let f = dot(&offset, &ray.dir) as f64;
+        let k = offset.norm_squared() as f64 - big * big - small * small;
+        let (dir_along, offset_along) =
+            (dot(&ray.dir, &self.axis) as f64, dot(&offset, &self.axis) as f64);
+        let four_r2 = 4. * big * big;
+        let roots = quartic::solve_quartic(
+            1., 4. * f, 4. * f * f + 2. * k + four_r2 * dir_along * dir_along,
+            4. * f * k + 2. * four_r2 * offset_along * dir_along,
+            k * k + four_r2 * (offset_along * offset_along - small * small));
+        roots.iter().map(|&t| (start + t) as Float).find(|&d| d > 0.).map(|d| {
+            let pos = ray.origin + ray.dir * d;
+            let (out, normal) = self.directions(&pos);
+            let u = angle_u(&self.axis, &(pos - self.center));
+            let angle = (-dot(&normal, &self.axis)).atan2(-dot(&normal, &out));
+            let v = 0.5 + angle / (2. * float::consts::PI);
+            mapped_hit(&self.material, pos, normal, d, u, v)
+                .with_tangent(cross(&self.axis, &(pos - self.center)))
+        })
+    }
+}
@@ -1,25 +1,38 @@
-use Vec3;
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
 
-use image::{self, ImageRgb8, RgbImage};
+use {Float, Vec3};
+use color::{srgb, srgb_inverse};
+
+use image::{self, ImageRgb8, Rgb, RgbImage};
+
+// Colors are linear, from 0 to 1 in each channel, and scale the material's color
+pub trait Texture: Send + Sync {
+    fn color(&self, u: Float, v: Float) -> Vec3;
+
+    // The average color over a square `footprint` wide in uv around u, v, for lookups that
+    // cover many texels, e.g. on a distant floor. Textures without mipmaps just sample u, v
+    fn color_filtered(&self, u: Float, v: Float, _footprint: Float) -> Vec3 {
+        self.color(u, v)
+    }
 
-pub trait Texture {
-    fn color(&self, u: f32, v: f32) -> Vec3;
     fn clone_(&self) -> Box<Texture>;
 }
 
 #[derive(Clone)]
 pub struct CheckerboardTexture {
-    pub dim: f32,
+    pub dim: Float,
 }
 
 impl CheckerboardTexture {
-    pub fn new(dim: f32) -> Self {
+    pub fn new(dim: Float) -> Self {
         CheckerboardTexture { dim: dim }
     }
 }
 
 impl Texture for CheckerboardTexture {
-    fn color(&self, u: f32, v: f32) -> Vec3 {
+    fn color(&self, u: Float, v: Float) -> Vec3 {
         let half = self.dim / 2.;
 
         let mut s = u % self.dim;
@@ -37,7 +50,7 @@ impl Texture for CheckerboardTexture {
         }
 
         let color1 = Vec3::new(0., 0., 0.);
-        let color2 = Vec3::new(255., 255., 255.);
+        let color2 = Vec3::new(1., 1., 1.);
 
         if s > 0. && t < 0. || s < 0. && t > 0. {
             color1
@@ -51,30 +64,364 @@ impl Texture for CheckerboardTexture {
     }
 }
 
+// Clones share the decoded image, so every surface given a copy of a material with this texture
+// uses the same pixels. Color images are sRGB encoded, like photos and paintings, and decoded to
+// linear colors; the linear ones hold values as they are, for normal, bump, roughness and metallic
+// maps
 #[derive(Clone)]
 pub struct ImageTexture {
-    image: RgbImage,
+    image: Arc<Mipmap>,
 }
 
 impl ImageTexture {
     pub fn new(filename: &str) -> Self {
-        let image = image::open(filename).unwrap();
-        if let ImageRgb8(im) = image {
-            ImageTexture { image: im }
-        } else {
-            panic!("Only RGB textures are supported");
-        }
+        ImageTexture::from_image(load_image(filename))
+    }
+
+    pub fn linear(filename: &str) -> Self {
+        ImageTexture::from_linear_image(load_image(filename))
+    }
+
+    // An image already decoded, e.g. one embedded in a glTF file
+    pub fn from_image(image: RgbImage) -> Self {
+        ImageTexture { image: Arc::new(Mipmap::srgb(image)) }
+    }
+
+    pub fn from_linear_image(image: RgbImage) -> Self {
+        ImageTexture { image: Arc::new(Mipmap::new(image)) }
+    }
+}
+
+fn load_image(filename: &str) -> RgbImage {
+    let image = image::open(filename).unwrap();
+    if let ImageRgb8(im) = image {
+        debug!("Loaded texture {} ({}x{})", filename, im.width(), im.height());
+        im
+    } else {
+        panic!("Only RGB textures are supported");
     }
 }
 
 impl Texture for ImageTexture {
-    fn color(&self, u: f32, v: f32) -> Vec3 {
-        let u = (u % 1.) * (self.image.width() as f32 - 1.);
-        let v = (v % 1.) * (self.image.height() as f32 - 1.);
+    fn color(&self, u: Float, v: Float) -> Vec3 {
+        self.image.sample(u, v)
+    }
+
+    fn color_filtered(&self, u: Float, v: Float, footprint: Float) -> Vec3 {
+        self.image.sample_footprint(u, v, footprint)
+    }
+
+    fn clone_(&self) -> Box<Texture> {
+        Box::new(self.clone())
+    }
+}
+
+// An image with its mipmap, made when it's loaded: each level is half the size of the one
+// before, down to a single pixel, with every pixel the average of the four it covers. Textures
+// wrap around in both directions
+pub struct Mipmap {
+    levels: Vec<RgbImage>,
+    // The value of each 8 bit level
+    values: [Float; 256],
+    srgb: bool,
+}
+
+impl Mipmap {
+    // With levels as linear values, 255 as 1
+    pub fn new(image: RgbImage) -> Self {
+        Mipmap::build(image, false)
+    }
+
+    // With sRGB encoded levels, averaged as the linear colors they stand for
+    pub fn srgb(image: RgbImage) -> Self {
+        Mipmap::build(image, true)
+    }
+
+    fn build(image: RgbImage, srgb: bool) -> Self {
+        let mut mipmap = Mipmap { levels: vec![image], values: values(srgb), srgb: srgb };
+        loop {
+            let next = match mipmap.levels.last() {
+                Some(last) if last.width() > 1 || last.height() > 1 => mipmap.downsample(last),
+                _ => break,
+            };
+            mipmap.levels.push(next);
+        }
+        mipmap
+    }
+
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    // Bilinear filtered at full resolution
+    pub fn sample(&self, u: Float, v: Float) -> Vec3 {
+        bilinear(&self.levels[0], u, v, &self.values)
+    }
+
+    // Trilinear filtered: bilinear in the two levels whose texels are closest to `footprint`
+    // wide (in uv, where 1 is the whole image), blended by how close each one is
+    pub fn sample_footprint(&self, u: Float, v: Float, footprint: Float) -> Vec3 {
+        let full = &self.levels[0];
+        let lod = level_of_detail(full.width(), full.height(), self.levels.len(), footprint);
+        let level = lod as usize;
+        let t = lod - level as Float;
+        let fine = bilinear(&self.levels[level], u, v, &self.values);
+        if t == 0. {
+            return fine;
+        }
+        fine * (1. - t) + bilinear(&self.levels[level + 1], u, v, &self.values) * t
+    }
+
+    // Halves the size, rounding down but not below 1. The last row or column of an odd sized
+    // image is averaged into the pixels before it by clamping
+    fn downsample(&self, image: &RgbImage) -> RgbImage {
+        let (width, height) = (cmp::max(image.width() / 2, 1), cmp::max(image.height() / 2, 1));
+        let encode = |value: Float| {
+            let level = if self.srgb { srgb(value) } else { value };
+            (level * 255.).round().max(0.).min(255.) as u8
+        };
+        RgbImage::from_fn(width, height, |x, y| {
+            let mut sum = [0.; 3];
+            for &(dx, dy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let p = image.get_pixel(cmp::min(x * 2 + dx, image.width() - 1),
+                                        cmp::min(y * 2 + dy, image.height() - 1));
+                for c in 0..3 {
+                    sum[c] += self.values[p.data[c] as usize];
+                }
+            }
+            Rgb { data: [encode(sum[0] / 4.), encode(sum[1] / 4.), encode(sum[2] / 4.)] }
+        })
+    }
+}
+
+// The value of each 8 bit level, sRGB encoded or linear with 255 as 1
+fn values(srgb: bool) -> [Float; 256] {
+    let mut values = [0.; 256];
+    for (level, value) in values.iter_mut().enumerate() {
+        let linear = level as Float / 255.;
+        *value = if srgb { srgb_inverse(linear) } else { linear };
+    }
+    values
+}
 
-        // TODO: Bilinear sampling
-        let p = self.image.get_pixel(u as u32, v as u32);
-        Vec3::new(p.data[0] as f32, p.data[1] as f32, p.data[2] as f32)
+// The level, with the fraction of the way to the next one, whose texels are closest to
+// `footprint` wide in an image of `levels` levels. 0 when even full resolution texels are wider
+fn level_of_detail(width: u32, height: u32, levels: usize, footprint: Float) -> Float {
+    let texels = footprint * cmp::max(width, height) as Float;
+    if !(texels > 1.) {
+        return 0.;
+    }
+    texels.log2().min((levels - 1) as Float)
+}
+
+// Blends the four texels around u, v, whose centers are at half texel offsets, each channel's
+// level standing for `values[level]`
+fn bilinear(image: &RgbImage, u: Float, v: Float, values: &[Float; 256]) -> Vec3 {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    let x = (u - u.floor()) * width as Float - 0.5;
+    let y = (v - v.floor()) * height as Float - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let texel = |x: i64, y: i64| {
+        let p = image.get_pixel(((x % width + width) % width) as u32,
+                                ((y % height + height) % height) as u32);
+        Vec3::new(values[p.data[0] as usize], values[p.data[1] as usize],
+                  values[p.data[2] as usize])
+    };
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = texel(x0, y0) * (1. - tx) + texel(x0 + 1, y0) * tx;
+    let bottom = texel(x0, y0 + 1) * (1. - tx) + texel(x0 + 1, y0 + 1) * tx;
+    top * (1. - ty) + bottom * ty
+}
+
+// Image textures that are only decoded when first sampled, and kept in memory while they fit in
+// the budget. The budget holds mip levels rather than whole textures: when it's full the least
+// recently used levels are dropped, so a texture seen from afar keeps only its small levels, and a
+// dropped level is decoded again if it's needed later. None of the supported image formats can be
+// decoded a level at a time, so that means decoding the whole file, keeping the levels asked for.
+// A level larger than the whole budget is never kept, and samples use the next one that fits.
+//
+// The lock is only held to look levels up, and they're sampled after it's released
+pub struct TextureCache {
+    budget: usize,
+    used: usize,
+    clock: u64,
+    // By the id CachedImageTexture::new registers them under
+    textures: Vec<CachedTexture>,
+    // Image of a level and the clock value when it was last used, by texture id and level
+    levels: HashMap<(usize, usize), (Arc<RgbImage>, u64)>,
+}
+
+struct CachedTexture {
+    filename: String,
+    srgb: bool,
+    // Known once the texture is first decoded, and kept when its levels are dropped
+    layout: Option<Layout>,
+}
+
+#[derive(Clone, Copy)]
+struct Layout {
+    width: u32,
+    height: u32,
+    levels: usize,
+    // The finest level that fits in the budget
+    finest: usize,
+}
+
+impl TextureCache {
+    // `budget` is in bytes of decoded image data
+    pub fn new(budget: usize) -> Arc<Mutex<TextureCache>> {
+        Arc::new(Mutex::new(TextureCache { budget: budget, used: 0, clock: 0,
+                                           textures: Vec::new(), levels: HashMap::new() }))
+    }
+
+    // The id of `filename`, decoded as sRGB if `srgb`, registering it the first time
+    fn register(&mut self, filename: &str, srgb: bool) -> usize {
+        if let Some(id) = self.textures.iter()
+            .position(|texture| texture.filename == filename && texture.srgb == srgb) {
+            return id;
+        }
+        self.textures.push(CachedTexture { filename: filename.to_owned(), srgb: srgb,
+                                           layout: None });
+        self.textures.len() - 1
+    }
+
+    // The level of texture `id` that `lod` picks from its layout, or the finest one kept if that's
+    // finer, with the level of detail actually used. Decoding happens without the cache's lock,
+    // so other threads keep sampling meanwhile and a file that fails to load doesn't leave the
+    // lock poisoned for the scenes after it
+    fn level<F>(cache: &Mutex<TextureCache>, id: usize, lod: F) -> (Float, Arc<RgbImage>)
+        where F: Fn(&Layout) -> Float
+    {
+        let (filename, srgb) = {
+            let mut cache = lock(cache);
+            if let Some(layout) = cache.textures[id].layout {
+                let lod = lod(&layout).max(layout.finest as Float);
+                if let Some(image) = cache.touch(id, lod as usize) {
+                    return (lod, image);
+                }
+            }
+            let texture = &cache.textures[id];
+            (texture.filename.clone(), texture.srgb)
+        };
+        let image = load_image(&filename);
+        let mipmap = if srgb { Mipmap::srgb(image) } else { Mipmap::new(image) };
+        let mut cache = lock(cache);
+        let layout = cache.layout(id, &mipmap);
+        let lod = lod(&layout).max(layout.finest as Float);
+        (lod, cache.insert(id, mipmap, lod as usize))
+    }
+
+    fn touch(&mut self, id: usize, level: usize) -> Option<Arc<RgbImage>> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.levels.get_mut(&(id, level)).map(|entry| {
+            entry.1 = clock;
+            entry.0.clone()
+        })
+    }
+
+    fn layout(&mut self, id: usize, mipmap: &Mipmap) -> Layout {
+        if let Some(layout) = self.textures[id].layout {
+            return layout;
+        }
+        let budget = self.budget;
+        let finest = mipmap.levels.iter().position(|level| image_size(level) <= budget)
+            .unwrap_or(mipmap.levels.len() - 1);
+        let full = &mipmap.levels[0];
+        if finest > 0 {
+            let kept = &mipmap.levels[finest];
+            warn!("Texture {} ({}x{}) is larger than the texture budget, using it at {}x{}",
+                  self.textures[id].filename, full.width(), full.height(), kept.width(),
+                  kept.height());
+        }
+        let layout = Layout { width: full.width(), height: full.height(),
+                              levels: mipmap.levels.len(), finest: finest };
+        self.textures[id].layout = Some(layout);
+        layout
+    }
+
+    // Keeps `level` of the decoded `mipmap`, and the coarser levels that aren't held either, as
+    // they're likely to be sampled next as the texture gets further away. Levels another thread
+    // loaded meanwhile are left as they are
+    fn insert(&mut self, id: usize, mipmap: Mipmap, level: usize) -> Arc<RgbImage> {
+        let mut levels: Vec<_> = mipmap.levels.into_iter().map(Arc::new).collect();
+        // The level asked for goes in last, so making room for it can't drop it again
+        for (i, image) in levels.iter().enumerate().skip(level).rev() {
+            if self.levels.contains_key(&(id, i)) {
+                continue;
+            }
+            let size = image_size(image);
+            while self.used + size > self.budget && !self.levels.is_empty() {
+                self.evict_oldest();
+            }
+            self.used += size;
+            self.levels.insert((id, i), (image.clone(), self.clock));
+        }
+        levels.swap_remove(level)
+    }
+
+    // Bytes of decoded image data currently held
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = *self.levels.iter()
+            .min_by_key(|&(_, &(_, last_used))| last_used)
+            .map(|(key, _)| key)
+            .unwrap();
+        let (image, _) = self.levels.remove(&oldest).unwrap();
+        self.used -= image_size(&image);
+        debug!("Evicted level {} of texture {}", oldest.1, self.textures[oldest.0].filename);
+    }
+}
+
+// A panic elsewhere while holding the lock leaves the cache as it was between calls, so it's
+// still usable
+pub fn lock(cache: &Mutex<TextureCache>) -> MutexGuard<TextureCache> {
+    cache.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn image_size(image: &RgbImage) -> usize {
+    (image.width() * image.height() * 3) as usize
+}
+
+// Decoded as sRGB or linear like ImageTexture
+#[derive(Clone)]
+pub struct CachedImageTexture {
+    // In the cache
+    id: usize,
+    values: [Float; 256],
+    cache: Arc<Mutex<TextureCache>>,
+}
+
+impl CachedImageTexture {
+    pub fn new(filename: &str, srgb: bool, cache: Arc<Mutex<TextureCache>>) -> Self {
+        let id = lock(&cache).register(filename, srgb);
+        CachedImageTexture { id: id, values: values(srgb), cache: cache }
+    }
+}
+
+impl Texture for CachedImageTexture {
+    fn color(&self, u: Float, v: Float) -> Vec3 {
+        let (_, image) = TextureCache::level(&self.cache, self.id, |_| 0.);
+        bilinear(&image, u, v, &self.values)
+    }
+
+    // Trilinear filtered like Mipmap
+    fn color_filtered(&self, u: Float, v: Float, footprint: Float) -> Vec3 {
+        let (lod, image) = TextureCache::level(&self.cache, self.id, |layout| {
+            level_of_detail(layout.width, layout.height, layout.levels, footprint)
+        });
+        let level = lod as usize;
+        let t = lod - level as Float;
+        let fine = bilinear(&image, u, v, &self.values);
+        if t == 0. {
+            return fine;
+        }
+        let (_, coarse) = TextureCache::level(&self.cache, self.id, |_| (level + 1) as Float);
+        fine * (1. - t) + bilinear(&coarse, u, v, &self.values) * t
     }
 
     fn clone_(&self) -> Box<Texture> {
@@ -0,0 +1,86 @@
+use std::cmp;
+use std::str::FromStr;
+
+use {float, Float};
+
+// The order tiles of an image are rendered in. It doesn't change the result, only which parts
+// of the image are finished first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    // Row by row from the top left
+    Scanline,
+    // Along a Hilbert curve, so consecutive tiles are always neighbors
+    Hilbert,
+    // Rings outwards from the middle, so the usually most important part resolves first
+    Spiral,
+}
+
+impl FromStr for TileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "scanline" => Ok(TileOrder::Scanline),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            "spiral" => Ok(TileOrder::Spiral),
+            _ => Err(format!("Unknown tile order: {}", s)),
+        }
+    }
+}
+
+// Every (column, row) of a tiles_x x tiles_y grid of tiles, in the given order
+pub fn tile_order(tiles_x: u32, tiles_y: u32, order: TileOrder) -> Vec<(u32, u32)> {
+    let mut tiles = Vec::new();
+    for ty in 0..tiles_y {
+        for tx in 0..tiles_x {
+            tiles.push((tx, ty));
+        }
+    }
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Hilbert => {
+            // Walk the curve over the enclosing power of two square, skipping tiles outside
+            let size = cmp::max(tiles_x, tiles_y).next_power_of_two();
+            tiles = (0..size * size).map(|d| hilbert(size, d))
+                .filter(|&(tx, ty)| tx < tiles_x && ty < tiles_y)
+                .collect();
+        }
+        TileOrder::Spiral => {
+            // Sort by square ring around the middle, then by angle within each ring
+            let (cx, cy) = ((tiles_x as Float - 1.) / 2., (tiles_y as Float - 1.) / 2.);
+            let key = |&(tx, ty): &(u32, u32)| {
+                let (dx, dy) = (tx as Float - cx, ty as Float - cy);
+                let ring = dx.abs().max(dy.abs()) as u32;
+                let angle = (dy.atan2(dx) + 2. * float::consts::PI) % (2. * float::consts::PI);
+                (ring, (angle * 1e4) as u32)
+            };
+            tiles.sort_by_key(key);
+        }
+    }
+    tiles
+}
+
+// The d-th cell along a Hilbert curve filling a size x size square, size a power of two
+fn hilbert(size: u32, d: u32) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < size {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            let tmp = x;
+            x = y;
+            y = tmp;
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
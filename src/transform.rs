@@ -0,0 +1,257 @@
+// Scaling, rotating and moving any surface. Rays are brought into the surface's own space and
+// intersected there, and the hits are brought back out, so spheres can be stretched into
+// ellipsoids and meshes turned without touching their own code. Instances do the same with a
+// surface shared between them, so a forest of copies of one tree mesh only stores it once.
+// Transforms compose, so the surfaces of a group are each placed by their own transform and then
+// by those of the groups around them, as one flat transform.
+// Moving surfaces are shifted by the time of the ray instead, for motion blur.
+
+use std::mem;
+use std::sync::Arc;
+
+use {float, Float, Vec3};
+use bounds::Aabb;
+use material::Material;
+use ray::{Intersection, Ray};
+use surface::Surface;
+
+use nalgebra::{Inverse, Matrix3, Norm, Rotation3, Transpose};
+
+#[derive(Clone)]
+pub struct Transform {
+    // Object to world space is `linear * p + offset`
+    linear: Matrix3<Float>,
+    inverse: Matrix3<Float>,
+    offset: Vec3,
+}
+
+impl Transform {
+    // Scales along each axis, then rotates about the x, y and z axes in that order by the angles
+    // in `rotation` (radians), then moves by `offset`
+    pub fn new(scale: Vec3, rotation: Vec3, offset: Vec3) -> Self {
+        assert!(scale.x != 0. && scale.y != 0. && scale.z != 0., "Can't scale a surface to 0");
+        let rotation = Rotation3::new_with_euler_angles(rotation.x, rotation.y, rotation.z);
+        let scale = Matrix3::new(scale.x, 0., 0., 0., scale.y, 0., 0., 0., scale.z);
+        let linear = *rotation.submatrix() * scale;
+        Transform { linear: linear, inverse: linear.inverse().unwrap(), offset: offset }
+    }
+
+    // This transform and then `outer`, e.g. a surface's own and then that of the group it's in
+    pub fn then(&self, outer: &Transform) -> Transform {
+        Transform {
+            linear: outer.linear * self.linear,
+            inverse: self.inverse * outer.inverse,
+            offset: outer.linear * self.offset + outer.offset,
+        }
+    }
+
+    fn to_world(&self, hit: Intersection, dist: Float) -> Intersection {
+        // Normals are transformed by the inverse transpose, to stay perpendicular to the
+        // stretched surface
+        let normal = (self.inverse.transpose() * hit.normal).normalize();
+        // Tangents lie along the surface, so they stretch with it
+        let tangent = hit.tangent.map(|tangent| (self.linear * tangent).normalize());
+        let uv_derivatives = hit.uv_derivatives
+            .map(|(dpdu, dpdv)| (self.linear * dpdu, self.linear * dpdv));
+        let edge = hit.edge.map(|edge| self.linear * edge);
+        let terminator = hit.terminator.map(|terminator| self.linear * terminator);
+        Intersection {
+            pos: self.linear * hit.pos + self.offset,
+            normal: normal,
+            dist: dist,
+            tangent: tangent,
+            uv_derivatives: uv_derivatives,
+            edge: edge,
+            terminator: terminator,
+            ..hit
+        }
+    }
+
+    fn bounds(&self, surface: &Surface) -> Option<Aabb> {
+        surface.bounds().map(|b| {
+            let mut min = Vec3::new(float::INFINITY, float::INFINITY, float::INFINITY);
+            let mut max = -min;
+            for i in 0..8 {
+                let corner = Vec3::new(if i & 1 == 0 { b.min.x } else { b.max.x },
+                                       if i & 2 == 0 { b.min.y } else { b.max.y },
+                                       if i & 4 == 0 { b.min.z } else { b.max.z });
+                let p = self.linear * corner + self.offset;
+                min = Vec3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+                max = Vec3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+            }
+            Aabb::new(min, max)
+        })
+    }
+
+    fn surface_point(&self, surface: &Surface, u: Float, v: Float) -> Option<Intersection> {
+        surface.surface_point(u, v).map(|hit| self.to_world(hit, 0.))
+    }
+
+    fn intersect(&self, surface: &Surface, ray: &Ray) -> Option<Intersection> {
+        let dir = self.inverse * ray.dir;
+        // Distances along the object space ray are this many times those in world space
+        let stretch = dir.norm();
+        let local = Ray::new(self.inverse * (ray.origin - self.offset), dir)
+            .with_extent(ray.near * stretch, ray.far * stretch).with_time(ray.time);
+        surface.intersect(&local).map(|hit| {
+            let dist = hit.dist / stretch;
+            self.to_world(hit, dist)
+        })
+    }
+}
+
+pub struct Transformed {
+    surface: Box<Surface>,
+    transform: Transform,
+}
+
+impl Transformed {
+    // Scales `surface` along each axis, then rotates it about the x, y and z axes in that order
+    // by the angles in `rotation` (radians), then moves it by `offset`
+    pub fn new(surface: Box<Surface>, scale: Vec3, rotation: Vec3, offset: Vec3) -> Self {
+        Transformed::placed(surface, Transform::new(scale, rotation, offset))
+    }
+
+    pub fn placed(surface: Box<Surface>, transform: Transform) -> Self {
+        Transformed { surface: surface, transform: transform }
+    }
+}
+
+impl Surface for Transformed {
+    fn name(&self) -> &'static str {
+        self.surface.name()
+    }
+
+    fn material(&self) -> &Material {
+        self.surface.material()
+    }
+
+    fn material_at(&self, hit: &Intersection) -> &Material {
+        self.surface.material_at(hit)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.transform.bounds(&*self.surface)
+    }
+
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        self.transform.surface_point(&*self.surface, u, v)
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        self.transform.intersect(&*self.surface, ray)
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.surface.triangle_count()
+    }
+
+    fn heap_size(&self) -> usize {
+        mem::size_of_val(&*self.surface) + self.surface.heap_size()
+    }
+}
+
+// A copy of a surface shared with other instances, placed like a Transformed surface
+pub struct Instance {
+    surface: Arc<Box<Surface>>,
+    transform: Transform,
+}
+
+impl Instance {
+    // See Transformed::new
+    pub fn new(surface: Arc<Box<Surface>>, scale: Vec3, rotation: Vec3, offset: Vec3) -> Self {
+        Instance::placed(surface, Transform::new(scale, rotation, offset))
+    }
+
+    pub fn placed(surface: Arc<Box<Surface>>, transform: Transform) -> Self {
+        Instance { surface: surface, transform: transform }
+    }
+}
+
+impl Surface for Instance {
+    fn name(&self) -> &'static str {
+        "Instance"
+    }
+
+    fn material(&self) -> &Material {
+        self.surface.material()
+    }
+
+    fn material_at(&self, hit: &Intersection) -> &Material {
+        self.surface.material_at(hit)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.transform.bounds(&**self.surface)
+    }
+
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        self.transform.surface_point(&**self.surface, u, v)
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        self.transform.intersect(&**self.surface, ray)
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.surface.triangle_count()
+    }
+
+    // An even share of the surface, so that it's counted once over all its instances
+    fn heap_size(&self) -> usize {
+        (mem::size_of_val(&**self.surface) + self.surface.heap_size()) /
+            Arc::strong_count(&self.surface)
+    }
+}
+
+// A surface moving in a straight line by `motion` from time 0 to time 1 (see Ray::time), blurred
+// while the camera's shutter is open
+pub struct Moving {
+    surface: Box<Surface>,
+    motion: Vec3,
+}
+
+impl Moving {
+    pub fn new(surface: Box<Surface>, motion: Vec3) -> Self {
+        Moving { surface: surface, motion: motion }
+    }
+}
+
+impl Surface for Moving {
+    fn name(&self) -> &'static str {
+        self.surface.name()
+    }
+
+    fn material(&self) -> &Material {
+        self.surface.material()
+    }
+
+    fn material_at(&self, hit: &Intersection) -> &Material {
+        self.surface.material_at(hit)
+    }
+
+    // Everywhere the surface passes through
+    fn bounds(&self) -> Option<Aabb> {
+        let motion = self.motion;
+        self.surface.bounds().map(|b| b.union(&Aabb::new(b.min + motion, b.max + motion)))
+    }
+
+    // Where the surface is at time 0
+    fn surface_point(&self, u: Float, v: Float) -> Option<Intersection> {
+        self.surface.surface_point(u, v)
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        let offset = self.motion * ray.time;
+        let local = Ray { origin: ray.origin - offset, ..ray.clone() };
+        self.surface.intersect(&local).map(|hit| Intersection { pos: hit.pos + offset, ..hit })
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.surface.triangle_count()
+    }
+
+    fn heap_size(&self) -> usize {
+        mem::size_of_val(&*self.surface) + self.surface.heap_size()
+    }
+}
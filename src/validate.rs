@@ -0,0 +1,989 @@
+// Checks a scene before it's decoded, so that a mistake in a scene file is reported with the
+// field it's in instead of as an unwrap panic somewhere in loading, or as NaNs in the image. All
+// problems are collected, e.g. "scene.surface[2] (sphere): radius must be positive, not 0".
+
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::str::FromStr;
+
+use tracerlib::{float, Float, Vec3};
+use tracerlib::aov::Aov;
+use tracerlib::color::OutputTransform;
+use tracerlib::debug::DebugMode;
+use tracerlib::procedural::{Basis, Pattern};
+use tracerlib::sampler;
+use tracerlib::tiles::TileOrder;
+
+use nalgebra::{cross, Norm};
+use toml::Value;
+
+const SURFACE_TYPES: &'static [&'static str] = &["plane", "sphere", "mesh", "cylinder", "cone",
+                                                 "disk", "triangle", "quad", "torus",
+                                                 "heightfield", "sdf", "csg", "instance",
+                                                 "group"];
+const LIGHT_TYPES: &'static [&'static str] = &["point", "sphere", "rect", "spot", "directional"];
+const POST_EFFECTS: &'static [&'static str] = &["saturation", "exposure", "white_balance",
+                                                "denoise", "bloom", "chromatic_aberration",
+                                                "film_grain", "lens_flare", "vignette"];
+
+// Problems with the scene, each naming where it is. Empty if the scene can be loaded
+pub fn validate_scene(toml: &Value) -> Vec<String> {
+    let mut problems = Vec::new();
+    let materials = match toml.lookup("material") {
+        Some(materials) => check_materials(materials, &mut problems),
+        None => BTreeSet::new(),
+    };
+    let scene = match toml.lookup("scene") {
+        Some(scene) => scene,
+        None => return vec!["There is no [scene] table".to_owned()],
+    };
+    {
+        let path = "scene".to_owned();
+        let mut check = Checker { value: scene, path: path, problems: &mut problems };
+        check.number("ambient_const");
+        check.vec3("ambient_color");
+        check.file("ambient_map");
+        if let Some(samples) = check.optional_number("emission_samples") {
+            check.require(samples >= 1., format!("emission_samples must be at least 1, not {}",
+                                                 samples));
+        }
+        for key in &["ray_bias", "ray_bias_scale"] {
+            if let Some(bias) = check.optional_number(key) {
+                check.require(bias >= 0., format!("{} can't be negative, not {}", key, bias));
+            }
+        }
+        check.file("environment");
+        for key in &["memory_budget_mb", "texture_budget_mb"] {
+            if let Some(mb) = check.optional_number(key) {
+                check.require(mb > 0., format!("{} must be positive, not {}", key, mb));
+            }
+        }
+        if let Some(name) = scene.lookup("sampler").map(|name| name.as_str()) {
+            check.require(name.map_or(false, |name| sampler::by_name(name).is_some()),
+                          "sampler should be random, stratified, halton or sobol".to_owned());
+        }
+    }
+
+    if let Some(bvh) = scene.lookup("bvh") {
+        check_bvh(bvh, "scene.bvh".to_owned(), &mut problems);
+    }
+
+    match scene.lookup("camera") {
+        Some(camera) => check_camera(camera, &mut problems),
+        None => problems.push("There is no [scene.camera] table".to_owned()),
+    }
+
+    let mut objects = BTreeSet::new();
+    for (i, object) in array(scene, "object", &mut problems).iter().enumerate() {
+        let path = format!("scene.object[{}]", i);
+        if is_group(object) {
+            problems.push(format!("{}: objects can't be groups", path));
+        }
+        check_surface(object, &path, &materials, &objects, &mut problems);
+        for (j, level) in array(object, "lod", &mut problems).iter().enumerate() {
+            let path = format!("{}.lod[{}]", path, j);
+            if is_group(level) {
+                problems.push(format!("{}: levels of detail can't be groups", path));
+            }
+            check_surface(level, &path, &materials, &objects, &mut problems);
+            let mut check = Checker { value: level, path: path, problems: &mut problems };
+            if let Some(size) = check.number("screen_size") {
+                check.require(size > 0., format!("screen_size must be positive, not {}", size));
+            }
+        }
+        match object.lookup("name").and_then(Value::as_str) {
+            Some(name) => {
+                objects.insert(name.to_owned());
+            }
+            None => problems.push(format!("{}: name is missing", path)),
+        }
+    }
+    if scene.lookup("surface").is_none() {
+        problems.push("There are no [[scene.surface]] tables".to_owned());
+    }
+    // Emissive materials can light a scene without lights
+    let emissive = toml.lookup("material").and_then(Value::as_slice).map_or(false, |materials| {
+        materials.iter().any(|material| material.lookup("emission").is_some())
+    });
+    if scene.lookup("light").is_none() && !emissive {
+        problems.push("There are no [[scene.light]] tables".to_owned());
+    }
+    for (i, surface) in array(scene, "surface", &mut problems).iter().enumerate() {
+        let path = format!("scene.surface[{}]", i);
+        if surface.lookup("lod").is_some() {
+            problems.push(format!("{}: only [[scene.object]] tables have levels of detail", path));
+        }
+        check_surface(surface, &path, &materials, &objects, &mut problems);
+    }
+    for (i, light) in array(scene, "light", &mut problems).iter().enumerate() {
+        check_light(light, &format!("scene.light[{}]", i), &mut problems);
+    }
+    for (i, portal) in array(scene, "portal", &mut problems).iter().enumerate() {
+        let path = format!("scene.portal[{}]", i);
+        let mut check = Checker { value: portal, path: path, problems: &mut problems };
+        check.vec3("pos");
+        if let (Some(u), Some(v)) = (check.vec3("u"), check.vec3("v")) {
+            check.require(cross(&u, &v).norm_squared() > 0.,
+                          "u and v must span a rectangle".to_owned());
+        }
+    }
+    if scene.lookup("portal").is_some() && scene.lookup("environment").is_none() {
+        problems.push("scene: portals only steer the light of an environment map".to_owned());
+    }
+    check_sections(scene, "scene", &mut problems);
+    problems
+}
+
+// Problems with the [config] table, like those of validate_scene. Empty if it can be decoded
+pub fn validate_config(toml: &Value) -> Vec<String> {
+    let config = match toml.lookup("config") {
+        Some(config) => config,
+        None => return vec!["There is no [config] table".to_owned()],
+    };
+    let mut problems = Vec::new();
+    {
+        let path = "config".to_owned();
+        let mut check = Checker { value: config, path: path, problems: &mut problems };
+        let mut size = (None, None);
+        for key in &["width", "height", "samples"] {
+            if let Some(n) = check.integer(key) {
+                check.require(n >= 1, format!("{} must be at least 1, not {}", key, n));
+                match *key {
+                    "width" => size.0 = Some(n),
+                    "height" => size.1 = Some(n),
+                    _ => {}
+                }
+            }
+        }
+        if let Some(depth) = check.integer("reflection_depth") {
+            check.require(depth >= 0 && depth <= 0xffff,
+                          format!("reflection_depth must be from 0 to 65535, not {}", depth));
+        }
+        check.string("out_file");
+        check.string("scene");
+        check.parsed::<DebugMode>("debug_mode");
+        check.parsed::<OutputTransform>("output_transform");
+        check.parsed::<TileOrder>("tile_order");
+        for key in &["dither", "stream", "light_groups", "stats"] {
+            check.boolean(key);
+        }
+        if let Some(bits) = check.optional_integer("bit_depth") {
+            check.require(bits == 8 || bits == 16,
+                          format!("bit_depth must be 8 or 16, not {}", bits));
+        }
+        if let Some(quality) = check.optional_integer("jpeg_quality") {
+            check.require(quality >= 1 && quality <= 100,
+                          format!("jpeg_quality must be from 1 to 100, not {}", quality));
+        }
+        for key in &["anaglyph", "side_by_side", "ods", "adaptive_threshold"] {
+            check.optional_number(key);
+        }
+        if let Some(mb) = check.optional_number("texture_budget_mb") {
+            check.require(mb > 0., format!("texture_budget_mb must be positive, not {}", mb));
+        }
+        if let Some(seed) = check.optional_integer("seed") {
+            check.require(seed >= 0, format!("seed can't be negative, not {}", seed));
+        }
+        for key in &["adaptive_samples", "adaptive_min_samples", "adaptive_max_samples"] {
+            if let Some(samples) = check.optional_integer(key) {
+                check.require(samples >= 1, format!("{} must be at least 1, not {}", key, samples));
+            }
+        }
+        for key in &["stats_heatmap", "serve_root"] {
+            if config.lookup(key).is_some() {
+                check.string(key);
+            }
+        }
+        if let Some(aovs) = config.lookup("aovs") {
+            match aovs.as_slice() {
+                Some(aovs) => {
+                    for aov in aovs {
+                        match aov.as_str().map(Aov::from_str) {
+                            Some(Ok(_)) => {}
+                            Some(Err(problem)) => check.problem(format!("aovs: {}", problem)),
+                            None => check.problem("aovs should be names".to_owned()),
+                        }
+                    }
+                }
+                None => check.problem("aovs should be a list of names".to_owned()),
+            }
+        }
+        if let Some(crop) = config.lookup("crop") {
+            let coords: Vec<i64> = crop.as_slice()
+                .map_or(Vec::new(), |c| c.iter().filter_map(Value::as_integer).collect());
+            if coords.len() != 4 || crop.as_slice().map_or(0, |c| c.len()) != 4 {
+                check.problem("crop should be [x, y, width, height]".to_owned());
+            } else if coords.iter().any(|&c| c < 0) || coords[2] == 0 || coords[3] == 0 {
+                check.problem("crop can't be negative or empty".to_owned());
+            } else if let (Some(width), Some(height)) = size {
+                check.require(coords[0] + coords[2] <= width && coords[1] + coords[3] <= height,
+                              format!("crop must lie within the {}x{} frame", width, height));
+            }
+        }
+    }
+    // Denoising's normals and albedos and the lens flare's lights come from the scene camera's
+    // view of the whole frame, which doesn't line up with either eye of a stereo render
+    let stereo = ["anaglyph", "side_by_side", "ods"].iter().any(|key| config.lookup(key).is_some());
+    for (i, effect) in array(config, "post", &mut problems).iter().enumerate() {
+        let path = format!("config.post[{}]", i);
+        let name = effect.lookup("effect").and_then(Value::as_str).unwrap_or("");
+        if stereo && (name == "denoise" || name == "lens_flare") {
+            problems.push(format!("{} ({}): doesn't work with anaglyph, side_by_side or ods",
+                                  path, name));
+        }
+        check_post_effect(effect, path, &mut problems);
+    }
+    problems
+}
+
+fn check_post_effect(effect: &Value, path: String, problems: &mut Vec<String>) {
+    let name = effect.lookup("effect").and_then(Value::as_str).unwrap_or("");
+    let mut check = Checker { value: effect, path: format!("{} ({})", path, name),
+                              problems: problems };
+    if !POST_EFFECTS.contains(&name) {
+        check.problem(format!("effect should be one of {}", POST_EFFECTS.join(", ")));
+        return;
+    }
+    match name {
+        "saturation" | "chromatic_aberration" => {
+            check.number("amount");
+        }
+        "exposure" => {
+            check.number("ev");
+        }
+        "white_balance" => {
+            check.number("temperature");
+            check.optional_number("tint");
+        }
+        "denoise" => {
+            if let Some(radius) = check.optional_integer("radius") {
+                check.require(radius >= 1, format!("radius must be at least 1, not {}", radius));
+            }
+            if let Some(sigma) = check.optional_number("color_sigma") {
+                check.require(sigma > 0., format!("color_sigma must be positive, not {}", sigma));
+            }
+        }
+        "bloom" => {
+            check.number("threshold");
+            check.positive("radius");
+            check.number("strength");
+        }
+        "film_grain" => {
+            check.number("amount");
+            if let Some(seed) = check.optional_integer("seed") {
+                check.require(seed >= 0, format!("seed can't be negative, not {}", seed));
+            }
+        }
+        "lens_flare" => {
+            check.number("strength");
+        }
+        "vignette" => {
+            if let Some(amount) = check.number("amount") {
+                check.require(amount >= 0. && amount <= 1.,
+                              format!("amount must be between 0 and 1, not {}", amount));
+            }
+            if let Some(radius) = check.optional_number("radius") {
+                check.require(radius >= 0. && radius < 1.,
+                              format!("radius must be at least 0 and below 1, not {}", radius));
+            }
+        }
+        _ => {}
+    }
+}
+
+// The [[section]] planes of the scene or a surface
+fn check_sections(table: &Value, path: &str, problems: &mut Vec<String>) {
+    for (i, section) in array(table, "section", problems).iter().enumerate() {
+        let path = format!("{}.section[{}]", path, i);
+        let mut check = Checker { value: section, path: path, problems: problems };
+        check.vec3("pos");
+        check.direction("normal");
+        if let Some(cap) = section.lookup("cap") {
+            check.require(cap.as_bool().is_some(), "cap should be true or false".to_owned());
+        }
+    }
+}
+
+// The names of the materials
+fn check_materials(materials: &Value, problems: &mut Vec<String>) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    let materials = match materials.as_slice() {
+        Some(materials) => materials,
+        None => {
+            problems.push("material should be [[material]] tables".to_owned());
+            return names;
+        }
+    };
+    for (i, material) in materials.iter().enumerate() {
+        let name = material.lookup("name").and_then(Value::as_str);
+        let path = match name {
+            Some(name) => format!("material[{}] ({})", i, name),
+            None => format!("material[{}]", i),
+        };
+        let mut check = Checker { value: material, path: path, problems: problems };
+        match name {
+            Some(name) => {
+                if !names.insert(name.to_owned()) {
+                    check.problem(format!("another material is named {}", name));
+                }
+            }
+            None => check.problem("name is missing".to_owned()),
+        }
+        check.vec3("color");
+        // GGX materials can leave out the Phong coefficients
+        let ggx = material.lookup("roughness").is_some() ||
+                  material.lookup("roughness_map").is_some();
+        for key in &["diffuse", "specular", "glossiness", "reflectivity"] {
+            if ggx { check.optional_number(key) } else { check.number(key) };
+        }
+        // Or the roughness along and across the tangent
+        match material.lookup("roughness").and_then(Value::as_slice) {
+            Some(r) => {
+                let ok = r.len() == 2 && r.iter().all(|n| number(n).map_or(false, |n| {
+                    n >= 0. && n <= 1.
+                }));
+                check.require(ok, "roughness should be a number or two, between 0 and 1"
+                    .to_owned());
+            }
+            None => check.fraction("roughness"),
+        }
+        check.fraction("metallic");
+        check.fraction("transparency");
+        check.backfaces();
+        if let Some(ior) = check.optional_number("ior") {
+            check.require(ior > 0., format!("ior must be positive, not {}", ior));
+        }
+        if let Some(dispersion) = check.optional_number("dispersion") {
+            check.require(dispersion >= 0.,
+                          format!("dispersion can't be negative, not {}", dispersion));
+        }
+        if material.lookup("absorption").is_some() {
+            if let Some(absorption) = check.vec3("absorption") {
+                check.require(absorption.x >= 0. && absorption.y >= 0. && absorption.z >= 0.,
+                              "absorption can't be negative".to_owned());
+            }
+        }
+        if material.lookup("subsurface_radius").is_some() {
+            if let Some(radius) = check.vec3("subsurface_radius") {
+                check.require(radius.x > 0. && radius.y > 0. && radius.z > 0.,
+                              "subsurface_radius must be positive".to_owned());
+            }
+            if material.lookup("subsurface_albedo").is_some() {
+                if let Some(albedo) = check.vec3("subsurface_albedo") {
+                    let fraction = |n: Float| n >= 0. && n <= 1.;
+                    check.require(fraction(albedo.x) && fraction(albedo.y) && fraction(albedo.z),
+                                  "subsurface_albedo must be between 0 and 1".to_owned());
+                }
+            }
+        } else if material.lookup("subsurface_albedo").is_some() {
+            check.problem("subsurface_albedo needs a subsurface_radius".to_owned());
+        }
+        check.file("texture");
+        check.file("roughness_map");
+        check.file("metallic_map");
+        check.file("normal_texture");
+        check.file("bump_texture");
+        check.optional_number("bump_strength");
+        if material.lookup("metallic_map").is_some() {
+            check.require(ggx, "metallic_map needs a roughness or roughness_map".to_owned());
+        }
+        if material.lookup("emission").is_some() {
+            if let Some(emission) = check.vec3("emission") {
+                check.require(emission.x >= 0. && emission.y >= 0. && emission.z >= 0.,
+                              "emission can't be negative".to_owned());
+            }
+            if let Some(strength) = check.optional_number("emission_strength") {
+                check.require(strength >= 0.,
+                              format!("emission_strength can't be negative: {}", strength));
+            }
+        }
+        for key in &["normal_map", "displacement_map"] {
+            if let Some(map) = material.lookup(key) {
+                let numbers = map.as_slice().map_or(false, |v| {
+                    v.len() == 5 && v.iter().all(|n| number(n).is_some())
+                });
+                check.require(numbers, format!("{} should be [seed, octaves, wavelength, \
+                                                persistence, lacunarity]", key));
+            }
+        }
+        for key in &["procedural", "bump"] {
+            if let Some(procedural) = get(material, key) {
+                let path = format!("{}.{}", check.path, key);
+                check_procedural(procedural, path, check.problems);
+            }
+        }
+    }
+    names
+}
+
+// A procedural texture or bump
+fn check_procedural(procedural: &Value, path: String, problems: &mut Vec<String>) {
+    let mut check = Checker { value: procedural, path: path, problems: problems };
+    if let Some(noise) = procedural.lookup("noise") {
+        let known = noise.as_str().map_or(false, |n| n.parse::<Basis>().is_ok());
+        check.require(known, "noise should be one of perlin, simplex, worley".to_owned());
+    }
+    if let Some(pattern) = procedural.lookup("pattern") {
+        let known = pattern.as_str().map_or(false, |p| p.parse::<Pattern>().is_ok());
+        check.require(known, "pattern should be one of fbm, turbulence, marble, wood".to_owned());
+    }
+    if let Some(scale) = check.optional_number("scale") {
+        check.require(scale > 0., format!("scale must be positive, not {}", scale));
+    }
+    if let Some(octaves) = check.optional_number("octaves") {
+        check.require(octaves >= 1., format!("octaves must be at least 1, not {}", octaves));
+    }
+    check.optional_number("strength");
+    if let Some(colors) = procedural.lookup("colors") {
+        let rgb = |color: &Value| {
+            let v = color.as_slice().unwrap_or(&[]);
+            v.len() == 3 && v.iter().all(|n| number(n).is_some())
+        };
+        let colors = colors.as_slice().map_or(false, |c| c.len() == 2 && c.iter().all(rgb));
+        check.require(colors, "colors should be [[r, g, b], [r, g, b]]".to_owned());
+    }
+}
+
+fn check_camera(camera: &Value, problems: &mut Vec<String>) {
+    let mut check = Checker { value: camera, path: "scene.camera".to_owned(), problems: problems };
+    // A glTF camera brings its own position, direction and projection
+    if camera.lookup("gltf").is_some() {
+        check.file("gltf");
+        if let Some(index) = check.optional_number("gltf_camera") {
+            check.require(index >= 0., format!("gltf_camera can't be negative, not {}", index));
+        }
+    } else {
+        let (pos, lookat, up) = (check.vec3("pos"), check.vec3("lookat"), check.vec3("up"));
+        if let (Some(pos), Some(lookat), Some(up)) = (pos, lookat, up) {
+            let dir = lookat - pos;
+            if dir.norm_squared() == 0. {
+                check.problem("lookat is the same point as pos".to_owned());
+            } else if up.norm_squared() == 0. ||
+                      cross(&up.normalize(), &dir.normalize()).norm() < 1e-6 {
+                check.problem(format!("up {:?} is parallel to the view direction from pos to \
+                                       lookat", (up.x, up.y, up.z)));
+            }
+        }
+    }
+    for key in &["fov", "view_height", "focus_dist"] {
+        if let Some(value) = check.optional_number(key) {
+            check.require(value > 0., format!("{} must be positive, not {}", key, value));
+        }
+    }
+    if camera.lookup("aperture").is_some() {
+        check.number("focus_dist");
+    }
+    if let Some(blades) = check.optional_integer("aperture_blades") {
+        check.require(blades >= 3, format!("aperture_blades must be at least 3, not {}", blades));
+    }
+    check.optional_number("aperture_rotation");
+    check.file("aperture_mask");
+    check.optional_number("exposure");
+    check.optional_number("tint");
+    if let Some(temperature) = check.optional_number("white_balance") {
+        check.require(temperature >= 1000. && temperature <= 40000.,
+                      format!("white_balance must be between 1000 and 40000 Kelvin, not {}",
+                              temperature));
+    }
+    check.fraction("vignette");
+    if let Some(radius) = check.optional_number("vignette_radius") {
+        check.require(radius >= 0. && radius < 1.,
+                      format!("vignette_radius must be at least 0 and below 1, not {}", radius));
+    }
+}
+
+fn check_surface(surface: &Value, path: &str, materials: &BTreeSet<String>,
+                 objects: &BTreeSet<String>, problems: &mut Vec<String>) {
+    let type_ = surface.lookup("type").and_then(Value::as_str).unwrap_or("");
+    let path = format!("{} ({})", path, type_);
+    let mut check = Checker { value: surface, path: path, problems: problems };
+    if !SURFACE_TYPES.contains(&type_) {
+        check.problem(format!("type should be one of {}", SURFACE_TYPES.join(", ")));
+        return;
+    }
+    if surface.lookup("motion").is_some() {
+        check.require(type_ != "group",
+                      "groups can't move, but the surfaces in them can".to_owned());
+        check.vec3("motion");
+    }
+    if surface.lookup("section").is_some() {
+        check.require(type_ != "group",
+                      "groups can't be sectioned, but the surfaces in them can".to_owned());
+        let path = check.path.clone();
+        check_sections(surface, &path, check.problems);
+    }
+    check.backfaces();
+    match type_ {
+        "group" => {
+            if surface.lookup("surface").is_none() {
+                check.problem("there are no [[surface]] tables in it".to_owned());
+            }
+            let path = check.path.clone();
+            for (i, child) in array(surface, "surface", check.problems).iter().enumerate() {
+                let path = format!("{}.surface[{}]", path, i);
+                check_surface(child, &path, materials, objects, check.problems);
+            }
+            return;
+        }
+        "instance" => {
+            if let Some(object) = check.string("object") {
+                check.require(objects.contains(object),
+                              format!("there is no [[scene.object]] named {} before it", object));
+            }
+            return;
+        }
+        "csg" => {
+            check.string("operation");
+            for key in &["a", "b"] {
+                match surface.lookup(key) {
+                    Some(part) => {
+                        let path = format!("{}.{}", check.path, key);
+                        if is_group(part) {
+                            check.problem(format!("{} can't be a group", key));
+                        }
+                        check_surface(part, &path, materials, objects, check.problems);
+                    }
+                    None => check.problem(format!("{} is missing", key)),
+                }
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    if let Some(material) = check.string("material") {
+        check.require(materials.contains(material),
+                      format!("there is no material named {}", material));
+    }
+    match type_ {
+        "plane" => {
+            check.vec3("pos");
+            check.direction("normal");
+            if let Some(pattern) = surface.lookup("pattern") {
+                let path = format!("{}.pattern", check.path);
+                check_plane_pattern(pattern, path, materials, check.problems);
+            }
+        }
+        "sphere" => {
+            check.vec3("pos");
+            check.positive("radius");
+        }
+        "cylinder" | "cone" => {
+            if let (Some(pos), Some(top)) = (check.vec3("pos"), check.vec3("top")) {
+                check.require(pos != top, "top is the same point as pos".to_owned());
+            }
+            check.positive("radius");
+        }
+        "disk" => {
+            check.vec3("pos");
+            check.direction("normal");
+            check.positive("radius");
+        }
+        "triangle" | "quad" => check_corners(surface, if type_ == "triangle" { 3 } else { 4 },
+                                             &mut check),
+        "torus" => {
+            check.vec3("pos");
+            check.direction("normal");
+            let radius = check.positive("radius");
+            if let (Some(radius), Some(minor)) = (radius, check.positive("minor_radius")) {
+                check.require(minor <= radius, format!("minor_radius {} is larger than radius {}",
+                                                       minor, radius));
+            }
+        }
+        "mesh" => {
+            if let Some(file) = check.string("file") {
+                check.file("file");
+                // Compact meshes come with their hierarchy, see compact.rs
+                if file.ends_with(".rtmesh") {
+                    for key in &["subdivisions", "watertight", "crease_angle", "bvh"] {
+                        check.require(surface.lookup(key).is_none(),
+                                      format!("{} doesn't apply to compact meshes", key));
+                    }
+                }
+            }
+            check.optional_number("scale");
+            if let Some(angle) = check.optional_number("crease_angle") {
+                check.require(angle >= 0. && angle <= 180.,
+                              format!("crease_angle must be from 0 to 180, not {}", angle));
+            }
+            if let Some(bvh) = surface.lookup("bvh") {
+                let path = format!("{}.bvh", check.path);
+                check_bvh(bvh, path, check.problems);
+            }
+        }
+        "heightfield" => {
+            if let Some(size) = check.vec3("size") {
+                check.require(size.x > 0. && size.z > 0.,
+                              "size needs a positive width and depth".to_owned());
+            }
+            if surface.lookup("file").is_some() {
+                check.file("file");
+            } else {
+                check_heights(surface, &mut check);
+            }
+        }
+        "sdf" => {
+            match surface.lookup("shape") {
+                Some(shape) => {
+                    let path = format!("{}.shape", check.path);
+                    check_shape(shape, path, check.problems);
+                }
+                None => check.problem("shape is missing".to_owned()),
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn check_shape(shape: &Value, path: String, problems: &mut Vec<String>) {
+    let mut check = Checker { value: shape, path: path, problems: problems };
+    if shape.lookup("pos").is_some() {
+        check.vec3("pos");
+    }
+    match check.string("type") {
+        Some("sphere") => {
+            check.positive("radius");
+        }
+        Some("rounded_box") => {
+            let size = check.vec3("size");
+            if let Some(size) = size {
+                check.require(size.x > 0. && size.y > 0. && size.z > 0.,
+                              "size must be positive along every axis".to_owned());
+            }
+            if let Some(radius) = check.optional_number("radius") {
+                let half = size.map_or(float::INFINITY, |s| s.x.min(s.y).min(s.z) / 2.);
+                check.require(radius >= 0. && radius <= half,
+                              format!("radius must be from 0 to half the size, not {}", radius));
+            }
+        }
+        Some("torus") => {
+            let radius = check.positive("radius");
+            if let (Some(radius), Some(minor)) = (radius, check.positive("minor_radius")) {
+                check.require(minor <= radius, format!("minor_radius {} is larger than radius {}",
+                                                       minor, radius));
+            }
+        }
+        Some("smooth_union") => {
+            if let Some(blend) = check.number("blend") {
+                check.require(blend >= 0., format!("blend can't be negative, not {}", blend));
+            }
+            for key in &["a", "b"] {
+                match shape.lookup(key) {
+                    Some(part) => {
+                        let path = format!("{}.{}", check.path, key);
+                        check_shape(part, path, check.problems);
+                    }
+                    None => check.problem(format!("{} is missing", key)),
+                }
+            }
+        }
+        Some(type_) => {
+            check.problem(format!("unknown shape {}, expected sphere, rounded_box, torus or \
+                                   smooth_union", type_))
+        }
+        None => {}
+    }
+}
+
+fn check_bvh(bvh: &Value, path: String, problems: &mut Vec<String>) {
+    let mut check = Checker { value: bvh, path: path, problems: problems };
+    if let Some(size) = check.optional_number("max_leaf_size") {
+        check.require(size >= 1. && size == size.floor(),
+                      format!("max_leaf_size must be a whole number from 1, not {}", size));
+    }
+    if let Some(cost) = check.optional_number("traversal_cost") {
+        check.require(cost >= 0., format!("traversal_cost can't be negative, not {}", cost));
+    }
+}
+
+fn check_plane_pattern(pattern: &Value, path: String, materials: &BTreeSet<String>,
+                       problems: &mut Vec<String>) {
+    let mut check = Checker { value: pattern, path: path, problems: problems };
+    if let Some(type_) = check.string("type") {
+        check.require(type_ == "checkerboard" || type_ == "grid",
+                      format!("unknown pattern {}, expected checkerboard or grid", type_));
+    }
+    if let Some(material) = check.string("material") {
+        check.require(materials.contains(material),
+                      format!("there is no material named {}", material));
+    }
+    let size = match pattern.lookup("size") {
+        Some(_) => check.positive("size"),
+        None => Some(1.),
+    };
+    if let Some(width) = check.optional_number("line_width") {
+        check.require(width > 0. && size.map_or(true, |size| width < size),
+                      format!("line_width must be positive and less than size, not {}", width));
+    }
+}
+
+fn is_group(surface: &Value) -> bool {
+    surface.lookup("type").and_then(Value::as_str) == Some("group")
+}
+
+// `heights` must be at least 2 rows of the same number of numbers, at least 2
+fn check_heights(surface: &Value, check: &mut Checker) {
+    let rows = match surface.lookup("heights").map(Value::as_slice) {
+        Some(Some(rows)) => rows,
+        Some(None) => return check.problem("heights should be an array of rows".to_owned()),
+        None => return check.problem("file or heights is missing".to_owned()),
+    };
+    let lengths: Vec<Option<usize>> = rows.iter().map(|row| {
+        row.as_slice().and_then(|row| {
+            if row.iter().all(|h| number(h).is_some()) { Some(row.len()) } else { None }
+        })
+    }).collect();
+    if lengths.iter().any(Option::is_none) {
+        return check.problem("heights should be rows of numbers".to_owned());
+    }
+    check.require(rows.len() >= 2 && lengths.iter().all(|&n| n >= Some(2) && n == lengths[0]),
+                  "heights should be at least 2 rows of the same length, at least 2".to_owned());
+}
+
+// `count` corners whose triangles aren't flattened into lines, and as many normals and uvs if
+// there are any
+fn check_corners(surface: &Value, count: usize, check: &mut Checker) {
+    let points = |key: &str, size: usize| {
+        get(surface, key).map(|values| {
+            values.as_slice().map_or(false, |values| {
+                values.len() == count && values.iter().all(|value| {
+                    value.as_slice().map_or(false, |v| {
+                        v.len() == size && v.iter().all(|n| number(n).is_some())
+                    })
+                })
+            })
+        })
+    };
+    match points("corners", 3) {
+        Some(true) => {
+            let corners: Vec<Vec3> = surface.lookup("corners").unwrap().as_slice().unwrap().iter()
+                .map(|corner| {
+                    let n: Vec<Float> = corner.as_slice().unwrap().iter().filter_map(number)
+                        .collect();
+                    Vec3::new(n[0], n[1], n[2])
+                })
+                .collect();
+            // The triangles of the corners, see Quad
+            for i in 2..count {
+                let flat = cross(&(corners[i - 1] - corners[0]), &(corners[i] - corners[0]));
+                check.require(flat.norm_squared() > 0.,
+                              format!("corners 0, {} and {} lie on a line", i - 1, i));
+            }
+        }
+        Some(false) => check.problem(format!("corners should be {} [x, y, z] points", count)),
+        None => check.problem("corners is missing".to_owned()),
+    }
+    if points("normals", 3) == Some(false) {
+        check.problem(format!("normals should be {} [x, y, z] vectors", count));
+    }
+    if points("uvs", 2) == Some(false) {
+        check.problem(format!("uvs should be {} [u, v] pairs", count));
+    }
+}
+
+fn check_light(light: &Value, path: &str, problems: &mut Vec<String>) {
+    let type_ = light.lookup("type").map_or(Some("point"), Value::as_str).unwrap_or("");
+    let path = format!("{} ({})", path, type_);
+    let mut check = Checker { value: light, path: path, problems: problems };
+    if !LIGHT_TYPES.contains(&type_) {
+        check.problem(format!("type should be one of {}", LIGHT_TYPES.join(", ")));
+        return;
+    }
+    if type_ == "directional" {
+        check.direction("dir");
+    } else {
+        check.vec3("pos");
+    }
+    check.vec3("color");
+    if let Some(intensity) = check.number("intensity") {
+        check.require(intensity >= 0., format!("intensity can't be negative: {}", intensity));
+    }
+    if light.lookup("group").is_some() {
+        if let Some(group) = check.string("group") {
+            check.require(group != "other", "other can't be a light group's name".to_owned());
+        }
+    }
+    match type_ {
+        "sphere" => {
+            check.positive("radius");
+        }
+        "rect" => {
+            if let (Some(u), Some(v)) = (check.vec3("u"), check.vec3("v")) {
+                check.require(cross(&u, &v).norm_squared() > 0.,
+                              "u and v must span a rectangle".to_owned());
+            }
+        }
+        "spot" => {
+            check.direction("dir");
+            check.positive("angle");
+        }
+        _ => {}
+    }
+}
+
+// The tables of the array `key` in `table`, none if it's missing
+fn array<'a>(table: &'a Value, key: &str, problems: &mut Vec<String>) -> &'a [Value] {
+    match get(table, key).map(Value::as_slice) {
+        Some(Some(tables)) => tables,
+        Some(None) => {
+            problems.push(format!("{} should be [[{}]] tables", key, key));
+            &[]
+        }
+        None => &[],
+    }
+}
+
+// Like lookup, but the value can outlive `key`
+fn get<'a>(table: &'a Value, key: &str) -> Option<&'a Value> {
+    table.as_table().and_then(|table| table.get(key))
+}
+
+// Accepts integers too, like decode_f32
+fn number(value: &Value) -> Option<Float> {
+    value.as_float().map(|f| f as Float).or(value.as_integer().map(|i| i as Float))
+}
+
+// Checks the fields of one table, adding problems prefixed with its path
+struct Checker<'a, 'b> {
+    value: &'a Value,
+    path: String,
+    problems: &'b mut Vec<String>,
+}
+
+impl<'a, 'b> Checker<'a, 'b> {
+    fn problem(&mut self, problem: String) {
+        self.problems.push(format!("{}: {}", self.path, problem));
+    }
+
+    fn require(&mut self, ok: bool, problem: String) {
+        if !ok {
+            self.problem(problem);
+        }
+    }
+
+    // The value of `key`, or None after adding a problem if it's missing
+    fn field(&mut self, key: &str) -> Option<&'a Value> {
+        let value = get(self.value, key);
+        if value.is_none() {
+            self.problem(format!("{} is missing", key));
+        }
+        value
+    }
+
+    fn string(&mut self, key: &str) -> Option<&'a str> {
+        self.field(key).and_then(|value| {
+            let s = value.as_str();
+            self.require(s.is_some(), format!("{} should be a string", key));
+            s
+        })
+    }
+
+    fn number(&mut self, key: &str) -> Option<Float> {
+        self.field(key).and_then(|value| self.as_number(key, value))
+    }
+
+    fn optional_number(&mut self, key: &str) -> Option<Float> {
+        self.value.lookup(key).and_then(|value| self.as_number(key, value))
+    }
+
+    fn integer(&mut self, key: &str) -> Option<i64> {
+        self.field(key).and_then(|value| self.as_integer(key, value))
+    }
+
+    fn optional_integer(&mut self, key: &str) -> Option<i64> {
+        self.value.lookup(key).and_then(|value| self.as_integer(key, value))
+    }
+
+    fn as_integer(&mut self, key: &str, value: &Value) -> Option<i64> {
+        let n = value.as_integer();
+        self.require(n.is_some(), format!("{} should be an integer", key));
+        n
+    }
+
+    // Optional, true or false
+    fn boolean(&mut self, key: &str) {
+        if let Some(value) = self.value.lookup(key) {
+            self.require(value.as_bool().is_some(), format!("{} should be true or false", key));
+        }
+    }
+
+    // Optional, a name that parses as a T
+    fn parsed<T: FromStr<Err = String>>(&mut self, key: &str) {
+        if let Some(value) = self.value.lookup(key) {
+            match value.as_str().map(T::from_str) {
+                Some(Ok(_)) => {}
+                Some(Err(problem)) => self.problem(format!("{}: {}", key, problem)),
+                None => self.problem(format!("{} should be a string", key)),
+            }
+        }
+    }
+
+    fn as_number(&mut self, key: &str, value: &Value) -> Option<Float> {
+        let n = number(value);
+        self.require(n.map_or(false, Float::is_finite), format!("{} should be a number", key));
+        n
+    }
+
+    fn positive(&mut self, key: &str) -> Option<Float> {
+        self.number(key).and_then(|n| {
+            self.require(n > 0., format!("{} must be positive, not {}", key, n));
+            if n > 0. { Some(n) } else { None }
+        })
+    }
+
+    // Optional, between 0 and 1
+    fn fraction(&mut self, key: &str) {
+        if let Some(n) = self.optional_number(key) {
+            self.require(n >= 0. && n <= 1.,
+                         format!("{} must be between 0 and 1, not {}", key, n));
+        }
+    }
+
+    // Either of `two_sided` and `cull_backfaces`, as true or false
+    fn backfaces(&mut self) {
+        let mut set = 0;
+        for key in &["two_sided", "cull_backfaces"] {
+            if let Some(value) = self.value.lookup(key) {
+                self.require(value.as_bool().is_some(), format!("{} should be true or false", key));
+                if value.as_bool() == Some(true) {
+                    set += 1;
+                }
+            }
+        }
+        self.require(set < 2, "faces can't be both two_sided and culled".to_owned());
+    }
+
+    fn vec3(&mut self, key: &str) -> Option<Vec3> {
+        self.field(key).and_then(|value| {
+            let v = value.as_slice().and_then(|v| {
+                match (v.len(), v.get(0).and_then(number), v.get(1).and_then(number),
+                       v.get(2).and_then(number)) {
+                    (3, Some(x), Some(y), Some(z)) => Some(Vec3::new(x, y, z)),
+                    _ => None,
+                }
+            });
+            self.require(v.is_some(), format!("{} should be [x, y, z]", key));
+            v
+        })
+    }
+
+    // A vector that's not zero
+    fn direction(&mut self, key: &str) {
+        if let Some(v) = self.vec3(key) {
+            self.require(v.norm_squared() > 0., format!("{} can't be [0, 0, 0]", key));
+        }
+    }
+
+    // Optional, the name of a file that exists
+    fn file(&mut self, key: &str) {
+        if let Some(value) = self.value.lookup(key) {
+            match value.as_str() {
+                Some(file) => {
+                    self.require(Path::new(file).exists(),
+                                 format!("{}: no such file {}", key, file))
+                }
+                None => self.problem(format!("{} should be a file name", key)),
+            }
+        }
+    }
+}
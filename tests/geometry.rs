@@ -0,0 +1,2321 @@
+// Randomized tests of geometric invariants. Each property is checked against many random rays
+// and primitives from a fixed seed, so failures are reproducible.
+
+extern crate image;
+extern crate nalgebra;
+extern crate rand;
+extern crate rustc_serialize;
+extern crate tracerlib;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use tracerlib::{float, ray_trace_events, Camera, Float, Projection, Scene, Vec3};
+use tracerlib::accumulate::{ray_trace_runs, Accumulator};
+use tracerlib::bake::bake_lightmaps;
+use tracerlib::bounds::Aabb;
+use tracerlib::bvh::BvhSettings;
+use tracerlib::compact::CompactMesh;
+use tracerlib::csg::{Csg, CsgOp};
+use tracerlib::dump::{trace_pixel, RayDump};
+use tracerlib::gltf;
+use tracerlib::heightfield::Heightfield;
+use tracerlib::light::{Falloff, LightSelection, LightShape, PointLight};
+use tracerlib::material::{Backfaces, Material, Shading, Visibility};
+use tracerlib::medium::Medium;
+use tracerlib::portal::Portal;
+use tracerlib::procedural::{Basis, Bump, Pattern, Procedural};
+use tracerlib::mesh::{Triangle, TriangleMesh};
+use tracerlib::output::{self, Format};
+use tracerlib::quartic;
+use tracerlib::ray::{self, Bias, Differentials, Intersection, Ray};
+use tracerlib::sampler;
+use tracerlib::sdf::{Sdf, Shape};
+use tracerlib::section::{SectionPlane, Sectioned};
+use tracerlib::sh::Sh9;
+use tracerlib::spectral;
+use tracerlib::stats;
+use tracerlib::subdivision::{Face, PolygonMesh};
+use tracerlib::surface::{self, Cone, Cylinder, Disk, Plane, PlanePattern, Quad, Sphere, Surface,
+                         Torus};
+use tracerlib::texture::{self, CachedImageTexture, CheckerboardTexture, ImageTexture, Mipmap,
+                         Texture, TextureCache};
+use tracerlib::tiles::{tile_order, TileOrder};
+use tracerlib::transform::{Instance, Moving, Transform, Transformed};
+
+use image::{ImageFormat, Rgb, RgbImage};
+
+use nalgebra::{cross, dot, Norm};
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+const CASES: u32 = 10000;
+
+fn rng() -> XorShiftRng {
+    XorShiftRng::from_seed([1, 2, 3, 4])
+}
+
+fn material() -> Material {
+    Material::new(Vec3::new(1., 1., 1.), 1., 0., 0., 0., None, None, None)
+}
+
+fn random_vec(rng: &mut XorShiftRng, range: Float) -> Vec3 {
+    Vec3::new(rng.gen_range(-range, range), rng.gen_range(-range, range),
+              rng.gen_range(-range, range))
+}
+
+fn random_dir(rng: &mut XorShiftRng) -> Vec3 {
+    loop {
+        let v = random_vec(rng, 1.);
+        let len = v.norm();
+        if len > 0.01 && len <= 1. {
+            return v / len;
+        }
+    }
+}
+
+fn assert_close(a: Float, b: Float, tolerance: Float, what: &str) {
+    assert!((a - b).abs() <= tolerance, "{}: {} != {} (tolerance {})", what, a, b, tolerance);
+}
+
+fn assert_unit(v: &Vec3, what: &str) {
+    assert_close(v.norm(), 1., 1e-4, what);
+}
+
+// Checks the intersection is consistent with the ray it came from
+fn check_hit_on_ray(ray: &Ray, pos: &Vec3, dist: Float, scale: Float) {
+    assert!(dist > 0., "hit behind the ray origin: {}", dist);
+    let expected = ray.origin + ray.dir * dist;
+    assert_close((expected - *pos).norm(), 0., 1e-4 * scale, "hit position along ray");
+}
+
+#[test]
+fn sphere_hits_lie_on_surface() {
+    let mut rng = rng();
+    let mut hits = 0;
+    for _ in 0..CASES {
+        let center = random_vec(&mut rng, 100.);
+        let radius = rng.gen_range(0.01, 50.);
+        let sphere = Sphere::new(center, radius, material());
+
+        // Aim somewhere near the sphere so that roughly half the rays hit it
+        let origin = random_vec(&mut rng, 200.);
+        let target = center + random_vec(&mut rng, radius * 1.5);
+        let ray = Ray::new(origin, target - origin);
+
+        if let Some(hit) = sphere.intersect(&ray) {
+            hits += 1;
+            let scale = radius + (origin - center).norm();
+            assert_close((hit.pos - center).norm(), radius, 1e-4 * scale, "distance to center");
+            assert_unit(&hit.normal, "sphere normal");
+            check_hit_on_ray(&ray, &hit.pos, hit.dist, scale);
+        }
+    }
+    assert!(hits > CASES / 10, "too few rays hit the spheres: {}", hits);
+}
+
+#[test]
+fn sphere_hit_from_inside() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let center = random_vec(&mut rng, 100.);
+        let radius = rng.gen_range(0.1, 50.);
+        let sphere = Sphere::new(center, radius, material());
+        let origin = center + random_dir(&mut rng) * radius * rng.gen_range(0., 0.9);
+        let ray = Ray::new(origin, random_dir(&mut rng));
+
+        let hit = sphere.intersect(&ray).expect("ray from inside the sphere must hit it");
+        let scale = radius + center.norm();
+        assert_close((hit.pos - center).norm(), radius, 1e-4 * scale, "distance to center");
+        check_hit_on_ray(&ray, &hit.pos, hit.dist, scale);
+    }
+}
+
+#[test]
+fn sphere_behind_ray_is_missed() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let center = random_vec(&mut rng, 100.);
+        let radius = rng.gen_range(0.01, 50.);
+        let sphere = Sphere::new(center, radius, material());
+
+        let away = random_dir(&mut rng);
+        let origin = center + away * radius * rng.gen_range(1.01, 10.);
+        assert!(sphere.intersect(&Ray::new(origin, away)).is_none());
+    }
+}
+
+#[test]
+fn sphere_tangent_rays() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let center = random_vec(&mut rng, 100.);
+        let radius = rng.gen_range(0.01, 50.);
+        let sphere = Sphere::new(center, radius, material());
+
+        // A ray grazing the sphere at `touch`, which may or may not count as a hit
+        let normal = random_dir(&mut rng);
+        let touch = center + normal * radius;
+        let dir = cross(&normal, &random_dir(&mut rng)).normalize();
+        let origin = touch - dir * rng.gen_range(1., 100.);
+        let ray = Ray::new(origin, dir);
+
+        if let Some(hit) = sphere.intersect(&ray) {
+            let scale = radius + (origin - center).norm();
+            assert!(hit.pos.x.is_finite() && hit.pos.y.is_finite() && hit.pos.z.is_finite(),
+                    "non finite tangent hit {:?}", hit.pos);
+            assert_close((hit.pos - center).norm(), radius, 1e-3 * scale, "distance to center");
+            assert_unit(&hit.normal, "tangent normal");
+        }
+    }
+}
+
+#[test]
+fn scaled_sphere_is_an_ellipsoid() {
+    let mut rng = rng();
+    let mut hits = 0;
+    for _ in 0..CASES {
+        let center = random_vec(&mut rng, 100.);
+        let axes = Vec3::new(rng.gen_range(0.1, 20.), rng.gen_range(0.1, 20.),
+                             rng.gen_range(0.1, 20.));
+        let sphere = Box::new(Sphere::new(Vec3::new(0., 0., 0.), 1., material()));
+        let ellipsoid = Transformed::new(sphere, axes, Vec3::new(0., 0., 0.), center);
+
+        let origin = random_vec(&mut rng, 200.);
+        let target = center + random_vec(&mut rng, 20.);
+        let ray = Ray::new(origin, target - origin);
+
+        if let Some(hit) = ellipsoid.intersect(&ray) {
+            hits += 1;
+            let scale = 20. + (origin - center).norm();
+            let p = hit.pos - center;
+            let local = Vec3::new(p.x / axes.x, p.y / axes.y, p.z / axes.z);
+            assert_close(local.norm(), 1., 1e-3 * scale, "on the ellipsoid");
+            let gradient = Vec3::new(local.x / axes.x, local.y / axes.y, local.z / axes.z);
+            assert_unit(&hit.normal, "ellipsoid normal");
+            assert_close(dot(&hit.normal, &gradient.normalize()), 1., 1e-3, "ellipsoid normal");
+            check_hit_on_ray(&ray, &hit.pos, hit.dist, scale);
+            let bounds = ellipsoid.bounds().unwrap();
+            let slack = 1e-3 * scale;
+            assert!(hit.pos.x >= bounds.min.x - slack && hit.pos.x <= bounds.max.x + slack &&
+                    hit.pos.y >= bounds.min.y - slack && hit.pos.y <= bounds.max.y + slack &&
+                    hit.pos.z >= bounds.min.z - slack && hit.pos.z <= bounds.max.z + slack,
+                    "hit outside the bounds");
+        }
+    }
+    assert!(hits > CASES / 10, "too few rays hit the ellipsoids: {}", hits);
+}
+
+#[test]
+fn rotation_keeps_sphere_hits() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let center = random_vec(&mut rng, 100.);
+        let radius = rng.gen_range(0.1, 20.);
+        let sphere = Sphere::new(center, radius, material());
+        // Rotating about the sphere's center doesn't move its surface
+        let rotated = Transformed::new(Box::new(Sphere::new(Vec3::new(0., 0., 0.), radius,
+                                                            material())),
+                                       Vec3::new(1., 1., 1.), random_vec(&mut rng, 3.), center);
+
+        let origin = random_vec(&mut rng, 200.);
+        let ray = Ray::new(origin, center + random_vec(&mut rng, radius * 1.5) - origin);
+        match (sphere.intersect(&ray), rotated.intersect(&ray)) {
+            (Some(a), Some(b)) => {
+                let scale = radius + (origin - center).norm();
+                assert_close(a.dist, b.dist, 1e-3 * scale, "distance");
+                assert_close(dot(&a.normal, &b.normal), 1., 1e-3, "normal");
+            }
+            (None, None) => {}
+            // Grazing rays may go either way
+            (a, b) => assert!(dot(&(a.or(b).unwrap().normal), &ray.dir).abs() < 0.05,
+                              "rotation changed whether a ray hits"),
+        }
+    }
+}
+
+#[test]
+fn composed_transforms_place_like_nested_ones() {
+    fn random_transform(rng: &mut XorShiftRng) -> (Vec3, Vec3, Vec3) {
+        let scale = Vec3::new(rng.gen_range(0.2, 5.), rng.gen_range(0.2, 5.),
+                              rng.gen_range(0.2, 5.));
+        (scale, random_vec(rng, float::consts::PI), random_vec(rng, 20.))
+    }
+
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let (inner, outer) = (random_transform(&mut rng), random_transform(&mut rng));
+        let sphere = || Box::new(Sphere::new(Vec3::new(0., 0., 0.), 1., material()));
+        // A surface in a group, and the same surface flattened out of it
+        let nested = Transformed::new(Box::new(Transformed::new(sphere(), inner.0, inner.1,
+                                                                inner.2)),
+                                      outer.0, outer.1, outer.2);
+        let composed = Transform::new(inner.0, inner.1, inner.2)
+            .then(&Transform::new(outer.0, outer.1, outer.2));
+        let flat = Transformed::placed(sphere(), composed);
+
+        let origin = random_vec(&mut rng, 200.);
+        let bounds = nested.bounds().unwrap();
+        let ray = Ray::new(origin, (bounds.min + bounds.max) / 2. - origin);
+        match (nested.intersect(&ray), flat.intersect(&ray)) {
+            (Some(a), Some(b)) => {
+                let scale = 20. + origin.norm();
+                assert_close(a.dist, b.dist, 1e-3 * scale, "distance");
+                assert_close(dot(&a.normal, &b.normal), 1., 1e-3, "normal");
+            }
+            (None, None) => {}
+            (a, b) => assert!(dot(&(a.or(b).unwrap().normal), &ray.dir).abs() < 0.05,
+                              "flattening changed whether a ray hits"),
+        }
+    }
+}
+
+#[test]
+fn moving_sphere_is_where_it_is_at_the_ray_time() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let center = random_vec(&mut rng, 10.);
+        let motion = random_vec(&mut rng, 5.);
+        let moving = Moving::new(Box::new(Sphere::new(center, 1., material())), motion);
+        let time = rng.gen_range(0., 1.);
+        let there = Sphere::new(center + motion * time, 1., material());
+        let origin = random_vec(&mut rng, 20.);
+        let ray = Ray::new(origin, random_vec(&mut rng, 10.) - origin).with_time(time);
+
+        match (moving.intersect(&ray), there.intersect(&ray)) {
+            (Some(hit), Some(expected)) => {
+                assert_close(hit.dist, expected.dist, 1e-3, "distance to moving sphere");
+                assert_close((hit.pos - expected.pos).norm(), 0., 1e-3, "moving sphere hit");
+            }
+            (None, None) => {}
+            (hit, expected) => panic!("moving sphere hit {:?}, expected {:?}",
+                                      hit.map(|h| h.dist), expected.map(|h| h.dist)),
+        }
+        let bounds = moving.bounds().unwrap();
+        let p = center + motion * time;
+        assert!(bounds.min.x <= p.x && p.x <= bounds.max.x && bounds.min.y <= p.y &&
+                p.y <= bounds.max.y && bounds.min.z <= p.z && p.z <= bounds.max.z,
+                "moving sphere leaves its bounds");
+    }
+}
+
+#[test]
+fn csg_difference_hits_boundary() {
+    let mut rng = rng();
+    let mut hits = 0;
+    for _ in 0..CASES {
+        let (center_a, radius_a) = (random_vec(&mut rng, 50.), rng.gen_range(1., 20.));
+        let center_b = center_a + random_vec(&mut rng, radius_a);
+        let radius_b = rng.gen_range(0.5, radius_a);
+        let solid = Csg::new(CsgOp::Difference, Box::new(Sphere::new(center_a, radius_a,
+                                                                     material())),
+                             Box::new(Sphere::new(center_b, radius_b, material())));
+        let inside = |p: &Vec3| {
+            (*p - center_a).norm() < radius_a && (*p - center_b).norm() > radius_b
+        };
+
+        let origin = random_vec(&mut rng, 100.);
+        let ray = Ray::new(origin, center_a + random_vec(&mut rng, radius_a) - origin);
+        if let Some(hit) = solid.intersect(&ray) {
+            hits += 1;
+            let scale = radius_a + (origin - center_a).norm();
+            let tolerance = 1e-3 * scale;
+            let (dist_a, dist_b) = ((hit.pos - center_a).norm(), (hit.pos - center_b).norm());
+            if (dist_a - radius_a).abs() <= tolerance {
+                assert!(dist_b >= radius_b - tolerance, "hit on the first sphere inside the hole");
+            } else {
+                assert_close(dist_b, radius_b, tolerance, "hit on neither sphere");
+                assert!(dist_a <= radius_a + tolerance, "hit on the hole outside the solid");
+                assert_close(dot(&hit.normal, &(center_b - hit.pos).normalize()), 1., 1e-3,
+                             "normal of the hole points into it");
+            }
+            check_hit_on_ray(&ray, &hit.pos, hit.dist, scale);
+            // The ray is outside the solid until it enters, and inside until it leaves again
+            if !inside(&origin) && dot(&hit.normal, &ray.dir) < -0.1 {
+                assert!(!inside(&((origin + hit.pos) / 2.)), "entered late");
+                // Unless the step past the hit already went through a thin part
+                let after = Ray::new(hit.pos + ray.dir * tolerance, ray.dir);
+                if inside(&after.origin) {
+                    let exit = solid.intersect(&after).expect("didn't leave");
+                    assert!(inside(&((after.origin + exit.pos) / 2.)), "left too early");
+                }
+            }
+        }
+    }
+    assert!(hits > CASES / 10, "too few rays hit the solids: {}", hits);
+}
+
+#[test]
+fn round_primitive_hits_face_outwards() {
+    let mut rng = rng();
+    let (base, top) = (Vec3::new(0.5, -1., 0.2), Vec3::new(-0.3, 1., 0.4));
+    let axis = (top - base).normalize();
+    let surfaces: Vec<(Box<Surface>, Vec3)> = vec![
+        (Box::new(Cylinder::new(base, top, 0.7, material())), (base + top) / 2.),
+        (Box::new(Cone::new(base, top, 0.9, material())), base + (top - base) / 4.),
+    ];
+    let disk = Disk::new(base, axis, 0.8, material());
+    for _ in 0..CASES {
+        let origin = random_vec(&mut rng, 5.);
+        let target = random_vec(&mut rng, 1.);
+        let ray = Ray::new(origin, target - origin);
+        for &(ref surface, center) in surfaces.iter() {
+            let hit = match surface.intersect(&ray) {
+                Some(hit) => hit,
+                None => continue,
+            };
+            assert_unit(&hit.normal, "normal");
+            check_hit_on_ray(&ray, &hit.pos, hit.dist, 5.);
+            // Both shapes are convex, so the normal faces away from a point inside
+            assert!(dot(&hit.normal, &(hit.pos - center)) > 0., "{} normal faces inwards",
+                    surface.name());
+            let height = dot(&(hit.pos - base), &axis);
+            assert!(height > -1e-4 && height < (top - base).norm() + 1e-4, "{} hit at height {}",
+                    surface.name(), height);
+        }
+        if let Some(hit) = disk.intersect(&ray) {
+            check_hit_on_ray(&ray, &hit.pos, hit.dist, 5.);
+            assert_close(dot(&(hit.pos - base), &axis), 0., 1e-4, "disk hit off its plane");
+            assert!((hit.pos - base).norm() <= 0.8 + 1e-4, "disk hit outside its radius");
+        }
+    }
+}
+
+#[test]
+fn torus_hits_lie_on_the_tube() {
+    // (x - 1)(x + 2)(x - 0.5)(x - 1.001), with two close roots
+    let roots = quartic::solve_quartic(1., -0.501, -3.0005, 3.5025, -1.001);
+    assert!(roots.len() == 4, "roots: {:?}", roots);
+    for (root, expected) in roots.iter().zip(&[-2., 0.5, 1., 1.001]) {
+        assert!((root - expected).abs() < 1e-9, "root {} instead of {}", root, expected);
+    }
+
+    let mut rng = rng();
+    let (center, axis) = (Vec3::new(0.3, -0.2, 0.1), Vec3::new(0.2, 1., -0.4).normalize());
+    let torus = Torus::new(center, axis, 1.2, 0.4, material());
+    let mut hits = 0;
+    for _ in 0..CASES {
+        let origin = random_vec(&mut rng, 5.);
+        let target = random_vec(&mut rng, 1.5);
+        let ray = Ray::new(origin, target - origin);
+        let hit = match torus.intersect(&ray) {
+            Some(hit) => hit,
+            None => continue,
+        };
+        hits += 1;
+        assert_unit(&hit.normal, "normal");
+        check_hit_on_ray(&ray, &hit.pos, hit.dist, 5.);
+        let offset = hit.pos - center;
+        let across = offset - axis * dot(&offset, &axis);
+        let ring = center + across.normalize() * 1.2;
+        assert_close((hit.pos - ring).norm(), 0.4, 1e-4, "hit off the tube");
+        assert!(dot(&hit.normal, &(hit.pos - ring)) > 0., "normal faces inwards");
+    }
+    assert!(hits > CASES / 10, "too few rays hit the torus: {}", hits);
+    // Straight through the hole
+    assert!(torus.intersect(&Ray::new(center - axis * 3., axis)).is_none());
+}
+
+// Sphere traced shapes land on their zero distance, and an SDF sphere is hit where the analytic
+// one is, from outside and inside
+#[test]
+fn sdf_hits_lie_on_the_surface() {
+    let mut rng = rng();
+    let center = Vec3::new(0.3, -0.2, 0.1);
+    let sphere = Sphere::new(center, 1.1, material());
+    let sdf_sphere = Sdf::new(Shape::Sphere { center: center, radius: 1.1 }, material());
+    for _ in 0..CASES {
+        let origin = if rng.gen() { random_vec(&mut rng, 5.) } else { center };
+        let ray = Ray::new(origin, random_vec(&mut rng, 1.5) - origin);
+        // Rays grazing the sphere can be hit by one and missed by the other
+        let offset = origin - center;
+        if ((offset - ray.dir * dot(&offset, &ray.dir)).norm() - 1.1).abs() < 1e-2 {
+            continue;
+        }
+        match (sdf_sphere.intersect(&ray), sphere.intersect(&ray)) {
+            (Some(hit), Some(expected)) => {
+                assert_close(hit.dist, expected.dist, 1e-3, "distance to the SDF sphere");
+                assert!(dot(&hit.normal, &expected.normal) > 0.999, "SDF sphere normal");
+            }
+            (None, None) => {}
+            (hit, _) => {
+                panic!("the SDF sphere was {}", if hit.is_some() { "hit" } else { "missed" })
+            }
+        }
+    }
+
+    // Two balls too far apart to touch melted together, and blended into a box below them
+    let shape = || {
+        let ball = |x: Float| Box::new(Shape::Sphere { center: Vec3::new(x, 0., 0.), radius: 0.5 });
+        let slab = Shape::RoundedBox {
+            center: Vec3::new(0., -1., 0.),
+            size: Vec3::new(3., 0.5, 1.),
+            radius: 0.1,
+        };
+        Shape::SmoothUnion(Box::new(Shape::SmoothUnion(ball(-0.6), ball(0.6), 0.8)),
+                           Box::new(slab), 0.3)
+    };
+    let (blended, sdf) = (shape(), Sdf::new(shape(), material()));
+    assert!(blended.distance(&Vec3::new(0., 0., 0.)) < 0., "the fillet doesn't fill the gap");
+    let mut hits = 0;
+    for _ in 0..CASES {
+        let origin = random_vec(&mut rng, 5.);
+        let ray = Ray::new(origin, random_vec(&mut rng, 1.5) - origin);
+        if let Some(hit) = sdf.intersect(&ray) {
+            hits += 1;
+            check_hit_on_ray(&ray, &hit.pos, hit.dist, 5.);
+            assert_unit(&hit.normal, "SDF normal");
+            assert_close(blended.distance(&hit.pos), 0., 1e-3, "distance at the SDF hit");
+            let outside = hit.pos + hit.normal * 1e-2;
+            assert!(blended.distance(&outside) > 0., "the SDF normal points inwards");
+        }
+    }
+    assert!(hits > CASES / 10, "too few rays hit the SDF: {}", hits);
+}
+
+// A sphere cut in half along x keeps only its hits on the far side of the plane, and with a cap
+// looks solid where the cut opens it
+#[test]
+fn sectioned_surfaces_are_cut_by_their_planes() {
+    let mut rng = rng();
+    let sectioned = |cap: bool| {
+        let sphere = Box::new(Sphere::new(Vec3::new(0., 0., 0.), 1., material()));
+        Sectioned::new(sphere, vec![SectionPlane::new(Vec3::new(0., 0., 0.), Vec3::new(2., 0., 0.),
+                                                      cap)])
+    };
+    let (open, capped) = (sectioned(false), sectioned(true));
+    let ray = Ray::new(Vec3::new(3., 0., 0.), Vec3::new(-1., 0., 0.));
+    assert_close(open.intersect(&ray).unwrap().dist, 4., 1e-4, "distance through the cut");
+    let cap = capped.intersect(&ray).unwrap();
+    assert_close(cap.dist, 3., 1e-4, "distance to the cap");
+    assert_close(cap.normal.x, 1., 1e-6, "cap normal");
+
+    let mut hits = 0;
+    for _ in 0..CASES {
+        let origin = random_vec(&mut rng, 3.);
+        let ray = Ray::new(origin, random_vec(&mut rng, 0.8) - origin);
+        for surface in &[&open, &capped] {
+            if let Some(hit) = surface.intersect(&ray) {
+                hits += 1;
+                check_hit_on_ray(&ray, &hit.pos, hit.dist, 5.);
+                assert!(hit.pos.x <= 1e-4, "hit on the cut side at {:?}", hit.pos);
+                let on_sphere = (hit.pos.norm() - 1.).abs() < 1e-4;
+                assert!(on_sphere || hit.pos.x.abs() < 1e-4 && hit.pos.norm() <= 1. + 1e-4,
+                        "hit off the sphere and the cap at {:?}", hit.pos);
+            }
+        }
+    }
+    assert!(hits > CASES / 10, "too few rays hit the sectioned sphere: {}", hits);
+}
+
+// Hits on a triangle and a quad get the uvs and normals of their corners blended by where they
+// are, and the uv layout leads back to the same points
+#[test]
+fn triangle_and_quad_hits_interpolate_their_corners() {
+    let mut rng = rng();
+    let up = Vec3::new(0., 0., -1.);
+    let tilted = Vec3::new(1., 0., -1.);
+    let triangle = surface::Triangle::new([Vec3::new(0., 0., 0.), Vec3::new(1., 0., 0.),
+                                           Vec3::new(0., 1., 0.)], material())
+        .with_uvs([(0., 0.), (0.5, 0.), (0., 0.5)])
+        .with_normals([up, tilted, up]);
+    let quad = Quad::new([Vec3::new(0., 0., 0.), Vec3::new(2., 0., 0.), Vec3::new(2., 0., 1.),
+                          Vec3::new(0., 0., 1.)], material());
+    assert_close(triangle.sample_area(0.3, 0.6).unwrap().2, 0.5, 1e-6, "triangle area");
+    assert_close(quad.sample_area(0.3, 0.6).unwrap().2, 2., 1e-6, "quad area");
+
+    let (mut triangle_hits, mut quad_hits) = (0, 0);
+    for _ in 0..CASES {
+        let origin = random_vec(&mut rng, 5.);
+        let target = Vec3::new(rng.gen_range(0., 2.), rng.gen_range(0., 1.), 0.);
+        if let Some(hit) = triangle.intersect(&Ray::new(origin, target - origin)) {
+            triangle_hits += 1;
+            assert!(target.x + target.y <= 1. + 1e-4, "hit outside the triangle");
+            assert_close(hit.pos.z, 0., 1e-4, "triangle hit height");
+            assert_close(hit.u, hit.pos.x / 2., 1e-4, "triangle u");
+            assert_close(hit.v, hit.pos.y / 2., 1e-4, "triangle v");
+            let expected = (up * (1. - hit.pos.x) + tilted.normalize() * hit.pos.x).normalize();
+            assert!(dot(&hit.normal, &expected) > 0.9999, "triangle normal {:?}", hit.normal);
+            let point = triangle.surface_point(hit.u, hit.v).unwrap();
+            assert!((point.pos - hit.pos).norm() < 1e-4, "triangle uv leads elsewhere");
+        }
+        let target = Vec3::new(target.x, 0., target.y);
+        if let Some(hit) = quad.intersect(&Ray::new(origin, target - origin)) {
+            quad_hits += 1;
+            assert_close(hit.pos.y, 0., 1e-4, "quad hit height");
+            assert_close(hit.u, hit.pos.x / 2., 1e-4, "quad u");
+            assert_close(hit.v, hit.pos.z, 1e-4, "quad v");
+            let point = quad.surface_point(hit.u, hit.v).unwrap();
+            assert!((point.pos - hit.pos).norm() < 1e-4, "quad uv leads elsewhere");
+        }
+    }
+    assert!(triangle_hits > CASES / 10 && quad_hits > CASES / 2,
+            "too few rays hit: {} and {}", triangle_hits, quad_hits);
+}
+
+#[test]
+fn plane_hits_lie_on_plane() {
+    let mut rng = rng();
+    let mut hits = 0;
+    for _ in 0..CASES {
+        let point = random_vec(&mut rng, 100.);
+        // Deliberately not normalized
+        let normal = random_dir(&mut rng) * rng.gen_range(0.1, 10.);
+        let plane = Plane::new(point, normal, material());
+        let origin = random_vec(&mut rng, 200.);
+        let ray = Ray::new(origin, random_dir(&mut rng));
+
+        if let Some(hit) = plane.intersect(&ray) {
+            hits += 1;
+            // Grazing rays hit far away, where the error grows with the distance
+            let scale = (origin - point).norm() + hit.dist + 1.;
+            assert_close(dot(&(hit.pos - point), &normal.normalize()), 0., 1e-4 * scale,
+                         "distance to plane");
+            assert_unit(&hit.normal, "plane normal");
+            check_hit_on_ray(&ray, &hit.pos, hit.dist, scale);
+        }
+    }
+    assert!(hits > CASES / 10, "too few rays hit the planes: {}", hits);
+}
+
+#[test]
+fn plane_parallel_rays_are_missed() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let normal = Vec3::new(0., 1., 0.);
+        let plane = Plane::new(random_vec(&mut rng, 100.), normal, material());
+        let dir = Vec3::new(rng.gen_range(-1., 1.), 0., rng.gen_range(-1., 1.));
+        if dir.norm() < 0.01 {
+            continue;
+        }
+        let ray = Ray::new(random_vec(&mut rng, 200.), dir);
+        assert!(plane.intersect(&ray).is_none());
+    }
+}
+
+#[test]
+fn ray_bias_keeps_leaving_rays_off_far_surfaces() {
+    let bias = Bias::default();
+    let mut rng = rng();
+    for _ in 0..CASES {
+        // Far from the origin, where hit positions are off by more than the smallest offset
+        let center = random_vec(&mut rng, 1e5);
+        let radius = rng.gen_range(1., 1e3);
+        let sphere = Sphere::new(center, radius, material());
+        let origin = center + random_dir(&mut rng) * radius * 3.;
+        let ray = Ray::new(origin, center + random_vec(&mut rng, radius * 0.5) - origin);
+        let hit = match sphere.intersect(&ray) {
+            Some(hit) => hit,
+            None => continue,
+        };
+        let dir = random_dir(&mut rng);
+        let dir = if dot(&dir, &hit.normal) < 0. { -dir } else { dir };
+        if dot(&dir, &hit.normal) < 0.05 {
+            continue;
+        }
+        // Rays leaving a convex surface on its outside never hit it again
+        let leaving = Ray::new(bias.origin(&hit, &hit.normal), dir);
+        assert!(sphere.intersect(&leaving).is_none(), "ray leaving {:?} hit the sphere again",
+                hit.pos);
+    }
+}
+
+#[test]
+fn reflection_preserves_angle() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let dir = random_dir(&mut rng);
+        let normal = random_dir(&mut rng);
+        let reflected = ray::reflect(&dir, &normal);
+
+        assert_unit(&reflected, "reflected direction");
+        assert_close(dot(&reflected, &normal), -dot(&dir, &normal), 1e-5, "angle to normal");
+        // The reflected ray stays in the plane spanned by the incoming ray and the normal
+        assert_close(dot(&cross(&dir, &normal), &reflected), 0., 1e-5, "plane of incidence");
+    }
+}
+
+#[test]
+fn refraction_follows_snells_law() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let dir = random_dir(&mut rng);
+        let normal = random_dir(&mut rng);
+        // Refract needs the normal facing against the ray
+        let normal = if dot(&dir, &normal) > 0. { -normal } else { normal };
+        let eta = rng.gen_range(0.5, 2.);
+        let sin_i = cross(&dir, &normal).norm();
+
+        match ray::refract(&dir, &normal, eta) {
+            Some(refracted) => {
+                assert_unit(&refracted, "refracted direction");
+                assert_close(cross(&refracted, &normal).norm(), eta * sin_i, 1e-4, "sin of angle");
+                assert!(dot(&refracted, &normal) <= 0., "refracted ray went back");
+                assert_close(dot(&cross(&dir, &normal), &refracted), 0., 1e-5,
+                             "plane of incidence");
+            }
+            None => assert!(eta * sin_i > 1. - 1e-5, "total internal reflection at sin {}",
+                            eta * sin_i),
+        }
+    }
+}
+
+#[test]
+fn medium_dims_light_by_the_distance_inside_it() {
+    let mut rng = rng();
+    let (density, scattering, absorption) = (2., 0.1, 0.05);
+    let extinction = density * (scattering + absorption);
+    let fog = Medium::new(density, scattering, absorption);
+    let bounds = Aabb::new(Vec3::new(-1., -1., -1.), Vec3::new(1., 1., 1.));
+    let boxed = Medium::new(density, scattering, absorption).with_bounds(bounds);
+    for _ in 0..CASES {
+        let ray = Ray::new(random_vec(&mut rng, 3.), random_dir(&mut rng));
+        let dist = rng.gen_range(0., 10.);
+        assert_close(fog.transmittance(&ray, dist), (-extinction * dist).exp(), 1e-5,
+                     "transmittance of unbounded medium");
+        // The length inside the box, from points sampled along the ray
+        let steps = 2000;
+        let inside = (0..steps).filter(|&i| {
+            let p = ray.origin + ray.dir * (dist * (i as Float + 0.5) / steps as Float);
+            p.x.abs() <= 1. && p.y.abs() <= 1. && p.z.abs() <= 1.
+        }).count() as Float * dist / steps as Float;
+        assert_close(boxed.transmittance(&ray, dist), (-extinction * inside).exp(), 0.01,
+                     "transmittance of medium in a box");
+    }
+    let ray = Ray::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.));
+    assert!(fog.transmittance(&ray, float::INFINITY) == 0., "light from infinitely far away");
+    assert_close(boxed.transmittance(&ray, float::INFINITY), (-extinction).exp(), 1e-5,
+                 "light from infinitely far away through a box");
+}
+
+#[test]
+fn spot_light_cone() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let axis = random_dir(&mut rng);
+        let angle = rng.gen_range(0.1, 1.5);
+        let falloff = rng.gen_range(0., angle);
+        let light = PointLight::new(Vec3::new(0., 0., 0.), Vec3::new(1., 1., 1.), 1.)
+            .with_spot(axis, angle, falloff);
+        // The light lies against `to_light` from the shaded point
+        let to_light = -random_dir(&mut rng);
+        let attenuation = light.attenuation(&to_light, rng.gen_range(0.1, 100.));
+        let off_axis = dot(&-to_light, &axis).min(1.).acos();
+        if off_axis < angle - falloff - 1e-3 {
+            assert_close(attenuation, 1., 1e-6, "attenuation inside the cone");
+        } else if off_axis > angle + 1e-3 {
+            assert_close(attenuation, 0., 1e-6, "attenuation outside the cone");
+        } else {
+            assert!(attenuation >= 0. && attenuation <= 1., "attenuation {}", attenuation);
+        }
+    }
+}
+
+#[test]
+fn light_falloff_with_distance() {
+    let mut rng = rng();
+    let light = |falloff| {
+        PointLight::new(Vec3::new(0., 0., 0.), Vec3::new(1., 1., 1.), 1.)
+            .with_falloff(falloff)
+    };
+    let none = light(Falloff::None);
+    let square = light(Falloff::InverseSquare);
+    let polynomial = light(Falloff::Polynomial { constant: 1., linear: 0.5, quadratic: 0.25 });
+    let sun = light(Falloff::InverseSquare)
+        .with_shape(LightShape::Directional(Vec3::new(0., -1., 0.)), 1);
+    let up = Vec3::new(0., 1., 0.);
+    for _ in 0..CASES {
+        let dist = rng.gen_range(0.1, 100.);
+        assert!(none.attenuation(&up, dist) == 1., "no falloff");
+        assert_close(square.attenuation(&up, dist) * dist * dist, 1., 1e-4, "inverse square");
+        assert_close(polynomial.attenuation(&up, dist) * (1. + 0.5 * dist + 0.25 * dist * dist),
+                     1., 1e-4, "polynomial falloff");
+        assert!(square.attenuation(&up, dist) >= square.attenuation(&up, dist * 1.5),
+                "farther is dimmer");
+    }
+    assert!(sun.attenuation(&up, float::INFINITY) == 1., "directional lights don't fall off");
+}
+
+#[test]
+fn absorption_dims_light_by_the_distance_inside() {
+    let glass = material().with_transparency(1., 1.).with_absorption(Vec3::new(0.5, 0., 1.));
+    let sphere = Sphere::new(Vec3::new(0., 0., 0.), 1., glass.clone());
+    let ray = Ray::new(Vec3::new(0., 0., -5.), Vec3::new(0., 0., 1.));
+    let entry = sphere.intersect(&ray).unwrap();
+    assert!(glass.interior_transmittance(&ray, &entry) == Vec3::new(1., 1., 1.),
+            "nothing is absorbed outside");
+
+    // Straight through the center, two units inside
+    let inside = Ray::new(entry.pos + ray.dir * 1e-3, ray.dir);
+    let exit = sphere.intersect(&inside).unwrap();
+    let left = glass.interior_transmittance(&inside, &exit);
+    assert_close(left.x, (-0.5 * 1.999 as Float).exp(), 1e-3, "red");
+    assert_close(left.y, 1., 1e-6, "green");
+    assert_close(left.z, Float::exp(-1.999), 1e-3, "blue");
+    assert!(material().interior_transmittance(&inside, &exit) == Vec3::new(1., 1., 1.),
+            "opaque materials have no inside");
+}
+
+#[test]
+fn light_color_tints_diffuse_and_specular() {
+    // A glossy floor with the light in its mirror image of the camera, so the highlight is in
+    // view
+    let render = |color: Vec3| {
+        let glossy = Material::new(Vec3::new(1., 1., 1.), 1., 1., 10., 0., None, None, None);
+        let floor = Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), glossy);
+        let light = PointLight::new(Vec3::new(0., 1., 2.), color, 1.);
+        let camera = Camera::from_lookat(Vec3::new(0., 1., -2.), Vec3::new(0., 0., 0.),
+                                         Vec3::new(0., 1., 0.));
+        let scene = Scene::new(vec![Box::new(floor) as Box<Surface>], vec![light], 0.,
+                               Vec3::new(0., 0., 0.), camera);
+        ray_trace_events(&scene, 4, 4, 1, 1, TileOrder::Scanline, |_| {})
+    };
+    let white = render(Vec3::new(1., 1., 1.));
+    let orange = render(Vec3::new(1., 0.5, 0.25));
+    assert!(white.pixels().iter().any(|p| p.x > 0.), "floor is lit");
+    for (w, o) in white.pixels().iter().zip(orange.pixels()) {
+        assert_close(o.x, w.x, 4e-6, "red of the light");
+        assert_close(o.y, w.y * 0.5, 4e-6, "green of the light");
+        assert_close(o.z, w.z * 0.25, 4e-6, "blue of the light");
+    }
+}
+
+#[test]
+fn accumulated_runs_and_exposures_average_back_to_one_render() {
+    let floor = Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), material());
+    let light = PointLight::new(Vec3::new(0., 3., 0.), Vec3::new(1., 1., 1.), 1.);
+    let camera = Camera::from_lookat(Vec3::new(0., 1., -2.), Vec3::new(0., 0., 0.),
+                                     Vec3::new(0., 1., 0.));
+    let mut scene = Scene::new(vec![Box::new(floor) as Box<Surface>], vec![light], 0.,
+                               Vec3::new(0., 0., 0.), camera);
+    scene.set_seed(7);
+    let render = |scene: &Scene| ray_trace_events(scene, 4, 4, 1, 2, TileOrder::Scanline, |_| {});
+    let first = render(&scene);
+    scene.set_seed(8);
+    let second = render(&scene);
+    scene.set_seed(7);
+
+    // Two runs are the average of renders with the scene's seed and the next, after which the
+    // scene renders with its own seed again
+    let runs = ray_trace_runs(&mut scene, 4, 4, 1, 2, 2);
+    for ((r, a), b) in runs.pixels().iter().zip(first.pixels()).zip(second.pixels()) {
+        for axis in 0..3 {
+            assert_close(r[axis], (a[axis] + b[axis]) * 0.5, 4e-6, "average of the runs");
+        }
+    }
+    for (again, p) in render(&scene).pixels().iter().zip(first.pixels()) {
+        assert_close(again.x, p.x, 1e-6, "seed put back");
+    }
+
+    // An exposure 2 stops brighter is scaled back to agree with the others, and weights count
+    // a render that many times
+    let mut brighter = first.clone();
+    for pixel in brighter.pixels_mut() {
+        *pixel = *pixel * 4.;
+    }
+    let mut accumulator = Accumulator::new(4, 4);
+    accumulator.add_exposure(&brighter, 2.);
+    accumulator.add_weighted(&second, 3.);
+    assert_close(accumulator.weight(), 4., 1e-6, "total weight");
+    let average = accumulator.image();
+    for y in 0..4 {
+        for x in 0..4 {
+            let expected = (first.get_pixel(x, y) + second.get_pixel(x, y) * 3.) * 0.25;
+            assert_close(average.get_pixel(x, y).y, expected.y, 4e-6, "weighted average");
+            assert_close(average.get_alpha(x, y), 1., 1e-6, "alpha of opaque renders");
+        }
+    }
+}
+
+#[test]
+fn light_groups_add_up_to_the_whole_render() {
+    // A mirror ball on a floor lit by two groups of lights, an ungrouped light, ambient light
+    // and a glowing ball, all of whose light is reflected by the mirror
+    let mirror = Material::new(Vec3::new(1., 1., 1.), 0.5, 0.5, 20., 0.5, None, None, None);
+    let glow = material().with_emission(Vec3::new(0.4, 0.2, 0.));
+    let floor = Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), material());
+    let objects = vec![Box::new(floor) as Box<Surface>,
+                       Box::new(Sphere::new(Vec3::new(0., 1., 0.), 1., mirror)),
+                       Box::new(Sphere::new(Vec3::new(2., 0.5, 1.), 0.5, glow))];
+    let white = Vec3::new(1., 1., 1.);
+    let lights = vec![PointLight::new(Vec3::new(3., 4., -2.), white, 0.6).with_group("key"),
+                      PointLight::new(Vec3::new(-3., 2., -2.), white, 0.3).with_group("fill"),
+                      PointLight::new(Vec3::new(0., 5., 3.), white, 0.2),
+                      PointLight::new(Vec3::new(-1., 3., -3.), white, 0.2).with_group("key")];
+    let camera = Camera::from_lookat(Vec3::new(0., 2., -5.), Vec3::new(0., 1., 0.),
+                                     Vec3::new(0., 1., 0.));
+    let mut scene = Scene::new(objects, lights, 0.1, Vec3::new(1., 0.8, 0.6), camera);
+    assert_eq!(scene.light_groups(), vec!["key", "fill", "light2"]);
+
+    let render = |scene: &Scene| ray_trace_events(scene, 8, 8, 3, 1, TileOrder::Scanline, |_| {});
+    let whole = render(&scene);
+    let mut sum = vec![Vec3::new(0., 0., 0.); whole.pixels().len()];
+    let mut selections: Vec<LightSelection> = scene.light_groups().into_iter()
+        .map(LightSelection::Group).collect();
+    selections.push(LightSelection::Rest);
+    for selection in selections {
+        scene.set_light_selection(selection);
+        for (total, p) in sum.iter_mut().zip(render(&scene).pixels()) {
+            *total = *total + *p;
+        }
+    }
+    for (total, p) in sum.iter().zip(whole.pixels()) {
+        for axis in 0..3 {
+            assert_close(total[axis], p[axis], 1e-2 * p[axis].max(1. / 255.),
+                         "sum of the light groups");
+        }
+    }
+}
+
+// A transparent sphere between the floor and a light overhead, seen from the side
+fn glass_sphere_scene(glass: bool) -> Scene {
+    let floor = Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), material());
+    let mut objects = vec![Box::new(floor) as Box<Surface>];
+    if glass {
+        let glass = material().with_transparency(0.5, 1.5);
+        objects.push(Box::new(Sphere::new(Vec3::new(0., 2., 0.), 0.5, glass)));
+    }
+    let light = PointLight::new(Vec3::new(0., 5., 0.), Vec3::new(1., 1., 1.), 1.);
+    let camera = Camera::from_lookat(Vec3::new(0., 1., -2.), Vec3::new(0., 0., 0.),
+                                     Vec3::new(0., 1., 0.));
+    Scene::new(objects, vec![light], 0., Vec3::new(0., 0., 0.), camera)
+}
+
+#[test]
+fn shadow_and_refraction_depths() {
+    // The middle pixel sees the floor right under the sphere, whose shadow ray crosses the
+    // sphere's surface twice
+    let light_at_floor = |scene: &Scene| {
+        trace_pixel(scene, 1, 1, 2, 2, 0).hit.unwrap().lights[0].color.x
+    };
+    let unshadowed = light_at_floor(&glass_sphere_scene(false));
+    let mut scene = glass_sphere_scene(true);
+    assert!(unshadowed > 0. && light_at_floor(&scene) == 0., "glass casts a full shadow");
+    scene.set_shadow_depth(1);
+    assert!(light_at_floor(&scene) == 0., "shadow ray passes only one surface of two");
+    scene.set_shadow_depth(2);
+    assert_close(light_at_floor(&scene), unshadowed * 0.25, 4e-6, "light through glass");
+
+    // Looking straight through the sphere from the light, refracted rays go through both its
+    // surfaces and on to the floor
+    fn refractions(dump: &RayDump) -> u16 {
+        match dump.hit.as_ref().and_then(|hit| hit.refraction.as_ref()) {
+            Some(refraction) => 1 + refraction.refracted.as_ref().map_or(0, |r| refractions(r)),
+            None => 0,
+        }
+    }
+    let camera = Camera::from_lookat(Vec3::new(0., 4., 0.), Vec3::new(0., 0., 0.),
+                                     Vec3::new(0., 0., 1.));
+    scene.set_camera(camera);
+    assert_eq!(refractions(&trace_pixel(&scene, 1, 1, 2, 2, 1)), 1);
+    scene.set_refraction_depth(3);
+    assert_eq!(refractions(&trace_pixel(&scene, 1, 1, 2, 2, 1)), 2);
+    scene.set_refraction_depth(1);
+    assert_eq!(refractions(&trace_pixel(&scene, 1, 1, 2, 2, 0)), 1);
+}
+
+#[test]
+fn hidden_surfaces_are_skipped_by_their_rays() {
+    // A sphere over a half mirror floor, with the light above and the camera looking at the
+    // floor under it
+    let sphere_scene = |visibility: Visibility| {
+        let mirror = Material::new(Vec3::new(1., 1., 1.), 1., 0., 0., 0.5, None, None,
+                                   None);
+        let floor = Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), mirror);
+        let sphere = Sphere::new(Vec3::new(0., 2., 0.), 0.5,
+                                 material().with_visibility(visibility));
+        let objects: Vec<Box<Surface>> = vec![Box::new(floor), Box::new(sphere)];
+        let light = PointLight::new(Vec3::new(0., 5., 0.), Vec3::new(1., 1., 1.), 1.);
+        let camera = Camera::from_lookat(Vec3::new(0., 1., -2.), Vec3::new(0., 0., 0.),
+                                         Vec3::new(0., 1., 0.));
+        Scene::new(objects, vec![light], 0., Vec3::new(0., 0., 0.), camera)
+    };
+    let light_at_floor = |scene: &Scene| {
+        trace_pixel(scene, 1, 1, 2, 2, 0).hit.unwrap().lights[0].color.x
+    };
+    let all = Visibility::all();
+    assert!(light_at_floor(&sphere_scene(all)) == 0.);
+    let no_shadow = sphere_scene(Visibility { shadows: false, ..all });
+    assert!(light_at_floor(&no_shadow) > 0., "shadow from a surface that casts none");
+
+    // Looking straight down through the sphere, and up again in the mirror
+    let reflected_surface = |visibility: Visibility| {
+        let mut scene = sphere_scene(visibility);
+        assert!(light_at_floor(&scene) == 0., "hidden surface casts no shadow");
+        scene.set_camera(Camera::from_lookat(Vec3::new(0., 4., 0.), Vec3::new(0., 0., 0.),
+                                             Vec3::new(0., 0., 1.)));
+        let hit = trace_pixel(&scene, 1, 1, 2, 2, 1).hit.unwrap();
+        assert_eq!(hit.surface, "Plane");
+        hit.reflected.unwrap().hit.map(|hit| hit.surface)
+    };
+    assert_eq!(reflected_surface(Visibility { camera: false, ..all }), Some("Sphere"));
+    assert_eq!(reflected_surface(Visibility { camera: false, reflections: false, ..all }), None);
+}
+
+#[test]
+fn back_faces_are_shaded_flipped_or_culled() {
+    // A disk over a floor, with the light above both
+    let disk_scene = |normal: Vec3, backfaces: Backfaces, camera: Camera| {
+        let floor = Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), material());
+        let disk = Disk::new(Vec3::new(0., 2., 0.), normal, 0.5,
+                             material().with_backfaces(backfaces));
+        let objects: Vec<Box<Surface>> = vec![Box::new(floor), Box::new(disk)];
+        let light = PointLight::new(Vec3::new(0., 5., 0.), Vec3::new(1., 1., 1.), 1.);
+        Scene::new(objects, vec![light], 0., Vec3::new(0., 0., 0.), camera)
+    };
+    let above = || {
+        Camera::from_lookat(Vec3::new(0., 4., 0.), Vec3::new(0., 0., 0.), Vec3::new(0., 0., 1.))
+    };
+    let down = Vec3::new(0., -1., 0.);
+
+    // Seen from above, a disk facing down is lit from behind unless it's two-sided
+    let hit = trace_pixel(&disk_scene(down, Backfaces::Shaded, above()), 1, 1, 2, 2, 0)
+        .hit.unwrap();
+    assert_eq!((hit.surface, hit.lights[0].color.x), ("Disk", 0.));
+    let hit = trace_pixel(&disk_scene(down, Backfaces::Flipped, above()), 1, 1, 2, 2, 0)
+        .hit.unwrap();
+    assert_eq!(hit.surface, "Disk");
+    assert_close(hit.normal.y, 1., 1e-6, "two-sided normal");
+    assert!(hit.lights[0].color.x > 0., "two-sided disk is lit");
+
+    // Culled, the camera sees the floor through it
+    let hit = trace_pixel(&disk_scene(down, Backfaces::Culled, above()), 1, 1, 2, 2, 0)
+        .hit.unwrap();
+    assert_eq!(hit.surface, "Plane");
+    // But its shadow stays, also where the shadow rays leave through its back
+    let side = Camera::from_lookat(Vec3::new(0., 1., -2.), Vec3::new(0., 0., 0.),
+                                   Vec3::new(0., 1., 0.));
+    for &normal in &[down, -down] {
+        let hit = trace_pixel(&disk_scene(normal, Backfaces::Culled, side.clone()), 1, 1, 2, 2, 0)
+            .hit.unwrap();
+        assert_eq!((hit.surface, hit.lights[0].color.x), ("Plane", 0.));
+    }
+}
+
+#[test]
+fn ggx_highlight_is_reciprocal_and_conserves_energy() {
+    let mut rng = rng();
+    let hit = Intersection::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), 1., 0., 0.);
+    for _ in 0..CASES / 10 {
+        let roughness = rng.gen_range(0.05, 1.);
+        let metal = material().with_ggx(1., roughness);
+        let (a, b) = (random_dir(&mut rng), random_dir(&mut rng));
+        let (a, b) = (Vec3::new(a.x, a.y.abs(), a.z), Vec3::new(b.x, b.y.abs(), b.z));
+        // Swapping the light and the camera gives the same highlight, apart from the cosine
+        // towards the light that's folded into it
+        let ab = metal.specular_color(&Ray::new(hit.pos, a), &Ray::new(b, -b), &hit).x;
+        let ba = metal.specular_color(&Ray::new(hit.pos, b), &Ray::new(a, -a), &hit).x;
+        assert_close(ab / a.y, ba / b.y, 1e-3 * (ab / a.y).max(1. / 255.), "reciprocity");
+
+        // A white metal reflects at most all the light arriving from the camera's direction
+        let view = Ray::new(b, -b);
+        let mut reflected = 0.;
+        for _ in 0..1000 {
+            let weight = metal.sample_specular(&view, &hit, rng.gen(), rng.gen())
+                .map_or(0., |(_, weight)| weight.x);
+            reflected += weight / 1000.;
+        }
+        assert!(reflected <= 1.02, "{} of the light reflected at roughness {}", reflected,
+                roughness);
+
+        // Each sample's weight is the highlight along it over the density it was picked with
+        if let Some((dir, weight)) = metal.sample_specular(&view, &hit, rng.gen(), rng.gen()) {
+            let pdf = metal.specular_pdf(&view, &hit, &dir);
+            let color = metal.specular_color(&Ray::new(hit.pos, dir), &view, &hit).x;
+            assert_close(weight.x, color / (float::consts::PI * pdf), 1e-2 * weight.x.max(1.),
+                         "sample weight");
+        }
+    }
+}
+
+#[test]
+fn anisotropic_ggx_stretches_the_highlight_across_the_tangent() {
+    let mut rng = rng();
+    let hit = Intersection::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), 1., 0., 0.)
+        .with_tangent(Vec3::new(2., 0., 0.));
+    let view = Ray::new(Vec3::new(0., 1., 0.), Vec3::new(0., -1., 0.));
+    let brushed = material().with_ggx(1., 0.5).with_anisotropic_roughness(0.1, 0.6);
+    let tilted = |x: Float, z: Float| {
+        let light = Ray::new(hit.pos, Vec3::new(x, 1., z).normalize());
+        brushed.specular_color(&light, &view, &hit).x
+    };
+    assert!(tilted(0., 0.6) > 2. * tilted(0.6, 0.),
+            "the highlight isn't wider across the tangent: {} {}", tilted(0., 0.6),
+            tilted(0.6, 0.));
+
+    // The same roughness both ways is isotropic
+    let even = material().with_ggx(1., 0.5).with_anisotropic_roughness(0.4, 0.4);
+    let plain = material().with_ggx(1., 0.4);
+    for _ in 0..CASES / 10 {
+        let (a, b) = (random_dir(&mut rng), random_dir(&mut rng));
+        let (a, b) = (Vec3::new(a.x, a.y.abs(), a.z), Vec3::new(b.x, b.y.abs(), b.z));
+        let (light, camera) = (Ray::new(hit.pos, a), Ray::new(b, -b));
+        let expected = plain.specular_color(&light, &camera, &hit).x;
+        assert_close(even.specular_color(&light, &camera, &hit).x, expected,
+                     1e-3 * expected.max(1. / 255.), "even anisotropy");
+
+        if let Some((dir, weight)) = brushed.sample_specular(&camera, &hit, rng.gen(), rng.gen()) {
+            let pdf = brushed.specular_pdf(&camera, &hit, &dir);
+            let color = brushed.specular_color(&Ray::new(hit.pos, dir), &camera, &hit).x;
+            assert_close(weight.x, color / (float::consts::PI * pdf), 1e-2 * weight.x.max(1.),
+                         "anisotropic sample weight");
+        }
+    }
+
+    // Round surfaces run their tangent around their axis, here along the equator
+    let sphere = Sphere::new(Vec3::new(0., 0., 0.), 1., material());
+    let hit = sphere.intersect(&Ray::new(Vec3::new(0., 0., -5.), Vec3::new(0., 0., 1.))).unwrap();
+    let tangent = hit.tangent.unwrap();
+    assert_close(tangent.y, 0., 1e-5, "sphere tangent");
+    assert_close(dot(&tangent, &hit.normal), 0., 1e-5, "sphere tangent");
+}
+
+// Where the maps are white a mapped material shades like its constants, and where they're black
+// like a smooth dielectric
+#[test]
+fn roughness_and_metallic_maps_scale_the_constants() {
+    let mut rng = rng();
+    let checkers = CheckerboardTexture::new(0.5);
+    let mapped = material().with_ggx(1., 0.8).with_roughness_map(Box::new(checkers.clone()))
+        .with_metallic_map(Box::new(checkers.clone()));
+    let (white, black) = (material().with_ggx(1., 0.8), material().with_ggx(0., 0.));
+    for _ in 0..CASES / 10 {
+        let (u, v) = (rng.gen(), rng.gen());
+        let hit = Intersection::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), 1., u, v);
+        let expected = if checkers.color(u, v).x > 0. { &white } else { &black };
+        let (a, b) = (random_dir(&mut rng), random_dir(&mut rng));
+        let (a, b) = (Vec3::new(a.x, a.y.abs(), a.z), Vec3::new(b.x, b.y.abs(), b.z));
+        let (light, view) = (Ray::new(hit.pos, a), Ray::new(b, -b));
+        let (color, reference) = (mapped.color(&light, &view, &hit),
+                                  expected.color(&light, &view, &hit));
+        assert_close(color.x, reference.x, 1e-3 * reference.x.max(1. / 255.), "mapped color");
+        assert_eq!(mapped.albedo(&hit), expected.albedo(&hit));
+    }
+}
+
+#[test]
+fn raycast_returns_closest_object() {
+    let mut rng = rng();
+    for _ in 0..CASES / 10 {
+        // A row of spheres along a random direction, cast at from outside the row
+        let dir = random_dir(&mut rng);
+        let origin = random_vec(&mut rng, 100.);
+        let mut objects = Vec::new();
+        for i in 0..5 {
+            let center = origin + dir * (i as Float * 10. + 5.);
+            objects.push(Box::new(Sphere::new(center, 1., material())) as Box<Surface>);
+        }
+        // Shuffle so the closest sphere isn't always first
+        rng.shuffle(&mut objects);
+        let camera = Camera::new(origin, dir, Vec3::new(0., 1., 0.));
+        let scene = Scene::new(objects, Vec::new(), 0., Vec3::new(0., 0., 0.), camera);
+
+        let hit = scene.raycast(origin, dir).expect("ray along the row must hit a sphere");
+        assert_close(hit.dist, 4., 1e-3, "distance to closest sphere");
+        assert_eq!(scene.object(hit.object).name(), "Sphere");
+        assert!(scene.raycast(origin, -dir).is_none());
+    }
+}
+
+#[test]
+fn bvh_visits_few_nodes_for_a_ray_through_a_crowd() {
+    // A 20 x 20 grid of spheres in the z = 0 plane, cast at head on and past the side
+    let mut objects = Vec::new();
+    for i in 0..20 {
+        for j in 0..20 {
+            let center = Vec3::new(i as Float * 3., j as Float * 3., 0.);
+            objects.push(Box::new(Sphere::new(center, 1., material())) as Box<Surface>);
+        }
+    }
+    let camera = Camera::new(Vec3::new(0., 0., -10.), Vec3::new(0., 0., 1.),
+                             Vec3::new(0., 1., 0.));
+    let scene = Scene::new(objects, Vec::new(), 0., Vec3::new(0., 0., 0.), camera);
+
+    stats::take_node_visits();
+    stats::take_intersection_tests();
+    let hit = scene.raycast(Vec3::new(30., 30., -10.), Vec3::new(0., 0., 1.));
+    assert_close(hit.expect("ray must hit the sphere in front of it").dist, 9., 1e-3,
+                 "distance to the sphere");
+    let (visits, tests) = (stats::take_node_visits(), stats::take_intersection_tests());
+    assert!(visits > 0 && visits < 40, "{} node visits for one hit among 400", visits);
+    assert!(tests > 0 && tests < 10, "{} intersection tests for one hit among 400", tests);
+
+    assert!(scene.raycast(Vec3::new(-10., 0., -10.), Vec3::new(0., 0., 1.)).is_none());
+    assert_eq!(stats::take_node_visits(), 1);
+    assert_eq!(stats::take_intersection_tests(), 0);
+}
+
+#[test]
+fn instances_share_and_find_closest() {
+    let mut rng = rng();
+    let base: Arc<Box<Surface>> = Arc::new(Box::new(Sphere::new(Vec3::new(0., 0., 0.), 1.,
+                                                              material())));
+    // A forest of instances, the copies kept to check the scene's hierarchy against
+    let mut objects = Vec::new();
+    let mut copies = Vec::new();
+    for _ in 0..10000 {
+        let scale = Vec3::new(rng.gen_range(0.5, 2.), rng.gen_range(0.5, 2.), 1.);
+        let rotation = random_vec(&mut rng, float::consts::PI);
+        let offset = random_vec(&mut rng, 200.);
+        objects.push(Box::new(Instance::new(base.clone(), scale, rotation, offset))
+                     as Box<Surface>);
+        copies.push(Transformed::new(Box::new(Sphere::new(Vec3::new(0., 0., 0.), 1.,
+                                                          material())),
+                                     scale, rotation, offset));
+    }
+    assert_eq!(Arc::strong_count(&base), 10001);
+    let camera = Camera::new(Vec3::new(0., 0., 0.), Vec3::new(0., 0., 1.),
+                             Vec3::new(0., 1., 0.));
+    let scene = Scene::new(objects, Vec::new(), 0., Vec3::new(0., 0., 0.), camera);
+
+    for _ in 0..CASES / 100 {
+        let origin = random_vec(&mut rng, 250.);
+        // Aimed at an instance, so most rays hit something
+        let bounds = copies[rng.gen_range(0, copies.len())].bounds().unwrap();
+        let target = (bounds.min + bounds.max) / 2.;
+        let dir = (target - origin).normalize();
+        let closest = copies.iter().enumerate()
+            .filter_map(|(i, copy)| copy.intersect(&Ray::new(origin, dir)).map(|hit| (i, hit)))
+            .fold(None, |best: Option<(usize, Float)>, (i, hit)| match best {
+                Some((_, dist)) if dist <= hit.dist => best,
+                _ => Some((i, hit.dist)),
+            });
+        match (scene.raycast(origin, dir), closest) {
+            (Some(hit), Some((i, dist))) => {
+                assert_close(hit.dist, dist, 1e-3, "distance to closest instance");
+                assert_eq!(hit.object, i);
+            }
+            (None, None) => {}
+            (hit, closest) => panic!("scene hit {:?}, brute force {:?}",
+                                     hit.map(|h| h.object), closest),
+        }
+    }
+}
+
+#[test]
+fn mesh_hierarchy_finds_closest_triangle() {
+    let mut rng = rng();
+    for _ in 0..CASES / 100 {
+        // A cloud of random triangles, checked against testing every triangle on its own
+        let mut positions = Vec::new();
+        let mut triangles = Vec::new();
+        for i in 0..200 {
+            let center = random_vec(&mut rng, 10.);
+            for _ in 0..3 {
+                positions.push(center + random_vec(&mut rng, 1.));
+            }
+            triangles.push(Triangle { positions: [3 * i, 3 * i + 1, 3 * i + 2], normals: None,
+                                      uvs: None, material: 0 });
+        }
+        // The default hierarchy, ones with single triangle leaves and with big leaves, and the
+        // watertight test
+        let new_mesh = || {
+            TriangleMesh::new(positions.clone(), Vec::new(), Vec::new(), triangles.clone(),
+                              material())
+        };
+        let meshes = [new_mesh(), new_mesh().with_bvh(BvhSettings::new(1, 0.)),
+                      new_mesh().with_bvh(BvhSettings::new(16, 4.)),
+                      new_mesh().with_watertight(true)];
+        let singles: Vec<_> = triangles.iter().map(|t| {
+            let triangle = Triangle { positions: [0, 1, 2], ..*t };
+            TriangleMesh::new(t.positions.iter().map(|&i| positions[i]).collect(), Vec::new(),
+                              Vec::new(), vec![triangle], material())
+        }).collect();
+
+        for _ in 0..100 {
+            let ray = Ray::new(random_vec(&mut rng, 20.), random_dir(&mut rng));
+            let expected = singles.iter().filter_map(|t| t.intersect(&ray))
+                .map(|hit| hit.dist).fold(float::INFINITY, Float::min);
+            for mesh in meshes.iter() {
+                match mesh.intersect(&ray) {
+                    Some(hit) => {
+                        assert_close(hit.dist, expected, 1e-4, "distance to closest triangle");
+                        check_hit_on_ray(&ray, &hit.pos, hit.dist, 20.);
+                        assert_unit(&hit.normal, "triangle normal");
+                    }
+                    None => assert!(expected == float::INFINITY, "missed a triangle"),
+                }
+            }
+        }
+    }
+}
+
+// Vertices on a grid of a 4096th, in a box 65535 of them wide, come through the 16 bit
+// quantization exactly, so the memory mapped mesh hits where the loaded one does
+#[test]
+fn compact_meshes_hit_like_the_meshes_they_came_from() {
+    let mut rng = rng();
+    let snap = |x: Float| (x * 4096.).round().max(0.).min(65535.) / 4096.;
+    let mut positions = vec![Vec3::new(0., 0., 0.), Vec3::new(0., 0.5, 0.),
+                             Vec3::new(65535., 65535., 65535.) / 4096., Vec3::new(15., 15., 15.)];
+    let mut triangles = vec![Triangle { positions: [0, 1, 1], normals: None, uvs: None,
+                                        material: 0 },
+                             Triangle { positions: [2, 3, 3], normals: None, uvs: None,
+                                        material: 0 }];
+    for i in 0..200 {
+        let center = random_vec(&mut rng, 6.) + Vec3::new(8., 8., 8.);
+        for _ in 0..3 {
+            let v = center + random_vec(&mut rng, 1.);
+            positions.push(Vec3::new(snap(v.x), snap(v.y), snap(v.z)));
+        }
+        triangles.push(Triangle { positions: [3 * i + 4, 3 * i + 5, 3 * i + 6], normals: None,
+                                  uvs: None, material: 0 });
+    }
+    let mesh = TriangleMesh::new(positions, Vec::new(), Vec::new(), triangles, material());
+    let path = env::temp_dir().join("ray-tracer-test-mesh.rtmesh");
+    mesh.write_compact(&mut File::create(&path).unwrap()).unwrap();
+    let compact = CompactMesh::open(path.to_str().unwrap(), material()).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(compact.triangle_count() == 202, "{} triangles", compact.triangle_count());
+
+    for _ in 0..CASES {
+        let ray = Ray::new(random_vec(&mut rng, 20.) + Vec3::new(8., 8., 8.),
+                           random_dir(&mut rng));
+        match (mesh.intersect(&ray), compact.intersect(&ray)) {
+            (Some(expected), Some(hit)) => {
+                assert_close(hit.dist, expected.dist, 1e-6, "distance to the compact mesh");
+                assert_close(dot(&hit.normal, &expected.normal), 1., 1e-6, "compact normal");
+            }
+            (None, None) => (),
+            (expected, _) => panic!("compact mesh hit is {:?}", expected.map(|hit| hit.dist)),
+        }
+    }
+}
+
+// A file whose indices point past its vertices, triangles or nodes is refused when it's opened.
+// With one triangle of three vertices, its indices start after the 64 byte header and 20 bytes
+// of padded positions, and its one node after them
+#[test]
+fn compact_meshes_with_indices_out_of_range_are_refused() {
+    let positions = vec![Vec3::new(0., 0., 0.), Vec3::new(1., 0., 0.), Vec3::new(0., 1., 0.)];
+    let triangles = vec![Triangle { positions: [0, 1, 2], normals: None, uvs: None, material: 0 }];
+    let mesh = TriangleMesh::new(positions, Vec::new(), Vec::new(), triangles, material());
+    let mut data = Vec::new();
+    mesh.write_compact(&mut data).unwrap();
+    assert_eq!(data.len(), 64 + 20 + 12 + 32);
+    let path = env::temp_dir().join("ray-tracer-test-corrupt.rtmesh");
+    let open = |data: &[u8]| {
+        File::create(&path).unwrap().write_all(data).unwrap();
+        CompactMesh::open(path.to_str().unwrap(), material()).err().map(|e| e.kind())
+    };
+    assert_eq!(open(&data), None);
+    for &(at, value) in &[(84 + 8, 3), (96 + 28, 2), (96 + 24, 1)] {
+        let mut corrupt = data.clone();
+        corrupt[at] = value;
+        assert_eq!(open(&corrupt), Some(io::ErrorKind::InvalidData), "byte {} = {}", at, value);
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+// Two triangles folded 20 degrees along the y axis, not sharing vertices as in an STL file, are
+// smooth across the fold with a crease angle above that and stay flat with one below
+#[test]
+fn crease_angle_smooths_only_shallow_edges() {
+    let fold = (10. as Float).to_radians();
+    let positions = vec![Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.),
+                         Vec3::new(-fold.cos(), 0., -fold.sin()), Vec3::new(0., 0., 0.),
+                         Vec3::new(fold.cos(), 0., -fold.sin()), Vec3::new(0., 1., 0.)];
+    let triangles = vec![Triangle { positions: [0, 1, 2], normals: None, uvs: None, material: 0 },
+                         Triangle { positions: [3, 4, 5], normals: None, uvs: None, material: 0 }];
+    let mesh = |degrees: Float| {
+        TriangleMesh::new(positions.clone(), Vec::new(), Vec::new(), triangles.clone(),
+                          material()).with_crease_angle(degrees)
+    };
+    let left = Vec3::new(-fold.sin(), 0., fold.cos());
+    let up = Vec3::new(0., 0., 1.);
+    // Just left of the fold and near the far corner of the left triangle
+    let normal = |mesh: &TriangleMesh, x: Float| {
+        let ray = Ray::new(Vec3::new(x, 0.01, -5.), Vec3::new(0., 0., 1.));
+        mesh.intersect(&ray).expect("missed the fold").normal
+    };
+    let (smooth, creased) = (mesh(30.), mesh(10.));
+    assert_close(dot(&normal(&smooth, -1e-4), &up), 1., 1e-4, "smooth along the fold");
+    assert_close(dot(&normal(&smooth, -0.95), &left), 1., 1e-3, "flat at the far corner");
+    assert_close(dot(&normal(&creased, -1e-4), &left), 1., 1e-4, "creased along the fold");
+}
+
+// An eight sided prism along z with normals pointing straight out from its axis, shaded like a
+// cylinder. Where its smooth normals face a light, shadow rays leave from above the triangles
+// they'd otherwise hit the neighbours of
+#[test]
+fn smooth_meshes_are_lit_up_to_the_terminator() {
+    let mut rng = rng();
+    let sides = 8;
+    let (mut positions, mut normals, mut triangles) = (Vec::new(), Vec::new(), Vec::new());
+    for k in 0..sides {
+        let angle = k as Float * 2. * float::consts::PI / sides as Float;
+        let out = Vec3::new(angle.cos(), angle.sin(), 0.);
+        positions.push(out + Vec3::new(0., 0., -5.));
+        positions.push(out + Vec3::new(0., 0., 5.));
+        normals.push(out);
+    }
+    for k in 0..sides {
+        let next = (k + 1) % sides;
+        let (a, b, c, d) = (2 * k, 2 * k + 1, 2 * next, 2 * next + 1);
+        triangles.push(Triangle { positions: [a, c, b], normals: Some([k, next, k]), uvs: None,
+                                  material: 0 });
+        triangles.push(Triangle { positions: [b, c, d], normals: Some([k, next, next]),
+                                  uvs: None, material: 0 });
+    }
+    let mesh = TriangleMesh::new(positions, normals, Vec::new(), triangles, material());
+    let bias = Bias::default();
+    let (mut lit, mut blocked, mut blocked_flat) = (0, 0, 0);
+    for _ in 0..CASES {
+        let angle = rng.gen_range(0., 2. * float::consts::PI);
+        let origin = Vec3::new(angle.cos() * 5., angle.sin() * 5., rng.gen_range(-2., 2.));
+        let target = Vec3::new(rng.gen_range(-0.5, 0.5), rng.gen_range(-0.5, 0.5), 0.);
+        let hit = mesh.intersect(&Ray::new(origin, target - origin)).expect("missed the prism");
+        let light = random_dir(&mut rng);
+        if dot(&hit.normal, &light) < 0.05 {
+            continue;
+        }
+        lit += 1;
+        let shadowed = |origin: Vec3| mesh.intersect(&Ray::new(origin, light)).is_some();
+        if shadowed(bias.origin(&hit, &hit.normal)) {
+            blocked += 1;
+        }
+        if shadowed(hit.pos + hit.normal * bias.offset(&hit)) {
+            blocked_flat += 1;
+        }
+    }
+    assert!(blocked == 0, "{} of {} shadow rays facing the light blocked", blocked, lit);
+    // Without the lift
+    assert!(blocked_flat > 0, "no shadow rays left from near the terminator");
+}
+
+// The wireframe debug mode draws where mesh hits are close to an edge of their triangle, also
+// after the mesh is scaled
+#[test]
+fn mesh_hits_know_their_closest_edge() {
+    let mut rng = rng();
+    let triangle = Triangle { positions: [0, 1, 2], normals: None, uvs: None, material: 0 };
+    let positions = vec![Vec3::new(0., 0., 0.), Vec3::new(1., 0., 0.), Vec3::new(0., 1., 0.)];
+    let mesh = || {
+        Box::new(TriangleMesh::new(positions.clone(), Vec::new(), Vec::new(), vec![triangle],
+                                   material()))
+    };
+    let scaled = Transformed::new(mesh(), Vec3::new(2., 2., 2.), Vec3::new(0., 0., 0.),
+                                  Vec3::new(0., 0., 0.));
+    for _ in 0..CASES {
+        let (x, y): (Float, Float) = (rng.gen_range(0., 1.), rng.gen_range(0., 1.));
+        if x + y >= 1. {
+            continue;
+        }
+        let expected = x.min(y).min((1. - x - y) / (2. as Float).sqrt());
+        for &(surface, scale) in [(&*mesh() as &Surface, 1.), (&scaled as &Surface, 2.)].iter() {
+            let ray = Ray::new(Vec3::new(x, y, 1.) * scale, Vec3::new(0., 0., -1.));
+            let hit = surface.intersect(&ray).expect("missed the triangle");
+            let edge = hit.edge.expect("mesh hits have an edge");
+            assert_close(edge.norm(), expected * scale, 1e-5, "distance to the closest edge");
+            assert_close(edge.z, 0., 1e-5, "edge offset along the triangle");
+        }
+    }
+}
+
+// Rays aimed at the edges and the corner that the triangles of a flat fan share all hit one of
+// them
+#[test]
+fn watertight_meshes_have_no_cracks() {
+    let mut rng = rng();
+    let sides = 7;
+    let mut positions = vec![Vec3::new(0.1, 0.2, 0.3)];
+    let mut triangles = Vec::new();
+    for i in 0..sides {
+        let angle = 2. * float::consts::PI * i as Float / sides as Float;
+        positions.push(Vec3::new(0.1 + angle.cos(), 0.2 + angle.sin(), 0.3));
+        triangles.push(Triangle { positions: [0, 1 + i, 1 + (i + 1) % sides], normals: None,
+                                  uvs: None, material: 0 });
+    }
+    let mesh = TriangleMesh::new(positions.clone(), Vec::new(), Vec::new(), triangles,
+                                 material())
+        .with_watertight(true);
+    for _ in 0..CASES {
+        let origin = random_vec(&mut rng, 5.);
+        if (origin.z - 0.3).abs() < 0.1 {
+            continue;
+        }
+        // A point on the edge from the center out to a corner, or the center itself
+        let corner = positions[rng.gen_range(1, sides + 1)];
+        let target = positions[0] + (corner - positions[0]) * rng.gen_range(0., 0.9);
+        let ray = Ray::new(origin, target - origin);
+        let hit = mesh.intersect(&ray).expect("a ray slipped through an edge");
+        check_hit_on_ray(&ray, &hit.pos, hit.dist, 5.);
+        assert!((hit.pos - target).norm() < 1e-3, "hit {:?} away from the edge", hit.pos);
+    }
+}
+
+#[test]
+fn heightfield_hits_match_its_triangles() {
+    let mut rng = rng();
+    for _ in 0..CASES / 100 {
+        // Random terrain, checked against a mesh of the same triangles
+        let (columns, rows) = (rng.gen_range(2, 9), rng.gen_range(2, 9));
+        let heights: Vec<Float> = (0..columns * rows).map(|_| rng.gen::<Float>()).collect();
+        let pos = random_vec(&mut rng, 10.);
+        let size = Vec3::new(rng.gen_range(1., 10.), rng.gen_range(0., 5.), rng.gen_range(1., 10.));
+        let field = Heightfield::new(heights.clone(), columns, rows, pos, size, material());
+        let mut positions = Vec::new();
+        for j in 0..rows {
+            for i in 0..columns {
+                positions.push(pos + Vec3::new(size.x * i as Float / (columns - 1) as Float,
+                                               size.y * heights[j * columns + i],
+                                               size.z * j as Float / (rows - 1) as Float));
+            }
+        }
+        let mut triangles = Vec::new();
+        for j in 0..rows - 1 {
+            for i in 0..columns - 1 {
+                let corner = |di: usize, dj: usize| (j + dj) * columns + i + di;
+                for &t in &[[corner(0, 0), corner(1, 0), corner(1, 1)],
+                            [corner(0, 0), corner(1, 1), corner(0, 1)]] {
+                    triangles.push(Triangle { positions: t, normals: None, uvs: None,
+                                              material: 0 });
+                }
+            }
+        }
+        let mesh = TriangleMesh::new(positions, Vec::new(), Vec::new(), triangles, material());
+        assert_eq!(field.triangle_count(), mesh.triangle_count());
+
+        for _ in 0..100 {
+            let ray = Ray::new(random_vec(&mut rng, 20.), random_dir(&mut rng));
+            match (field.intersect(&ray), mesh.intersect(&ray)) {
+                (Some(hit), Some(expected)) => {
+                    assert_close(hit.dist, expected.dist, 1e-3, "distance to the terrain");
+                    check_hit_on_ray(&ray, &hit.pos, hit.dist, 20.);
+                    assert_unit(&hit.normal, "terrain normal");
+                }
+                (None, None) => {}
+                (hit, _) => panic!("heightfield {} a triangle",
+                                   if hit.is_some() { "hit outside" } else { "missed" }),
+            }
+        }
+    }
+}
+
+// A unit square in the z = 0 plane and a triangle without area, in every way they can be written
+#[test]
+fn ply_and_stl_meshes_load_without_degenerate_triangles() {
+    let vertices = [[0., 0., 0.], [1., 0., 0.], [1., 1., 0.], [0., 1., 0.], [2., 0., 0.]];
+    let faces: [&[u8]; 2] = [&[0, 1, 2, 3], &[0, 1, 4]];
+    let header = |format: &str| {
+        format!("ply\nformat {} 1.0\ncomment test\nelement vertex 5\nproperty float x\n\
+                 property float y\nproperty float z\nproperty uchar red\nelement face 2\n\
+                 property list uchar int vertex_indices\nend_header\n", format).into_bytes()
+    };
+    let u32_bytes = |value: u32, big_endian: bool| {
+        let mut bytes: Vec<u8> = (0..4).map(|i| (value >> (8 * i)) as u8).collect();
+        if big_endian {
+            bytes.reverse();
+        }
+        bytes
+    };
+
+    let mut ascii_ply = header("ascii");
+    for v in vertices.iter() {
+        ascii_ply.extend(format!("{} {} {} 255\n", v[0], v[1], v[2]).bytes());
+    }
+    for face in faces.iter() {
+        let indices: Vec<_> = face.iter().map(|i| i.to_string()).collect();
+        ascii_ply.extend(format!("{} {}\n", face.len(), indices.join(" ")).bytes());
+    }
+    let binary_ply = |big_endian: bool| {
+        let format = if big_endian { "binary_big_endian" } else { "binary_little_endian" };
+        let mut data = header(format);
+        for v in vertices.iter() {
+            for &x in v.iter() {
+                data.extend(u32_bytes((x as f32).to_bits(), big_endian));
+            }
+            data.push(255);
+        }
+        for face in faces.iter() {
+            data.push(face.len() as u8);
+            for &i in face.iter() {
+                data.extend(u32_bytes(i as u32, big_endian));
+            }
+        }
+        data
+    };
+
+    let stl_triangles = [[0, 1, 2], [0, 2, 3], [0, 1, 4]];
+    let mut ascii_stl = b"solid test\n".to_vec();
+    let mut binary_stl = vec![0; 80];
+    binary_stl.extend(u32_bytes(stl_triangles.len() as u32, false));
+    for t in stl_triangles.iter() {
+        ascii_stl.extend(b"facet normal 0 0 1\nouter loop\n".iter());
+        binary_stl.extend(vec![0; 12]);
+        for &i in t.iter() {
+            let v = vertices[i];
+            ascii_stl.extend(format!("vertex {} {} {}\n", v[0], v[1], v[2]).bytes());
+            for &x in v.iter() {
+                binary_stl.extend(u32_bytes((x as f32).to_bits(), false));
+            }
+        }
+        ascii_stl.extend(b"endloop\nendfacet\n".iter());
+        binary_stl.extend(vec![0; 2]);
+    }
+    ascii_stl.extend(b"endsolid test\n".iter());
+
+    let files = [("ascii.ply", ascii_ply), ("little.ply", binary_ply(false)),
+                 ("big.ply", binary_ply(true)), ("ascii.stl", ascii_stl),
+                 ("binary.stl", binary_stl)];
+    for &(name, ref data) in files.iter() {
+        let path = env::temp_dir().join(format!("ray-tracer-test-{}", name));
+        File::create(&path).unwrap().write_all(data).unwrap();
+        let mesh = TriangleMesh::load(path.to_str().unwrap(), material());
+        fs::remove_file(&path).unwrap();
+        assert!(mesh.triangle_count() == 2, "{} has {} triangles", name, mesh.triangle_count());
+        for &(x, y) in [(0.7, 0.3), (0.3, 0.7)].iter() {
+            let ray = Ray::new(Vec3::new(x, y, 1.), Vec3::new(0., 0., -1.));
+            let hit = mesh.intersect(&ray).unwrap_or_else(|| panic!("{} missed", name));
+            assert_close(hit.dist, 1., 1e-5, name);
+        }
+    }
+}
+
+// A glTF triangle with a red material, scaled by a child node of a node moving it away from the
+// camera node, all in an embedded buffer of three positions and three 16 bit indices
+#[test]
+fn gltf_meshes_and_cameras_load_in_the_tracers_space() {
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+
+    let mut buffer = Vec::new();
+    for &x in [0., 0., 0., 1., 0., 0., 0., 1., 0.].iter() {
+        let bits = (x as f32).to_bits();
+        buffer.extend((0..4).map(|i| (bits >> (8 * i)) as u8));
+    }
+    buffer.extend([0, 0, 1, 0, 2, 0, 0, 0].iter());
+    let json = format!(r#"{{
+        "asset": {{"version": "2.0"}},
+        "scene": 0,
+        "scenes": [{{"nodes": [0, 2]}}],
+        "nodes": [{{"translation": [0, 0, -5], "children": [1]}},
+                  {{"mesh": 0, "scale": [2, 2, 2]}},
+                  {{"camera": 0, "translation": [0, 0, 2]}}],
+        "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}, "indices": 1,
+                                       "material": 0}}]}}],
+        "materials": [{{"pbrMetallicRoughness": {{"baseColorFactor": [1, 0, 0, 1],
+                                                   "metallicFactor": 0,
+                                                   "roughnessFactor": 0.5}}}}],
+        "cameras": [{{"type": "perspective", "perspective": {{"yfov": 0.8, "znear": 0.1}}}}],
+        "accessors": [{{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"}},
+                      {{"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}}],
+        "bufferViews": [{{"buffer": 0, "byteLength": 36}},
+                        {{"buffer": 0, "byteOffset": 36, "byteLength": 6}}],
+        "buffers": [{{"byteLength": 44,
+                      "uri": "data:application/octet-stream;base64,{}"}}]
+    }}"#, buffer.to_base64(STANDARD));
+    let path = env::temp_dir().join("ray-tracer-test-scene.gltf");
+    File::create(&path).unwrap().write_all(json.as_bytes()).unwrap();
+    let mesh = TriangleMesh::load(path.to_str().unwrap(), material());
+    let camera = gltf::load_camera(path.to_str().unwrap(), 0);
+    fs::remove_file(&path).unwrap();
+
+    // glTF's -5 along z is 5 in front of the tracer's origin, and the triangle faces back
+    assert_eq!(mesh.triangle_count(), 1);
+    let hit = mesh.intersect(&Ray::new(Vec3::new(1.5, 0.2, 0.), Vec3::new(0., 0., 1.))).unwrap();
+    assert_close(hit.dist, 5., 1e-5, "distance to the glTF triangle");
+    assert!(hit.normal.z < -0.999, "the glTF triangle faces {:?}", hit.normal);
+    assert!(mesh.intersect(&Ray::new(Vec3::new(1.5, 0.6, 0.), Vec3::new(0., 0., 1.))).is_none());
+    let red = mesh.material_at(&hit);
+    assert_eq!(red.raw_color(), Vec3::new(1., 0., 0.));
+    assert_eq!(red.shading(), Shading::Ggx { metallic: 0., roughness: 0.5 });
+
+    assert_eq!(*camera.pos(), Vec3::new(0., 0., -2.));
+    let (x, y) = camera.project(&Vec3::new(0., 0., 5.), 1.).unwrap();
+    assert_close(x, 0.5, 1e-5, "the glTF camera's view direction");
+    assert_close(y, 0.5, 1e-5, "the glTF camera's view direction");
+    let (x, y) = camera.project(&Vec3::new(1., 1., 5.), 1.).unwrap();
+    assert!(x > 0.5 && y < 0.5, "+x and +y project to {:?}, not up and to the right", (x, y));
+}
+
+// A roof of two faces meeting at a ridge, the second with a red material from an MTL file
+#[test]
+fn obj_smoothing_groups_and_face_materials() {
+    let dir = env::temp_dir();
+    let mtl = dir.join("ray-tracer-test-faces.mtl");
+    File::create(&mtl).unwrap()
+        .write_all(b"newmtl red\nKd 1 0 0\nd 0.25\nNi 1.3\nPm 1\nPr 0.4\n").unwrap();
+    for &smoothing in ["1", "off"].iter() {
+        let obj = format!("mtllib ray-tracer-test-faces.mtl\nv -1 0 0\nv -1 1 0\nv 0 0 1\n\
+                           v 0 1 1\nv 1 0 0\nv 1 1 0\ns {}\nf 1 3 4 2\nusemtl red\n\
+                           f 3 5 6 4\n", smoothing);
+        let path = dir.join("ray-tracer-test-faces.obj");
+        File::create(&path).unwrap().write_all(obj.as_bytes()).unwrap();
+        let mesh = TriangleMesh::load(path.to_str().unwrap(), material());
+        fs::remove_file(&path).unwrap();
+
+        let down = |x: Float| {
+            mesh.intersect(&Ray::new(Vec3::new(x, 0.5, 5.), Vec3::new(0., 0., -1.))).unwrap()
+        };
+        let (left, right) = (down(-0.5), down(0.5));
+        let flat = Vec3::new(-1., 0., 1.).normalize();
+        if smoothing == "off" {
+            assert_close(dot(&left.normal, &flat), 1., 1e-5, "flat normal");
+        } else {
+            // Turned from the face's normal towards the other face's, and mirrored there
+            assert!(left.normal.x < 0. && left.normal.z > flat.z + 0.1, "{:?}", left.normal);
+            assert_close(right.normal.x, -left.normal.x, 1e-5, "mirrored");
+        }
+        assert_eq!(mesh.material_at(&left).raw_color(), Vec3::new(1., 1., 1.));
+        let red = mesh.material_at(&right);
+        assert_eq!(red.raw_color(), Vec3::new(1., 0., 0.));
+        assert_eq!((red.transparency(), red.ior()), (0.75, 1.3));
+        assert_eq!(red.shading(), Shading::Ggx { metallic: 1., roughness: 0.4 });
+    }
+    fs::remove_file(&mtl).unwrap();
+}
+
+// Subdividing the unit cube rounds it off towards a sphere inside it, with smooth normals
+#[test]
+fn subdivided_cube_is_smooth_and_inside_the_cage() {
+    let positions: Vec<Vec3> = (0..8u32).map(|i| {
+        Vec3::new((i & 1) as Float - 0.5, (i >> 1 & 1) as Float - 0.5, (i >> 2 & 1) as Float - 0.5)
+    }).collect();
+    let quads = [[0, 2, 3, 1], [4, 5, 7, 6], [0, 1, 5, 4], [2, 6, 7, 3], [0, 4, 6, 2],
+                 [1, 3, 7, 5]];
+    let faces = quads.iter().map(|q| Face { corners: q.to_vec(), uvs: None }).collect();
+    let mut polygons = PolygonMesh::new(positions, faces);
+    for _ in 0..3 {
+        polygons = polygons.subdivided();
+    }
+    assert_eq!(polygons.faces.len(), 6 * 4 * 4 * 4);
+    let dists: Vec<Float> = polygons.positions.iter().map(|p| p.norm()).collect();
+    let (nearest, farthest) = dists.iter().fold((float::INFINITY, 0. as Float), |(a, b), &d| {
+        (a.min(d), b.max(d))
+    });
+    assert!(farthest < 0.5 * Float::sqrt(3.) && farthest < nearest * 1.2,
+            "Distances from the center from {} to {}", nearest, farthest);
+
+    let mesh = polygons.to_triangle_mesh(material());
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let dir = random_dir(&mut rng);
+        let hit = mesh.intersect(&Ray::new(dir * 2., -dir)).expect("Missed the center");
+        assert!(hit.dist > 1.5 && hit.dist < 1.8, "Hit at {}", hit.dist);
+        assert_unit(&hit.normal, "normal");
+        assert!(dot(&hit.normal, &dir) > 0.9, "Normal {:?} far from {:?}", hit.normal, dir);
+    }
+}
+
+#[test]
+fn visibility_through_sphere() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let center = random_vec(&mut rng, 100.);
+        let radius = rng.gen_range(0.1, 10.);
+        let camera = Camera::new(center, Vec3::new(0., 0., 1.), Vec3::new(0., 1., 0.));
+        let objects = vec![Box::new(Sphere::new(center, radius, material())) as Box<Surface>];
+        let scene = Scene::new(objects, Vec::new(), 0., Vec3::new(0., 0., 0.), camera);
+
+        // Points on opposite sides of the sphere can't see each other, nearby points outside it
+        // on the same side can, including points on the surface itself
+        let dir = random_dir(&mut rng);
+        let near = center + dir * radius * rng.gen_range(1.1, 3.);
+        let far = center - dir * radius * rng.gen_range(1.1, 3.);
+        let on_surface = center + dir * radius;
+        assert!(!scene.visible(near, far));
+        assert!(!scene.visible(far, near));
+        assert!(scene.visible(near, on_surface));
+        assert!(scene.visible(on_surface, near));
+    }
+
+    // What blocks the view is what casts shadows, not what the camera sees
+    let visible = |visibility: Visibility| {
+        let sphere = Sphere::new(Vec3::new(0., 0., 0.), 1., material().with_visibility(visibility));
+        let camera = Camera::new(Vec3::new(0., 0., -5.), Vec3::new(0., 0., 1.),
+                                 Vec3::new(0., 1., 0.));
+        let scene = Scene::new(vec![Box::new(sphere) as Box<Surface>], Vec::new(), 0.,
+                               Vec3::new(0., 0., 0.), camera);
+        scene.visible(Vec3::new(0., 0., -2.), Vec3::new(0., 0., 2.))
+    };
+    let all = Visibility::all();
+    assert!(!visible(Visibility { camera: false, reflections: false, ..all }));
+    assert!(visible(Visibility { shadows: false, ..all }));
+}
+
+#[test]
+fn projection_inverts_camera_rays() {
+    let mut rng = rng();
+    let (width, height) = (640, 480);
+    let projections = [Projection::Perspective { fov: 53.1301 },
+                       Projection::Perspective { fov: 90. },
+                       Projection::Orthographic { height: 20. },
+                       Projection::Fisheye { fov: 200. },
+                       Projection::Equirectangular];
+    for i in 0..CASES / 10 {
+        let projection = projections[i as usize % projections.len()];
+        let pos = random_vec(&mut rng, 100.);
+        let camera = Camera::new(pos, random_dir(&mut rng), random_dir(&mut rng))
+            .with_projection(projection);
+        let scene = Scene::new(Vec::new(), Vec::new(), 0., Vec3::new(0., 0., 0.), camera);
+
+        // Away from the poles of the panorama, where every x is the same direction
+        let (x, y) = (rng.gen_range(0, width), rng.gen_range(1, height));
+        let ray = trace_pixel(&scene, x, y, width, height, 0);
+        let point = ray.origin + ray.dir * rng.gen_range(0.1, 100.);
+        let (px, py) = scene.camera().project_pixel(&point, width, height)
+            .expect("point in front of the camera must project");
+        let what = format!("{:?} projected", projection);
+        assert_close(px, x as Float, 1e-2, &what);
+        assert_close(py, y as Float, 1e-2, &what);
+
+        if let Projection::Perspective { .. } = projection {
+            let behind = ray.origin - ray.dir * rng.gen_range(0.1, 100.);
+            assert!(scene.camera().project_pixel(&behind, width, height).is_none());
+        }
+    }
+}
+
+// Levels of detail are picked by how much of the image height a bounding sphere spans, which for
+// a small one ahead of the camera is how far apart its top and bottom project
+#[test]
+fn screen_size_matches_the_projected_size() {
+    let mut rng = rng();
+    let projections = [Projection::Perspective { fov: 53.1301 },
+                       Projection::Perspective { fov: 90. },
+                       Projection::Orthographic { height: 20. },
+                       Projection::Fisheye { fov: 200. },
+                       Projection::Equirectangular];
+    for i in 0..CASES / 10 {
+        let projection = projections[i as usize % projections.len()];
+        let camera = Camera::new(Vec3::new(0., 0., 0.), Vec3::new(0., 0., 1.),
+                                 Vec3::new(0., 1., 0.)).with_projection(projection);
+        let dist = rng.gen_range(5., 100.);
+        let (center, radius) = (Vec3::new(0., 0., dist), dist * 0.01);
+        let project = |point: Vec3| camera.project(&point, 4. / 3.).unwrap().1;
+        let expected = 2. * (project(center + Vec3::new(0., radius, 0.)) - project(center)).abs();
+        let size = camera.screen_size(&center, radius);
+        assert_close(size, expected, 1e-2 * expected, &format!("{:?} screen size", projection));
+        assert!(camera.screen_size(&center, dist * 2.) == float::INFINITY);
+    }
+}
+
+#[test]
+fn sphere_surface_points_match_uvs() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let center = random_vec(&mut rng, 100.);
+        let radius = rng.gen_range(0.1, 50.);
+        let sphere = Sphere::new(center, radius, material());
+        let (u, v) = (rng.gen_range(0.01, 0.99), rng.gen_range(0.26, 0.74));
+        let point = sphere.surface_point(u, v).expect("uv inside the sphere's layout");
+        assert_close((point.pos - center).norm(), radius, 1e-4 * (radius + center.norm()),
+                     "distance to center");
+
+        // Shooting a ray at the point must give back the same uv
+        let origin = point.pos + point.normal * radius;
+        let hit = sphere.intersect(&Ray::new(origin, -point.normal)).expect("ray at the point");
+        assert_close(hit.u, u, 1e-3, "u");
+        assert_close(hit.v, v, 1e-3, "v");
+    }
+}
+
+// Meshes bake through the texture coordinates of their triangles: each uv leads back to where
+// rays hit with it, on the mesh loaded or compact, and the mesh fills its tile of the atlas
+#[test]
+fn meshes_bake_through_their_uv_layout() {
+    let mut rng = rng();
+    let positions = vec![Vec3::new(0., 0., 0.), Vec3::new(2., 0., 0.), Vec3::new(2., 1., 0.5),
+                         Vec3::new(0., 1., 0.)];
+    let uvs = vec![(0., 0.), (1., 0.), (1., 1.), (0., 1.)];
+    let triangles = vec![
+        Triangle { positions: [0, 1, 2], normals: None, uvs: Some([0, 1, 2]), material: 0 },
+        Triangle { positions: [0, 2, 3], normals: None, uvs: Some([0, 2, 3]), material: 0 },
+    ];
+    let mesh = TriangleMesh::new(positions.clone(), Vec::new(), uvs, triangles.clone(),
+                                 material());
+    let path = env::temp_dir().join("ray-tracer-test-baked.rtmesh");
+    mesh.write_compact(&mut File::create(&path).unwrap()).unwrap();
+    let compact = CompactMesh::open(path.to_str().unwrap(), material()).unwrap();
+    fs::remove_file(&path).unwrap();
+    let mut hits = 0;
+    for _ in 0..CASES {
+        let target = Vec3::new(rng.gen_range(0., 2.), rng.gen_range(0., 1.), 0.);
+        let origin = target + Vec3::new(0., 0., 5.) + random_vec(&mut rng, 2.);
+        let ray = Ray::new(origin, target - origin);
+        for surface in &[&mesh as &Surface, &compact as &Surface] {
+            if let Some(hit) = surface.intersect(&ray) {
+                hits += 1;
+                let point = surface.surface_point(hit.u, hit.v).expect("uv outside the layout");
+                assert!((point.pos - hit.pos).norm() < 1e-3, "{} uv leads elsewhere",
+                        surface.name());
+                assert!(dot(&point.normal, &hit.normal) > 0.9999, "{} normal", surface.name());
+            }
+        }
+    }
+    assert!(hits > CASES, "too few rays hit: {}", hits);
+
+    let floor = Plane::new(Vec3::new(0., -1., 0.), Vec3::new(0., 1., 0.), material());
+    let light = PointLight::new(Vec3::new(1., 0.5, 5.), Vec3::new(1., 1., 1.), 1.);
+    let camera = Camera::from_lookat(Vec3::new(0., 0., 5.), Vec3::new(0., 0., 0.),
+                                     Vec3::new(0., 1., 0.));
+    let scene = Scene::new(vec![Box::new(floor) as Box<Surface>, Box::new(mesh)], vec![light],
+                           0.1, Vec3::new(1., 1., 1.), camera.clone());
+    let (atlas, tiles) = bake_lightmaps(&scene, 8).unwrap();
+    assert!(tiles.len() == 1 && tiles[0].object == 1, "the mesh alone is baked");
+    assert!(atlas.pixels().iter().all(|p| p.x > 0.1), "texels the mesh doesn't cover");
+
+    // Without texture coordinates there's nothing to bake into
+    let bare = triangles.iter().map(|t| Triangle { uvs: None, ..*t }).collect();
+    let bare = TriangleMesh::new(positions, Vec::new(), Vec::new(), bare, material());
+    let scene = Scene::new(vec![Box::new(bare) as Box<Surface>], Vec::new(), 0.1,
+                           Vec3::new(1., 1., 1.), camera);
+    assert!(bake_lightmaps(&scene, 8).is_err(), "a mesh without uvs baked");
+}
+
+#[test]
+fn area_samples_lie_on_surfaces() {
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let center = random_vec(&mut rng, 100.);
+        let radius = rng.gen_range(0.1, 50.);
+        let dir = random_dir(&mut rng);
+        let scale = radius + center.norm();
+        let (u1, u2) = (rng.gen::<Float>(), rng.gen::<Float>());
+
+        let sphere = Sphere::new(center, radius, material());
+        let (pos, normal, area) = sphere.sample_area(u1, u2).unwrap();
+        assert_unit(&normal, "sphere normal");
+        assert_close((pos - center).norm(), radius, 1e-4 * scale, "distance to center");
+        assert_close(area, 4. * float::consts::PI * radius * radius, 1e-3 * area, "sphere area");
+
+        let disk = Disk::new(center, dir, radius, material());
+        let (pos, normal, area) = disk.sample_area(u1, u2).unwrap();
+        assert_unit(&normal, "disk normal");
+        assert_close(dot(&(pos - center), &dir), 0., 1e-4 * scale, "in the disk's plane");
+        assert!((pos - center).norm() <= radius * (1. + 1e-4), "inside the disk");
+        assert_close(area, float::consts::PI * radius * radius, 1e-3 * area, "disk area");
+
+        // The ray back along the sampled normal must hit the cylinder where it was sampled
+        let top = center + dir * rng.gen_range(0.1, 50.);
+        let cylinder = Cylinder::new(center, top, radius, material());
+        let (pos, normal, _) = cylinder.sample_area(u1, u2).unwrap();
+        assert_unit(&normal, "cylinder normal");
+        let ray = Ray::new(pos + normal * scale, -normal);
+        let hit = cylinder.intersect(&ray).expect("ray at the sampled point");
+        assert_close((hit.pos - pos).norm(), 0., 1e-3 * scale, "cylinder sample");
+    }
+}
+
+#[test]
+fn plane_patterns_alternate_materials() {
+    let white = material();
+    let black = Material::new(Vec3::new(0., 0., 0.), 1., 0., 0., 0.5, None, None, None);
+    let floor = |pattern| {
+        Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), white.clone())
+            .with_pattern(pattern, black.clone())
+    };
+    let reflectivity_at = |plane: &Plane, x: Float, z: Float| {
+        let ray = Ray::new(Vec3::new(x, 1., z), Vec3::new(0., -1., 0.));
+        let hit = plane.intersect(&ray).expect("ray straight down must hit the floor");
+        plane.material_at(&hit).reflectivity()
+    };
+
+    let checkers = floor(PlanePattern::Checkerboard { size: 2. });
+    assert_eq!(reflectivity_at(&checkers, 1., 1.), 0.);
+    assert_eq!(reflectivity_at(&checkers, 3., 1.), 0.5);
+    assert_eq!(reflectivity_at(&checkers, 1., -1.), 0.5);
+    assert_eq!(reflectivity_at(&checkers, -1., -1.), 0.);
+
+    let grid = floor(PlanePattern::Grid { size: 2., width: 0.2 });
+    assert_eq!(reflectivity_at(&grid, 1., 1.), 0.);
+    assert_eq!(reflectivity_at(&grid, 2.05, 1.), 0.5);
+    assert_eq!(reflectivity_at(&grid, 1., -3.95), 0.5);
+    assert_eq!(reflectivity_at(&grid, 2.2, 1.), 0.);
+}
+
+#[test]
+fn fresnel_reflectivity_rises_at_grazing_angles() {
+    let hit = Intersection::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), 1., 0., 0.);
+    let ray_at = |degrees: Float| {
+        let angle = degrees.to_radians();
+        Ray::new(Vec3::new(-angle.sin(), angle.cos(), 0.), Vec3::new(angle.sin(), -angle.cos(), 0.))
+    };
+    let mirror = Material::new(Vec3::new(1., 1., 1.), 0., 0., 0., 0.04, None, None, None);
+    let glass = mirror.clone().with_fresnel();
+    let mut last = 0.;
+    for &degrees in &[0., 30., 60., 80., 89.9] {
+        assert_close(mirror.reflectivity_at(&ray_at(degrees), &hit), 0.04, 1e-6,
+                     "flat reflectivity");
+        let reflectivity = glass.reflectivity_at(&ray_at(degrees), &hit);
+        assert!(reflectivity >= last, "{} at {} degrees, {} before", reflectivity, degrees, last);
+        last = reflectivity;
+    }
+    assert_close(glass.reflectivity_at(&ray_at(0.), &hit), 0.04, 1e-6, "head on");
+    assert!(last > 0.95, "{} at a grazing angle", last);
+}
+
+#[test]
+fn emissive_surfaces_light_the_scene() {
+    // A glowing disk facing down over the floor, with no lights at all
+    let floor = Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), material());
+    let glow = material().with_emission(Vec3::new(1., 1., 1.));
+    let panel = Disk::new(Vec3::new(0., 2., 1.), Vec3::new(0., -1., 0.), 0.5, glow);
+    let camera = Camera::from_lookat(Vec3::new(0., 1., -2.), Vec3::new(0., 0., 1.),
+                                     Vec3::new(0., 1., 0.));
+    let objects = vec![Box::new(floor) as Box<Surface>, Box::new(panel)];
+    let scene = Scene::new(objects, vec![], 0., Vec3::new(0., 0., 0.), camera);
+    let floor_hit = trace_pixel(&scene, 1, 2, 2, 4, 0).hit.unwrap();
+    assert!(floor_hit.emitters.x > 0., "panel lights the floor");
+    assert!(floor_hit.emission.x == 0., "floor doesn't glow");
+
+    // From below, the panel shows its own emission
+    let camera = Camera::from_lookat(Vec3::new(0., 0.5, 1.), Vec3::new(0., 2., 1.),
+                                     Vec3::new(0., 0., 1.));
+    let mut scene = scene;
+    scene.set_camera(camera);
+    let panel_hit = trace_pixel(&scene, 1, 1, 2, 2, 0).hit.unwrap();
+    assert_close(panel_hit.emission.x, 1., 4e-6, "panel emission");
+}
+
+#[test]
+fn traced_pixels_name_the_object_and_material_hit() {
+    let floor = Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), material());
+    let gold = material().with_name("gold").with_ggx(1., 0.25);
+    let ball = Sphere::new(Vec3::new(0., 1., 0.), 0.5, gold);
+    let camera = Camera::from_lookat(Vec3::new(0., 1., -3.), Vec3::new(0., 1., 0.),
+                                     Vec3::new(0., 1., 0.));
+    let objects = vec![Box::new(floor) as Box<Surface>, Box::new(ball)];
+    let scene = Scene::new(objects, vec![], 0., Vec3::new(0., 0., 0.), camera);
+    let dump = trace_pixel(&scene, 1, 1, 2, 2, 0);
+    let hit = dump.hit.as_ref().unwrap();
+    assert_eq!(hit.object, 1);
+    assert_eq!(hit.material.as_ref().map(|name| &name[..]), Some("gold"));
+    assert_eq!(hit.shading, Shading::Ggx { metallic: 1., roughness: 0.25 });
+    assert!(dump.to_string().contains("object 1, material gold (ggx, metallic 1.00"));
+
+    let floor_hit = trace_pixel(&scene, 1, 3, 2, 4, 0).hit.unwrap();
+    assert_eq!((floor_hit.object, floor_hit.material), (0, None));
+}
+
+#[test]
+fn spectral_weights_average_to_white_and_dispersion_bends_blue_most() {
+    let n = 3500;
+    let mut sum = Vec3::new(0., 0., 0.);
+    for i in 0..n {
+        let u = (i as Float + 0.5) / n as Float;
+        sum = sum + spectral::weight(spectral::wavelength(u));
+    }
+    let average = sum / n as Float;
+    assert_close(average.x, 1., 1e-2, "red");
+    assert_close(average.y, 1., 1e-2, "green");
+    assert_close(average.z, 1., 1e-2, "blue");
+    let blue = spectral::weight(450.);
+    let red = spectral::weight(650.);
+    assert!(blue.z > blue.x && blue.z > blue.y, "450 nm looks blue: {:?}", blue);
+    assert!(red.x > red.y && red.x > red.z, "650 nm looks red: {:?}", red);
+
+    let flint = material().with_transparency(1., 1.62).with_dispersion(0.01);
+    assert_eq!(flint.ior_at(None), 1.62);
+    assert_close(flint.ior_at(Some(spectral::REFERENCE_WAVELENGTH)), 1.62, 1e-6, "reference");
+    assert!(flint.ior_at(Some(450.)) > 1.62 && flint.ior_at(Some(650.)) < 1.62);
+    let glass = material().with_transparency(1., 1.5);
+    assert_eq!(glass.ior_at(Some(450.)), 1.5);
+}
+
+#[test]
+fn portal_samples_have_the_density_they_claim() {
+    // A 2 by 1 window, 1.5 in front of the point and off to the side
+    let portal = Portal::new(Vec3::new(0.5, 0.2, 1.5), Vec3::new(2., 0., 0.),
+                             Vec3::new(0., 1., 0.));
+    let from = Vec3::new(0., 0., 0.);
+    let mut rng = rng();
+    for _ in 0..100 {
+        let (u1, u2) = (rng.gen::<Float>(), rng.gen::<Float>());
+        let dir = portal.sample(&from, u1, u2).unwrap();
+        assert_unit(&dir, "portal sample");
+        let point = Vec3::new(-0.5 + 2. * u1, -0.3 + u2, 1.5);
+        let dist = point.norm();
+        assert_close(portal.pdf(&from, &dir), dist * dist / (2. * dir.z), 1e-3, "density");
+        // Looking back through it from behind finds it too
+        assert_close(portal.pdf(&(point * 2.), &-dir), dist * dist / (2. * dir.z), 1e-3,
+                     "density from behind");
+    }
+    assert_eq!(portal.pdf(&from, &Vec3::new(0., 0., -1.)), 0.);
+    assert_eq!(portal.pdf(&from, &Vec3::new(-1., 0., 1.).normalize()), 0.);
+
+    // Over the whole sphere of directions, the density adds up to 1
+    let n = 1000000;
+    let sum = (0..n).map(|_| portal.pdf(&from, &random_dir(&mut rng))).sum::<Float>();
+    assert_close(sum / n as Float * 4. * float::consts::PI, 1., 0.02, "total probability");
+}
+
+#[test]
+fn mipmap_levels_average_and_filter() {
+    // Black and white columns, which every level after the first averages to gray
+    let columns = RgbImage::from_fn(8, 4, |x, _| {
+        Rgb { data: [if x % 2 == 0 { 0 } else { 255 }; 3] }
+    });
+    let mipmap = Mipmap::new(columns);
+    assert_eq!(mipmap.levels(), 4);
+    // Texel centers are exact at full resolution, with their average halfway between them
+    assert_close(mipmap.sample(0.5 / 8., 0.5).x, 0., 4e-6, "black texel");
+    assert_close(mipmap.sample(1.5 / 8., 0.5).x, 1., 4e-6, "white texel");
+    assert_close(mipmap.sample(1. / 8., 0.5).x, 0.5, 4e-6, "between texels");
+    assert_close(mipmap.sample(-7. / 8., 2.5).x, 0.5, 4e-6, "wrapped around");
+
+    let mut rng = rng();
+    for _ in 0..CASES {
+        let (u, v) = (rng.gen_range(-2., 2.), rng.gen_range(-2., 2.));
+        let footprint = rng.gen_range(0.25, 4.);
+        assert_close(mipmap.sample_footprint(u, v, footprint).x, 128. / 255., 4e-6,
+                     "wide footprint");
+        let narrow = mipmap.sample_footprint(u, v, 0.1);
+        assert!(narrow.x >= 0. && narrow.x <= 1., "narrow footprint {}", narrow.x);
+    }
+
+    // Color images are sRGB: their levels decode to linear values, and average as those, so
+    // black and white levels average to the 188 that encodes half as much light
+    let columns = RgbImage::from_fn(8, 4, |x, _| {
+        Rgb { data: [if x % 2 == 0 { 0 } else { 255 }; 3] }
+    });
+    let mipmap = Mipmap::srgb(columns);
+    assert_close(mipmap.sample(1.5 / 8., 0.5).x, 1., 4e-6, "white sRGB texel");
+    assert_close(mipmap.sample(1. / 8., 0.5).x, 0.5, 4e-6, "between sRGB texels");
+    assert_close(mipmap.sample_footprint(0.5, 0.5, 1.).x, 0.5, 4e-3, "averaged sRGB texels");
+    let gray = Mipmap::srgb(RgbImage::from_pixel(2, 2, Rgb { data: [188; 3] }));
+    assert_close(gray.sample(0.5, 0.5).x, 0.503, 1e-3, "sRGB gray");
+}
+
+// A texture that fails to load fails only the render sampling it: the shared cache stays usable
+#[test]
+fn texture_cache_survives_a_bad_texture() {
+    let cache = TextureCache::new(1 << 20);
+    let path = env::temp_dir().join("ray-tracer-test-cached.png");
+    RgbImage::from_pixel(4, 4, Rgb { data: [255, 0, 0] }).save(&path).unwrap();
+    let missing = CachedImageTexture::new("no-such-texture.png", true, cache.clone());
+    let failed = panic::catch_unwind(AssertUnwindSafe(|| missing.color(0.5, 0.5)));
+    assert!(failed.is_err(), "a missing texture sampled");
+    let red = CachedImageTexture::new(path.to_str().unwrap(), true, cache.clone());
+    assert_eq!(red.color(0.5, 0.5), Vec3::new(1., 0., 0.));
+    fs::remove_file(&path).unwrap();
+    assert_eq!(texture::lock(&cache).used(), 4 * 4 * 3 + 2 * 2 * 3 + 3);
+}
+
+// The budget holds mip levels: a texture too big for it is sampled at the finest level that fits,
+// without being decoded again, and other textures make room by dropping its levels
+#[test]
+fn texture_cache_keeps_mip_levels_that_fit() {
+    let cache = TextureCache::new(2 * 2 * 3 + 3);
+    let (red_path, green_path) = (env::temp_dir().join("ray-tracer-test-red.png"),
+                                  env::temp_dir().join("ray-tracer-test-green.png"));
+    RgbImage::from_pixel(4, 4, Rgb { data: [255, 0, 0] }).save(&red_path).unwrap();
+    RgbImage::from_pixel(2, 2, Rgb { data: [0, 255, 0] }).save(&green_path).unwrap();
+    let red = CachedImageTexture::new(red_path.to_str().unwrap(), true, cache.clone());
+    assert_eq!(red.color(0.5, 0.5), Vec3::new(1., 0., 0.));
+    assert_eq!(texture::lock(&cache).used(), 2 * 2 * 3 + 3);
+    // Which would fail if the levels were decoded again
+    fs::remove_file(&red_path).unwrap();
+    assert_eq!(red.color(0.25, 0.75), Vec3::new(1., 0., 0.));
+    assert_eq!(red.color_filtered(0.5, 0.5, 1.), Vec3::new(1., 0., 0.));
+    let green = CachedImageTexture::new(green_path.to_str().unwrap(), true, cache.clone());
+    assert_eq!(green.color(0.5, 0.5), Vec3::new(0., 1., 0.));
+    fs::remove_file(&green_path).unwrap();
+    assert_eq!(texture::lock(&cache).used(), 2 * 2 * 3 + 3);
+}
+
+// Normal and bump textures tilt the normal in the frame the texture coordinates run in, with v
+// mirrored or not and u and v as far apart as they happen to be
+#[test]
+fn normal_textures_follow_the_texture_coordinates() {
+    let mut rng = rng();
+    // Tilted towards +u and a little towards -v
+    let texel = Rgb { data: [200, 100, 220] };
+    let tilted = material().with_normal_texture(Box::new(ImageTexture::from_linear_image(
+        RgbImage::from_pixel(4, 4, texel))));
+    // Raised 4 / 255 per texel along u, with bilinear filtering
+    let ramp = RgbImage::from_fn(64, 4, |x, _| Rgb { data: [(x * 4) as u8; 3] });
+    let strength = 0.5;
+    let bumped = material().with_bump_texture(Box::new(ImageTexture::from_linear_image(ramp)),
+                                              strength);
+    let slope = 4. * 64. / 255. * strength;
+    for _ in 0..CASES / 10 {
+        let normal = random_dir(&mut rng);
+        let along = random_dir(&mut rng);
+        let u_dir = (along - normal * dot(&along, &normal)).normalize();
+        let mirror = if rng.gen() { 1. } else { -1. };
+        let v_dir = cross(&normal, &u_dir) * mirror;
+        let (su, sv): (Float, Float) = (rng.gen_range(0.1, 10.), rng.gen_range(0.1, 10.));
+        let (u, v): (Float, Float) = (rng.gen_range(0.3, 0.7), rng.gen_range(0., 1.));
+        let hit = Intersection::new(Vec3::new(0., 0., 0.), normal, 1., u, v)
+            .with_uv_derivatives(u_dir * su, v_dir * sv);
+
+        let mapped = tilted.apply_normal_textures(hit.clone()).normal;
+        let t = Vec3::new(200., 100., 220.) / 255. * 2. - Vec3::new(1., 1., 1.);
+        let expected = (u_dir * t.x + v_dir * t.y + normal * t.z).normalize();
+        assert!((mapped - expected).norm() < 1e-4, "normal texture gave {:?}, not {:?}",
+                mapped, expected);
+
+        // The surface climbs `slope` per unit of u, so per su along u_dir
+        let raised = bumped.apply_normal_textures(hit.clone()).normal;
+        let expected = (normal - u_dir * (slope / su)).normalize();
+        assert!((raised - expected).norm() < 1e-3, "bump texture gave {:?}, not {:?}", raised,
+                expected);
+    }
+    // Surfaces without texture coordinate derivatives keep their normal
+    let hit = Intersection::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), 1., 0.5, 0.5);
+    assert_eq!(tilted.apply_normal_textures(hit).normal, Vec3::new(0., 1., 0.));
+}
+
+#[test]
+fn procedural_patterns_stay_in_range_and_bumps_tilt_normals() {
+    let mut rng = rng();
+    let bases = [Basis::Perlin, Basis::Simplex, Basis::Worley];
+    let patterns = [Pattern::Fbm, Pattern::Turbulence, Pattern::Marble, Pattern::Wood];
+    for (i, &basis) in bases.iter().enumerate() {
+        for &pattern in patterns.iter() {
+            let procedural = Procedural::new(basis, pattern, i as u32, 4, 2.);
+            let bump = Bump::new(procedural.clone(), 0.1);
+            for _ in 0..CASES / 10 {
+                let pos = random_vec(&mut rng, 10.);
+                let value = procedural.value(&pos);
+                assert!(value >= 0. && value <= 1., "{:?} {:?} value {}", basis, pattern, value);
+                assert!(procedural.clone().value(&pos) == value, "clones give the same pattern");
+
+                // The tilted normal is still a unit normal on the same side of the surface
+                let normal = random_dir(&mut rng);
+                let bumped = bump.apply(&normal, &pos);
+                assert_unit(&bumped, "bumped normal");
+                assert!(dot(&bumped, &normal) > 0., "bump turned the normal over");
+            }
+        }
+    }
+}
+
+#[test]
+fn sh_reproduces_low_order_functions() {
+    let mut rng = rng();
+    // Polynomials up to degree 2 are represented exactly, up to the sampling error
+    let sh = Sh9::project(4096, |dir| Vec3::new(1., 2. + dir.y, 3. - dir.x * dir.z));
+    for _ in 0..CASES {
+        let dir = random_dir(&mut rng);
+        let value = sh.eval(&dir);
+        assert_close(value.x, 1., 1e-2, "constant");
+        assert_close(value.y, 2. + dir.y, 1e-2, "linear");
+        assert_close(value.z, 3. - dir.x * dir.z, 1e-2, "quadratic");
+    }
+}
+
+#[test]
+fn sh_irradiance_of_uniform_light() {
+    let mut rng = rng();
+    // A diffuse surface under uniform light reflects that light no matter which way it faces
+    let sh = Sh9::project(4096, |_| Vec3::new(1., 1., 1.));
+    for _ in 0..CASES {
+        let irradiance = sh.irradiance(&random_dir(&mut rng)) / float::consts::PI;
+        assert_close(irradiance.x, 1., 4e-4, "average radiance");
+    }
+}
+
+#[test]
+fn tile_orders_cover_every_tile_once() {
+    let mut rng = rng();
+    for _ in 0..100 {
+        let (tiles_x, tiles_y) = (rng.gen_range(1, 40), rng.gen_range(1, 40));
+        for &order in [TileOrder::Scanline, TileOrder::Hilbert, TileOrder::Spiral].iter() {
+            let mut tiles = tile_order(tiles_x, tiles_y, order);
+            if order == TileOrder::Spiral {
+                let first = tiles[0];
+                assert!(first.0 * 2 + 1 >= tiles_x - 1 && first.0 * 2 <= tiles_x &&
+                        first.1 * 2 + 1 >= tiles_y - 1 && first.1 * 2 <= tiles_y,
+                        "spiral starts at {:?} in {}x{}", first, tiles_x, tiles_y);
+            }
+            tiles.sort();
+            tiles.dedup();
+            assert_eq!(tiles.len() as u32, tiles_x * tiles_y, "{:?} {}x{}", order, tiles_x,
+                       tiles_y);
+            assert!(tiles.iter().all(|&(tx, ty)| tx < tiles_x && ty < tiles_y));
+        }
+    }
+}
+
+#[test]
+fn samplers_cover_the_unit_square() {
+    let mut rng = rng();
+    for &name in ["random", "stratified", "halton", "sobol"].iter() {
+        let sampler = sampler::by_name(name).unwrap();
+        for _ in 0..CASES {
+            let seed = rng.gen();
+            let points: Vec<_> = (0..16).map(|i| sampler.get_2d(seed, i, 16, 3)).collect();
+            assert!(points.iter().all(|&(u, v)| u >= 0. && u < 1. && v >= 0. && v < 1.), "{}",
+                    name);
+            // One point per cell of a 4x4 grid
+            if name == "stratified" || name == "sobol" {
+                let mut cells: Vec<_> = points.iter()
+                    .map(|&(u, v)| (u * 4.) as u32 + 4 * (v * 4.) as u32)
+                    .collect();
+                cells.sort();
+                cells.dedup();
+                assert_eq!(cells.len(), 16, "{} {:?}", name, points);
+            }
+        }
+        // The mean of x*y over the unit square is 1/4
+        let mean = (0..256).map(|i| {
+            let (u, v) = sampler.get_2d(7, i, 256, 1);
+            u * v
+        }).sum::<Float>() / 256.;
+        assert_close(mean, 0.25, if name == "random" { 0.05 } else { 0.01 }, name);
+    }
+}
+
+#[test]
+fn jpeg_and_tiff_output_decode_back() {
+    // A smooth gradient in each channel, 20 by 13 so the JPEG blocks don't fit evenly
+    let (width, height) = (20, 13);
+    let mut data = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            data.extend_from_slice(&[(x * 12) as u8, (y * 19) as u8, 128]);
+        }
+    }
+    for &(format, image_format, tolerance) in &[(Format::Jpeg, ImageFormat::JPEG, 8),
+                                                (Format::Tiff, ImageFormat::TIFF, 0)] {
+        let mut file = Vec::new();
+        output::write_8(&mut file, format, &data, width, height, 3, 95).unwrap();
+        let decoded = image::load_from_memory_with_format(&file, image_format).unwrap().to_rgb();
+        assert_eq!(decoded.dimensions(), (width, height));
+        for (a, &b) in decoded.into_raw().iter().zip(data.iter()) {
+            assert!((*a as i32 - b as i32).abs() <= tolerance, "{:?}: {} for {}", format, a, b);
+        }
+    }
+}
+
+#[test]
+fn ray_differentials_widen_footprints_with_distance_angle_and_mirrors() {
+    let floor = Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), material());
+    let ceiling = Plane::new(Vec3::new(0., 2., 0.), Vec3::new(0., -1., 0.), material());
+    // A pinhole's rays, a thousandth of a radian apart from one pixel to the next
+    let ray = |origin: Vec3, dir: Vec3, across: Vec3| {
+        let dir = dir.normalize();
+        let down = cross(&dir, &across).normalize();
+        Ray::new(origin, dir).with_differentials(Some(Differentials {
+            origin_dx: Vec3::new(0., 0., 0.),
+            origin_dy: Vec3::new(0., 0., 0.),
+            dir_dx: across.normalize() * 1e-3,
+            dir_dy: down * 1e-3,
+        }))
+    };
+    let footprint = |surface: &Surface, ray: &Ray| {
+        let hit = surface.intersect(ray).unwrap();
+        (ray.differentials.unwrap().footprint(ray, &hit), hit)
+    };
+    let x = Vec3::new(1., 0., 0.);
+    let down = Vec3::new(0., -1., 0.);
+    assert_close(footprint(&floor, &ray(Vec3::new(0., 1., 0.), down, x)).0, 1e-3, 1e-6, "1 up");
+    assert_close(footprint(&floor, &ray(Vec3::new(0., 10., 0.), down, x)).0, 1e-2, 1e-5,
+                 "10 up");
+    // At 45 degrees, √2 away and stretched by √2 along the floor
+    let slanted = ray(Vec3::new(0., 1., 0.), Vec3::new(0., -1., 1.), x);
+    assert_close(footprint(&floor, &slanted).0, 2e-3, 1e-6, "slanted");
+
+    // A flat mirror unfolds the path: 1 down and 2 up
+    let (_, hit) = footprint(&floor, &ray(Vec3::new(0., 1., 0.), down, x));
+    let incoming = ray(Vec3::new(0., 1., 0.), down, x);
+    let reflected = Ray::new(hit.pos, ray::reflect(&incoming.dir, &hit.normal))
+        .with_differentials(incoming.differentials.unwrap().reflected(&incoming, &hit,
+                                                                      &hit.normal));
+    assert_close(footprint(&ceiling, &reflected).0, 3e-3, 1e-6, "mirrored");
+}
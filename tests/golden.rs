@@ -0,0 +1,412 @@
+// Golden image regression tests. Each test renders a small built-in scene and compares it with
+// a reference image in tests/golden/. Run with UPDATE_GOLDEN=1 to (re)generate the references
+// after an intentional change to the output, and check in the new images. The comparison only
+// works if rendering is deterministic, which renders_are_deterministic checks exactly.
+
+extern crate image;
+extern crate nalgebra;
+extern crate tracerlib;
+
+use std::cmp;
+use std::env;
+use std::path::PathBuf;
+
+use tracerlib::{float, ray_trace, ray_trace_events, ray_trace_hdr, ray_trace_resumed,
+                ray_trace_tiles, Camera, Float, RenderEvent, Scene, Vec3};
+use tracerlib::adaptive::{ray_trace_adaptive, AdaptiveSettings};
+use tracerlib::aov::{ray_trace_aov, Aov};
+use tracerlib::color::OutputTransform;
+use tracerlib::hdr::HdrImage;
+use tracerlib::light::PointLight;
+use tracerlib::log::{self, Level};
+use tracerlib::material::{DisplacementMap, Material, NormalMap};
+use tracerlib::path::Integrator;
+use tracerlib::post::{FilmGrain, Imaging, PostPipeline};
+use tracerlib::subsurface::Subsurface;
+use tracerlib::surface::{Plane, Sphere, Surface};
+use tracerlib::texture::{CheckerboardTexture, ImageTexture, Texture};
+use tracerlib::tiles::TileOrder;
+
+use image::{Rgb, RgbImage};
+use nalgebra::Norm;
+
+const WIDTH: u32 = 80;
+const HEIGHT: u32 = 60;
+
+// Average per channel difference allowed over the whole image, out of 255
+const MAX_MEAN_DIFF: Float = 1.0;
+// Pixels with a channel off by more than this count as changed...
+const PIXEL_THRESHOLD: u8 = 16;
+// ...and at most this fraction of pixels may change
+const MAX_CHANGED_FRACTION: Float = 0.005;
+
+// A color given in 8 bit levels, like the scenes the references were first rendered from
+fn levels(r: Float, g: Float, b: Float) -> Vec3 {
+    Vec3::new(r, g, b) / 255.
+}
+
+fn plain_material(color: Vec3, reflectivity: Float) -> Material {
+    Material::new(color, 0.7, 0.0, 0.0, reflectivity, None, None, None)
+}
+
+fn shiny_material(color: Vec3) -> Material {
+    Material::new(color, 0.3, 0.2, 20., 0., None, None, None)
+}
+
+fn checkerboard_material() -> Material {
+    let texture = Box::new(CheckerboardTexture::new(1.)) as Box<Texture>;
+    Material::new(levels(100., 100., 100.), 0.7, 0., 0., 1., Some(texture), None, None)
+}
+
+fn white_light(pos: Vec3, intensity: Float) -> PointLight {
+    PointLight::new(pos, Vec3::new(1., 1., 1.), intensity)
+}
+
+fn sphere_scene() -> Scene {
+    let objects = vec![
+        Box::new(Sphere::new(Vec3::new(0., 1., 0.), 1., shiny_material(Vec3::new(0., 0., 1.))))
+            as Box<Surface>,
+        Box::new(Plane::new(Vec3::new(1., 0., 1.), Vec3::new(0., 1., 0.),
+                            checkerboard_material())),
+    ];
+    let lights = vec![white_light(Vec3::new(3., 3., -4.), 2.)];
+    let camera = Camera::from_lookat(Vec3::new(0., 2., -5.), Vec3::new(0., 1., 0.),
+                                     Vec3::new(0., 1., 0.));
+    Scene::new(objects, lights, 0.1, Vec3::new(1., 1., 1.), camera)
+}
+
+fn reflection_scene() -> Scene {
+    let objects = vec![
+        Box::new(Sphere::new(Vec3::new(-1., 1., 0.), 1.,
+                             plain_material(Vec3::new(1., 0., 0.), 0.5))) as Box<Surface>,
+        Box::new(Sphere::new(Vec3::new(1.2, 0.7, -0.5), 0.7,
+                             plain_material(Vec3::new(0., 1., 0.), 0.5))),
+        Box::new(Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.),
+                            plain_material(levels(100., 100., 100.), 0.3))),
+        Box::new(Plane::new(Vec3::new(0., 0., 3.), Vec3::new(0., 0., -1.),
+                            plain_material(levels(0., 0., 200.), 0.))),
+    ];
+    let lights = vec![white_light(Vec3::new(2., 4., -4.), 1.5),
+                      PointLight::new(Vec3::new(-3., 2., -2.), levels(255., 200., 100.), 0.5)];
+    let camera = Camera::from_lookat(Vec3::new(0., 1.5, -6.), Vec3::new(0., 1., 0.),
+                                     Vec3::new(0., 1., 0.));
+    Scene::new(objects, lights, 0.1, Vec3::new(1., 1., 1.), camera)
+}
+
+fn noise_map_scene() -> Scene {
+    let material = Material::new(levels(2., 62., 112.), 0.4, 0.5, 40., 1., None,
+                                 Some(NormalMap::new(11, 4, 6.25, 0.9, 3.)),
+                                 Some(DisplacementMap::new(11, 2, 6.25, 0.9, 3.)));
+    let objects = vec![
+        Box::new(Plane::new(Vec3::new(1., 0., 1.), Vec3::new(0., 1., 0.), material))
+            as Box<Surface>,
+    ];
+    let lights = vec![white_light(Vec3::new(1., 2., -1.), 1.),
+                      white_light(Vec3::new(-1., 1., 2.), 0.5)];
+    let camera = Camera::from_lookat(Vec3::new(0., 2., 5.), Vec3::new(0., 1., 0.),
+                                     Vec3::new(0., 1., 0.));
+    Scene::new(objects, lights, 0.1, Vec3::new(1., 1., 1.), camera)
+}
+
+// A floor under an sRGB image texture, rendered through the sRGB output transform so that the
+// texture's levels decode to linear colors and encode back for display
+fn image_texture_scene() -> Scene {
+    let image = RgbImage::from_fn(16, 16, |x, y| {
+        Rgb { data: [(x * 17) as u8, (y * 17) as u8, if (x + y) % 2 == 0 { 188 } else { 60 }] }
+    });
+    let texture = Box::new(ImageTexture::from_image(image)) as Box<Texture>;
+    let material = Material::new(Vec3::new(1., 1., 1.), 0.7, 0., 0., 0., Some(texture), None,
+                                 None);
+    let objects = vec![
+        Box::new(Plane::new(Vec3::new(0., 0., 0.), Vec3::new(0., 1., 0.), material))
+            as Box<Surface>,
+        Box::new(Sphere::new(Vec3::new(0., 1., 0.), 1., plain_material(levels(188., 188., 188.),
+                                                                        0.))),
+    ];
+    let lights = vec![white_light(Vec3::new(3., 4., -4.), 1.5)];
+    let camera = Camera::from_lookat(Vec3::new(0., 2., -5.), Vec3::new(0., 1., 0.),
+                                     Vec3::new(0., 1., 0.));
+    Scene::new(objects, lights, 0.1, Vec3::new(1., 1., 1.), camera)
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests");
+    path.push("golden");
+    path.push(format!("{}.png", name));
+    path
+}
+
+fn render(scene: &Scene, max_depth: u16) -> RgbImage {
+    log::set_level(Level::Warn);
+    ray_trace(scene, WIDTH, HEIGHT, max_depth)
+}
+
+fn check_golden(name: &str, im: &RgbImage) {
+    let path = golden_path(name);
+    if env::var("UPDATE_GOLDEN").is_ok() || !path.exists() {
+        im.save(&path).unwrap();
+        println!("Wrote reference image {}", path.display());
+        return;
+    }
+
+    let reference = image::open(&path).unwrap().to_rgb();
+    assert_eq!(reference.dimensions(), im.dimensions(), "{}: image size changed", name);
+
+    let mut total_diff = 0u64;
+    let mut changed = 0u32;
+    for (a, b) in reference.pixels().zip(im.pixels()) {
+        let mut max_diff = 0;
+        for c in 0..3 {
+            let diff = (a.data[c] as i16 - b.data[c] as i16).abs() as u8;
+            total_diff += diff as u64;
+            max_diff = cmp::max(max_diff, diff);
+        }
+        if max_diff > PIXEL_THRESHOLD {
+            changed += 1;
+        }
+    }
+
+    let pixels = (im.width() * im.height()) as Float;
+    let mean_diff = total_diff as Float / (pixels * 3.);
+    let changed_fraction = changed as Float / pixels;
+    assert!(mean_diff <= MAX_MEAN_DIFF && changed_fraction <= MAX_CHANGED_FRACTION,
+            "{}: output differs from {} (mean difference {:.3}, {:.2}% of pixels changed)",
+            name, path.display(), mean_diff, changed_fraction * 100.);
+}
+
+#[test]
+fn golden_sphere() {
+    check_golden("sphere", &render(&sphere_scene(), 1));
+}
+
+#[test]
+fn golden_reflections() {
+    check_golden("reflections", &render(&reflection_scene(), 3));
+}
+
+#[test]
+fn golden_noise_maps() {
+    check_golden("noise_maps", &render(&noise_map_scene(), 1));
+}
+
+#[test]
+fn golden_image_texture() {
+    log::set_level(Level::Warn);
+    let im = ray_trace_hdr(&image_texture_scene(), WIDTH, HEIGHT, 1, |_, _| ());
+    check_golden("image_texture", &im.encode(OutputTransform::Srgb, false));
+}
+
+#[test]
+fn renders_are_deterministic() {
+    log::set_level(Level::Warn);
+    let scene = reflection_scene();
+    // The order tiles are rendered in mustn't change any pixel, not even in the last bit
+    let scanline = ray_trace_tiles(&scene, WIDTH, HEIGHT, 3, TileOrder::Scanline, |_, _| {});
+    let spiral = ray_trace_tiles(&scene, WIDTH, HEIGHT, 3, TileOrder::Spiral, |_, _| {});
+    assert!(scanline.pixels() == spiral.pixels(), "tile order changed the image");
+
+    // Adaptive sampling schedules by measured cost, which must not depend on timing
+    let settings = AdaptiveSettings::new(8);
+    let render = || {
+        ray_trace_adaptive(&scene, WIDTH, HEIGHT, 3, settings, TileOrder::Hilbert, |_| {})
+    };
+    assert!(render().pixels() == render().pixels(), "adaptive render isn't reproducible");
+}
+
+// Continuing from the tiles finished so far gives the same image as rendering without stopping
+#[test]
+fn resumed_render_matches_uninterrupted_one() {
+    log::set_level(Level::Warn);
+    let scene = reflection_scene();
+    let mut stopped = None;
+    let mut finished = Vec::new();
+    let full = ray_trace_events(&scene, WIDTH, HEIGHT, 3, 2, TileOrder::Spiral, |event| {
+        if let RenderEvent::TileFinished { region, done, image, .. } = event {
+            if done <= 3 {
+                finished.push(region);
+                stopped = Some(image.clone());
+            }
+        }
+    });
+
+    let mut rendered = 0;
+    let resumed = ray_trace_resumed(&scene, WIDTH, HEIGHT, 3, 2, TileOrder::Scanline,
+                                    stopped.unwrap(), &finished, |event| {
+        if let RenderEvent::TileFinished { region, done, total, .. } = event {
+            assert!(!finished.contains(&region), "finished tile {:?} rendered again", region);
+            assert!(done > 3 && done <= total);
+            rendered += 1;
+        }
+    });
+    assert_eq!(rendered, 6 - 3);
+    assert!(full.pixels() == resumed.pixels(), "resuming changed the image");
+}
+
+#[test]
+fn radiance_output_keeps_highlights() {
+    let colors = [Vec3::new(0.5, 0.25, 0.), Vec3::new(40., 2., 0.001)];
+    let mut im = HdrImage::new(2, 1);
+    for (x, color) in colors.iter().enumerate() {
+        im.put_pixel(x as u32, 0, *color);
+    }
+    let mut data = Vec::new();
+    im.write_radiance(&mut data).unwrap();
+
+    let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n";
+    assert!(data.starts_with(header), "bad header");
+    let pixels = &data[header.len()..];
+    assert_eq!(pixels.len(), 8);
+    for (rgbe, color) in pixels.chunks(4).zip(colors.iter()) {
+        let scale = Float::powi(2., rgbe[3] as i32 - 136);
+        let decoded = [rgbe[0] as Float * scale, rgbe[1] as Float * scale,
+                       rgbe[2] as Float * scale];
+        let max = color.x.max(color.y).max(color.z);
+        for (&d, &c) in decoded.iter().zip([color.x, color.y, color.z].iter()) {
+            assert!(d <= c && c - d <= max / 128., "{} decoded as {}", c, d);
+        }
+    }
+
+    let largest = |v: Vec3| v.x.abs().max(v.y.abs()).max(v.z.abs());
+    let read = HdrImage::read_radiance(&mut &data[..]).unwrap();
+    assert_eq!((read.width(), read.height()), (2, 1));
+    for (x, color) in colors.iter().enumerate() {
+        let read = read.get_pixel(x as u32, 0);
+        assert!(largest(read - *color) <= largest(*color) / 100., "pixel {} read back as {:?}",
+                x, read);
+    }
+
+    // Run length encoded scanlines: a run of 8 for red, then literals for green, and runs of 4
+    // for blue and the exponent
+    let mut rle = b"#?RADIANCE\n\n-Y 1 +X 8\n".to_vec();
+    rle.extend_from_slice(&[2, 2, 0, 8, 136, 128, 8, 0, 1, 2, 3, 4, 5, 6, 7, 132, 64, 132, 0,
+                            132, 129, 132, 130]);
+    let read = HdrImage::read_radiance(&mut &rle[..]).unwrap();
+    for x in 0..8 {
+        let color = read.get_pixel(x, 0);
+        let scale = if x < 4 { Float::powi(2., -7) } else { Float::powi(2., -6) };
+        let expected = Vec3::new(128.5, x as Float + 0.5, if x < 4 { 64.5 } else { 0.5 }) * scale;
+        assert!(largest(color - expected) < 1e-6, "pixel {} read as {:?}", x, color);
+    }
+}
+
+// The camera's exposure and vignette apply with the post effects, even without any, and white
+// balance at the 6500K reference changes nothing
+#[test]
+fn camera_imaging_brightens_and_vignettes() {
+    let mut scene = sphere_scene();
+    let camera = scene.camera().clone().with_imaging(Imaging {
+        ev: 1.,
+        white_balance: Some((6500., 0.)),
+        vignette: Some((0.5, 0.)),
+    });
+    scene.set_camera(camera);
+    let mut im = HdrImage::new(41, 31);
+    for y in 0..31 {
+        for x in 0..41 {
+            im.put_pixel(x, y, Vec3::new(0.4, 0.4, 0.4));
+        }
+    }
+    PostPipeline::new().apply(&mut im, &scene);
+
+    let center = im.get_pixel(20, 15);
+    assert!((center.x - 0.8).abs() < 4e-4 && (center.z - 0.8).abs() < 4e-4,
+            "the center is {:?} instead of twice as bright", center);
+    let corner = im.get_pixel(0, 0);
+    assert!(corner.x > 0.38 && corner.x < 0.42, "the corner is {:?} instead of half as bright",
+            corner);
+    let between = im.get_pixel(10, 8);
+    assert!(between.x < center.x && between.x > corner.x, "the vignette doesn't ease in");
+}
+
+// The grain is as strong in the encoded image whichever curve encodes it
+#[test]
+fn film_grain_follows_the_output_transform() {
+    let scene = sphere_scene();
+    for &transform in &[OutputTransform::Linear, OutputTransform::Srgb, OutputTransform::Rec709,
+                        OutputTransform::Aces, OutputTransform::Reinhard] {
+        // Midtone gray once encoded, where the grain is strongest
+        let gray = transform.invert(Vec3::new(0.5, 0.5, 0.5));
+        let mut im = HdrImage::new(32, 32);
+        for y in 0..32 {
+            for x in 0..32 {
+                im.put_pixel(x, y, gray);
+            }
+        }
+        PostPipeline::new().then(FilmGrain::new(0.1, 7, transform)).apply(&mut im, &scene);
+        let mut largest: Float = 0.;
+        for y in 0..32 {
+            for x in 0..32 {
+                let grain = transform.apply(im.get_pixel(x, y)).x - 0.5;
+                assert!(grain.abs() < 0.1 + 1e-4, "{:?} grain of {}", transform, grain);
+                largest = largest.max(grain.abs());
+            }
+        }
+        assert!(largest > 0.09, "{:?} grain of at most {}", transform, largest);
+    }
+}
+
+#[test]
+fn ambient_occlusion_darkens_contact() {
+    let mut scene = sphere_scene();
+    scene.set_integrator(Integrator::AmbientOcclusion { samples: 64, distance: float::INFINITY });
+    let im = render(&scene, 1);
+    let brightness = |x: u32, y: u32| im.get_pixel(x, y).data[0];
+    // The sphere's top is open to the whole sky, and so is the plane far from it, while the
+    // plane right next to the sphere is half hidden under it
+    let (top, far, contact) = (brightness(WIDTH / 2, HEIGHT * 3 / 10),
+                               brightness(WIDTH / 20, HEIGHT * 19 / 20),
+                               brightness(WIDTH / 2, HEIGHT * 11 / 16));
+    assert!(top > 240 && far > 200, "open points are gray: {} {}", top, far);
+    assert!(contact < far / 2, "contact shadow {} isn't darker than {}", contact, far);
+}
+
+// A sphere lit only from behind is black in front when diffuse, but light walking through it
+// shows there when it scatters under the surface
+#[test]
+fn subsurface_light_glows_through() {
+    let front = |material: Material| {
+        let objects = vec![Box::new(Sphere::new(Vec3::new(0., 0., 0.), 1., material))
+                               as Box<Surface>];
+        let lights = vec![white_light(Vec3::new(0., 0., 4.), 4.)];
+        let camera = Camera::from_lookat(Vec3::new(0., 0., -5.), Vec3::new(0., 0., 0.),
+                                         Vec3::new(0., 1., 0.));
+        let mut scene = Scene::new(objects, lights, 0.1, Vec3::new(0., 0., 0.), camera);
+        scene.set_integrator(Integrator::Path);
+        let im = render(&scene, 1);
+        let mut sum = 0;
+        for y in HEIGHT / 2 - 4..HEIGHT / 2 + 5 {
+            for x in WIDTH / 2 - 4..WIDTH / 2 + 5 {
+                sum += im.get_pixel(x, y).data.iter().map(|&c| c as u32).sum::<u32>();
+            }
+        }
+        sum
+    };
+    let diffuse = plain_material(Vec3::new(1., 1., 1.), 0.);
+    assert_eq!(front(diffuse.clone()), 0);
+    let waxy = diffuse.with_subsurface(Subsurface::new(Vec3::new(1., 1., 1.),
+                                                       Vec3::new(1., 1., 1.)));
+    assert!(front(waxy) > 0, "no light gets through the subsurface sphere");
+}
+
+#[test]
+fn aovs_describe_the_first_hit() {
+    let scene = sphere_scene();
+    let pass = |aov: Aov| ray_trace_aov(&scene, WIDTH, HEIGHT, 1, aov);
+    let (depth, normal, albedo, id) =
+        (pass(Aov::Depth), pass(Aov::Normal), pass(Aov::Albedo), pass(Aov::ObjectId));
+    // The middle of the image looks straight at the sphere's center
+    let (x, y) = (WIDTH / 2, HEIGHT / 2);
+    let expected_normal = Vec3::new(0., 1., -5.).normalize();
+    assert!((depth.get_pixel(x, y).x - (Float::sqrt(26.) - 1.)).abs() < 1e-3,
+            "depth {}", depth.get_pixel(x, y).x);
+    assert!((normal.get_pixel(x, y) - expected_normal).norm() < 1e-3, "sphere normal");
+    assert!((albedo.get_pixel(x, y) - Vec3::new(0., 0., 0.3)).norm() < 1e-3, "albedo");
+
+    // IDs are the object's index plus one, with 0 where nothing was hit
+    let ids: Vec<u32> = id.pixels().iter().map(|p| p.x.round() as u32).collect();
+    assert!(ids[(y * WIDTH + x) as usize] == 1, "the sphere, object 0, doesn't have ID 1");
+    for object in 0..3 {
+        assert!(ids.contains(&object), "no pixels with object ID {}", object);
+    }
+}